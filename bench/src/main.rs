@@ -1,5 +1,4 @@
 #![deny(clippy::all)]
-#![feature(test)]
 
 use libzerostash::stash::{Stash, StashKey};
 use libzerostash::{backends, objects};
@@ -36,19 +35,32 @@ pub fn main() {
 
     // i am really, truly sorry for this. there must be a better way,
     // but i can't be bothered to find it
-    let (store_time, commit_time, ol, fl, cl, creuse_sum, creuse_cnt, ssize, tlen, tsize) = {
-        let key = StashKey::open_stash(&key, &key).unwrap();
+    let (
+        store_time,
+        store_summary,
+        commit_time,
+        ol,
+        fl,
+        cl,
+        creuse_sum,
+        creuse_cnt,
+        ssize,
+        tlen,
+        tsize,
+    ) = {
+        let key = StashKey::open_stash(key, key).unwrap();
         let mut repo = Stash::new(Arc::new(backends::Directory::new(&output).unwrap()), key);
 
         let store_start = Instant::now();
-        repo.add_recursive(threads.parse().unwrap(), &path).unwrap();
+        let store_summary = repo.add_recursive(threads.parse().unwrap(), &path).unwrap();
         let store_time = store_start.elapsed();
 
         let commit_start = Instant::now();
-        let mobjects = repo.commit().unwrap();
+        let committed = repo.commit().unwrap();
         let commit_time = commit_start.elapsed();
 
-        let objects = mobjects
+        let objects = committed
+            .objects
             .values()
             .flatten()
             .collect::<HashSet<&objects::ObjectId>>();
@@ -84,6 +96,7 @@ pub fn main() {
 
         (
             store_time,
+            store_summary,
             commit_time,
             ol,
             fl,
@@ -110,7 +123,8 @@ pub fn main() {
  * meta dump time: {}
  * meta object count: {}
  * chunk reuse: {}/{} = {}
-"#,
+
+store stages: {}"#,
         // * storage for chunks: {}
         path,
         store_time.as_secs_f64(),
@@ -125,11 +139,12 @@ pub fn main() {
         ol,
         creuse_sum,
         creuse_cnt,
-        creuse_sum / creuse_cnt
+        creuse_sum / creuse_cnt,
+        store_summary
     );
 
     {
-        let key = StashKey::open_stash(&key, &key).unwrap();
+        let key = StashKey::open_stash(key, key).unwrap();
         let mut repo = Stash::new(Arc::new(backends::Directory::new(&output).unwrap()), key);
 
         let read_start = Instant::now();
@@ -137,7 +152,8 @@ pub fn main() {
         let read_time = read_start.elapsed();
 
         let restore_start = Instant::now();
-        repo.restore_by_glob(threads.parse().unwrap(), &["*"], restore_to)
+        let restore_summary = repo
+            .restore_by_glob(threads.parse().unwrap(), &["*"], restore_to)
             .unwrap();
         let restore_time = restore_start.elapsed();
 
@@ -148,11 +164,13 @@ pub fn main() {
 restore time: {}
 throughput packed: {}
 throughput unpacked: {}
-"#,
+
+restore stages: {}"#,
             read_time.as_secs_f64(),
             restore_time.as_secs_f64(),
             mb(tsize as f64) / total_time,
-            mb(ssize as f64) / total_time
+            mb(ssize) / total_time,
+            restore_summary
         );
     }
 }
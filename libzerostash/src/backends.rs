@@ -1,15 +1,77 @@
-use crate::objects::{Object, ObjectId, ReadBuffer, ReadObject, WriteObject};
+use crate::objects::{BlockBuffer, Object, ObjectId, ReadBuffer, ReadObject, WriteObject};
 
-use lru::LruCache;
-use memmap::{Mmap, MmapOptions};
 use thiserror::Error;
 
 use std::collections::HashMap;
-use std::fs;
-use std::io::{self, Write};
-use std::path::{Path, PathBuf};
+use std::convert::TryFrom;
+use std::io;
 use std::sync::{Arc, Mutex};
 
+#[cfg(feature = "fs")]
+mod directory;
+#[cfg(feature = "fs")]
+pub use directory::Directory;
+#[cfg(feature = "fs")]
+mod cache;
+#[cfg(feature = "fs")]
+pub use cache::{CachedBackend, Eviction};
+#[cfg(feature = "async")]
+mod async_backend;
+mod fetch;
+pub use fetch::{Fetch, FetchBackend};
+mod immutable;
+#[cfg(feature = "async")]
+pub use async_backend::{AsyncAdapter, AsyncBackend, BoxFuture, SyncAdapter};
+pub use immutable::ImmutableBackend;
+mod mirror;
+pub use mirror::{MirrorBackend, Quorum};
+mod retry;
+pub use retry::{Retry, RetryBackend};
+mod throttle;
+pub use throttle::{Throttle, ThrottledBackend};
+mod verified;
+pub use verified::VerifiedBackend;
+mod names;
+pub use names::{Nested, ObjectNames, Prefixed};
+mod uri;
+pub use uri::from_uri;
+#[cfg(feature = "cloud")]
+mod http;
+#[cfg(feature = "cloud")]
+pub use http::Curl;
+#[cfg(feature = "gateway")]
+mod remote;
+#[cfg(feature = "gateway")]
+pub use remote::Remote;
+#[cfg(feature = "cloud")]
+mod azure;
+#[cfg(feature = "cloud")]
+pub use azure::{AzureAuth, AzureBackend};
+#[cfg(feature = "cloud")]
+mod b2;
+#[cfg(feature = "cloud")]
+pub use b2::B2Backend;
+#[cfg(feature = "cloud")]
+mod gcs;
+#[cfg(feature = "cloud")]
+pub use gcs::{GcsBackend, ServiceAccount};
+#[cfg(feature = "cloud")]
+mod s3;
+#[cfg(feature = "cloud")]
+pub use s3::{Credentials, S3Backend};
+#[cfg(feature = "cloud")]
+mod webdav;
+#[cfg(feature = "cloud")]
+pub use webdav::{WebDavAuth, WebDavBackend};
+#[cfg(feature = "helper")]
+mod helper;
+#[cfg(feature = "helper")]
+pub use helper::HelperBackend;
+#[cfg(feature = "sftp")]
+mod sftp;
+#[cfg(feature = "sftp")]
+pub use sftp::SftpBackend;
+
 #[derive(Error, Debug)]
 pub enum BackendError {
     #[error("IO error: {source}")]
@@ -21,106 +83,278 @@ pub enum BackendError {
     NoObjectFound,
     #[error("Can't create object")]
     Create,
+    #[error("Backend is read-only")]
+    ReadOnly,
+    #[error("Invalid backend URI: {0}")]
+    Uri(String),
+    #[error("Object is in archival storage, and has to be retrieved first")]
+    Archived,
+    #[error("Backend can't {0}")]
+    Unsupported(&'static str),
 }
 
 pub type Result<T> = std::result::Result<T, BackendError>;
 
-pub trait Backend: Send + Sync {
-    fn write_object(&self, object: &WriteObject) -> Result<()>;
-    fn read_object(&self, id: &ObjectId) -> Result<Arc<ReadObject>>;
+/// Whether an object in archival storage can be read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Retrieval {
+    Ready,
+    /// It's being retrieved, which can take hours
+    Pending,
 }
 
-pub struct MmappedFile {
-    pub _file: fs::File,
-    pub mmap: Mmap,
+/// What a backend can do, as found by `Backend::probe`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Objects can be written
+    pub writable: bool,
+    /// The storage can read parts of an object
+    pub range_reads: bool,
+    /// The storage can list the objects in it
+    pub listing: bool,
 }
 
-impl AsRef<[u8]> for MmappedFile {
-    fn as_ref(&self) -> &[u8] {
-        self.mmap.as_ref()
+impl BackendError {
+    /// Whether trying again may succeed, like after a dropped
+    /// connection or a timeout.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            BackendError::Io { source } => !matches!(
+                source.kind(),
+                io::ErrorKind::NotFound
+                    | io::ErrorKind::PermissionDenied
+                    | io::ErrorKind::InvalidInput
+                    | io::ErrorKind::InvalidData
+                    | io::ErrorKind::Unsupported
+                    | io::ErrorKind::AlreadyExists
+            ),
+            _ => false,
+        }
     }
 }
 
-#[derive(Clone)]
-pub struct Directory {
-    target: Arc<PathBuf>,
-    read_lru: Arc<Mutex<LruCache<ObjectId, Arc<ReadObject>>>>,
+pub trait Backend: Send + Sync {
+    fn write_object(&self, object: &WriteObject) -> Result<()>;
+    fn read_object(&self, id: &ObjectId) -> Result<Arc<ReadObject>>;
+
+    /// Write an object of file contents. These are rarely read back,
+    /// so backends with archival storage may put them there, while
+    /// metadata written by `write_object` stays readable.
+    fn write_data_object(&self, object: &WriteObject) -> Result<()> {
+        self.write_object(object)
+    }
+
+    /// Ask for object `id` to be brought back from archival storage,
+    /// if it's there. Calling it again tells whether it's done.
+    fn retrieve_object(&self, _id: &ObjectId) -> Result<Retrieval> {
+        Ok(Retrieval::Ready)
+    }
+
+    /// Read `len` bytes of object `id`, starting at `offset`.
+    ///
+    /// Backends that advertise `range_reads` only transfer those
+    /// bytes, the default reads the whole object.
+    fn read_range(&self, id: &ObjectId, offset: u64, len: usize) -> Result<Vec<u8>> {
+        range_of(self.read_object(id)?.buffer.as_ref(), offset, len)
+    }
+
+    /// Delete object `id`, to collect garbage. Fails with
+    /// `NoObjectFound` if it isn't there.
+    fn delete_object(&self, _id: &ObjectId) -> Result<()> {
+        Err(BackendError::Unsupported("delete objects"))
+    }
+
+    /// The ids of all the objects in the storage, in no particular
+    /// order, for audits. Files and keys that aren't named like
+    /// objects of this backend are left out.
+    fn list_objects(&self) -> Result<Vec<ObjectId>> {
+        Err(BackendError::Unsupported("list objects"))
+    }
+
+    /// What the storage behind the backend supports. Whether it's
+    /// writable is only known from a `probe`.
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::default()
+    }
+
+    /// Check that the backend can be reached with the credentials it
+    /// has, and whether they allow writes, by writing a small probe
+    /// object and reading it back.
+    ///
+    /// This is meant to fail fast before a long backup. The probe
+    /// object is removed where the backend can, and overwritten by
+    /// the next probe otherwise.
+    fn probe(&self) -> Result<Capabilities> {
+        Ok(Capabilities {
+            writable: probe_object(self)?,
+            ..self.capabilities()
+        })
+    }
 }
 
-impl Directory {
-    pub fn new(target: impl AsRef<Path>) -> Result<Directory> {
-        fs::create_dir_all(&target)?;
-        Ok(Directory {
-            target: Arc::new(target.as_ref().into()),
-            read_lru: Arc::new(Mutex::new(LruCache::new(100))),
+/// Bytes `offset..offset + len` of `data`, which has to hold them.
+pub(crate) fn range_of(data: &[u8], offset: u64, len: usize) -> Result<Vec<u8>> {
+    usize::try_from(offset)
+        .ok()
+        .and_then(|start| data.get(start..start.checked_add(len)?))
+        .map(<[u8]>::to_vec)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "range past the end of the object",
+            )
+            .into()
         })
+}
+
+const PROBE: &[u8] = b"zerostash probe";
+
+/// The id of the object written by `Backend::probe`.
+pub(crate) fn probe_id() -> ObjectId {
+    ObjectId::from_bytes([0xff; 32])
+}
+
+/// Write the probe object and read it back, returning whether the
+/// backend is writable. Read-only backends only need to answer.
+pub(crate) fn probe_object<B: Backend + ?Sized>(backend: &B) -> Result<bool> {
+    let object = Object::with_id(probe_id(), BlockBuffer::from(PROBE.to_vec()));
+    let writable = match backend.write_object(&object) {
+        Ok(()) => true,
+        Err(BackendError::ReadOnly) => false,
+        Err(e) => return Err(e),
+    };
+
+    match backend.read_object(&object.id) {
+        Ok(read) if !writable || read.buffer.as_ref() == PROBE => Ok(writable),
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "the probe object was read back changed",
+        )
+        .into()),
+        Err(BackendError::NoObjectFound) if !writable => Ok(false),
+        Err(e) => Err(e),
     }
 }
 
-impl Backend for Directory {
+impl<T: Backend + ?Sized> Backend for Arc<T> {
     fn write_object(&self, object: &WriteObject) -> Result<()> {
-        let size = object.buffer.as_ref().len();
+        (**self).write_object(object)
+    }
 
-        let filename = self.target.join(object.id.to_string());
+    fn read_object(&self, id: &ObjectId) -> Result<Arc<ReadObject>> {
+        (**self).read_object(id)
+    }
 
-        let mut file = fs::OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(filename)?;
+    fn write_data_object(&self, object: &WriteObject) -> Result<()> {
+        (**self).write_data_object(object)
+    }
 
-        file.set_len(size as u64)?;
+    fn retrieve_object(&self, id: &ObjectId) -> Result<Retrieval> {
+        (**self).retrieve_object(id)
+    }
 
-        {
-            let mut mmap = unsafe { MmapOptions::new().len(size).map_mut(&file)? };
-            mmap.copy_from_slice(object.buffer.as_ref());
-        }
+    fn read_range(&self, id: &ObjectId, offset: u64, len: usize) -> Result<Vec<u8>> {
+        (**self).read_range(id, offset, len)
+    }
 
-        file.flush()?;
-        Ok(())
+    fn delete_object(&self, id: &ObjectId) -> Result<()> {
+        (**self).delete_object(id)
     }
 
-    fn read_object(&self, id: &ObjectId) -> Result<Arc<ReadObject>> {
-        let mut lru = self.read_lru.lock().unwrap();
-
-        match lru.get(id) {
-            Some(mmap) => Ok(mmap.clone()),
-            None => {
-                let filename = self.target.join(id.to_string());
-                let file = fs::OpenOptions::new().read(true).open(filename)?;
-                let mmap = unsafe { MmapOptions::new().map(&file)? };
-
-                let obj = Arc::new(Object::with_id(
-                    *id,
-                    ReadBuffer::new(MmappedFile { _file: file, mmap }),
-                ));
-                lru.put(*id, obj.clone());
-
-                Ok(obj)
-            }
-        }
+    fn list_objects(&self) -> Result<Vec<ObjectId>> {
+        (**self).list_objects()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        (**self).capabilities()
+    }
+
+    fn probe(&self) -> Result<Capabilities> {
+        (**self).probe()
     }
 }
 
+/// Keeps objects in memory, for tests and scratch stashes that never
+/// touch the disk.
+///
+/// Clones share the same objects.
 #[derive(Clone, Default)]
-pub struct InMemoryBackend(Arc<Mutex<HashMap<ObjectId, Arc<ReadObject>>>>);
+pub struct MemoryBackend(Arc<Mutex<HashMap<ObjectId, Vec<u8>>>>);
+
+#[deprecated(note = "renamed to `MemoryBackend`")]
+pub type InMemoryBackend = MemoryBackend;
+
+impl MemoryBackend {
+    /// The number of objects stored.
+    pub fn len(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.lock().unwrap().is_empty()
+    }
+
+    pub fn contains(&self, id: &ObjectId) -> bool {
+        self.0.lock().unwrap().contains_key(id)
+    }
+
+    /// The ids of all stored objects, in no particular order.
+    pub fn ids(&self) -> Vec<ObjectId> {
+        self.0.lock().unwrap().keys().copied().collect()
+    }
 
-impl Backend for InMemoryBackend {
+    /// Forget object `id`, returning its contents.
+    pub fn remove(&self, id: &ObjectId) -> Option<Vec<u8>> {
+        self.0.lock().unwrap().remove(id)
+    }
+}
+
+impl Backend for MemoryBackend {
     fn write_object(&self, object: &WriteObject) -> Result<()> {
         self.0
             .lock()
             .unwrap()
-            .insert(object.id, Arc::new(object.into()));
+            .insert(object.id, object.buffer.as_ref().to_vec());
         Ok(())
     }
 
     fn read_object(&self, id: &ObjectId) -> Result<Arc<ReadObject>> {
-        self.0
+        let data = self
+            .0
             .lock()
             .unwrap()
             .get(id)
-            .ok_or_else(|| BackendError::NoObjectFound)
-            .map(Arc::clone)
+            .cloned()
+            .ok_or(BackendError::NoObjectFound)?;
+        Ok(Arc::new(Object::with_id(*id, ReadBuffer::new(data))))
+    }
+
+    fn read_range(&self, id: &ObjectId, offset: u64, len: usize) -> Result<Vec<u8>> {
+        let objects = self.0.lock().unwrap();
+        let data = objects.get(id).ok_or(BackendError::NoObjectFound)?;
+        range_of(data, offset, len)
+    }
+
+    fn delete_object(&self, id: &ObjectId) -> Result<()> {
+        self.remove(id)
+            .map(|_| ())
+            .ok_or(BackendError::NoObjectFound)
+    }
+
+    fn list_objects(&self) -> Result<Vec<ObjectId>> {
+        Ok(self.ids())
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            writable: true,
+            range_reads: true,
+            listing: true,
+        }
+    }
+
+    fn probe(&self) -> Result<Capabilities> {
+        Ok(self.capabilities())
     }
 }
 
@@ -131,6 +365,10 @@ impl NullBackend {
     pub fn len(&self) -> usize {
         *self.0.lock().unwrap()
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 }
 
 impl Backend for NullBackend {
@@ -140,6 +378,65 @@ impl Backend for NullBackend {
     }
 
     fn read_object(&self, _id: &ObjectId) -> Result<Arc<ReadObject>> {
-        unimplemented!();
+        // nothing written is kept
+        Err(BackendError::NoObjectFound)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn memory_backend_keeps_objects() {
+        use super::*;
+        use crate::objects::BlockBuffer;
+
+        let backend = MemoryBackend::default();
+        let mut object = Object::new(BlockBuffer::default());
+        object.set_id(ObjectId::from_bytes([1; 32]));
+        object.buffer.as_mut()[0] = 1;
+
+        backend.clone().write_object(&object).unwrap();
+        assert_eq!(backend.len(), 1);
+        assert!(backend.contains(&object.id));
+
+        let read = backend.read_object(&object.id).unwrap();
+        assert_eq!(read.buffer.as_ref(), object.buffer.as_ref());
+        assert_eq!(backend.read_range(&object.id, 0, 2).unwrap(), [1, 0]);
+        assert!(backend
+            .read_range(&object.id, crate::BLOCK_SIZE as u64 - 1, 2)
+            .is_err());
+
+        assert!(backend.remove(&object.id).is_some());
+        assert!(backend.is_empty());
+        assert!(matches!(
+            backend.read_object(&object.id),
+            Err(BackendError::NoObjectFound)
+        ));
+    }
+
+    #[test]
+    fn probe_finds_read_only_backends() {
+        use super::*;
+
+        struct ReadOnly(io::ErrorKind);
+        impl Backend for ReadOnly {
+            fn write_object(&self, _object: &WriteObject) -> Result<()> {
+                Err(BackendError::ReadOnly)
+            }
+            fn read_object(&self, _id: &ObjectId) -> Result<Arc<ReadObject>> {
+                match self.0 {
+                    io::ErrorKind::NotFound => Err(BackendError::NoObjectFound),
+                    kind => Err(io::Error::from(kind).into()),
+                }
+            }
+        }
+
+        let capabilities = ReadOnly(io::ErrorKind::NotFound).probe().unwrap();
+        assert!(!capabilities.writable);
+        assert!(ReadOnly(io::ErrorKind::ConnectionRefused).probe().is_err());
+
+        let memory = MemoryBackend::default();
+        assert!(Arc::new(memory.clone()).probe().unwrap().writable);
+        assert!(memory.is_empty());
     }
 }
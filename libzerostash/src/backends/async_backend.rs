@@ -0,0 +1,160 @@
+//! Backends for callers that keep many transfers in flight on a
+//! tokio runtime, instead of a thread for each.
+//!
+//! A `Stash` given an async backend with `Stash::set_async_backend`
+//! uploads the data objects of backups as tasks on the runtime,
+//! everything else still goes through its blocking `Backend`.
+//! `SyncAdapter` makes one of the other, and `AsyncAdapter` turns
+//! blocking backends into async ones.
+
+use crate::backends::{Backend, Result};
+use crate::objects::{ObjectId, ReadObject, WriteObject};
+
+use tokio::runtime::Handle;
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// The async counterpart of `Backend`. It's object safe, so
+/// implementations return boxed futures.
+pub trait AsyncBackend: Send + Sync {
+    fn write_object<'a>(&'a self, object: &'a WriteObject) -> BoxFuture<'a, Result<()>>;
+    fn read_object<'a>(&'a self, id: &'a ObjectId) -> BoxFuture<'a, Result<Arc<ReadObject>>>;
+
+    /// Write an object of file contents. See
+    /// `Backend::write_data_object`.
+    fn write_data_object<'a>(&'a self, object: &'a WriteObject) -> BoxFuture<'a, Result<()>> {
+        self.write_object(object)
+    }
+}
+
+impl<T: AsyncBackend + ?Sized> AsyncBackend for Arc<T> {
+    fn write_object<'a>(&'a self, object: &'a WriteObject) -> BoxFuture<'a, Result<()>> {
+        (**self).write_object(object)
+    }
+
+    fn read_object<'a>(&'a self, id: &'a ObjectId) -> BoxFuture<'a, Result<Arc<ReadObject>>> {
+        (**self).read_object(id)
+    }
+
+    fn write_data_object<'a>(&'a self, object: &'a WriteObject) -> BoxFuture<'a, Result<()>> {
+        (**self).write_data_object(object)
+    }
+}
+
+/// Runs the operations of a blocking backend in place on the
+/// runtime, which moves its other tasks to another thread meanwhile.
+///
+/// This takes a thread per operation in flight, like the upload
+/// threads of a stash, and needs a multi-threaded runtime.
+pub struct AsyncAdapter<B>(pub B);
+
+impl<B: Backend> AsyncBackend for AsyncAdapter<B> {
+    fn write_object<'a>(&'a self, object: &'a WriteObject) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move { tokio::task::block_in_place(|| self.0.write_object(object)) })
+    }
+
+    fn read_object<'a>(&'a self, id: &'a ObjectId) -> BoxFuture<'a, Result<Arc<ReadObject>>> {
+        Box::pin(async move { tokio::task::block_in_place(|| self.0.read_object(id)) })
+    }
+
+    fn write_data_object<'a>(&'a self, object: &'a WriteObject) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move { tokio::task::block_in_place(|| self.0.write_data_object(object)) })
+    }
+}
+
+/// Blocks on the futures of an async backend, run on `runtime`, to
+/// use it where a `Backend` is needed.
+///
+/// Its operations must not be called from a thread of the runtime,
+/// which would be blocked with them.
+pub struct SyncAdapter<A> {
+    inner: A,
+    runtime: Handle,
+}
+
+impl<A: AsyncBackend> SyncAdapter<A> {
+    pub fn new(inner: A, runtime: Handle) -> SyncAdapter<A> {
+        SyncAdapter { inner, runtime }
+    }
+}
+
+impl<A: AsyncBackend> Backend for SyncAdapter<A> {
+    fn write_object(&self, object: &WriteObject) -> Result<()> {
+        self.runtime.block_on(self.inner.write_object(object))
+    }
+
+    fn read_object(&self, id: &ObjectId) -> Result<Arc<ReadObject>> {
+        self.runtime.block_on(self.inner.read_object(id))
+    }
+
+    fn write_data_object(&self, object: &WriteObject) -> Result<()> {
+        self.runtime.block_on(self.inner.write_data_object(object))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn many_objects_are_in_flight() {
+        use super::*;
+        use crate::backends::{BackendError, MemoryBackend};
+        use crate::objects::{BlockBuffer, Object};
+        use std::sync::Barrier;
+
+        // writes only finish once 8 of them run at the same time
+        struct Gathering {
+            inner: MemoryBackend,
+            barrier: Barrier,
+        }
+        impl Backend for Gathering {
+            fn write_object(&self, object: &WriteObject) -> Result<()> {
+                self.barrier.wait();
+                self.inner.write_object(object)
+            }
+            fn read_object(&self, id: &ObjectId) -> Result<Arc<ReadObject>> {
+                self.inner.read_object(id)
+            }
+        }
+
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .build()
+            .unwrap();
+        let memory = MemoryBackend::default();
+        let backend = Arc::new(AsyncAdapter(Gathering {
+            inner: memory.clone(),
+            barrier: Barrier::new(8),
+        }));
+
+        let objects = (0..8u8)
+            .map(|n| {
+                let mut object = Object::new(BlockBuffer::default());
+                object.set_id(ObjectId::from_bytes([n; 32]));
+                object
+            })
+            .collect::<Vec<_>>();
+        let writes = objects
+            .iter()
+            .cloned()
+            .map(|object| {
+                let backend = backend.clone();
+                runtime.spawn(async move { backend.write_object(&object).await })
+            })
+            .collect::<Vec<_>>();
+        for write in writes {
+            runtime.block_on(write).unwrap().unwrap();
+        }
+        assert_eq!(memory.len(), 8);
+
+        let sync = SyncAdapter::new(backend, runtime.handle().clone());
+        assert!(sync.read_object(&objects[3].id).is_ok());
+        assert!(matches!(
+            sync.read_object(&ObjectId::from_bytes([9; 32])),
+            Err(BackendError::NoObjectFound)
+        ));
+    }
+}
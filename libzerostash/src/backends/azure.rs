@@ -0,0 +1,452 @@
+use crate::backends::http::{byte_range, uri_encode, Curl, Request, Response};
+use crate::backends::{Backend, BackendError, Capabilities, ObjectNames, Prefixed, Result};
+use crate::objects::{Object, ObjectId, ReadBuffer, ReadObject, WriteObject};
+use crate::time::http_date;
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use ring::hmac;
+use secrecy::{ExposeSecret, Secret};
+
+use std::io;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const VERSION: &str = "2020-10-02";
+
+/// How requests to Azure are authorized.
+pub enum AzureAuth {
+    /// The base64 access key of the storage account
+    AccountKey(Secret<String>),
+    /// A shared access signature, the query string granting access
+    Sas(Secret<String>),
+}
+
+impl AzureAuth {
+    pub fn account_key(key: impl Into<String>) -> AzureAuth {
+        AzureAuth::AccountKey(Secret::new(key.into()))
+    }
+
+    pub fn sas(token: impl Into<String>) -> AzureAuth {
+        AzureAuth::Sas(Secret::new(token.into()))
+    }
+
+    /// The account key in `AZURE_STORAGE_KEY`, or else the SAS token
+    /// in `AZURE_STORAGE_SAS_TOKEN`.
+    pub fn from_env() -> Option<AzureAuth> {
+        match (
+            std::env::var("AZURE_STORAGE_KEY"),
+            std::env::var("AZURE_STORAGE_SAS_TOKEN"),
+        ) {
+            (Ok(key), _) => Some(AzureAuth::account_key(key)),
+            (_, Ok(sas)) => Some(AzureAuth::sas(sas)),
+            _ => None,
+        }
+    }
+}
+
+/// Stores objects as block blobs in an Azure Storage container.
+///
+/// Objects larger than the block size are uploaded with Put Block,
+/// so a failure only needs the block it happened in sent again, and
+/// committed with Put Block List.
+#[derive(Clone)]
+pub struct AzureBackend {
+    curl: Curl,
+    account: String,
+    container: String,
+    auth: Arc<AzureAuth>,
+    endpoint: Option<String>,
+    names: Arc<dyn ObjectNames>,
+    block_size: usize,
+}
+
+impl AzureBackend {
+    pub fn new(
+        account: impl Into<String>,
+        container: impl Into<String>,
+        auth: AzureAuth,
+    ) -> AzureBackend {
+        AzureBackend {
+            curl: Curl::default(),
+            account: account.into(),
+            container: container.into(),
+            auth: Arc::new(auth),
+            endpoint: None,
+            names: Arc::new(Prefixed::default()),
+            block_size: 1024 * 1024,
+        }
+    }
+
+    /// The blob service of the account, if it's not
+    /// `https://<account>.blob.core.windows.net`, like
+    /// `http://127.0.0.1:10000/devstoreaccount1` for Azurite.
+    pub fn endpoint(mut self, endpoint: impl Into<String>) -> AzureBackend {
+        self.endpoint = Some(endpoint.into().trim_end_matches('/').to_string());
+        self
+    }
+
+    /// Store the objects under `prefix` in the container, like `home/`.
+    pub fn prefix(self, prefix: impl Into<String>) -> AzureBackend {
+        self.names(Prefixed(prefix.into()))
+    }
+
+    /// Name the objects in the container with `names`.
+    pub fn names(mut self, names: impl ObjectNames + 'static) -> AzureBackend {
+        self.names = Arc::new(names);
+        self
+    }
+
+    /// Upload objects larger than `size` in blocks of `size` bytes,
+    /// 1 MiB by default.
+    pub fn block_size(mut self, size: usize) -> AzureBackend {
+        self.block_size = size.max(1);
+        self
+    }
+
+    /// The `curl` to send requests with.
+    pub fn curl(mut self, curl: Curl) -> AzureBackend {
+        self.curl = curl;
+        self
+    }
+
+    fn send(
+        &self,
+        method: &'static str,
+        id: &ObjectId,
+        query: &[(&str, String)],
+        headers: &[(&str, &str)],
+        body: Option<&[u8]>,
+    ) -> io::Result<Response> {
+        let endpoint = match &self.endpoint {
+            Some(endpoint) => endpoint.clone(),
+            None => format!("https://{}.blob.core.windows.net", self.account),
+        };
+        let path = format!(
+            "/{}/{}",
+            self.container,
+            uri_encode(&self.names.name(id), true)
+        );
+
+        let query_string = query
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, uri_encode(v, false)))
+            .collect::<Vec<_>>();
+        let mut url = format!("{}{}", endpoint, path);
+        if !query_string.is_empty() {
+            url = format!("{}?{}", url, query_string.join("&"));
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let mut ms_headers = headers
+            .iter()
+            .map(|(n, v)| (n.to_string(), v.to_string()))
+            .collect::<Vec<_>>();
+        ms_headers.push(("x-ms-date".into(), http_date(now)));
+        ms_headers.push(("x-ms-version".into(), VERSION.into()));
+
+        let content_type = "application/octet-stream";
+        let mut request = ms_headers
+            .iter()
+            .fold(Request::new(method, url), |r, (n, v)| r.header(n, v));
+        if let Some(body) = body {
+            request = request.header("Content-Type", content_type).body(body);
+        }
+        // the token is a credential, which is kept out of the URL
+        // that's logged
+        if let AzureAuth::Sas(token) = &*self.auth {
+            request = request.secret_query(token.expose_secret().trim_start_matches('?'));
+        }
+
+        if let AzureAuth::AccountKey(key) = &*self.auth {
+            let key = BASE64
+                .decode(key.expose_secret())
+                .map_err(|_| io::Error::other("the account key isn't base64"))?;
+            let resource = format!(
+                "/{}{}{}",
+                self.account,
+                endpoint
+                    .split("://")
+                    .nth(1)
+                    .and_then(|rest| rest.find('/').map(|i| &rest[i..]))
+                    .unwrap_or_default(),
+                path
+            );
+            let signature = sign(
+                &key,
+                method,
+                &resource,
+                query,
+                &ms_headers,
+                body.map(|b| b.len()).unwrap_or(0),
+                body.map(|_| content_type).unwrap_or_default(),
+            );
+            request = request.header(
+                "Authorization",
+                format!("SharedKey {}:{}", self.account, signature),
+            );
+        }
+
+        self.curl.send(&request)
+    }
+
+    fn get(&self, id: &ObjectId, range: Option<String>) -> Result<Response> {
+        let headers = match &range {
+            Some(range) => vec![("x-ms-range", range.as_str())],
+            None => vec![],
+        };
+        let response = self.send("GET", id, &[], &headers, None)?;
+
+        match response.status {
+            200 | 206 => Ok(response),
+            404 => Err(BackendError::NoObjectFound),
+            _ => Err(response.error("Get Blob").into()),
+        }
+    }
+
+    fn put_blocks(&self, id: &ObjectId, data: &[u8]) -> Result<()> {
+        let mut list = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?><BlockList>");
+
+        for (i, block) in data.chunks(self.block_size).enumerate() {
+            // ids of a blob have to be the same length
+            let block_id = BASE64.encode(format!("{:08}", i));
+            let response = self.send(
+                "PUT",
+                id,
+                &[("comp", "block".into()), ("blockid", block_id.clone())],
+                &[],
+                Some(block),
+            )?;
+            if !response.is_success() {
+                return Err(response.error("Put Block").into());
+            }
+
+            list.push_str(&format!("<Latest>{}</Latest>", block_id));
+        }
+        list.push_str("</BlockList>");
+
+        let response = self.send(
+            "PUT",
+            id,
+            &[("comp", "blocklist".into())],
+            &[],
+            Some(list.as_bytes()),
+        )?;
+        if !response.is_success() {
+            return Err(response.error("Put Block List").into());
+        }
+        Ok(())
+    }
+}
+
+impl Backend for AzureBackend {
+    fn write_object(&self, object: &WriteObject) -> Result<()> {
+        let data = object.buffer.as_ref();
+        if data.len() > self.block_size {
+            return self.put_blocks(&object.id, data);
+        }
+
+        let response = self.send(
+            "PUT",
+            &object.id,
+            &[],
+            &[("x-ms-blob-type", "BlockBlob")],
+            Some(data),
+        )?;
+        if !response.is_success() {
+            return Err(response.error("Put Blob").into());
+        }
+        Ok(())
+    }
+
+    fn read_object(&self, id: &ObjectId) -> Result<Arc<ReadObject>> {
+        let response = self.get(id, None)?;
+        Ok(Arc::new(Object::with_id(
+            *id,
+            ReadBuffer::new(response.body),
+        )))
+    }
+
+    fn read_range(&self, id: &ObjectId, offset: u64, len: usize) -> Result<Vec<u8>> {
+        self.get(id, Some(byte_range(offset, len)))?
+            .into_range(offset, len)
+    }
+
+    fn delete_object(&self, id: &ObjectId) -> Result<()> {
+        let response = self.send("DELETE", id, &[], &[], None)?;
+        match response.status {
+            404 => Err(BackendError::NoObjectFound),
+            _ if response.is_success() => Ok(()),
+            _ => Err(response.error("Delete Blob").into()),
+        }
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            writable: true,
+            range_reads: true,
+            listing: true,
+        }
+    }
+}
+
+/// The Shared Key signature of a request for `resource`, with the
+/// `x-ms-` headers in `headers`.
+fn sign(
+    key: &[u8],
+    method: &str,
+    resource: &str,
+    query: &[(&str, String)],
+    headers: &[(String, String)],
+    content_length: usize,
+    content_type: &str,
+) -> String {
+    let mut headers = headers
+        .iter()
+        .map(|(n, v)| (n.to_ascii_lowercase(), v.trim()))
+        .collect::<Vec<_>>();
+    headers.sort();
+    let mut query = query.to_vec();
+    query.sort();
+
+    let length = match content_length {
+        0 => String::new(),
+        len => len.to_string(),
+    };
+    // the standard headers that aren't sent are empty
+    let mut string_to_sign = format!(
+        "{}\n\n\n{}\n\n{}\n\n\n\n\n\n\n",
+        method, length, content_type
+    );
+    for (name, value) in headers {
+        string_to_sign.push_str(&format!("{}:{}\n", name, value));
+    }
+    string_to_sign.push_str(resource);
+    for (name, value) in query {
+        string_to_sign.push_str(&format!("\n{}:{}", name.to_ascii_lowercase(), value));
+    }
+
+    let key = hmac::Key::new(hmac::HMAC_SHA256, key);
+    BASE64.encode(hmac::sign(&key, string_to_sign.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    const KEY: &str =
+        "Eby8vdM02xNOcqFlqUwJPLlmEtlCDXJ1OUzFT50uSRZ6IFsuFq2UVErCz4I6tq/K1SZFPTOtr/KBHBeksoGMGw==";
+
+    #[test]
+    fn signs_with_the_account_key() {
+        use super::*;
+
+        let key = BASE64.decode(KEY).unwrap();
+        let headers = |extra: &[(&str, &str)]| {
+            let mut headers = extra
+                .iter()
+                .map(|(n, v)| (n.to_string(), v.to_string()))
+                .collect::<Vec<_>>();
+            headers.push(("x-ms-version".into(), VERSION.into()));
+            headers.push(("x-ms-date".into(), http_date(1_435_361_952)));
+            headers
+        };
+
+        assert_eq!(
+            sign(
+                &key,
+                "PUT",
+                "/myaccount/mycontainer/myblob",
+                &[],
+                &headers(&[("x-ms-blob-type", "BlockBlob")]),
+                11,
+                "application/octet-stream"
+            ),
+            "BXCk4LfmccdlEWQrjW6gXbeLdmw9GMubEIEJ6dr2d/o="
+        );
+        assert_eq!(
+            sign(
+                &key,
+                "PUT",
+                "/myaccount/mycontainer/myblob",
+                &[("comp", "block".into()), ("blockid", "MDAwMDAwMDA=".into())],
+                &headers(&[]),
+                5,
+                "application/octet-stream"
+            ),
+            "lCJNHX/uppFy0QzEI/fy/ZKiATj43OHjwSjM6fXF/GI="
+        );
+    }
+
+    #[test]
+    fn objects_are_uploaded_in_blocks() {
+        use super::*;
+        use crate::backends::http::test_server;
+        use crate::backends::Retry;
+        use crate::objects::BlockBuffer;
+        use std::collections::HashMap;
+        use std::sync::Mutex;
+
+        let blobs = Arc::new(Mutex::new(HashMap::<String, Vec<u8>>::new()));
+        let blocks = Arc::new(Mutex::new(HashMap::<String, Vec<u8>>::new()));
+        let store = blobs.clone();
+        let url = test_server::start(move |request| {
+            let (path, query) = request.path.split_once('?').unwrap();
+            let mut blobs = store.lock().unwrap();
+            let mut blocks = blocks.lock().unwrap();
+            assert!(query.ends_with("&sig=s") || query == "sig=s");
+            assert_eq!(request.header("x-ms-version"), Some(VERSION));
+
+            match (request.method.as_str(), query) {
+                ("PUT", "sig=s") => {
+                    assert_eq!(request.header("x-ms-blob-type"), Some("BlockBlob"));
+                    blobs.insert(path.into(), request.body);
+                    (201, vec![], vec![])
+                }
+                ("PUT", "comp=blocklist&sig=s") => {
+                    let list = String::from_utf8(request.body).unwrap();
+                    let mut blob = vec![];
+                    for id in list.split("<Latest>").skip(1) {
+                        let id = &id[..id.find('<').unwrap()];
+                        blob.extend_from_slice(&blocks[&format!("{}{}", path, id)]);
+                    }
+                    blobs.insert(path.into(), blob);
+                    (201, vec![], vec![])
+                }
+                ("PUT", query) => {
+                    let id = query.split("blockid=").nth(1).unwrap().split('&').next();
+                    let id = id.unwrap().replace("%3D", "=");
+                    blocks.insert(format!("{}{}", path, id), request.body);
+                    (201, vec![], vec![])
+                }
+                ("GET", _) => match blobs.get(path) {
+                    Some(blob) => (200, vec![], blob.clone()),
+                    None => (404, vec![], vec![]),
+                },
+                _ => (400, vec![], vec![]),
+            }
+        });
+
+        let azure = AzureBackend::new("account", "container", AzureAuth::sas("?sig=s"))
+            .endpoint(format!("{}/account", url))
+            .curl(Curl::default().retry(Retry::never()));
+
+        let mut object = Object::new(BlockBuffer::default());
+        object.set_id(ObjectId::from_bytes([1; 32]));
+        object.buffer.as_mut()[crate::BLOCK_SIZE - 1] = 1;
+        azure.write_object(&object).unwrap();
+        let read = azure.read_object(&object.id).unwrap();
+        assert_eq!(read.buffer.as_ref(), object.buffer.as_ref());
+
+        let azure = azure.block_size(crate::BLOCK_SIZE);
+        object.set_id(ObjectId::from_bytes([2; 32]));
+        azure.write_object(&object).unwrap();
+        let read = azure.read_object(&object.id).unwrap();
+        assert_eq!(read.buffer.as_ref(), object.buffer.as_ref());
+
+        assert!(matches!(
+            azure.read_object(&ObjectId::from_bytes([9; 32])),
+            Err(BackendError::NoObjectFound)
+        ));
+    }
+}
@@ -0,0 +1,497 @@
+use crate::backends::http::{byte_range, hex, uri_encode, Curl, Request, Response};
+use crate::backends::{Backend, BackendError, Capabilities, ObjectNames, Prefixed, Result};
+use crate::objects::{Object, ObjectId, ReadBuffer, ReadObject, WriteObject};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use ring::digest;
+use secrecy::{ExposeSecret, Secret};
+use serde::de::DeserializeOwned;
+use serde_json::json;
+
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Authorization {
+    account_id: String,
+    authorization_token: String,
+    api_url: String,
+    download_url: String,
+    recommended_part_size: usize,
+}
+
+#[derive(Deserialize)]
+struct Buckets {
+    buckets: Vec<Bucket>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Bucket {
+    bucket_id: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UploadUrl {
+    upload_url: String,
+    authorization_token: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LargeFile {
+    file_id: String,
+}
+
+struct Session {
+    auth: Authorization,
+    bucket_id: String,
+}
+
+#[derive(Default)]
+struct State {
+    session: Mutex<Option<Arc<Session>>>,
+    /// Upload URLs not in use. Each of them takes one upload at a time.
+    uploads: Mutex<Vec<UploadUrl>>,
+}
+
+/// Stores objects in a Backblaze B2 bucket, through the native API.
+///
+/// Uploads that fail are retried with a new upload URL, as B2 asks
+/// clients to. Objects larger than the part size are uploaded as
+/// large files.
+#[derive(Clone)]
+pub struct B2Backend {
+    curl: Curl,
+    api_url: String,
+    key_id: String,
+    application_key: Arc<Secret<String>>,
+    bucket: String,
+    names: Arc<dyn ObjectNames>,
+    part_size: Option<usize>,
+    state: Arc<State>,
+}
+
+impl B2Backend {
+    /// Objects in `bucket`, with an application key that can access it.
+    pub fn new(
+        bucket: impl Into<String>,
+        key_id: impl Into<String>,
+        application_key: impl Into<String>,
+    ) -> B2Backend {
+        B2Backend {
+            curl: Curl::default(),
+            api_url: "https://api.backblazeb2.com".into(),
+            key_id: key_id.into(),
+            application_key: Arc::new(Secret::new(application_key.into())),
+            bucket: bucket.into(),
+            names: Arc::new(Prefixed::default()),
+            part_size: None,
+            state: Arc::default(),
+        }
+    }
+
+    /// Store the objects under `prefix` in the bucket, like `home/`.
+    pub fn prefix(self, prefix: impl Into<String>) -> B2Backend {
+        self.names(Prefixed(prefix.into()))
+    }
+
+    /// Name the objects in the bucket with `names`.
+    pub fn names(mut self, names: impl ObjectNames + 'static) -> B2Backend {
+        self.names = Arc::new(names);
+        self
+    }
+
+    /// Upload objects larger than `size` as large files, in parts of
+    /// `size` bytes. The part size recommended by B2 is used if unset.
+    pub fn part_size(mut self, size: usize) -> B2Backend {
+        self.part_size = Some(size);
+        self
+    }
+
+    /// The `curl` to send requests with.
+    pub fn curl(mut self, curl: Curl) -> B2Backend {
+        self.curl = curl;
+        self
+    }
+
+    /// Where to authorize the account, for testing.
+    #[cfg(test)]
+    fn api_url(mut self, url: impl Into<String>) -> B2Backend {
+        self.api_url = url.into();
+        self
+    }
+
+    fn session(&self) -> Result<Arc<Session>> {
+        let mut session = self.state.session.lock().unwrap();
+        if let Some(session) = session.as_ref() {
+            return Ok(session.clone());
+        }
+
+        let credentials = format!("{}:{}", self.key_id, self.application_key.expose_secret());
+        let request = Request::new(
+            "GET",
+            format!("{}/b2api/v2/b2_authorize_account", self.api_url),
+        )
+        .header(
+            "Authorization",
+            format!("Basic {}", BASE64.encode(credentials)),
+        );
+        let auth: Authorization = json_response(self.curl.send(&request)?, "b2_authorize_account")?;
+
+        let body = json!({ "accountId": auth.account_id, "bucketName": self.bucket }).to_string();
+        let request = Request::new("POST", format!("{}/b2api/v2/b2_list_buckets", auth.api_url))
+            .header("Authorization", auth.authorization_token.clone())
+            .body(body.as_bytes());
+        let buckets: Buckets = json_response(self.curl.send(&request)?, "b2_list_buckets")?;
+        let bucket_id = match buckets.buckets.into_iter().next() {
+            Some(bucket) => bucket.bucket_id,
+            None => return Err(io::Error::other(format!("no bucket `{}`", self.bucket)).into()),
+        };
+
+        let new = Arc::new(Session { auth, bucket_id });
+        *session = Some(new.clone());
+        Ok(new)
+    }
+
+    /// Forget the session after its token expired.
+    fn reauthorize(&self) {
+        *self.state.session.lock().unwrap() = None;
+        self.state.uploads.lock().unwrap().clear();
+    }
+
+    /// Call `operation` of the API with the JSON `body`, authorizing
+    /// again once if the token expired.
+    fn call<T: DeserializeOwned>(
+        &self,
+        operation: &str,
+        body: impl Fn(&Session) -> serde_json::Value,
+    ) -> Result<T> {
+        for _ in 0..2 {
+            let session = self.session()?;
+            let body = body(&session).to_string();
+            let request = Request::new(
+                "POST",
+                format!("{}/b2api/v2/{}", session.auth.api_url, operation),
+            )
+            .header("Authorization", session.auth.authorization_token.clone())
+            .header("Content-Type", "application/json")
+            .body(body.as_bytes());
+
+            let response = self.curl.send(&request)?;
+            if response.status == 401 {
+                self.reauthorize();
+                continue;
+            }
+            return json_response(response, operation);
+        }
+
+        Err(io::Error::other(format!("{} is not authorized", operation)).into())
+    }
+
+    /// Upload `data` to URLs from `target`, which is asked for a new
+    /// one after every failure.
+    fn upload(
+        &self,
+        mut target: impl FnMut() -> Result<UploadUrl>,
+        headers: &[(&str, String)],
+        data: &[u8],
+    ) -> Result<UploadUrl> {
+        let policy = self.curl.policy();
+        let mut retry = 0;
+
+        loop {
+            let url = target()?;
+            let request = headers
+                .iter()
+                .fold(Request::new("POST", url.upload_url.clone()), |r, (n, v)| {
+                    r.header(*n, v.clone())
+                })
+                .header("Authorization", url.authorization_token.clone())
+                .header("X-Bz-Content-Sha1", sha1(data))
+                .body(data);
+
+            let error = match self.curl.send_once(&request) {
+                Ok(response) if response.is_success() => return Ok(url),
+                Ok(response)
+                    if matches!(response.status, 401 | 408 | 429) || response.status >= 500 =>
+                {
+                    response.error("upload")
+                }
+                Ok(response) => return Err(response.error("upload").into()),
+                Err(e) => e,
+            };
+
+            retry += 1;
+            if retry >= policy.attempts {
+                return Err(error.into());
+            }
+            debug!("getting a new upload URL, after {}", error);
+            thread::sleep(policy.delay(retry));
+        }
+    }
+
+    fn get(&self, id: &ObjectId, range: Option<String>) -> Result<Response> {
+        let name = self.names.name(id);
+
+        for _ in 0..2 {
+            let session = self.session()?;
+            let mut request = Request::new(
+                "GET",
+                format!(
+                    "{}/file/{}/{}",
+                    session.auth.download_url,
+                    uri_encode(&self.bucket, false),
+                    uri_encode(&name, true)
+                ),
+            )
+            .header("Authorization", session.auth.authorization_token.clone());
+            if let Some(range) = &range {
+                request = request.header("Range", range.clone());
+            }
+
+            let response = self.curl.send(&request)?;
+            return match response.status {
+                200 | 206 => Ok(response),
+                401 => {
+                    self.reauthorize();
+                    continue;
+                }
+                404 => Err(BackendError::NoObjectFound),
+                _ => Err(response.error("download").into()),
+            };
+        }
+
+        Err(io::Error::other("download is not authorized").into())
+    }
+
+    fn upload_file(&self, name: &str, data: &[u8]) -> Result<()> {
+        let headers = [
+            ("X-Bz-File-Name", uri_encode(name, true)),
+            ("Content-Type", "b2/x-auto".into()),
+        ];
+        let target = || match self.state.uploads.lock().unwrap().pop() {
+            Some(url) => Ok(url),
+            None => self.call("b2_get_upload_url", |s| json!({ "bucketId": s.bucket_id })),
+        };
+
+        let url = self.upload(target, &headers, data)?;
+        self.state.uploads.lock().unwrap().push(url);
+        Ok(())
+    }
+
+    fn upload_large_file(&self, name: &str, data: &[u8], part_size: usize) -> Result<()> {
+        let file: LargeFile = self.call("b2_start_large_file", |s| {
+            json!({
+                "bucketId": s.bucket_id,
+                "fileName": name,
+                "contentType": "application/octet-stream",
+            })
+        })?;
+
+        let result = self.upload_parts(&file.file_id, data, part_size);
+        if result.is_err() {
+            // unfinished files are billed
+            let _ = self.call::<serde_json::Value>(
+                "b2_cancel_large_file",
+                |_| json!({ "fileId": file.file_id }),
+            );
+        }
+        result
+    }
+
+    fn upload_parts(&self, file_id: &str, data: &[u8], part_size: usize) -> Result<()> {
+        let mut url = None;
+        let mut hashes = vec![];
+
+        for (i, part) in data.chunks(part_size).enumerate() {
+            let headers = [("X-Bz-Part-Number", (i + 1).to_string())];
+            let target = || match url.take() {
+                Some(url) => Ok(url),
+                None => self.call("b2_get_upload_part_url", |_| json!({ "fileId": file_id })),
+            };
+
+            url = Some(self.upload(target, &headers, part)?);
+            hashes.push(sha1(part));
+        }
+
+        self.call::<serde_json::Value>(
+            "b2_finish_large_file",
+            |_| json!({ "fileId": file_id, "partSha1Array": hashes }),
+        )?;
+        Ok(())
+    }
+}
+
+impl Backend for B2Backend {
+    fn write_object(&self, object: &WriteObject) -> Result<()> {
+        let name = self.names.name(&object.id);
+        let data = object.buffer.as_ref();
+
+        let part_size = match self.part_size {
+            Some(size) => size,
+            None => self.session()?.auth.recommended_part_size,
+        };
+        if part_size > 0 && data.len() > part_size {
+            self.upload_large_file(&name, data, part_size)
+        } else {
+            self.upload_file(&name, data)
+        }
+    }
+
+    fn read_object(&self, id: &ObjectId) -> Result<Arc<ReadObject>> {
+        let response = self.get(id, None)?;
+        Ok(Arc::new(Object::with_id(
+            *id,
+            ReadBuffer::new(response.body),
+        )))
+    }
+
+    fn read_range(&self, id: &ObjectId, offset: u64, len: usize) -> Result<Vec<u8>> {
+        self.get(id, Some(byte_range(offset, len)))?
+            .into_range(offset, len)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            writable: true,
+            range_reads: true,
+            listing: true,
+        }
+    }
+}
+
+fn json_response<T: DeserializeOwned>(response: Response, operation: &str) -> Result<T> {
+    if !response.is_success() {
+        return Err(response.error(operation).into());
+    }
+
+    serde_json::from_slice(&response.body).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} returned invalid JSON: {}", operation, e),
+        )
+        .into()
+    })
+}
+
+fn sha1(data: &[u8]) -> String {
+    hex(digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, data).as_ref())
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn uploads_are_retried_with_new_urls() {
+        use super::*;
+        use crate::backends::http::test_server;
+        use crate::backends::Retry;
+        use crate::objects::BlockBuffer;
+        use std::collections::HashMap;
+        use std::time::Duration;
+
+        #[derive(Default)]
+        struct Bucket {
+            url: String,
+            files: HashMap<String, Vec<u8>>,
+            large: String,
+            parts: Vec<Vec<u8>>,
+            uploads: usize,
+        }
+
+        let bucket = Arc::new(Mutex::new(Bucket::default()));
+        let server = bucket.clone();
+        let url = test_server::start(move |request| {
+            let mut bucket = server.lock().unwrap();
+            let url = bucket.url.clone();
+            let body = |value: serde_json::Value| (200, vec![], value.to_string().into_bytes());
+            if let Some(hash) = request.header("x-bz-content-sha1") {
+                assert_eq!(hash, sha1(&request.body));
+            }
+
+            match request.path.as_str() {
+                "/b2api/v2/b2_authorize_account" => {
+                    assert_eq!(request.header("authorization"), Some("Basic aWQ6a2V5"));
+                    body(json!({
+                        "accountId": "a",
+                        "authorizationToken": "t",
+                        "apiUrl": url,
+                        "downloadUrl": url,
+                        "recommendedPartSize": 100_000_000,
+                    }))
+                }
+                "/b2api/v2/b2_list_buckets" => {
+                    body(json!({ "buckets": [{ "bucketId": "b", "bucketName": "bucket" }] }))
+                }
+                "/b2api/v2/b2_get_upload_url" => {
+                    bucket.uploads += 1;
+                    body(json!({ "uploadUrl": url + "/upload", "authorizationToken": "u" }))
+                }
+                "/upload" if bucket.uploads == 1 => (503, vec![], vec![]),
+                "/upload" => {
+                    let name = request.header("x-bz-file-name").unwrap().to_string();
+                    bucket.files.insert(name, request.body);
+                    body(json!({}))
+                }
+                "/b2api/v2/b2_start_large_file" => {
+                    let start: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+                    bucket.large = start["fileName"].as_str().unwrap().into();
+                    body(json!({ "fileId": "f" }))
+                }
+                "/b2api/v2/b2_get_upload_part_url" => {
+                    body(json!({ "uploadUrl": url + "/part", "authorizationToken": "p" }))
+                }
+                "/part" => {
+                    bucket.parts.push(request.body);
+                    body(json!({}))
+                }
+                "/b2api/v2/b2_finish_large_file" => {
+                    let finish: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+                    assert_eq!(finish["partSha1Array"].as_array().unwrap().len(), 2);
+                    let (name, data) = (bucket.large.clone(), bucket.parts.concat());
+                    bucket.files.insert(name, data);
+                    body(json!({}))
+                }
+                path => match bucket.files.get(path.trim_start_matches("/file/bucket/")) {
+                    Some(data) => (200, vec![], data.clone()),
+                    None => (404, vec![], vec![]),
+                },
+            }
+        });
+        bucket.lock().unwrap().url = url.clone();
+
+        let retry = Retry {
+            backoff: Duration::from_millis(1),
+            ..Retry::default()
+        };
+        let b2 = B2Backend::new("bucket", "id", "key")
+            .api_url(url)
+            .curl(Curl::default().retry(retry));
+
+        let mut object = Object::new(BlockBuffer::default());
+        object.set_id(ObjectId::from_bytes([1; 32]));
+        object.buffer.as_mut()[0] = 1;
+        b2.write_object(&object).unwrap();
+        b2.write_object(&object).unwrap();
+        // one URL failed, the other one is reused
+        assert_eq!(bucket.lock().unwrap().uploads, 2);
+
+        let read = b2.read_object(&object.id).unwrap();
+        assert_eq!(read.buffer.as_ref(), object.buffer.as_ref());
+        assert!(matches!(
+            b2.read_object(&ObjectId::from_bytes([9; 32])),
+            Err(BackendError::NoObjectFound)
+        ));
+
+        let b2 = b2.part_size(crate::BLOCK_SIZE / 2);
+        object.set_id(ObjectId::from_bytes([2; 32]));
+        object.buffer.as_mut()[crate::BLOCK_SIZE - 1] = 2;
+        b2.write_object(&object).unwrap();
+        assert_eq!(bucket.lock().unwrap().parts.len(), 2);
+        let read = b2.read_object(&object.id).unwrap();
+        assert_eq!(read.buffer.as_ref(), object.buffer.as_ref());
+    }
+}
@@ -0,0 +1,299 @@
+use crate::backends::directory::temp_path;
+use crate::backends::{range_of, Backend, Capabilities, Result, Retrieval};
+use crate::objects::{Object, ObjectId, ReadBuffer, ReadObject, WriteObject};
+
+use lru::LruCache;
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// Which objects a full `CachedBackend` drops first.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Eviction {
+    /// The ones that haven't been read for the longest
+    #[default]
+    LeastRecentlyUsed,
+    /// The ones that were cached first, even if they're read often
+    OldestFirst,
+}
+
+/// Keeps a copy of recently read and written objects of another
+/// backend in a local directory, up to a limit.
+///
+/// The cache survives restarts, and may be removed at any time while
+/// it's not in use. Failures of the cache are logged, and fall back
+/// to the wrapped backend.
+#[derive(Clone)]
+pub struct CachedBackend<B> {
+    inner: B,
+    dir: Arc<PathBuf>,
+    index: Arc<Mutex<Index>>,
+}
+
+struct Index {
+    /// Object sizes, least recently used first
+    entries: LruCache<ObjectId, u64>,
+    size: u64,
+    limit: u64,
+    eviction: Eviction,
+}
+
+impl<B: Backend> CachedBackend<B> {
+    /// Cache objects of `inner` in `dir`, using at most `limit`
+    /// bytes. Objects already in `dir` are kept.
+    pub fn new(inner: B, dir: impl AsRef<Path>, limit: u64) -> Result<CachedBackend<B>> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let mut found = vec![];
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            match parse_id(&name) {
+                Some(id) => {
+                    let metadata = entry.metadata()?;
+                    let used = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                    found.push((used, id, metadata.len()));
+                }
+                // left over from a crash
+                None if name.starts_with('.') => {
+                    let _ = fs::remove_file(entry.path());
+                }
+                None => {}
+            }
+        }
+        found.sort_by_key(|(used, _, _)| *used);
+
+        let mut index = Index {
+            entries: LruCache::unbounded(),
+            size: 0,
+            limit,
+            eviction: Eviction::default(),
+        };
+        for (_, id, size) in found {
+            index.entries.put(id, size);
+            index.size += size;
+        }
+
+        let cache = CachedBackend {
+            inner,
+            dir: Arc::new(dir),
+            index: Arc::new(Mutex::new(index)),
+        };
+        cache.evict();
+        Ok(cache)
+    }
+
+    pub fn eviction(self, eviction: Eviction) -> CachedBackend<B> {
+        self.index.lock().unwrap().eviction = eviction;
+        self
+    }
+
+    /// The size of the objects in the cache.
+    pub fn cached_bytes(&self) -> u64 {
+        self.index.lock().unwrap().size
+    }
+
+    fn path(&self, id: &ObjectId) -> PathBuf {
+        self.dir.join(id.to_string())
+    }
+
+    fn get(&self, id: &ObjectId) -> Option<Arc<ReadObject>> {
+        {
+            let mut index = self.index.lock().unwrap();
+            let cached = match index.eviction {
+                Eviction::LeastRecentlyUsed => index.entries.get(id).is_some(),
+                Eviction::OldestFirst => index.entries.contains(id),
+            };
+            if !cached {
+                return None;
+            }
+        }
+
+        match fs::read(self.path(id)) {
+            Ok(data) => Some(Arc::new(Object::with_id(*id, ReadBuffer::new(data)))),
+            Err(e) => {
+                if e.kind() != io::ErrorKind::NotFound {
+                    warn!("reading cached object {}: {}", id.to_string(), e);
+                }
+                let mut index = self.index.lock().unwrap();
+                if let Some(size) = index.entries.pop(id) {
+                    index.size -= size;
+                }
+                None
+            }
+        }
+    }
+
+    fn insert(&self, id: &ObjectId, data: &[u8]) {
+        let size = data.len() as u64;
+        if size > self.index.lock().unwrap().limit {
+            return;
+        }
+
+        let path = self.path(id);
+        let temp = temp_path(&path);
+        if let Err(e) = fs::write(&temp, data).and_then(|_| fs::rename(&temp, &path)) {
+            warn!("caching object {}: {}", id.to_string(), e);
+            let _ = fs::remove_file(&temp);
+            return;
+        }
+
+        {
+            let mut index = self.index.lock().unwrap();
+            if let Some(replaced) = index.entries.put(*id, size) {
+                index.size -= replaced;
+            }
+            index.size += size;
+        }
+        self.evict();
+    }
+
+    fn evict(&self) {
+        let mut index = self.index.lock().unwrap();
+        while index.size > index.limit {
+            let (id, size) = match index.entries.pop_lru() {
+                Some(entry) => entry,
+                None => break,
+            };
+            index.size -= size;
+            let _ = fs::remove_file(self.path(&id));
+        }
+    }
+}
+
+impl<B: Backend> Backend for CachedBackend<B> {
+    fn write_object(&self, object: &WriteObject) -> Result<()> {
+        self.inner.write_object(object)?;
+        self.insert(&object.id, object.buffer.as_ref());
+        Ok(())
+    }
+
+    fn write_data_object(&self, object: &WriteObject) -> Result<()> {
+        self.inner.write_data_object(object)?;
+        self.insert(&object.id, object.buffer.as_ref());
+        Ok(())
+    }
+
+    fn retrieve_object(&self, id: &ObjectId) -> Result<Retrieval> {
+        if self.index.lock().unwrap().entries.contains(id) {
+            return Ok(Retrieval::Ready);
+        }
+        self.inner.retrieve_object(id)
+    }
+
+    fn read_object(&self, id: &ObjectId) -> Result<Arc<ReadObject>> {
+        if let Some(object) = self.get(id) {
+            return Ok(object);
+        }
+
+        let object = self.inner.read_object(id)?;
+        self.insert(id, object.buffer.as_ref());
+        Ok(object)
+    }
+
+    /// Parts of objects aren't cached, only read from whole ones
+    /// that already are.
+    fn read_range(&self, id: &ObjectId, offset: u64, len: usize) -> Result<Vec<u8>> {
+        match self.get(id) {
+            Some(object) => range_of(object.buffer.as_ref(), offset, len),
+            None => self.inner.read_range(id, offset, len),
+        }
+    }
+
+    fn delete_object(&self, id: &ObjectId) -> Result<()> {
+        let cached = {
+            let mut index = self.index.lock().unwrap();
+            let size = index.entries.pop(id);
+            index.size -= size.unwrap_or(0);
+            size.is_some()
+        };
+        if cached {
+            let _ = fs::remove_file(self.path(id));
+        }
+        self.inner.delete_object(id)
+    }
+
+    /// The objects of the wrapped backend, as the cache may only hold
+    /// some of them.
+    fn list_objects(&self) -> Result<Vec<ObjectId>> {
+        self.inner.list_objects()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.inner.capabilities()
+    }
+
+    fn probe(&self) -> Result<Capabilities> {
+        self.inner.probe()
+    }
+}
+
+/// The id of an object cached as `name`.
+fn parse_id(name: &str) -> Option<ObjectId> {
+    if name.len() != 64 || !name.is_ascii() {
+        return None;
+    }
+
+    let mut bytes = [0; 32];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&name[2 * i..2 * i + 2], 16).ok()?;
+    }
+    Some(ObjectId::from_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn objects_are_cached_up_to_the_limit() {
+        use super::*;
+        use crate::backends::{BackendError, MemoryBackend};
+        use crate::objects::BlockBuffer;
+        use crate::BLOCK_SIZE;
+
+        let dir = std::env::temp_dir().join("0s_test_cache");
+        let _ = fs::remove_dir_all(&dir);
+
+        let remote = MemoryBackend::default();
+        let limit = 2 * BLOCK_SIZE as u64;
+        let cache = CachedBackend::new(remote.clone(), &dir, limit).unwrap();
+
+        let objects = (1..=3u8)
+            .map(|n| {
+                let mut object = Object::new(BlockBuffer::default());
+                object.set_id(ObjectId::from_bytes([n; 32]));
+                object.buffer.as_mut()[0] = n;
+                object
+            })
+            .collect::<Vec<_>>();
+        cache.write_object(&objects[0]).unwrap();
+        cache.write_object(&objects[1]).unwrap();
+
+        // read the first, so the second is the least recently used
+        cache.read_object(&objects[0].id).unwrap();
+        cache.write_object(&objects[2]).unwrap();
+        assert_eq!(cache.cached_bytes(), limit);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+
+        remote.remove(&objects[0].id);
+        let read = cache.read_object(&objects[0].id).unwrap();
+        assert_eq!(read.buffer.as_ref(), objects[0].buffer.as_ref());
+
+        remote.remove(&objects[1].id);
+        assert!(matches!(
+            cache.read_object(&objects[1].id),
+            Err(BackendError::NoObjectFound)
+        ));
+
+        // what's on disk is picked up again
+        let cache = CachedBackend::new(MemoryBackend::default(), &dir, limit).unwrap();
+        assert_eq!(cache.cached_bytes(), limit);
+        assert!(cache.read_object(&objects[2].id).is_ok());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
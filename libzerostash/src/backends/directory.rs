@@ -0,0 +1,339 @@
+use crate::backends::names::id_of;
+use crate::backends::{range_of, Backend, BackendError, Capabilities, Nested, ObjectNames, Result};
+use crate::limits;
+use crate::objects::{Object, ObjectId, ReadBuffer, ReadObject, WriteObject};
+
+use lru::LruCache;
+use memmap::MmapOptions;
+use walkdir::WalkDir;
+
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Stores objects as files in a local directory.
+///
+/// Objects are spread over two levels of subdirectories by the first
+/// bytes of their id, like `ab/cd/abcd…`, so no directory grows too
+/// large. Files are written under a temporary name and renamed when
+/// complete, so a crash never leaves a partial object behind.
+///
+/// Objects in the flat layout of earlier versions can still be read.
+/// Stashes can share a directory with a prefix each, which is joined
+/// to the shard, or with other `names`.
+#[derive(Clone)]
+pub struct Directory {
+    target: Arc<PathBuf>,
+    names: Arc<dyn ObjectNames>,
+    read_lru: Arc<Mutex<LruCache<ObjectId, Arc<ReadObject>>>>,
+}
+
+impl Directory {
+    pub fn new(target: impl AsRef<Path>) -> Result<Directory> {
+        fs::create_dir_all(&target)?;
+        Ok(Directory {
+            target: Arc::new(target.as_ref().into()),
+            names: Arc::new(Nested::default()),
+            read_lru: Arc::new(Mutex::new(LruCache::new(100))),
+        })
+    }
+
+    /// Store the objects under `prefix` in the directory, like
+    /// `home/`, which gives `home/ab/cd/abcd…`.
+    pub fn prefix(self, prefix: impl Into<String>) -> Directory {
+        self.names(Nested(prefix.into()))
+    }
+
+    /// Name the files of objects in the directory with `names`.
+    pub fn names(mut self, names: impl ObjectNames + 'static) -> Directory {
+        self.names = Arc::new(names);
+        self
+    }
+
+    fn object_path(&self, id: &ObjectId) -> PathBuf {
+        self.target.join(self.names.name(id))
+    }
+
+    fn open(&self, id: &ObjectId) -> io::Result<fs::File> {
+        let open = |path| fs::OpenOptions::new().read(true).open(path);
+        match open(self.object_path(id)) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => open(self.target.join(id.to_string())),
+            result => result,
+        }
+    }
+}
+
+/// A name for a file being written next to `path`.
+pub(super) fn temp_path(path: &Path) -> PathBuf {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+    let mut name = std::ffi::OsString::from(".");
+    name.push(path.file_name().unwrap());
+    name.push(format!(
+        ".tmp-{}-{}",
+        process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ));
+    path.with_file_name(name)
+}
+
+impl Backend for Directory {
+    fn write_object(&self, object: &WriteObject) -> Result<()> {
+        let size = object.buffer.as_ref().len();
+
+        let filename = self.object_path(&object.id);
+        let temp = temp_path(&filename);
+        fs::create_dir_all(filename.parent().unwrap())?;
+
+        let _permit = limits::open_file();
+        let write = || -> io::Result<()> {
+            let mut file = fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(&temp)?;
+
+            file.set_len(size as u64)?;
+
+            {
+                let mut mmap = unsafe { MmapOptions::new().len(size).map_mut(&file)? };
+                mmap.copy_from_slice(object.buffer.as_ref());
+            }
+
+            file.flush()?;
+            file.sync_data()?;
+            fs::rename(&temp, &filename)
+        };
+
+        write().map_err(|e| {
+            let _ = fs::remove_file(&temp);
+            BackendError::from(e)
+        })?;
+        // objects like the lock are overwritten, and read back
+        self.read_lru.lock().unwrap().pop(&object.id);
+        Ok(())
+    }
+
+    fn read_object(&self, id: &ObjectId) -> Result<Arc<ReadObject>> {
+        if let Some(obj) = self.read_lru.lock().unwrap().get(id) {
+            return Ok(obj.clone());
+        }
+
+        // don't hold the lock while opening the file, so parallel
+        // readers don't need to wait for each other
+        let mmap = {
+            // the mapping stays valid after the file is closed, so
+            // cached objects don't hold on to descriptors
+            let _permit = limits::open_file();
+            let file = self.open(id).map_err(|e| match e.kind() {
+                io::ErrorKind::NotFound => BackendError::NoObjectFound,
+                _ => e.into(),
+            })?;
+            unsafe { MmapOptions::new().map(&file)? }
+        };
+
+        let obj = Arc::new(Object::with_id(*id, ReadBuffer::new(mmap)));
+        self.read_lru.lock().unwrap().put(*id, obj.clone());
+
+        Ok(obj)
+    }
+
+    fn read_range(&self, id: &ObjectId, offset: u64, len: usize) -> Result<Vec<u8>> {
+        if let Some(obj) = self.read_lru.lock().unwrap().get(id) {
+            return range_of(obj.buffer.as_ref(), offset, len);
+        }
+
+        let _permit = limits::open_file();
+        let mut file = self.open(id).map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => BackendError::NoObjectFound,
+            _ => e.into(),
+        })?;
+        let mut data = vec![0; len];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    fn delete_object(&self, id: &ObjectId) -> Result<()> {
+        self.read_lru.lock().unwrap().pop(id);
+        let remove = |path| match fs::remove_file(path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Err(BackendError::NoObjectFound),
+            result => result.map_err(Into::into),
+        };
+        match remove(self.object_path(id)) {
+            Err(BackendError::NoObjectFound) => remove(self.target.join(id.to_string())),
+            result => result,
+        }
+    }
+
+    fn list_objects(&self) -> Result<Vec<ObjectId>> {
+        let prefix = self.names.prefix();
+        let dir = self
+            .target
+            .join(&prefix[..prefix.rfind('/').map_or(0, |end| end + 1)]);
+        let mut ids = HashSet::new();
+        if dir.exists() {
+            for entry in WalkDir::new(&dir) {
+                let entry = entry.map_err(io::Error::from)?;
+                if !entry.file_type().is_file() {
+                    continue;
+                }
+                let name = entry.path().strip_prefix(&*self.target).unwrap();
+                let name = name
+                    .to_string_lossy()
+                    .replace(std::path::MAIN_SEPARATOR, "/");
+                ids.extend(id_of(&*self.names, &name));
+            }
+        }
+
+        // and those in the flat layout of earlier versions
+        for entry in fs::read_dir(&*self.target)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                ids.extend(ObjectId::from_hex(&entry.file_name().to_string_lossy()));
+            }
+        }
+        Ok(ids.into_iter().collect())
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            writable: true,
+            range_reads: true,
+            listing: true,
+        }
+    }
+
+    fn probe(&self) -> Result<Capabilities> {
+        let capabilities = Capabilities {
+            writable: crate::backends::probe_object(self)?,
+            ..self.capabilities()
+        };
+        fs::remove_file(self.object_path(&crate::backends::probe_id()))?;
+        Ok(capabilities)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn objects_are_sharded() {
+        use super::*;
+        use crate::objects::BlockBuffer;
+
+        let dir = std::env::temp_dir().join("0s_test_directory_shards");
+        let _ = fs::remove_dir_all(&dir);
+        let backend = Directory::new(&dir).unwrap();
+
+        let mut object = Object::new(BlockBuffer::default());
+        object.set_id(ObjectId::from_bytes([0xab; 32]));
+        object.buffer.as_mut()[0] = 1;
+        backend.write_object(&object).unwrap();
+
+        let shard = dir.join("ab").join("ab");
+        let files = fs::read_dir(&shard)
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect::<Vec<_>>();
+        assert_eq!(files, vec![std::ffi::OsString::from(object.id.to_string())]);
+
+        // overwritten objects aren't read from the cache
+        assert_eq!(
+            backend.read_object(&object.id).unwrap().buffer.as_ref()[0],
+            1
+        );
+        object.buffer.as_mut()[0] = 2;
+        backend.write_object(&object).unwrap();
+        assert_eq!(
+            backend.read_object(&object.id).unwrap().buffer.as_ref()[0],
+            2
+        );
+
+        // the flat layout of earlier versions
+        let legacy = ObjectId::from_bytes([0xcd; 32]);
+        fs::write(dir.join(legacy.to_string()), b"flat").unwrap();
+        let read = backend.read_object(&legacy).unwrap();
+        assert_eq!(read.buffer.as_ref(), b"flat");
+        // past the cache of whole objects
+        let backend = Directory::new(&dir).unwrap();
+        assert_eq!(backend.read_range(&legacy, 1, 2).unwrap(), b"la");
+
+        assert!(matches!(
+            backend.read_object(&ObjectId::from_bytes([1; 32])),
+            Err(BackendError::NoObjectFound)
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn prefixes_share_a_directory() {
+        use super::*;
+        use crate::objects::BlockBuffer;
+
+        struct Flat;
+        impl ObjectNames for Flat {
+            fn name(&self, id: &ObjectId) -> String {
+                format!("flat-{}", id.to_string())
+            }
+        }
+
+        let dir = std::env::temp_dir().join("0s_test_directory_prefixes");
+        let _ = fs::remove_dir_all(&dir);
+        let home = Directory::new(&dir).unwrap().prefix("home/");
+        let work = Directory::new(&dir).unwrap().prefix("work/");
+        let flat = Directory::new(&dir).unwrap().names(Flat);
+
+        let mut object = Object::new(BlockBuffer::default());
+        object.set_id(ObjectId::from_bytes([0xab; 32]));
+        for (i, backend) in [&home, &work, &flat].iter().enumerate() {
+            object.buffer.as_mut()[0] = i as u8 + 1;
+            backend.write_object(&object).unwrap();
+        }
+
+        // the same id is a different object in each
+        assert_eq!(home.read_range(&object.id, 0, 1).unwrap(), [1]);
+        assert_eq!(work.read_range(&object.id, 0, 1).unwrap(), [2]);
+        assert_eq!(flat.read_range(&object.id, 0, 1).unwrap(), [3]);
+        let name = object.id.to_string();
+        assert!(dir.join("work/ab/ab").join(&name).exists());
+        assert!(dir.join(format!("flat-{}", name)).exists());
+
+        // and each lists only its own
+        let other = ObjectId::from_bytes([0xcd; 32]);
+        object.set_id(other);
+        work.write_object(&object).unwrap();
+        object.set_id(ObjectId::from_bytes([0xab; 32]));
+        assert_eq!(home.list_objects().unwrap(), vec![object.id]);
+        assert_eq!(flat.list_objects().unwrap(), vec![object.id]);
+        let mut listed = work.list_objects().unwrap();
+        listed.sort_by_key(|id| id.to_string());
+        assert_eq!(listed, vec![object.id, other]);
+
+        home.delete_object(&object.id).unwrap();
+        assert!(matches!(
+            home.read_object(&object.id),
+            Err(BackendError::NoObjectFound)
+        ));
+        assert!(work.read_object(&object.id).is_ok());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn probe_leaves_nothing_behind() {
+        use super::*;
+
+        let dir = std::env::temp_dir().join("0s_test_directory_probe");
+        let _ = fs::remove_dir_all(&dir);
+        let backend = Directory::new(&dir).unwrap();
+
+        assert!(backend.probe().unwrap().writable);
+        let probe = backend.object_path(&crate::backends::probe_id());
+        assert!(!probe.exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,159 @@
+use crate::backends::{range_of, Backend, BackendError, Capabilities, Nested, ObjectNames, Result};
+use crate::objects::{Object, ObjectId, ReadBuffer, ReadObject, WriteObject};
+
+use std::io;
+use std::sync::Arc;
+
+/// Gets objects by their name, like with `fetch()` in a browser.
+///
+/// Closures taking the name and an optional range of bytes are
+/// fetchers too. `None` means there's no such object.
+pub trait Fetch: Send + Sync {
+    /// All of object `name`, or only `offset..offset + len` of it if
+    /// `range` is set. Fetchers that can't ask for a range may return
+    /// the whole object instead.
+    fn fetch(&self, name: &str, range: Option<(u64, usize)>) -> io::Result<Option<Vec<u8>>>;
+}
+
+impl<F> Fetch for F
+where
+    F: Fn(&str, Option<(u64, usize)>) -> io::Result<Option<Vec<u8>>> + Send + Sync,
+{
+    fn fetch(&self, name: &str, range: Option<(u64, usize)>) -> io::Result<Option<Vec<u8>>> {
+        self(name, range)
+    }
+}
+
+/// Reads objects through a `Fetch`, so a stash can be read without a
+/// file system or sockets, like from a page in a browser, where the
+/// page brings its own requests.
+///
+/// Objects are named like in a `Directory` by default, so a stash
+/// served by any static web server can be read. Writes fail with
+/// `ReadOnly`.
+#[derive(Clone)]
+pub struct FetchBackend {
+    fetch: Arc<dyn Fetch>,
+    names: Arc<dyn ObjectNames>,
+    range_reads: bool,
+}
+
+impl FetchBackend {
+    pub fn new(fetch: impl Fetch + 'static) -> FetchBackend {
+        FetchBackend {
+            fetch: Arc::new(fetch),
+            names: Arc::new(Nested::default()),
+            range_reads: false,
+        }
+    }
+
+    /// Look for the objects under `prefix`, like the path of the
+    /// stash on the server.
+    pub fn prefix(self, prefix: impl Into<String>) -> FetchBackend {
+        self.names(Nested(prefix.into()))
+    }
+
+    pub fn names(mut self, names: impl ObjectNames + 'static) -> FetchBackend {
+        self.names = Arc::new(names);
+        self
+    }
+
+    /// Ask the fetcher for only the bytes of chunks being read,
+    /// instead of whole objects.
+    pub fn range_reads(mut self, range_reads: bool) -> FetchBackend {
+        self.range_reads = range_reads;
+        self
+    }
+
+    fn get(&self, id: &ObjectId, range: Option<(u64, usize)>) -> Result<Vec<u8>> {
+        self.fetch
+            .fetch(&self.names.name(id), range)?
+            .ok_or(BackendError::NoObjectFound)
+    }
+}
+
+impl Backend for FetchBackend {
+    fn write_object(&self, _object: &WriteObject) -> Result<()> {
+        Err(BackendError::ReadOnly)
+    }
+
+    fn read_object(&self, id: &ObjectId) -> Result<Arc<ReadObject>> {
+        let data = self.get(id, None)?;
+        Ok(Arc::new(Object::with_id(*id, ReadBuffer::new(data))))
+    }
+
+    fn read_range(&self, id: &ObjectId, offset: u64, len: usize) -> Result<Vec<u8>> {
+        if !self.range_reads {
+            return range_of(&self.get(id, None)?, offset, len);
+        }
+
+        match self.get(id, Some((offset, len)))? {
+            data if data.len() == len => Ok(data),
+            // the whole object, from fetchers that ignore the range
+            data => range_of(&data, offset, len),
+        }
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            writable: false,
+            range_reads: self.range_reads,
+            listing: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn stashes_are_read_through_fetch() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use crate::files::Entry;
+        use crate::snapshots::Labels;
+        use crate::stash::{Stash, StashKey};
+        use std::collections::HashMap;
+        use std::io::Read;
+        use std::sync::Mutex;
+
+        let backend = MemoryBackend::default();
+        let key = || StashKey::open_stash("fetch", "test").unwrap();
+        let mut stash = Stash::new(Arc::new(backend.clone()), key());
+        let mut ingest = stash.ingest().unwrap();
+        ingest
+            .add_file(Entry::from_stream("page.html"), b"<p>hello</p>")
+            .unwrap();
+        ingest.finish(0, vec![], vec![], Labels::new()).unwrap();
+
+        // served like the directory of a stash
+        let names = Nested("stash/".into());
+        let served = backend
+            .ids()
+            .into_iter()
+            .map(|id| (names.name(&id), backend.remove(&id).unwrap()))
+            .collect::<HashMap<_, _>>();
+        let requests = Arc::new(Mutex::new(vec![]));
+        let log = requests.clone();
+        let fetch = FetchBackend::new(move |name: &str, range: Option<(u64, usize)>| {
+            log.lock().unwrap().push(range);
+            Ok(served.get(name).map(|data| match range {
+                Some((offset, len)) => data[offset as usize..][..len].to_vec(),
+                None => data.clone(),
+            }))
+        })
+        .prefix("stash/")
+        .range_reads(true);
+        assert!(!fetch.probe().unwrap().writable);
+
+        let mut stash = Stash::new(Arc::new(fetch), key());
+        stash.read().unwrap();
+        let mut page = String::new();
+        stash
+            .open_file("page.html")
+            .unwrap()
+            .read_to_string(&mut page)
+            .unwrap();
+        assert_eq!(page, "<p>hello</p>");
+        assert!(requests.lock().unwrap().iter().any(Option::is_some));
+    }
+}
@@ -0,0 +1,489 @@
+use crate::backends::http::{byte_range, uri_encode, Curl, Request, Response};
+use crate::backends::{Backend, BackendError, Capabilities, ObjectNames, Prefixed, Result};
+use crate::objects::{Object, ObjectId, ReadBuffer, ReadObject, WriteObject};
+
+use base64::{
+    engine::general_purpose::{STANDARD as BASE64, URL_SAFE_NO_PAD as BASE64URL},
+    Engine,
+};
+use ring::{rand, signature};
+use secrecy::{ExposeSecret, Secret};
+use serde_json::json;
+use zeroize::Zeroize;
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+const SCOPE: &str = "https://www.googleapis.com/auth/devstorage.read_write";
+
+/// An access token, and when to get a new one
+type CachedToken = Option<(Secret<String>, Instant)>;
+
+/// The key of a Google Cloud service account, as downloaded in JSON.
+pub struct ServiceAccount {
+    client_email: String,
+    token_uri: String,
+    key: signature::RsaKeyPair,
+}
+
+#[derive(Deserialize)]
+struct KeyFile {
+    client_email: String,
+    private_key: String,
+    token_uri: String,
+}
+
+#[derive(Deserialize)]
+struct Token {
+    access_token: String,
+    expires_in: u64,
+}
+
+impl ServiceAccount {
+    pub fn from_file(path: impl AsRef<Path>) -> Result<ServiceAccount> {
+        ServiceAccount::from_json(&fs::read_to_string(path)?)
+    }
+
+    pub fn from_json(json: &str) -> Result<ServiceAccount> {
+        let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, what.to_string());
+
+        let mut file: KeyFile =
+            serde_json::from_str(json).map_err(|_| invalid("invalid service account key"))?;
+        let mut pem = file
+            .private_key
+            .lines()
+            .filter(|l| !l.starts_with("-----"))
+            .collect::<String>();
+        file.private_key.zeroize();
+
+        let der = BASE64.decode(&pem);
+        pem.zeroize();
+        let mut der = der.map_err(|_| invalid("invalid private key"))?;
+        let key = signature::RsaKeyPair::from_pkcs8(&der);
+        der.zeroize();
+        let key = key.map_err(|_| invalid("invalid private key"))?;
+
+        Ok(ServiceAccount {
+            client_email: file.client_email,
+            token_uri: file.token_uri,
+            key,
+        })
+    }
+
+    /// A JWT asserting the identity of the account, to exchange for
+    /// an access token.
+    fn assertion(&self, unix_secs: u64) -> Result<String> {
+        let header = BASE64URL.encode(json!({ "alg": "RS256", "typ": "JWT" }).to_string());
+        let claims = BASE64URL.encode(
+            json!({
+                "iss": self.client_email,
+                "scope": SCOPE,
+                "aud": self.token_uri,
+                "iat": unix_secs,
+                "exp": unix_secs + 3600,
+            })
+            .to_string(),
+        );
+        let message = format!("{}.{}", header, claims);
+
+        let mut signature = vec![0; self.key.public_modulus_len()];
+        self.key
+            .sign(
+                &signature::RSA_PKCS1_SHA256,
+                &rand::SystemRandom::new(),
+                message.as_bytes(),
+                &mut signature,
+            )
+            .map_err(|_| io::Error::other("can't sign the token request"))?;
+
+        Ok(format!("{}.{}", message, BASE64URL.encode(signature)))
+    }
+}
+
+/// Stores objects in a Google Cloud Storage bucket, through the JSON
+/// API, authorized as a service account.
+///
+/// Objects are written with resumable uploads, so an upload that
+/// fails carries on from what the service already received.
+#[derive(Clone)]
+pub struct GcsBackend {
+    curl: Curl,
+    account: Arc<ServiceAccount>,
+    bucket: String,
+    names: Arc<dyn ObjectNames>,
+    endpoint: String,
+    chunk_size: usize,
+    token: Arc<Mutex<CachedToken>>,
+}
+
+impl GcsBackend {
+    pub fn new(bucket: impl Into<String>, account: ServiceAccount) -> GcsBackend {
+        GcsBackend {
+            curl: Curl::default(),
+            account: Arc::new(account),
+            bucket: bucket.into(),
+            names: Arc::new(Prefixed::default()),
+            endpoint: "https://storage.googleapis.com".into(),
+            chunk_size: 1024 * 1024,
+            token: Arc::default(),
+        }
+    }
+
+    /// Store the objects under `prefix` in the bucket, like `home/`.
+    pub fn prefix(self, prefix: impl Into<String>) -> GcsBackend {
+        self.names(Prefixed(prefix.into()))
+    }
+
+    /// Name the objects in the bucket with `names`.
+    pub fn names(mut self, names: impl ObjectNames + 'static) -> GcsBackend {
+        self.names = Arc::new(names);
+        self
+    }
+
+    /// The base URL of the service, for emulators.
+    pub fn endpoint(mut self, endpoint: impl Into<String>) -> GcsBackend {
+        self.endpoint = endpoint.into().trim_end_matches('/').to_string();
+        self
+    }
+
+    /// Send uploads in requests of `size` bytes, 1 MiB by default.
+    /// It's rounded up to a multiple of 256 KiB, as the service
+    /// requires.
+    pub fn chunk_size(mut self, size: usize) -> GcsBackend {
+        const QUANTUM: usize = 256 * 1024;
+        self.chunk_size = size.div_ceil(QUANTUM).max(1) * QUANTUM;
+        self
+    }
+
+    /// The `curl` to send requests with.
+    pub fn curl(mut self, curl: Curl) -> GcsBackend {
+        self.curl = curl;
+        self
+    }
+
+    /// A valid access token, exchanged for a new one a minute before
+    /// it expires.
+    fn token(&self) -> Result<String> {
+        let mut token = self.token.lock().unwrap();
+        if let Some((token, expires)) = token.as_ref() {
+            if Instant::now() < *expires {
+                return Ok(token.expose_secret().clone());
+            }
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let form = format!(
+            "grant_type={}&assertion={}",
+            uri_encode("urn:ietf:params:oauth:grant-type:jwt-bearer", false),
+            self.account.assertion(now)?
+        );
+        let request = Request::new("POST", self.account.token_uri.clone())
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body(form.as_bytes());
+        let response = self.curl.send(&request)?;
+        if !response.is_success() {
+            return Err(response.error("token request").into());
+        }
+
+        let new: Token = serde_json::from_slice(&response.body)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid token response"))?;
+        let expires =
+            Instant::now() + std::time::Duration::from_secs(new.expires_in.saturating_sub(60));
+        let access = new.access_token.clone();
+        *token = Some((Secret::new(new.access_token), expires));
+        Ok(access)
+    }
+
+    fn name(&self, id: &ObjectId) -> String {
+        self.names.name(id)
+    }
+
+    /// Start a resumable upload, and return the URL of the session.
+    fn get(&self, id: &ObjectId, range: Option<String>) -> Result<Response> {
+        let mut request = Request::new(
+            "GET",
+            format!(
+                "{}/storage/v1/b/{}/o/{}?alt=media",
+                self.endpoint,
+                uri_encode(&self.bucket, false),
+                uri_encode(&self.name(id), false)
+            ),
+        )
+        .header("Authorization", format!("Bearer {}", self.token()?));
+        if let Some(range) = range {
+            request = request.header("Range", range);
+        }
+
+        let response = self.curl.send(&request)?;
+        match response.status {
+            200 | 206 => Ok(response),
+            404 => Err(BackendError::NoObjectFound),
+            _ => Err(response.error("download").into()),
+        }
+    }
+
+    fn start_upload(&self, name: &str, len: usize) -> Result<String> {
+        let request = Request::new(
+            "POST",
+            format!(
+                "{}/upload/storage/v1/b/{}/o?uploadType=resumable&name={}",
+                self.endpoint,
+                uri_encode(&self.bucket, false),
+                uri_encode(name, false)
+            ),
+        )
+        .header("Authorization", format!("Bearer {}", self.token()?))
+        .header("X-Upload-Content-Type", "application/octet-stream")
+        .header("X-Upload-Content-Length", len.to_string())
+        .body(&[]);
+
+        let response = self.curl.send(&request)?;
+        match response.header("location") {
+            Some(location) if response.is_success() => Ok(location.to_string()),
+            _ => Err(response.error("starting the upload").into()),
+        }
+    }
+
+    /// Send `data[offset..end]` of an upload of `data.len()` bytes, or
+    /// with an empty range, ask where the upload is.
+    fn put_range(
+        &self,
+        session: &str,
+        data: &[u8],
+        offset: usize,
+        end: usize,
+    ) -> io::Result<Response> {
+        let range = if offset == end {
+            format!("bytes */{}", data.len())
+        } else {
+            format!("bytes {}-{}/{}", offset, end - 1, data.len())
+        };
+        let request = Request::new("PUT", session)
+            .header("Content-Range", range)
+            .body(&data[offset..end]);
+
+        self.curl.send_once(&request)
+    }
+}
+
+/// The bytes the service has of an incomplete upload, from the
+/// `Range` of a 308 response.
+fn received(response: &Response) -> usize {
+    response
+        .header("range")
+        .and_then(|r| r.rsplit('-').next())
+        .and_then(|end| end.parse::<usize>().ok())
+        .map(|end| end + 1)
+        .unwrap_or(0)
+}
+
+impl Backend for GcsBackend {
+    fn write_object(&self, object: &WriteObject) -> Result<()> {
+        let data = object.buffer.as_ref();
+        let session = self.start_upload(&self.name(&object.id), data.len())?;
+
+        let policy = self.curl.policy();
+        let mut offset = 0;
+        let mut retry = 0;
+        loop {
+            let end = (offset + self.chunk_size).min(data.len());
+            let error = match self.put_range(&session, data, offset, end) {
+                Ok(response) if response.is_success() => return Ok(()),
+                Ok(response) if response.status == 308 => {
+                    offset = received(&response);
+                    retry = 0;
+                    continue;
+                }
+                Ok(response) if response.status == 429 || response.status >= 500 => {
+                    response.error("upload")
+                }
+                Ok(response) => return Err(response.error("upload").into()),
+                Err(e) => e,
+            };
+
+            retry += 1;
+            if retry >= policy.attempts {
+                return Err(error.into());
+            }
+            debug!("resuming upload, after {}", error);
+            thread::sleep(policy.delay(retry));
+
+            // the service may have received part of the request
+            match self.put_range(&session, data, 0, 0) {
+                Ok(response) if response.is_success() => return Ok(()),
+                Ok(response) if response.status == 308 => offset = received(&response),
+                _ => {}
+            }
+        }
+    }
+
+    fn read_object(&self, id: &ObjectId) -> Result<Arc<ReadObject>> {
+        let response = self.get(id, None)?;
+        Ok(Arc::new(Object::with_id(
+            *id,
+            ReadBuffer::new(response.body),
+        )))
+    }
+
+    fn read_range(&self, id: &ObjectId, offset: u64, len: usize) -> Result<Vec<u8>> {
+        self.get(id, Some(byte_range(offset, len)))?
+            .into_range(offset, len)
+    }
+
+    fn delete_object(&self, id: &ObjectId) -> Result<()> {
+        let request = Request::new(
+            "DELETE",
+            format!(
+                "{}/storage/v1/b/{}/o/{}",
+                self.endpoint,
+                uri_encode(&self.bucket, false),
+                uri_encode(&self.name(id), false)
+            ),
+        )
+        .header("Authorization", format!("Bearer {}", self.token()?));
+
+        let response = self.curl.send(&request)?;
+        match response.status {
+            404 => Err(BackendError::NoObjectFound),
+            _ if response.is_success() => Ok(()),
+            _ => Err(response.error("delete").into()),
+        }
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            writable: true,
+            range_reads: true,
+            listing: true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn uploads_resume_after_failures() {
+        use super::*;
+        use crate::backends::http::test_server;
+        use crate::backends::Retry;
+        use crate::objects::BlockBuffer;
+        use std::collections::HashMap;
+        use std::time::Duration;
+
+        #[derive(Default)]
+        struct Bucket {
+            objects: HashMap<String, Vec<u8>>,
+            upload: Vec<u8>,
+            name: String,
+            requests: usize,
+            tokens: usize,
+        }
+
+        let bucket = Arc::new(Mutex::new(Bucket::default()));
+        let server = bucket.clone();
+        let url = Arc::new(Mutex::new(String::new()));
+        let base = url.clone();
+        let started = test_server::start(move |request| {
+            let mut bucket = server.lock().unwrap();
+            let (path, query) = request.path.split_once('?').unwrap_or((&request.path, ""));
+
+            if path == "/token" {
+                let form = String::from_utf8(request.body).unwrap();
+                let assertion = form.split("assertion=").nth(1).unwrap();
+                assert_eq!(assertion.split('.').count(), 3);
+                bucket.tokens += 1;
+                let token = json!({ "access_token": "t", "expires_in": 3600 });
+                return (200, vec![], token.to_string().into_bytes());
+            }
+            if path != "/session" {
+                assert_eq!(request.header("authorization"), Some("Bearer t"));
+            }
+
+            match (request.method.as_str(), path) {
+                ("POST", "/upload/storage/v1/b/bucket/o") => {
+                    bucket.name = query.split("name=").nth(1).unwrap().into();
+                    bucket.upload.clear();
+                    let location = format!("{}/session", base.lock().unwrap());
+                    (200, vec![("Location".into(), location)], vec![])
+                }
+                ("PUT", "/session") => {
+                    bucket.requests += 1;
+                    let range = request.header("content-range").unwrap().to_string();
+                    let total = range.rsplit('/').next().unwrap().parse().unwrap();
+                    let status = |bucket: &Bucket| match bucket.upload.len() {
+                        0 => (308, vec![], vec![]),
+                        len => {
+                            let range = format!("bytes=0-{}", len - 1);
+                            (308, vec![("Range".into(), range)], vec![])
+                        }
+                    };
+
+                    // lose every third request
+                    if bucket.requests % 3 == 0 && !range.contains('*') {
+                        return (503, vec![], vec![]);
+                    }
+                    bucket.upload.extend_from_slice(&request.body);
+                    if bucket.upload.len() < total {
+                        return status(&bucket);
+                    }
+                    let (name, data) = (bucket.name.clone(), bucket.upload.clone());
+                    bucket.objects.insert(name, data);
+                    (200, vec![], vec![])
+                }
+                ("GET", path) => {
+                    let name = path.trim_start_matches("/storage/v1/b/bucket/o/");
+                    assert_eq!(query, "alt=media");
+                    match bucket.objects.get(name) {
+                        Some(data) => (200, vec![], data.clone()),
+                        None => (404, vec![], vec![]),
+                    }
+                }
+                _ => (400, vec![], vec![]),
+            }
+        });
+        *url.lock().unwrap() = started.clone();
+
+        let mut key: serde_json::Value = serde_json::from_str(
+            &fs::read_to_string("tests/data/gcs_service_account.json").unwrap(),
+        )
+        .unwrap();
+        key["token_uri"] = json!(format!("{}/token", started));
+        let account = ServiceAccount::from_json(&key.to_string()).unwrap();
+
+        let retry = Retry {
+            backoff: Duration::from_millis(1),
+            ..Retry::default()
+        };
+        let gcs = GcsBackend::new("bucket", account)
+            .endpoint(started)
+            .prefix("home/")
+            .chunk_size(1)
+            .curl(Curl::default().retry(retry));
+
+        let mut object = Object::new(BlockBuffer::default());
+        object.set_id(ObjectId::from_bytes([1; 32]));
+        object.buffer.as_mut()[crate::BLOCK_SIZE - 1] = 1;
+        gcs.write_object(&object).unwrap();
+        assert_eq!(
+            bucket.lock().unwrap().name,
+            format!("home%2F{}", object.id.to_string())
+        );
+
+        let read = gcs.read_object(&object.id).unwrap();
+        assert_eq!(read.buffer.as_ref(), object.buffer.as_ref());
+        assert!(matches!(
+            gcs.read_object(&ObjectId::from_bytes([9; 32])),
+            Err(BackendError::NoObjectFound)
+        ));
+        assert_eq!(bucket.lock().unwrap().tokens, 1);
+
+        assert!(ServiceAccount::from_json("{}").is_err());
+    }
+}
@@ -0,0 +1,252 @@
+//! A backend that leaves storing objects to a helper program, like
+//! the remote helpers of git. A small script around a tool like
+//! `rclone` makes any storage it supports a backend.
+//!
+//! The helper reads commands from its standard input, one per line,
+//! and answers each of them on its standard output:
+//!
+//!  - `put <id> <size>`, followed by `size` bytes: `ok`
+//!  - `get <id>`: `ok <size>`, followed by `size` bytes, or `missing`
+//!  - `delete <id>`: `ok`, or `missing`
+//!
+//! Ids are 64 hexadecimal digits. Any command may be answered with
+//! `error <message>` instead. The helper should exit when its input
+//! is closed, and can tell about problems on its standard error.
+
+use crate::backends::{Backend, BackendError, Result};
+use crate::objects::{Object, ObjectId, ReadBuffer, ReadObject, WriteObject};
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Stores objects through a helper program, see the module
+/// documentation for the protocol.
+///
+/// Helpers are started when first needed, and restarted if they
+/// don't follow the protocol.
+#[derive(Clone)]
+pub struct HelperBackend {
+    program: String,
+    args: Vec<String>,
+    helpers: Arc<Vec<Mutex<Option<Helper>>>>,
+    next: Arc<AtomicUsize>,
+}
+
+struct Helper {
+    child: Child,
+    stdin: Option<ChildStdin>,
+    stdout: BufReader<ChildStdout>,
+}
+
+enum Answer {
+    Ok(Option<usize>),
+    Missing,
+    Error(String),
+}
+
+impl HelperBackend {
+    pub fn new(
+        program: impl Into<String>,
+        args: impl IntoIterator<Item = impl Into<String>>,
+    ) -> HelperBackend {
+        HelperBackend {
+            program: program.into(),
+            args: args.into_iter().map(Into::into).collect(),
+            helpers: Arc::new(vec![Mutex::new(None)]),
+            next: Arc::default(),
+        }
+    }
+
+    /// Run up to `count` helpers, to transfer as many objects at the
+    /// same time. There's one by default.
+    pub fn processes(mut self, count: usize) -> HelperBackend {
+        self.helpers = Arc::new((0..count.max(1)).map(|_| Mutex::new(None)).collect());
+        self
+    }
+
+    /// Run `exchange` with one of the helpers. If it fails, the
+    /// helper is out of step, and replaced next time.
+    fn call<T>(&self, exchange: impl FnOnce(&mut Helper) -> io::Result<Result<T>>) -> Result<T> {
+        let slot = self.next.fetch_add(1, Ordering::Relaxed) % self.helpers.len();
+        let mut slot = self.helpers[slot].lock().unwrap();
+
+        if slot.is_none() {
+            *slot = Some(self.spawn()?);
+        }
+        match exchange(slot.as_mut().unwrap()) {
+            Ok(result) => result,
+            Err(e) => {
+                if let Some(mut helper) = slot.take() {
+                    let _ = helper.child.kill();
+                }
+                Err(io::Error::new(e.kind(), format!("helper {}: {}", self.program, e)).into())
+            }
+        }
+    }
+
+    fn spawn(&self) -> io::Result<Helper> {
+        let mut child = Command::new(&self.program)
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+
+        Ok(Helper {
+            stdin: child.stdin.take(),
+            stdout: BufReader::new(child.stdout.take().unwrap()),
+            child,
+        })
+    }
+}
+
+impl Helper {
+    fn send(&mut self, command: &[u8], data: &[u8]) -> io::Result<()> {
+        let stdin = self.stdin.as_mut().unwrap();
+        stdin.write_all(command)?;
+        stdin.write_all(data)?;
+        stdin.flush()
+    }
+
+    fn answer(&mut self) -> io::Result<Answer> {
+        let mut line = String::new();
+        if self.stdout.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "exited"));
+        }
+
+        let line = line.trim_end();
+        let (answer, rest) = line.split_once(' ').unwrap_or((line, ""));
+        match answer {
+            "ok" if rest.is_empty() => Ok(Answer::Ok(None)),
+            "ok" => match rest.parse() {
+                Ok(size) => Ok(Answer::Ok(Some(size))),
+                Err(_) => Err(protocol(line)),
+            },
+            "missing" => Ok(Answer::Missing),
+            "error" => Ok(Answer::Error(rest.to_string())),
+            _ => Err(protocol(line)),
+        }
+    }
+}
+
+impl Drop for Helper {
+    fn drop(&mut self) {
+        // closing the input tells the helper to exit
+        drop(self.stdin.take());
+        let _ = self.child.wait();
+    }
+}
+
+impl Backend for HelperBackend {
+    fn write_object(&self, object: &WriteObject) -> Result<()> {
+        let data = object.buffer.as_ref();
+        self.call(|helper| {
+            let command = format!("put {} {}\n", object.id.to_string(), data.len());
+            helper.send(command.as_bytes(), data)?;
+            Ok(match helper.answer()? {
+                Answer::Ok(None) => Ok(()),
+                Answer::Error(e) => Err(failed(e)),
+                _ => return Err(protocol("unexpected answer to put")),
+            })
+        })
+    }
+
+    fn read_object(&self, id: &ObjectId) -> Result<Arc<ReadObject>> {
+        self.call(|helper| {
+            helper.send(format!("get {}\n", id.to_string()).as_bytes(), &[])?;
+            Ok(match helper.answer()? {
+                Answer::Ok(Some(size)) => {
+                    let mut data = vec![0; size];
+                    helper.stdout.read_exact(&mut data)?;
+                    Ok(Arc::new(Object::with_id(*id, ReadBuffer::new(data))))
+                }
+                Answer::Missing => Err(BackendError::NoObjectFound),
+                Answer::Error(e) => Err(failed(e)),
+                Answer::Ok(None) => return Err(protocol("no size in answer to get")),
+            })
+        })
+    }
+
+    fn delete_object(&self, id: &ObjectId) -> Result<()> {
+        self.call(|helper| {
+            helper.send(format!("delete {}\n", id.to_string()).as_bytes(), &[])?;
+            Ok(match helper.answer()? {
+                Answer::Ok(_) => Ok(()),
+                Answer::Missing => Err(BackendError::NoObjectFound),
+                Answer::Error(e) => Err(failed(e)),
+            })
+        })
+    }
+}
+
+fn protocol(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("protocol error: {}", what),
+    )
+}
+
+fn failed(message: String) -> BackendError {
+    io::Error::other(message).into()
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    #[test]
+    fn objects_are_stored_by_the_helper() {
+        use super::*;
+        use crate::objects::BlockBuffer;
+        use std::fs;
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join("0s_test_helper");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("objects")).unwrap();
+
+        let program = dir.join("helper");
+        fs::write(
+            &program,
+            "#!/bin/sh\n\
+             cd \"$1\"\n\
+             while read -r command id size; do\n\
+             case \"$command\" in\n\
+             put) head -c \"$size\" > \"$id\"; echo ok ;;\n\
+             get) if [ -f \"$id\" ]; then echo \"ok $(wc -c < \"$id\")\"; cat \"$id\"; else echo missing; fi ;;\n\
+             delete) if rm \"$id\" 2>/dev/null; then echo ok; else echo missing; fi ;;\n\
+             *) echo \"error unknown command $command\" ;;\n\
+             esac\n\
+             done\n",
+        )
+        .unwrap();
+        fs::set_permissions(&program, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let helper = HelperBackend::new(
+            program.to_string_lossy(),
+            [dir.join("objects").to_string_lossy()],
+        )
+        .processes(2);
+
+        let mut object = Object::new(BlockBuffer::default());
+        object.set_id(ObjectId::from_bytes([1; 32]));
+        object.buffer.as_mut()[0] = 1;
+        object.buffer.as_mut()[crate::BLOCK_SIZE - 1] = b'\n';
+        helper.write_object(&object).unwrap();
+        assert!(dir.join("objects").join(object.id.to_string()).exists());
+
+        // from the other helper
+        let read = helper.read_object(&object.id).unwrap();
+        assert_eq!(read.buffer.as_ref(), object.buffer.as_ref());
+
+        helper.delete_object(&object.id).unwrap();
+        assert!(matches!(
+            helper.read_object(&object.id),
+            Err(BackendError::NoObjectFound)
+        ));
+
+        let broken = HelperBackend::new("/bin/echo", ["nonsense"]);
+        assert!(broken.read_object(&object.id).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
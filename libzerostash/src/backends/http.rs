@@ -0,0 +1,507 @@
+//! HTTP requests for the object storage backends.
+//!
+//! Requests are made by `curl`, which takes care of TLS, proxies and
+//! authentication to them, so the crate doesn't need a TLS stack of
+//! its own. Requests that fail in a way that may be transient are
+//! retried with exponential backoff.
+
+use crate::backends::Retry;
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process::{self, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+/// Runs requests with `curl`.
+#[derive(Clone, Debug)]
+pub struct Curl {
+    program: String,
+    retry: Retry,
+    insecure: bool,
+    ca_file: Option<String>,
+}
+
+impl Default for Curl {
+    fn default() -> Curl {
+        Curl {
+            program: "curl".into(),
+            retry: Retry::default(),
+            insecure: false,
+            ca_file: None,
+        }
+    }
+}
+
+impl Curl {
+    /// The `curl` executable to run.
+    pub fn program(mut self, program: impl Into<String>) -> Curl {
+        self.program = program.into();
+        self
+    }
+
+    pub fn retry(mut self, retry: Retry) -> Curl {
+        self.retry = retry;
+        self
+    }
+
+    /// Don't verify TLS certificates. Objects are encrypted, but
+    /// credentials may be sent to anyone in the network path.
+    pub fn insecure(mut self, insecure: bool) -> Curl {
+        self.insecure = insecure;
+        self
+    }
+
+    /// Verify TLS certificates with the CA bundle in `path`, instead
+    /// of the system's.
+    pub fn ca_file(mut self, path: impl Into<String>) -> Curl {
+        self.ca_file = Some(path.into());
+        self
+    }
+
+    pub(crate) fn policy(&self) -> Retry {
+        self.retry
+    }
+
+    /// Send `request`, retrying according to the policy.
+    pub(crate) fn send(&self, request: &Request) -> io::Result<Response> {
+        let mut retry = 0;
+        loop {
+            let result = self.send_once(request);
+            let transient = match &result {
+                Ok(response) => {
+                    response.status == 408 || response.status == 429 || response.status >= 500
+                }
+                // curl itself is missing
+                Err(e) => !matches!(
+                    e.kind(),
+                    io::ErrorKind::NotFound | io::ErrorKind::PermissionDenied
+                ),
+            };
+
+            retry += 1;
+            if !transient || retry >= self.retry.attempts {
+                return result;
+            }
+
+            let delay = self.retry.delay(retry);
+            debug!(
+                "retrying {} {} in {:?}, after {}",
+                request.method,
+                request.url,
+                delay,
+                match &result {
+                    Ok(response) => response.status.to_string(),
+                    Err(e) => e.to_string(),
+                }
+            );
+            thread::sleep(delay);
+        }
+    }
+
+    /// Send `request` once, for callers with their own way of
+    /// retrying.
+    pub(crate) fn send_once(&self, request: &Request) -> io::Result<Response> {
+        // the URL and headers can hold credentials, so they're passed
+        // in a file, and don't show up in `ps`
+        let config = ConfigFile::write(request)?;
+
+        let mut command = Command::new(&self.program);
+        command
+            .args(["--silent", "--show-error", "--dump-header", "-"])
+            .args(["--output", "-", "--config"])
+            .arg(&config.0);
+        if self.insecure {
+            command.arg("--insecure");
+        }
+        if let Some(ca_file) = &self.ca_file {
+            command.arg("--cacert").arg(ca_file);
+        }
+        if request.body.is_some() {
+            command.args(["--data-binary", "@-"]);
+        }
+
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        // write the body while the output is read, so neither pipe
+        // fills up
+        let stdin = child.stdin.take().unwrap();
+        let body = request.body.unwrap_or_default();
+        let output = thread::scope(|s| {
+            s.spawn(move || {
+                let mut stdin = stdin;
+                let _ = stdin.write_all(body);
+            });
+            child.wait_with_output()
+        })?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(io::Error::other(format!(
+                "{} {}: {}",
+                request.method,
+                request.url,
+                stderr.trim()
+            )));
+        }
+
+        Response::parse(output.stdout)
+    }
+}
+
+pub(crate) struct Request<'a> {
+    pub(crate) method: &'static str,
+    /// Shows up in logs and errors, so it must not hold credentials
+    pub(crate) url: String,
+    /// Appended to the query of `url` only for `curl`
+    secret_query: Option<String>,
+    pub(crate) headers: Vec<(String, String)>,
+    pub(crate) body: Option<&'a [u8]>,
+}
+
+impl<'a> Request<'a> {
+    pub(crate) fn new(method: &'static str, url: impl Into<String>) -> Request<'a> {
+        Request {
+            method,
+            url: url.into(),
+            secret_query: None,
+            headers: vec![],
+            body: None,
+        }
+    }
+
+    pub(crate) fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    pub(crate) fn body(mut self, body: &'a [u8]) -> Self {
+        self.body = Some(body);
+        self
+    }
+
+    /// Add `query` to the query string, like a token that grants
+    /// access, without it being logged.
+    pub(crate) fn secret_query(mut self, query: impl Into<String>) -> Self {
+        self.secret_query = Some(query.into());
+        self
+    }
+
+    /// The URL to send the request to, with the secret query.
+    fn full_url(&self) -> String {
+        match &self.secret_query {
+            Some(query) if self.url.contains('?') => format!("{}&{}", self.url, query),
+            Some(query) => format!("{}?{}", self.url, query),
+            None => self.url.clone(),
+        }
+    }
+}
+
+pub(crate) struct Response {
+    pub(crate) status: u16,
+    pub(crate) headers: Vec<(String, String)>,
+    pub(crate) body: Vec<u8>,
+}
+
+impl Response {
+    /// Split the output of `curl --dump-header - --output -`.
+    fn parse(mut output: Vec<u8>) -> io::Result<Response> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid HTTP response");
+
+        let mut start = 0;
+        loop {
+            let end = output[start..]
+                .windows(4)
+                .position(|w| w == b"\r\n\r\n")
+                .map(|p| start + p)
+                .ok_or_else(invalid)?;
+            let head = String::from_utf8_lossy(&output[start..end]).into_owned();
+            start = end + 4;
+
+            let mut lines = head.split("\r\n");
+            let status: u16 = lines
+                .next()
+                .and_then(|l| l.split(' ').nth(1))
+                .and_then(|s| s.parse().ok())
+                .ok_or_else(invalid)?;
+            // informational responses are followed by the real one
+            if status < 200 {
+                continue;
+            }
+
+            let headers = lines
+                .filter_map(|l| l.split_once(':'))
+                .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+                .collect();
+            let body = output.split_off(start);
+
+            return Ok(Response {
+                status,
+                headers,
+                body,
+            });
+        }
+    }
+
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub(crate) fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    /// The body of a response to a request for `len` bytes from
+    /// `offset`, which servers that ignore the range send whole.
+    pub(crate) fn into_range(self, offset: u64, len: usize) -> crate::backends::Result<Vec<u8>> {
+        match self.status {
+            206 if self.body.len() == len => Ok(self.body),
+            200 => crate::backends::range_of(&self.body, offset, len),
+            _ => Err(self.error("range read").into()),
+        }
+    }
+
+    /// An error describing an unexpected response to `what`.
+    pub(crate) fn error(&self, what: &str) -> io::Error {
+        let body = String::from_utf8_lossy(&self.body[..self.body.len().min(512)]);
+        io::Error::other(format!(
+            "{} returned {}: {}",
+            what,
+            self.status,
+            body.trim()
+        ))
+    }
+}
+
+/// A curl configuration file, removed when dropped.
+struct ConfigFile(PathBuf);
+
+impl ConfigFile {
+    fn write(request: &Request) -> io::Result<ConfigFile> {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+        let path = std::env::temp_dir().join(format!(
+            "0s-curl-{}-{}",
+            process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(&path)?;
+        let config = ConfigFile(path);
+
+        writeln!(file, "url = {}", quote(&request.full_url()))?;
+        writeln!(file, "request = {}", quote(request.method))?;
+        // without this, curl waits for a `100 Continue` before large
+        // bodies
+        writeln!(file, "header = \"Expect:\"")?;
+        if request.body.is_some()
+            && !request
+                .headers
+                .iter()
+                .any(|(n, _)| n.eq_ignore_ascii_case("content-type"))
+        {
+            writeln!(file, "header = \"Content-Type: application/octet-stream\"")?;
+        }
+        for (name, value) in request.headers.iter() {
+            writeln!(file, "header = {}", quote(&format!("{}: {}", name, value)))?;
+        }
+
+        Ok(config)
+    }
+}
+
+impl Drop for ConfigFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+fn quote(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' | '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Percent-encode `s` as AWS and most other services expect in
+/// URLs, leaving `/` alone if `path` is set.
+pub(crate) fn uri_encode(s: &str, path: bool) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            b'/' if path => "/".into(),
+            b => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// A `Range` header value for `len` bytes from `offset`, which has
+/// to be more than 0.
+pub(crate) fn byte_range(offset: u64, len: usize) -> String {
+    debug_assert!(len > 0);
+    format!("bytes={}-{}", offset, offset + len as u64 - 1)
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// A plain HTTP server for the tests of the backends, answering
+/// requests with `respond`.
+#[cfg(test)]
+pub(crate) mod test_server {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::thread;
+
+    pub(crate) struct Received {
+        pub(crate) method: String,
+        pub(crate) path: String,
+        pub(crate) headers: Vec<(String, String)>,
+        pub(crate) body: Vec<u8>,
+    }
+
+    impl Received {
+        pub(crate) fn header(&self, name: &str) -> Option<&str> {
+            self.headers
+                .iter()
+                .find(|(n, _)| n.eq_ignore_ascii_case(name))
+                .map(|(_, v)| v.as_str())
+        }
+    }
+
+    /// Start the server, and return its base URL.
+    pub(crate) fn start(
+        respond: impl Fn(Received) -> (u16, Vec<(String, String)>, Vec<u8>) + Send + Sync + 'static,
+    ) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let respond = Arc::new(respond);
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let respond = respond.clone();
+                thread::spawn(move || {
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    let mut parts = line.split_whitespace();
+                    let method = parts.next().unwrap_or_default().to_string();
+                    let path = parts.next().unwrap_or_default().to_string();
+
+                    let mut headers = vec![];
+                    loop {
+                        let mut line = String::new();
+                        reader.read_line(&mut line).unwrap();
+                        match line.trim_end().split_once(':') {
+                            Some((n, v)) => headers.push((n.trim().into(), v.trim().into())),
+                            None => break,
+                        }
+                    }
+                    let received = Received {
+                        method,
+                        path,
+                        headers,
+                        body: vec![],
+                    };
+                    let len = received
+                        .header("content-length")
+                        .and_then(|l| l.parse().ok())
+                        .unwrap_or(0);
+                    let mut body = vec![0; len];
+                    reader.read_exact(&mut body).unwrap();
+
+                    let (status, headers, body) = respond(Received { body, ..received });
+                    write!(stream, "HTTP/1.1 {} X\r\n", status).unwrap();
+                    for (name, value) in headers {
+                        write!(stream, "{}: {}\r\n", name, value).unwrap();
+                    }
+                    write!(
+                        stream,
+                        "Content-Length: {}\r\nConnection: close\r\n\r\n",
+                        body.len()
+                    )
+                    .unwrap();
+                    stream.write_all(&body).unwrap();
+                });
+            }
+        });
+
+        url
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn requests_are_retried() {
+        use super::*;
+        use std::sync::atomic::AtomicUsize;
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let counter = attempts.clone();
+        let url = test_server::start(move |request| {
+            if counter.fetch_add(1, Ordering::SeqCst) == 0 {
+                return (503, vec![], vec![]);
+            }
+            let headers = vec![("X-Method".into(), request.method.clone())];
+            (200, headers, request.body)
+        });
+
+        let retry = Retry {
+            backoff: Duration::from_millis(1),
+            ..Retry::default()
+        };
+        let curl = Curl::default().retry(retry);
+        let body = vec![7; 100_000];
+        let response = curl
+            .send(&Request::new("PUT", format!("{}/x", url)).body(&body))
+            .unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("x-method"), Some("PUT"));
+        assert_eq!(response.body, body);
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+
+        let curl = curl.program("/nonexistent/curl");
+        assert!(curl.send(&Request::new("GET", url.clone())).is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+
+        assert!(retry.delay(40) <= retry.max_backoff);
+        let secret = Request::new("GET", format!("{}/x?a=1", url)).secret_query("sig=secret");
+        assert_eq!(secret.full_url(), format!("{}/x?a=1&sig=secret", url));
+        // curl runs, and fails
+        let e = curl.program("false").send(&secret).err().unwrap();
+        assert!(e.to_string().contains("/x?a=1"));
+        assert!(!e.to_string().contains("secret"));
+        assert_eq!(quote("a \"b\" \\"), "\"a \\\"b\\\" \\\\\"");
+    }
+}
@@ -0,0 +1,104 @@
+use crate::backends::{Backend, BackendError, Capabilities, Result, Retrieval};
+use crate::objects::{ObjectId, ReadObject, WriteObject};
+
+use std::collections::HashSet;
+use std::sync::Arc;
+
+/// Refuses to delete objects, or to overwrite the ones that are
+/// there, but those it's told can change, like the root of a stash.
+///
+/// Every write first checks that the object isn't there yet, which
+/// takes a read. Put it in front of the backend where the restricted
+/// side runs, like a server that clients write through, as a client
+/// with full credentials can always go around it.
+pub struct ImmutableBackend<B> {
+    inner: B,
+    mutable: HashSet<ObjectId>,
+}
+
+impl<B: Backend> ImmutableBackend<B> {
+    pub fn new(inner: B, mutable: impl IntoIterator<Item = ObjectId>) -> ImmutableBackend<B> {
+        ImmutableBackend {
+            inner,
+            mutable: mutable.into_iter().collect(),
+        }
+    }
+
+    fn check_new(&self, id: &ObjectId) -> Result<()> {
+        if self.mutable.contains(id) {
+            return Ok(());
+        }
+        match self.inner.read_range(id, 0, 1) {
+            Err(BackendError::NoObjectFound) => Ok(()),
+            Ok(_) | Err(BackendError::Archived) => Err(BackendError::Unsupported(
+                "overwrite objects of an immutable stash",
+            )),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl<B: Backend> Backend for ImmutableBackend<B> {
+    fn write_object(&self, object: &WriteObject) -> Result<()> {
+        self.check_new(&object.id)?;
+        self.inner.write_object(object)
+    }
+
+    fn write_data_object(&self, object: &WriteObject) -> Result<()> {
+        self.check_new(&object.id)?;
+        self.inner.write_data_object(object)
+    }
+
+    fn retrieve_object(&self, id: &ObjectId) -> Result<Retrieval> {
+        self.inner.retrieve_object(id)
+    }
+
+    fn read_object(&self, id: &ObjectId) -> Result<Arc<ReadObject>> {
+        self.inner.read_object(id)
+    }
+
+    fn read_range(&self, id: &ObjectId, offset: u64, len: usize) -> Result<Vec<u8>> {
+        self.inner.read_range(id, offset, len)
+    }
+
+    fn delete_object(&self, _id: &ObjectId) -> Result<()> {
+        Err(BackendError::Unsupported(
+            "delete objects of an immutable stash",
+        ))
+    }
+
+    fn list_objects(&self) -> Result<Vec<ObjectId>> {
+        self.inner.list_objects()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.inner.capabilities()
+    }
+
+    fn probe(&self) -> Result<Capabilities> {
+        self.inner.probe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn objects_are_only_added() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use crate::objects::{BlockBuffer, Object};
+
+        let inner = MemoryBackend::default();
+        let (root, data) = (ObjectId::from_bytes([1; 32]), ObjectId::from_bytes([2; 32]));
+        let backend = ImmutableBackend::new(inner.clone(), vec![root]);
+        let object = |id| Object::with_id(id, BlockBuffer::from(b"object".to_vec()));
+
+        backend.write_object(&object(data)).unwrap();
+        assert!(backend.write_data_object(&object(data)).is_err());
+        assert!(backend.delete_object(&data).is_err());
+        assert!(inner.contains(&data));
+
+        backend.write_object(&object(root)).unwrap();
+        backend.write_object(&object(root)).unwrap();
+    }
+}
@@ -0,0 +1,237 @@
+use crate::backends::{Backend, BackendError, Capabilities, Result, Retrieval};
+use crate::objects::{ObjectId, ReadObject, WriteObject};
+
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::thread;
+
+/// How many of the backends of a `MirrorBackend` have to store an
+/// object for the write to succeed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Quorum {
+    #[default]
+    All,
+    Any,
+    AtLeast(usize),
+}
+
+impl Quorum {
+    fn required(&self, backends: usize) -> usize {
+        match self {
+            Quorum::All => backends,
+            Quorum::Any => 1,
+            Quorum::AtLeast(n) => (*n).clamp(1, backends),
+        }
+    }
+}
+
+/// Writes every object to all of its backends at the same time, and
+/// reads from the first one that has it.
+///
+/// Failed writes are logged, and only fail the mirror if fewer
+/// backends succeeded than the `Quorum` requires.
+#[derive(Clone)]
+pub struct MirrorBackend {
+    backends: Vec<Arc<dyn Backend>>,
+    quorum: Quorum,
+}
+
+impl MirrorBackend {
+    /// Mirror to `backends`, which are read in this order.
+    pub fn new(backends: Vec<Arc<dyn Backend>>) -> MirrorBackend {
+        MirrorBackend {
+            backends,
+            quorum: Quorum::default(),
+        }
+    }
+
+    /// How many backends have to succeed, all by default.
+    pub fn quorum(mut self, quorum: Quorum) -> MirrorBackend {
+        self.quorum = quorum;
+        self
+    }
+
+    fn write_all(&self, write: impl Fn(&dyn Backend) -> Result<()> + Sync) -> Result<()> {
+        let results = thread::scope(|s| {
+            let write = &write;
+            self.backends
+                .iter()
+                .map(|backend| s.spawn(move || write(backend.as_ref())))
+                .collect::<Vec<_>>()
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+
+        let mut succeeded = 0;
+        let mut error = None;
+        for (i, result) in results.into_iter().enumerate() {
+            match result {
+                Ok(()) => succeeded += 1,
+                Err(e) => {
+                    warn!("writing to mirror {}: {}", i, e);
+                    error.get_or_insert(e);
+                }
+            }
+        }
+
+        match error {
+            Some(e) if succeeded < self.quorum.required(self.backends.len()) => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Backend for MirrorBackend {
+    fn write_object(&self, object: &WriteObject) -> Result<()> {
+        self.write_all(|backend| backend.write_object(object))
+    }
+
+    fn write_data_object(&self, object: &WriteObject) -> Result<()> {
+        self.write_all(|backend| backend.write_data_object(object))
+    }
+
+    /// Ready as soon as any of the backends is.
+    fn retrieve_object(&self, id: &ObjectId) -> Result<Retrieval> {
+        let mut result = Err(BackendError::NoObjectFound);
+        for backend in self.backends.iter() {
+            match backend.retrieve_object(id) {
+                Ok(Retrieval::Ready) => return Ok(Retrieval::Ready),
+                Err(BackendError::NoObjectFound) => {}
+                other => result = other,
+            }
+        }
+        result
+    }
+
+    fn read_object(&self, id: &ObjectId) -> Result<Arc<ReadObject>> {
+        let mut error = BackendError::NoObjectFound;
+        for backend in self.backends.iter() {
+            match backend.read_object(id) {
+                Ok(object) => return Ok(object),
+                // a missing object is the least interesting failure
+                Err(BackendError::NoObjectFound) => {}
+                Err(e) => error = e,
+            }
+        }
+        Err(error)
+    }
+
+    fn read_range(&self, id: &ObjectId, offset: u64, len: usize) -> Result<Vec<u8>> {
+        let mut error = BackendError::NoObjectFound;
+        for backend in self.backends.iter() {
+            match backend.read_range(id, offset, len) {
+                Ok(data) => return Ok(data),
+                Err(BackendError::NoObjectFound) => {}
+                Err(e) => error = e,
+            }
+        }
+        Err(error)
+    }
+
+    /// Deleted from every backend that has it, failing with
+    /// `NoObjectFound` only if none did.
+    fn delete_object(&self, id: &ObjectId) -> Result<()> {
+        let mut deleted = false;
+        for backend in self.backends.iter() {
+            match backend.delete_object(id) {
+                Ok(()) => deleted = true,
+                Err(BackendError::NoObjectFound) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if deleted {
+            Ok(())
+        } else {
+            Err(BackendError::NoObjectFound)
+        }
+    }
+
+    /// The objects that are on any of the backends.
+    fn list_objects(&self) -> Result<Vec<ObjectId>> {
+        let mut all = HashSet::new();
+        for backend in self.backends.iter() {
+            all.extend(backend.list_objects()?);
+        }
+        Ok(all.into_iter().collect())
+    }
+
+    /// What all of the backends support.
+    fn capabilities(&self) -> Capabilities {
+        self.backends
+            .iter()
+            .map(|b| b.capabilities())
+            .fold(ALL, intersect)
+    }
+
+    /// Probe every backend, failing if any of them does.
+    fn probe(&self) -> Result<Capabilities> {
+        let mut capabilities = ALL;
+        for backend in self.backends.iter() {
+            capabilities = intersect(capabilities, backend.probe()?);
+        }
+        Ok(capabilities)
+    }
+}
+
+const ALL: Capabilities = Capabilities {
+    writable: true,
+    range_reads: true,
+    listing: true,
+};
+
+fn intersect(a: Capabilities, b: Capabilities) -> Capabilities {
+    Capabilities {
+        writable: a.writable && b.writable,
+        range_reads: a.range_reads && b.range_reads,
+        listing: a.listing && b.listing,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn writes_succeed_with_a_quorum() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use crate::objects::{BlockBuffer, Object};
+
+        struct Broken;
+        impl Backend for Broken {
+            fn write_object(&self, _object: &WriteObject) -> Result<()> {
+                Err(BackendError::Create)
+            }
+            fn read_object(&self, _id: &ObjectId) -> Result<Arc<ReadObject>> {
+                Err(BackendError::Create)
+            }
+        }
+
+        let local = MemoryBackend::default();
+        let offsite = MemoryBackend::default();
+        let backends: Vec<Arc<dyn Backend>> = vec![
+            Arc::new(Broken),
+            Arc::new(local.clone()),
+            Arc::new(offsite.clone()),
+        ];
+
+        let mut object = Object::new(BlockBuffer::default());
+        object.set_id(ObjectId::from_bytes([1; 32]));
+        object.buffer.as_mut()[0] = 1;
+
+        let mirror = MirrorBackend::new(backends);
+        assert!(mirror.write_object(&object).is_err());
+        let mirror = mirror.quorum(Quorum::AtLeast(2));
+        mirror.write_object(&object).unwrap();
+        assert!(local.contains(&object.id) && offsite.contains(&object.id));
+
+        local.remove(&object.id);
+        let read = mirror.read_object(&object.id).unwrap();
+        assert_eq!(read.buffer.as_ref(), object.buffer.as_ref());
+
+        offsite.remove(&object.id);
+        assert!(matches!(
+            mirror.read_object(&object.id),
+            Err(BackendError::Create)
+        ));
+    }
+}
@@ -0,0 +1,78 @@
+use crate::objects::ObjectId;
+
+/// Names objects in the storage of a backend, like the keys of a
+/// bucket, or the paths in a directory, with `/` between their parts.
+///
+/// Stashes that share a bucket or a directory each need names of
+/// their own, which is usually a prefix, so they don't collide, and
+/// so a bucket policy can scope credentials to one stash. A name
+/// must only depend on the id, and never change for a stash, or its
+/// objects can't be found again.
+pub trait ObjectNames: Send + Sync {
+    fn name(&self, id: &ObjectId) -> String;
+
+    /// What all the names start with, where listing the objects
+    /// starts.
+    fn prefix(&self) -> &str {
+        ""
+    }
+}
+
+/// The id of the object named `name`, if it is a name of `names`.
+#[cfg(any(feature = "fs", feature = "cloud"))]
+pub(crate) fn id_of(names: &dyn ObjectNames, name: &str) -> Option<ObjectId> {
+    let id = ObjectId::from_hex(name.get(name.len().checked_sub(64)?..)?)?;
+    Some(id).filter(|id| names.name(id) == name)
+}
+
+/// `<prefix><id>`, the names of objects in buckets.
+#[derive(Clone, Debug, Default)]
+pub struct Prefixed(pub String);
+
+impl ObjectNames for Prefixed {
+    fn name(&self, id: &ObjectId) -> String {
+        format!("{}{}", self.0, id.to_string())
+    }
+
+    fn prefix(&self) -> &str {
+        &self.0
+    }
+}
+
+/// `<prefix>ab/cd/<id>`, by the first bytes of the id, the paths of
+/// objects in directories.
+#[derive(Clone, Debug, Default)]
+pub struct Nested(pub String);
+
+impl ObjectNames for Nested {
+    fn name(&self, id: &ObjectId) -> String {
+        let id = id.to_string();
+        format!("{}{}/{}/{}", self.0, &id[..2], &id[2..4], id)
+    }
+
+    fn prefix(&self) -> &str {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn names_have_their_prefix() {
+        use super::*;
+
+        let id = ObjectId::from_bytes([0xab; 32]);
+        let hex = id.to_string();
+        assert_eq!(Prefixed::default().name(&id), hex);
+        assert_eq!(Prefixed("home/".into()).name(&id), format!("home/{}", hex));
+        assert_eq!(
+            Nested("work/".into()).name(&id),
+            format!("work/ab/ab/{}", hex)
+        );
+
+        let nested = Nested("work/".into());
+        assert_eq!(id_of(&nested, &nested.name(&id)), Some(id));
+        assert_eq!(id_of(&nested, &format!("home/ab/ab/{}", hex)), None);
+        assert_eq!(id_of(&nested, "work/ab/ab/.tmp"), None);
+    }
+}
@@ -0,0 +1,104 @@
+use crate::backends::{range_of, Backend, BackendError, Capabilities, Result};
+use crate::gateway::http::Response;
+use crate::objects::{Object, ObjectId, ReadBuffer, ReadObject, WriteObject};
+
+use std::io::{self, BufReader, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+
+/// Reads objects from a `Gateway` over plain HTTP.
+///
+/// Objects are encrypted, but anyone on the network path can see
+/// which objects are read. The gateway is read-only, so are remotes.
+#[derive(Clone)]
+pub struct Remote {
+    address: Arc<String>,
+}
+
+impl Remote {
+    /// Connect to the gateway listening on `address`, like
+    /// `backup.local:8080`.
+    pub fn new(address: impl Into<String>) -> Remote {
+        Remote {
+            address: Arc::new(address.into()),
+        }
+    }
+
+    /// `GET` `path` with `headers`, each terminated by CRLF.
+    pub(crate) fn request(&self, path: &str, headers: &str) -> io::Result<Response> {
+        self.method("GET", path, headers)
+    }
+
+    pub(crate) fn method(&self, method: &str, path: &str, headers: &str) -> io::Result<Response> {
+        self.send(method, path, headers, &[])
+    }
+
+    /// Send `body` with the request too.
+    pub(crate) fn send(
+        &self,
+        method: &str,
+        path: &str,
+        headers: &str,
+        body: &[u8],
+    ) -> io::Result<Response> {
+        let mut stream = TcpStream::connect(self.address.as_str())?;
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\nHost: {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n",
+            method,
+            path,
+            self.address,
+            headers,
+            body.len()
+        )?;
+        stream.write_all(body)?;
+        stream.flush()?;
+
+        Response::read(&mut BufReader::new(stream))
+    }
+}
+
+impl Backend for Remote {
+    fn write_object(&self, _object: &WriteObject) -> Result<()> {
+        Err(BackendError::ReadOnly)
+    }
+
+    fn read_object(&self, id: &ObjectId) -> Result<Arc<ReadObject>> {
+        let response = self.request(&format!("/objects/{}", id.to_string()), "")?;
+
+        match response.status {
+            200 => Ok(Arc::new(Object::with_id(
+                *id,
+                ReadBuffer::new(response.body),
+            ))),
+            404 => Err(BackendError::NoObjectFound),
+            status => Err(io::Error::other(format!("gateway returned {}", status)).into()),
+        }
+    }
+
+    fn read_range(&self, id: &ObjectId, offset: u64, len: usize) -> Result<Vec<u8>> {
+        if len == 0 {
+            return Ok(vec![]);
+        }
+        let range = format!("Range: bytes={}-{}\r\n", offset, offset + len as u64 - 1);
+        let response = self.request(&format!("/objects/{}", id.to_string()), &range)?;
+
+        match response.status {
+            206 if response.body.len() == len => Ok(response.body),
+            // gateways that ignore the range send all of it
+            200 => range_of(&response.body, offset, len),
+            // cut short by the end of the object
+            206 | 416 => range_of(&[], offset, len),
+            404 => Err(BackendError::NoObjectFound),
+            status => Err(io::Error::other(format!("gateway returned {}", status)).into()),
+        }
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            writable: false,
+            range_reads: true,
+            listing: false,
+        }
+    }
+}
@@ -0,0 +1,203 @@
+use crate::backends::{Backend, BackendError, Capabilities, Result, Retrieval};
+use crate::objects::{ObjectId, ReadObject, WriteObject};
+
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// How often, and how patiently, failed operations are retried.
+#[derive(Clone, Copy, Debug)]
+pub struct Retry {
+    /// Attempts in total, including the first one
+    pub attempts: u32,
+    /// Wait before the first retry, doubled for every further one
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+    /// The part of every wait that's random, between 0 and 1, so
+    /// clients failing together don't retry together
+    pub jitter: f64,
+}
+
+impl Default for Retry {
+    fn default() -> Retry {
+        Retry {
+            attempts: 5,
+            backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            jitter: 0.5,
+        }
+    }
+}
+
+impl Retry {
+    /// No retries, only a single attempt.
+    pub fn never() -> Retry {
+        Retry {
+            attempts: 1,
+            ..Retry::default()
+        }
+    }
+
+    /// How long to wait before retry number `retry`, starting at 1.
+    pub fn delay(&self, retry: u32) -> Duration {
+        let wait = self
+            .backoff
+            .saturating_mul(1 << retry.saturating_sub(1).min(16))
+            .min(self.max_backoff);
+
+        let mut random = [0; 2];
+        getrandom::getrandom(&mut random).unwrap();
+        let random = f64::from(u16::from_le_bytes(random)) / f64::from(u16::MAX);
+        wait.mul_f64(1.0 - self.jitter.clamp(0.0, 1.0) * random)
+    }
+}
+
+/// Retries the operations of another backend that fail with errors
+/// that may be transient.
+///
+/// Which errors are worth retrying is decided by
+/// `BackendError::is_transient` unless set otherwise.
+#[derive(Clone)]
+pub struct RetryBackend<B> {
+    inner: B,
+    retry: Retry,
+    retryable: fn(&BackendError) -> bool,
+}
+
+impl<B: Backend> RetryBackend<B> {
+    pub fn new(inner: B) -> RetryBackend<B> {
+        RetryBackend {
+            inner,
+            retry: Retry::default(),
+            retryable: BackendError::is_transient,
+        }
+    }
+
+    pub fn retry(mut self, retry: Retry) -> RetryBackend<B> {
+        self.retry = retry;
+        self
+    }
+
+    /// Retry the errors `retryable` returns `true` for.
+    pub fn retryable(mut self, retryable: fn(&BackendError) -> bool) -> RetryBackend<B> {
+        self.retryable = retryable;
+        self
+    }
+
+    fn run<T>(&self, what: &str, operation: impl Fn() -> Result<T>) -> Result<T> {
+        let mut retry = 0;
+        loop {
+            let error = match operation() {
+                Ok(value) => return Ok(value),
+                Err(e) => e,
+            };
+
+            retry += 1;
+            if !(self.retryable)(&error) || retry >= self.retry.attempts {
+                return Err(error);
+            }
+
+            let delay = self.retry.delay(retry);
+            debug!("retrying {} in {:?}, after {}", what, delay, error);
+            thread::sleep(delay);
+        }
+    }
+}
+
+impl<B: Backend> Backend for RetryBackend<B> {
+    fn write_object(&self, object: &WriteObject) -> Result<()> {
+        self.run("write", || self.inner.write_object(object))
+    }
+
+    fn read_object(&self, id: &ObjectId) -> Result<Arc<ReadObject>> {
+        self.run("read", || self.inner.read_object(id))
+    }
+
+    fn read_range(&self, id: &ObjectId, offset: u64, len: usize) -> Result<Vec<u8>> {
+        self.run("read", || self.inner.read_range(id, offset, len))
+    }
+
+    fn write_data_object(&self, object: &WriteObject) -> Result<()> {
+        self.run("write", || self.inner.write_data_object(object))
+    }
+
+    fn retrieve_object(&self, id: &ObjectId) -> Result<Retrieval> {
+        self.run("retrieve", || self.inner.retrieve_object(id))
+    }
+
+    fn delete_object(&self, id: &ObjectId) -> Result<()> {
+        self.run("delete", || self.inner.delete_object(id))
+    }
+
+    fn list_objects(&self) -> Result<Vec<ObjectId>> {
+        self.run("list", || self.inner.list_objects())
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.inner.capabilities()
+    }
+
+    fn probe(&self) -> Result<Capabilities> {
+        self.run("probe", || self.inner.probe())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn transient_errors_are_retried() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use crate::objects::{BlockBuffer, Object};
+        use std::io;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        struct Flaky {
+            inner: MemoryBackend,
+            failures: AtomicUsize,
+            calls: AtomicUsize,
+        }
+        impl Backend for Flaky {
+            fn write_object(&self, object: &WriteObject) -> Result<()> {
+                self.calls.fetch_add(1, Ordering::SeqCst);
+                if self.failures.load(Ordering::SeqCst) > 0 {
+                    self.failures.fetch_sub(1, Ordering::SeqCst);
+                    return Err(io::Error::from(io::ErrorKind::ConnectionReset).into());
+                }
+                self.inner.write_object(object)
+            }
+            fn read_object(&self, id: &ObjectId) -> Result<Arc<ReadObject>> {
+                self.calls.fetch_add(1, Ordering::SeqCst);
+                self.inner.read_object(id)
+            }
+        }
+
+        let retry = Retry {
+            attempts: 3,
+            backoff: Duration::from_millis(1),
+            ..Retry::default()
+        };
+        let flaky = Arc::new(Flaky {
+            inner: MemoryBackend::default(),
+            failures: AtomicUsize::new(2),
+            calls: AtomicUsize::new(0),
+        });
+        let backend = RetryBackend::new(flaky.clone()).retry(retry);
+
+        let mut object = Object::new(BlockBuffer::default());
+        object.set_id(ObjectId::from_bytes([1; 32]));
+        backend.write_object(&object).unwrap();
+        assert!(flaky.inner.contains(&object.id));
+        assert_eq!(flaky.calls.load(Ordering::SeqCst), 3);
+
+        // a missing object won't show up by asking again
+        assert!(matches!(
+            backend.read_object(&ObjectId::from_bytes([2; 32])),
+            Err(BackendError::NoObjectFound)
+        ));
+        assert_eq!(flaky.calls.load(Ordering::SeqCst), 4);
+
+        assert!(retry.delay(40) <= retry.max_backoff);
+        assert!(retry.delay(1) >= retry.backoff / 2);
+    }
+}
@@ -0,0 +1,265 @@
+use crate::backends::{Backend, BackendError, Capabilities, Result};
+use crate::objects::{Object, ObjectId, ReadBuffer, ReadObject, WriteObject};
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process::{self, Command, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+
+/// Stores objects in a directory of a server reachable over SSH,
+/// through the `sftp` program of OpenSSH.
+///
+/// Authentication is left to `ssh`, so the agent, key files and
+/// `~/.ssh/config` work as usual. All transfers share one connection,
+/// which is kept open for a minute after the last one.
+#[derive(Clone)]
+pub struct SftpBackend {
+    program: String,
+    destination: String,
+    path: String,
+    port: Option<u16>,
+    identity: Option<String>,
+    transfers: Arc<Transfers>,
+    created: Arc<AtomicBool>,
+}
+
+/// Limits the transfers running at the same time.
+struct Transfers {
+    limit: AtomicUsize,
+    running: Mutex<usize>,
+    finished: Condvar,
+}
+
+impl SftpBackend {
+    /// Objects in `path` on `destination`, like `me@host`. The
+    /// directory is created on the first write.
+    pub fn new(destination: impl Into<String>, path: impl Into<String>) -> SftpBackend {
+        SftpBackend {
+            program: "sftp".into(),
+            destination: destination.into(),
+            path: path.into().trim_end_matches('/').to_string(),
+            port: None,
+            identity: None,
+            transfers: Arc::new(Transfers {
+                limit: AtomicUsize::new(4),
+                running: Mutex::new(0),
+                finished: Condvar::new(),
+            }),
+            created: Arc::default(),
+        }
+    }
+
+    /// The `sftp` executable to run.
+    pub fn program(mut self, program: impl Into<String>) -> SftpBackend {
+        self.program = program.into();
+        self
+    }
+
+    pub fn port(mut self, port: u16) -> SftpBackend {
+        self.port = Some(port);
+        self
+    }
+
+    /// Authenticate with the private key in `path`, instead of the
+    /// ones of the agent.
+    pub fn identity(mut self, path: impl Into<String>) -> SftpBackend {
+        self.identity = Some(path.into());
+        self
+    }
+
+    /// How many objects are transferred at the same time, 4 by
+    /// default. The server's `MaxSessions` should allow as many.
+    pub fn concurrency(self, transfers: usize) -> SftpBackend {
+        self.transfers
+            .limit
+            .store(transfers.max(1), Ordering::Relaxed);
+        self
+    }
+
+    /// Run the sftp `commands` on the server.
+    fn batch(&self, commands: &str) -> Result<()> {
+        let _permit = self.transfers.acquire();
+
+        let control = std::env::temp_dir().join("0s-ssh-%C");
+        let mut command = Command::new(&self.program);
+        command
+            .args(["-q", "-b", "-", "-o", "ControlMaster=auto"])
+            .arg("-o")
+            .arg(format!("ControlPath={}", control.display()))
+            .args(["-o", "ControlPersist=60"]);
+        if let Some(port) = self.port {
+            command.arg("-P").arg(port.to_string());
+        }
+        if let Some(identity) = &self.identity {
+            command.arg("-i").arg(identity);
+        }
+        command.arg(&self.destination);
+
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        child.stdin.take().unwrap().write_all(commands.as_bytes())?;
+        let output = child.wait_with_output()?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            if stderr.contains("not found") || stderr.contains("No such file") {
+                return Err(BackendError::NoObjectFound);
+            }
+            return Err(io::Error::other(format!("sftp failed: {}", stderr.trim())).into());
+        }
+        Ok(())
+    }
+
+    fn remote_path(&self, id: &ObjectId) -> String {
+        quote(&format!("{}/{}", self.path, id.to_string()))
+    }
+}
+
+impl Backend for SftpBackend {
+    fn write_object(&self, object: &WriteObject) -> Result<()> {
+        let local = Scratch::new();
+        fs::write(&local.0, object.buffer.as_ref())?;
+
+        let mut commands = String::new();
+        if !self.created.load(Ordering::Relaxed) {
+            // `-` ignores the error if it exists
+            commands.push_str(&format!("-mkdir {}\n", quote(&self.path)));
+        }
+        commands.push_str(&format!(
+            "put {} {}\n",
+            quote(&local.0.to_string_lossy()),
+            self.remote_path(&object.id)
+        ));
+
+        self.batch(&commands)?;
+        self.created.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn read_object(&self, id: &ObjectId) -> Result<Arc<ReadObject>> {
+        let local = Scratch::new();
+        self.batch(&format!(
+            "get {} {}\n",
+            self.remote_path(id),
+            quote(&local.0.to_string_lossy())
+        ))?;
+
+        let data = fs::read(&local.0)?;
+        Ok(Arc::new(Object::with_id(*id, ReadBuffer::new(data))))
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            writable: true,
+            range_reads: false,
+            listing: true,
+        }
+    }
+}
+
+impl Transfers {
+    fn acquire(&self) -> Permit<'_> {
+        let mut running = self.running.lock().unwrap();
+        while *running >= self.limit.load(Ordering::Relaxed) {
+            running = self.finished.wait(running).unwrap();
+        }
+
+        *running += 1;
+        Permit(self)
+    }
+}
+
+struct Permit<'a>(&'a Transfers);
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        *self.0.running.lock().unwrap() -= 1;
+        self.0.finished.notify_one();
+    }
+}
+
+/// A local file for an object in transfer, removed when dropped.
+struct Scratch(PathBuf);
+
+impl Scratch {
+    fn new() -> Scratch {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+        let path = std::env::temp_dir().join(format!(
+            "0s-sftp-{}-{}",
+            process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        Scratch(path)
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// Quote a path for an sftp batch.
+fn quote(path: &str) -> String {
+    format!("\"{}\"", path.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    #[test]
+    fn objects_are_transferred_by_sftp() {
+        use super::*;
+        use crate::objects::BlockBuffer;
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join("0s_test_sftp");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        // stands in for sftp, with the remote side in `dir`
+        let program = dir.join("sftp");
+        fs::write(
+            &program,
+            format!(
+                "#!/bin/sh\n\
+                 cd {}\n\
+                 while read -r line; do\n\
+                 eval set -- $line\n\
+                 case \"$1\" in\n\
+                 -mkdir) mkdir -p \"./$2\" ;;\n\
+                 put) cp \"$2\" \"./$3\" ;;\n\
+                 get) cp \"./$2\" \"$3\" 2>/dev/null || {{ echo \"File $2 not found.\" >&2; exit 1; }} ;;\n\
+                 esac\n\
+                 done\n",
+                dir.display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&program, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let sftp = SftpBackend::new("me@host", "/stash/")
+            .program(program.to_string_lossy())
+            .concurrency(2);
+
+        let mut object = Object::new(BlockBuffer::default());
+        object.set_id(ObjectId::from_bytes([1; 32]));
+        object.buffer.as_mut()[0] = 1;
+        sftp.write_object(&object).unwrap();
+        assert!(dir.join("stash").join(object.id.to_string()).exists());
+
+        let read = sftp.read_object(&object.id).unwrap();
+        assert_eq!(read.buffer.as_ref(), object.buffer.as_ref());
+        assert!(matches!(
+            sftp.read_object(&ObjectId::from_bytes([9; 32])),
+            Err(BackendError::NoObjectFound)
+        ));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
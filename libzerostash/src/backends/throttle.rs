@@ -0,0 +1,301 @@
+#[cfg(feature = "async")]
+use crate::backends::{AsyncBackend, BoxFuture};
+use crate::backends::{Backend, BackendError, Capabilities, Result, Retrieval};
+use crate::objects::{ObjectId, ReadObject, WriteObject};
+use crate::progress::Progress;
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Limits on the bytes per second written to and read from a
+/// backend, which can be changed while objects are transferred.
+///
+/// Clones share the same limits.
+#[derive(Clone, Default)]
+pub struct Throttle(Arc<Directions>);
+
+#[derive(Default)]
+struct Directions {
+    upload: Mutex<Bucket>,
+    download: Mutex<Bucket>,
+}
+
+/// A token bucket. Transfers may take more than is available, and
+/// wait until the debt is paid off.
+#[derive(Default)]
+struct Bucket {
+    limit: Option<u64>,
+    available: f64,
+    updated: Option<Instant>,
+}
+
+impl Throttle {
+    /// No limits.
+    pub fn new() -> Throttle {
+        Throttle::default()
+    }
+
+    /// Write at most `limit` bytes per second, or as fast as
+    /// possible if `None`.
+    pub fn set_upload(&self, limit: Option<u64>) {
+        self.0.upload.lock().unwrap().set_limit(limit);
+    }
+
+    /// Read at most `limit` bytes per second, or as fast as possible
+    /// if `None`.
+    pub fn set_download(&self, limit: Option<u64>) {
+        self.0.download.lock().unwrap().set_limit(limit);
+    }
+
+    pub fn upload(&self) -> Option<u64> {
+        self.0.upload.lock().unwrap().limit
+    }
+
+    pub fn download(&self) -> Option<u64> {
+        self.0.download.lock().unwrap().limit
+    }
+
+    fn wait(bucket: &Mutex<Bucket>, bytes: usize) {
+        let wait = bucket.lock().unwrap().take(bytes as f64);
+        if wait > Duration::ZERO {
+            thread::sleep(wait);
+        }
+    }
+
+    /// Like `wait`, without blocking the thread of an async runtime.
+    #[cfg(feature = "async")]
+    async fn sleep(bucket: &Mutex<Bucket>, bytes: usize) {
+        let wait = bucket.lock().unwrap().take(bytes as f64);
+        if wait > Duration::ZERO {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+impl Bucket {
+    fn set_limit(&mut self, limit: Option<u64>) {
+        self.limit = limit.filter(|l| *l > 0);
+        // don't let transfers under the old limit hold up the new one
+        self.available = self.available.max(0.0);
+        self.updated = None;
+    }
+
+    /// Take `bytes`, returning how long to wait for them.
+    fn take(&mut self, bytes: f64) -> Duration {
+        let limit = match self.limit {
+            Some(limit) => limit as f64,
+            None => return Duration::ZERO,
+        };
+
+        // up to a second worth of bytes can be sent at once
+        let now = Instant::now();
+        let elapsed = self.updated.map_or(1.0, |t| (now - t).as_secs_f64());
+        self.available = (self.available + elapsed * limit).min(limit) - bytes;
+        self.updated = Some(now);
+
+        if self.available < 0.0 {
+            Duration::from_secs_f64(-self.available / limit)
+        } else {
+            Duration::ZERO
+        }
+    }
+}
+
+/// Holds up the transfers of another backend to keep within the
+/// limits of a `Throttle`.
+///
+/// Every stash sends its requests through one, so this is also where
+/// they're traced, each in a span with the id of the object and how
+/// many bytes it moves, and where their errors are reported.
+#[derive(Clone)]
+pub struct ThrottledBackend<B> {
+    inner: B,
+    throttle: Throttle,
+    progress: Option<Arc<dyn Progress>>,
+}
+
+impl<B> ThrottledBackend<B> {
+    pub fn new(inner: B, throttle: Throttle) -> ThrottledBackend<B> {
+        ThrottledBackend {
+            inner,
+            throttle,
+            progress: None,
+        }
+    }
+
+    /// Report the requests that fail to `progress`.
+    pub fn report_to(mut self, progress: Arc<dyn Progress>) -> ThrottledBackend<B> {
+        self.progress = Some(progress);
+        self
+    }
+
+    pub fn throttle(&self) -> &Throttle {
+        &self.throttle
+    }
+
+    fn report<T>(&self, operation: &'static str, result: Result<T>) -> Result<T> {
+        if let (Err(e), Some(progress)) = (&result, &self.progress) {
+            // objects asked for that aren't there, or not yet, are
+            // answers rather than failures
+            if !matches!(
+                e,
+                BackendError::NoObjectFound | BackendError::Archived | BackendError::Unsupported(_)
+            ) {
+                progress.backend_error(operation);
+            }
+        }
+        result
+    }
+}
+
+impl<B: Backend> Backend for ThrottledBackend<B> {
+    fn write_object(&self, object: &WriteObject) -> Result<()> {
+        let size = object.buffer.as_ref().len();
+        span!("write_object", object = %object.id.to_string(), bytes = size);
+        Throttle::wait(&self.throttle.0.upload, size);
+        self.report("write", self.inner.write_object(object))
+    }
+
+    fn write_data_object(&self, object: &WriteObject) -> Result<()> {
+        let size = object.buffer.as_ref().len();
+        span!("write_data_object", object = %object.id.to_string(), bytes = size);
+        Throttle::wait(&self.throttle.0.upload, size);
+        self.report("write", self.inner.write_data_object(object))
+    }
+
+    fn retrieve_object(&self, id: &ObjectId) -> Result<Retrieval> {
+        span!("retrieve_object", object = %id.to_string());
+        self.report("retrieve", self.inner.retrieve_object(id))
+    }
+
+    fn read_object(&self, id: &ObjectId) -> Result<Arc<ReadObject>> {
+        span!("read_object", object = %id.to_string());
+        // the size is only known once it's read
+        let object = self.report("read", self.inner.read_object(id))?;
+        let size = object.buffer.as_ref().len();
+        event!(bytes = size, "read");
+        Throttle::wait(&self.throttle.0.download, size);
+        Ok(object)
+    }
+
+    fn read_range(&self, id: &ObjectId, offset: u64, len: usize) -> Result<Vec<u8>> {
+        span!("read_range", object = %id.to_string(), offset, bytes = len);
+        let data = self.report("read", self.inner.read_range(id, offset, len))?;
+        Throttle::wait(&self.throttle.0.download, data.len());
+        Ok(data)
+    }
+
+    fn delete_object(&self, id: &ObjectId) -> Result<()> {
+        span!("delete_object", object = %id.to_string());
+        self.report("delete", self.inner.delete_object(id))
+    }
+
+    fn list_objects(&self) -> Result<Vec<ObjectId>> {
+        self.report("list", self.inner.list_objects())
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.inner.capabilities()
+    }
+
+    fn probe(&self) -> Result<Capabilities> {
+        self.inner.probe()
+    }
+}
+
+// spans can't be held across awaits, so async transfers go without
+#[cfg(feature = "async")]
+impl<A: AsyncBackend> AsyncBackend for ThrottledBackend<A> {
+    fn write_object<'a>(&'a self, object: &'a WriteObject) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let size = object.buffer.as_ref().len();
+            Throttle::sleep(&self.throttle.0.upload, size).await;
+            self.report("write", self.inner.write_object(object).await)
+        })
+    }
+
+    fn write_data_object<'a>(&'a self, object: &'a WriteObject) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let size = object.buffer.as_ref().len();
+            Throttle::sleep(&self.throttle.0.upload, size).await;
+            self.report("write", self.inner.write_data_object(object).await)
+        })
+    }
+
+    fn read_object<'a>(&'a self, id: &'a ObjectId) -> BoxFuture<'a, Result<Arc<ReadObject>>> {
+        Box::pin(async move {
+            let object = self.report("read", self.inner.read_object(id).await)?;
+            Throttle::sleep(&self.throttle.0.download, object.buffer.as_ref().len()).await;
+            Ok(object)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn transfers_are_held_up() {
+        use super::*;
+
+        let mut bucket = Bucket::default();
+        assert_eq!(bucket.take(1e9), Duration::ZERO);
+
+        bucket.set_limit(Some(1000));
+        // a second worth of bytes goes through at once
+        assert_eq!(bucket.take(1000.0), Duration::ZERO);
+        let wait = bucket.take(500.0);
+        assert!(wait > Duration::from_millis(450) && wait <= Duration::from_millis(500));
+        let wait = bucket.take(500.0);
+        assert!(wait > Duration::from_millis(950) && wait <= Duration::from_secs(1));
+
+        bucket.set_limit(None);
+        assert_eq!(bucket.take(1e9), Duration::ZERO);
+
+        let throttle = Throttle::new();
+        throttle.set_upload(Some(0));
+        assert_eq!(throttle.upload(), None);
+        throttle.clone().set_download(Some(100));
+        assert_eq!(throttle.download(), Some(100));
+    }
+
+    #[test]
+    fn failed_requests_are_reported() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use crate::objects::{BlockBuffer, Object};
+
+        #[derive(Default)]
+        struct Errors(Mutex<Vec<&'static str>>);
+        impl Progress for Errors {
+            fn backend_error(&self, operation: &'static str) {
+                self.0.lock().unwrap().push(operation);
+            }
+        }
+
+        struct Failing;
+        impl Backend for Failing {
+            fn write_object(&self, _object: &WriteObject) -> Result<()> {
+                Err(std::io::Error::other("full").into())
+            }
+            fn read_object(&self, _id: &ObjectId) -> Result<Arc<ReadObject>> {
+                Err(BackendError::NoObjectFound)
+            }
+        }
+
+        let errors = Arc::new(Errors::default());
+        let object = Object::with_id(ObjectId::from_bytes([1; 32]), BlockBuffer::default());
+        let backend = ThrottledBackend::new(Failing, Throttle::new()).report_to(errors.clone());
+        assert!(backend.write_object(&object).is_err());
+        assert!(backend.write_data_object(&object).is_err());
+        // missing objects are an answer
+        assert!(backend.read_object(&object.id).is_err());
+        assert!(backend.list_objects().is_err());
+        assert_eq!(*errors.0.lock().unwrap(), vec!["write", "write"]);
+
+        let backend = ThrottledBackend::new(MemoryBackend::default(), Throttle::new())
+            .report_to(errors.clone());
+        backend.write_object(&object).unwrap();
+        assert_eq!(errors.0.lock().unwrap().len(), 2);
+    }
+}
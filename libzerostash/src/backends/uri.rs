@@ -0,0 +1,194 @@
+use crate::backends::{Backend, BackendError, MemoryBackend, Result};
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Open the backend that `uri` points to, so it can be chosen at
+/// runtime, like from a configuration file.
+///
+/// The schemes are
+///
+///  - `memory:` for a `MemoryBackend`
+///  - `file:///path`, or just a path, for a `Directory`
+///  - `gateway://host:port` for a `Remote`
+///  - `s3://bucket/prefix?region=...&endpoint=...`
+///  - `b2://bucket/prefix`
+///  - `azure://account/container/prefix?endpoint=...`
+///  - `gs://bucket/prefix?service_account=key.json`
+///  - `webdav://host/path`, or `webdav+http://` without TLS
+///  - `sftp://user@host:port/path`
+///
+/// Only those enabled by the features of the crate work. Credentials
+/// of object storage services are taken from the same environment
+/// variables as in the configuration.
+pub fn from_uri(uri: &str) -> Result<Arc<dyn Backend>> {
+    let (scheme, rest) = match uri.find("://") {
+        Some(i) => (&uri[..i], &uri[i + 3..]),
+        None if uri == "memory:" => ("memory", ""),
+        None => ("file", uri),
+    };
+    let (location, query) = match rest.find('?') {
+        Some(i) if scheme != "file" => (&rest[..i], &rest[i + 1..]),
+        _ => (rest, ""),
+    };
+
+    let mut params = HashMap::new();
+    for pair in query.split('&').filter(|p| !p.is_empty()) {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        params.insert(name, value);
+    }
+    #[cfg_attr(
+        not(any(feature = "gateway", feature = "cloud", feature = "sftp")),
+        allow(unused_variables)
+    )]
+    let (host, path) = location.split_once('/').unwrap_or((location, ""));
+    let path = path.trim_matches('/');
+    #[cfg_attr(not(feature = "cloud"), allow(unused_variables))]
+    let prefix = Some(path).filter(|p| !p.is_empty());
+
+    let backend: Arc<dyn Backend> = match scheme {
+        "memory" => Arc::new(MemoryBackend::default()),
+        #[cfg(feature = "fs")]
+        "file" => Arc::new(crate::backends::Directory::new(location)?),
+        #[cfg(feature = "gateway")]
+        "gateway" => Arc::new(crate::backends::Remote::new(host)),
+        #[cfg(feature = "cloud")]
+        "s3" => {
+            use crate::backends::{Credentials, S3Backend};
+
+            let credentials = Credentials::from_env().ok_or_else(|| {
+                invalid("AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY are not set")
+            })?;
+            let mut s3 = S3Backend::new(bucket(host)?, credentials);
+            if let Some(region) = params.remove("region") {
+                s3 = s3.region(region);
+            }
+            if let Some(endpoint) = params.remove("endpoint") {
+                s3 = s3.endpoint(endpoint);
+            }
+            if let Some(prefix) = prefix {
+                s3 = s3.prefix(prefix);
+            }
+            Arc::new(s3)
+        }
+        #[cfg(feature = "cloud")]
+        "b2" => {
+            let env =
+                |name| std::env::var(name).map_err(|_| invalid(&format!("{} is not set", name)));
+            let mut b2 = crate::backends::B2Backend::new(
+                bucket(host)?,
+                env("B2_APPLICATION_KEY_ID")?,
+                env("B2_APPLICATION_KEY")?,
+            );
+            if let Some(prefix) = prefix {
+                b2 = b2.prefix(prefix);
+            }
+            Arc::new(b2)
+        }
+        #[cfg(feature = "cloud")]
+        "azure" => {
+            use crate::backends::{AzureAuth, AzureBackend};
+
+            let (container, prefix) = path.split_once('/').unwrap_or((path, ""));
+            let auth = AzureAuth::from_env().ok_or_else(|| {
+                invalid("AZURE_STORAGE_KEY or AZURE_STORAGE_SAS_TOKEN is not set")
+            })?;
+            let mut azure = AzureBackend::new(host, bucket(container)?, auth);
+            if !prefix.is_empty() {
+                azure = azure.prefix(prefix);
+            }
+            if let Some(endpoint) = params.remove("endpoint") {
+                azure = azure.endpoint(endpoint);
+            }
+            Arc::new(azure)
+        }
+        #[cfg(feature = "cloud")]
+        "gs" => {
+            use crate::backends::{GcsBackend, ServiceAccount};
+
+            let key = match params.remove("service_account") {
+                Some(path) => path.to_string(),
+                None => std::env::var("GOOGLE_APPLICATION_CREDENTIALS")
+                    .map_err(|_| invalid("GOOGLE_APPLICATION_CREDENTIALS is not set"))?,
+            };
+            let mut gcs = GcsBackend::new(bucket(host)?, ServiceAccount::from_file(key)?);
+            if let Some(prefix) = prefix {
+                gcs = gcs.prefix(prefix);
+            }
+            Arc::new(gcs)
+        }
+        #[cfg(feature = "cloud")]
+        "webdav" | "webdav+http" => {
+            use crate::backends::{WebDavAuth, WebDavBackend};
+
+            let protocol = if scheme == "webdav" { "https" } else { "http" };
+            let url = format!("{}://{}", protocol, location);
+            Arc::new(WebDavBackend::new(url, WebDavAuth::None))
+        }
+        #[cfg(feature = "sftp")]
+        "sftp" => {
+            let (destination, port) = match host.rsplit_once(':') {
+                Some((destination, port)) => {
+                    let port = port.parse().map_err(|_| invalid("bad port"))?;
+                    (destination, Some(port))
+                }
+                None => (host, None),
+            };
+            let mut sftp = crate::backends::SftpBackend::new(destination, format!("/{}", path));
+            if let Some(port) = port {
+                sftp = sftp.port(port);
+            }
+            Arc::new(crate::backends::RetryBackend::new(sftp))
+        }
+        _ => return Err(invalid(&format!("unsupported scheme `{}`", scheme))),
+    };
+
+    match params.keys().next() {
+        Some(name) => Err(invalid(&format!("unknown parameter `{}`", name))),
+        None => Ok(backend),
+    }
+}
+
+#[cfg_attr(not(feature = "cloud"), allow(dead_code))]
+fn bucket(name: &str) -> Result<&str> {
+    match name {
+        "" => Err(invalid("empty bucket")),
+        name => Ok(name),
+    }
+}
+
+fn invalid(what: &str) -> BackendError {
+    BackendError::Uri(what.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn uris_are_parsed() {
+        use super::*;
+
+        let backend = from_uri("memory:").unwrap();
+        assert!(matches!(
+            backend.read_object(&Default::default()),
+            Err(BackendError::NoObjectFound)
+        ));
+
+        let dir = std::env::temp_dir().join("0s_test_uri");
+        let _ = std::fs::remove_dir_all(&dir);
+        from_uri(&format!("file://{}", dir.display())).unwrap();
+        assert!(dir.is_dir());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        #[cfg(feature = "sftp")]
+        from_uri("sftp://me@host:2222/srv/stash").unwrap();
+
+        assert!(matches!(
+            from_uri("ftp://host/stash"),
+            Err(BackendError::Uri(_))
+        ));
+        assert!(matches!(
+            from_uri("memory://?unknown=1"),
+            Err(BackendError::Uri(_))
+        ));
+    }
+}
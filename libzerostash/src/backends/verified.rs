@@ -0,0 +1,156 @@
+use crate::backends::{Backend, BackendError, Capabilities, Result, Retrieval};
+use crate::objects::{ObjectId, ReadObject, WriteObject};
+
+use std::io;
+use std::sync::Arc;
+
+/// Reads every object it writes back, and compares it to what was
+/// written, before the write succeeds.
+///
+/// Objects that were stored changed are written once more, then the
+/// write fails, so the commit that would refer to them does too,
+/// with the objects before it intact. This costs a read of each
+/// object, and finds storage that corrupts uploads without a word.
+/// Objects put in archival storage can't be read back, and aren't
+/// checked. Put it behind any cache, so objects are read from the
+/// storage.
+pub struct VerifiedBackend<B> {
+    inner: B,
+}
+
+impl<B: Backend> VerifiedBackend<B> {
+    pub fn new(inner: B) -> VerifiedBackend<B> {
+        VerifiedBackend { inner }
+    }
+
+    /// Write `object` again with `write` if it wasn't read back the
+    /// same, once.
+    fn verify(
+        &self,
+        object: &WriteObject,
+        write: impl Fn(&WriteObject) -> Result<()>,
+    ) -> Result<()> {
+        if self.is_intact(object)? {
+            return Ok(());
+        }
+        warn!(
+            "object {} was stored changed, writing it again",
+            object.id.to_string()
+        );
+        write(object)?;
+        if self.is_intact(object)? {
+            return Ok(());
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("object {} was read back changed", object.id.to_string()),
+        )
+        .into())
+    }
+
+    fn is_intact(&self, object: &WriteObject) -> Result<bool> {
+        match self.inner.read_object(&object.id) {
+            Ok(read) => Ok(read.buffer.as_ref() == object.buffer.as_ref()),
+            Err(BackendError::Archived) => Ok(true),
+            Err(BackendError::NoObjectFound) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl<B: Backend> Backend for VerifiedBackend<B> {
+    fn write_object(&self, object: &WriteObject) -> Result<()> {
+        self.inner.write_object(object)?;
+        self.verify(object, |o| self.inner.write_object(o))
+    }
+
+    fn write_data_object(&self, object: &WriteObject) -> Result<()> {
+        self.inner.write_data_object(object)?;
+        self.verify(object, |o| self.inner.write_data_object(o))
+    }
+
+    fn retrieve_object(&self, id: &ObjectId) -> Result<Retrieval> {
+        self.inner.retrieve_object(id)
+    }
+
+    fn read_object(&self, id: &ObjectId) -> Result<Arc<ReadObject>> {
+        self.inner.read_object(id)
+    }
+
+    fn read_range(&self, id: &ObjectId, offset: u64, len: usize) -> Result<Vec<u8>> {
+        self.inner.read_range(id, offset, len)
+    }
+
+    fn delete_object(&self, id: &ObjectId) -> Result<()> {
+        self.inner.delete_object(id)
+    }
+
+    fn list_objects(&self) -> Result<Vec<ObjectId>> {
+        self.inner.list_objects()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.inner.capabilities()
+    }
+
+    fn probe(&self) -> Result<Capabilities> {
+        self.inner.probe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn corrupt_uploads_are_caught() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use crate::objects::{BlockBuffer, Object};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        /// Flips a bit of the first `corrupt` writes.
+        struct Flaky {
+            inner: MemoryBackend,
+            corrupt: AtomicUsize,
+        }
+        impl Backend for Flaky {
+            fn write_object(&self, object: &WriteObject) -> Result<()> {
+                let mut data = object.buffer.as_ref().to_vec();
+                if self.corrupt.load(Ordering::SeqCst) > 0 {
+                    self.corrupt.fetch_sub(1, Ordering::SeqCst);
+                    data[0] ^= 1;
+                }
+                self.inner
+                    .write_object(&Object::with_id(object.id, BlockBuffer::from(data)))
+            }
+            fn read_object(&self, id: &ObjectId) -> Result<Arc<ReadObject>> {
+                self.inner.read_object(id)
+            }
+        }
+
+        let inner = MemoryBackend::default();
+        let backend = VerifiedBackend::new(Flaky {
+            inner: inner.clone(),
+            corrupt: AtomicUsize::new(1),
+        });
+        let object = |id| {
+            Object::with_id(
+                ObjectId::from_bytes([id; 32]),
+                BlockBuffer::from(b"object".to_vec()),
+            )
+        };
+
+        // written again once
+        backend.write_data_object(&object(1)).unwrap();
+        assert_eq!(
+            inner.read_object(&object(1).id).unwrap().buffer.as_ref(),
+            b"object"
+        );
+        backend.write_object(&object(2)).unwrap();
+
+        // and failed the second time
+        backend.inner.corrupt.store(2, Ordering::SeqCst);
+        let e = backend.write_object(&object(4)).unwrap_err();
+        assert!(e.to_string().contains("read back changed"));
+    }
+}
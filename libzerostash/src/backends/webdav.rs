@@ -0,0 +1,253 @@
+use crate::backends::http::{byte_range, Curl, Request, Response};
+use crate::backends::{Backend, BackendError, Capabilities, Result};
+use crate::objects::{Object, ObjectId, ReadBuffer, ReadObject, WriteObject};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use secrecy::{ExposeSecret, Secret};
+
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// How requests to a WebDAV server are authorized.
+pub enum WebDavAuth {
+    None,
+    Basic {
+        user: String,
+        password: Secret<String>,
+    },
+    Bearer(Secret<String>),
+}
+
+impl WebDavAuth {
+    pub fn basic(user: impl Into<String>, password: impl Into<String>) -> WebDavAuth {
+        WebDavAuth::Basic {
+            user: user.into(),
+            password: Secret::new(password.into()),
+        }
+    }
+
+    pub fn bearer(token: impl Into<String>) -> WebDavAuth {
+        WebDavAuth::Bearer(Secret::new(token.into()))
+    }
+
+    fn header(&self) -> Option<String> {
+        match self {
+            WebDavAuth::None => None,
+            WebDavAuth::Basic { user, password } => Some(format!(
+                "Basic {}",
+                BASE64.encode(format!("{}:{}", user, password.expose_secret()))
+            )),
+            WebDavAuth::Bearer(token) => Some(format!("Bearer {}", token.expose_secret())),
+        }
+    }
+}
+
+/// Stores objects as files in a collection of a WebDAV server, like
+/// Nextcloud, or any HTTP server that takes `PUT` and `DELETE`.
+///
+/// TLS certificates are verified by `curl`, which can be configured
+/// to trust another CA, or nothing at all.
+#[derive(Clone)]
+pub struct WebDavBackend {
+    curl: Curl,
+    url: String,
+    auth: Arc<WebDavAuth>,
+    created: Arc<AtomicBool>,
+}
+
+impl WebDavBackend {
+    /// Objects in the collection at `url`, like
+    /// `https://cloud.example.com/remote.php/dav/files/me/stash`.
+    /// It's created on the first write, if needed.
+    pub fn new(url: impl Into<String>, auth: WebDavAuth) -> WebDavBackend {
+        WebDavBackend {
+            curl: Curl::default(),
+            url: url.into().trim_end_matches('/').to_string(),
+            auth: Arc::new(auth),
+            created: Arc::default(),
+        }
+    }
+
+    /// The `curl` to send requests with, and verify TLS.
+    pub fn curl(mut self, curl: Curl) -> WebDavBackend {
+        self.curl = curl;
+        self
+    }
+
+    fn request(&self, method: &'static str, url: String) -> Request<'static> {
+        let request = Request::new(method, url);
+        match self.auth.header() {
+            Some(auth) => request.header("Authorization", auth),
+            None => request,
+        }
+    }
+
+    fn object_url(&self, id: &ObjectId) -> String {
+        format!("{}/{}", self.url, id.to_string())
+    }
+
+    fn get(&self, id: &ObjectId, range: Option<String>) -> Result<Response> {
+        let mut request = self.request("GET", self.object_url(id));
+        if let Some(range) = range {
+            request = request.header("Range", range);
+        }
+
+        let response = self.curl.send(&request)?;
+        match response.status {
+            200 | 206 => Ok(response),
+            404 => Err(BackendError::NoObjectFound),
+            _ => Err(response.error("GET").into()),
+        }
+    }
+
+    fn create_collection(&self) -> io::Result<()> {
+        let response = self
+            .curl
+            .send(&self.request("MKCOL", format!("{}/", self.url)))?;
+
+        // 405 means it's already there
+        if !response.is_success() && response.status != 405 {
+            return Err(response.error("MKCOL"));
+        }
+        Ok(())
+    }
+}
+
+impl Backend for WebDavBackend {
+    fn write_object(&self, object: &WriteObject) -> Result<()> {
+        let data = object.buffer.as_ref();
+        let put = || {
+            self.curl
+                .send(&self.request("PUT", self.object_url(&object.id)).body(data))
+        };
+
+        let mut response = put()?;
+        // the collection is missing
+        if response.status == 409 && !self.created.load(Ordering::Relaxed) {
+            self.create_collection()?;
+            response = put()?;
+        }
+        if !response.is_success() {
+            return Err(response.error("PUT").into());
+        }
+
+        self.created.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn read_object(&self, id: &ObjectId) -> Result<Arc<ReadObject>> {
+        let response = self.get(id, None)?;
+        Ok(Arc::new(Object::with_id(
+            *id,
+            ReadBuffer::new(response.body),
+        )))
+    }
+
+    fn read_range(&self, id: &ObjectId, offset: u64, len: usize) -> Result<Vec<u8>> {
+        self.get(id, Some(byte_range(offset, len)))?
+            .into_range(offset, len)
+    }
+
+    fn delete_object(&self, id: &ObjectId) -> Result<()> {
+        let response = self
+            .curl
+            .send(&self.request("DELETE", self.object_url(id)))?;
+
+        match response.status {
+            404 => Err(BackendError::NoObjectFound),
+            _ if response.is_success() => Ok(()),
+            _ => Err(response.error("DELETE").into()),
+        }
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            writable: true,
+            range_reads: true,
+            listing: true,
+        }
+    }
+
+    fn probe(&self) -> Result<Capabilities> {
+        let capabilities = Capabilities {
+            writable: crate::backends::probe_object(self)?,
+            ..self.capabilities()
+        };
+        self.delete_object(&crate::backends::probe_id())?;
+        Ok(capabilities)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn objects_are_stored_in_a_collection() {
+        use super::*;
+        use crate::backends::http::test_server;
+        use crate::backends::Retry;
+        use crate::objects::BlockBuffer;
+        use std::collections::HashMap;
+        use std::sync::Mutex;
+
+        let files = Arc::new(Mutex::new(HashMap::<String, Vec<u8>>::new()));
+        let collection = Arc::new(AtomicBool::new(false));
+        let store = files.clone();
+        let url = test_server::start(move |request| {
+            if request.header("authorization") != Some("Basic bWU6c2VjcmV0") {
+                return (401, vec![], vec![]);
+            }
+            let mut files = store.lock().unwrap();
+
+            match request.method.as_str() {
+                "MKCOL" => {
+                    assert_eq!(request.path, "/dav/stash/");
+                    collection.store(true, Ordering::SeqCst);
+                    (201, vec![], vec![])
+                }
+                "PUT" if !collection.load(Ordering::SeqCst) => (409, vec![], vec![]),
+                "PUT" => {
+                    files.insert(request.path, request.body);
+                    (201, vec![], vec![])
+                }
+                "GET" => match (files.get(&request.path), request.header("range")) {
+                    (Some(data), Some("bytes=1-2")) => (206, vec![], data[1..3].to_vec()),
+                    (Some(data), _) => (200, vec![], data.clone()),
+                    (None, _) => (404, vec![], vec![]),
+                },
+                "DELETE" => match files.remove(&request.path) {
+                    Some(_) => (204, vec![], vec![]),
+                    None => (404, vec![], vec![]),
+                },
+                _ => (405, vec![], vec![]),
+            }
+        });
+
+        let curl = Curl::default().retry(Retry::never());
+        let dav = WebDavBackend::new(
+            format!("{}/dav/stash/", url),
+            WebDavAuth::basic("me", "secret"),
+        )
+        .curl(curl.clone());
+
+        let mut object = Object::new(BlockBuffer::default());
+        object.set_id(ObjectId::from_bytes([1; 32]));
+        object.buffer.as_mut()[0] = 1;
+        dav.write_object(&object).unwrap();
+        let read = dav.read_object(&object.id).unwrap();
+        assert_eq!(read.buffer.as_ref(), object.buffer.as_ref());
+        assert_eq!(dav.read_range(&object.id, 1, 2).unwrap(), [0, 0]);
+        assert_eq!(dav.read_range(&object.id, 0, 2).unwrap(), [1, 0]);
+
+        dav.delete_object(&object.id).unwrap();
+        assert!(matches!(
+            dav.read_object(&object.id),
+            Err(BackendError::NoObjectFound)
+        ));
+        assert!(files.lock().unwrap().is_empty());
+
+        let dav =
+            WebDavBackend::new(format!("{}/dav/stash", url), WebDavAuth::bearer("t")).curl(curl);
+        assert!(dav.read_object(&object.id).is_err());
+    }
+}
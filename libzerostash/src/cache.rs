@@ -0,0 +1,389 @@
+//! Local cache of file states from previous runs.
+//!
+//! Unchanged files are identified by their size, timestamps and inode,
+//! so they can be re-referenced without reading and chunking them
+//! again. The cache holds chunk pointers in plaintext, so it should
+//! be stored somewhere only the user can access.
+//!
+//! Saved during a run at checkpoints, the cache also lets a backup
+//! that was interrupted resume: files stored before are taken from it,
+//! with their chunks, as if they were committed.
+//!
+//! Repacking, pruning and collecting garbage start a new epoch of the
+//! stash, which drops what was cached before, as the objects it points
+//! to may be gone.
+use crate::compress;
+use crate::crypto::CryptoDigest;
+use crate::files::Entry;
+
+use dashmap::DashMap;
+use serde::Deserialize;
+use thiserror::Error;
+
+use std::fs;
+use std::io::{self, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const CACHE_VERSION: u8 = 2;
+
+/// Entries that haven't been seen for this many runs are dropped
+const MAX_AGE: u32 = 20;
+
+#[derive(Error, Debug)]
+pub enum CacheError {
+    #[error("IO error: {source}")]
+    Io {
+        #[from]
+        source: io::Error,
+    },
+    #[error("Invalid cache file")]
+    InvalidFormat,
+}
+
+pub type Result<T> = std::result::Result<T, CacheError>;
+
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct FileState {
+    pub size: u64,
+    pub mtime_secs: i64,
+    pub mtime_nanos: i64,
+    pub ctime_secs: i64,
+    pub ctime_nanos: i64,
+    pub inode: u64,
+    pub device: u64,
+}
+
+impl FileState {
+    #[cfg(unix)]
+    pub fn from_metadata(metadata: &fs::Metadata) -> FileState {
+        use std::os::unix::fs::MetadataExt;
+
+        FileState {
+            size: metadata.len(),
+            mtime_secs: metadata.mtime(),
+            mtime_nanos: metadata.mtime_nsec(),
+            ctime_secs: metadata.ctime(),
+            ctime_nanos: metadata.ctime_nsec(),
+            inode: metadata.ino(),
+            device: metadata.dev(),
+        }
+    }
+
+    #[cfg(windows)]
+    pub fn from_metadata(metadata: &fs::Metadata) -> FileState {
+        use std::time::UNIX_EPOCH;
+
+        let mtime = metadata
+            .modified()
+            .ok()
+            .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+            .unwrap_or_default();
+
+        FileState {
+            size: metadata.len(),
+            mtime_secs: mtime.as_secs() as i64,
+            mtime_nanos: mtime.subsec_nanos() as i64,
+            ..FileState::default()
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    version: u8,
+    stash: CryptoDigest,
+    epoch: u64,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct CachedFile {
+    state: FileState,
+    age: u32,
+    entry: Arc<Entry>,
+}
+
+pub struct FileCache {
+    path: PathBuf,
+    stash: CryptoDigest,
+    /// The epoch of the stash the entries were cached in
+    epoch: u64,
+    files: DashMap<String, CachedFile>,
+    #[cfg_attr(not(feature = "fs"), allow(dead_code))]
+    checkpoints: Mutex<Checkpoints>,
+}
+
+/// How often to save the cache during a run, and when it was last.
+#[cfg_attr(not(feature = "fs"), allow(dead_code))]
+struct Checkpoints {
+    every: Option<Duration>,
+    last: Instant,
+}
+
+impl Checkpoints {
+    fn new(every: Option<Duration>) -> Checkpoints {
+        Checkpoints {
+            every,
+            last: Instant::now(),
+        }
+    }
+}
+
+impl FileCache {
+    /// Open the cache at `path` for the stash identified by `stash`.
+    ///
+    /// A missing cache, or a cache that belongs to a different stash
+    /// will start out empty.
+    pub fn open(path: impl AsRef<Path>, stash: CryptoDigest) -> Result<FileCache> {
+        let mut cache = FileCache {
+            path: path.as_ref().to_owned(),
+            stash,
+            epoch: 0,
+            files: DashMap::default(),
+            checkpoints: Mutex::new(Checkpoints::new(None)),
+        };
+
+        let file = match fs::File::open(&cache.path) {
+            Ok(f) => f,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(cache),
+            Err(e) => return Err(e.into()),
+        };
+
+        let decoder = compress::destream(BufReader::new(file))?;
+        let mut de = serde_cbor::Deserializer::from_reader(decoder);
+
+        let header = Header::deserialize(&mut de).map_err(|_| CacheError::InvalidFormat)?;
+        if header.version != CACHE_VERSION || header.stash != stash {
+            debug!(
+                "ignoring file cache {:?} of another stash or version",
+                cache.path
+            );
+            return Ok(cache);
+        }
+        cache.epoch = header.epoch;
+
+        loop {
+            match <(String, CachedFile)>::deserialize(&mut de) {
+                Ok((name, mut cached)) => {
+                    cached.age += 1;
+                    cache.files.insert(name, cached);
+                }
+                Err(e) if e.is_eof() => break,
+                Err(_) => return Err(CacheError::InvalidFormat),
+            }
+        }
+
+        debug!(
+            "loaded {} entries from file cache {:?}",
+            cache.len(),
+            cache.path
+        );
+        Ok(cache)
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// Drop the entries cached in an earlier epoch of the stash, as
+    /// the objects their chunks are in may be rewritten or deleted
+    /// since.
+    pub fn set_epoch(&mut self, epoch: u64) {
+        if self.epoch == epoch {
+            return;
+        }
+        if !self.files.is_empty() {
+            debug!(
+                "dropping file cache {:?} of epoch {}, the stash is at {}",
+                self.path, self.epoch, epoch
+            );
+        }
+        self.files.clear();
+        self.epoch = epoch;
+    }
+
+    /// Look up the entry stored for `name`, if the file hasn't changed
+    /// since.
+    pub fn get(&self, name: &str, state: &FileState) -> Option<Arc<Entry>> {
+        let mut cached = self.files.get_mut(name)?;
+        if &cached.state != state {
+            return None;
+        }
+
+        cached.age = 0;
+        Some(cached.entry.clone())
+    }
+
+    /// A copy to look files up in and insert into, which is never
+    /// saved, like for a dry run.
+    #[cfg(feature = "fs")]
+    pub(crate) fn scratch(&self) -> FileCache {
+        FileCache {
+            path: self.path.clone(),
+            stash: self.stash,
+            epoch: self.epoch,
+            files: self.files.clone(),
+            checkpoints: Mutex::new(Checkpoints::new(None)),
+        }
+    }
+
+    pub fn insert(&self, state: FileState, entry: Arc<Entry>) {
+        self.files.insert(
+            entry.name.clone(),
+            CachedFile {
+                state,
+                age: 0,
+                entry,
+            },
+        );
+    }
+
+    /// Save the cache every `every` from now on, when `checkpoint` is
+    /// called, or only when asked to.
+    #[cfg(feature = "fs")]
+    pub(crate) fn set_checkpoints(&self, every: Option<Duration>) {
+        *self.checkpoints.lock().unwrap() = Checkpoints::new(every);
+    }
+
+    /// Save the cache, if a checkpoint is due.
+    #[cfg(feature = "fs")]
+    pub(crate) fn checkpoint(&self) -> Result<()> {
+        // whoever finds a checkpoint due while another one saves
+        // leaves it to them
+        let mut checkpoints = match self.checkpoints.try_lock() {
+            Ok(checkpoints) => checkpoints,
+            Err(_) => return Ok(()),
+        };
+        match checkpoints.every {
+            Some(every) if checkpoints.last.elapsed() >= every => {}
+            _ => return Ok(()),
+        }
+
+        self.save()?;
+        checkpoints.last = Instant::now();
+        debug!("saved {} entries of file cache {:?}", self.len(), self.path);
+        Ok(())
+    }
+
+    /// Write the cache to disk, dropping entries that were not seen
+    /// for a while.
+    pub fn save(&self) -> Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        {
+            let file = open_private(&tmp)?;
+            let mut encoder = compress::stream(BufWriter::new(file))?;
+            let header = Header {
+                version: CACHE_VERSION,
+                stash: self.stash,
+                epoch: self.epoch,
+            };
+
+            serde_cbor::to_writer(&mut encoder, &header).map_err(|_| CacheError::InvalidFormat)?;
+            for f in self.files.iter().filter(|f| f.value().age <= MAX_AGE) {
+                serde_cbor::to_writer(&mut encoder, &(f.key(), f.value()))
+                    .map_err(|_| CacheError::InvalidFormat)?;
+            }
+
+            let (mut writer, result) = encoder.finish();
+            result?;
+            io::Write::flush(&mut writer)?;
+        }
+
+        fs::rename(&tmp, &self.path)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+pub(crate) fn open_private(path: &Path) -> io::Result<fs::File> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn open_private(path: &Path) -> io::Result<fs::File> {
+    fs::File::create(path)
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn cache_survives_save_and_open() {
+        use super::*;
+        use crate::files::Entry;
+
+        let path = std::env::temp_dir().join("0s_test_file_cache");
+        let stash = [1; 32];
+
+        let entry = Entry {
+            unix_secs: 1,
+            unix_nanos: 2,
+            unix_perm: 0o644,
+            unix_uid: 0,
+            unix_gid: 0,
+            unix_user: None,
+            unix_group: None,
+            size: 1234,
+            readonly: false,
+            name: "some/file".into(),
+            symlink: None,
+            hardlink: None,
+            xattrs: None,
+            device: None,
+            windows_attributes: None,
+            raw_name: None,
+            chunks: vec![],
+        };
+        let state = FileState {
+            size: 1234,
+            inode: 42,
+            ..FileState::default()
+        };
+
+        let cache = FileCache::open(&path, stash).unwrap();
+        cache.insert(state.clone(), Arc::new(entry.clone()));
+        cache.save().unwrap();
+
+        let cache = FileCache::open(&path, stash).unwrap();
+        assert_eq!(cache.len(), 1);
+        assert!(cache.get("some/file", &state).unwrap().as_ref() == &entry);
+        assert!(cache
+            .get(
+                "some/file",
+                &FileState {
+                    size: 1,
+                    ..state.clone()
+                }
+            )
+            .is_none());
+
+        // caches of other stashes are ignored
+        let cache = FileCache::open(&path, [2; 32]).unwrap();
+        assert!(cache.is_empty());
+
+        // and entries of an earlier epoch dropped
+        let mut cache = FileCache::open(&path, stash).unwrap();
+        cache.set_epoch(0);
+        assert_eq!(cache.len(), 1);
+        cache.set_epoch(1);
+        assert!(cache.is_empty());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,30 @@
+//! Cooperative cancellation of long running operations.
+//!
+//! Operations check the token between chunks or objects, so a large
+//! file or a long sweep stops soon after, and everything written so
+//! far stays consistent. Files a backup cuts short are left out of
+//! the files it caches, so the next one reads them again.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// A cheaply cloneable flag shared between a frontend and the
+/// operations it wants to be able to stop.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    /// Ask all operations using this token to stop. Safe to call from
+    /// a signal handler thread.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
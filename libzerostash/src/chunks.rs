@@ -1,10 +1,36 @@
 use crate::crypto::{CryptoDigest, Tag};
-use crate::meta::{FieldReader, FieldWriter, MetaObjectField};
+use crate::meta::{FieldReader, FieldWriter, MetaObjectField, WriteError};
 use crate::objects::{ObjectError, ObjectId};
 
-use dashmap::{mapref::entry::Entry, DashMap};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::ops::Deref;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
-use std::sync::Arc;
+use local::{Delta, Mapped};
+
+mod local;
+
+const SHARDS: usize = 64;
+
+// Recently inserted pointers are kept in a hash map until they're
+// merged into the sorted table.
+const MIN_PENDING: usize = 1024;
+
+// The index is serialized in batches of fixed size records, which
+// is a lot faster for large stashes than going through serde for
+// every pointer.
+//
+// Layout: hash | offs (LE) | size (LE) | object id | tag
+const RECORD_SIZE: usize = 32 + 4 + 4 + 32 + 16;
+const RECORDS_PER_BATCH: usize = 1024;
+
+// Bits of the filter of a shard for every chunk in its table, and how
+// many of them each chunk sets, which is wrong for about 1% of the
+// chunks that aren't there.
+const FILTER_BITS: usize = 12;
+const FILTER_PROBES: u32 = 6;
 
 #[derive(Eq, PartialEq, Hash, Default, Serialize, Deserialize)]
 pub struct ChunkPointer {
@@ -15,7 +41,593 @@ pub struct ChunkPointer {
     pub tag: Tag,
 }
 
-pub type ChunkIndex = DashMap<CryptoDigest, Arc<ChunkPointer>>;
+/// How the chunks of the files split since the last commit
+/// deduplicated.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Stats {
+    pub new_chunks: u64,
+    pub reused_chunks: u64,
+    /// Bytes of all chunks, before compression
+    pub logical_bytes: u64,
+    /// Bytes of the new chunks, before compression
+    pub new_bytes: u64,
+    /// Bytes stored for the new chunks, compressed and encrypted
+    pub stored_bytes: u64,
+    pub files: Vec<FileStats>,
+}
+
+impl Stats {
+    /// Count a chunk of `len` bytes, which took up `stored` bytes if
+    /// it's new.
+    pub fn add_chunk(&mut self, len: usize, stored: Option<u32>) {
+        self.logical_bytes += len as u64;
+        match stored {
+            Some(stored) => {
+                self.new_chunks += 1;
+                self.new_bytes += len as u64;
+                self.stored_bytes += stored as u64;
+            }
+            None => self.reused_chunks += 1,
+        }
+    }
+
+    /// The share of bytes that were already stored.
+    pub fn dedup_ratio(&self) -> f64 {
+        dedup_ratio(self.logical_bytes, self.new_bytes)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FileStats {
+    pub name: String,
+    pub bytes: u64,
+    /// Bytes of the file in new chunks
+    pub new_bytes: u64,
+}
+
+impl FileStats {
+    /// The share of the bytes of the file that were already stored.
+    pub fn dedup_ratio(&self) -> f64 {
+        dedup_ratio(self.bytes, self.new_bytes)
+    }
+}
+
+fn dedup_ratio(bytes: u64, new_bytes: u64) -> f64 {
+    match bytes {
+        0 => 0.0,
+        _ => 1.0 - new_bytes as f64 / bytes as f64,
+    }
+}
+
+/// A `ChunkPointer` with the object id replaced by its position in
+/// the object table. Objects hold thousands of chunks, so this saves
+/// most of the space an id would take up.
+#[derive(Clone, Copy)]
+struct Slot {
+    hash: CryptoDigest,
+    offs: u32,
+    size: u32,
+    object: u32,
+    tag: Tag,
+}
+
+impl Slot {
+    fn encode(&self, object: &ObjectId, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.hash);
+        buf.extend_from_slice(&self.offs.to_le_bytes());
+        buf.extend_from_slice(&self.size.to_le_bytes());
+        buf.extend_from_slice(object.as_ref());
+        buf.extend_from_slice(&self.tag);
+    }
+}
+
+/// A serialized batch of index entries.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum ChunkRecords {
+    Packed(serde_bytes::ByteBuf),
+    /// Stashes written before records were packed store every
+    /// pointer separately
+    Single(CryptoDigest, Arc<ChunkPointer>),
+}
+
+/// A blocked Bloom filter of the hashes in a sorted table, so most
+/// chunks that aren't there are turned away with a single cache line
+/// read, instead of a binary search through the table.
+///
+/// All bits of a hash are in the same 512 bit block. The hashes are
+/// random already, so their bytes pick the block and the bits.
+#[derive(Default)]
+struct Filter {
+    blocks: Vec<[u64; 8]>,
+}
+
+impl Filter {
+    fn new<'a>(hashes: impl ExactSizeIterator<Item = &'a CryptoDigest>) -> Filter {
+        let len = (hashes.len() * FILTER_BITS).div_ceil(512);
+        let mut filter = Filter {
+            blocks: vec![[0; 8]; len],
+        };
+        for hash in hashes {
+            let (block, bits) = filter.probe(hash);
+            for bit in bits {
+                filter.blocks[block][bit / 64] |= 1 << (bit % 64);
+            }
+        }
+        filter
+    }
+
+    /// False if `hash` is surely not in the table.
+    fn may_contain(&self, hash: &CryptoDigest) -> bool {
+        if self.blocks.is_empty() {
+            return false;
+        }
+        let (block, mut bits) = self.probe(hash);
+        let block = &self.blocks[block];
+        bits.all(|bit| block[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    // the first byte picks the shard, so it's left out
+    fn probe(&self, hash: &CryptoDigest) -> (usize, impl Iterator<Item = usize>) {
+        let word = |i: usize| {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&hash[i..i + 8]);
+            u64::from_le_bytes(bytes)
+        };
+        let (block, bits) = (word(8), word(16));
+        let block = (block % self.blocks.len() as u64) as usize;
+        (
+            block,
+            (0..FILTER_PROBES).map(move |i| (bits >> (i * 9)) as usize & 511),
+        )
+    }
+}
+
+/// The chunks of a shard added and removed since changes were
+/// tracked, see `ChunkStore::track_changes`.
+#[derive(Default)]
+struct Changes {
+    added: HashSet<CryptoDigest>,
+    /// Including chunks that were added again, with another pointer
+    removed: HashSet<CryptoDigest>,
+}
+
+#[derive(Default)]
+struct Shard {
+    sorted: Vec<Slot>,
+    /// Of the hashes in `sorted`, which only changes on merges, so
+    /// chunks removed since are false positives
+    filter: Filter,
+    pending: HashMap<CryptoDigest, Slot>,
+    /// Chunks of the local index that are gone, or in this shard
+    /// instead, with their stored size
+    removed: HashMap<CryptoDigest, u32>,
+    tracked: Option<Changes>,
+}
+
+impl Shard {
+    fn get(&self, digest: &CryptoDigest) -> Option<Slot> {
+        if let Some(slot) = self.pending.get(digest) {
+            return Some(*slot);
+        }
+        self.position(digest).map(|i| self.sorted[i])
+    }
+
+    fn position(&self, digest: &CryptoDigest) -> Option<usize> {
+        if !self.filter.may_contain(digest) {
+            return None;
+        }
+        self.sorted.binary_search_by(|s| s.hash.cmp(digest)).ok()
+    }
+
+    /// Add `slot`, in place of the one with the same hash, if any.
+    fn insert(&mut self, slot: Slot) {
+        if let Some(i) = self.position(&slot.hash) {
+            self.sorted[i] = slot;
+            return;
+        }
+        self.pending.insert(slot.hash, slot);
+
+        // merging is linear in the size of the shard, so only do it
+        // once the pending map has grown proportionally
+        if self.pending.len() > MIN_PENDING.max(self.sorted.len() / 8) {
+            let mut new = self.pending.drain().map(|(_, s)| s).collect::<Vec<_>>();
+            new.sort_unstable_by_key(|s| s.hash);
+
+            // both halves are sorted, which the stable sort merges in
+            // a single pass, leaving the pending slot after the one it
+            // replaces
+            self.sorted.reserve_exact(new.len());
+            self.sorted.extend(new);
+            self.sorted.sort_by_key(|s| s.hash);
+            self.sorted.dedup_by(|pending, replaced| {
+                pending.hash == replaced.hash && {
+                    *replaced = *pending;
+                    true
+                }
+            });
+            self.filter = Filter::new(self.sorted.iter().map(|s| &s.hash));
+
+            self.pending.shrink_to_fit();
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.sorted.len() + self.pending.len()
+    }
+
+    /// Remove the slots `keep` is false for, and return them.
+    fn retain(&mut self, mut keep: impl FnMut(&Slot) -> bool) -> Vec<Slot> {
+        let mut removed = vec![];
+        self.sorted.retain(|s| {
+            keep(s) || {
+                removed.push(*s);
+                false
+            }
+        });
+        self.pending.retain(|_, s| {
+            keep(s) || {
+                removed.push(*s);
+                false
+            }
+        });
+        removed
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &Slot> {
+        self.sorted.iter().chain(self.pending.values())
+    }
+
+    fn track_added(&mut self, digest: CryptoDigest, replaced: bool) {
+        if let Some(changes) = &mut self.tracked {
+            if replaced {
+                changes.removed.insert(digest);
+            }
+            changes.added.insert(digest);
+        }
+    }
+
+    fn track_removed(&mut self, digest: CryptoDigest) {
+        if let Some(changes) = &mut self.tracked {
+            if !changes.added.remove(&digest) {
+                changes.removed.insert(digest);
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct Objects {
+    ids: Vec<ObjectId>,
+    positions: HashMap<ObjectId, u32>,
+}
+
+/// The most bytes new chunks may take up as they're stored, and how
+/// many the index holds.
+#[derive(Clone, Copy)]
+struct Quota {
+    limit: u64,
+    used: u64,
+}
+
+/// Maps chunk hashes to their location in the stash.
+///
+/// Pointers are stored inline in per-shard sorted tables instead of
+/// individual allocations, and handed out as `Arc`s on lookup. With
+/// a local index, the shards only hold what changed since it was
+/// saved.
+pub struct ChunkIndex {
+    shards: Vec<RwLock<Shard>>,
+    objects: RwLock<Objects>,
+    stats: Mutex<Stats>,
+    quota: Mutex<Option<Quota>>,
+    mapped: Option<Mapped>,
+}
+
+impl Default for ChunkIndex {
+    fn default() -> Self {
+        ChunkIndex {
+            shards: (0..SHARDS).map(|_| RwLock::default()).collect(),
+            objects: RwLock::default(),
+            stats: Mutex::default(),
+            quota: Mutex::default(),
+            mapped: None,
+        }
+    }
+}
+
+impl ChunkIndex {
+    pub fn len(&self) -> usize {
+        let (slots, removed) = self.shards.iter().fold((0, 0), |(slots, removed), s| {
+            let shard = s.read().unwrap();
+            (slots + shard.len(), removed + shard.removed.len())
+        });
+        self.mapped.as_ref().map_or(0, Mapped::len) + slots - removed
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// If the index is mapped from a local index on disk, and only
+    /// holds what changed since in memory.
+    pub fn is_mapped(&self) -> bool {
+        self.mapped.is_some()
+    }
+
+    /// Bytes of all chunks as they're stored, compressed and
+    /// encrypted.
+    pub fn stored_bytes(&self) -> u64 {
+        let mapped = self.mapped.as_ref().map_or(0, Mapped::stored_bytes);
+        let (slots, removed) = self.shards.iter().fold((0, 0), |(slots, removed), s| {
+            let shard = s.read().unwrap();
+            (
+                slots + shard.iter().map(|slot| u64::from(slot.size)).sum::<u64>(),
+                removed
+                    + shard
+                        .removed
+                        .values()
+                        .map(|size| u64::from(*size))
+                        .sum::<u64>(),
+            )
+        });
+        mapped + slots - removed
+    }
+
+    pub fn get(&self, digest: &CryptoDigest) -> Option<Arc<ChunkPointer>> {
+        self.find(&self.shard(digest).read().unwrap(), digest)
+    }
+
+    pub fn insert(&self, digest: CryptoDigest, pointer: &ChunkPointer) {
+        let slot = self.slot(digest, pointer);
+        self.insert_slot(slot);
+    }
+
+    pub fn for_each(&self, mut f: impl FnMut(&CryptoDigest, Arc<ChunkPointer>)) {
+        for shard in self.shards.iter() {
+            for slot in shard.read().unwrap().iter() {
+                f(&slot.hash, self.pointer(slot));
+            }
+        }
+
+        let shards = self.read_shards();
+        for record in self.mapped_records(&shards) {
+            let pointer = local::decode(record);
+            f(&pointer.hash.clone(), Arc::new(pointer));
+        }
+    }
+
+    /// Remove the chunks `keep` is false for, like ones no file
+    /// refers to anymore, and return them.
+    pub fn retain(&self, keep: impl Fn(&CryptoDigest) -> bool) -> Vec<Arc<ChunkPointer>> {
+        let mut removed = vec![];
+        for shard in self.shards.iter() {
+            let mut shard = shard.write().unwrap();
+            let slots = shard.retain(|s| keep(&s.hash));
+            for slot in slots.iter() {
+                shard.track_removed(slot.hash);
+            }
+            removed.extend(slots.iter().map(|s| self.pointer(s)));
+        }
+
+        if let Some(mapped) = &self.mapped {
+            let mut shards = self
+                .shards
+                .iter()
+                .map(|s| s.write().unwrap())
+                .collect::<Vec<_>>();
+            for record in mapped.records() {
+                let shard = &mut shards[shard_of(&record[..32])];
+                if shard.removed.contains_key(&record[..32]) {
+                    continue;
+                }
+
+                let pointer = local::decode(record);
+                if !keep(&pointer.hash) {
+                    shard.removed.insert(pointer.hash, pointer.size);
+                    shard.track_removed(pointer.hash);
+                    removed.push(Arc::new(pointer));
+                }
+            }
+        }
+        removed
+    }
+
+    fn write_records<E>(&self, mut f: impl FnMut(&[u8]) -> Result<(), E>) -> Result<(), E> {
+        let mut batch = Vec::with_capacity(RECORD_SIZE * RECORDS_PER_BATCH);
+        let mut add = |record: &[u8]| {
+            batch.extend_from_slice(record);
+            if batch.len() == batch.capacity() {
+                f(&batch)?;
+                batch.clear();
+            }
+            Ok(())
+        };
+
+        let mut record = Vec::with_capacity(RECORD_SIZE);
+        for shard in self.shards.iter() {
+            let shard = shard.read().unwrap();
+            let objects = self.objects.read().unwrap();
+
+            for slot in shard.iter() {
+                record.clear();
+                slot.encode(&objects.ids[slot.object as usize], &mut record);
+                add(&record)?;
+            }
+        }
+
+        let shards = self.read_shards();
+        for record in self.mapped_records(&shards) {
+            add(record)?;
+        }
+
+        if !batch.is_empty() {
+            f(&batch)?;
+        }
+        Ok(())
+    }
+
+    /// Every record, sorted by hash, as a local index holds them.
+    fn write_sorted(&self, f: &mut dyn FnMut(&[u8]) -> io::Result<()>) -> io::Result<()> {
+        let shards = self.read_shards();
+        let mut slots = shards
+            .iter()
+            .flat_map(|s| s.iter().copied())
+            .collect::<Vec<_>>();
+        slots.sort_unstable_by_key(|s| s.hash);
+
+        // the slots and the local index are both sorted, and never
+        // have the same chunk
+        let objects = self.objects.read().unwrap();
+        let mut mapped = self.mapped_records(&shards).peekable();
+        let mut record = Vec::with_capacity(RECORD_SIZE);
+        for slot in slots {
+            while let Some(earlier) = mapped.next_if(|r| r[..32] < slot.hash[..]) {
+                f(earlier)?;
+            }
+            record.clear();
+            slot.encode(&objects.ids[slot.object as usize], &mut record);
+            f(&record)?;
+        }
+        mapped.try_for_each(f)
+    }
+
+    /// The slots and the removed chunks of the shards, which are what
+    /// changed since the local index was saved.
+    fn changes(&self) -> (Vec<u8>, Vec<CryptoDigest>) {
+        let shards = self.read_shards();
+        let objects = self.objects.read().unwrap();
+        let mut records = vec![];
+        for slot in shards.iter().flat_map(|s| s.iter()) {
+            slot.encode(&objects.ids[slot.object as usize], &mut records);
+        }
+        let removed = shards.iter().flat_map(|s| s.removed.keys().copied());
+        (records, removed.collect())
+    }
+
+    /// How many slots and removed chunks the shards hold.
+    fn changed(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|s| {
+                let shard = s.read().unwrap();
+                shard.len() + shard.removed.len()
+            })
+            .sum()
+    }
+
+    /// The records of the local index that aren't removed in
+    /// `shards`.
+    fn mapped_records<'a, S: Deref<Target = Shard>>(
+        &'a self,
+        shards: &'a [S],
+    ) -> impl Iterator<Item = &'a [u8]> + 'a {
+        self.mapped
+            .iter()
+            .flat_map(Mapped::records)
+            .filter(move |r| !shards[shard_of(&r[..32])].removed.contains_key(&r[..32]))
+    }
+
+    fn read_shards(&self) -> Vec<RwLockReadGuard<'_, Shard>> {
+        self.shards.iter().map(|s| s.read().unwrap()).collect()
+    }
+
+    /// The chunk in `shard`, or else in the local index.
+    fn find(&self, shard: &Shard, digest: &CryptoDigest) -> Option<Arc<ChunkPointer>> {
+        if let Some(slot) = shard.get(digest) {
+            return Some(self.pointer(&slot));
+        }
+        if shard.removed.contains_key(digest) {
+            return None;
+        }
+        self.mapped.as_ref()?.get(digest).map(Arc::new)
+    }
+
+    fn insert_slot(&self, slot: Slot) {
+        let mut shard = self.shard(&slot.hash).write().unwrap();
+        let replaced = shard.tracked.is_some() && self.find(&shard, &slot.hash).is_some();
+        self.hide(&mut shard, &slot.hash);
+        shard.insert(slot);
+        shard.track_added(slot.hash, replaced);
+    }
+
+    /// Leave the chunk out of the local index, for the one in
+    /// `shard`.
+    fn hide(&self, shard: &mut RwLockWriteGuard<'_, Shard>, digest: &CryptoDigest) {
+        if shard.removed.contains_key(digest) {
+            return;
+        }
+        if let Some(pointer) = self.mapped.as_ref().and_then(|m| m.get(digest)) {
+            shard.removed.insert(*digest, pointer.size);
+        }
+    }
+
+    fn read_records(&self, batch: &[u8]) {
+        let mut last_object = None;
+
+        for record in batch.chunks_exact(RECORD_SIZE) {
+            let mut slot = Slot {
+                hash: CryptoDigest::default(),
+                offs: u32::from_le_bytes([record[32], record[33], record[34], record[35]]),
+                size: u32::from_le_bytes([record[36], record[37], record[38], record[39]]),
+                object: 0,
+                tag: Tag::default(),
+            };
+            slot.hash.copy_from_slice(&record[..32]);
+            slot.tag.copy_from_slice(&record[72..]);
+
+            // chunks of the same object are usually next to each other
+            let object = ObjectId::from_bytes(&record[40..72]);
+            slot.object = match last_object {
+                Some((id, pos)) if id == object => pos,
+                _ => self.object_position(&object),
+            };
+            last_object = Some((object, slot.object));
+
+            self.insert_slot(slot);
+        }
+    }
+
+    #[inline]
+    fn shard(&self, digest: &CryptoDigest) -> &RwLock<Shard> {
+        &self.shards[shard_of(digest)]
+    }
+
+    fn pointer(&self, slot: &Slot) -> Arc<ChunkPointer> {
+        Arc::new(ChunkPointer {
+            offs: slot.offs,
+            size: slot.size,
+            file: self.objects.read().unwrap().ids[slot.object as usize],
+            hash: slot.hash,
+            tag: slot.tag,
+        })
+    }
+
+    fn slot(&self, digest: CryptoDigest, pointer: &ChunkPointer) -> Slot {
+        Slot {
+            hash: digest,
+            offs: pointer.offs,
+            size: pointer.size,
+            object: self.object_position(&pointer.file),
+            tag: pointer.tag,
+        }
+    }
+
+    fn object_position(&self, id: &ObjectId) -> u32 {
+        if let Some(pos) = self.objects.read().unwrap().positions.get(id) {
+            return *pos;
+        }
+
+        let mut objects = self.objects.write().unwrap();
+        let next = objects.ids.len() as u32;
+        let pos = *objects.positions.entry(*id).or_insert(next);
+        if pos == next {
+            objects.ids.push(*id);
+        }
+
+        pos
+    }
+}
 
 #[derive(Clone, Default)]
 pub struct ChunkStore(Arc<ChunkIndex>);
@@ -29,37 +641,434 @@ impl ChunkStore {
         digest: CryptoDigest,
         mut store: impl FnMut() -> Result<Arc<ChunkPointer>, ObjectError>,
     ) -> Result<Arc<ChunkPointer>, ObjectError> {
+        let shard = self.0.shard(&digest);
+
         // do a simple check to ensure we don't write-lock straight away
-        if let Some(ptr) = self.0.get(&digest) {
-            return Ok(ptr.clone());
+        if let Some(pointer) = self.0.find(&shard.read().unwrap(), &digest) {
+            return Ok(pointer);
         }
 
         // be as lazy as possible in storing the object:
-        // at this stage the store is locked, so it's still best to
+        // at this stage the shard is locked, so it's still best to
         // release it asap
-        match self.0.entry(digest) {
-            Entry::Occupied(e) => Ok(e.get().clone()),
-            Entry::Vacant(e) => {
+        let mut shard = shard.write().unwrap();
+        match self.0.find(&shard, &digest) {
+            Some(pointer) => Ok(pointer),
+            None => {
                 let address = (store)()?;
-                e.insert(address.clone());
+                self.charge(address.size)?;
+                shard.insert(self.0.slot(digest, &address));
+                shard.track_added(digest, false);
                 Ok(address)
             }
         }
     }
 }
 
-impl MetaObjectField for ChunkStore {
-    type Item = (CryptoDigest, Arc<ChunkPointer>);
+impl ChunkStore {
+    /// Refuse new chunks once the index would take more than `limit`
+    /// bytes as they're stored, counting the chunks in it now, or
+    /// don't limit them with `None`.
+    pub fn set_quota(&self, limit: Option<u64>) {
+        let quota = limit.map(|limit| Quota {
+            limit,
+            used: self.0.stored_bytes(),
+        });
+        *self.0.quota.lock().unwrap() = quota;
+    }
+
+    /// The quota, and the bytes stored against it.
+    pub fn quota(&self) -> Option<(u64, u64)> {
+        let quota = *self.0.quota.lock().unwrap();
+        quota.map(|q| (q.limit, q.used))
+    }
+
+    fn charge(&self, size: u32) -> Result<(), ObjectError> {
+        if let Some(quota) = self.0.quota.lock().unwrap().as_mut() {
+            let used = quota.used + u64::from(size);
+            if used > quota.limit {
+                return Err(ObjectError::QuotaExceeded(quota.limit));
+            }
+            quota.used = used;
+        }
+        Ok(())
+    }
+
+    /// Add the `counts` of the chunks of the file `name`.
+    pub fn record(&self, name: &str, counts: Stats) {
+        let mut stats = self.0.stats.lock().unwrap();
+        stats.new_chunks += counts.new_chunks;
+        stats.reused_chunks += counts.reused_chunks;
+        stats.logical_bytes += counts.logical_bytes;
+        stats.new_bytes += counts.new_bytes;
+        stats.stored_bytes += counts.stored_bytes;
+        stats.files.push(FileStats {
+            name: name.into(),
+            bytes: counts.logical_bytes,
+            new_bytes: counts.new_bytes,
+        });
+    }
+
+    /// The statistics recorded so far, which starts them over.
+    pub fn take_stats(&self) -> Stats {
+        std::mem::take(&mut *self.0.stats.lock().unwrap())
+    }
+
+    /// Put back the statistics taken before, instead of the ones
+    /// recorded since.
+    pub(crate) fn put_stats(&self, stats: Stats) {
+        *self.0.stats.lock().unwrap() = stats;
+    }
+
+    /// Track the chunks added and removed from now on, and forget the
+    /// ones before.
+    pub(crate) fn track_changes(&self) {
+        for shard in self.0.shards.iter() {
+            shard.write().unwrap().tracked = Some(Changes::default());
+        }
+    }
+
+    /// The chunks removed since `track_changes`, and a store of the
+    /// ones added, or nothing if changes aren't tracked. Chunks that
+    /// were added with another pointer are in both.
+    pub(crate) fn tracked_changes(&self) -> Option<(Vec<CryptoDigest>, ChunkStore)> {
+        let mut removed = vec![];
+        let added = ChunkStore::default();
+        for shard in self.0.shards.iter() {
+            let shard = shard.read().unwrap();
+            let changes = shard.tracked.as_ref()?;
+            removed.extend(changes.removed.iter().copied());
+            for digest in changes.added.iter() {
+                if let Some(pointer) = self.0.find(&shard, digest) {
+                    added.0.insert(*digest, &pointer);
+                }
+            }
+        }
+        removed.sort_unstable();
+        Some((removed, added))
+    }
+}
+
+impl ChunkStore {
+    /// The index saved at `path` for commit `commit` of the stash
+    /// `stash`, if it's there, see `local`.
+    pub(crate) fn open_local(
+        path: &Path,
+        stash: &CryptoDigest,
+        commit: &CryptoDigest,
+    ) -> io::Result<Option<ChunkStore>> {
+        let mapped = match Mapped::open(path, stash)? {
+            Some(mapped) => mapped,
+            None => return Ok(None),
+        };
+        let delta = match Delta::open(path, &mapped, commit)? {
+            Some(delta) => Some(delta),
+            None if mapped.commit() == commit => None,
+            None => return Ok(None),
+        };
+
+        let store = ChunkStore(Arc::new(ChunkIndex {
+            mapped: Some(mapped),
+            ..ChunkIndex::default()
+        }));
+        if let Some(delta) = delta {
+            store.0.read_records(&delta.records);
+            for hash in delta.removed.iter() {
+                let mut shard = store.0.shard(hash).write().unwrap();
+                store.0.hide(&mut shard, hash);
+            }
+        }
+        Ok(Some(store))
+    }
 
-    fn serialize(&self, mw: &mut impl FieldWriter) {
-        for f in self.0.iter() {
-            mw.write_next((f.key(), f.value()));
+    /// Save the index to `path` as of commit `commit`, only with what
+    /// changed since it was opened there, unless that's a lot. Returns
+    /// if the whole index was written, which is only mapped once it's
+    /// opened again.
+    pub(crate) fn save_local(
+        &self,
+        path: &Path,
+        stash: &CryptoDigest,
+        commit: &CryptoDigest,
+    ) -> io::Result<bool> {
+        let index = &self.0;
+        if let Some(mapped) = &index.mapped {
+            if index.changed() <= mapped.len() / 8 {
+                let (records, removed) = index.changes();
+                let delta = Delta::new(*stash, *mapped.commit(), *commit, records, removed);
+                delta.write(path)?;
+                return Ok(false);
+            }
         }
+
+        let size = (index.len(), index.stored_bytes());
+        Mapped::write(path, stash, commit, size, |f| index.write_sorted(f))?;
+        Delta::remove(path)?;
+        Ok(true)
+    }
+}
+
+impl MetaObjectField for ChunkStore {
+    type Item = ChunkRecords;
+
+    fn serialize(&self, mw: &mut impl FieldWriter) -> Result<(), WriteError> {
+        self.0
+            .write_records(|batch| mw.write_next(serde_bytes::Bytes::new(batch)))
     }
 
     fn deserialize(&self, mw: &mut impl FieldReader<Self::Item>) {
-        while let Ok((hash, pointer)) = mw.read_next() {
-            self.0.insert(hash, pointer);
+        while let Ok(records) = mw.read_next() {
+            match records {
+                ChunkRecords::Packed(batch) => self.0.read_records(&batch),
+                ChunkRecords::Single(hash, pointer) => self.0.insert(hash, &pointer),
+            }
+        }
+    }
+}
+
+/// The shard of the chunk with the hash `digest`.
+#[inline]
+fn shard_of(digest: &[u8]) -> usize {
+    digest[0] as usize % SHARDS
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn index_finds_pointers_across_merges() {
+        use super::*;
+        use crate::crypto::chunk_hash;
+
+        let store = ChunkStore::default();
+        let objects = [ObjectId::from_bytes([1; 32]), ObjectId::from_bytes([2; 32])];
+
+        for i in 0..100_000u32 {
+            let hash = chunk_hash(&i.to_le_bytes());
+            let stored = store
+                .push(hash, || {
+                    Ok(Arc::new(ChunkPointer {
+                        offs: i,
+                        size: i * 2,
+                        file: objects[i as usize % 2],
+                        hash,
+                        tag: [i as u8; 16],
+                    }))
+                })
+                .unwrap();
+            assert_eq!(stored.offs, i);
+        }
+
+        // nothing gets stored twice
+        let hash = chunk_hash(&5u32.to_le_bytes());
+        let ptr = store.push(hash, || panic!("stored twice")).unwrap();
+        assert_eq!(ptr.offs, 5);
+        assert_eq!(ptr.file, objects[1]);
+
+        let index = store.index();
+        assert_eq!(index.len(), 100_000);
+        for i in (0..100_000u32).step_by(997) {
+            let ptr = index.get(&chunk_hash(&i.to_le_bytes())).unwrap();
+            assert_eq!(ptr.size, i * 2);
+            assert_eq!(ptr.file, objects[i as usize % 2]);
+            assert_eq!(ptr.tag, [i as u8; 16]);
+        }
+        assert!(index.get(&[0; 32]).is_none());
+
+        let mut count = 0;
+        index.for_each(|_, _| count += 1);
+        assert_eq!(count, 100_000);
+    }
+
+    #[test]
+    fn inserts_replace_sorted_and_pending_chunks() {
+        use super::*;
+        use crate::crypto::chunk_hash;
+
+        let hash = |i: u32| chunk_hash(&i.to_le_bytes());
+        let pointer = |offs: u32| ChunkPointer {
+            offs,
+            size: 10,
+            ..ChunkPointer::default()
+        };
+
+        // enough for a merge into the sorted slots in every shard
+        let index = ChunkIndex::default();
+        let count = (SHARDS * MIN_PENDING * 2) as u32;
+        (0..count).for_each(|i| index.insert(hash(i), &pointer(i)));
+        assert!(index
+            .shards
+            .iter()
+            .all(|s| !s.read().unwrap().sorted.is_empty()));
+
+        for i in 0..count {
+            index.insert(hash(i), &pointer(i + 1));
+            index.insert(hash(i), &pointer(i + 2));
         }
+        assert_eq!(index.len(), count as usize);
+        assert_eq!(index.stored_bytes(), u64::from(count) * 10);
+        assert!((0..count).all(|i| index.get(&hash(i)).unwrap().offs == i + 2));
+    }
+
+    #[test]
+    fn filters_turn_away_most_missing_chunks() {
+        use super::*;
+        use crate::crypto::chunk_hash;
+
+        let hashes = (0..10_000u32)
+            .map(|i| chunk_hash(&i.to_le_bytes()))
+            .collect::<Vec<_>>();
+        let filter = Filter::new(hashes.iter());
+        assert!(hashes.iter().all(|h| filter.may_contain(h)));
+
+        let missing = (10_000..110_000u32)
+            .filter(|i| filter.may_contain(&chunk_hash(&i.to_le_bytes())))
+            .count();
+        assert!(missing < 2_000, "{} false positives", missing);
+        assert!(!Filter::default().may_contain(&hashes[0]));
+    }
+
+    #[test]
+    fn local_indexes_hold_what_changed_on_top() {
+        use super::*;
+        use crate::crypto::chunk_hash;
+
+        let path = std::env::temp_dir().join("0s_test_local_chunks");
+        let (stash, first, second) = ([1; 32], [2; 32], [3; 32]);
+        let hash = |i: u32| chunk_hash(&i.to_le_bytes());
+        let push = |store: &ChunkStore, i: u32| {
+            store
+                .push(hash(i), || {
+                    Ok(Arc::new(ChunkPointer {
+                        offs: i,
+                        size: 10,
+                        file: ObjectId::from_bytes([i as u8; 32]),
+                        hash: hash(i),
+                        tag: [4; 16],
+                    }))
+                })
+                .unwrap()
+        };
+
+        let store = ChunkStore::default();
+        (0..1000).for_each(|i| drop(push(&store, i)));
+        assert!(store.save_local(&path, &stash, &first).unwrap());
+        assert!(ChunkStore::open_local(&path, &[9; 32], &first)
+            .unwrap()
+            .is_none());
+
+        let store = ChunkStore::open_local(&path, &stash, &first)
+            .unwrap()
+            .unwrap();
+        let index = store.index();
+        assert!(index.is_mapped());
+        assert_eq!(index.get(&hash(7)).unwrap().offs, 7);
+        assert_eq!(push(&store, 7).file, ObjectId::from_bytes([7; 32]));
+        drop(push(&store, 1000));
+        let removed = index.retain(|h| *h != hash(3) && *h != hash(1000));
+        assert_eq!(removed.len(), 2);
+        index.insert(
+            hash(5),
+            &ChunkPointer {
+                offs: 55,
+                size: 20,
+                ..ChunkPointer::default()
+            },
+        );
+        assert_eq!((index.len(), index.stored_bytes()), (999, 9_990 + 10));
+
+        // only the changes are written
+        assert!(!store.save_local(&path, &stash, &second).unwrap());
+        assert!(ChunkStore::open_local(&path, &stash, &[7; 32])
+            .unwrap()
+            .is_none());
+        let store = ChunkStore::open_local(&path, &stash, &second)
+            .unwrap()
+            .unwrap();
+        let index = store.index();
+        assert!(index.get(&hash(3)).is_none());
+        assert_eq!(index.get(&hash(5)).unwrap().offs, 55);
+        assert_eq!((index.len(), index.stored_bytes()), (999, 10_000));
+        let mut count = 0;
+        index.for_each(|_, _| count += 1);
+        assert_eq!(count, 999);
+
+        // and merged into a new base once there are many
+        (2000..2200).for_each(|i| drop(push(&store, i)));
+        assert!(store.save_local(&path, &stash, &first).unwrap());
+        let store = ChunkStore::open_local(&path, &stash, &first)
+            .unwrap()
+            .unwrap();
+        assert_eq!(store.index().len(), 1199);
+        assert_eq!(store.index().get(&hash(5)).unwrap().offs, 55);
+        assert!(store
+            .index()
+            .shards
+            .iter()
+            .all(|s| s.read().unwrap().len() == 0));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn records_roundtrip_and_legacy_pointers_load() {
+        use super::*;
+        use crate::crypto::chunk_hash;
+        use serde::Serialize;
+
+        struct Records(Vec<Vec<u8>>);
+        impl FieldWriter for Records {
+            fn write_next(&mut self, obj: impl Serialize) -> Result<(), WriteError> {
+                self.0.push(serde_cbor::to_vec(&obj)?);
+                Ok(())
+            }
+        }
+        impl FieldReader<ChunkRecords> for Records {
+            fn read_next(&mut self) -> Result<ChunkRecords, Box<dyn std::error::Error>> {
+                match self.0.pop() {
+                    Some(r) => Ok(serde_cbor::from_slice(&r)?),
+                    None => Err("end".into()),
+                }
+            }
+        }
+
+        let store = ChunkStore::default();
+        for i in 0..3000u32 {
+            let hash = chunk_hash(&i.to_le_bytes());
+            store
+                .push(hash, || {
+                    Ok(Arc::new(ChunkPointer {
+                        offs: i,
+                        size: 1,
+                        file: ObjectId::from_bytes([i as u8; 32]),
+                        hash,
+                        tag: [3; 16],
+                    }))
+                })
+                .unwrap();
+        }
+
+        let mut records = Records(vec![]);
+        store.serialize(&mut records).unwrap();
+        assert_eq!(records.0.len(), 3);
+
+        let legacy = ChunkPointer {
+            hash: [9; 32],
+            ..ChunkPointer::default()
+        };
+        records
+            .0
+            .push(serde_cbor::to_vec(&([9u8; 32], Arc::new(legacy))).unwrap());
+
+        let restored = ChunkStore::default();
+        restored.deserialize(&mut records);
+
+        let index = restored.index();
+        assert_eq!(index.len(), 3001);
+        assert!(index.get(&[9; 32]).is_some());
+
+        let ptr = index.get(&chunk_hash(&1234u32.to_le_bytes())).unwrap();
+        assert_eq!(ptr.offs, 1234);
+        assert_eq!(ptr.file, ObjectId::from_bytes([1234u32 as u8; 32]));
+        assert_eq!(ptr.tag, [3; 16]);
     }
 }
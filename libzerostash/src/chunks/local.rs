@@ -0,0 +1,266 @@
+//! The chunk index of a stash, kept on the local disk between runs.
+//!
+//! The base is a file of every record sorted by hash, which is mapped
+//! into memory, so opening the stash reads nothing, and lookups only
+//! touch the pages they search through. What commits change is saved
+//! next to it, in a delta that's small enough to read into memory,
+//! until it's merged into a new base.
+//!
+//! Both are of a single commit of the stash, and ignored for any other,
+//! so they're rebuilt from the metadata once a commit was made
+//! elsewhere. Like the file cache, they hold the chunk pointers in
+//! plaintext.
+use crate::chunks::{ChunkPointer, RECORD_SIZE};
+use crate::crypto::{CryptoDigest, Tag};
+use crate::objects::ObjectId;
+
+use serde::Deserialize;
+
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+const MAGIC: &[u8; 4] = b"0sci";
+const VERSION: u32 = 1;
+
+// Layout: magic | version (LE) | stash | commit | records (LE) |
+// stored bytes (LE)
+const HEADER_SIZE: usize = 4 + 4 + 32 + 32 + 8 + 8;
+
+/// The base of a local index, mapped into memory.
+pub(super) struct Mapped {
+    data: Box<dyn AsRef<[u8]> + Send + Sync>,
+    commit: CryptoDigest,
+    len: usize,
+    stored: u64,
+}
+
+impl Mapped {
+    /// The base at `path`, if it's there, and of the stash `stash`.
+    pub(super) fn open(path: &Path, stash: &CryptoDigest) -> io::Result<Option<Mapped>> {
+        let file = match fs::File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let data = map(&file)?;
+
+        let bytes = (*data).as_ref();
+        let header = match bytes.get(..HEADER_SIZE) {
+            Some(header) if &header[..4] == MAGIC => header,
+            _ => return Ok(None),
+        };
+        let len = u64_at(header, 72) as usize;
+        if u32::from_le_bytes([header[4], header[5], header[6], header[7]]) != VERSION
+            || header[8..40] != stash[..]
+            || bytes.len() != HEADER_SIZE + len * RECORD_SIZE
+        {
+            debug!(
+                "ignoring local index {:?} of another stash or version",
+                path
+            );
+            return Ok(None);
+        }
+
+        let mut commit = CryptoDigest::default();
+        commit.copy_from_slice(&header[40..72]);
+        let stored = u64_at(header, 80);
+        Ok(Some(Mapped {
+            data,
+            commit,
+            len,
+            stored,
+        }))
+    }
+
+    /// Write a base of `len` records to `path`, which `records` hands
+    /// to the function it's given, sorted by hash.
+    pub(super) fn write(
+        path: &Path,
+        stash: &CryptoDigest,
+        commit: &CryptoDigest,
+        (len, stored): (usize, u64),
+        records: impl FnOnce(&mut dyn FnMut(&[u8]) -> io::Result<()>) -> io::Result<()>,
+    ) -> io::Result<()> {
+        replace(path, |file| {
+            let mut file = BufWriter::new(file);
+            file.write_all(MAGIC)?;
+            file.write_all(&VERSION.to_le_bytes())?;
+            file.write_all(stash)?;
+            file.write_all(commit)?;
+            file.write_all(&(len as u64).to_le_bytes())?;
+            file.write_all(&stored.to_le_bytes())?;
+
+            let mut written = 0;
+            records(&mut |record| {
+                written += 1;
+                file.write_all(record)
+            })?;
+            if written != len {
+                return Err(io::Error::other("the index changed while it was saved"));
+            }
+            file.flush()
+        })
+    }
+
+    pub(super) fn commit(&self) -> &CryptoDigest {
+        &self.commit
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Bytes of the chunks in the base as they're stored.
+    pub(super) fn stored_bytes(&self) -> u64 {
+        self.stored
+    }
+
+    pub(super) fn records(&self) -> impl Iterator<Item = &[u8]> {
+        (*self.data).as_ref()[HEADER_SIZE..].chunks_exact(RECORD_SIZE)
+    }
+
+    pub(super) fn get(&self, digest: &CryptoDigest) -> Option<ChunkPointer> {
+        let records = &(*self.data).as_ref()[HEADER_SIZE..];
+        let (mut low, mut high) = (0, self.len);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            let record = &records[mid * RECORD_SIZE..][..RECORD_SIZE];
+            match record[..32].cmp(&digest[..]) {
+                std::cmp::Ordering::Less => low = mid + 1,
+                std::cmp::Ordering::Greater => high = mid,
+                std::cmp::Ordering::Equal => return Some(decode(record)),
+            }
+        }
+        None
+    }
+}
+
+/// The records and removed chunks on top of a base.
+#[derive(Serialize, Deserialize)]
+pub(super) struct Delta {
+    version: u32,
+    stash: CryptoDigest,
+    /// The commit of the base this is on top of
+    base: CryptoDigest,
+    commit: CryptoDigest,
+    pub(super) records: serde_bytes::ByteBuf,
+    pub(super) removed: Vec<CryptoDigest>,
+}
+
+impl Delta {
+    pub(super) fn new(
+        stash: CryptoDigest,
+        base: CryptoDigest,
+        commit: CryptoDigest,
+        records: Vec<u8>,
+        removed: Vec<CryptoDigest>,
+    ) -> Delta {
+        Delta {
+            version: VERSION,
+            stash,
+            base,
+            commit,
+            records: serde_bytes::ByteBuf::from(records),
+            removed,
+        }
+    }
+
+    /// The delta next to the base at `path`, if it's on top of `base`
+    /// and takes it to `commit`.
+    pub(super) fn open(
+        path: &Path,
+        base: &Mapped,
+        commit: &CryptoDigest,
+    ) -> io::Result<Option<Delta>> {
+        let bytes = match fs::read(delta_path(path)) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let mut de = serde_cbor::Deserializer::from_slice(&bytes);
+        Ok(Delta::deserialize(&mut de).ok().filter(|delta| {
+            delta.version == VERSION
+                && delta.base == base.commit
+                && &delta.commit == commit
+                && delta.records.len() % RECORD_SIZE == 0
+        }))
+    }
+
+    pub(super) fn write(&self, path: &Path) -> io::Result<()> {
+        replace(&delta_path(path), |file| {
+            let mut file = BufWriter::new(file);
+            serde_cbor::to_writer(&mut file, self).map_err(io::Error::other)?;
+            file.flush()
+        })
+    }
+
+    /// Remove the delta next to the base at `path`, which a new base
+    /// leaves behind.
+    pub(super) fn remove(path: &Path) -> io::Result<()> {
+        match fs::remove_file(delta_path(path)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+pub(super) fn decode(record: &[u8]) -> ChunkPointer {
+    let mut hash = CryptoDigest::default();
+    hash.copy_from_slice(&record[..32]);
+    let mut tag = Tag::default();
+    tag.copy_from_slice(&record[72..]);
+
+    ChunkPointer {
+        offs: u32::from_le_bytes([record[32], record[33], record[34], record[35]]),
+        size: u32::from_le_bytes([record[36], record[37], record[38], record[39]]),
+        file: ObjectId::from_bytes(&record[40..72]),
+        hash,
+        tag,
+    }
+}
+
+fn u64_at(bytes: &[u8], at: usize) -> u64 {
+    let mut le = [0; 8];
+    le.copy_from_slice(&bytes[at..at + 8]);
+    u64::from_le_bytes(le)
+}
+
+fn delta_path(path: &Path) -> PathBuf {
+    with_suffix(path, ".delta")
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    path.into()
+}
+
+/// Write `path` through a temporary file, so it's either what it was or
+/// all of what's written.
+fn replace(path: &Path, write: impl FnOnce(&fs::File) -> io::Result<()>) -> io::Result<()> {
+    let tmp = with_suffix(path, ".tmp");
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    write(&crate::cache::open_private(&tmp)?)?;
+    fs::rename(&tmp, path)
+}
+
+#[cfg(feature = "fs")]
+fn map(file: &fs::File) -> io::Result<Box<dyn AsRef<[u8]> + Send + Sync>> {
+    // an empty file can't be mapped, but isn't an index either
+    if file.metadata()?.len() < HEADER_SIZE as u64 {
+        return Ok(Box::new(vec![]));
+    }
+    // the file is only ever replaced, never written in place
+    Ok(Box::new(unsafe { memmap::Mmap::map(file)? }))
+}
+
+#[cfg(not(feature = "fs"))]
+fn map(mut file: &fs::File) -> io::Result<Box<dyn AsRef<[u8]> + Send + Sync>> {
+    let mut data = vec![];
+    io::Read::read_to_end(&mut file, &mut data)?;
+    Ok(Box::new(data))
+}
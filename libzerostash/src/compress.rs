@@ -1,83 +1,660 @@
-use lz4::block::{compress, decompress, CompressionMode};
-use lz4::{BlockMode, BlockSize, ContentChecksum, EncoderBuilder};
-pub use lz4::{Decoder, Encoder};
+//! LZ4 compression of chunks and metadata streams, and other
+//! compressions of chunks. Metadata streams can also be zstd with a
+//! dictionary trained on their records.
+//!
+//! Native builds use liblz4. On wasm32, where there's no C toolchain
+//! to build it with, a pure Rust implementation of the same formats
+//! is used instead.
+//!
+//! Chunks record their compression in their first 4 bytes. For LZ4
+//! that's the size, which is below 2^24 for any chunk, and other
+//! compressions set the top byte instead.
 
-use std::io::{Read, Result, Write};
+#[cfg(not(target_arch = "wasm32"))]
+mod native;
+#[cfg(not(target_arch = "wasm32"))]
+pub use native::*;
 
-pub const STREAM_LEVEL: u32 = 1;
-pub const BLOCK_LEVEL: i32 = 32;
+#[cfg(target_arch = "wasm32")]
+mod portable;
+#[cfg(target_arch = "wasm32")]
+pub use portable::*;
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{BufRead, Error, ErrorKind, Read, Result, Write};
+
+/// The default of `Tuning::stream_block_size`
 pub const STREAM_BLOCK_SIZE: usize = 64 * 1024;
-const LZ4_BLOCK_SIZE: BlockSize = BlockSize::Max64KB;
 
-pub fn block(buf: &[u8]) -> Result<Vec<u8>> {
-    compress(&buf, Some(CompressionMode::FAST(BLOCK_LEVEL)), true)
+const UNCOMPRESSED: [u8; 4] = [b'0', b's', 0, 0xff];
+const ZSTD: [u8; 4] = [b'0', b's', 1, 0xff];
+
+/// The most a metadata dictionary takes
+pub const DICTIONARY_SIZE: usize = 32 * 1024;
+
+/// zstd dictionaries start with this, followed by their id
+const DICTIONARY_MAGIC: [u8; 4] = [0x37, 0xa4, 0x30, 0xec];
+
+/// The entropy of samples above which chunks are taken to be
+/// compressed already, in bits per byte
+const INCOMPRESSIBLE_ENTROPY: f64 = 7.9;
+
+/// Chunks are sampled in this many windows of `SAMPLE_WINDOW` bytes
+const SAMPLE_WINDOWS: usize = 4;
+const SAMPLE_WINDOW: usize = 1024;
+
+/// The highest acceleration of liblz4, which mostly copies
+const FASTEST_LZ4: i32 = 65537;
+
+/// How the chunks of a stash are compressed, `none`, `lz4` or
+/// `zstd-<level>` by name.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub enum Compression {
+    None,
+    Lz4,
+    /// Zstandard at a level of 1 to 22, with the `zstd` feature
+    Zstd(i32),
+}
+
+impl Default for Compression {
+    /// The compression of stashes from before it could be selected
+    fn default() -> Compression {
+        Compression::Lz4
+    }
+}
+
+impl Compression {
+    /// The name of the compression in the format of the stash
+    pub fn name(self) -> String {
+        match self {
+            Compression::None => "none".into(),
+            Compression::Lz4 => "lz4".into(),
+            Compression::Zstd(level) => format!("zstd-{}", level),
+        }
+    }
+
+    /// The compression called `name`, if this build supports it.
+    pub fn from_name(name: &str) -> Option<Compression> {
+        match name {
+            "none" => Some(Compression::None),
+            "lz4" => Some(Compression::Lz4),
+            _ if cfg!(feature = "zstd") => name
+                .strip_prefix("zstd-")
+                .and_then(|level| level.parse().ok())
+                .filter(|level| (1..=22).contains(level))
+                .map(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    /// Compress the chunk `src` into `dst`, like `block_into`.
+    pub fn compress_into(self, dst: &mut Vec<u8>, src: &[u8]) -> Result<usize> {
+        match self {
+            Compression::Lz4 => block_into(dst, src),
+            Compression::None => {
+                dst.clear();
+                dst.extend_from_slice(&UNCOMPRESSED);
+                dst.extend_from_slice(src);
+                Ok(dst.len())
+            }
+            Compression::Zstd(level) => zstd_into(dst, src, level),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.name())
+    }
+}
+
+impl From<Compression> for String {
+    fn from(compression: Compression) -> String {
+        compression.name()
+    }
+}
+
+impl TryFrom<String> for Compression {
+    type Error = String;
+
+    fn try_from(name: String) -> std::result::Result<Compression, String> {
+        Compression::from_name(&name).ok_or_else(|| format!("unsupported compression: {}", name))
+    }
+}
+
+/// Compress the files matching the glob `pattern` with `compression`,
+/// which is by name, so that a rule this build doesn't support is an
+/// error instead of being dropped.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompressionRule {
+    pub pattern: String,
+    pub compression: String,
+}
+
+impl CompressionRule {
+    pub fn new(pattern: impl Into<String>, compression: Compression) -> CompressionRule {
+        CompressionRule {
+            pattern: pattern.into(),
+            compression: compression.name(),
+        }
+    }
+}
+
+/// The compression of each file, from the first rule that matches
+/// its path, or the compression of the stash.
+#[derive(Clone, Debug, Default)]
+pub struct CompressionRules {
+    pub default: Compression,
+    rules: Vec<(glob::Pattern, Compression)>,
+}
+
+impl CompressionRules {
+    pub fn new(
+        default: Compression,
+        rules: &[CompressionRule],
+    ) -> std::result::Result<CompressionRules, String> {
+        let rules = rules
+            .iter()
+            .map(|rule| {
+                let pattern = glob::Pattern::new(&rule.pattern)
+                    .map_err(|e| format!("invalid pattern {}: {}", rule.pattern, e))?;
+                let compression = Compression::try_from(rule.compression.clone())?;
+                Ok((pattern, compression))
+            })
+            .collect::<std::result::Result<_, String>>()?;
+
+        Ok(CompressionRules { default, rules })
+    }
+
+    pub fn for_path(&self, name: &str) -> Compression {
+        self.rules
+            .iter()
+            .find(|(pattern, _)| pattern.matches(name))
+            .map_or(self.default, |(_, compression)| *compression)
+    }
+}
+
+/// Trade-offs between speed and size, which readers don't need to
+/// know about, so they can change between runs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Tuning {
+    /// The level of chunks, instead of the one of their compression.
+    /// For LZ4, this is the acceleration, where higher is faster.
+    pub chunk_level: Option<i32>,
+    /// The level of metadata streams, from 0 to 12. From 3 up, LZ4 HC
+    /// compresses smaller and writes slower, but reads as fast. With
+    /// a dictionary, this is the zstd level, where 0 is its default.
+    pub stream_level: u32,
+    /// Fields of the metadata are padded to multiples of this, so
+    /// larger blocks compress better, but take more padding.
+    pub stream_block_size: usize,
+}
+
+impl Default for Tuning {
+    fn default() -> Tuning {
+        Tuning {
+            chunk_level: None,
+            stream_level: STREAM_LEVEL,
+            stream_block_size: STREAM_BLOCK_SIZE,
+        }
+    }
+}
+
+impl Tuning {
+    pub fn check(&self) -> std::result::Result<(), String> {
+        if self.chunk_level.is_some_and(|level| level < 1) {
+            return Err("the chunk level must be at least 1".into());
+        }
+        if self.stream_level > 12 {
+            return Err("the stream level must be at most 12".into());
+        }
+        if !(4 * 1024..=1024 * 1024).contains(&self.stream_block_size) {
+            return Err("the stream block size must be between 4 KiB and 1 MiB".into());
+        }
+        Ok(())
+    }
+
+    /// Compress the chunk `src` into `dst` with `compression`, at the
+    /// chunk level, if there is one.
+    ///
+    /// Chunks that look compressed already, like media or encrypted
+    /// files, are stored uncompressed instead. LZ4 stashes may be read
+    /// by builds that only know LZ4, so those chunks are compressed at
+    /// the highest acceleration instead, which mostly copies them.
+    pub fn compress_into(
+        &self,
+        compression: Compression,
+        dst: &mut Vec<u8>,
+        src: &[u8],
+    ) -> Result<usize> {
+        let incompressible = compression != Compression::None && looks_incompressible(src);
+        let len = match (compression, self.chunk_level) {
+            (Compression::Lz4, _) if incompressible => block_into_fast(dst, src, FASTEST_LZ4)?,
+            (Compression::Zstd(_), _) if incompressible => {
+                Compression::None.compress_into(dst, src)?
+            }
+            (Compression::Lz4, Some(acceleration)) => block_into_fast(dst, src, acceleration)?,
+            (Compression::Zstd(_), Some(level)) => zstd_into(dst, src, level.min(22))?,
+            _ => compression.compress_into(dst, src)?,
+        };
+
+        match compression {
+            Compression::Zstd(_) if len > src.len() + UNCOMPRESSED.len() => {
+                Compression::None.compress_into(dst, src)
+            }
+            _ => Ok(len),
+        }
+    }
+
+    /// A metadata stream into `w`, at the stream level and block
+    /// size, in zstd if there's a `dictionary`.
+    pub fn stream<W: Write>(
+        &self,
+        w: W,
+        dictionary: Option<&Dictionary>,
+    ) -> Result<StreamEncoder<W>> {
+        match dictionary {
+            None => {
+                stream_with(w, self.stream_level, self.stream_block_size).map(StreamEncoder::Lz4)
+            }
+            #[cfg(feature = "zstd")]
+            Some(dictionary) => zstd::stream::write::Encoder::with_dictionary(
+                w,
+                self.stream_level as i32,
+                dictionary.as_bytes(),
+            )
+            .map(StreamEncoder::Zstd),
+            #[cfg(not(feature = "zstd"))]
+            Some(_) => Err(no_zstd()),
+        }
+    }
+}
+
+/// Whether the byte entropy of a few windows spread over `src` is
+/// that of compressed data. This doesn't see repetitions at longer
+/// distances, but those are mostly deduplicated already.
+fn looks_incompressible(src: &[u8]) -> bool {
+    if src.len() < SAMPLE_WINDOWS * SAMPLE_WINDOW {
+        return false;
+    }
+
+    let mut histogram = [0u32; 256];
+    let stride = (src.len() - SAMPLE_WINDOW) / (SAMPLE_WINDOWS - 1);
+    for window in 0..SAMPLE_WINDOWS {
+        let start = window * stride;
+        for byte in &src[start..start + SAMPLE_WINDOW] {
+            histogram[*byte as usize] += 1;
+        }
+    }
+
+    let total = (SAMPLE_WINDOWS * SAMPLE_WINDOW) as f64;
+    let entropy: f64 = histogram
+        .iter()
+        .filter(|count| **count > 0)
+        .map(|count| {
+            let p = *count as f64 / total;
+            -p * p.log2()
+        })
+        .sum();
+    entropy > INCOMPRESSIBLE_ENTROPY
+}
+
+/// A zstd dictionary for metadata streams, trained on a sample of
+/// their records, so that even short streams compress well.
+pub struct Dictionary {
+    id: u32,
+    bytes: Vec<u8>,
 }
 
-pub fn deblock(buf: &[u8]) -> Result<Vec<u8>> {
-    decompress(&buf, None)
+impl Dictionary {
+    /// Train a dictionary on `samples`, which fails if there are too
+    /// few of them to learn from.
+    pub fn train(samples: &[Vec<u8>]) -> Result<Dictionary> {
+        Dictionary::from_bytes(train_dictionary(samples)?)
+    }
+
+    /// The dictionary stored as `bytes`.
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Dictionary> {
+        let id = match (bytes.get(..4), bytes.get(4..8)) {
+            (Some(magic), Some(id)) if magic == DICTIONARY_MAGIC => {
+                u32::from_le_bytes([id[0], id[1], id[2], id[3]])
+            }
+            _ => 0,
+        };
+        if id == 0 {
+            return Err(Error::new(ErrorKind::InvalidData, "Not a dictionary."));
+        }
+
+        Ok(Dictionary { id, bytes })
+    }
+
+    /// The id zstd records in the frames compressed with it
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
 }
 
-pub fn stream<W: Write>(w: W) -> Result<Encoder<W>> {
-    EncoderBuilder::new()
-        .level(STREAM_LEVEL)
-        .block_mode(BlockMode::Independent)
-        .block_size(LZ4_BLOCK_SIZE)
-        .checksum(ContentChecksum::NoChecksum)
-        .build(w)
+/// A metadata stream being written, in LZ4, or in zstd with a
+/// dictionary.
+pub enum StreamEncoder<W: Write> {
+    Lz4(Encoder<W>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::stream::write::Encoder<'static, W>),
 }
 
-pub fn destream<R: Read>(r: R) -> Result<Decoder<R>> {
-    Decoder::new(r)
+impl<W: Write> StreamEncoder<W> {
+    pub fn writer(&self) -> &W {
+        match self {
+            StreamEncoder::Lz4(e) => e.writer(),
+            #[cfg(feature = "zstd")]
+            StreamEncoder::Zstd(e) => e.get_ref(),
+        }
+    }
+
+    pub fn finish(self) -> Result<W> {
+        match self {
+            StreamEncoder::Lz4(e) => {
+                let (w, result) = e.finish();
+                result.map(|_| w)
+            }
+            #[cfg(feature = "zstd")]
+            StreamEncoder::Zstd(e) => e.finish(),
+        }
+    }
 }
 
-pub fn decompress_into(dst: &mut [u8], mut src: &[u8]) -> Result<()> {
-    // Copied and adapted from https://github.com/bozaro/lz4-rs/blob/master/src/block/mod.rs
-    use lz4::liblz4::*;
-    use libc::c_char;
-    use std::io::{Error, ErrorKind};
+impl<W: Write> Write for StreamEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        match self {
+            StreamEncoder::Lz4(e) => e.write(buf),
+            #[cfg(feature = "zstd")]
+            StreamEncoder::Zstd(e) => e.write(buf),
+        }
+    }
 
-    let size;
+    fn flush(&mut self) -> Result<()> {
+        match self {
+            StreamEncoder::Lz4(e) => e.flush(),
+            #[cfg(feature = "zstd")]
+            StreamEncoder::Zstd(e) => e.flush(),
+        }
+    }
+}
 
-    if src.len() < 4 {
-	return Err(Error::new(
-	    ErrorKind::InvalidInput,
-	    "Source buffer must at least contain size prefix.",
-	));
+/// A metadata stream being read, in the compression of its
+/// `StreamEncoder`.
+pub enum StreamDecoder<R: BufRead> {
+    Lz4(Decoder<R>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::stream::read::Decoder<'static, R>),
+}
+
+impl<R: BufRead> Read for StreamDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match self {
+            StreamDecoder::Lz4(d) => d.read(buf),
+            #[cfg(feature = "zstd")]
+            StreamDecoder::Zstd(d) => d.read(buf),
+        }
     }
-    size =
-	(src[0] as i32) | (src[1] as i32) << 8 | (src[2] as i32) << 16 | (src[3] as i32) << 24;
+}
 
-    src = &src[4..];
+/// Read a metadata stream from `r`, which is in zstd if it was
+/// written with a `dictionary`.
+pub fn destream_with<R: BufRead>(
+    r: R,
+    dictionary: Option<&Dictionary>,
+) -> Result<StreamDecoder<R>> {
+    match dictionary {
+        None => destream(r).map(StreamDecoder::Lz4),
+        #[cfg(feature = "zstd")]
+        Some(dictionary) => zstd::stream::read::Decoder::with_dictionary(r, dictionary.as_bytes())
+            .map(|d| StreamDecoder::Zstd(d.single_frame())),
+        #[cfg(not(feature = "zstd"))]
+        Some(_) => Err(no_zstd()),
+    }
+}
 
-    if size <= 0 {
-        return Err(Error::new(
-            ErrorKind::InvalidInput,
-            "Parsed size prefix in buffer must not be negative."
-        ));
+/// Decompress a chunk of any compression into `dst`.
+pub fn unpack_into(dst: &mut [u8], src: &[u8]) -> Result<()> {
+    match src.get(..4) {
+        Some(header) if header == UNCOMPRESSED => {
+            let src = &src[4..];
+            dst.get_mut(..src.len())
+                .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Chunk too long."))?
+                .copy_from_slice(src);
+            Ok(())
+        }
+        Some(header) if header == ZSTD => unzstd_into(dst, &src[4..]),
+        _ => decompress_into(dst, src),
     }
+}
+
+#[cfg(feature = "zstd")]
+fn zstd_into(dst: &mut Vec<u8>, src: &[u8], level: i32) -> Result<usize> {
+    use std::cell::RefCell;
+    use zstd::bulk::Compressor;
 
-    if unsafe { LZ4_compressBound(size) } <= 0 {
-        return Err(Error::new(
-            ErrorKind::InvalidInput,
-            "Given size parameter is too big",
-        ));
+    // contexts are large at high levels, so every thread keeps one
+    thread_local! {
+        static COMPRESSOR: RefCell<Option<(i32, Compressor<'static>)>> =
+            const { RefCell::new(None) };
     }
 
-    let dec_bytes = unsafe {
-        LZ4_decompress_safe(
-            src.as_ptr() as *const c_char,
-            dst.as_mut_ptr() as *mut c_char,
-            src.len() as i32,
-            size,
-        )
-    };
+    dst.clear();
+    dst.resize(4 + zstd::zstd_safe::compress_bound(src.len()), 0);
+    dst[..4].copy_from_slice(&ZSTD);
+    let len = COMPRESSOR.with(|compressor| {
+        let mut compressor = compressor.borrow_mut();
+        if compressor.as_ref().map(|(l, _)| *l) != Some(level) {
+            *compressor = Some((level, Compressor::new(level)?));
+        }
+        compressor
+            .as_mut()
+            .unwrap()
+            .1
+            .compress_to_buffer(src, &mut dst[4..])
+    })?;
+
+    dst.truncate(4 + len);
+    Ok(dst.len())
+}
 
-    if dec_bytes < 0 {
-        return Err(Error::new(
-            ErrorKind::InvalidData,
-            "Decompression failed. Input invalid or too long?",
-        ));
+#[cfg(feature = "zstd")]
+fn unzstd_into(dst: &mut [u8], src: &[u8]) -> Result<()> {
+    zstd::bulk::decompress_to_buffer(src, dst).map(|_| ())
+}
+
+#[cfg(feature = "zstd")]
+fn train_dictionary(samples: &[Vec<u8>]) -> Result<Vec<u8>> {
+    zstd::dict::from_samples(samples, DICTIONARY_SIZE)
+}
+
+#[cfg(not(feature = "zstd"))]
+fn zstd_into(_dst: &mut Vec<u8>, _src: &[u8], _level: i32) -> Result<usize> {
+    Err(no_zstd())
+}
+
+#[cfg(not(feature = "zstd"))]
+fn unzstd_into(_dst: &mut [u8], _src: &[u8]) -> Result<()> {
+    Err(no_zstd())
+}
+
+#[cfg(not(feature = "zstd"))]
+fn train_dictionary(_samples: &[Vec<u8>]) -> Result<Vec<u8>> {
+    Err(no_zstd())
+}
+
+#[cfg(not(feature = "zstd"))]
+fn no_zstd() -> Error {
+    Error::other("Built without zstd support.")
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn block_and_stream_roundtrip() {
+        use super::*;
+        use std::io::{Read, Write};
+
+        let data = (0..100_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+
+        let mut block = vec![];
+        block_into(&mut block, &data).unwrap();
+        assert!(block.len() < data.len());
+
+        let mut out = vec![0; data.len()];
+        decompress_into(&mut out, &block).unwrap();
+        assert_eq!(out, data);
+        assert_eq!(deblock(&block).unwrap(), data);
+
+        let mut encoder = stream(vec![]).unwrap();
+        encoder.write_all(&data).unwrap();
+        let (compressed, result) = encoder.finish();
+        result.unwrap();
+
+        let mut out = vec![];
+        destream(&compressed[..])
+            .unwrap()
+            .read_to_end(&mut out)
+            .unwrap();
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn chunks_record_their_compression() {
+        use super::*;
+
+        let data = (0..100_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        for name in ["none", "lz4", "zstd-19"] {
+            let compression = match Compression::from_name(name) {
+                Some(compression) => compression,
+                None => continue,
+            };
+            assert_eq!(compression.name(), name);
+
+            let mut chunk = vec![];
+            compression.compress_into(&mut chunk, &data).unwrap();
+            let mut out = vec![0; data.len()];
+            unpack_into(&mut out, &chunk).unwrap();
+            assert_eq!(out, data);
+        }
+        assert!(Compression::from_name("zstd-23").is_none());
+    }
+
+    #[test]
+    fn tuning_keeps_the_formats() {
+        use super::*;
+        use std::io::{Read, Write};
+
+        let data = (0..100_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let tuning = Tuning {
+            chunk_level: Some(1),
+            stream_level: 9,
+            stream_block_size: 256 * 1024,
+        };
+        assert!(tuning.check().is_ok());
+
+        let mut chunk = vec![];
+        tuning
+            .compress_into(Compression::Lz4, &mut chunk, &data)
+            .unwrap();
+        let mut out = vec![0; data.len()];
+        unpack_into(&mut out, &chunk).unwrap();
+        assert_eq!(out, data);
+
+        let mut encoder = tuning.stream(vec![], None).unwrap();
+        encoder.write_all(&data).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let mut out = vec![];
+        destream(&compressed[..])
+            .unwrap()
+            .read_to_end(&mut out)
+            .unwrap();
+        assert_eq!(out, data);
+
+        let invalid = Tuning {
+            stream_block_size: 1024,
+            ..Tuning::default()
+        };
+        assert!(invalid.check().is_err());
     }
 
-    Ok(())
+    #[test]
+    fn incompressible_chunks_are_stored_as_they_are() {
+        use super::*;
+
+        let random = crate::test_util::pseudo_random(100_000, 1);
+        let text = (0..100_000u32)
+            .map(|i| b"zerostash "[i as usize % 10])
+            .collect::<Vec<_>>();
+        assert!(looks_incompressible(&random));
+        assert!(!looks_incompressible(&text));
+        assert!(!looks_incompressible(&random[..1000]));
+
+        let tuning = Tuning::default();
+        for name in ["lz4", "zstd-19"] {
+            let compression = match Compression::from_name(name) {
+                Some(compression) => compression,
+                None => continue,
+            };
+            for data in [&random, &text] {
+                let mut chunk = vec![];
+                tuning.compress_into(compression, &mut chunk, data).unwrap();
+                let mut out = vec![0; data.len()];
+                unpack_into(&mut out, &chunk).unwrap();
+                assert_eq!(&out, data);
+
+                // LZ4 stashes only ever hold LZ4 chunks
+                let stored = chunk[..4] == UNCOMPRESSED;
+                assert_eq!(stored, compression != Compression::Lz4 && data == &random);
+            }
+        }
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn dictionaries_compress_short_streams() {
+        use super::*;
+        use std::io::{Read, Write};
+
+        let record = |i: usize| {
+            format!(
+                "{{\"name\": \"home/user/photos/{}.jpg\", \"size\": {}}}",
+                i,
+                i * 7
+            )
+        };
+        let samples = (0..2000)
+            .map(|i| record(i).into_bytes())
+            .collect::<Vec<_>>();
+        let dictionary = Dictionary::train(&samples).unwrap();
+        let dictionary = Dictionary::from_bytes(dictionary.as_bytes().to_vec()).unwrap();
+        assert!(dictionary.as_bytes().len() <= DICTIONARY_SIZE);
+
+        let data = record(5000).into_bytes();
+        let compress = |dictionary| {
+            let mut encoder = Tuning::default().stream(vec![], dictionary).unwrap();
+            encoder.write_all(&data).unwrap();
+            encoder.finish().unwrap()
+        };
+        let plain = compress(None);
+        let compressed = compress(Some(&dictionary));
+        assert!(compressed.len() < plain.len());
+
+        let mut out = vec![];
+        destream_with(&compressed[..], Some(&dictionary))
+            .unwrap()
+            .read_to_end(&mut out)
+            .unwrap();
+        assert_eq!(out, data);
+        assert!(Dictionary::from_bytes(vec![0; 64]).is_err());
+    }
 }
@@ -0,0 +1,138 @@
+use lz4::block::{compress, decompress, CompressionMode};
+use lz4::{BlockMode, BlockSize, ContentChecksum, EncoderBuilder};
+pub use lz4::{Decoder, Encoder};
+
+use std::io::{Read, Result, Write};
+
+pub const STREAM_LEVEL: u32 = 1;
+pub const BLOCK_LEVEL: i32 = 32;
+
+pub fn block(buf: &[u8]) -> Result<Vec<u8>> {
+    compress(buf, Some(CompressionMode::FAST(BLOCK_LEVEL)), true)
+}
+
+/// Compress `src` into `dst`, in the same format as `block`.
+///
+/// `dst` is cleared first, but its capacity is reused, so
+/// compressing into the same buffer repeatedly won't allocate.
+pub fn block_into(dst: &mut Vec<u8>, src: &[u8]) -> Result<usize> {
+    block_into_fast(dst, src, BLOCK_LEVEL)
+}
+
+/// `block_into` at `acceleration`, where higher is faster.
+pub fn block_into_fast(dst: &mut Vec<u8>, src: &[u8], acceleration: i32) -> Result<usize> {
+    // Adapted from https://github.com/bozaro/lz4-rs/blob/master/src/block/mod.rs
+    use libc::c_char;
+    use lz4::liblz4::*;
+    use std::io::{Error, ErrorKind};
+
+    let size = src.len() as i32;
+    let bound = unsafe { LZ4_compressBound(size) };
+
+    if size > 0 && bound <= 0 {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Compression input too long.",
+        ));
+    }
+
+    dst.clear();
+    dst.resize(4 + bound as usize, 0);
+    dst[..4].copy_from_slice(&(size as u32).to_le_bytes());
+
+    let len = unsafe {
+        LZ4_compress_fast(
+            src.as_ptr() as *const c_char,
+            dst[4..].as_mut_ptr() as *mut c_char,
+            size,
+            bound,
+            acceleration,
+        )
+    };
+
+    if len <= 0 {
+        return Err(Error::other("Compression failed"));
+    }
+
+    dst.truncate(4 + len as usize);
+    Ok(dst.len())
+}
+
+pub fn deblock(buf: &[u8]) -> Result<Vec<u8>> {
+    decompress(buf, None)
+}
+
+pub fn stream<W: Write>(w: W) -> Result<Encoder<W>> {
+    stream_with(w, STREAM_LEVEL, super::STREAM_BLOCK_SIZE)
+}
+
+/// A stream at `level`, in frame blocks that fit in `block_size`.
+pub fn stream_with<W: Write>(w: W, level: u32, block_size: usize) -> Result<Encoder<W>> {
+    // the writer keeps room for one block, so frames mustn't buffer more
+    let block_size = match block_size {
+        0..=262143 => BlockSize::Max64KB,
+        262144..=1048575 => BlockSize::Max256KB,
+        _ => BlockSize::Max1MB,
+    };
+
+    EncoderBuilder::new()
+        .level(level)
+        .block_mode(BlockMode::Independent)
+        .block_size(block_size)
+        .checksum(ContentChecksum::NoChecksum)
+        .build(w)
+}
+
+pub fn destream<R: Read>(r: R) -> Result<Decoder<R>> {
+    Decoder::new(r)
+}
+
+pub fn decompress_into(dst: &mut [u8], mut src: &[u8]) -> Result<()> {
+    // Copied and adapted from https://github.com/bozaro/lz4-rs/blob/master/src/block/mod.rs
+    use libc::c_char;
+    use lz4::liblz4::*;
+    use std::io::{Error, ErrorKind};
+
+    if src.len() < 4 {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Source buffer must at least contain size prefix.",
+        ));
+    }
+    let size =
+        (src[0] as i32) | (src[1] as i32) << 8 | (src[2] as i32) << 16 | (src[3] as i32) << 24;
+
+    src = &src[4..];
+
+    if size <= 0 {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Parsed size prefix in buffer must not be negative.",
+        ));
+    }
+
+    if unsafe { LZ4_compressBound(size) } <= 0 {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Given size parameter is too big",
+        ));
+    }
+
+    let dec_bytes = unsafe {
+        LZ4_decompress_safe(
+            src.as_ptr() as *const c_char,
+            dst.as_mut_ptr() as *mut c_char,
+            src.len() as i32,
+            size,
+        )
+    };
+
+    if dec_bytes < 0 {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "Decompression failed. Input invalid or too long?",
+        ));
+    }
+
+    Ok(())
+}
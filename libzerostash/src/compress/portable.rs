@@ -0,0 +1,96 @@
+use lz4_flex::block;
+use lz4_flex::frame::{BlockMode, BlockSize, FrameDecoder, FrameEncoder, FrameInfo};
+
+use std::io::{Error, ErrorKind, Read, Result, Write};
+
+pub type Decoder<R> = FrameDecoder<R>;
+
+pub const STREAM_LEVEL: u32 = 1;
+
+/// Mirrors the interface of `lz4::Encoder`
+pub struct Encoder<W: Write>(FrameEncoder<W>);
+
+impl<W: Write> Encoder<W> {
+    pub fn writer(&self) -> &W {
+        self.0.get_ref()
+    }
+
+    pub fn finish(mut self) -> (W, Result<()>) {
+        let result = self.0.try_finish().map_err(Error::from);
+        (self.0.into_inner(), result)
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.0.flush()
+    }
+}
+
+fn invalid(e: impl std::error::Error) -> Error {
+    Error::new(ErrorKind::InvalidData, e.to_string())
+}
+
+pub fn block(buf: &[u8]) -> Result<Vec<u8>> {
+    Ok(block::compress_prepend_size(buf))
+}
+
+pub fn block_into(dst: &mut Vec<u8>, src: &[u8]) -> Result<usize> {
+    dst.clear();
+    dst.resize(4 + block::get_maximum_output_size(src.len()), 0);
+    dst[..4].copy_from_slice(&(src.len() as u32).to_le_bytes());
+
+    let len = block::compress_into(src, &mut dst[4..]).map_err(invalid)?;
+    dst.truncate(4 + len);
+    Ok(dst.len())
+}
+
+/// lz4_flex has no acceleration, so this is `block_into`.
+pub fn block_into_fast(dst: &mut Vec<u8>, src: &[u8], _acceleration: i32) -> Result<usize> {
+    block_into(dst, src)
+}
+
+pub fn deblock(buf: &[u8]) -> Result<Vec<u8>> {
+    block::decompress_size_prepended(buf).map_err(invalid)
+}
+
+pub fn stream<W: Write>(w: W) -> Result<Encoder<W>> {
+    stream_with(w, STREAM_LEVEL, super::STREAM_BLOCK_SIZE)
+}
+
+/// A stream in frame blocks that fit in `block_size`. lz4_flex has no
+/// high compression, so `level` is ignored.
+pub fn stream_with<W: Write>(w: W, _level: u32, block_size: usize) -> Result<Encoder<W>> {
+    // the writer keeps room for one block, so frames mustn't buffer more
+    let block_size = match block_size {
+        0..=262143 => BlockSize::Max64KB,
+        262144..=1048575 => BlockSize::Max256KB,
+        _ => BlockSize::Max1MB,
+    };
+    let info = FrameInfo::new()
+        .block_mode(BlockMode::Independent)
+        .block_size(block_size);
+
+    Ok(Encoder(FrameEncoder::with_frame_info(info, w)))
+}
+
+pub fn destream<R: Read>(r: R) -> Result<Decoder<R>> {
+    Ok(FrameDecoder::new(r))
+}
+
+pub fn decompress_into(dst: &mut [u8], src: &[u8]) -> Result<()> {
+    let (size, src) = block::uncompressed_size(src).map_err(invalid)?;
+    if size > dst.len() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Given size parameter is too big",
+        ));
+    }
+
+    block::decompress_into(src, &mut dst[..size]).map_err(invalid)?;
+    Ok(())
+}
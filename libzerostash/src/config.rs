@@ -0,0 +1,1161 @@
+//! Configuration shared by zerostash frontends.
+//!
+//! The configuration is a TOML document with one `[stash.<alias>]`
+//! table per stash, and an optional `[tuning]` table:
+//!
+//! ```toml
+//! [stash.home]
+//! # one of `{ source = "plaintext", user = "...", password = "..." }`
+//! # or `{ source = "ask" }` to prompt for credentials, or with the
+//! # `kms` feature, a master key wrapped by a key management service:
+//! # `{ source = "kms", service = "aws", key = "alias/backups",
+//! #    wrapped = "..." }`, where `service` is "aws", "gcp" or "vault",
+//! # or by a hardware token, asking for its PIN:
+//! # `{ source = "pkcs11", module = "/usr/lib/libykcs11.so", id = "03",
+//! #    wrapped = "..." }`,
+//! # or `{ source = "public-key", public_key = "..." }` to only add
+//! # snapshots, which the owner collects with `0s collect`, or
+//! # `{ source = "shares" }` to ask for shares of the master key, or
+//! # `{ source = "keyfile", path = "/etc/zerostash/home.key" }` to
+//! # open it with a keyfile, raw or made by `0s keyfile`
+//! key = { source = "plaintext", user = "me", password = "${HOME_STASH_PASSWORD}" }
+//! backend = { type = "fs", path = "/mnt/backup/home" }
+//! # or `{ type = "gateway", address = "host:port" }` with the
+//! # `gateway` feature, or with the `cloud` feature,
+//! # `{ type = "s3", bucket = "backups", region = "eu-west-1" }`, or
+//! # `{ type = "b2", bucket = "backups" }`,
+//! # `{ type = "azure", account = "acme", container = "backups" }`,
+//! # `{ type = "gcs", bucket = "backups", service_account = "key.json" }`,
+//! # `{ type = "webdav", url = "https://cloud.example.com/dav/stash" }`, or
+//! # `{ type = "sftp", destination = "me@host", path = "/srv/stash" }`
+//! # with the `sftp` feature, or
+//! # `{ type = "helper", program = "zerostash-rclone", args = ["drive:"] }`
+//! # with the `helper` feature, or
+//! # `{ type = "mirror", quorum = 1, backends = [{ type = "fs", ... }, ...] }`
+//! # to write to all `backends`, succeeding if `quorum` of them do
+//! # glob patterns of paths to skip
+//! exclude = ["*/.cache/*", "*.tmp"]
+//! # read every object back after it's written, for storage that may
+//! # corrupt uploads
+//! verify_writes = true
+//! # keep up to 2 GiB of recently used objects on the local disk,
+//! # dropping the "least-recently-used" or the "oldest-first"
+//! cache = { path = "/var/cache/zerostash/home", size_mib = 2048, eviction = "least-recently-used" }
+//! # the sizes, times and inodes of the files of the last commit, to
+//! # skip reading files that haven't changed since
+//! file_cache = "/var/cache/zerostash/home.files"
+//! # the chunk index, mapped from the local disk instead of read into
+//! # memory
+//! index_cache = "/var/cache/zerostash/home.chunks"
+//! # every version of the files of all snapshots, for `find` to
+//! # search instead of the snapshots
+//! find_index = "/var/cache/zerostash/home.find"
+//! # refuse to store more than 100 GiB of chunks, for users of a
+//! # shared server
+//! quota_mib = 102400
+//! # "aes-256-gcm", "xchacha20-poly1305" or "chacha20-poly1305" for a
+//! # new stash, instead of the fastest one on this machine
+//! cipher = "aes-256-gcm"
+//! # "none", "lz4" or "zstd-1" to "zstd-22" to compress the chunks
+//! # of a new stash, instead of "lz4"
+//! compression = "zstd-19"
+//! # the compression of the files matching a glob pattern, from the
+//! # first rule that matches, instead of the one of the stash
+//! compression_rules = [
+//!     { pattern = "*/Videos/*", compression = "none" },
+//!     { pattern = "*.sql", compression = "zstd-22" },
+//! ]
+//! # compress the metadata with a zstd dictionary trained on it
+//! metadata_dictionary = true
+//! # write only what changed in the metadata, for up to 8 commits in
+//! # a row
+//! delta_commits = 8
+//! # "fastcdc" to split the files of a new stash faster than the
+//! # default "seasplit-13"
+//! chunker = "fastcdc"
+//! # larger chunks than the defaults of the chunker, in bytes, with
+//! # 2^mask_bits bytes between cut points on average
+//! chunk_sizes = { min = 262144, target = 1048576, max = 2097152, mask_bits = 20 }
+//! # data objects of 16 MiB for a new stash, for fewer requests to a
+//! # backend of high latency
+//! object_size = 16777216
+//! # derive the chunk keys of a new stash from their content and this
+//! # secret, to share chunks with other stashes that have it, at the
+//! # cost of revealing to its holders which content a stash has
+//! convergence = "${ZEROSTASH_FAMILY}"
+//!
+//! [tuning]
+//! threads = 8
+//! # upload objects on threads of their own, while the next are filled
+//! uploads = 2
+//! # read objects on threads of their own when restoring
+//! downloads = 8
+//! # chunk and compress large files and streams on all threads
+//! parallel_chunking = true
+//! max_open_files = 512
+//! # the LZ4 acceleration or zstd level of chunks, instead of the one
+//! # of the compression, and the LZ4 level of the metadata, where 3
+//! # and up write slower but smaller, and read as fast
+//! chunk_level = 1
+//! metadata_level = 9
+//! # metadata fields are padded to multiples of this, in bytes
+//! metadata_block_size = 262144
+//! # "walk", "small-first" or "interleave"
+//! schedule = "small-first"
+//! # KiB per second to and from the backends
+//! upload_kib = 2048
+//! download_kib = 8192
+//! # MiB of memory to keep indexes and buffers within, by running on
+//! # fewer threads
+//! memory_mib = 512
+//! ```
+//!
+//! Every string value may refer to environment variables as
+//! `${NAME}`, which are substituted before the configuration is
+//! validated. A literal `$` is written as `$$`.
+
+#[cfg(feature = "fs")]
+use crate::backends::Directory;
+use crate::error::ZerostashError;
+use crate::prompt::{self, SecretPrompt};
+use crate::stash::{Schedule, StashBuilder};
+
+use secrecy::ExposeSecret;
+use serde::Deserialize;
+use thiserror::Error;
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Parse error: {source}")]
+    Parse {
+        #[from]
+        source: toml::de::Error,
+    },
+    #[error("Can't write the configuration: {source}")]
+    Serialize {
+        #[from]
+        source: toml::ser::Error,
+    },
+    #[error("Environment variable `{0}` is not set")]
+    UndefinedVariable(String),
+    #[error("Unterminated variable reference in `{0}`")]
+    Unterminated(String),
+    #[error("Invalid configuration: {0}")]
+    Invalid(String),
+    #[error("IO error: {source}")]
+    Io {
+        #[from]
+        source: io::Error,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, ConfigError>;
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(rename = "stash", default)]
+    pub stashes: HashMap<String, Stash>,
+
+    #[serde(default)]
+    pub tuning: Tuning,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Stash {
+    pub key: Key,
+    pub backend: Backend,
+
+    /// Glob patterns of paths to skip
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude: Vec<String>,
+
+    /// Read every object back after writing it, see
+    /// `backends::VerifiedBackend`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub verify_writes: bool,
+
+    #[cfg(feature = "fs")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache: Option<Cache>,
+
+    /// Where the states of committed files are kept, to skip
+    /// unchanged ones, see `StashBuilder::file_cache`
+    #[cfg(feature = "fs")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_cache: Option<String>,
+
+    /// Where the chunk index is kept on the local disk, see
+    /// `StashBuilder::index_cache`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub index_cache: Option<String>,
+
+    /// Where the files of all snapshots are indexed on the local
+    /// disk, see `StashBuilder::find_index`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub find_index: Option<String>,
+
+    /// The cipher of a new stash, like "aes-256-gcm"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cipher: Option<crate::crypto::Cipher>,
+
+    /// The compression of a new stash, like "zstd-19"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<crate::compress::Compression>,
+
+    /// The compression of the files matching each rule, instead of
+    /// the rules recorded in the stash
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression_rules: Option<Vec<crate::compress::CompressionRule>>,
+
+    /// Compress the metadata with a trained dictionary
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub metadata_dictionary: bool,
+
+    /// Only write what changed to the metadata, for up to this many
+    /// commits in a row, see `Stash::set_delta_commits`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delta_commits: Option<u32>,
+
+    /// Never delete or overwrite objects, see `Stash::set_immutable`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub immutable: bool,
+
+    /// The most MiB the chunks of the stash may take up as they're
+    /// stored, see `Stash::set_quota`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quota_mib: Option<u64>,
+
+    /// The chunker of a new stash, like "fastcdc"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunker: Option<crate::splitter::Chunker>,
+
+    /// The chunk sizes of a new stash
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunk_sizes: Option<crate::splitter::ChunkSizes>,
+
+    /// The size of the data objects of a new stash, in bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub object_size: Option<usize>,
+
+    /// The secret of the convergence family of a new stash, in hex
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub convergence: Option<crate::crypto::ConvergenceSecret>,
+}
+
+/// A local copy of recently used objects of a stash, so they don't
+/// have to be fetched from the backend again.
+#[cfg(feature = "fs")]
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Cache {
+    pub path: String,
+    /// The most the cache may hold, in MiB
+    pub size_mib: u64,
+    #[serde(default)]
+    pub eviction: crate::backends::Eviction,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+#[serde(tag = "source")]
+pub enum Key {
+    #[serde(rename = "plaintext")]
+    Plaintext { user: String, password: String },
+    #[serde(rename = "ask")]
+    None,
+    /// Ask for shares of the master key, see `Stash::split_key`
+    #[serde(rename = "shares")]
+    Shares,
+    /// Open the stash with a keyfile, see `crypto::keyfile`
+    #[serde(rename = "keyfile")]
+    Keyfile { path: String },
+    /// Write as an append-only writer, see `Stash::append_only`
+    #[serde(rename = "public-key")]
+    PublicKey {
+        public_key: crate::crypto::PublicKey,
+    },
+    /// A master key wrapped by `kms::new_key`, in base64
+    #[cfg(feature = "kms")]
+    #[serde(rename = "kms")]
+    Kms {
+        service: KmsService,
+        key: String,
+        wrapped: String,
+    },
+    /// A master key wrapped by a key `id` on a PKCS#11 token, see
+    /// `kms::Pkcs11`
+    #[cfg(feature = "kms")]
+    #[serde(rename = "pkcs11")]
+    Pkcs11 {
+        module: String,
+        id: String,
+        wrapped: String,
+    },
+}
+
+#[cfg(feature = "kms")]
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum KmsService {
+    Aws,
+    Gcp,
+    Vault,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+#[serde(tag = "type")]
+pub enum Backend {
+    /// A local directory, with the objects under `prefix` in it if
+    /// it's shared by stashes, like `prefix = "home/"`
+    #[serde(rename = "fs")]
+    Filesystem {
+        path: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        prefix: Option<String>,
+    },
+    /// A read-only `Gateway`, like `address = "backup.local:8080"`
+    #[cfg(feature = "gateway")]
+    #[serde(rename = "gateway")]
+    Gateway { address: String },
+    /// A bucket of S3, or a compatible service at `endpoint`.
+    /// Unless set, the keys are taken from `AWS_ACCESS_KEY_ID` and
+    /// `AWS_SECRET_ACCESS_KEY`.
+    #[cfg(feature = "cloud")]
+    #[serde(rename = "s3")]
+    S3 {
+        bucket: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        region: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        endpoint: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        prefix: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        access_key: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        secret_key: Option<String>,
+        /// The storage class of file contents, like `DEEP_ARCHIVE`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        archive_class: Option<String>,
+    },
+    /// A Backblaze B2 bucket. Unless set, the application key is
+    /// taken from `B2_APPLICATION_KEY_ID` and `B2_APPLICATION_KEY`.
+    #[cfg(feature = "cloud")]
+    #[serde(rename = "b2")]
+    B2 {
+        bucket: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        prefix: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key_id: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        application_key: Option<String>,
+    },
+    /// A container of an Azure Storage account, authorized by
+    /// `account_key` or `sas`. Unless set, they're taken from
+    /// `AZURE_STORAGE_KEY` or `AZURE_STORAGE_SAS_TOKEN`.
+    #[cfg(feature = "cloud")]
+    #[serde(rename = "azure")]
+    Azure {
+        account: String,
+        container: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        prefix: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        endpoint: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        account_key: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        sas: Option<String>,
+    },
+    /// A Google Cloud Storage bucket, written as the service account
+    /// with the JSON key in `service_account`. Unless set, the key is
+    /// read from `GOOGLE_APPLICATION_CREDENTIALS`.
+    #[cfg(feature = "cloud")]
+    #[serde(rename = "gcs")]
+    Gcs {
+        bucket: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        prefix: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        service_account: Option<String>,
+    },
+    /// A collection on a WebDAV server, with basic authentication if
+    /// `user` is set, or a bearer `token`
+    #[cfg(feature = "cloud")]
+    #[serde(rename = "webdav")]
+    WebDav {
+        url: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        user: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        password: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        token: Option<String>,
+        /// Don't verify the TLS certificate of the server
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        insecure: bool,
+        /// Verify the server with the CA certificates in this file
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ca_file: Option<String>,
+    },
+    /// A directory on a server reachable over SSH, with the keys of
+    /// the agent unless `identity` is set
+    #[cfg(feature = "sftp")]
+    #[serde(rename = "sftp")]
+    Sftp {
+        destination: String,
+        path: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        port: Option<u16>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        identity: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        concurrency: Option<usize>,
+    },
+    /// Objects stored by an external `program`, run with `args`,
+    /// which speaks the protocol of `HelperBackend`
+    #[cfg(feature = "helper")]
+    #[serde(rename = "helper")]
+    Helper {
+        program: String,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        args: Vec<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        processes: Option<usize>,
+    },
+    /// Writes to all of `backends`, and reads from the first that has
+    /// an object. Writes succeed if `quorum` of them do, or all of
+    /// them, unless set.
+    #[serde(rename = "mirror")]
+    Mirror {
+        backends: Vec<Backend>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        quorum: Option<usize>,
+    },
+}
+
+/// Settings that apply to every stash. Unset values keep the
+/// defaults of `StashBuilder`.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Tuning {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub threads: Option<usize>,
+    /// Threads uploading full objects, see `Stash::set_uploads`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uploads: Option<usize>,
+    /// Threads reading objects to restore, see `Stash::set_downloads`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub downloads: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parallel_chunking: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_open_files: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schedule: Option<Schedule>,
+    /// Bandwidth limits for the backend, in KiB per second
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upload_kib: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub download_kib: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunk_level: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata_level: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata_block_size: Option<usize>,
+    /// Memory limit of operations, in MiB
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory_mib: Option<u64>,
+}
+
+impl Config {
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Config> {
+        Config::from_toml(&fs::read_to_string(path)?)
+    }
+
+    pub fn from_toml(s: &str) -> Result<Config> {
+        Config::from_value(toml::from_str(s)?)
+    }
+
+    /// The configuration as TOML, which `from_toml` reads back. The
+    /// environment variables it referred to are substituted, so it
+    /// may hold secrets.
+    pub fn to_toml(&self) -> Result<String> {
+        // through a value, which puts plain keys before tables
+        Ok(toml::to_string(&toml::Value::try_from(self)?)?)
+    }
+
+    /// Interpolate environment variables in an already parsed
+    /// document, then validate it.
+    ///
+    /// Frontends with their own TOML loading can use this to get the
+    /// same behaviour.
+    pub fn from_value(mut value: toml::Value) -> Result<Config> {
+        interpolate(&mut value)?;
+
+        let config = Config::deserialize(value)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.tuning.threads == Some(0) {
+            return Err(ConfigError::Invalid("threads must be at least 1".into()));
+        }
+        if self.tuning.max_open_files == Some(0) {
+            return Err(ConfigError::Invalid(
+                "max_open_files must be at least 1".into(),
+            ));
+        }
+        if self.tuning.upload_kib == Some(0) || self.tuning.download_kib == Some(0) {
+            return Err(ConfigError::Invalid(
+                "bandwidth limits must be at least 1 KiB".into(),
+            ));
+        }
+        if self.tuning.memory_mib == Some(0) {
+            return Err(ConfigError::Invalid("memory_mib must be at least 1".into()));
+        }
+        self.tuning
+            .compression()
+            .check()
+            .map_err(ConfigError::Invalid)?;
+
+        for (alias, stash) in self.stashes.iter() {
+            for pattern in stash.exclude.iter() {
+                glob::Pattern::new(pattern).map_err(|e| {
+                    ConfigError::Invalid(format!(
+                        "stash `{}`: bad exclude `{}`: {}",
+                        alias, pattern, e
+                    ))
+                })?;
+            }
+            if let Some(rules) = &stash.compression_rules {
+                crate::compress::CompressionRules::new(Default::default(), rules)
+                    .map_err(|e| ConfigError::Invalid(format!("stash `{}`: {}", alias, e)))?;
+            }
+
+            stash
+                .backend
+                .validate()
+                .map_err(|e| ConfigError::Invalid(format!("stash `{}`: {}", alias, e)))?;
+        }
+
+        Ok(())
+    }
+
+    pub fn resolve_stash(&self, alias: impl AsRef<str>) -> Option<&Stash> {
+        self.stashes.get(alias.as_ref())
+    }
+}
+
+impl Backend {
+    fn validate(&self) -> std::result::Result<(), &'static str> {
+        match self {
+            Backend::Filesystem { path, .. } if path.is_empty() => Err("empty backend path"),
+            #[cfg(feature = "cloud")]
+            Backend::S3 { bucket, .. }
+            | Backend::B2 { bucket, .. }
+            | Backend::Gcs { bucket, .. }
+            | Backend::Azure {
+                container: bucket, ..
+            } if bucket.is_empty() => Err("empty bucket"),
+            #[cfg(feature = "helper")]
+            Backend::Helper { program, .. } if program.is_empty() => Err("empty helper program"),
+            #[cfg(feature = "helper")]
+            Backend::Helper {
+                processes: Some(0), ..
+            } => Err("processes must be at least 1"),
+            Backend::Mirror { backends, .. } if backends.is_empty() => {
+                Err("no backends to mirror to")
+            }
+            Backend::Mirror {
+                backends,
+                quorum: Some(quorum),
+            } if *quorum == 0 || *quorum > backends.len() => {
+                Err("quorum must be between 1 and the number of backends")
+            }
+            Backend::Mirror { backends, .. } => backends.iter().try_for_each(Backend::validate),
+            _ => Ok(()),
+        }
+    }
+
+    /// Open the backend this describes.
+    #[cfg(feature = "fs")]
+    pub fn open(
+        &self,
+    ) -> std::result::Result<std::sync::Arc<dyn crate::backends::Backend>, ZerostashError> {
+        Ok(match self {
+            Backend::Filesystem { path, prefix } => {
+                let directory = Directory::new(path)?;
+                std::sync::Arc::new(match prefix {
+                    Some(prefix) => directory.prefix(prefix),
+                    None => directory,
+                })
+            }
+            Backend::Mirror { backends, quorum } => {
+                use crate::backends::{MirrorBackend, Quorum};
+
+                let backends = backends
+                    .iter()
+                    .map(Backend::open)
+                    .collect::<std::result::Result<_, _>>()?;
+                let quorum = quorum.map_or(Quorum::All, Quorum::AtLeast);
+                std::sync::Arc::new(MirrorBackend::new(backends).quorum(quorum))
+            }
+            #[cfg(feature = "gateway")]
+            Backend::Gateway { address } => std::sync::Arc::new(
+                crate::backends::RetryBackend::new(crate::backends::Remote::new(address.as_str())),
+            ),
+            #[cfg(feature = "cloud")]
+            Backend::S3 {
+                bucket,
+                region,
+                endpoint,
+                prefix,
+                access_key,
+                secret_key,
+                archive_class,
+            } => {
+                use crate::backends::{Credentials, S3Backend};
+
+                let credentials = match (access_key, secret_key) {
+                    (Some(access), Some(secret)) => Credentials::new(access, secret),
+                    _ => Credentials::from_env().ok_or_else(|| {
+                        ZerostashError::Config("no credentials for the S3 backend".into())
+                    })?,
+                };
+                let mut s3 = S3Backend::new(bucket, credentials);
+                if let Some(region) = region {
+                    s3 = s3.region(region);
+                }
+                if let Some(endpoint) = endpoint {
+                    s3 = s3.endpoint(endpoint);
+                }
+                if let Some(prefix) = prefix {
+                    s3 = s3.prefix(prefix);
+                }
+                if let Some(class) = archive_class {
+                    s3 = s3.archive_class(class);
+                }
+                std::sync::Arc::new(s3)
+            }
+            #[cfg(feature = "cloud")]
+            Backend::B2 {
+                bucket,
+                prefix,
+                key_id,
+                application_key,
+            } => {
+                let from_env = |name| {
+                    std::env::var(name).map_err(|_| {
+                        ZerostashError::Config(format!("{} is not set for the B2 backend", name))
+                    })
+                };
+                let key_id = match key_id {
+                    Some(id) => id.clone(),
+                    None => from_env("B2_APPLICATION_KEY_ID")?,
+                };
+                let application_key = match application_key {
+                    Some(key) => key.clone(),
+                    None => from_env("B2_APPLICATION_KEY")?,
+                };
+
+                let mut b2 = crate::backends::B2Backend::new(bucket, key_id, application_key);
+                if let Some(prefix) = prefix {
+                    b2 = b2.prefix(prefix);
+                }
+                std::sync::Arc::new(b2)
+            }
+            #[cfg(feature = "cloud")]
+            Backend::Azure {
+                account,
+                container,
+                prefix,
+                endpoint,
+                account_key,
+                sas,
+            } => {
+                use crate::backends::{AzureAuth, AzureBackend};
+
+                let auth = match (account_key, sas) {
+                    (Some(key), _) => AzureAuth::account_key(key),
+                    (None, Some(sas)) => AzureAuth::sas(sas),
+                    (None, None) => AzureAuth::from_env().ok_or_else(|| {
+                        ZerostashError::Config("no credentials for the Azure backend".into())
+                    })?,
+                };
+
+                let mut azure = AzureBackend::new(account, container, auth);
+                if let Some(prefix) = prefix {
+                    azure = azure.prefix(prefix);
+                }
+                if let Some(endpoint) = endpoint {
+                    azure = azure.endpoint(endpoint);
+                }
+                std::sync::Arc::new(azure)
+            }
+            #[cfg(feature = "cloud")]
+            Backend::Gcs {
+                bucket,
+                prefix,
+                service_account,
+            } => {
+                use crate::backends::{GcsBackend, ServiceAccount};
+
+                let path = match service_account {
+                    Some(path) => path.clone(),
+                    None => std::env::var("GOOGLE_APPLICATION_CREDENTIALS").map_err(|_| {
+                        ZerostashError::Config("no service account for the GCS backend".into())
+                    })?,
+                };
+
+                let mut gcs = GcsBackend::new(bucket, ServiceAccount::from_file(path)?);
+                if let Some(prefix) = prefix {
+                    gcs = gcs.prefix(prefix);
+                }
+                std::sync::Arc::new(gcs)
+            }
+            #[cfg(feature = "cloud")]
+            Backend::WebDav {
+                url,
+                user,
+                password,
+                token,
+                insecure,
+                ca_file,
+            } => {
+                use crate::backends::{Curl, WebDavAuth, WebDavBackend};
+
+                let auth = match (user, token) {
+                    (Some(user), _) => {
+                        WebDavAuth::basic(user, password.clone().unwrap_or_default())
+                    }
+                    (None, Some(token)) => WebDavAuth::bearer(token),
+                    (None, None) => WebDavAuth::None,
+                };
+                let mut curl = Curl::default().insecure(*insecure);
+                if let Some(ca_file) = ca_file {
+                    curl = curl.ca_file(ca_file);
+                }
+                std::sync::Arc::new(WebDavBackend::new(url, auth).curl(curl))
+            }
+            #[cfg(feature = "sftp")]
+            Backend::Sftp {
+                destination,
+                path,
+                port,
+                identity,
+                concurrency,
+            } => {
+                let mut sftp = crate::backends::SftpBackend::new(destination, path);
+                if let Some(port) = port {
+                    sftp = sftp.port(*port);
+                }
+                if let Some(identity) = identity {
+                    sftp = sftp.identity(identity);
+                }
+                if let Some(concurrency) = concurrency {
+                    sftp = sftp.concurrency(*concurrency);
+                }
+                std::sync::Arc::new(crate::backends::RetryBackend::new(sftp))
+            }
+            #[cfg(feature = "helper")]
+            Backend::Helper {
+                program,
+                args,
+                processes,
+            } => {
+                let helper = crate::backends::HelperBackend::new(program, args)
+                    .processes(processes.unwrap_or(1));
+                std::sync::Arc::new(helper)
+            }
+        })
+    }
+}
+
+impl Key {
+    /// Open the stash of `builder` with the key stored in the
+    /// configuration, or the credentials `prompt` asks for.
+    pub fn apply(
+        &self,
+        builder: StashBuilder,
+        prompt: &dyn SecretPrompt,
+    ) -> std::result::Result<StashBuilder, ZerostashError> {
+        match self {
+            Key::Plaintext { user, password } => Ok(builder.credentials(user, password)),
+            Key::None => {
+                let (user, password) = prompt::ask_credentials(prompt)?;
+                Ok(builder.credentials(user, password.expose_secret()))
+            }
+            Key::Shares => Ok(builder.shares(prompt::ask_shares(prompt)?)),
+            Key::Keyfile { path } => {
+                let keyfile = crate::crypto::keyfile::Keyfile::parse(&fs::read(path)?)?;
+                Ok(builder.key(keyfile.into_key()))
+            }
+            Key::PublicKey { public_key } => Ok(builder.append_only(*public_key)),
+            #[cfg(feature = "kms")]
+            Key::Kms {
+                service,
+                key,
+                wrapped,
+            } => open_wrapped(builder, service.wrapper(key), wrapped, prompt),
+            #[cfg(feature = "kms")]
+            Key::Pkcs11 {
+                module,
+                id,
+                wrapped,
+            } => open_wrapped(
+                builder,
+                Box::new(crate::kms::Pkcs11::new(module, id)),
+                wrapped,
+                prompt,
+            ),
+        }
+    }
+}
+
+/// Unwrap the base64 `wrapped` master key with `wrapper`, once
+/// `prompt` unlocks it.
+#[cfg(feature = "kms")]
+fn open_wrapped(
+    builder: StashBuilder,
+    mut wrapper: Box<dyn crate::kms::KeyWrapper>,
+    wrapped: &str,
+    prompt: &dyn SecretPrompt,
+) -> std::result::Result<StashBuilder, ZerostashError> {
+    use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+
+    let wrapped = BASE64
+        .decode(wrapped)
+        .map_err(|_| ZerostashError::Config("wrapped key isn't base64".into()))?;
+    wrapper.unlock(prompt)?;
+    Ok(builder.key(crate::kms::open_key(&*wrapper, &wrapped)?))
+}
+
+#[cfg(feature = "kms")]
+impl KmsService {
+    /// The wrapper for `key` of this service.
+    pub fn wrapper(&self, key: &str) -> Box<dyn crate::kms::KeyWrapper> {
+        use crate::kms::{AwsKms, GcpKms, VaultTransit};
+
+        match self {
+            KmsService::Aws => Box::new(AwsKms::new(key)),
+            KmsService::Gcp => Box::new(GcpKms::new(key)),
+            KmsService::Vault => Box::new(VaultTransit::new(key)),
+        }
+    }
+}
+
+impl Stash {
+    /// Set up a builder for this stash, opening the backend, with
+    /// the configured key or the credentials `prompt` asks for.
+    #[cfg(feature = "fs")]
+    pub fn builder(
+        &self,
+        prompt: &dyn SecretPrompt,
+        tuning: &Tuning,
+    ) -> std::result::Result<StashBuilder, ZerostashError> {
+        let mut backend = self.backend.open()?;
+        if self.verify_writes {
+            backend = std::sync::Arc::new(crate::backends::VerifiedBackend::new(backend));
+        }
+        if let Some(cache) = &self.cache {
+            let cached = crate::backends::CachedBackend::new(
+                backend,
+                &cache.path,
+                cache.size_mib * 1024 * 1024,
+            )?;
+            backend = std::sync::Arc::new(cached.eviction(cache.eviction));
+        }
+
+        let mut builder = self
+            .key
+            .apply(StashBuilder::new().backend(backend), prompt)?;
+        if let Some(cipher) = self.cipher {
+            builder = builder.cipher(cipher);
+        }
+        if let Some(compression) = self.compression {
+            builder = builder.compression(compression);
+        }
+        if let Some(rules) = self.compression_rules.clone() {
+            builder = builder.compression_rules(rules);
+        }
+        if self.metadata_dictionary {
+            builder = builder.metadata_dictionary(true);
+        }
+        if let Some(commits) = self.delta_commits {
+            builder = builder.delta_commits(commits);
+        }
+        if self.immutable {
+            builder = builder.immutable(true);
+        }
+        if let Some(mib) = self.quota_mib {
+            builder = builder.quota(mib * 1024 * 1024);
+        }
+        if let Some(chunker) = self.chunker {
+            builder = builder.chunker(chunker);
+        }
+        if let Some(sizes) = self.chunk_sizes {
+            builder = builder.chunk_sizes(sizes);
+        }
+        if let Some(size) = self.object_size {
+            builder = builder.object_size(size);
+        }
+        if let Some(convergence) = self.convergence.clone() {
+            builder = builder.convergent(convergence);
+        }
+        if let Some(path) = &self.file_cache {
+            builder = builder.file_cache(path);
+        }
+        if let Some(path) = &self.index_cache {
+            builder = builder.index_cache(path);
+        }
+        if let Some(path) = &self.find_index {
+            builder = builder.find_index(path);
+        }
+        Ok(tuning.apply(builder))
+    }
+}
+
+impl Tuning {
+    pub fn apply(&self, mut builder: StashBuilder) -> StashBuilder {
+        if let Some(threads) = self.threads {
+            builder = builder.threads(threads);
+        }
+        if let Some(uploads) = self.uploads {
+            builder = builder.uploads(uploads);
+        }
+        if let Some(downloads) = self.downloads {
+            builder = builder.downloads(downloads);
+        }
+        if let Some(parallel) = self.parallel_chunking {
+            builder = builder.parallel_chunking(parallel);
+        }
+        if let Some(limit) = self.max_open_files {
+            builder = builder.max_open_files(limit);
+        }
+        if let Some(schedule) = self.schedule {
+            builder = builder.schedule(schedule);
+        }
+        if let Some(kib) = self.upload_kib {
+            builder = builder.upload_limit(kib * 1024);
+        }
+        if let Some(kib) = self.download_kib {
+            builder = builder.download_limit(kib * 1024);
+        }
+        if let Some(mib) = self.memory_mib {
+            builder = builder.memory_limit(mib * 1024 * 1024);
+        }
+        builder.tuning(self.compression())
+    }
+
+    /// The compression tuning, with the defaults for what isn't set.
+    pub fn compression(&self) -> crate::compress::Tuning {
+        let defaults = crate::compress::Tuning::default();
+        crate::compress::Tuning {
+            chunk_level: self.chunk_level,
+            stream_level: self.metadata_level.unwrap_or(defaults.stream_level),
+            stream_block_size: self
+                .metadata_block_size
+                .unwrap_or(defaults.stream_block_size),
+        }
+    }
+}
+
+fn interpolate(value: &mut toml::Value) -> Result<()> {
+    use toml::Value::*;
+
+    match value {
+        String(s) => *s = expand(s)?,
+        Array(a) => a.iter_mut().try_for_each(interpolate)?,
+        Table(t) => t.iter_mut().try_for_each(|(_, v)| interpolate(v))?,
+        _ => {}
+    }
+
+    Ok(())
+}
+
+fn expand(s: &str) -> Result<String> {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+
+    while let Some(start) = rest.find('$') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        if rest.starts_with("$$") {
+            out.push('$');
+            rest = &rest[2..];
+        } else if rest.starts_with("${") {
+            let end = rest
+                .find('}')
+                .ok_or_else(|| ConfigError::Unterminated(s.to_string()))?;
+            let name = &rest[2..end];
+            let var =
+                env::var(name).map_err(|_| ConfigError::UndefinedVariable(name.to_string()))?;
+
+            out.push_str(&var);
+            rest = &rest[end + 1..];
+        } else {
+            out.push('$');
+            rest = &rest[1..];
+        }
+    }
+
+    out.push_str(rest);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn parse_interpolate_and_validate() {
+        use super::*;
+
+        env::set_var("ZEROSTASH_TEST_CONFIG_PASSWORD", "secret");
+        let config = Config::from_toml(
+            r#"
+[stash.home]
+key = { source = "plaintext", user = "me", password = "${ZEROSTASH_TEST_CONFIG_PASSWORD}" }
+backend = { type = "fs", path = "/path/to/$$stash" }
+exclude = ["*.tmp"]
+file_cache = "/var/cache/$$home.files"
+compression_rules = [{ pattern = "*/Videos/*", compression = "none" }]
+
+[stash.work]
+key = { source = "ask" }
+backend = { type = "fs", path = "/path/to/shared", prefix = "work/" }
+delta_commits = 8
+
+[tuning]
+threads = 4
+uploads = 2
+downloads = 8
+schedule = "small-first"
+metadata_level = 9
+memory_mib = 512
+"#,
+        )
+        .unwrap();
+        // what's written is read back the same
+        let written = Config::from_toml(&config.to_toml().unwrap()).unwrap();
+        assert_eq!(written.to_toml().unwrap(), config.to_toml().unwrap());
+        assert_eq!(written.tuning.uploads, Some(2));
+        assert_eq!(written.tuning.downloads, Some(8));
+
+        let home = config.resolve_stash("home").unwrap();
+        match (&home.key, &home.backend) {
+            (Key::Plaintext { password, .. }, Backend::Filesystem { path, .. }) => {
+                assert_eq!(password, "secret");
+                assert_eq!(path, "/path/to/$stash");
+            }
+            _ => panic!("wrong stash"),
+        }
+        assert_eq!(home.file_cache.as_deref(), Some("/var/cache/$home.files"));
+        assert_eq!(
+            home.compression_rules.as_ref().unwrap()[0].compression,
+            "none"
+        );
+        let work = config.resolve_stash("work").unwrap();
+        match &work.backend {
+            Backend::Filesystem { prefix, .. } => assert_eq!(prefix.as_deref(), Some("work/")),
+            _ => panic!("wrong stash"),
+        }
+        assert_eq!(work.delta_commits, Some(8));
+        assert_eq!(config.tuning.threads, Some(4));
+        assert_eq!(config.tuning.schedule, Some(Schedule::SmallFirst));
+        assert_eq!(config.tuning.memory_mib, Some(512));
+        assert_eq!(config.tuning.compression().stream_level, 9);
+        assert!(matches!(
+            Config::from_toml("[tuning]\nmetadata_block_size = 100"),
+            Err(ConfigError::Invalid(_))
+        ));
+
+        #[cfg(feature = "kms")]
+        {
+            let config = Config::from_toml(
+                "[stash.a]\nkey = { source = \"kms\", service = \"vault\", key = \"backups\", wrapped = \"dmF1bHQ6\" }\nbackend = { type = \"fs\", path = \"/a\" }",
+            )
+            .unwrap();
+            assert!(matches!(
+                config.resolve_stash("a").unwrap().key,
+                Key::Kms {
+                    service: KmsService::Vault,
+                    ..
+                }
+            ));
+
+            let config = Config::from_toml(
+                "[stash.a]\nkey = { source = \"pkcs11\", module = \"libykcs11.so\", id = \"03\", wrapped = \"dG9rZW4=\" }\nbackend = { type = \"fs\", path = \"/a\" }",
+            )
+            .unwrap();
+            assert!(matches!(
+                config.resolve_stash("a").unwrap().key,
+                Key::Pkcs11 { .. }
+            ));
+        }
+
+        let config = Config::from_toml(
+            "[stash.a]\nkey = { source = \"keyfile\", path = \"/etc/a.key\" }\nbackend = { type = \"fs\", path = \"/a\" }",
+        )
+        .unwrap();
+        assert!(matches!(
+            config.resolve_stash("a").unwrap().key,
+            Key::Keyfile { ref path } if path == "/etc/a.key"
+        ));
+
+        let append = |key: &str| {
+            Config::from_toml(&format!(
+                "[stash.a]\nkey = {{ source = \"public-key\", public_key = \"{}\" }}\nbackend = {{ type = \"fs\", path = \"/a\" }}",
+                key
+            ))
+        };
+        assert!(matches!(
+            append(&"ab".repeat(32))
+                .unwrap()
+                .resolve_stash("a")
+                .unwrap()
+                .key,
+            Key::PublicKey { .. }
+        ));
+        assert!(matches!(append("ab"), Err(ConfigError::Parse { .. })));
+
+        #[cfg(feature = "cloud")]
+        assert!(matches!(
+            Config::from_toml(
+                "[stash.a]\nkey = { source = \"ask\" }\nbackend = { type = \"s3\", bucket = \"\" }"
+            ),
+            Err(ConfigError::Invalid(_))
+        ));
+
+        assert!(matches!(
+            Config::from_toml(
+                "[stash.a]\nkey = { source = \"ask\" }\nbackend = { type = \"mirror\", quorum = 1, backends = [{ type = \"fs\", path = \"\" }] }"
+            ),
+            Err(ConfigError::Invalid(_))
+        ));
+        assert!(matches!(
+            Config::from_toml("[stash.a]\nkey = { source = \"${ZEROSTASH_TEST_UNSET}\" }"),
+            Err(ConfigError::UndefinedVariable(_))
+        ));
+        assert!(matches!(
+            Config::from_toml("[tuning]\nthreads = 0"),
+            Err(ConfigError::Invalid(_))
+        ));
+        assert!(matches!(
+            Config::from_toml("[tuning]\nunknown = 1"),
+            Err(ConfigError::Parse { .. })
+        ));
+    }
+}
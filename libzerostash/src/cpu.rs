@@ -0,0 +1,103 @@
+//! Runtime CPU feature detection.
+//!
+//! Blake2 hashing picks its accelerated implementation at runtime,
+//! and new stashes pick their cipher by whether the CPU accelerates
+//! AES. Setting `ZEROSTASH_PORTABLE=1` in the environment, or calling
+//! `force_portable(true)`, forces the portable Blake2 and reports no
+//! features. Ring does its own detection, which this doesn't affect.
+use std::sync::atomic::{AtomicU8, Ordering};
+
+const UNKNOWN: u8 = 0;
+const ACCELERATED: u8 = 1;
+const PORTABLE: u8 = 2;
+
+static MODE: AtomicU8 = AtomicU8::new(UNKNOWN);
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Features {
+    pub aes: bool,
+    pub sse41: bool,
+    pub avx2: bool,
+    pub neon: bool,
+}
+
+impl Features {
+    /// Features supported by the CPU we're running on
+    #[allow(unreachable_code)]
+    pub fn detect() -> Features {
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        return Features {
+            aes: is_x86_feature_detected!("aes"),
+            sse41: is_x86_feature_detected!("sse4.1"),
+            avx2: is_x86_feature_detected!("avx2"),
+            neon: false,
+        };
+
+        #[cfg(target_arch = "aarch64")]
+        return Features {
+            neon: true,
+            ..Features::default()
+        };
+
+        Features::default()
+    }
+}
+
+/// Features that implementations are allowed to use.
+///
+/// Returns no features if the portable implementations are forced.
+pub fn features() -> Features {
+    features_for(is_portable())
+}
+
+fn features_for(portable: bool) -> Features {
+    if portable {
+        Features::default()
+    } else {
+        Features::detect()
+    }
+}
+
+pub fn is_portable() -> bool {
+    match MODE.load(Ordering::Relaxed) {
+        UNKNOWN => {
+            let portable = std::env::var_os("ZEROSTASH_PORTABLE")
+                .map(|v| v != "0" && !v.is_empty())
+                .unwrap_or(false);
+            if portable {
+                info!("ZEROSTASH_PORTABLE is set, using portable implementations");
+            }
+            force_portable(portable);
+            portable
+        }
+        mode => mode == PORTABLE,
+    }
+}
+
+pub fn force_portable(portable: bool) {
+    MODE.store(
+        if portable { PORTABLE } else { ACCELERATED },
+        Ordering::Relaxed,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    // the mode is global, so this leaves it alone for the tests
+    // running alongside
+    #[test]
+    fn portable_hashing_matches_accelerated() {
+        use super::*;
+        use crate::crypto::blake2_for;
+
+        let data = std::fs::read("tests/data/10k_random_blob").unwrap();
+
+        let accelerated = blake2_for(false).hash(&data);
+        assert_eq!(features_for(false), Features::detect());
+
+        let portable = blake2_for(true).hash(&data);
+        assert_eq!(features_for(true), Features::default());
+
+        assert_eq!(accelerated, portable);
+    }
+}
@@ -1,18 +1,44 @@
 use crate::chunks::ChunkPointer;
 use crate::objects::{Object, ObjectId, WriteObject};
+use crate::splitter::BuzhashTable;
 
 use blake2b_simd::blake2bp::Params as Blake2;
+use chacha20::cipher::consts::U10;
+use chacha20::cipher::generic_array::GenericArray;
+use chacha20::hchacha;
 use getrandom::getrandom;
+use itertools::Itertools;
 use ring::aead;
+use ring::signature::{self, Ed25519KeyPair, KeyPair, UnparsedPublicKey};
 use secrecy::{ExposeSecret, Secret};
 use thiserror::Error;
-use zeroize::Zeroize;
+use x25519_dalek::{x25519, X25519_BASEPOINT_BYTES};
+use zeroize::{Zeroize, Zeroizing};
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+pub mod convergence;
+pub mod keyfile;
+pub mod shamir;
+
+pub use convergence::ConvergenceSecret;
 
 pub const CRYPTO_DIGEST_SIZE: usize = 32;
 pub type CryptoDigest = [u8; CRYPTO_DIGEST_SIZE];
 pub type Tag = [u8; 16];
 type Nonce = [u8; 12];
+/// The nonce of XChaCha20-Poly1305, which the other ciphers take the
+/// first 12 bytes of
+type XNonce = [u8; 24];
 type Key = Secret<[u8; CRYPTO_DIGEST_SIZE]>;
+/// Scratch space for key material, wiped when it goes out of scope
+type KeyBuffer = Zeroizing<[u8; CRYPTO_DIGEST_SIZE]>;
+/// The keys of the chunks a grant gives access to, by their hash
+type GrantedKeys = Arc<HashMap<CryptoDigest, Key>>;
 
 #[derive(Error, Debug)]
 pub enum CryptoError {
@@ -21,6 +47,16 @@ pub enum CryptoError {
         #[from]
         source: argon2::Error,
     },
+    #[error("Failed to decrypt data")]
+    Decrypt,
+    #[error("Invalid public key: {0}")]
+    InvalidPublicKey(String),
+    #[error("Invalid key shares: {0}")]
+    InvalidShares(String),
+    #[error("Invalid keyfile: {0}")]
+    InvalidKeyfile(String),
+    #[error("Invalid convergence secret, it should be 32 bytes of hex")]
+    InvalidConvergenceSecret,
 }
 pub type Result<T> = std::result::Result<T, CryptoError>;
 
@@ -28,7 +64,7 @@ pub fn chunk_hash(content: &[u8]) -> CryptoDigest {
     let mut output = CryptoDigest::default();
 
     output.copy_from_slice(
-        Blake2::new()
+        blake2()
             .hash_length(CRYPTO_DIGEST_SIZE)
             .hash(content)
             .as_bytes(),
@@ -37,6 +73,22 @@ pub fn chunk_hash(content: &[u8]) -> CryptoDigest {
     output
 }
 
+/// Blake2 picks the fastest implementation at runtime, unless
+/// portable code paths are forced.
+#[inline]
+fn blake2() -> Blake2 {
+    blake2_for(crate::cpu::is_portable())
+}
+
+#[inline]
+pub(crate) fn blake2_for(portable: bool) -> Blake2 {
+    let mut params = Blake2::new();
+    if portable {
+        blake2b_simd::benchmarks::force_portable_blake2bp(&mut params);
+    }
+    params
+}
+
 pub trait Random {
     fn fill(&self, buf: &mut [u8]);
 }
@@ -50,23 +102,350 @@ pub trait CryptoProvider: Random + Clone + Send {
         target: &mut [u8],
         o: &Object<T>,
         chunk: &ChunkPointer,
-    ) -> usize;
+    ) -> Result<usize>;
 
     fn decrypt_object_into<I: AsRef<[u8]>, O: AsMut<[u8]>>(
         &self,
         output: &mut Object<O>,
         obj: &Object<I>,
-    );
+    ) -> Result<()>;
+}
+
+/// The AEAD that encrypts the data and metadata of a stash. It's
+/// recorded in the root object, which is opened by trying each.
+///
+/// Nonces are taken from the random ids of objects. The 96 bits that
+/// ChaCha20-Poly1305 and AES-256-GCM take are likely to repeat after
+/// 2^48 objects under one key, the 192 bits of XChaCha20-Poly1305
+/// never do.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Cipher {
+    #[serde(rename = "chacha20-poly1305")]
+    ChaCha20Poly1305,
+    #[serde(rename = "aes-256-gcm")]
+    Aes256Gcm,
+    #[serde(rename = "xchacha20-poly1305")]
+    XChaCha20Poly1305,
+}
+
+/// Keys are always sealed with this, as they're opened before the
+/// cipher of the stash is known.
+const KEY_CIPHER: Cipher = Cipher::ChaCha20Poly1305;
+
+impl Default for Cipher {
+    /// The cipher of stashes from before it could be selected
+    fn default() -> Cipher {
+        Cipher::ChaCha20Poly1305
+    }
+}
+
+impl Cipher {
+    pub const ALL: [Cipher; 3] = [
+        Cipher::ChaCha20Poly1305,
+        Cipher::Aes256Gcm,
+        Cipher::XChaCha20Poly1305,
+    ];
+
+    /// AES-256-GCM if the CPU accelerates AES, XChaCha20-Poly1305
+    /// otherwise, which is faster in software.
+    pub fn fastest() -> Cipher {
+        if crate::cpu::features().aes {
+            Cipher::Aes256Gcm
+        } else {
+            Cipher::XChaCha20Poly1305
+        }
+    }
+
+    /// The name of the cipher in the format of the stash
+    pub fn name(self) -> &'static str {
+        match self {
+            Cipher::ChaCha20Poly1305 => "chacha20-poly1305",
+            Cipher::Aes256Gcm => "aes-256-gcm",
+            Cipher::XChaCha20Poly1305 => "xchacha20-poly1305",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Cipher> {
+        Cipher::ALL.iter().copied().find(|c| c.name() == name)
+    }
+
+    fn algorithm(self) -> &'static aead::Algorithm {
+        match self {
+            // with a subkey, see `get_object_aead`
+            Cipher::ChaCha20Poly1305 | Cipher::XChaCha20Poly1305 => &aead::CHACHA20_POLY1305,
+            Cipher::Aes256Gcm => &aead::AES_256_GCM,
+        }
+    }
+}
+
+/// How keys are derived from credentials. The parameters are stored
+/// with the stash, so they can change without breaking older ones.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kdf", rename_all = "kebab-case")]
+pub enum Kdf {
+    Argon2id {
+        memory_kib: u32,
+        iterations: u32,
+        parallelism: u32,
+    },
+}
+
+impl Default for Kdf {
+    /// The parameters of stashes from before they were stored.
+    fn default() -> Kdf {
+        Kdf::Argon2id {
+            memory_kib: 4096,
+            iterations: 3,
+            parallelism: 1,
+        }
+    }
+}
+
+impl Kdf {
+    /// Argon2id parameters that take about `target` to derive a key
+    /// on this machine.
+    pub fn calibrate(target: Duration) -> Result<Kdf> {
+        let parallelism = std::thread::available_parallelism()
+            .map(|n| n.get().min(4) as u32)
+            .unwrap_or(1);
+        let mut memory_kib = 64 * 1024;
+
+        loop {
+            let once = Kdf::Argon2id {
+                memory_kib,
+                iterations: 1,
+                parallelism,
+            };
+            let start = Instant::now();
+            once.derive(b"calibrate", b"calibrate")?;
+            let elapsed = start.elapsed().max(Duration::from_millis(1));
+
+            if elapsed > target && memory_kib > 8 * 1024 {
+                memory_kib /= 2;
+                continue;
+            }
+
+            let iterations = (target.as_secs_f64() / elapsed.as_secs_f64()).round();
+            return Ok(Kdf::Argon2id {
+                memory_kib,
+                iterations: (iterations as u32).max(1),
+                parallelism,
+            });
+        }
+    }
+
+    fn derive(&self, salt_raw: &[u8], password: &[u8]) -> Result<Key> {
+        let Kdf::Argon2id {
+            memory_kib,
+            iterations,
+            parallelism,
+        } = *self;
+        let salt = blake2().hash_length(16).hash(salt_raw);
+
+        let mut result = argon2::hash_raw(
+            password,
+            salt.as_bytes(),
+            &argon2::Config {
+                hash_length: CRYPTO_DIGEST_SIZE as u32,
+                variant: argon2::Variant::Argon2id,
+                mem_cost: memory_kib,
+                time_cost: iterations,
+                lanes: parallelism,
+                thread_mode: argon2::ThreadMode::from_threads(parallelism),
+                ..argon2::Config::default()
+            },
+        )?;
+
+        let mut outbuf = KeyBuffer::new([0; CRYPTO_DIGEST_SIZE]);
+        outbuf.copy_from_slice(&result);
+        result.zeroize();
+
+        Ok(Secret::new(*outbuf))
+    }
 }
 
 pub struct StashKey {
     master_key: Key,
+    cipher: Cipher,
+    convergence: Option<ConvergenceSecret>,
+    granted: Option<GrantedKeys>,
+}
+
+/// The X25519 public key of a stash, in hex when serialized. Keys
+/// sealed with it can only be opened with the master key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub struct PublicKey([u8; 32]);
+
+impl PublicKey {
+    /// Encrypt `key` for the stash, with an ephemeral key pair: the
+    /// public half, then `key` and its tag.
+    pub(crate) fn seal_key(&self, key: &StashKey) -> Vec<u8> {
+        let mut ephemeral = [0; 32];
+        getrandom(&mut ephemeral).unwrap();
+        let ephemeral_public = x25519(ephemeral, X25519_BASEPOINT_BYTES);
+        let shared = x25519(ephemeral, self.0);
+        ephemeral.zeroize();
+
+        // every sealing key is used once, so the nonce can be fixed
+        let mut sealed = key.expose().to_vec();
+        get_aead(KEY_CIPHER, sealing_key(shared, &ephemeral_public, &self.0))
+            .seal_in_place_append_tag(
+                aead::Nonce::assume_unique_for_key(Nonce::default()),
+                aead::Aad::empty(),
+                &mut sealed,
+            )
+            .unwrap();
+
+        [&ephemeral_public[..], &sealed].concat()
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}", self.0.iter().format(""))
+    }
+}
+
+impl From<PublicKey> for String {
+    fn from(key: PublicKey) -> String {
+        key.to_string()
+    }
+}
+
+impl TryFrom<String> for PublicKey {
+    type Error = CryptoError;
+
+    fn try_from(hex: String) -> Result<PublicKey> {
+        match ObjectId::from_hex(&hex) {
+            Some(id) => {
+                let mut key = [0; 32];
+                key.copy_from_slice(id.as_ref());
+                Ok(PublicKey(key))
+            }
+            None => Err(CryptoError::InvalidPublicKey(hex)),
+        }
+    }
 }
 
 impl StashKey {
+    /// The key derived from the credentials with the default `Kdf`.
+    /// `StashBuilder::credentials` uses the one stored in the stash.
     pub fn open_stash(username: impl AsRef<str>, password: impl AsRef<str>) -> Result<StashKey> {
-        derive_argon2(username.as_ref().as_bytes(), password.as_ref().as_bytes())
-            .map(|k| StashKey { master_key: k })
+        StashKey::derive(username, password, &Kdf::default())
+    }
+
+    pub fn derive(
+        username: impl AsRef<str>,
+        password: impl AsRef<str>,
+        kdf: &Kdf,
+    ) -> Result<StashKey> {
+        kdf.derive(username.as_ref().as_bytes(), password.as_ref().as_bytes())
+            .map(|k| StashKey {
+                master_key: k,
+                cipher: Cipher::fastest(),
+                convergence: None,
+                granted: None,
+            })
+    }
+
+    /// A random key, for stashes whose key is kept somewhere else
+    /// instead of derived from credentials.
+    pub fn generate() -> StashKey {
+        let mut key = [0; CRYPTO_DIGEST_SIZE];
+        getrandom(&mut key).unwrap();
+
+        StashKey::from_bytes(key)
+    }
+
+    pub(crate) fn from_bytes(mut key: [u8; CRYPTO_DIGEST_SIZE]) -> StashKey {
+        let stash_key = StashKey {
+            master_key: Secret::new(key),
+            cipher: Cipher::fastest(),
+            convergence: None,
+            granted: None,
+        };
+        key.zeroize();
+        stash_key
+    }
+
+    /// The cipher of the stash data. Until an existing stash is read,
+    /// this is the one a new stash would use.
+    pub fn cipher(&self) -> Cipher {
+        self.cipher
+    }
+
+    pub(crate) fn set_cipher(&mut self, cipher: Cipher) {
+        self.cipher = cipher;
+    }
+
+    /// The secret chunk keys are derived from, if they're convergent.
+    pub fn convergence(&self) -> Option<&ConvergenceSecret> {
+        self.convergence.as_ref()
+    }
+
+    pub(crate) fn set_convergence(&mut self, convergence: Option<ConvergenceSecret>) {
+        self.convergence = convergence;
+    }
+
+    /// The key of the grant named `name`, which is one way from this
+    /// one, and opens nothing but what's sealed with it.
+    pub(crate) fn grant_key(&self, name: &str) -> Result<StashKey> {
+        // not the context of the grant object, whose id is no secret
+        let key = derive_subkey(&self.master_key, b"_0s_grant_key")?;
+        let mut granted = [0; CRYPTO_DIGEST_SIZE];
+        granted.copy_from_slice(
+            blake2()
+                .hash_length(CRYPTO_DIGEST_SIZE)
+                .key(key.expose_secret())
+                .hash(name.as_bytes())
+                .as_bytes(),
+        );
+        let grant_key = StashKey::from_bytes(granted);
+        granted.zeroize();
+        Ok(grant_key)
+    }
+
+    /// The id of the object with what the grant of this key gives
+    /// access to.
+    pub(crate) fn grant_object_id(&self) -> Result<ObjectId> {
+        derive_subkey(&self.master_key, b"_0s_grant")
+            .map(|k| ObjectId::from_bytes(k.expose_secret()))
+    }
+
+    /// The keys of the chunks with `hashes`, each after its hash, to
+    /// grant access to them. Only convergent chunk keys are one way
+    /// from the object key, so there are none without them.
+    pub(crate) fn chunk_keys<'a>(
+        &self,
+        hashes: impl IntoIterator<Item = &'a CryptoDigest>,
+    ) -> Option<Zeroizing<Vec<u8>>> {
+        let convergence = self.convergence.as_ref()?;
+        let mut keys = Zeroizing::new(vec![]);
+        for hash in hashes {
+            keys.extend_from_slice(hash);
+            keys.extend_from_slice(convergence.chunk_key(hash).expose_secret());
+        }
+        Some(keys)
+    }
+
+    /// Decrypt chunks with the `keys` a grant gave, packed like
+    /// `chunk_keys` does, instead of any derived from this one.
+    pub(crate) fn set_granted(&mut self, keys: &[u8]) {
+        let mut granted = HashMap::new();
+        for pair in keys.chunks_exact(2 * CRYPTO_DIGEST_SIZE) {
+            let (hash, key) = pair.split_at(CRYPTO_DIGEST_SIZE);
+            let mut digest = CryptoDigest::default();
+            digest.copy_from_slice(hash);
+            let mut buffer = KeyBuffer::new([0; CRYPTO_DIGEST_SIZE]);
+            buffer.copy_from_slice(key);
+            granted.insert(digest, Secret::new(*buffer));
+        }
+        self.granted = Some(Arc::new(granted));
+    }
+
+    pub(crate) fn expose(&self) -> &[u8; CRYPTO_DIGEST_SIZE] {
+        self.master_key.expose_secret()
     }
 
     pub(crate) fn root_object_id(&self) -> Result<ObjectId> {
@@ -74,23 +453,237 @@ impl StashKey {
             .map(|k| ObjectId::from_bytes(k.expose_secret()))
     }
 
+    /// The id of the key object that can hold a master key wrapped
+    /// by this one.
+    pub(crate) fn key_object_id(&self) -> Result<ObjectId> {
+        derive_subkey(&self.master_key, b"_0s_key").map(|k| ObjectId::from_bytes(k.expose_secret()))
+    }
+
+    /// The id of the object listing the key slots of the stash.
+    pub(crate) fn slots_object_id(&self) -> Result<ObjectId> {
+        derive_subkey(&self.master_key, b"_0s_slots")
+            .map(|k| ObjectId::from_bytes(k.expose_secret()))
+    }
+
+    /// The id of the object listing the holders of the lock of the
+    /// stash.
+    pub(crate) fn lock_object_id(&self) -> Result<ObjectId> {
+        derive_subkey(&self.master_key, b"_0s_lock")
+            .map(|k| ObjectId::from_bytes(k.expose_secret()))
+    }
+
+    /// The id of the object with the signature of commit number
+    /// `generation`.
+    pub(crate) fn signature_object_id(&self, generation: u64) -> Result<ObjectId> {
+        let key = derive_subkey(&self.master_key, b"_0s_rsig")?;
+        let mut id = [0; CRYPTO_DIGEST_SIZE];
+        id.copy_from_slice(
+            blake2()
+                .hash_length(CRYPTO_DIGEST_SIZE)
+                .key(key.expose_secret())
+                .hash(&generation.to_le_bytes())
+                .as_bytes(),
+        );
+        Ok(ObjectId::from_bytes(id))
+    }
+
+    /// The table of `Chunker::Buzhash`. Stashes of a family derive it
+    /// from their secret, so they cut chunks at the same places.
+    pub(crate) fn buzhash_table(&self) -> Result<BuzhashTable> {
+        let seed = match &self.convergence {
+            Some(secret) => secret.buzhash_seed()?,
+            None => derive_subkey(&self.master_key, b"_0s_buzhash")?,
+        };
+
+        let mut values = [0; 256];
+        for (i, values) in values.chunks_mut(16).enumerate() {
+            let block = blake2()
+                .hash_length(64)
+                .key(seed.expose_secret())
+                .hash(&[i as u8]);
+            for (value, bytes) in values.iter_mut().zip(block.as_bytes().chunks(4)) {
+                *value = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+            }
+        }
+        let table = BuzhashTable::new(values);
+        values[..].zeroize();
+        Ok(table)
+    }
+
+    /// The Ed25519 key pair the metadata of the stash is signed with.
+    fn signing_key(&self) -> Result<Ed25519KeyPair> {
+        let seed = derive_subkey(&self.master_key, b"_0s_sign")?;
+        Ok(Ed25519KeyPair::from_seed_unchecked(seed.expose_secret()).expect("bad seed"))
+    }
+
+    pub(crate) fn sign(&self, message: &[u8]) -> Result<Vec<u8>> {
+        Ok(self.signing_key()?.sign(message).as_ref().to_vec())
+    }
+
+    pub(crate) fn verify(&self, message: &[u8], signature: &[u8]) -> Result<bool> {
+        let key = self.signing_key()?;
+        Ok(
+            UnparsedPublicKey::new(&signature::ED25519, key.public_key().as_ref())
+                .verify(message, signature)
+                .is_ok(),
+        )
+    }
+
+    /// Encrypt `key` with this one, to keep it in a key object.
+    pub(crate) fn wrap_key(&self, key: &StashKey) -> Result<Vec<u8>> {
+        self.seal(b"_0s_wrap", key.expose())
+    }
+
+    /// Decrypt a key encrypted by `wrap_key`.
+    pub(crate) fn unwrap_key(&self, wrapped: &[u8]) -> Result<StashKey> {
+        let mut nonce = Nonce::default();
+        let len = nonce.len();
+        if wrapped.len() != len + CRYPTO_DIGEST_SIZE + Tag::default().len() {
+            return Err(CryptoError::Decrypt);
+        }
+        nonce.copy_from_slice(&wrapped[..len]);
+
+        open_key(
+            derive_subkey(&self.master_key, b"_0s_wrap")?,
+            nonce,
+            &wrapped[len..],
+        )
+    }
+
+    /// Encrypt `data` with the subkey for `ctx`: a random nonce, then
+    /// the ciphertext and its tag.
+    pub(crate) fn seal(&self, ctx: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+        let mut nonce = Nonce::default();
+        getrandom(&mut nonce).unwrap();
+
+        let mut sealed = data.to_vec();
+        get_aead(KEY_CIPHER, derive_subkey(&self.master_key, ctx)?)
+            .seal_in_place_append_tag(
+                aead::Nonce::assume_unique_for_key(nonce),
+                aead::Aad::empty(),
+                &mut sealed,
+            )
+            .unwrap();
+
+        Ok([&nonce[..], &sealed].concat())
+    }
+
+    /// Decrypt data encrypted by `seal` with the same `ctx`.
+    pub(crate) fn open(&self, ctx: &[u8], sealed: &[u8]) -> Result<Vec<u8>> {
+        let len = Nonce::default().len();
+        if sealed.len() < len + Tag::default().len() {
+            return Err(CryptoError::Decrypt);
+        }
+        let mut nonce = Nonce::default();
+        nonce.copy_from_slice(&sealed[..len]);
+
+        let mut data = sealed[len..].to_vec();
+        let plain = match get_aead(KEY_CIPHER, derive_subkey(&self.master_key, ctx)?).open_in_place(
+            aead::Nonce::assume_unique_for_key(nonce),
+            aead::Aad::empty(),
+            &mut data,
+        ) {
+            Ok(plain) => plain.len(),
+            Err(_) => {
+                data.zeroize();
+                return Err(CryptoError::Decrypt);
+            }
+        };
+        data.truncate(plain);
+
+        Ok(data)
+    }
+
+    /// The public key of the stash, to seal keys for it.
+    pub fn public_key(&self) -> Result<PublicKey> {
+        let secret = derive_subkey(&self.master_key, b"_0s_x25519")?;
+        Ok(PublicKey(x25519(
+            *secret.expose_secret(),
+            X25519_BASEPOINT_BYTES,
+        )))
+    }
+
+    /// Decrypt a key sealed by `PublicKey::seal_key`.
+    pub(crate) fn unseal_key(&self, sealed: &[u8]) -> Result<StashKey> {
+        if sealed.len() != 32 + CRYPTO_DIGEST_SIZE + Tag::default().len() {
+            return Err(CryptoError::Decrypt);
+        }
+        let mut ephemeral_public = [0; 32];
+        ephemeral_public.copy_from_slice(&sealed[..32]);
+
+        let secret = derive_subkey(&self.master_key, b"_0s_x25519")?;
+        let shared = x25519(*secret.expose_secret(), ephemeral_public);
+        // a low order point from a forged seal
+        if shared == [0; 32] {
+            return Err(CryptoError::Decrypt);
+        }
+
+        open_key(
+            sealing_key(shared, &ephemeral_public, &self.public_key()?.0),
+            Nonce::default(),
+            &sealed[32..],
+        )
+    }
+
     pub(crate) fn get_meta_crypto(&self) -> Result<impl CryptoProvider> {
-        derive_subkey(&self.master_key, b"_0s_meta").map(ObjectOperations::new)
+        derive_subkey(&self.master_key, b"_0s_meta")
+            .map(|key| ObjectOperations::new(key).cipher(self.cipher))
     }
 
-    pub(crate) fn get_object_crypto(&self) -> Result<impl CryptoProvider> {
-        derive_subkey(&self.master_key, b"_0s_obj_").map(ObjectOperations::new)
+    pub(crate) fn get_object_crypto(&self) -> Result<ObjectOperations> {
+        derive_subkey(&self.master_key, b"_0s_obj_").map(|key| {
+            let mut crypto = ObjectOperations::new(key)
+                .cipher(self.cipher)
+                .convergent(self.convergence.clone());
+            crypto.granted = self.granted.clone();
+            crypto
+        })
     }
 }
 
 #[derive(Clone)]
 pub struct ObjectOperations {
     key: Key,
+    cipher: Cipher,
+    convergence: Option<ConvergenceSecret>,
+    granted: Option<GrantedKeys>,
 }
 
 impl ObjectOperations {
     pub fn new(key: Key) -> ObjectOperations {
-        ObjectOperations { key }
+        ObjectOperations {
+            key,
+            cipher: Cipher::default(),
+            convergence: None,
+            granted: None,
+        }
+    }
+
+    pub fn cipher(mut self, cipher: Cipher) -> ObjectOperations {
+        self.cipher = cipher;
+        self
+    }
+
+    /// Derive chunk keys from the content and `convergence`, instead
+    /// of the object key. See `crypto::convergence` for what this
+    /// gives up.
+    pub fn convergent(mut self, convergence: Option<ConvergenceSecret>) -> ObjectOperations {
+        self.convergence = convergence;
+        self
+    }
+
+    fn chunk_key(&self, hash: &CryptoDigest) -> Key {
+        if let Some(granted) = &self.granted {
+            // chunks that weren't granted fail to decrypt
+            return granted
+                .get(hash)
+                .cloned()
+                .unwrap_or_else(|| Secret::new([0; CRYPTO_DIGEST_SIZE]));
+        }
+        match &self.convergence {
+            Some(convergence) => convergence.chunk_key(hash),
+            None => derive_chunk_key(&self.key, hash),
+        }
     }
 }
 
@@ -102,13 +695,13 @@ impl Random for ObjectOperations {
 
 impl CryptoProvider for ObjectOperations {
     fn encrypt_chunk(&self, object: &WriteObject, hash: &CryptoDigest, data: &mut [u8]) -> Tag {
-        let aead = get_aead(derive_chunk_key(&self.key, hash));
+        let (aead, nonce) = get_object_aead(
+            self.cipher,
+            self.chunk_key(hash),
+            &get_chunk_nonce(&object.id, data.len() as u32),
+        );
         let tag = aead
-            .seal_in_place_separate_tag(
-                get_chunk_nonce(&object.id, data.len() as u32),
-                aead::Aad::empty(),
-                data,
-            )
+            .seal_in_place_separate_tag(nonce, aead::Aad::empty(), data)
             .unwrap();
 
         let mut t = Tag::default();
@@ -117,14 +710,11 @@ impl CryptoProvider for ObjectOperations {
     }
 
     fn encrypt_object(&self, object: &mut WriteObject) {
-        let aead = get_aead(self.key.clone());
+        let (aead, nonce) =
+            get_object_aead(self.cipher, self.key.clone(), &get_object_nonce(&object.id));
 
         let tag = aead
-            .seal_in_place_separate_tag(
-                get_object_nonce(&object.id),
-                aead::Aad::empty(),
-                object.as_mut(),
-            )
+            .seal_in_place_separate_tag(nonce, aead::Aad::empty(), object.as_mut())
             .unwrap();
 
         object.write_tag(tag.as_ref());
@@ -135,7 +725,7 @@ impl CryptoProvider for ObjectOperations {
         target: &mut [u8],
         o: &Object<T>,
         chunk: &ChunkPointer,
-    ) -> usize {
+    ) -> Result<usize> {
         let size = chunk.size as usize;
         let cyphertext_size = size + chunk.tag.len();
 
@@ -147,61 +737,103 @@ impl CryptoProvider for ObjectOperations {
         target[..size].copy_from_slice(&o.buffer.as_ref()[start..end]);
         target[size..cyphertext_size].copy_from_slice(&chunk.tag);
 
-        let aead = get_aead(derive_chunk_key(&self.key, &chunk.hash));
-        aead.open_in_place(
-            get_chunk_nonce(&o.id, chunk.size),
-            aead::Aad::empty(),
-            &mut target[..cyphertext_size],
-        )
-        .unwrap();
+        let (aead, nonce) = get_object_aead(
+            self.cipher,
+            self.chunk_key(&chunk.hash),
+            &get_chunk_nonce(&o.id, chunk.size),
+        );
+        if aead
+            .open_in_place(nonce, aead::Aad::empty(), &mut target[..cyphertext_size])
+            .is_err()
+        {
+            // don't leave unauthenticated plaintext behind
+            target[..cyphertext_size].zeroize();
+            return Err(CryptoError::Decrypt);
+        }
 
-        size
+        Ok(size)
     }
 
     fn decrypt_object_into<I: AsRef<[u8]>, O: AsMut<[u8]>>(
         &self,
         output: &mut Object<O>,
         obj: &Object<I>,
-    ) {
+    ) -> Result<()> {
         let buf: &mut [u8] = output.buffer.as_mut();
-        buf.copy_from_slice(&obj.buffer.as_ref());
+        buf.copy_from_slice(obj.buffer.as_ref());
 
-        let aead = get_aead(self.key.clone());
-        aead.open_in_place(get_object_nonce(&obj.id), aead::Aad::empty(), buf)
-            .unwrap();
+        let (aead, nonce) =
+            get_object_aead(self.cipher, self.key.clone(), &get_object_nonce(&obj.id));
+        if aead.open_in_place(nonce, aead::Aad::empty(), buf).is_err() {
+            buf.zeroize();
+            return Err(CryptoError::Decrypt);
+        }
 
         output.reserve_tag();
+        Ok(())
     }
 }
 
 #[inline]
-fn get_aead(key: Key) -> aead::LessSafeKey {
-    let key =
-        aead::UnboundKey::new(&aead::CHACHA20_POLY1305, key.expose_secret()).expect("bad key");
+fn get_aead(cipher: Cipher, key: Key) -> aead::LessSafeKey {
+    let key = aead::UnboundKey::new(cipher.algorithm(), key.expose_secret()).expect("bad key");
     aead::LessSafeKey::new(key)
 }
 
+/// The AEAD of `cipher` with `key`, and the nonce it takes of
+/// `nonce`. XChaCha20-Poly1305 is ChaCha20-Poly1305 with a subkey
+/// derived by HChaCha20 from the key and the first 16 bytes of the
+/// nonce, and the rest as its nonce.
+#[inline]
+fn get_object_aead(cipher: Cipher, key: Key, nonce: &XNonce) -> (aead::LessSafeKey, aead::Nonce) {
+    let mut short = Zeroizing::new(Nonce::default());
+    let key = match cipher {
+        Cipher::XChaCha20Poly1305 => {
+            let mut subkey = hchacha::<U10>(
+                GenericArray::from_slice(key.expose_secret()),
+                GenericArray::from_slice(&nonce[..16]),
+            );
+            let mut outbuf = KeyBuffer::new([0; CRYPTO_DIGEST_SIZE]);
+            outbuf.copy_from_slice(&subkey);
+            subkey.as_mut_slice().zeroize();
+
+            short[4..].copy_from_slice(&nonce[16..]);
+            Secret::new(*outbuf)
+        }
+        Cipher::ChaCha20Poly1305 | Cipher::Aes256Gcm => {
+            let len = short.len();
+            short.copy_from_slice(&nonce[..len]);
+            key
+        }
+    };
+
+    (
+        get_aead(cipher, key),
+        aead::Nonce::assume_unique_for_key(*short),
+    )
+}
+
 #[inline]
 fn derive_chunk_key(key_src: &Key, hash: &CryptoDigest) -> Key {
-    let mut key = *key_src.expose_secret();
+    let mut key = KeyBuffer::new(*key_src.expose_secret());
     for i in 0..key.len() {
         key[i] ^= hash[i];
     }
-    Secret::new(key)
+    Secret::new(*key)
 }
 
 #[inline]
-fn get_object_nonce(object_id: &ObjectId) -> aead::Nonce {
-    let mut nonce = Nonce::default();
+fn get_object_nonce(object_id: &ObjectId) -> XNonce {
+    let mut nonce = Zeroizing::new(XNonce::default());
     let len = nonce.len();
 
     nonce.copy_from_slice(&object_id.as_ref()[..len]);
-    aead::Nonce::assume_unique_for_key(nonce)
+    *nonce
 }
 
 #[inline]
-fn get_chunk_nonce(object_id: &ObjectId, data_size: u32) -> aead::Nonce {
-    let mut nonce = Nonce::default();
+fn get_chunk_nonce(object_id: &ObjectId, data_size: u32) -> XNonce {
+    let mut nonce = Zeroizing::new(XNonce::default());
     let len = nonce.len();
     nonce.copy_from_slice(&object_id.as_ref()[..len]);
 
@@ -210,42 +842,65 @@ fn get_chunk_nonce(object_id: &ObjectId, data_size: u32) -> aead::Nonce {
         nonce[i] ^= size[i];
     }
 
-    aead::Nonce::assume_unique_for_key(nonce)
+    *nonce
 }
 
-fn derive_argon2(salt_raw: &[u8], password: &[u8]) -> Result<Key> {
-    let salt = Blake2::new().hash_length(16).hash(salt_raw);
+/// Decrypt the key in `sealed`, which is followed by its tag.
+fn open_key(key: Key, nonce: Nonce, sealed: &[u8]) -> Result<StashKey> {
+    let mut data = sealed.to_vec();
+    let mut opened = KeyBuffer::new([0; CRYPTO_DIGEST_SIZE]);
+    let valid = match get_aead(KEY_CIPHER, key).open_in_place(
+        aead::Nonce::assume_unique_for_key(nonce),
+        aead::Aad::empty(),
+        &mut data,
+    ) {
+        Ok(plain) => {
+            opened.copy_from_slice(plain);
+            true
+        }
+        Err(_) => false,
+    };
+    data.zeroize();
 
-    let mut result = argon2::hash_raw(
-        password,
-        salt.as_bytes(),
-        &argon2::Config {
-            hash_length: CRYPTO_DIGEST_SIZE as u32,
-            variant: argon2::Variant::Argon2id,
-            ..argon2::Config::default()
-        },
-    )?;
+    if !valid {
+        return Err(CryptoError::Decrypt);
+    }
+    Ok(StashKey::from_bytes(*opened))
+}
 
-    let mut outbuf = [0; CRYPTO_DIGEST_SIZE];
-    outbuf.copy_from_slice(&result);
-    result.zeroize();
+/// The key to seal with, from the X25519 `shared` secret, bound to
+/// both public keys.
+fn sealing_key(mut shared: [u8; 32], ephemeral: &[u8; 32], recipient: &[u8; 32]) -> Key {
+    let mut outbuf = KeyBuffer::new([0; CRYPTO_DIGEST_SIZE]);
+    outbuf.copy_from_slice(
+        blake2()
+            .hash_length(CRYPTO_DIGEST_SIZE)
+            .key(b"_0s_seal")
+            .to_state()
+            .update(&shared)
+            .update(ephemeral)
+            .update(recipient)
+            .finalize()
+            .as_bytes(),
+    );
+    shared.zeroize();
 
-    Ok(Secret::new(outbuf))
+    Secret::new(*outbuf)
 }
 
 fn derive_subkey(key: &Key, ctx: &[u8]) -> Result<Key> {
     assert!(ctx.len() < 16);
 
-    let mut outbuf = [0; CRYPTO_DIGEST_SIZE];
+    let mut outbuf = KeyBuffer::new([0; CRYPTO_DIGEST_SIZE]);
     outbuf.copy_from_slice(
-        Blake2::new()
+        blake2()
             .hash_length(CRYPTO_DIGEST_SIZE)
-            .key(&ctx)
+            .key(ctx)
             .hash(key.expose_secret())
             .as_bytes(),
     );
 
-    Ok(Secret::new(outbuf))
+    Ok(Secret::new(*outbuf))
 }
 
 #[cfg(test)]
@@ -270,12 +925,17 @@ mod tests {
         crypto.encrypt_object(&mut obj);
 
         let mut decrypted = WriteObject::default();
-        crypto.decrypt_object_into(&mut decrypted, &obj);
+        crypto.decrypt_object_into(&mut decrypted, &obj).unwrap();
 
         // do it again, because reusing target buffers is fair game
-        crypto.decrypt_object_into(&mut decrypted, &obj);
+        crypto.decrypt_object_into(&mut decrypted, &obj).unwrap();
 
         assert_eq!(&decrypted.buffer.as_ref()[..len], cleartext.as_ref());
+
+        // tampering is detected
+        let slice: &mut [u8] = obj.as_mut();
+        slice[0] ^= 1;
+        assert!(crypto.decrypt_object_into(&mut decrypted, &obj).is_err());
     }
 
     #[test]
@@ -292,7 +952,7 @@ mod tests {
         let crypto = ObjectOperations::new(key);
         let mut obj = WriteObject::default();
 
-        let mut encrypted = cleartext.clone();
+        let mut encrypted = *cleartext;
         let tag = crypto.encrypt_chunk(&obj, hash, &mut encrypted);
         let cp = ChunkPointer {
             offs: 0,
@@ -301,11 +961,114 @@ mod tests {
             tag,
             ..ChunkPointer::default()
         };
-        obj.write(&encrypted).unwrap();
+        obj.write_all(&encrypted).unwrap();
 
         let mut decrypted = vec![0; size + tag.len()];
-        crypto.decrypt_chunk(&mut decrypted, &obj, &cp);
+        crypto.decrypt_chunk(&mut decrypted, &obj, &cp).unwrap();
 
         assert_eq!(&decrypted[..size], cleartext.as_ref());
     }
+
+    #[test]
+    fn ciphers_only_open_their_own_objects() {
+        use super::{Cipher, CryptoProvider, ObjectOperations};
+        use crate::objects::WriteObject;
+        use secrecy::Secret;
+
+        let crypto = |cipher| {
+            ObjectOperations::new(Secret::new(*b"abcdef1234567890abcdef1234567890")).cipher(cipher)
+        };
+        let mut obj = WriteObject::default();
+        obj.reserve_tag();
+        let slice: &mut [u8] = obj.as_mut();
+        slice[..4].copy_from_slice(b"data");
+        crypto(Cipher::Aes256Gcm).encrypt_object(&mut obj);
+
+        let mut decrypted = WriteObject::default();
+        assert!(crypto(Cipher::ChaCha20Poly1305)
+            .decrypt_object_into(&mut decrypted, &obj)
+            .is_err());
+        // nothing is left of a failed decryption
+        assert!(decrypted.buffer.as_ref().iter().all(|b| *b == 0));
+        crypto(Cipher::Aes256Gcm)
+            .decrypt_object_into(&mut decrypted, &obj)
+            .unwrap();
+        assert_eq!(&decrypted.buffer.as_ref()[..4], b"data");
+        assert!(crypto(Cipher::XChaCha20Poly1305)
+            .decrypt_object_into(&mut decrypted, &obj)
+            .is_err());
+
+        for cipher in Cipher::ALL.iter() {
+            assert_eq!(Cipher::from_name(cipher.name()), Some(*cipher));
+        }
+    }
+    #[test]
+    fn xchacha20_poly1305_matches_the_draft() {
+        use super::{get_object_aead, Cipher, XNonce};
+        use ring::aead::Aad;
+        use secrecy::Secret;
+        use std::convert::TryInto;
+
+        // draft-irtf-cfrg-xchacha-03, A.3.1
+        let mut key = [0; 32];
+        let mut nonce = XNonce::default();
+        for (i, b) in key.iter_mut().enumerate() {
+            *b = 0x80 + i as u8;
+        }
+        for (i, b) in nonce.iter_mut().enumerate() {
+            *b = 0x40 + i as u8;
+        }
+        let aad = [
+            0x50, 0x51, 0x52, 0x53, 0xc0, 0xc1, 0xc2, 0xc3, 0xc4, 0xc5, 0xc6, 0xc7,
+        ];
+        let mut data = b"Ladies and Gentlemen of the class of '99: If I could offer you only \
+            one tip for the future, sunscreen would be it."
+            .to_vec();
+
+        let (aead, nonce) = get_object_aead(Cipher::XChaCha20Poly1305, Secret::new(key), &nonce);
+        let tag = aead
+            .seal_in_place_separate_tag(nonce, Aad::from(aad), &mut data)
+            .unwrap();
+        let hex = |bytes: &[u8]| {
+            bytes
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<String>()
+        };
+        assert_eq!(
+            hex(&data),
+            "bd6d179d3e83d43b9576579493c0e939572a1700252bfaccbed2902c21396cbb\
+             731c7f1b0b4aa6440bf3a82f4eda7e39ae64c6708c54c216cb96b72e1213b452\
+             2f8c9ba40db5d945b11b69b982c1bb9e3f3fac2bc369488f76b2383565d3fff9\
+             21f9664c97637da9768812f615c68b13b52e"
+        );
+        let tag: [u8; 16] = tag.as_ref().try_into().unwrap();
+        assert_eq!(hex(&tag), "c0875924c1c7987947deafd8780acf49");
+    }
+
+    #[test]
+    fn kdf_parameters_change_the_key() {
+        use super::{Kdf, StashKey};
+
+        let light = |parallelism| Kdf::Argon2id {
+            memory_kib: 16,
+            iterations: 1,
+            parallelism,
+        };
+        let root = |kdf: &Kdf| {
+            StashKey::derive("user", "password", kdf)
+                .unwrap()
+                .root_object_id()
+                .unwrap()
+        };
+
+        assert_ne!(root(&light(1)), root(&light(2)));
+        assert_eq!(
+            root(&Kdf::default()),
+            StashKey::open_stash("user", "password")
+                .unwrap()
+                .root_object_id()
+                .unwrap()
+        );
+    }
 }
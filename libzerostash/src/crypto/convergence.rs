@@ -0,0 +1,142 @@
+//! Convergent chunk keys, so that stashes of the same *family* store
+//! equal chunks as equal ciphertext, and can be merged or synced
+//! without encrypting anything again.
+//!
+//! The key of a chunk is derived from its hash and a secret shared by
+//! the family, instead of the master key of the stash. This is off by
+//! default, as it gives up some confidentiality:
+//!
+//!  * anyone with the family secret and the objects of a stash can
+//!    check whether it holds content they can guess, like a known
+//!    file;
+//!  * a stash of the family reveals which chunks it has in common with
+//!    another one to whoever can see the objects of both.
+//!
+//! The secret itself is recorded in the encrypted metadata of the
+//! stash, so it only has to be given when the stash is created.
+
+use crate::crypto::{
+    blake2, derive_subkey, CryptoDigest, CryptoError, Key, Result, CRYPTO_DIGEST_SIZE,
+};
+
+use getrandom::getrandom;
+use itertools::Itertools;
+use secrecy::{ExposeSecret, Secret};
+use zeroize::{Zeroize, Zeroizing};
+
+use std::convert::TryFrom;
+use std::fmt;
+
+/// The secret a family of stashes derives its chunk keys from, in hex
+/// when serialized.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub struct ConvergenceSecret(Key);
+
+impl ConvergenceSecret {
+    /// A new random secret, for the first stash of a family.
+    pub fn generate() -> ConvergenceSecret {
+        let mut secret = Zeroizing::new([0; CRYPTO_DIGEST_SIZE]);
+        getrandom(&mut *secret).unwrap();
+
+        ConvergenceSecret(Secret::new(*secret))
+    }
+
+    /// The secret in hex, to set up other stashes of the family with.
+    pub fn expose_hex(&self) -> String {
+        format!("{:02x}", self.0.expose_secret().iter().format(""))
+    }
+
+    /// A short id of the family, which doesn't reveal the secret.
+    pub fn fingerprint(&self) -> String {
+        let hash = blake2()
+            .hash_length(8)
+            .key(b"_0s_family")
+            .hash(self.0.expose_secret());
+
+        format!("{:02x}", hash.as_bytes().iter().format(""))
+    }
+
+    /// The key of the chunk with `hash`.
+    pub(super) fn chunk_key(&self, hash: &CryptoDigest) -> Key {
+        let mut key = Zeroizing::new([0; CRYPTO_DIGEST_SIZE]);
+        key.copy_from_slice(
+            blake2()
+                .hash_length(CRYPTO_DIGEST_SIZE)
+                .key(self.0.expose_secret())
+                .to_state()
+                .update(b"_0s_chunk")
+                .update(hash)
+                .finalize()
+                .as_bytes(),
+        );
+
+        Secret::new(*key)
+    }
+
+    /// The seed of the buzhash table of the family.
+    pub(super) fn buzhash_seed(&self) -> Result<Key> {
+        derive_subkey(&self.0, b"_0s_buzhash")
+    }
+}
+
+impl PartialEq for ConvergenceSecret {
+    fn eq(&self, other: &ConvergenceSecret) -> bool {
+        self.0.expose_secret() == other.0.expose_secret()
+    }
+}
+
+impl fmt::Debug for ConvergenceSecret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ConvergenceSecret({})", self.fingerprint())
+    }
+}
+
+impl From<ConvergenceSecret> for String {
+    fn from(secret: ConvergenceSecret) -> String {
+        secret.expose_hex()
+    }
+}
+
+impl TryFrom<String> for ConvergenceSecret {
+    type Error = CryptoError;
+
+    fn try_from(mut text: String) -> Result<ConvergenceSecret> {
+        let mut bytes = Zeroizing::new([0; CRYPTO_DIGEST_SIZE]);
+        let hex = text.trim();
+        let parsed = hex.len() == 2 * CRYPTO_DIGEST_SIZE
+            && hex.is_ascii()
+            && bytes.iter_mut().enumerate().all(|(i, byte)| {
+                u8::from_str_radix(&hex[2 * i..2 * i + 2], 16)
+                    .map(|b| *byte = b)
+                    .is_ok()
+            });
+        text.zeroize();
+
+        if !parsed {
+            return Err(CryptoError::InvalidConvergenceSecret);
+        }
+        Ok(ConvergenceSecret(Secret::new(*bytes)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn chunk_keys_depend_on_the_family() {
+        use super::*;
+
+        let secret = ConvergenceSecret::generate();
+        let parsed = ConvergenceSecret::try_from(secret.expose_hex()).unwrap();
+        assert_eq!(parsed, secret);
+        assert_eq!(parsed.fingerprint().len(), 16);
+        assert!(!format!("{:?}", secret).contains(&secret.expose_hex()));
+
+        let hash = crate::crypto::chunk_hash(b"content");
+        let key = |s: &ConvergenceSecret| *s.chunk_key(&hash).expose_secret();
+        assert_eq!(key(&secret), key(&parsed));
+        assert_ne!(key(&secret), key(&ConvergenceSecret::generate()));
+
+        assert!(ConvergenceSecret::try_from("00".to_string()).is_err());
+    }
+}
@@ -0,0 +1,156 @@
+//! Keyfiles, which open a stash without a passphrase, so backups can
+//! run unattended.
+//!
+//! A keyfile holds either the 32 raw bytes of a key, or a JSON
+//! document like `{"id": "...", "created": 1600000000, "key": "..."}`
+//! with the key in hex. The key opens the stash directly, or through
+//! a key slot added with `Stash::add_slot`.
+
+use crate::crypto::{blake2, CryptoError, Result, StashKey, CRYPTO_DIGEST_SIZE};
+
+use itertools::Itertools;
+use zeroize::Zeroize;
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Serialize, Deserialize)]
+struct Document {
+    id: String,
+    created: u64,
+    key: String,
+}
+
+pub struct Keyfile {
+    id: String,
+    created: u64,
+    key: StashKey,
+}
+
+impl Keyfile {
+    /// A keyfile with a new random key.
+    pub fn generate() -> Keyfile {
+        Keyfile::from_key(StashKey::generate())
+    }
+
+    /// A keyfile with `key`, like one that `Stash::grant` derived.
+    pub fn from_key(key: StashKey) -> Keyfile {
+        let created = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+
+        Keyfile {
+            id: fingerprint(&key),
+            created,
+            key,
+        }
+    }
+
+    /// Read a keyfile in either format. Raw keys get their
+    /// fingerprint as the id, and no creation date.
+    pub fn parse(data: &[u8]) -> Result<Keyfile> {
+        let invalid = |reason: &str| CryptoError::InvalidKeyfile(reason.into());
+
+        if data.len() == CRYPTO_DIGEST_SIZE {
+            let mut raw = [0; CRYPTO_DIGEST_SIZE];
+            raw.copy_from_slice(data);
+            let key = StashKey::from_bytes(raw);
+            raw.zeroize();
+
+            return Ok(Keyfile {
+                id: fingerprint(&key),
+                created: 0,
+                key,
+            });
+        }
+
+        let mut document: Document =
+            serde_json::from_slice(data).map_err(|_| invalid("neither raw nor JSON"))?;
+        let hex = document.key.trim();
+        let mut raw = [0; CRYPTO_DIGEST_SIZE];
+        let parsed = hex.len() == 2 * CRYPTO_DIGEST_SIZE
+            && hex.is_ascii()
+            && raw.iter_mut().enumerate().all(|(i, byte)| {
+                u8::from_str_radix(&hex[2 * i..2 * i + 2], 16)
+                    .map(|b| *byte = b)
+                    .is_ok()
+            });
+        document.key.zeroize();
+        if !parsed {
+            raw.zeroize();
+            return Err(invalid("the key isn't 32 bytes of hex"));
+        }
+
+        let key = StashKey::from_bytes(raw);
+        raw.zeroize();
+        Ok(Keyfile {
+            id: document.id,
+            created: document.created,
+            key,
+        })
+    }
+
+    /// The JSON form of the keyfile.
+    pub fn to_json(&self) -> String {
+        let mut document = Document {
+            id: self.id.clone(),
+            created: self.created,
+            key: format!("{:02x}", self.key.expose().iter().format("")),
+        };
+        let json = serde_json::to_string_pretty(&document).unwrap();
+        document.key.zeroize();
+
+        json
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// When the key was generated, in seconds since the Unix epoch,
+    /// or 0 for raw keys.
+    pub fn created(&self) -> u64 {
+        self.created
+    }
+
+    /// The key to open the stash with, as `StashBuilder::key`.
+    pub fn into_key(self) -> StashKey {
+        self.key
+    }
+}
+
+/// A short id for `key`, which doesn't reveal it.
+fn fingerprint(key: &StashKey) -> String {
+    let hash = blake2()
+        .hash_length(8)
+        .key(b"_0s_keyfile")
+        .hash(key.expose());
+
+    format!("{:02x}", hash.as_bytes().iter().format(""))
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn keyfiles_are_raw_or_json() {
+        use super::*;
+
+        let keyfile = Keyfile::generate();
+        assert!(keyfile.created() > 0);
+        assert_eq!(keyfile.id().len(), 16);
+
+        let parsed = Keyfile::parse(keyfile.to_json().as_bytes()).unwrap();
+        assert_eq!(parsed.id(), keyfile.id());
+        assert_eq!(parsed.created(), keyfile.created());
+        assert_eq!(parsed.key.expose(), keyfile.key.expose());
+
+        // a raw key has the same fingerprint
+        let raw = Keyfile::parse(keyfile.key.expose()).unwrap();
+        assert_eq!(raw.id(), keyfile.id());
+        assert_eq!(raw.created(), 0);
+        assert_eq!(raw.into_key().expose(), keyfile.key.expose());
+
+        assert!(Keyfile::parse(b"too short").is_err());
+        assert!(Keyfile::parse(br#"{"id": "a", "created": 0, "key": "00"}"#).is_err());
+    }
+}
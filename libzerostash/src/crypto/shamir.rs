@@ -0,0 +1,213 @@
+//! Shamir secret sharing of master keys, so that any `threshold` of
+//! the shares recover the key, but fewer reveal nothing about it.
+//!
+//! Every byte of the key is shared separately, as the constant term
+//! of a random polynomial over GF(2^8) with the AES reduction
+//! polynomial. A share holds the polynomials evaluated at its index.
+
+use crate::crypto::{CryptoError, Result, StashKey, CRYPTO_DIGEST_SIZE};
+
+use getrandom::getrandom;
+use itertools::Itertools;
+use zeroize::{Zeroize, Zeroizing};
+
+use std::convert::TryFrom;
+use std::fmt;
+
+/// One share of a key, in the form `threshold-index-hex` when
+/// serialized.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub struct Share {
+    threshold: u8,
+    index: u8,
+    value: [u8; CRYPTO_DIGEST_SIZE],
+}
+
+impl Share {
+    /// How many shares are needed to recover the key.
+    pub fn threshold(&self) -> u8 {
+        self.threshold
+    }
+}
+
+impl Drop for Share {
+    fn drop(&mut self) {
+        self.value.zeroize();
+    }
+}
+
+impl fmt::Debug for Share {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Share({}-{})", self.threshold, self.index)
+    }
+}
+
+impl fmt::Display for Share {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}-{}-{:02x}",
+            self.threshold,
+            self.index,
+            self.value.iter().format("")
+        )
+    }
+}
+
+impl From<Share> for String {
+    fn from(share: Share) -> String {
+        share.to_string()
+    }
+}
+
+impl TryFrom<String> for Share {
+    type Error = CryptoError;
+
+    fn try_from(text: String) -> Result<Share> {
+        let invalid = || CryptoError::InvalidShares("can't parse a share".into());
+        let mut parts = text.trim().splitn(3, '-');
+        let mut number = || parts.next().and_then(|n| n.parse::<u8>().ok());
+        let (threshold, index) = match (number(), number()) {
+            (Some(threshold), Some(index)) if threshold > 0 && index > 0 => (threshold, index),
+            _ => return Err(invalid()),
+        };
+
+        let hex = parts.next().ok_or_else(invalid)?;
+        if hex.len() != 2 * CRYPTO_DIGEST_SIZE || !hex.is_ascii() {
+            return Err(invalid());
+        }
+        let mut value = [0; CRYPTO_DIGEST_SIZE];
+        for (i, byte) in value.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).map_err(|_| invalid())?;
+        }
+
+        Ok(Share {
+            threshold,
+            index,
+            value,
+        })
+    }
+}
+
+/// Split `key` into `shares` shares, any `threshold` of which
+/// recover it.
+pub fn split(key: &StashKey, threshold: u8, shares: u8) -> Result<Vec<Share>> {
+    if threshold == 0 || threshold > shares {
+        return Err(CryptoError::InvalidShares(format!(
+            "can't recover from {} of {} shares",
+            threshold, shares
+        )));
+    }
+
+    let mut coefficients = vec![0; (threshold as usize - 1) * CRYPTO_DIGEST_SIZE];
+    getrandom(&mut coefficients).unwrap();
+
+    let shares = (1..=shares)
+        .map(|index| {
+            let mut value = [0; CRYPTO_DIGEST_SIZE];
+            for (i, byte) in value.iter_mut().enumerate() {
+                // Horner's method, from the highest coefficient
+                let mut y = 0;
+                for c in coefficients.chunks(CRYPTO_DIGEST_SIZE).rev() {
+                    y = mul(y, index) ^ c[i];
+                }
+                *byte = mul(y, index) ^ key.expose()[i];
+            }
+
+            Share {
+                threshold,
+                index,
+                value,
+            }
+        })
+        .collect();
+    coefficients.zeroize();
+
+    Ok(shares)
+}
+
+/// Recover the key from at least as many `shares` as their
+/// threshold.
+pub fn combine(shares: &[Share]) -> Result<StashKey> {
+    let threshold = match shares.first() {
+        Some(share) => share.threshold,
+        None => return Err(CryptoError::InvalidShares("no shares".into())),
+    };
+    let shares = shares.iter().unique_by(|s| s.index).collect::<Vec<_>>();
+    if shares.iter().any(|s| s.threshold != threshold) {
+        return Err(CryptoError::InvalidShares(
+            "the shares are of different splits".into(),
+        ));
+    }
+    if shares.len() < threshold as usize {
+        return Err(CryptoError::InvalidShares(format!(
+            "{} shares are needed, but only {} are given",
+            threshold,
+            shares.len()
+        )));
+    }
+
+    // Lagrange interpolation at 0, where subtraction is xor
+    let shares = &shares[..threshold as usize];
+    let mut key = Zeroizing::new([0; CRYPTO_DIGEST_SIZE]);
+    for share in shares {
+        let mut basis = 1;
+        for other in shares.iter().filter(|o| o.index != share.index) {
+            basis = mul(basis, div(other.index, other.index ^ share.index));
+        }
+        for (byte, value) in key.iter_mut().zip(share.value.iter()) {
+            *byte ^= mul(basis, *value);
+        }
+    }
+
+    Ok(StashKey::from_bytes(*key))
+}
+
+/// Multiplication in GF(2^8), without branching on the operands.
+fn mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    for _ in 0..8 {
+        product ^= a & (b & 1).wrapping_neg();
+        let carry = (a >> 7).wrapping_neg();
+        a = (a << 1) ^ (carry & 0x1b);
+        b >>= 1;
+    }
+    product
+}
+
+/// `a / b` in GF(2^8), through `b` to the power of 254.
+fn div(a: u8, b: u8) -> u8 {
+    let mut inverse = 1;
+    for _ in 0..254 {
+        inverse = mul(inverse, b);
+    }
+    mul(a, inverse)
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn any_threshold_of_shares_recover_the_key() {
+        use super::*;
+
+        let key = StashKey::generate();
+        let shares = split(&key, 3, 5).unwrap();
+        assert_eq!(shares.len(), 5);
+
+        for picked in shares.iter().cloned().combinations(3) {
+            assert_eq!(combine(&picked).unwrap().expose(), key.expose());
+        }
+        let parsed = shares
+            .iter()
+            .map(|s| Share::try_from(s.to_string()).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(parsed, shares);
+
+        // two shares, or the same one twice, aren't enough
+        assert!(combine(&shares[..2]).is_err());
+        assert!(combine(&[shares[0].clone(), shares[0].clone(), shares[1].clone()]).is_err());
+        assert!(split(&key, 4, 3).is_err());
+        assert!(Share::try_from("3-0-00".to_string()).is_err());
+    }
+}
@@ -0,0 +1,258 @@
+use crate::backends::BackendError;
+use crate::cache::CacheError;
+use crate::crypto::CryptoError;
+use crate::files::FilterError;
+use crate::format::FormatError;
+use crate::meta::{ReadError, WriteError};
+use crate::namespaces::NamespaceError;
+use crate::objects::{ObjectError, ObjectId};
+
+use thiserror::Error;
+
+use std::io;
+
+/// Errors returned by the high level `Stash` API.
+#[derive(Error, Debug)]
+pub enum ZerostashError {
+    #[error("Stash not found, or wrong username or password")]
+    WrongPassphrase,
+    #[error("A stash already exists with these credentials")]
+    Exists,
+    #[error("No key slot is named {0}")]
+    NoSuchSlot(String),
+    #[error("A key slot is already named {0}")]
+    SlotExists(String),
+    #[error("The last key slot can't be revoked")]
+    LastSlot,
+    #[error("Backend error: {source}")]
+    Backend {
+        #[from]
+        source: BackendError,
+    },
+    #[error("Crypto error: {source}")]
+    Crypto {
+        #[from]
+        source: CryptoError,
+    },
+    #[error("Object {} failed authentication", .object.to_string())]
+    Corrupt { object: ObjectId },
+    /// The metadata doesn't match its signature, so the backend may
+    /// have swapped or rolled back objects.
+    #[error("Metadata signature verification failed: {0}")]
+    Tampered(String),
+    #[error("Incompatible stash: {source}")]
+    Incompatible {
+        #[from]
+        source: FormatError,
+    },
+    #[error("Invalid metadata in object {}: {source}", .object.to_string())]
+    Format { object: ObjectId, source: ReadError },
+    #[error("Failed to write metadata: {source}")]
+    Write { source: WriteError },
+    #[error("Object storage error: {source}")]
+    Object { source: ObjectError },
+    #[error("File cache error: {source}")]
+    Cache {
+        #[from]
+        source: CacheError,
+    },
+    #[error("Invalid pattern: {source}")]
+    InvalidPattern {
+        #[from]
+        source: glob::PatternError,
+    },
+    #[error("Invalid filter: {source}")]
+    Filter {
+        #[from]
+        source: FilterError,
+    },
+    #[error("Invalid configuration: {0}")]
+    Config(String),
+    #[error("Invalid bundle: {0}")]
+    Bundle(String),
+    /// Two restored paths name the same file on the file system of
+    /// the target, see `Collisions`
+    #[error("Paths collide: {0}")]
+    Collision(String),
+    #[cfg(feature = "kms")]
+    #[error("Key management error: {source}")]
+    Kms {
+        #[from]
+        source: crate::kms::KmsError,
+    },
+    #[error("Namespace error: {source}")]
+    Namespace {
+        #[from]
+        source: crate::namespaces::NamespaceError,
+    },
+    #[error("No file is named {0}")]
+    NoSuchFile(String),
+    #[error("Operation cancelled")]
+    Cancelled,
+    /// Another process holds a lock that conflicts, see `Stash::lock`
+    #[error("Stash is locked: {0}")]
+    Locked(String),
+    /// The stash is kept immutable, see `Stash::set_immutable`
+    #[error("Stash is immutable, and can't {0}")]
+    Immutable(&'static str),
+    /// The stash was opened through a grant, see `Stash::grant`
+    #[error("Stash is opened through a grant, which can't {0}")]
+    Granted(&'static str),
+    /// New chunks would take more than the quota of the stash, see
+    /// `Stash::set_quota`
+    #[error("Stash quota of {0} bytes exceeded")]
+    QuotaExceeded(u64),
+    #[error("IO error: {source}")]
+    Io {
+        #[from]
+        source: io::Error,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, ZerostashError>;
+
+/// What went wrong, broadly, so callers can decide whether to try
+/// again, ask for the password again, or give up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The credentials are wrong, or there's no stash with them
+    Credentials,
+    /// The backend failed, which `is_transient` tells apart from
+    /// failures that trying again won't fix
+    Backend,
+    /// Stored data failed authentication, or can't be read, so it
+    /// was damaged or tampered with
+    Corrupt,
+    /// The stash was written by a newer version, or in another format
+    Incompatible,
+    /// An argument, pattern or configuration is wrong
+    InvalidInput,
+    /// The state of the stash doesn't allow it, like when it's
+    /// locked, immutable, full, or a key slot already exists
+    Conflict,
+    /// A local file or the file cache failed
+    Io,
+    Cancelled,
+}
+
+impl From<WriteError> for ZerostashError {
+    fn from(err: WriteError) -> ZerostashError {
+        // backend failures are told apart the same, wherever they are
+        match err {
+            WriteError::Backend { source } => ZerostashError::Backend { source },
+            WriteError::Io { source } => ZerostashError::Io { source },
+            source => ZerostashError::Write { source },
+        }
+    }
+}
+
+impl From<ObjectError> for ZerostashError {
+    fn from(err: ObjectError) -> ZerostashError {
+        match err {
+            ObjectError::QuotaExceeded(quota) => ZerostashError::QuotaExceeded(quota),
+            source => ZerostashError::Object { source },
+        }
+    }
+}
+
+impl ZerostashError {
+    pub fn kind(&self) -> ErrorKind {
+        use ZerostashError::*;
+
+        match self {
+            WrongPassphrase => ErrorKind::Credentials,
+            Backend { .. } | Object { .. } => ErrorKind::Backend,
+            #[cfg(feature = "kms")]
+            Kms { .. } => ErrorKind::Backend,
+            Crypto {
+                source: CryptoError::Decrypt,
+            }
+            | Corrupt { .. }
+            | Tampered(_)
+            | Format { .. }
+            | Bundle(_) => ErrorKind::Corrupt,
+            Namespace { source } => match source {
+                NamespaceError::InvalidKey
+                | NamespaceError::InvalidPublicKey(_)
+                | NamespaceError::OutsideNamespace { .. } => ErrorKind::InvalidInput,
+                _ => ErrorKind::Corrupt,
+            },
+            Incompatible { .. } => ErrorKind::Incompatible,
+            Crypto { .. }
+            | InvalidPattern { .. }
+            | Filter { .. }
+            | Config(_)
+            | NoSuchSlot(_)
+            | NoSuchFile(_) => ErrorKind::InvalidInput,
+            Exists | SlotExists(_) | LastSlot | Locked(_) | Immutable(_) | Granted(_)
+            | QuotaExceeded(_) => ErrorKind::Conflict,
+            Io { .. } | Write { .. } | Cache { .. } | Collision(_) => ErrorKind::Io,
+            Cancelled => ErrorKind::Cancelled,
+        }
+    }
+
+    /// Whether trying again later may succeed, like after a dropped
+    /// connection, or once another process releases its lock.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            ZerostashError::Backend { source }
+            | ZerostashError::Object {
+                source: ObjectError::Backend { source },
+            } => source.is_transient(),
+            ZerostashError::Locked(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Classify an error that happened while reading metadata
+    /// `object`.
+    ///
+    /// The root object's id is derived from the credentials, so
+    /// failing to find or decrypt it means the credentials are wrong.
+    pub(crate) fn reading(object: ObjectId, is_root: bool, err: ReadError) -> ZerostashError {
+        match err {
+            ReadError::Backend {
+                source: BackendError::NoObjectFound,
+            }
+            | ReadError::Crypto { .. }
+                if is_root =>
+            {
+                ZerostashError::WrongPassphrase
+            }
+            ReadError::Backend { source } => ZerostashError::Backend { source },
+            ReadError::Crypto { .. } => ZerostashError::Corrupt { object },
+            source => ZerostashError::Format { object, source },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn errors_tell_what_to_do() {
+        use super::*;
+
+        let timeout =
+            ZerostashError::from(BackendError::from(io::Error::from(io::ErrorKind::TimedOut)));
+        assert_eq!(timeout.kind(), ErrorKind::Backend);
+        assert!(timeout.is_transient());
+        let missing = ZerostashError::from(BackendError::NoObjectFound);
+        assert!(!missing.is_transient());
+
+        assert_eq!(
+            ZerostashError::WrongPassphrase.kind(),
+            ErrorKind::Credentials
+        );
+        assert_eq!(
+            ZerostashError::from(CryptoError::Decrypt).kind(),
+            ErrorKind::Corrupt
+        );
+        assert_eq!(
+            ZerostashError::from(CryptoError::InvalidConvergenceSecret).kind(),
+            ErrorKind::InvalidInput
+        );
+        let locked = ZerostashError::Locked("pid 1".into());
+        assert_eq!(locked.kind(), ErrorKind::Conflict);
+        assert!(locked.is_transient());
+    }
+}
@@ -1,16 +1,30 @@
 use crate::chunks::ChunkPointer;
-use crate::meta::{FieldReader, FieldWriter, MetaObjectField};
+use crate::meta::{FieldReader, FieldWriter, MetaObjectField, WriteError};
 
 use dashmap::DashMap;
+use serde_bytes::ByteBuf;
 
-use std::error::Error;
-use std::fs;
-use std::path::Path;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
 use std::sync::Arc;
-use std::time::UNIX_EPOCH;
+#[cfg(any(unix, windows))]
+use std::{error::Error, fs, path::Path, time::UNIX_EPOCH};
+
+mod filter;
+mod ignore;
+pub use filter::{Filter, FilterError};
+pub use ignore::Ignores;
 
 type DashSet<T> = DashMap<T, ()>;
 
+// the file types in `unix_perm`, which are the same on all Unixes
+const S_IFMT: u32 = 0o170_000;
+const S_IFREG: u32 = 0o100_000;
+const S_IFIFO: u32 = 0o010_000;
+const S_IFCHR: u32 = 0o020_000;
+const S_IFBLK: u32 = 0o060_000;
+const S_IFSOCK: u32 = 0o140_000;
+
 #[derive(Hash, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub struct Entry {
     pub unix_secs: u64,
@@ -18,43 +32,160 @@ pub struct Entry {
     pub unix_perm: u32,
     pub unix_uid: u32,
     pub unix_gid: u32,
+    /// The names of the owners, if they had one, to restore by on
+    /// other systems where the ids differ
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unix_user: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unix_group: Option<String>,
 
     pub size: u64,
     pub readonly: bool,
     pub name: String,
+    /// The target of a symbolic link, which has no chunks
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub symlink: Option<String>,
+    /// The first path of a file with several hard links, whose
+    /// chunks this one has too
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hardlink: Option<String>,
+    /// Extended attributes by name, including capabilities, SELinux
+    /// labels, and POSIX ACLs as `system.posix_acl_*`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub xattrs: Option<BTreeMap<String, ByteBuf>>,
+    /// The major and minor numbers of a character or block device
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device: Option<(u32, u32)>,
+    /// The attributes of files from Windows, like hidden or system,
+    /// see `attributes::MASK`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub windows_attributes: Option<u32>,
+    /// The bytes of the path, if they aren't UTF-8, which `name` is
+    /// a lossy version of
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub raw_name: Option<ByteBuf>,
 
     pub chunks: Vec<(u64, Arc<ChunkPointer>)>,
 }
 
 impl Entry {
-    #[cfg(windows)]
+    #[cfg(any(unix, windows))]
     pub fn from_file(file: &fs::File, path: impl AsRef<Path>) -> Result<Entry, Box<dyn Error>> {
+        Entry::from_metadata(&file.metadata()?, path)
+    }
+
+    /// The symbolic link at `path`, with its target instead of the
+    /// contents of what it points to. Junctions on Windows are
+    /// stored as links too.
+    #[cfg(any(unix, windows))]
+    pub fn from_symlink(path: impl AsRef<Path>) -> Result<Entry, Box<dyn Error>> {
         let path = path.as_ref();
-        let metadata = file.metadata()?;
-        let (unix_secs, unix_nanos) = to_unix_mtime(&metadata)?;
+        let target = fs::read_link(path)?;
 
-        Ok(File {
-            unix_secs,
-            unix_nanos,
-            unix_perm: 0,
-            unix_uid: 0,
-            unix_gid: 0,
+        Ok(Entry {
+            size: 0,
+            symlink: Some(portable_name(&target)?),
+            ..Entry::from_metadata(&fs::symlink_metadata(path)?, path)?
+        })
+    }
 
-            size: metadata.len(),
-            readonly: metadata.permissions().readonly(),
-            name: path.as_ref().to_str().unwrap().to_string(),
+    /// The FIFO, socket or device at `path`, which only has
+    /// metadata.
+    #[cfg(unix)]
+    pub fn from_special(path: impl AsRef<Path>) -> Result<Entry, Box<dyn Error>> {
+        use std::os::unix::fs::{FileTypeExt, MetadataExt};
 
-            chunks: Vec::new(),
+        let path = path.as_ref();
+        let metadata = fs::symlink_metadata(path)?;
+        let file_type = metadata.file_type();
+        let device = (file_type.is_char_device() || file_type.is_block_device()).then(|| {
+            let rdev = metadata.rdev() as libc::dev_t;
+            (libc::major(rdev) as u32, libc::minor(rdev) as u32)
+        });
+
+        Ok(Entry {
+            size: 0,
+            device,
+            ..Entry::from_metadata(&metadata, path)?
         })
     }
 
+    #[cfg(windows)]
+    pub fn from_special(path: impl AsRef<Path>) -> Result<Entry, Box<dyn Error>> {
+        Err(format!("{:?} is not a regular file", path.as_ref()).into())
+    }
+
+    /// A regular file called `name`, owned by this process and
+    /// modified now, for contents that don't come from a file, like a
+    /// database dump on stdin.
+    pub fn from_stream(name: impl Into<String>) -> Entry {
+        use std::time::{SystemTime, UNIX_EPOCH};
+
+        let mtime = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        #[cfg(unix)]
+        let (unix_uid, unix_gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        #[cfg(not(unix))]
+        let (unix_uid, unix_gid) = (0, 0);
+
+        Entry {
+            unix_secs: mtime.as_secs(),
+            unix_nanos: mtime.subsec_nanos(),
+            unix_perm: S_IFREG | 0o644,
+            unix_uid,
+            unix_gid,
+            #[cfg(unix)]
+            unix_user: owners::user(unix_uid),
+            #[cfg(unix)]
+            unix_group: owners::group(unix_gid),
+            #[cfg(not(unix))]
+            unix_user: None,
+            #[cfg(not(unix))]
+            unix_group: None,
+
+            size: 0,
+            readonly: false,
+            name: name.into(),
+            symlink: None,
+            hardlink: None,
+            xattrs: None,
+            device: None,
+            windows_attributes: None,
+            raw_name: None,
+
+            chunks: Vec::new(),
+        }
+    }
+
+    /// The path of the file, exactly as it was backed up.
+    pub fn path(&self) -> PathBuf {
+        #[cfg(unix)]
+        if let Some(raw) = &self.raw_name {
+            use std::os::unix::ffi::OsStrExt;
+
+            return std::ffi::OsStr::from_bytes(raw).into();
+        }
+        PathBuf::from(&self.name)
+    }
+
+    /// If this is a FIFO, socket or device, which has no contents.
+    pub fn is_special(&self) -> bool {
+        matches!(
+            self.unix_perm & S_IFMT,
+            S_IFIFO | S_IFCHR | S_IFBLK | S_IFSOCK
+        )
+    }
+
     #[cfg(unix)]
-    pub fn from_file(file: &fs::File, path: impl AsRef<Path>) -> Result<Entry, Box<dyn Error>> {
+    fn from_metadata(
+        metadata: &fs::Metadata,
+        path: impl AsRef<Path>,
+    ) -> Result<Entry, Box<dyn Error>> {
         use std::os::unix::fs::{MetadataExt, PermissionsExt};
 
-        let metadata = file.metadata()?;
         let perms = metadata.permissions();
-        let (unix_secs, unix_nanos) = to_unix_mtime(&metadata)?;
+        let (unix_secs, unix_nanos) = to_unix_mtime(metadata)?;
 
         Ok(Entry {
             unix_secs,
@@ -62,16 +193,329 @@ impl Entry {
             unix_perm: perms.mode(),
             unix_uid: metadata.uid(),
             unix_gid: metadata.gid(),
+            unix_user: owners::user(metadata.uid()),
+            unix_group: owners::group(metadata.gid()),
 
             size: metadata.len(),
             readonly: metadata.permissions().readonly(),
-            name: path.as_ref().to_str().unwrap().to_string(),
+            name: path.as_ref().to_string_lossy().into_owned(),
+            symlink: None,
+            hardlink: None,
+            xattrs: xattrs::read(path.as_ref()),
+            device: None,
+            windows_attributes: None,
+            raw_name: raw_name(path.as_ref()),
+
+            chunks: Vec::new(),
+        })
+    }
+
+    /// Files from Windows have no mode or owners, which restores on
+    /// other systems leave to the defaults there.
+    #[cfg(windows)]
+    fn from_metadata(
+        metadata: &fs::Metadata,
+        path: impl AsRef<Path>,
+    ) -> Result<Entry, Box<dyn Error>> {
+        use std::os::windows::fs::MetadataExt;
+
+        let (unix_secs, unix_nanos) = to_unix_mtime(metadata)?;
+
+        Ok(Entry {
+            unix_secs,
+            unix_nanos,
+            unix_perm: 0,
+            unix_uid: 0,
+            unix_gid: 0,
+            unix_user: None,
+            unix_group: None,
+
+            size: metadata.len(),
+            readonly: metadata.permissions().readonly(),
+            name: portable_name(path.as_ref())?,
+            symlink: None,
+            hardlink: None,
+            xattrs: None,
+            device: None,
+            windows_attributes: Some(metadata.file_attributes() & attributes::MASK),
+            raw_name: None,
 
             chunks: Vec::new(),
         })
     }
 }
 
+/// `path` as stored in the index, which is UTF-8, and separated by
+/// `/` on Windows too, without the `\\?\` prefix of long paths.
+#[cfg(any(unix, windows))]
+fn portable_name(path: &Path) -> Result<String, Box<dyn Error>> {
+    let name = path.to_str().ok_or("the path isn't UTF-8")?;
+    #[cfg(windows)]
+    let name = name
+        .strip_prefix(r"\\?\")
+        .unwrap_or(name)
+        .replace('\\', "/");
+
+    Ok(name.to_string())
+}
+
+/// The bytes of `path`, if they aren't UTF-8.
+#[cfg(unix)]
+fn raw_name(path: &Path) -> Option<ByteBuf> {
+    use std::os::unix::ffi::OsStrExt;
+
+    match path.to_str() {
+        Some(_) => None,
+        None => Some(ByteBuf::from(path.as_os_str().as_bytes())),
+    }
+}
+
+/// The attributes of files on Windows, which are restored there.
+#[cfg(windows)]
+pub(crate) mod attributes {
+    const READONLY: u32 = 0x1;
+    const HIDDEN: u32 = 0x2;
+    const SYSTEM: u32 = 0x4;
+    const ARCHIVE: u32 = 0x20;
+    const NOT_CONTENT_INDEXED: u32 = 0x2000;
+
+    /// The attributes that are stored. Others, like compression or
+    /// reparse points, describe how a file is kept rather than the
+    /// file, and can't be set directly.
+    pub const MASK: u32 = READONLY | HIDDEN | SYSTEM | ARCHIVE | NOT_CONTENT_INDEXED;
+
+    #[cfg(feature = "fs")]
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn SetFileAttributesW(name: *const u16, attributes: u32) -> i32;
+    }
+
+    #[cfg(feature = "fs")]
+    pub fn write(path: &std::path::Path, attributes: u32) -> std::io::Result<()> {
+        use std::os::windows::ffi::OsStrExt;
+
+        let name = path
+            .as_os_str()
+            .encode_wide()
+            .chain(Some(0))
+            .collect::<Vec<_>>();
+
+        match unsafe { SetFileAttributesW(name.as_ptr(), attributes & MASK) } {
+            0 => Err(std::io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Extended attributes of files, which are only read and written on
+/// Linux. They aren't followed through symbolic links.
+#[cfg(unix)]
+pub(crate) mod xattrs {
+    use serde_bytes::ByteBuf;
+
+    use std::collections::BTreeMap;
+    use std::path::Path;
+
+    /// The attributes of `path`, if it has any that can be read.
+    #[cfg(target_os = "linux")]
+    pub fn read(path: &Path) -> Option<BTreeMap<String, ByteBuf>> {
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+        let names = read_buffer(|buf, len| unsafe { libc::llistxattr(path.as_ptr(), buf, len) })?;
+
+        let attrs = names
+            .split(|&b| b == 0)
+            .filter(|name| !name.is_empty())
+            .filter_map(|name| {
+                let value = read_buffer(|buf, len| unsafe {
+                    libc::lgetxattr(path.as_ptr(), name.as_ptr().cast(), buf.cast(), len)
+                })?;
+                let name = std::str::from_utf8(name).ok()?;
+                Some((name.to_string(), ByteBuf::from(value)))
+            })
+            .collect::<BTreeMap<_, _>>();
+
+        Some(attrs).filter(|a| !a.is_empty())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn read(_path: &Path) -> Option<BTreeMap<String, ByteBuf>> {
+        None
+    }
+
+    /// Set the attribute `name` of `path` to `value`.
+    #[cfg(all(feature = "fs", target_os = "linux"))]
+    pub fn write(path: &Path, name: &str, value: &[u8]) -> std::io::Result<()> {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = CString::new(path.as_os_str().as_bytes())?;
+        let name = CString::new(name)?;
+        let result = unsafe {
+            libc::lsetxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                value.as_ptr().cast(),
+                value.len(),
+                0,
+            )
+        };
+        if result != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    #[cfg(all(feature = "fs", not(target_os = "linux")))]
+    pub fn write(_path: &Path, _name: &str, _value: &[u8]) -> std::io::Result<()> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "extended attributes are only restored on Linux",
+        ))
+    }
+
+    /// Run a call that fills a buffer, asking for the size first, and
+    /// again if it grew in between.
+    #[cfg(target_os = "linux")]
+    fn read_buffer(
+        call: impl Fn(*mut libc::c_char, libc::size_t) -> libc::ssize_t,
+    ) -> Option<Vec<u8>> {
+        loop {
+            let size = call(std::ptr::null_mut(), 0);
+            if size < 0 {
+                return None;
+            }
+            if size == 0 {
+                return Some(vec![]);
+            }
+
+            let mut buffer = vec![0u8; size as usize];
+            let read = call(buffer.as_mut_ptr().cast(), buffer.len());
+            if read >= 0 {
+                buffer.truncate(read as usize);
+                return Some(buffer);
+            }
+            if std::io::Error::last_os_error().raw_os_error() != Some(libc::ERANGE) {
+                return None;
+            }
+        }
+    }
+}
+
+/// Names of users and groups, through the reentrant lookups of libc.
+#[cfg(unix)]
+pub(crate) mod owners {
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::ffi::CStr;
+    use std::mem::MaybeUninit;
+    use std::ptr;
+
+    // Lookups call into NSS, which can be slow, so each thread
+    // remembers the names it has seen.
+    thread_local! {
+        static USERS: RefCell<HashMap<u32, Option<String>>> = RefCell::default();
+        static GROUPS: RefCell<HashMap<u32, Option<String>>> = RefCell::default();
+    }
+
+    pub fn user(uid: u32) -> Option<String> {
+        USERS.with(|users| {
+            users
+                .borrow_mut()
+                .entry(uid)
+                .or_insert_with(|| {
+                    lookup(
+                        |pwd, buf, len, result| unsafe {
+                            libc::getpwuid_r(uid, pwd, buf, len, result)
+                        },
+                        |pwd: &libc::passwd| name(pwd.pw_name),
+                    )
+                    .flatten()
+                })
+                .clone()
+        })
+    }
+
+    pub fn group(gid: u32) -> Option<String> {
+        GROUPS.with(|groups| {
+            groups
+                .borrow_mut()
+                .entry(gid)
+                .or_insert_with(|| {
+                    lookup(
+                        |grp, buf, len, result| unsafe {
+                            libc::getgrgid_r(gid, grp, buf, len, result)
+                        },
+                        |grp: &libc::group| name(grp.gr_name),
+                    )
+                    .flatten()
+                })
+                .clone()
+        })
+    }
+
+    /// The id of the user called `name` on this system.
+    #[cfg(feature = "fs")]
+    pub fn uid(name: &str) -> Option<u32> {
+        let name = std::ffi::CString::new(name).ok()?;
+        lookup(
+            |pwd, buf, len, result| unsafe {
+                libc::getpwnam_r(name.as_ptr(), pwd, buf, len, result)
+            },
+            |pwd: &libc::passwd| pwd.pw_uid,
+        )
+    }
+
+    /// The id of the group called `name` on this system.
+    #[cfg(feature = "fs")]
+    pub fn gid(name: &str) -> Option<u32> {
+        let name = std::ffi::CString::new(name).ok()?;
+        lookup(
+            |grp, buf, len, result| unsafe {
+                libc::getgrnam_r(name.as_ptr(), grp, buf, len, result)
+            },
+            |grp: &libc::group| grp.gr_gid,
+        )
+    }
+
+    fn name(name: *const libc::c_char) -> Option<String> {
+        unsafe { CStr::from_ptr(name) }
+            .to_str()
+            .ok()
+            .map(String::from)
+    }
+
+    /// Run a `get*_r` lookup, growing the buffer for the strings of
+    /// the record until they fit.
+    fn lookup<T, R>(
+        call: impl Fn(*mut T, *mut libc::c_char, libc::size_t, *mut *mut T) -> libc::c_int,
+        read: impl Fn(&T) -> R,
+    ) -> Option<R> {
+        let mut buffer = vec![0 as libc::c_char; 1024];
+        loop {
+            let mut record = MaybeUninit::<T>::uninit();
+            let mut result = ptr::null_mut();
+            let error = call(
+                record.as_mut_ptr(),
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut result,
+            );
+            if error == libc::ERANGE && buffer.len() < 1 << 20 {
+                buffer.resize(buffer.len() * 2, 0);
+                continue;
+            }
+            if error != 0 || result.is_null() {
+                return None;
+            }
+            return Some(read(unsafe { &*result }));
+        }
+    }
+}
+
+#[cfg(any(unix, windows))]
 fn to_unix_mtime(m: &fs::Metadata) -> Result<(u64, u32), Box<dyn Error>> {
     let mtime = m.modified()?.duration_since(UNIX_EPOCH)?;
     Ok((mtime.as_secs(), mtime.subsec_nanos()))
@@ -92,17 +536,22 @@ impl FileStore {
     }
 
     pub fn push(&mut self, file: Entry) {
-        self.0.insert(Arc::new(file), ());
+        self.insert(Arc::new(file));
+    }
+
+    pub fn insert(&mut self, file: Arc<Entry>) {
+        self.0.insert(file, ());
     }
 }
 
 impl MetaObjectField for FileStore {
     type Item = Entry;
 
-    fn serialize(&self, mw: &mut impl FieldWriter) {
+    fn serialize(&self, mw: &mut impl FieldWriter) -> Result<(), WriteError> {
         for f in self.0.iter() {
-            mw.write_next(f.key());
+            mw.write_next(f.key())?;
         }
+        Ok(())
     }
 
     fn deserialize(&self, mw: &mut impl FieldReader<Self::Item>) {
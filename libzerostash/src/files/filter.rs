@@ -0,0 +1,197 @@
+//! Include and exclude rules, which select the files a backup walks.
+//!
+//! Rules are tried in order, and the first one that matches a path
+//! decides whether it's included. Paths no rule matches are included.
+//! A rule is a pattern after `- ` to exclude, or `+ ` to include, and
+//! rules without either exclude.
+//!
+//!  * glob patterns without a `/` match the name of files, like
+//!    `*.tmp`, and others match their whole path;
+//!  * a trailing `/` only matches directories, like `target/`;
+//!  * patterns after `re:` are regular expressions searched for in
+//!    the whole path, like `re:\.(o|so)$`.
+//!
+//! Directories that are excluded aren't walked at all, so including
+//! files below them needs their directories included first, like
+//! `+ */`. Ignore files and cache directories, if enabled, are left
+//! out after the rules, see `Ignores`.
+
+use crate::files::Ignores;
+
+use regex::Regex;
+use thiserror::Error;
+
+use std::path::Path;
+
+#[derive(Error, Debug)]
+pub enum FilterError {
+    #[error("Invalid glob in `{rule}`: {source}")]
+    Glob {
+        rule: String,
+        source: glob::PatternError,
+    },
+    #[error("Invalid regex in `{rule}`: {source}")]
+    Regex { rule: String, source: regex::Error },
+}
+
+pub type Result<T> = std::result::Result<T, FilterError>;
+
+#[derive(Clone, Debug)]
+enum Matcher {
+    Name(glob::Pattern),
+    Path(glob::Pattern),
+    Regex(Regex),
+}
+
+#[derive(Clone, Debug)]
+struct Rule {
+    include: bool,
+    dirs_only: bool,
+    matcher: Matcher,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Filter {
+    rules: Vec<Rule>,
+    ignore_files: Vec<String>,
+    exclude_caches: bool,
+    one_file_system: bool,
+}
+
+impl Filter {
+    /// Parse `rules`, in the order they are tried.
+    pub fn new(rules: &[impl AsRef<str>]) -> Result<Filter> {
+        let rules = rules
+            .iter()
+            .map(|r| Rule::parse(r.as_ref()))
+            .collect::<Result<_>>()?;
+
+        Ok(Filter {
+            rules,
+            ..Filter::default()
+        })
+    }
+
+    /// Leave out what ignore files called any of `names` match.
+    pub fn ignore_files(mut self, names: &[String]) -> Self {
+        self.ignore_files = names.to_vec();
+        self
+    }
+
+    /// Leave out directories tagged with `CACHEDIR.TAG`.
+    pub fn exclude_caches(mut self, exclude: bool) -> Self {
+        self.exclude_caches = exclude;
+        self
+    }
+
+    /// Don't enter other file systems mounted below the walked path.
+    pub fn one_file_system(mut self, yes: bool) -> Self {
+        self.one_file_system = yes;
+        self
+    }
+
+    pub fn is_one_file_system(&self) -> bool {
+        self.one_file_system
+    }
+
+    /// The ignore files of a new walk.
+    pub fn ignores(&self) -> Ignores {
+        Ignores::new(&self.ignore_files, self.exclude_caches)
+    }
+
+    /// If `path` is selected, and for directories, walked.
+    pub fn includes(&self, path: &Path, is_dir: bool) -> bool {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(path, is_dir))
+            .is_none_or(|rule| rule.include)
+    }
+}
+
+impl Rule {
+    fn parse(rule: &str) -> Result<Rule> {
+        let (include, pattern) = match (rule.strip_prefix("+ "), rule.strip_prefix("- ")) {
+            (Some(pattern), _) => (true, pattern),
+            (_, Some(pattern)) => (false, pattern),
+            _ => (false, rule),
+        };
+
+        if let Some(regex) = pattern.strip_prefix("re:") {
+            let regex = Regex::new(regex).map_err(|source| FilterError::Regex {
+                rule: rule.into(),
+                source,
+            })?;
+            return Ok(Rule {
+                include,
+                dirs_only: false,
+                matcher: Matcher::Regex(regex),
+            });
+        }
+
+        let (dirs_only, pattern) = match pattern.strip_suffix('/') {
+            Some(pattern) => (true, pattern),
+            None => (false, pattern),
+        };
+        let glob = glob::Pattern::new(pattern).map_err(|source| FilterError::Glob {
+            rule: rule.into(),
+            source,
+        })?;
+        let matcher = if pattern.contains('/') {
+            Matcher::Path(glob)
+        } else {
+            Matcher::Name(glob)
+        };
+
+        Ok(Rule {
+            include,
+            dirs_only,
+            matcher,
+        })
+    }
+
+    fn matches(&self, path: &Path, is_dir: bool) -> bool {
+        if self.dirs_only && !is_dir {
+            return false;
+        }
+
+        match &self.matcher {
+            Matcher::Name(glob) => path
+                .file_name()
+                .is_some_and(|name| glob.matches(&name.to_string_lossy())),
+            Matcher::Path(glob) => glob.matches_path(path),
+            Matcher::Regex(regex) => regex.is_match(&path.to_string_lossy()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn the_first_matching_rule_decides() {
+        use super::*;
+
+        let filter = Filter::new(&[
+            "+ keep.tmp",
+            "*.tmp",
+            "- node_modules/",
+            "re:/build-[0-9]+$",
+            "- */cache/*",
+        ])
+        .unwrap();
+        let included = |path: &str, is_dir| filter.includes(Path::new(path), is_dir);
+
+        assert!(included("home/keep.tmp", false));
+        assert!(!included("home/other.tmp", false));
+        assert!(!included("home/node_modules", true));
+        // only directories match a trailing `/`
+        assert!(included("home/node_modules", false));
+        assert!(!included("home/build-12", true));
+        assert!(included("home/build-12/x", false));
+        assert!(!included("home/cache/x", false));
+        assert!(included("home/src/main.rs", false));
+
+        assert!(Filter::default().includes(Path::new("any"), false));
+        assert!(Filter::new(&["- a**b"]).is_err());
+        assert!(Filter::new(&["re:("]).is_err());
+    }
+}
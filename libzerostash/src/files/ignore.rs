@@ -0,0 +1,233 @@
+//! Ignore files, like `.gitignore`, and cache directories tagged
+//! with `CACHEDIR.TAG`, which backups can leave out.
+//!
+//! The patterns of an ignore file apply to its directory and below,
+//! with the usual rules: the last pattern that matches decides, `!`
+//! includes again, patterns with a `/` are relative to the directory
+//! of the file, and others match names at any depth. Patterns in
+//! deeper files go before those of their parents.
+
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// What cache directories start their `CACHEDIR.TAG` with, see
+/// <https://bford.info/cachedir/>
+const CACHEDIR_SIGNATURE: &[u8] = b"Signature: 8a477f597d28d172789f06886806bc55";
+
+const MATCH_OPTIONS: glob::MatchOptions = glob::MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+struct Pattern {
+    glob: glob::Pattern,
+    negated: bool,
+    dirs_only: bool,
+    anchored: bool,
+}
+
+/// The patterns of one ignore file.
+struct IgnoreFile {
+    dir: PathBuf,
+    patterns: Vec<Pattern>,
+}
+
+type Files = Arc<Vec<Arc<IgnoreFile>>>;
+
+/// The state of a walk, with the ignore files of the directories it
+/// is in.
+pub struct Ignores {
+    names: Vec<String>,
+    exclude_caches: bool,
+    /// The ignore files applying in each directory from the root of
+    /// the walk to its current one, deepest first
+    dirs: Vec<(PathBuf, Files)>,
+}
+
+impl Ignores {
+    /// Read ignore files called any of `names`, and with
+    /// `exclude_caches`, leave out tagged cache directories.
+    pub fn new(names: &[String], exclude_caches: bool) -> Ignores {
+        Ignores {
+            names: names.to_vec(),
+            exclude_caches,
+            dirs: Vec::new(),
+        }
+    }
+
+    /// If `path` is left out. Directories have to be asked about
+    /// before what's in them, and in the order of a depth-first walk.
+    pub fn is_ignored(&mut self, path: &Path, is_dir: bool) -> bool {
+        if is_dir && self.exclude_caches && is_cache(path) {
+            return true;
+        }
+        if self.names.is_empty() {
+            return false;
+        }
+
+        let files = match path.parent() {
+            Some(parent) => self.files(parent),
+            None => return false,
+        };
+        for file in files.iter() {
+            let relative = match path.strip_prefix(&file.dir) {
+                Ok(relative) => relative,
+                Err(_) => continue,
+            };
+            if let Some(pattern) = file
+                .patterns
+                .iter()
+                .rev()
+                .find(|p| p.matches(relative, is_dir))
+            {
+                return !pattern.negated;
+            }
+        }
+
+        false
+    }
+
+    /// The ignore files applying in `dir`, which are those of its
+    /// parent, if the walk has seen it, after its own.
+    ///
+    /// Only the directories above `dir` are kept, as a depth-first
+    /// walk doesn't come back to others, so memory stays bounded by
+    /// the depth of the tree.
+    fn files(&mut self, dir: &Path) -> Files {
+        while self.dirs.last().is_some_and(|(d, _)| !dir.starts_with(d)) {
+            self.dirs.pop();
+        }
+        match self.dirs.last() {
+            Some((d, files)) if d == dir => return files.clone(),
+            _ => {}
+        }
+
+        let mut files = self
+            .names
+            .iter()
+            .filter_map(|name| IgnoreFile::read(dir, name))
+            .map(Arc::new)
+            .collect::<Vec<_>>();
+        match self.dirs.last() {
+            Some((d, inherited)) if dir.parent() == Some(d.as_path()) => {
+                files.extend(inherited.iter().cloned())
+            }
+            _ => {}
+        }
+
+        let files = Arc::new(files);
+        self.dirs.push((dir.to_owned(), files.clone()));
+        files
+    }
+}
+
+impl IgnoreFile {
+    fn read(dir: &Path, name: &str) -> Option<IgnoreFile> {
+        let contents = fs::read_to_string(dir.join(name)).ok()?;
+        let patterns = contents
+            .lines()
+            .filter_map(|line| match Pattern::parse(line) {
+                Ok(pattern) => pattern,
+                Err(e) => {
+                    warn!("skipping `{}` in {:?}: {}", line, dir.join(name), e);
+                    None
+                }
+            })
+            .collect();
+
+        Some(IgnoreFile {
+            dir: dir.to_owned(),
+            patterns,
+        })
+    }
+}
+
+impl Pattern {
+    /// A line of an ignore file, unless it's blank or a comment.
+    fn parse(line: &str) -> Result<Option<Pattern>, glob::PatternError> {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(None);
+        }
+
+        let (negated, line) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, line.strip_prefix('\\').unwrap_or(line)),
+        };
+        let (dirs_only, line) = match line.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        let anchored = line.contains('/');
+        let line = line.strip_prefix('/').unwrap_or(line);
+
+        Ok(Some(Pattern {
+            glob: glob::Pattern::new(line)?,
+            negated,
+            dirs_only,
+            anchored,
+        }))
+    }
+
+    fn matches(&self, relative: &Path, is_dir: bool) -> bool {
+        if self.dirs_only && !is_dir {
+            return false;
+        }
+
+        if self.anchored {
+            self.glob.matches_path_with(relative, MATCH_OPTIONS)
+        } else {
+            relative.file_name().is_some_and(|name| {
+                self.glob
+                    .matches_with(&name.to_string_lossy(), MATCH_OPTIONS)
+            })
+        }
+    }
+}
+
+fn is_cache(dir: &Path) -> bool {
+    let mut signature = [0; CACHEDIR_SIGNATURE.len()];
+    fs::File::open(dir.join("CACHEDIR.TAG"))
+        .and_then(|mut f| f.read_exact(&mut signature))
+        .is_ok_and(|_| signature == CACHEDIR_SIGNATURE)
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn ignore_files_apply_below_their_directory() {
+        use super::*;
+
+        let root = std::env::temp_dir().join("0s_test_ignores");
+        let _ = fs::remove_dir_all(&root);
+        for dir in ["a/b", "cache"] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        fs::write(root.join(".gitignore"), "# build output\n*.o\n/target/\n").unwrap();
+        fs::write(root.join("a/.gitignore"), "!keep.o\nb/*.log\n").unwrap();
+        fs::write(root.join("cache/CACHEDIR.TAG"), CACHEDIR_SIGNATURE).unwrap();
+
+        let mut ignores = Ignores::new(&[".gitignore".into()], true);
+        let mut ignored = |path: &str, is_dir| ignores.is_ignored(&root.join(path), is_dir);
+
+        assert!(ignored("x.o", false));
+        assert!(ignored("target", true));
+        assert!(!ignored("a", true));
+        // anchored to the directory of the file
+        assert!(!ignored("a/target", true));
+        assert!(ignored("a/x.o", false));
+        assert!(!ignored("a/keep.o", false));
+        assert!(!ignored("a/b", true));
+        assert!(ignored("a/b/x.log", false));
+        assert!(!ignored("a/x.log", false));
+        assert!(ignored("cache", true));
+
+        let mut ignores = Ignores::new(&[], false);
+        assert!(!ignores.is_ignored(&root.join("cache"), true));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,420 @@
+//! Format parameters recorded in the root object of a stash.
+//!
+//! They are checked before any other metadata is decoded, so opening
+//! a stash written by an incompatible version fails with an error
+//! saying so, instead of garbage from the decoder. Stashes from
+//! before the parameters were recorded are assumed to be version 1.
+
+use crate::compress::{Compression, CompressionRule, CompressionRules};
+use crate::crypto::{Cipher, ConvergenceSecret};
+use crate::meta::{DictionaryRef, FieldReader, FieldWriter, MetaObjectField, WriteError};
+use crate::objects::ObjectId;
+use crate::parity::Scheme;
+use crate::splitter::{ChunkSizes, Chunker, Chunking};
+use crate::BLOCK_SIZE;
+
+use thiserror::Error;
+
+use std::sync::Mutex;
+
+/// The format version written by this build
+pub const FORMAT_VERSION: u32 = 6;
+
+/// Builds from before this version can't read convergent chunk keys
+const CONVERGENT_VERSION: u32 = 2;
+
+/// Builds from before this version only read LZ4 chunks
+const COMPRESSION_VERSION: u32 = 3;
+
+/// Builds from before this version only read LZ4 metadata
+const DICTIONARY_VERSION: u32 = 4;
+
+/// Builds from before this version only read objects of `BLOCK_SIZE`
+const OBJECT_SIZE_VERSION: u32 = 5;
+
+/// Builds from before this version only read metadata written in full
+const DELTA_VERSION: u32 = 6;
+
+/// The smallest data objects a stash can be created with
+pub const MIN_OBJECT_SIZE: usize = 1024 * 1024;
+/// The largest data objects a stash can be created with
+pub const MAX_OBJECT_SIZE: usize = 64 * 1024 * 1024;
+
+#[derive(Error, Debug)]
+pub enum FormatError {
+    #[error("Stash was created by a newer version of zerostash, need format version >= {required}, this build supports {supported}")]
+    TooNew { required: u32, supported: u32 },
+    #[error("Unsupported cipher: {0}")]
+    Cipher(String),
+    #[error("Unsupported chunker: {0}")]
+    Chunker(String),
+    #[error("Unsupported compression: {0}")]
+    Compression(String),
+    #[error("Invalid chunk sizes: {0}")]
+    ChunkSizes(String),
+    #[error("Unsupported object size: {0}, has to be a power of two from {MIN_OBJECT_SIZE} to {MAX_OBJECT_SIZE}")]
+    ObjectSize(usize),
+    #[error("The stash isn't of the convergence family {0}")]
+    Family(String),
+    #[error("Invalid parity: {0}")]
+    Parity(String),
+}
+
+pub type Result<T> = std::result::Result<T, FormatError>;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Format {
+    /// Version of the format that wrote the stash
+    pub version: u32,
+    /// Oldest format version that can read the stash
+    pub min_version: u32,
+    pub cipher: String,
+    pub compression: String,
+    pub chunker: String,
+    /// The defaults of the chunker if missing
+    pub chunk_sizes: Option<ChunkSizes>,
+    /// The size of data objects. Metadata objects are always
+    /// `BLOCK_SIZE`
+    pub object_size: usize,
+    /// Number of the commit, which is signed from 1 onwards
+    pub generation: u64,
+    /// The secret of the family, if chunk keys are convergent
+    pub convergence: Option<ConvergenceSecret>,
+    /// The dictionary of the metadata, if it's in zstd
+    pub dictionary: Option<DictionaryRef>,
+    /// The compression of the files matching a rule, instead of
+    /// `compression`
+    pub compression_rules: Vec<CompressionRule>,
+    /// Data objects waiting to be collected
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub garbage: Vec<Garbage>,
+    /// The parity objects written for new data objects
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parity: Option<Scheme>,
+    /// Data objects of other stashes of the family that files refer
+    /// to, which are never deleted
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub shared: Vec<ObjectId>,
+    /// The most bytes the chunks may take up as they're stored
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quota: Option<u64>,
+    /// Bytes of the chunks as they're stored, compressed and
+    /// encrypted
+    pub stored_bytes: u64,
+    /// How many commits in a row only wrote what changed since the
+    /// one before, 0 if this one wrote all of the metadata
+    #[serde(skip_serializing_if = "is_zero")]
+    pub deltas: u32,
+    /// Bumped whenever data objects are rewritten or go away, so file
+    /// caches that may point to them are dropped
+    #[serde(skip_serializing_if = "is_zero")]
+    pub epoch: u64,
+}
+
+/// A data object none of the chunks of the stash are in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Garbage {
+    pub object: ObjectId,
+    /// When it was found to be unreferenced
+    pub unix_secs: u64,
+}
+
+impl Default for Format {
+    fn default() -> Format {
+        Format {
+            version: FORMAT_VERSION,
+            min_version: 1,
+            cipher: Cipher::default().name().into(),
+            compression: Compression::default().name(),
+            chunker: Chunker::default().name().into(),
+            chunk_sizes: None,
+            object_size: BLOCK_SIZE,
+            generation: 0,
+            convergence: None,
+            dictionary: None,
+            compression_rules: vec![],
+            garbage: vec![],
+            parity: None,
+            shared: vec![],
+            quota: None,
+            stored_bytes: 0,
+            deltas: 0,
+            epoch: 0,
+        }
+    }
+}
+
+impl Format {
+    /// The format of a new stash encrypted with `cipher`.
+    pub fn with_cipher(cipher: Cipher) -> Format {
+        Format {
+            cipher: cipher.name().into(),
+            ..Format::default()
+        }
+    }
+
+    /// Record how files are split.
+    pub fn with_chunking(mut self, chunking: &Chunking) -> Format {
+        self.chunker = chunking.chunker.name().into();
+        self.chunk_sizes = Some(chunking.sizes);
+        self
+    }
+
+    /// Record how chunks are compressed.
+    pub fn with_compression(mut self, compression: Compression) -> Format {
+        if compression != Compression::Lz4 {
+            self.min_version = self.min_version.max(COMPRESSION_VERSION);
+        }
+        self.compression = compression.name();
+        self
+    }
+
+    /// Record the compression rules of the stash.
+    pub fn with_compression_rules(mut self, rules: &[CompressionRule]) -> Format {
+        if rules
+            .iter()
+            .any(|r| r.compression != Compression::Lz4.name())
+        {
+            self.min_version = self.min_version.max(COMPRESSION_VERSION);
+        }
+        self.compression_rules = rules.to_vec();
+        self
+    }
+
+    /// Record that chunk keys are derived from `convergence`.
+    pub fn convergent(mut self, convergence: Option<ConvergenceSecret>) -> Format {
+        if convergence.is_some() {
+            self.min_version = self.min_version.max(CONVERGENT_VERSION);
+        }
+        self.convergence = convergence;
+        self
+    }
+
+    /// Record the dictionary the metadata is compressed with.
+    pub fn with_dictionary(mut self, dictionary: Option<DictionaryRef>) -> Format {
+        if dictionary.is_some() {
+            self.min_version = self.min_version.max(DICTIONARY_VERSION);
+        }
+        self.dictionary = dictionary;
+        self
+    }
+
+    /// Record the size of data objects.
+    pub fn with_object_size(mut self, size: usize) -> Format {
+        if size != BLOCK_SIZE {
+            self.min_version = self.min_version.max(OBJECT_SIZE_VERSION);
+        }
+        self.object_size = size;
+        self
+    }
+
+    /// Record the objects waiting to be collected.
+    pub fn with_garbage(mut self, garbage: &[Garbage]) -> Format {
+        self.garbage = garbage.to_vec();
+        self
+    }
+
+    /// Record the objects borrowed from other stashes of the family.
+    pub fn with_shared(mut self, shared: impl IntoIterator<Item = ObjectId>) -> Format {
+        self.shared = shared.into_iter().collect();
+        self.shared
+            .sort_unstable_by(|a, b| a.as_ref().cmp(b.as_ref()));
+        self
+    }
+
+    /// Record that this is the `deltas`th commit in a row to only
+    /// write what changed.
+    pub fn with_deltas(mut self, deltas: u32) -> Format {
+        if deltas > 0 {
+            self.min_version = self.min_version.max(DELTA_VERSION);
+        }
+        self.deltas = deltas;
+        self
+    }
+
+    /// Record the parity scheme of new data objects.
+    pub fn with_parity(mut self, parity: Option<Scheme>) -> Format {
+        self.parity = parity;
+        self
+    }
+
+    pub fn cipher(&self) -> Result<Cipher> {
+        Cipher::from_name(&self.cipher).ok_or_else(|| FormatError::Cipher(self.cipher.clone()))
+    }
+
+    pub fn compression(&self) -> Result<Compression> {
+        Compression::from_name(&self.compression)
+            .ok_or_else(|| FormatError::Compression(self.compression.clone()))
+    }
+
+    pub fn chunking(&self) -> Result<Chunking> {
+        let chunker = Chunker::from_name(&self.chunker)
+            .ok_or_else(|| FormatError::Chunker(self.chunker.clone()))?;
+        let sizes = self.chunk_sizes.unwrap_or_else(|| chunker.default_sizes());
+        sizes.check().map_err(FormatError::ChunkSizes)?;
+
+        Ok(Chunking {
+            sizes,
+            ..Chunking::new(chunker)
+        })
+    }
+
+    /// Check that this build can read and write a stash with this
+    /// format.
+    pub fn check(&self) -> Result<()> {
+        if self.min_version > FORMAT_VERSION {
+            return Err(FormatError::TooNew {
+                required: self.min_version,
+                supported: FORMAT_VERSION,
+            });
+        }
+        self.cipher()?;
+        let compression = self.compression()?;
+        CompressionRules::new(compression, &self.compression_rules)
+            .map_err(FormatError::Compression)?;
+        self.chunking()?;
+        if self.dictionary.is_some() && !cfg!(feature = "zstd") {
+            return Err(FormatError::Compression("zstd metadata".into()));
+        }
+        check_object_size(self.object_size)?;
+        if let Some(parity) = &self.parity {
+            parity.check().map_err(FormatError::Parity)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn is_zero<T: Default + PartialEq>(n: &T) -> bool {
+    *n == T::default()
+}
+
+/// Check that data objects can be `size` bytes.
+pub fn check_object_size(size: usize) -> Result<()> {
+    if !size.is_power_of_two() || !(MIN_OBJECT_SIZE..=MAX_OBJECT_SIZE).contains(&size) {
+        return Err(FormatError::ObjectSize(size));
+    }
+    Ok(())
+}
+
+/// The `Format` metadata field
+#[derive(Default)]
+pub struct FormatField(Mutex<Option<Format>>);
+
+impl FormatField {
+    pub fn new(format: Format) -> FormatField {
+        FormatField(Mutex::new(Some(format)))
+    }
+
+    pub fn get(&self) -> Option<Format> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+impl MetaObjectField for FormatField {
+    type Item = Format;
+
+    fn serialize(&self, mw: &mut impl FieldWriter) -> std::result::Result<(), WriteError> {
+        match self.get() {
+            Some(format) => mw.write_next(format),
+            None => Ok(()),
+        }
+    }
+
+    fn deserialize(&self, mw: &mut impl FieldReader<Self::Item>) {
+        if let Ok(format) = mw.read_next() {
+            *self.0.lock().unwrap() = Some(format);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn check_rejects_incompatible_formats() {
+        use super::*;
+
+        assert!(Format::default().check().is_ok());
+
+        let newer = Format {
+            version: FORMAT_VERSION + 2,
+            ..Format::default()
+        };
+        assert!(newer.check().is_ok());
+
+        let incompatible = Format {
+            min_version: FORMAT_VERSION + 1,
+            ..newer
+        };
+        assert!(matches!(
+            incompatible.check(),
+            Err(FormatError::TooNew { required, .. }) if required == FORMAT_VERSION + 1
+        ));
+
+        let aes = Format::with_cipher(Cipher::Aes256Gcm);
+        assert!(aes.check().is_ok());
+        assert_eq!(aes.cipher().unwrap(), Cipher::Aes256Gcm);
+
+        let cipher = Format {
+            cipher: "aes-128-ocb".into(),
+            ..Format::default()
+        };
+        assert!(matches!(cipher.check(), Err(FormatError::Cipher(_))));
+
+        let mut sizes = Chunker::FastCdc.default_sizes();
+        sizes.max = BLOCK_SIZE;
+        let chunking = Format::default().with_chunking(&Chunking {
+            sizes,
+            ..Chunking::new(Chunker::FastCdc)
+        });
+        assert!(matches!(chunking.check(), Err(FormatError::ChunkSizes(_))));
+
+        let convergent = Format::default().convergent(Some(ConvergenceSecret::generate()));
+        assert!(convergent.check().is_ok());
+        assert_eq!(convergent.min_version, 2);
+
+        let uncompressed = Format::default().with_compression(Compression::None);
+        assert_eq!(uncompressed.compression().unwrap(), Compression::None);
+        assert_eq!(uncompressed.min_version, 3);
+        let dictionary = Format::default().with_dictionary(Some(DictionaryRef {
+            id: 1,
+            object: Default::default(),
+        }));
+        assert_eq!(dictionary.min_version, 4);
+        assert_eq!(dictionary.check().is_ok(), cfg!(feature = "zstd"));
+        assert_eq!(Format::default().with_deltas(2).min_version, 6);
+        assert_eq!(Format::default().with_deltas(0).min_version, 1);
+        let unknown = Format {
+            compression: "brotli".into(),
+            ..Format::default()
+        };
+        assert!(matches!(unknown.check(), Err(FormatError::Compression(_))));
+        let rules = Format::default()
+            .with_compression_rules(&[CompressionRule::new("*.mkv", Compression::None)]);
+        assert!(rules.check().is_ok());
+        assert_eq!(rules.min_version, 3);
+        let invalid = Format::default().with_compression_rules(&[CompressionRule {
+            pattern: "[".into(),
+            compression: "lz4".into(),
+        }]);
+        assert!(matches!(invalid.check(), Err(FormatError::Compression(_))));
+
+        let objects = Format {
+            object_size: 1024,
+            ..Format::default()
+        };
+        assert!(matches!(
+            objects.check(),
+            Err(FormatError::ObjectSize(1024))
+        ));
+        let large = Format::default().with_object_size(MAX_OBJECT_SIZE);
+        assert!(large.check().is_ok());
+        assert_eq!(large.min_version, 5);
+        assert_eq!(
+            Format::default().with_object_size(BLOCK_SIZE).min_version,
+            1
+        );
+        assert!(check_object_size(3 * 1024 * 1024).is_err());
+    }
+}
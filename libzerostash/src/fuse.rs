@@ -0,0 +1,646 @@
+//! A read-only FUSE file system of snapshots, on Linux.
+//!
+//! Each snapshot is a directory named after its id, with its files
+//! at their paths below it. File contents are decrypted when they're
+//! read, so only the chunks that are read are fetched, with range
+//! reads where the backend has them.
+//!
+//! The kernel protocol is spoken directly over `/dev/fuse`, so no
+//! FUSE library is needed. As root, the file system is mounted with
+//! `mount(2)`, otherwise through `fusermount3` or `fusermount`, which
+//! have to be installed.
+
+use crate::error::Result;
+use crate::files::Entry;
+use crate::snapshots::Snapshot;
+use crate::stash::{ChunkReader, FileReader, Stash};
+
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryInto;
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::Path;
+use std::process::Command;
+use std::sync::Arc;
+
+/// The node id of the root directory
+const ROOT: u64 = 1;
+/// The minor version of the protocol, of major version 7
+const MINOR: u32 = 31;
+/// The most the kernel asks to read at once
+const MAX_READ: u32 = 128 * 1024;
+/// How long the kernel may cache names and attributes, which don't
+/// change
+const TTL_SECS: u64 = 60 * 60;
+
+const LOOKUP: u32 = 1;
+const FORGET: u32 = 2;
+const GETATTR: u32 = 3;
+const READLINK: u32 = 5;
+const OPEN: u32 = 14;
+const READ: u32 = 15;
+const STATFS: u32 = 17;
+const RELEASE: u32 = 18;
+const INIT: u32 = 26;
+const OPENDIR: u32 = 27;
+const READDIR: u32 = 28;
+const RELEASEDIR: u32 = 29;
+const ACCESS: u32 = 34;
+const INTERRUPT: u32 = 36;
+const DESTROY: u32 = 38;
+const BATCH_FORGET: u32 = 42;
+/// What would change the file system
+const WRITES: &[u32] = &[4, 6, 8, 9, 10, 11, 12, 13, 16, 21, 24, 35, 43, 45];
+
+/// Size of `fuse_in_header`
+const IN_HEADER: usize = 40;
+const FOPEN_KEEP_CACHE: u32 = 1 << 1;
+
+const S_IFMT: u32 = 0o170_000;
+const S_IFDIR: u32 = 0o040_000;
+const S_IFREG: u32 = 0o100_000;
+const S_IFLNK: u32 = 0o120_000;
+
+pub struct Fuse {
+    nodes: Vec<Node>,
+    reader: ChunkReader,
+    /// Open files by handle, so reading on keeps the chunk decoded
+    files: RefCell<HashMap<u64, FileReader>>,
+    next_handle: Cell<u64>,
+    /// The owner of the directories
+    owner: (u32, u32),
+}
+
+struct Node {
+    parent: u64,
+    kind: Kind,
+}
+
+enum Kind {
+    Dir {
+        children: BTreeMap<Vec<u8>, u64>,
+        unix_secs: u64,
+    },
+    File(Arc<Entry>),
+}
+
+impl Fuse {
+    /// The file system of `snapshots` of `stash`.
+    pub fn new(stash: &Stash, snapshots: Vec<Arc<Snapshot>>) -> Result<Fuse> {
+        let mut fuse = Fuse {
+            nodes: vec![Node {
+                parent: ROOT,
+                kind: Kind::dir(0),
+            }],
+            reader: stash.chunk_reader()?,
+            files: RefCell::default(),
+            next_handle: Cell::new(1),
+            owner: unsafe { (libc::getuid(), libc::getgid()) },
+        };
+
+        for snapshot in snapshots {
+            let name = snapshot.id.to_string().into_bytes();
+            let dir = fuse.child(ROOT, &name, || Kind::dir(snapshot.unix_secs));
+            for entry in snapshot.files.iter() {
+                let path = entry.path();
+                let mut names = path
+                    .as_os_str()
+                    .as_bytes()
+                    .split(|b| *b == b'/')
+                    .filter(|name| !matches!(*name, b"" | b"." | b".."))
+                    .peekable();
+
+                let mut parent = dir;
+                while let Some(name) = names.next() {
+                    parent = match names.peek() {
+                        Some(_) => fuse.child(parent, name, || Kind::dir(snapshot.unix_secs)),
+                        None => fuse.child(parent, name, || Kind::File(entry.clone())),
+                    };
+                }
+            }
+        }
+
+        Ok(fuse)
+    }
+
+    /// Mount the file system at `mountpoint`, and answer the requests
+    /// of the kernel one at a time, until it's unmounted with
+    /// `fusermount3 -u` or `umount`.
+    pub fn mount(self, mountpoint: impl AsRef<Path>) -> io::Result<()> {
+        let mut device = open_device(mountpoint.as_ref())?;
+        let mut buffer = vec![0; MAX_READ as usize + 4096];
+
+        loop {
+            let len = match device.read(&mut buffer) {
+                Ok(len) => len,
+                // interrupted before it was read
+                Err(e) if matches!(e.raw_os_error(), Some(libc::ENOENT | libc::EINTR)) => continue,
+                Err(e) if e.raw_os_error() == Some(libc::ENODEV) => return Ok(()),
+                Err(e) => return Err(e),
+            };
+
+            let request = &buffer[..len];
+            if let Some(reply) = self.handle(request) {
+                match device.write(&reply) {
+                    Ok(_) => {}
+                    // the request was interrupted since
+                    Err(e) if e.raw_os_error() == Some(libc::ENOENT) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            if request.len() >= IN_HEADER && u32_at(request, 4) == DESTROY {
+                return Ok(());
+            }
+        }
+    }
+
+    /// The reply to `request`, if it needs one.
+    fn handle(&self, request: &[u8]) -> Option<Vec<u8>> {
+        if request.len() < IN_HEADER {
+            return None;
+        }
+        let opcode = u32_at(request, 4);
+        let unique = u64_at(request, 8);
+        let ino = u64_at(request, 16);
+        let body = &request[IN_HEADER..];
+
+        let result = match opcode {
+            FORGET | BATCH_FORGET | INTERRUPT => return None,
+            INIT => Ok(self.init(body)),
+            LOOKUP => self.lookup(ino, body),
+            GETATTR => self.node(ino).map(|_| {
+                let mut out = attr_valid();
+                out.extend_from_slice(&self.attr(ino));
+                out
+            }),
+            READLINK => match self.node(ino).map(|n| &n.kind) {
+                Ok(Kind::File(entry)) if entry.symlink.is_some() => {
+                    Ok(entry.symlink.clone().unwrap().into_bytes())
+                }
+                Ok(_) => Err(libc::EINVAL),
+                Err(e) => Err(e),
+            },
+            OPEN => self.open(ino, body),
+            READ => self.read(ino, body),
+            OPENDIR => match self.node(ino).map(|n| &n.kind) {
+                Ok(Kind::Dir { .. }) => Ok(open_out(0, 0)),
+                Ok(_) => Err(libc::ENOTDIR),
+                Err(e) => Err(e),
+            },
+            READDIR => self.readdir(ino, body),
+            RELEASE => {
+                if body.len() >= 8 {
+                    self.files.borrow_mut().remove(&u64_at(body, 0));
+                }
+                Ok(vec![])
+            }
+            RELEASEDIR | DESTROY => Ok(vec![]),
+            STATFS => Ok(self.statfs()),
+            ACCESS => match body.get(..4).map(|_| u32_at(body, 0)) {
+                Some(mask) if mask & libc::W_OK as u32 != 0 => Err(libc::EROFS),
+                _ => self.node(ino).map(|_| vec![]),
+            },
+            opcode if WRITES.contains(&opcode) => Err(libc::EROFS),
+            _ => Err(libc::ENOSYS),
+        };
+
+        let (error, body) = match result {
+            Ok(body) => (0, body),
+            Err(errno) => (-errno, vec![]),
+        };
+        let mut reply = Vec::with_capacity(16 + body.len());
+        reply.extend_from_slice(&((16 + body.len()) as u32).to_le_bytes());
+        reply.extend_from_slice(&error.to_le_bytes());
+        reply.extend_from_slice(&unique.to_le_bytes());
+        reply.extend_from_slice(&body);
+        Some(reply)
+    }
+
+    /// `fuse_init_out`, taking the minor version of the kernel if it's
+    /// older.
+    fn init(&self, body: &[u8]) -> Vec<u8> {
+        let (minor, readahead) = if body.len() >= 12 {
+            (u32_at(body, 4).min(MINOR), u32_at(body, 8))
+        } else {
+            (MINOR, 0)
+        };
+
+        let mut out = vec![];
+        for field in [7, minor, readahead, 0].iter() {
+            out.extend_from_slice(&field.to_le_bytes());
+        }
+        // max_background, congestion_threshold
+        out.extend_from_slice(&16u16.to_le_bytes());
+        out.extend_from_slice(&12u16.to_le_bytes());
+        // max_write, time_gran
+        out.extend_from_slice(&MAX_READ.to_le_bytes());
+        out.extend_from_slice(&1u32.to_le_bytes());
+        out.resize(64, 0);
+        out
+    }
+
+    fn lookup(&self, parent: u64, body: &[u8]) -> std::result::Result<Vec<u8>, i32> {
+        let name = body.split(|b| *b == 0).next().unwrap_or_default();
+        let ino = match &self.node(parent)?.kind {
+            Kind::Dir { children, .. } => *children.get(name).ok_or(libc::ENOENT)?,
+            Kind::File(_) => return Err(libc::ENOTDIR),
+        };
+
+        let mut out = vec![];
+        out.extend_from_slice(&ino.to_le_bytes());
+        // generation, entry_valid, attr_valid
+        out.extend_from_slice(&0u64.to_le_bytes());
+        out.extend_from_slice(&TTL_SECS.to_le_bytes());
+        out.extend_from_slice(&TTL_SECS.to_le_bytes());
+        out.extend_from_slice(&[0; 8]);
+        out.extend_from_slice(&self.attr(ino));
+        Ok(out)
+    }
+
+    fn open(&self, ino: u64, body: &[u8]) -> std::result::Result<Vec<u8>, i32> {
+        let flags = body.get(..4).map(|_| u32_at(body, 0)).unwrap_or(0) as i32;
+        if flags & libc::O_ACCMODE != libc::O_RDONLY {
+            return Err(libc::EROFS);
+        }
+        match &self.node(ino)?.kind {
+            Kind::File(entry) => {
+                let fh = self.next_handle.get();
+                self.next_handle.set(fh + 1);
+                self.files
+                    .borrow_mut()
+                    .insert(fh, self.reader.open(entry.clone()));
+                Ok(open_out(fh, FOPEN_KEEP_CACHE))
+            }
+            Kind::Dir { .. } => Err(libc::EISDIR),
+        }
+    }
+
+    fn read(&self, ino: u64, body: &[u8]) -> std::result::Result<Vec<u8>, i32> {
+        let entry = match &self.node(ino)?.kind {
+            Kind::File(entry) => entry,
+            Kind::Dir { .. } => return Err(libc::EISDIR),
+        };
+        if body.len() < 20 {
+            return Err(libc::EINVAL);
+        }
+        let (fh, offset, size) = (u64_at(body, 0), u64_at(body, 8), u32_at(body, 16) as u64);
+
+        // reads of handles that weren't opened here get a reader of
+        // their own
+        let mut files = self.files.borrow_mut();
+        let mut unopened;
+        let file = match files.get_mut(&fh) {
+            Some(file) => file,
+            None => {
+                unopened = self.reader.open(entry.clone());
+                &mut unopened
+            }
+        };
+
+        let mut out = vec![];
+        file.seek(SeekFrom::Start(offset))
+            .and_then(|_| file.take(size).read_to_end(&mut out))
+            .map_err(|e| {
+                warn!("can't read {}: {}", entry.name, e);
+                libc::EIO
+            })?;
+        Ok(out)
+    }
+
+    fn readdir(&self, ino: u64, body: &[u8]) -> std::result::Result<Vec<u8>, i32> {
+        let node = self.node(ino)?;
+        let children = match &node.kind {
+            Kind::Dir { children, .. } => children,
+            Kind::File(_) => return Err(libc::ENOTDIR),
+        };
+        if body.len() < 20 {
+            return Err(libc::EINVAL);
+        }
+        let (offset, size) = (u64_at(body, 8), u32_at(body, 16) as usize);
+
+        let dots = [(&b"."[..], ino), (&b".."[..], node.parent)];
+        let entries = dots
+            .iter()
+            .copied()
+            .chain(children.iter().map(|(name, ino)| (name.as_slice(), *ino)));
+
+        let mut out = vec![];
+        for (i, (name, child)) in entries.enumerate().skip(offset as usize) {
+            let len = (24 + name.len() + 7) & !7;
+            if out.len() + len > size {
+                break;
+            }
+            out.extend_from_slice(&child.to_le_bytes());
+            // the offset of the next entry
+            out.extend_from_slice(&(i as u64 + 1).to_le_bytes());
+            out.extend_from_slice(&(name.len() as u32).to_le_bytes());
+            out.extend_from_slice(&(self.mode(child) >> 12).to_le_bytes());
+            out.extend_from_slice(name);
+            out.resize(out.len() + len - 24 - name.len(), 0);
+        }
+        Ok(out)
+    }
+
+    /// `fuse_kstatfs`, of a file system with no free space.
+    fn statfs(&self) -> Vec<u8> {
+        let mut out = vec![0; 80];
+        out[24..32].copy_from_slice(&(self.nodes.len() as u64).to_le_bytes());
+        // bsize, namelen, frsize
+        out[40..44].copy_from_slice(&4096u32.to_le_bytes());
+        out[44..48].copy_from_slice(&255u32.to_le_bytes());
+        out[48..52].copy_from_slice(&4096u32.to_le_bytes());
+        out
+    }
+
+    /// `fuse_attr` of node `ino`, which has to exist.
+    fn attr(&self, ino: u64) -> Vec<u8> {
+        let mode = self.mode(ino);
+        let (size, secs, nanos, nlink, (uid, gid), rdev) = match &self.node(ino).unwrap().kind {
+            Kind::Dir { unix_secs, .. } => (0, *unix_secs, 0, 2, self.owner, 0),
+            Kind::File(entry) => {
+                let size = match &entry.symlink {
+                    Some(target) => target.len() as u64,
+                    None => entry.size,
+                };
+                // the encoding of `new_encode_dev`
+                let rdev = entry.device.map_or(0, |(major, minor)| {
+                    (minor & 0xff) | (major << 8) | ((minor & !0xff) << 12)
+                });
+                let owner = (entry.unix_uid, entry.unix_gid);
+                (size, entry.unix_secs, entry.unix_nanos, 1, owner, rdev)
+            }
+        };
+
+        let mut out = vec![];
+        for field in [ino, size, size.div_ceil(512), secs, secs, secs].iter() {
+            out.extend_from_slice(&field.to_le_bytes());
+        }
+        for field in [nanos, nanos, nanos, mode, nlink, uid, gid, rdev, 4096, 0].iter() {
+            out.extend_from_slice(&field.to_le_bytes());
+        }
+        out
+    }
+
+    fn mode(&self, ino: u64) -> u32 {
+        let entry = match &self.nodes[ino as usize - 1].kind {
+            Kind::Dir { .. } => return S_IFDIR | 0o555,
+            Kind::File(entry) => entry,
+        };
+
+        let kind = match entry.unix_perm & S_IFMT {
+            _ if entry.symlink.is_some() => S_IFLNK,
+            0 => S_IFREG,
+            kind => kind,
+        };
+        // files from Windows have no mode
+        match entry.unix_perm & 0o7777 {
+            0 => kind | 0o444,
+            perm => kind | perm,
+        }
+    }
+
+    fn node(&self, ino: u64) -> std::result::Result<&Node, i32> {
+        ino.checked_sub(1)
+            .and_then(|i| self.nodes.get(i as usize))
+            .ok_or(libc::ENOENT)
+    }
+
+    /// The child `name` of directory `parent`, added as `kind` if
+    /// there's none.
+    fn child(&mut self, parent: u64, name: &[u8], kind: impl FnOnce() -> Kind) -> u64 {
+        let ino = self.nodes.len() as u64 + 1;
+        match &mut self.nodes[parent as usize - 1].kind {
+            Kind::Dir { children, .. } => match children.get(name) {
+                Some(existing) => return *existing,
+                None => children.insert(name.to_vec(), ino),
+            },
+            // a file and a directory of the same name
+            Kind::File(_) => return parent,
+        };
+
+        self.nodes.push(Node {
+            parent,
+            kind: kind(),
+        });
+        ino
+    }
+}
+
+impl Kind {
+    fn dir(unix_secs: u64) -> Kind {
+        Kind::Dir {
+            children: BTreeMap::new(),
+            unix_secs,
+        }
+    }
+}
+
+/// `fuse_attr_out` up to the attributes.
+fn attr_valid() -> Vec<u8> {
+    let mut out = TTL_SECS.to_le_bytes().to_vec();
+    out.extend_from_slice(&[0; 8]);
+    out
+}
+
+/// `fuse_open_out`
+fn open_out(fh: u64, flags: u32) -> Vec<u8> {
+    let mut out = vec![0; 16];
+    out[..8].copy_from_slice(&fh.to_le_bytes());
+    out[8..12].copy_from_slice(&flags.to_le_bytes());
+    out
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+/// Mount a read-only FUSE file system at `mountpoint`, returning the
+/// device to answer its requests on.
+fn open_device(mountpoint: &Path) -> io::Result<File> {
+    if unsafe { libc::geteuid() } != 0 {
+        return fusermount(mountpoint);
+    }
+
+    let device = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/fuse")?;
+    let target = CString::new(mountpoint.as_os_str().as_bytes())?;
+    let options = CString::new(format!(
+        "fd={},rootmode=40000,user_id=0,group_id=0,default_permissions,allow_other",
+        device.as_raw_fd()
+    ))?;
+    let (source, fstype) = (CString::new("zerostash")?, CString::new("fuse.zerostash")?);
+
+    let mounted = unsafe {
+        libc::mount(
+            source.as_ptr(),
+            target.as_ptr(),
+            fstype.as_ptr(),
+            libc::MS_RDONLY | libc::MS_NOSUID | libc::MS_NODEV,
+            options.as_ptr() as *const libc::c_void,
+        )
+    };
+    if mounted != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(device)
+}
+
+/// Have `fusermount3` or `fusermount` mount it, which passes the
+/// device back over a socket.
+fn fusermount(mountpoint: &Path) -> io::Result<File> {
+    let mut fds = [0; 2];
+    if unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_STREAM, 0, fds.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let (ours, theirs) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+    let status = ["fusermount3", "fusermount"]
+        .iter()
+        .find_map(|program| {
+            Command::new(program)
+                .args([
+                    "-o",
+                    "ro,nosuid,nodev,default_permissions,fsname=zerostash",
+                    "--",
+                ])
+                .arg(mountpoint)
+                .env("_FUSE_COMMFD", theirs.as_raw_fd().to_string())
+                .status()
+                .ok()
+        })
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                "mounting as a user needs fusermount3 or fusermount",
+            )
+        })?;
+    drop(theirs);
+    if !status.success() {
+        return Err(io::Error::other(format!("fusermount failed: {}", status)));
+    }
+
+    receive_fd(ours.as_raw_fd())
+}
+
+/// The descriptor sent over `socket` with `SCM_RIGHTS`.
+fn receive_fd(socket: RawFd) -> io::Result<File> {
+    let mut byte = [0u8; 1];
+    let mut iov = libc::iovec {
+        iov_base: byte.as_mut_ptr() as *mut libc::c_void,
+        iov_len: byte.len(),
+    };
+    // aligned for `cmsghdr`
+    let mut control = [0u64; 8];
+    let mut message: libc::msghdr = unsafe { std::mem::zeroed() };
+    message.msg_iov = &mut iov;
+    message.msg_iovlen = 1;
+    message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    message.msg_controllen = std::mem::size_of_val(&control) as _;
+
+    if unsafe { libc::recvmsg(socket, &mut message, 0) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let header = unsafe { libc::CMSG_FIRSTHDR(&message) };
+    if header.is_null() || unsafe { (*header).cmsg_type } != libc::SCM_RIGHTS {
+        return Err(io::Error::other("fusermount sent no descriptor"));
+    }
+    let fd = unsafe { std::ptr::read_unaligned(libc::CMSG_DATA(header) as *const libc::c_int) };
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn snapshots_are_served_as_files() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use crate::snapshots::Labels;
+        use crate::stash::StashKey;
+
+        let key = StashKey::open_stash("fuse", "test").unwrap();
+        let mut stash = Stash::new(Arc::new(MemoryBackend::default()), key);
+        let mut ingest = stash.ingest().unwrap();
+        let contents = (0..100_000u32).map(|i| i as u8).collect::<Vec<_>>();
+        ingest
+            .add_file(Entry::from_stream("/etc/app.conf"), &contents[..])
+            .unwrap();
+        ingest
+            .add_file(Entry::from_stream("/notes"), &b"hi"[..])
+            .unwrap();
+        let snapshot = ingest.finish(0, vec![], vec![], Labels::new()).unwrap();
+        let fuse = Fuse::new(&stash, stash.snapshots()).unwrap();
+
+        let call = |opcode: u32, ino: u64, body: &[u8]| {
+            let mut request = vec![0; IN_HEADER];
+            request[..4].copy_from_slice(&((IN_HEADER + body.len()) as u32).to_le_bytes());
+            request[4..8].copy_from_slice(&opcode.to_le_bytes());
+            request[8..16].copy_from_slice(&7u64.to_le_bytes());
+            request[16..24].copy_from_slice(&ino.to_le_bytes());
+            request.extend_from_slice(body);
+
+            let reply = fuse.handle(&request).unwrap();
+            assert_eq!(u32_at(&reply, 0) as usize, reply.len());
+            assert_eq!(u64_at(&reply, 8), 7);
+            (u32_at(&reply, 4) as i32, reply[16..].to_vec())
+        };
+        let lookup = |parent: u64, name: &str| {
+            let (error, out) = call(LOOKUP, parent, format!("{}\0", name).as_bytes());
+            (error == 0).then(|| u64_at(&out, 0))
+        };
+        let read = |offset: u64, size: u32| {
+            let mut body = vec![0; 40];
+            body[8..16].copy_from_slice(&offset.to_le_bytes());
+            body[16..20].copy_from_slice(&size.to_le_bytes());
+            body
+        };
+
+        let (error, init) = call(INIT, 0, &[7, 0, 0, 0, 40, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0]);
+        assert_eq!((error, u32_at(&init, 0), u32_at(&init, 4)), (0, 7, 31));
+
+        let dir = lookup(ROOT, &snapshot.id.to_string()).unwrap();
+        let etc = lookup(dir, "etc").unwrap();
+        let file = lookup(etc, "app.conf").unwrap();
+        assert_eq!(lookup(etc, "missing"), None);
+        assert_eq!(lookup(file, "below"), None);
+
+        let (_, attr) = call(GETATTR, file, &[0; 16]);
+        // size, after attr_valid and the inode
+        assert_eq!(u64_at(&attr, 24), contents.len() as u64);
+        assert_eq!(u32_at(&attr, 16 + 60) & S_IFMT, S_IFREG);
+
+        let (_, data) = call(READ, file, &read(99_000, 4096));
+        assert_eq!(data, &contents[99_000..]);
+        let (_, data) = call(READ, file, &read(200_000, 4096));
+        assert!(data.is_empty());
+
+        let (_, listing) = call(READDIR, dir, &read(0, 4096));
+        let names = (0..4)
+            .scan(0, |at, _| {
+                let len = u32_at(&listing, *at + 16) as usize;
+                let name = listing.get(*at + 24..*at + 24 + len)?.to_vec();
+                *at += (24 + len + 7) & !7;
+                Some(String::from_utf8(name).unwrap())
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec![".", "..", "etc", "notes"]);
+
+        // read-only, whatever is asked
+        assert_eq!(call(16, file, &[0; 40]).0, -libc::EROFS);
+        assert_eq!(
+            call(OPEN, file, &(libc::O_WRONLY as u32).to_le_bytes()).0,
+            -libc::EROFS
+        );
+        assert_eq!(call(OPEN, file, &[0; 8]).0, 0);
+        assert!(fuse.handle(&[0; 8]).is_none());
+    }
+}
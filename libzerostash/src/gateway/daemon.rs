@@ -0,0 +1,358 @@
+use crate::cancel::CancelToken;
+use crate::error::Result;
+use crate::gateway::http::{self, Request, Response};
+use crate::gateway::{contents, summary};
+#[cfg(feature = "metrics")]
+use crate::metrics::Metrics;
+use crate::snapshots::Snapshot;
+use crate::stash::{BackupOptions, RestoreOptions, Stash, VerifyOptions};
+
+use serde::Deserialize;
+use serde_json::json;
+
+use std::io;
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex, TryLockError};
+#[cfg(feature = "metrics")]
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// Serves an API to a stash open for writing, so a NAS can run
+/// backups, restores and verification as a service that thin clients
+/// drive.
+///
+/// Every request needs an `Authorization: Bearer <token>` header with
+/// the token the daemon was made with. Paths are those of the machine
+/// the daemon runs on, and files are read and written with its
+/// permissions. One request is answered at a time, others get
+/// `409 Conflict` until it's done.
+///
+/// * `GET /snapshots`, `GET /snapshots/<id>`: as from the `Gateway`
+/// * `POST /backup`: `{"paths": [..], "tags": [..]}`, answered with
+///   the new snapshot
+/// * `POST /snapshots/<id>/restore`: `{"target": "..", "patterns":
+///   [..]}`, answered with the files and bytes restored
+/// * `POST /verify`: the objects and chunks checked, with the ids of
+///   missing and corrupt objects
+/// * `GET /metrics`: with `with_metrics`, the metrics of the stash and
+///   its runs for Prometheus, also while a request is running
+///
+/// Failures are answered with `{"error": ".."}`.
+pub struct Daemon {
+    stash: Mutex<Stash>,
+    token: String,
+    #[cfg(feature = "metrics")]
+    metrics: Option<Arc<Metrics>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Backup {
+    paths: Vec<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Restore {
+    target: String,
+    #[serde(default)]
+    patterns: Vec<String>,
+}
+
+impl Daemon {
+    /// Serve `stash` to clients that know `token`. With an empty
+    /// token, every request is refused.
+    pub fn new(stash: Stash, token: impl Into<String>) -> Daemon {
+        Daemon {
+            stash: Mutex::new(stash),
+            token: token.into(),
+            #[cfg(feature = "metrics")]
+            metrics: None,
+        }
+    }
+
+    /// Count what the stash does in `metrics`, and record each
+    /// backup, restore and verification as a run.
+    #[cfg(feature = "metrics")]
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Daemon {
+        self.stash.get_mut().unwrap().set_progress(metrics.clone());
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Answer requests on `listener` until it fails, each connection
+    /// in its own thread.
+    pub fn serve(self, listener: TcpListener) -> io::Result<()> {
+        http::serve(listener, move |request| self.respond(request))
+    }
+
+    fn respond(&self, request: &Request) -> Response {
+        if !self.authorized(request) {
+            return Response::new(401).header("WWW-Authenticate", "Bearer");
+        }
+        #[cfg(feature = "metrics")]
+        if let ("GET", Some(metrics)) = (request.method.as_str(), &self.metrics) {
+            if request.path() == "/metrics" {
+                return Response::new(200)
+                    .header("Content-Type", "text/plain; version=0.0.4")
+                    .body(metrics.render().into_bytes());
+            }
+        }
+        let mut stash = match self.stash.try_lock() {
+            Ok(stash) => stash,
+            Err(TryLockError::WouldBlock) => return error(409, "another request is running"),
+            Err(TryLockError::Poisoned(_)) => return error(500, "an earlier request failed"),
+        };
+
+        #[cfg(feature = "metrics")]
+        let start = Instant::now();
+        let path = request.path();
+        let segments = path.trim_matches('/').split('/').collect::<Vec<_>>();
+        let response = match (request.method.as_str(), segments.as_slice()) {
+            ("GET", ["snapshots"]) => Response::json(&json!(stash
+                .snapshots()
+                .iter()
+                .map(|s| summary(s))
+                .collect::<Vec<_>>())),
+            ("GET", ["snapshots", id]) => match find(&stash, id) {
+                Some(snapshot) => Response::json(&contents(&snapshot)),
+                None => Response::new(404),
+            },
+            ("POST", ["backup"]) => match parse(request) {
+                Ok(backup) => backup_paths(&mut stash, backup),
+                Err(response) => response,
+            },
+            ("POST", ["snapshots", id, "restore"]) => match (find(&stash, id), parse(request)) {
+                (None, _) => Response::new(404),
+                (_, Err(response)) => response,
+                (Some(snapshot), Ok(restore)) => {
+                    outcome(restore_to(&mut stash, &snapshot, restore))
+                }
+            },
+            ("POST", ["verify"]) => outcome(verify(&mut stash)),
+            (_, ["snapshots"]) | (_, ["snapshots", _]) => Response::new(405).header("Allow", "GET"),
+            (_, ["backup"]) | (_, ["verify"]) | (_, ["snapshots", _, "restore"]) => {
+                Response::new(405).header("Allow", "POST")
+            }
+            _ => Response::new(404),
+        };
+
+        #[cfg(feature = "metrics")]
+        self.record(request, start, &response);
+        response
+    }
+
+    /// Record a backup, restore or verification that ran, rather than
+    /// being turned away, as a run.
+    #[cfg(feature = "metrics")]
+    fn record(&self, request: &Request, start: Instant, response: &Response) {
+        let metrics = match &self.metrics {
+            Some(metrics) if request.method == "POST" => metrics,
+            _ => return,
+        };
+        match response.status {
+            200..=299 => {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+                metrics.success(start.elapsed(), now.as_secs());
+            }
+            500 => metrics.failure(start.elapsed()),
+            _ => {}
+        }
+    }
+
+    fn authorized(&self, request: &Request) -> bool {
+        let given = request
+            .header("Authorization")
+            .and_then(|h| h.strip_prefix("Bearer "))
+            .unwrap_or_default();
+
+        // compared in constant time, so the token can't be guessed
+        // byte by byte
+        !self.token.is_empty()
+            && given.len() == self.token.len()
+            && given
+                .bytes()
+                .zip(self.token.bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+}
+
+fn find(stash: &Stash, id: &str) -> Option<Arc<Snapshot>> {
+    stash
+        .snapshots()
+        .into_iter()
+        .find(|s| id.parse() == Ok(s.id))
+}
+
+fn parse<'a, T: Deserialize<'a>>(request: &'a Request) -> std::result::Result<T, Response> {
+    serde_json::from_slice(&request.body).map_err(|e| error(400, &e.to_string()))
+}
+
+fn backup_paths(stash: &mut Stash, backup: Backup) -> Response {
+    if backup.paths.is_empty() {
+        return error(400, "no paths to back up");
+    }
+    let options = BackupOptions {
+        tags: backup.tags,
+        ..BackupOptions::default()
+    };
+
+    match stash.backup(&backup.paths, &options) {
+        Ok(snapshot) => {
+            let mut response = Response::json(&summary(&snapshot));
+            response.status = 201;
+            response
+        }
+        Err(e) => error(500, &e.to_string()),
+    }
+}
+
+fn restore_to(
+    stash: &mut Stash,
+    snapshot: &Snapshot,
+    restore: Restore,
+) -> Result<serde_json::Value> {
+    let options = RestoreOptions {
+        patterns: restore.patterns,
+        ..RestoreOptions::default()
+    };
+    let summary = stash.restore(snapshot, &restore.target, &options)?;
+
+    Ok(json!({
+        "files": summary.files,
+        "bytes": summary.bytes,
+    }))
+}
+
+fn verify(stash: &mut Stash) -> Result<serde_json::Value> {
+    let verified = stash.verify_with(&VerifyOptions::default(), &CancelToken::default())?;
+    let ids =
+        |ids: &[crate::objects::ObjectId]| ids.iter().map(|id| id.to_string()).collect::<Vec<_>>();
+
+    Ok(json!({
+        "objects": verified.objects,
+        "chunks": verified.chunks,
+        "missing": ids(&verified.missing),
+        "corrupt": ids(&verified.corrupt),
+    }))
+}
+
+fn outcome(result: Result<serde_json::Value>) -> Response {
+    match result {
+        Ok(value) => Response::json(&value),
+        Err(e) => error(500, &e.to_string()),
+    }
+}
+
+fn error(status: u16, message: &str) -> Response {
+    let mut response = Response::json(&json!({ "error": message }));
+    response.status = status;
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn daemons_back_up_restore_and_verify_on_request() {
+        use super::*;
+        use crate::backends::{MemoryBackend, Remote};
+        use crate::stash::StashKey;
+        use std::{env, fs, thread};
+
+        let key = StashKey::open_stash("daemon", "test").unwrap();
+        let stash = Stash::new(Arc::new(MemoryBackend::default()), key);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = Remote::new(listener.local_addr().unwrap().to_string());
+        thread::spawn(move || Daemon::new(stash, "secret").serve(listener));
+
+        let auth = "Authorization: Bearer secret\r\n";
+        let call = |method, path: &str, body: &str| {
+            let response = client.send(method, path, auth, body.as_bytes()).unwrap();
+            let value = serde_json::from_slice(&response.body).unwrap_or_default();
+            (response.status, value)
+        };
+
+        let (status, snapshot): (_, serde_json::Value) = call(
+            "POST",
+            "/backup",
+            r#"{"paths": ["tests/data/10k_random_blob"], "tags": ["nas"]}"#,
+        );
+        assert_eq!(status, 201);
+        assert_eq!(snapshot["tags"], json!(["nas"]));
+        let id = snapshot["id"].as_u64().unwrap();
+
+        let (_, list) = call("GET", "/snapshots", "");
+        assert_eq!(list.as_array().unwrap().len(), 1);
+        let (_, manifest) = call("GET", &format!("/snapshots/{}", id), "");
+        assert_eq!(manifest["files"].as_array().unwrap().len(), 1);
+
+        let target = env::temp_dir().join("0s_test_daemon");
+        let _ = fs::remove_dir_all(&target);
+        let body = json!({ "target": target }).to_string();
+        let (status, restored) = call("POST", &format!("/snapshots/{}/restore", id), &body);
+        assert_eq!(status, 200);
+        assert_eq!(restored["files"], 1);
+        assert!(
+            fs::read(target.join("tests/data/10k_random_blob")).unwrap()
+                == fs::read("tests/data/10k_random_blob").unwrap()
+        );
+        fs::remove_dir_all(&target).unwrap();
+
+        let (status, verified) = call("POST", "/verify", "");
+        assert_eq!(status, 200);
+        assert!(verified["chunks"].as_u64().unwrap() > 0);
+        assert_eq!(verified["corrupt"], json!([]));
+
+        let (status, error) = call("POST", "/backup", r#"{"paths": []}"#);
+        assert_eq!(status, 400);
+        assert!(error["error"].is_string());
+        assert_eq!(call("GET", "/backup", "").0, 405);
+        assert_eq!(call("POST", "/snapshots/9/restore", &body).0, 404);
+
+        let wrong = "Authorization: Bearer secreT\r\n";
+        assert_eq!(
+            client.send("GET", "/snapshots", wrong, &[]).unwrap().status,
+            401
+        );
+        assert_eq!(client.request("/snapshots", "").unwrap().status, 401);
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn daemons_serve_metrics() {
+        use super::*;
+        use crate::backends::{MemoryBackend, Remote};
+        use crate::stash::StashKey;
+        use std::thread;
+
+        let key = StashKey::open_stash("daemon metrics", "test").unwrap();
+        let stash = Stash::new(Arc::new(MemoryBackend::default()), key);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = Remote::new(listener.local_addr().unwrap().to_string());
+        let daemon = Daemon::new(stash, "secret").with_metrics(Arc::new(Metrics::new("nas")));
+        thread::spawn(move || daemon.serve(listener));
+
+        let auth = "Authorization: Bearer secret\r\n";
+        let body = r#"{"paths": ["tests/data/10k_random_blob"]}"#;
+        for _ in 0..2 {
+            let backup = client.send("POST", "/backup", auth, body.as_bytes());
+            assert_eq!(backup.unwrap().status, 201);
+        }
+        let empty = client.send("POST", "/backup", auth, br#"{"paths": []}"#);
+        assert_eq!(empty.unwrap().status, 400);
+
+        let metrics = client.send("GET", "/metrics", auth, &[]).unwrap();
+        assert_eq!(metrics.status, 200);
+        let text = String::from_utf8(metrics.body).unwrap();
+        assert!(text.contains("zerostash_processed_bytes_total{stash=\"nas\"} 20480\n"));
+        assert!(text.contains("zerostash_chunks_total{stash=\"nas\",chunk=\"new\"}"));
+        assert!(text.contains("stage=\"upload\"}"));
+
+        // runs that were turned away aren't counted
+        assert!(text.contains("zerostash_runs_total{stash=\"nas\"} 2\n"));
+        assert!(text.contains("zerostash_errors_total{stash=\"nas\"} 0\n"));
+        assert_eq!(client.send("GET", "/metrics", "", &[]).unwrap().status, 401);
+    }
+}
@@ -0,0 +1,367 @@
+//! Just enough HTTP/1.1 for the gateway and its clients. Every
+//! connection carries a single request.
+
+pub(crate) use crate::time::http_date;
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+
+/// Requests with longer headers are refused.
+const MAX_HEADER: usize = 16 * 1024;
+/// Requests with larger bodies are refused.
+const MAX_BODY: usize = 1024 * 1024;
+
+pub(crate) struct Request {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+/// Writes a body of a known length, without holding all of it in
+/// memory.
+pub(crate) type Stream = Box<dyn FnOnce(&mut dyn Write) -> io::Result<()> + Send>;
+
+pub(crate) struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    stream: Option<(u64, Stream)>,
+}
+
+/// Answer requests on `listener` with `handler` until it fails, each
+/// connection in its own thread.
+pub(crate) fn serve<H>(listener: TcpListener, handler: H) -> io::Result<()>
+where
+    H: Fn(&Request) -> Response + Send + Sync + 'static,
+{
+    let handler = Arc::new(handler);
+
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let handler = handler.clone();
+
+        thread::spawn(move || {
+            if let Err(e) = handle(stream, &*handler) {
+                debug!("connection failed: {}", e);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+fn handle(stream: TcpStream, handler: &dyn Fn(&Request) -> Response) -> io::Result<()> {
+    let mut input = BufReader::new(stream.try_clone()?);
+    let request = match Request::read(&mut input) {
+        Ok(request) => request,
+        Err(_) => return Response::new(400).write(&mut &stream, false),
+    };
+
+    let response = handler(&request);
+    trace!("{} {} {}", request.method, request.path, response.status);
+    response.write(&mut &stream, request.method == "HEAD")
+}
+
+impl Request {
+    pub fn read(input: &mut impl BufRead) -> io::Result<Request> {
+        let (start, headers) = read_head(input)?;
+
+        let mut parts = start.split(' ');
+        let method = parts.next().unwrap_or_default().to_string();
+        let path = parts
+            .next()
+            .ok_or_else(|| invalid("no request target"))?
+            .to_string();
+
+        if read_len(&headers)? > MAX_BODY {
+            return Err(invalid("body too large"));
+        }
+        let body = read_body(input, &headers)?;
+
+        Ok(Request {
+            method,
+            path,
+            headers,
+            body,
+        })
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        header(&self.headers, name)
+    }
+
+    /// The decoded path, without the query.
+    pub fn path(&self) -> String {
+        percent_decode(self.path.split('?').next().unwrap_or_default())
+    }
+}
+
+impl Response {
+    pub fn new(status: u16) -> Response {
+        Response {
+            status,
+            headers: vec![],
+            body: vec![],
+            stream: None,
+        }
+    }
+
+    pub fn json(value: &serde_json::Value) -> Response {
+        Response::new(200)
+            .header("Content-Type", "application/json")
+            .body(value.to_string().into_bytes())
+    }
+
+    pub fn header(mut self, name: &str, value: impl ToString) -> Response {
+        self.headers.push((name.into(), value.to_string()));
+        self
+    }
+
+    pub fn body(mut self, body: Vec<u8>) -> Response {
+        self.body = body;
+        self
+    }
+
+    /// Write `len` bytes of body with `stream` instead of `body`.
+    pub fn stream(mut self, len: u64, stream: Stream) -> Response {
+        self.stream = Some((len, stream));
+        self
+    }
+
+    /// Write the response, leaving out the body for `HEAD` requests.
+    pub fn write(self, out: &mut impl Write, head_only: bool) -> io::Result<()> {
+        let len = match &self.stream {
+            Some((len, _)) => *len,
+            None => self.body.len() as u64,
+        };
+
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason(self.status));
+        for (name, value) in self.headers.iter() {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str(&format!(
+            "Content-Length: {}\r\nConnection: close\r\n\r\n",
+            len
+        ));
+
+        out.write_all(head.as_bytes())?;
+        match self.stream {
+            _ if head_only => {}
+            Some((_, stream)) => stream(out)?,
+            None => out.write_all(&self.body)?,
+        }
+        out.flush()
+    }
+
+    pub fn read(input: &mut impl BufRead) -> io::Result<Response> {
+        let (start, headers) = read_head(input)?;
+        let status = start
+            .split(' ')
+            .nth(1)
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(|| invalid("bad status line"))?;
+
+        let body = read_body(input, &headers)?;
+        Ok(Response {
+            status,
+            headers,
+            body,
+            stream: None,
+        })
+    }
+}
+
+fn read_head(input: &mut impl BufRead) -> io::Result<(String, Vec<(String, String)>)> {
+    let mut lines = vec![];
+    let mut total = 0;
+
+    loop {
+        let mut line = String::new();
+        let n = input
+            .by_ref()
+            .take(MAX_HEADER as u64)
+            .read_line(&mut line)?;
+        total += n;
+        if n == 0 || total > MAX_HEADER {
+            return Err(invalid("incomplete header"));
+        }
+
+        let line = line.trim_end_matches(&['\r', '\n'][..]);
+        if line.is_empty() {
+            break;
+        }
+        lines.push(line.to_string());
+    }
+
+    let start = lines.remove(0);
+    let headers = lines
+        .into_iter()
+        .filter_map(|l| {
+            let (name, value) = l.split_once(':')?;
+            Some((name.trim().to_string(), value.trim().to_string()))
+        })
+        .collect();
+
+    Ok((start, headers))
+}
+
+fn read_len(headers: &[(String, String)]) -> io::Result<usize> {
+    match header(headers, "Content-Length") {
+        Some(len) => len.parse().map_err(|_| invalid("bad Content-Length")),
+        None => Ok(0),
+    }
+}
+
+fn read_body(input: &mut impl BufRead, headers: &[(String, String)]) -> io::Result<Vec<u8>> {
+    let mut body = vec![0; read_len(headers)?];
+    input.read_exact(&mut body)?;
+    Ok(body)
+}
+
+fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str())
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        206 => "Partial Content",
+        207 => "Multi-Status",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        416 => "Range Not Satisfiable",
+        _ => "Internal Server Error",
+    }
+}
+
+/// Decode `%XX` escapes. Invalid escapes are kept as they are.
+pub(crate) fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|h| u8::from_str_radix(std::str::from_utf8(h).ok()?, 16).ok());
+        match escaped {
+            Some(b) => {
+                out.push(b);
+                i += 3;
+            }
+            None => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Escape everything but unreserved characters and `/` in a path.
+pub(crate) fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                (b as char).to_string()
+            }
+            b => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// The inclusive byte range requested by a `Range` header for a
+/// resource of `len` bytes. `Ok(None)` means the whole resource.
+///
+/// Multiple ranges aren't supported, so those get the whole resource.
+pub(crate) fn byte_range(range: Option<&str>, len: u64) -> Result<Option<(u64, u64)>, ()> {
+    let spec = match range {
+        Some(r) => r.trim().strip_prefix("bytes=").ok_or(())?,
+        None => return Ok(None),
+    };
+    if spec.contains(',') {
+        return Ok(None);
+    }
+
+    let (start, end) = spec.split_once('-').ok_or(())?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix: u64 = suffix.parse().map_err(|_| ())?;
+            (len.saturating_sub(suffix), len.saturating_sub(1))
+        }
+        (start, "") => (start.parse().map_err(|_| ())?, len.saturating_sub(1)),
+        (start, end) => {
+            let end: u64 = end.parse().map_err(|_| ())?;
+            (
+                start.parse().map_err(|_| ())?,
+                end.min(len.saturating_sub(1)),
+            )
+        }
+    };
+
+    if len == 0 || start > end {
+        return Err(());
+    }
+    Ok(Some((start, end)))
+}
+
+/// The range of a `Range` header that gives both ends, like the ones
+/// `Remote` asks for, which can be read without knowing the length.
+pub(crate) fn closed_range(range: Option<&str>) -> Option<(u64, u64)> {
+    let spec = range?.trim().strip_prefix("bytes=")?;
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = (start.trim().parse().ok()?, end.trim().parse().ok()?);
+    if start > end {
+        return None;
+    }
+    Some((start, end))
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn parses_byte_ranges() {
+        use super::{byte_range, closed_range};
+
+        assert_eq!(byte_range(None, 100), Ok(None));
+        assert_eq!(byte_range(Some("bytes=0-9"), 100), Ok(Some((0, 9))));
+        assert_eq!(byte_range(Some("bytes=90-"), 100), Ok(Some((90, 99))));
+        assert_eq!(byte_range(Some("bytes=-10"), 100), Ok(Some((90, 99))));
+        assert_eq!(byte_range(Some("bytes=50-500"), 100), Ok(Some((50, 99))));
+        assert_eq!(byte_range(Some("bytes=0-1,5-6"), 100), Ok(None));
+        assert_eq!(byte_range(Some("bytes=100-"), 100), Err(()));
+        assert_eq!(byte_range(Some("items=0-1"), 100), Err(()));
+
+        assert_eq!(closed_range(Some("bytes=50-500")), Some((50, 500)));
+        assert_eq!(closed_range(Some("bytes=90-")), None);
+        assert_eq!(closed_range(Some("bytes=9-0")), None);
+    }
+
+    #[test]
+    fn formats_and_escapes() {
+        use super::*;
+
+        assert_eq!(http_date(0), "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(http_date(1_615_730_966), "Sun, 14 Mar 2021 14:09:26 GMT");
+        assert_eq!(percent_encode("/a b/ü"), "/a%20b/%C3%BC");
+        assert_eq!(percent_decode("/a%20b/%C3%BC%zz"), "/a b/ü%zz");
+    }
+}
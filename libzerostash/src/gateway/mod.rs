@@ -0,0 +1,260 @@
+//! A read-only HTTP gateway to a stash.
+//!
+//! The gateway serves the encrypted objects of a backend, so other
+//! machines can open the stash through `backends::Remote` with only
+//! the stash credentials, and never see the credentials of the
+//! backend itself. Byte ranges of objects can be requested with the
+//! `Range` header.
+//!
+//! If the gateway is made from an open stash, it also lists the
+//! snapshots and their files. Anyone who can connect to it can read
+//! these manifests, so only listen on trusted networks in that case.
+//!
+//! * `GET /objects/<id>`: the contents of an object
+//! * `GET /snapshots`: all snapshots
+//! * `GET /snapshots/<id>`: a snapshot with its files
+//!
+//! To browse the files themselves, `WebDav` serves snapshots as a
+//! read-only file system instead. With the `daemon` feature, `Daemon`
+//! also backs up, restores and verifies on request.
+
+use crate::backends::{Backend, BackendError};
+use crate::objects::ObjectId;
+use crate::snapshots::Snapshot;
+use crate::stash::Stash;
+
+use serde_json::json;
+
+use std::io;
+use std::net::TcpListener;
+use std::sync::Arc;
+
+#[cfg(feature = "daemon")]
+mod daemon;
+pub(crate) mod http;
+mod webdav;
+
+#[cfg(feature = "daemon")]
+pub use daemon::Daemon;
+pub use webdav::WebDav;
+
+use http::{Request, Response};
+
+pub struct Gateway {
+    backend: Arc<dyn Backend>,
+    snapshots: Option<Vec<Arc<Snapshot>>>,
+}
+
+impl Gateway {
+    /// Serve only the objects of `backend`.
+    pub fn new(backend: Arc<dyn Backend>) -> Gateway {
+        Gateway {
+            backend,
+            snapshots: None,
+        }
+    }
+
+    /// Serve the objects of `stash`, and the manifests of its
+    /// snapshots as they were read.
+    pub fn for_stash(stash: &Stash) -> Gateway {
+        Gateway {
+            backend: stash.backend(),
+            snapshots: Some(stash.snapshots()),
+        }
+    }
+
+    /// Answer requests on `listener` until it fails, each connection
+    /// in its own thread.
+    pub fn serve(self, listener: TcpListener) -> io::Result<()> {
+        http::serve(listener, move |request| match request.method.as_str() {
+            "GET" | "HEAD" => self.route(request),
+            _ => Response::new(405).header("Allow", "GET, HEAD"),
+        })
+    }
+
+    fn route(&self, request: &Request) -> Response {
+        let path = request.path();
+        let segments = path.trim_matches('/').split('/').collect::<Vec<_>>();
+
+        match segments.as_slice() {
+            ["objects", id] => self.object(id, request.header("Range")),
+            ["snapshots"] => self.list(),
+            ["snapshots", id] => self.manifest(id),
+            _ => Response::new(404),
+        }
+    }
+
+    fn object(&self, id: &str, range: Option<&str>) -> Response {
+        let id = match ObjectId::from_hex(id) {
+            Some(id) => id,
+            None => return Response::new(404),
+        };
+        if let Some(response) = self.partial(&id, range) {
+            return response;
+        }
+
+        let object = match self.backend.read_object(&id) {
+            Ok(object) => object,
+            Err(BackendError::NoObjectFound) => return Response::new(404),
+            Err(e) => {
+                warn!("reading object {}: {}", id.to_string(), e);
+                return Response::new(500);
+            }
+        };
+
+        let data = object.buffer.as_ref();
+        let len = data.len() as u64;
+        match http::byte_range(range, len) {
+            Ok(None) => Response::new(200).body(data.to_vec()),
+            Ok(Some((start, end))) => Response::new(206)
+                .header("Content-Range", format!("bytes {}-{}/{}", start, end, len))
+                .body(data[start as usize..=end as usize].to_vec()),
+            Err(()) => Response::new(416).header("Content-Range", format!("bytes */{}", len)),
+        }
+        .header("Accept-Ranges", "bytes")
+        .header("Content-Type", "application/octet-stream")
+    }
+
+    /// Read only the requested range from backends that can, so a
+    /// restore through the gateway doesn't fetch whole objects either.
+    /// Anything else is read in full, and cut.
+    fn partial(&self, id: &ObjectId, range: Option<&str>) -> Option<Response> {
+        let (start, end) = http::closed_range(range)?;
+        if !self.backend.capabilities().range_reads {
+            return None;
+        }
+
+        let data = match self
+            .backend
+            .read_range(id, start, (end - start + 1) as usize)
+        {
+            Ok(data) => data,
+            Err(BackendError::NoObjectFound) => return Some(Response::new(404)),
+            // past the end, which the whole object answers
+            Err(_) => return None,
+        };
+        Some(
+            Response::new(206)
+                .header("Content-Range", format!("bytes {}-{}/*", start, end))
+                .header("Accept-Ranges", "bytes")
+                .header("Content-Type", "application/octet-stream")
+                .body(data),
+        )
+    }
+
+    fn list(&self) -> Response {
+        let snapshots = match &self.snapshots {
+            Some(snapshots) => snapshots,
+            None => return Response::new(404),
+        };
+
+        Response::json(&json!(snapshots
+            .iter()
+            .map(|s| summary(s))
+            .collect::<Vec<_>>()))
+    }
+
+    fn manifest(&self, id: &str) -> Response {
+        let snapshot = self
+            .snapshots
+            .iter()
+            .flatten()
+            .find(|s| id.parse() == Ok(s.id));
+        match snapshot {
+            Some(snapshot) => Response::json(&contents(snapshot)),
+            None => Response::new(404),
+        }
+    }
+}
+
+/// The summary of `snapshot`, with its files.
+fn contents(snapshot: &Snapshot) -> serde_json::Value {
+    let mut manifest = summary(snapshot);
+    manifest["files"] = json!(snapshot
+        .files
+        .iter()
+        .map(|f| {
+            json!({
+                "name": f.name,
+                "size": f.size,
+                "unix_secs": f.unix_secs,
+                "unix_perm": f.unix_perm,
+                "readonly": f.readonly,
+            })
+        })
+        .collect::<Vec<_>>());
+    manifest
+}
+
+fn summary(snapshot: &Snapshot) -> serde_json::Value {
+    json!({
+        "id": snapshot.id,
+        "unix_secs": snapshot.unix_secs,
+        "paths": snapshot.paths,
+        "tags": snapshot.tags,
+        "labels": snapshot.labels,
+        "files": snapshot.files.len(),
+        "size": snapshot.size(),
+    })
+}
+
+#[cfg(all(test, feature = "fs"))]
+mod tests {
+    #[test]
+    fn stashes_are_readable_through_the_gateway() {
+        use super::*;
+        use crate::backends::{MemoryBackend, Remote};
+        use crate::stash::{BackupOptions, StashKey};
+        use std::thread;
+
+        let backend = Arc::new(MemoryBackend::default());
+        let key = || StashKey::open_stash("gateway", "test").unwrap();
+        let mut stash = Stash::new(backend, key());
+        stash
+            .backup(&["tests/data/100_random_1k"], &BackupOptions::default())
+            .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let gateway = Gateway::for_stash(&stash);
+        thread::spawn(move || gateway.serve(listener));
+
+        let remote = Arc::new(Remote::new(&address));
+        let mut copy = Stash::new(remote.clone(), key());
+        copy.read().unwrap();
+        assert_eq!(copy.snapshots().len(), 1);
+        assert_eq!(
+            copy.verify(&Default::default()).unwrap() as usize,
+            stash.chunk_index().len()
+        );
+
+        let get = |path: &str, range: Option<&str>| {
+            let range = range.map(|r| format!("Range: {}\r\n", r));
+            remote
+                .request(path, range.as_deref().unwrap_or(""))
+                .unwrap()
+        };
+
+        let manifest = get("/snapshots/1", None);
+        let manifest: serde_json::Value = serde_json::from_slice(&manifest.body).unwrap();
+        assert_eq!(manifest["files"].as_array().unwrap().len(), 100);
+        assert_eq!(get("/snapshots/2", None).status, 404);
+
+        let object = stash.snapshots()[0].files[0].chunks[0].1.file.to_string();
+        let partial = get(&format!("/objects/{}", object), Some("bytes=10-19"));
+        assert_eq!(partial.status, 206);
+        assert_eq!(partial.body.len(), 10);
+        assert_eq!(get("/objects/00", None).status, 404);
+
+        // remotes read only the ranges they need
+        let id = stash.snapshots()[0].files[0].chunks[0].1.file;
+        assert!(remote.capabilities().range_reads);
+        assert_eq!(
+            remote.read_range(&id, 10, 10).unwrap(),
+            stash.backend().read_range(&id, 10, 10).unwrap()
+        );
+        let size = stash.object_size() as u64;
+        assert!(remote.read_range(&id, size - 1, 2).is_err());
+        assert_eq!(remote.read_range(&id, size - 1, 1).unwrap().len(), 1);
+    }
+}
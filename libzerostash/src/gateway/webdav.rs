@@ -0,0 +1,307 @@
+use crate::error::Result;
+use crate::files::Entry;
+use crate::gateway::http::{self, Request, Response};
+use crate::snapshots::Snapshot;
+use crate::stash::{ChunkReader, Stash};
+
+use std::collections::BTreeMap;
+use std::io;
+use std::net::TcpListener;
+use std::sync::Arc;
+
+/// Serves snapshots read-only over WebDAV, which file managers on
+/// most systems can mount without extra drivers.
+///
+/// Each snapshot is a directory named after its id. File contents
+/// are decrypted on request, so only the chunks that are read are
+/// fetched from the backend.
+pub struct WebDav {
+    trees: Vec<Tree>,
+    reader: ChunkReader,
+}
+
+struct Tree {
+    snapshot: Arc<Snapshot>,
+    files: BTreeMap<String, Arc<Entry>>,
+}
+
+enum Node<'a> {
+    Root,
+    Dir(&'a Tree, String),
+    File(&'a Tree, &'a Arc<Entry>),
+}
+
+impl WebDav {
+    /// Serve `snapshots` of `stash`.
+    pub fn new(stash: &Stash, snapshots: Vec<Arc<Snapshot>>) -> Result<WebDav> {
+        let trees = snapshots
+            .into_iter()
+            .map(|snapshot| Tree {
+                files: snapshot
+                    .files
+                    .iter()
+                    .map(|f| (f.name.trim_start_matches('/').to_string(), f.clone()))
+                    .collect(),
+                snapshot,
+            })
+            .collect();
+
+        Ok(WebDav {
+            trees,
+            reader: stash.chunk_reader()?,
+        })
+    }
+
+    /// Answer requests on `listener` until it fails, each connection
+    /// in its own thread.
+    pub fn serve(self, listener: TcpListener) -> io::Result<()> {
+        http::serve(listener, move |request| self.respond(request))
+    }
+
+    fn respond(&self, request: &Request) -> Response {
+        let path = request.path();
+        let node = match request.method.as_str() {
+            "OPTIONS" => {
+                return Response::new(200)
+                    .header("DAV", "1")
+                    .header("Allow", "OPTIONS, GET, HEAD, PROPFIND")
+            }
+            "GET" | "HEAD" | "PROPFIND" => match self.resolve(&path) {
+                Some(node) => node,
+                None => return Response::new(404),
+            },
+            _ => return Response::new(405).header("Allow", "OPTIONS, GET, HEAD, PROPFIND"),
+        };
+
+        match (request.method.as_str(), node) {
+            ("PROPFIND", node) => {
+                let depth = request.header("Depth").unwrap_or("1");
+                self.propfind(node, depth != "0")
+            }
+            (_, Node::File(_, entry)) => self.get(entry, request.header("Range")),
+            (_, node) => {
+                let listing = self
+                    .children(&node)
+                    .iter()
+                    .map(|child| format!("{}\n", self.name(child)))
+                    .collect::<String>();
+                Response::new(200)
+                    .header("Content-Type", "text/plain; charset=utf-8")
+                    .body(listing.into_bytes())
+            }
+        }
+    }
+
+    fn resolve(&self, path: &str) -> Option<Node<'_>> {
+        let path = path.trim_matches('/');
+        if path.is_empty() {
+            return Some(Node::Root);
+        }
+
+        let (id, rest) = path.split_once('/').unwrap_or((path, ""));
+        let tree = self
+            .trees
+            .iter()
+            .find(|t| id.parse() == Ok(t.snapshot.id))?;
+
+        if rest.is_empty() {
+            return Some(Node::Dir(tree, String::new()));
+        }
+        if let Some(entry) = tree.files.get(rest) {
+            return Some(Node::File(tree, entry));
+        }
+
+        let prefix = format!("{}/", rest);
+        tree.files
+            .range(prefix.clone()..)
+            .next()
+            .filter(|(name, _)| name.starts_with(&prefix))
+            .map(|_| Node::Dir(tree, prefix))
+    }
+
+    fn children<'a>(&'a self, node: &Node<'a>) -> Vec<Node<'a>> {
+        let (tree, prefix) = match node {
+            Node::Root => {
+                return self
+                    .trees
+                    .iter()
+                    .map(|t| Node::Dir(t, String::new()))
+                    .collect()
+            }
+            Node::Dir(tree, prefix) => (tree, prefix),
+            Node::File(..) => return vec![],
+        };
+
+        let mut children = BTreeMap::new();
+        for (name, entry) in tree
+            .files
+            .range(prefix.clone()..)
+            .take_while(|(name, _)| name.starts_with(prefix.as_str()))
+        {
+            let child = match name[prefix.len()..].split_once('/') {
+                Some((dir, _)) => Node::Dir(tree, format!("{}{}/", prefix, dir)),
+                None => Node::File(tree, entry),
+            };
+            children.entry(self.href(&child)).or_insert(child);
+        }
+
+        children.into_values().collect()
+    }
+
+    fn href(&self, node: &Node<'_>) -> String {
+        match node {
+            Node::Root => "/".into(),
+            Node::Dir(tree, prefix) => {
+                http::percent_encode(&format!("/{}/{}", tree.snapshot.id, prefix))
+            }
+            Node::File(tree, entry) => http::percent_encode(&format!(
+                "/{}/{}",
+                tree.snapshot.id,
+                entry.name.trim_start_matches('/')
+            )),
+        }
+    }
+
+    fn name(&self, node: &Node<'_>) -> String {
+        match node {
+            Node::Root => String::new(),
+            Node::Dir(tree, prefix) if prefix.is_empty() => tree.snapshot.id.to_string(),
+            Node::Dir(_, prefix) => prefix
+                .trim_end_matches('/')
+                .rsplit('/')
+                .next()
+                .unwrap_or_default()
+                .into(),
+            Node::File(_, entry) => entry.name.rsplit('/').next().unwrap_or_default().into(),
+        }
+    }
+
+    fn propfind(&self, node: Node<'_>, with_children: bool) -> Response {
+        let mut xml = String::from(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">\n",
+        );
+
+        let children = match with_children {
+            true => self.children(&node),
+            false => vec![],
+        };
+        for node in std::iter::once(node).chain(children) {
+            let (kind, size, unix_secs) = match &node {
+                Node::Root => ("<D:collection/>", None, 0),
+                Node::Dir(tree, _) => ("<D:collection/>", None, tree.snapshot.unix_secs),
+                Node::File(_, entry) => ("", Some(entry.size), entry.unix_secs),
+            };
+
+            xml.push_str(&format!(
+                "<D:response><D:href>{}</D:href><D:propstat><D:prop>\
+                 <D:displayname>{}</D:displayname>\
+                 <D:resourcetype>{}</D:resourcetype>",
+                escape(&self.href(&node)),
+                escape(&self.name(&node)),
+                kind
+            ));
+            if let Some(size) = size {
+                xml.push_str(&format!(
+                    "<D:getcontentlength>{}</D:getcontentlength>",
+                    size
+                ));
+            }
+            xml.push_str(&format!(
+                "<D:getlastmodified>{}</D:getlastmodified>\
+                 </D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>\n",
+                http::http_date(unix_secs)
+            ));
+        }
+        xml.push_str("</D:multistatus>\n");
+
+        Response::new(207)
+            .header("Content-Type", "application/xml; charset=utf-8")
+            .body(xml.into_bytes())
+    }
+
+    fn get(&self, entry: &Arc<Entry>, range: Option<&str>) -> Response {
+        let (status, start, end) = match http::byte_range(range, entry.size) {
+            Ok(Some((start, end))) => (206, start, end),
+            Ok(None) if entry.size == 0 => return Response::new(200),
+            Ok(None) => (200, 0, entry.size - 1),
+            Err(()) => {
+                return Response::new(416)
+                    .header("Content-Range", format!("bytes */{}", entry.size))
+            }
+        };
+
+        let mut response = Response::new(status)
+            .header("Accept-Ranges", "bytes")
+            .header("Content-Type", "application/octet-stream")
+            .header("Last-Modified", http::http_date(entry.unix_secs));
+        if status == 206 {
+            response = response.header(
+                "Content-Range",
+                format!("bytes {}-{}/{}", start, end, entry.size),
+            );
+        }
+
+        let reader = self.reader.clone();
+        let entry = entry.clone();
+        response.stream(
+            end - start + 1,
+            Box::new(move |out| reader.copy(&entry, start, end, out)),
+        )
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(all(test, feature = "fs"))]
+mod tests {
+    #[test]
+    fn snapshots_are_browsable_over_webdav() {
+        use super::*;
+        use crate::backends::{MemoryBackend, Remote};
+        use crate::stash::{BackupOptions, StashKey};
+        use std::fs;
+        use std::thread;
+
+        let backend = Arc::new(MemoryBackend::default());
+        let key = StashKey::open_stash("webdav", "test").unwrap();
+        let mut stash = Stash::new(backend, key);
+        stash
+            .backup(&["tests/data/10k_random_blob"], &BackupOptions::default())
+            .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = Remote::new(listener.local_addr().unwrap().to_string());
+        let webdav = WebDav::new(&stash, stash.snapshots()).unwrap();
+        thread::spawn(move || webdav.serve(listener));
+
+        let root = client
+            .method("PROPFIND", "/1/tests/", "Depth: 1\r\n")
+            .unwrap();
+        assert_eq!(root.status, 207);
+        let xml = String::from_utf8(root.body).unwrap();
+        assert!(xml.contains("<D:href>/1/tests/data/</D:href>"));
+        assert!(xml.contains("<D:collection/>"));
+
+        let contents = fs::read("tests/data/10k_random_blob").unwrap();
+        let file = client.request("/1/tests/data/10k_random_blob", "").unwrap();
+        assert_eq!(file.status, 200);
+        assert!(file.body == contents);
+
+        let part = client
+            .request(
+                "/1/tests/data/10k_random_blob",
+                "Range: bytes=5000-5099\r\n",
+            )
+            .unwrap();
+        assert_eq!(part.status, 206);
+        assert!(part.body[..] == contents[5000..5100]);
+
+        assert_eq!(client.method("PUT", "/1/new", "").unwrap().status, 405);
+        assert_eq!(client.request("/2/", "").unwrap().status, 404);
+    }
+}
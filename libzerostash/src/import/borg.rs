@@ -0,0 +1,303 @@
+//! Importing archives from Borg repositories.
+//!
+//! Borg's repository format isn't stable, so the `borg` program is
+//! driven instead: archives are listed with `borg list --json`, and
+//! streamed with `borg export-tar` straight into the stash, without
+//! extracting them to disk first.
+//!
+//! ```no_run
+//! use libzerostash::import::borg::Borg;
+//! # fn import(stash: &mut libzerostash::Stash) -> Result<(), Box<dyn std::error::Error>> {
+//!
+//! let borg = Borg::new("/mnt/borg").passphrase("password");
+//! let archives = borg.archives()?;
+//! borg.import(stash, &archives)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::error::ZerostashError;
+use crate::import::tarball::{TarError, Tarball};
+use crate::import::{imported, parse_time};
+use crate::snapshots::Snapshot;
+use crate::stash::Stash;
+
+use secrecy::{ExposeSecret, SecretString};
+use thiserror::Error;
+
+use std::io;
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+
+#[derive(Error, Debug)]
+pub enum BorgError {
+    #[error("IO error: {source}")]
+    Io {
+        #[from]
+        source: io::Error,
+    },
+    #[error("Invalid JSON: {source}")]
+    Json {
+        #[from]
+        source: serde_json::Error,
+    },
+    #[error("borg failed: {0}")]
+    Command(String),
+    #[error("Invalid archive: {0}")]
+    Invalid(String),
+    #[error("Stash error: {source}")]
+    Stash {
+        #[from]
+        source: ZerostashError,
+    },
+    #[error("Invalid archive: {source}")]
+    Tar {
+        #[from]
+        source: TarError,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, BorgError>;
+
+/// The label imported snapshots keep the name of their archive in
+const BORG_ARCHIVE: &str = "borg_archive";
+
+#[derive(Deserialize)]
+struct ArchiveList {
+    archives: Vec<ArchiveItem>,
+}
+
+#[derive(Deserialize)]
+struct ArchiveItem {
+    name: String,
+    #[serde(default)]
+    start: Option<String>,
+    #[serde(default)]
+    time: Option<String>,
+}
+
+/// An archive in a Borg repository
+#[derive(Clone, Debug)]
+pub struct BorgArchive {
+    pub name: String,
+    pub unix_secs: u64,
+}
+
+pub struct Borg {
+    program: String,
+    repository: String,
+    passphrase: Option<SecretString>,
+}
+
+impl Borg {
+    pub fn new(repository: impl Into<String>) -> Borg {
+        Borg {
+            program: "borg".into(),
+            repository: repository.into(),
+            passphrase: None,
+        }
+    }
+
+    /// The `borg` executable to run.
+    pub fn program(mut self, program: impl Into<String>) -> Borg {
+        self.program = program.into();
+        self
+    }
+
+    /// Passphrase of the repository, if it's encrypted.
+    pub fn passphrase(mut self, passphrase: impl Into<String>) -> Borg {
+        self.passphrase = Some(SecretString::new(passphrase.into()));
+        self
+    }
+
+    fn command(&self) -> Command {
+        let mut command = Command::new(&self.program);
+
+        // borg prints local times without an offset
+        command.env("TZ", "UTC");
+        if let Some(p) = &self.passphrase {
+            command.env("BORG_PASSPHRASE", p.expose_secret());
+        }
+
+        command
+    }
+
+    /// All archives of the repository, oldest first.
+    pub fn archives(&self) -> Result<Vec<BorgArchive>> {
+        let output = self
+            .command()
+            .args(["list", "--json", &self.repository])
+            .stderr(Stdio::inherit())
+            .output()?;
+        if !output.status.success() {
+            return Err(BorgError::Command(format!("list {}", output.status)));
+        }
+
+        let list: ArchiveList = serde_json::from_slice(&output.stdout)?;
+        let mut archives = list
+            .archives
+            .into_iter()
+            .map(|a| {
+                let time = a.start.or(a.time).unwrap_or_default();
+                let (unix_secs, _) = parse_time(&time)
+                    .ok_or_else(|| BorgError::Invalid(format!("bad timestamp: {}", time)))?;
+
+                Ok(BorgArchive {
+                    name: a.name,
+                    unix_secs,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        archives.sort_by_key(|a| a.unix_secs);
+        Ok(archives)
+    }
+
+    /// Import `archives` into `stash` as snapshots taken at the start
+    /// of the archive, tagged with the archive name, which is also
+    /// kept as the `borg_archive` label. Each snapshot is committed as
+    /// soon as it's imported, and archives imported before are
+    /// skipped. Returns the new snapshots.
+    ///
+    /// Members are imported like `Tarball::import` does.
+    pub fn import(
+        &self,
+        stash: &mut Stash,
+        archives: &[BorgArchive],
+    ) -> Result<Vec<Arc<Snapshot>>> {
+        let done = imported(stash, BORG_ARCHIVE)?;
+        let mut imported = vec![];
+
+        for archive in archives.iter() {
+            if done.contains(&archive.name) {
+                info!("skipping borg archive {}, imported already", archive.name);
+                continue;
+            }
+            debug!("importing borg archive {}", archive.name);
+            let mut child = self
+                .command()
+                .arg("export-tar")
+                .arg(format!("{}::{}", self.repository, archive.name))
+                .arg("-")
+                .stdout(Stdio::piped())
+                .spawn()?;
+
+            let stdout = child.stdout.take().unwrap();
+            let result = Tarball::new(archive.unix_secs)
+                .tag(archive.name.clone())
+                .label(BORG_ARCHIVE, archive.name.clone())
+                .import(stash, stdout);
+
+            // make sure borg exits even if the stream wasn't read to
+            // the end
+            let status = match result {
+                Ok(_) => child.wait()?,
+                Err(_) => {
+                    let _ = child.kill();
+                    child.wait()?
+                }
+            };
+            let snapshot = result?;
+            if !status.success() {
+                return Err(BorgError::Command(format!("export-tar {}", status)));
+            }
+
+            imported.push(snapshot);
+        }
+
+        Ok(imported)
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    #[test]
+    fn imports_archives_through_borg() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use crate::stash::StashKey;
+        use std::fs;
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join("0s_test_borg");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let contents = (0..70_000u32).map(|i| (i % 17) as u8).collect::<Vec<_>>();
+        let mut tar = tar::Builder::new(vec![]);
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o600);
+        header.set_uid(1000);
+        header.set_gid(100);
+        header.set_mtime(1_615_730_966);
+        tar.append_data(&mut header, "home/user/file", &contents[..])
+            .unwrap();
+
+        let mut link = tar::Header::new_gnu();
+        link.set_entry_type(tar::EntryType::Link);
+        link.set_mode(0o600);
+        link.set_size(0);
+        link.set_uid(1000);
+        link.set_gid(100);
+        link.set_mtime(1_615_730_966);
+        tar.append_link(&mut link, "home/user/link", "home/user/file")
+            .unwrap();
+        fs::write(dir.join("archive.tar"), tar.into_inner().unwrap()).unwrap();
+
+        fs::write(
+            dir.join("list.json"),
+            r#"{"archives": [{"name": "monday", "start": "2021-03-14T14:09:26.000000"}]}"#,
+        )
+        .unwrap();
+
+        // stands in for borg, and checks the passphrase is passed on
+        let program = dir.join("borg");
+        fs::write(
+            &program,
+            format!(
+                "#!/bin/sh\n[ \"$BORG_PASSPHRASE\" = secret ] || exit 2\n\
+                 case $1 in list) cat {0}/list.json ;; export-tar) cat {0}/archive.tar ;; esac\n",
+                dir.display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&program, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let borg = Borg::new("/mnt/borg")
+            .program(program.to_string_lossy())
+            .passphrase("secret");
+        let archives = borg.archives().unwrap();
+        assert_eq!(archives.len(), 1);
+        assert_eq!(archives[0].unix_secs, 1_615_730_966);
+
+        let backend = Arc::new(MemoryBackend::default());
+        let key = || StashKey::open_stash("borg", "test").unwrap();
+        let mut stash = Stash::new(backend.clone(), key());
+        borg.import(&mut stash, &archives).unwrap();
+
+        let mut stash = Stash::new(backend, key());
+        stash.read().unwrap();
+        assert!(borg.import(&mut stash, &archives).unwrap().is_empty());
+        assert_eq!(stash.snapshots().len(), 1);
+        let snapshot = &stash.snapshots()[0];
+        assert_eq!(snapshot.paths, vec!["/home"]);
+        assert_eq!(snapshot.tags, vec!["monday"]);
+        assert_eq!(snapshot.unix_secs, 1_615_730_966);
+
+        let mut files = snapshot.files.clone();
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+        assert_eq!(files[0].name, "/home/user/file");
+        assert_eq!(files[0].size, contents.len() as u64);
+        assert_eq!(
+            (files[0].unix_uid, files[0].unix_perm & 0o777),
+            (1000, 0o600)
+        );
+        assert_eq!(files[1].name, "/home/user/link");
+        assert!(files[1].chunks == files[0].chunks);
+
+        let locked = Borg::new("/mnt/borg").program(program.to_string_lossy());
+        assert!(locked.archives().is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
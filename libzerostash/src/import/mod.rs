@@ -0,0 +1,92 @@
+//! Migrating snapshots from other backup tools into a stash.
+
+#[cfg(feature = "borg")]
+pub mod borg;
+#[cfg(feature = "restic")]
+pub mod restic;
+#[cfg(feature = "tarball")]
+pub mod tarball;
+
+/// The values of `label` on the snapshots of `stash`, which importers
+/// label what they imported with, so importing again skips those.
+#[cfg(any(feature = "restic", feature = "borg"))]
+pub(crate) fn imported(
+    stash: &mut crate::Stash,
+    label: &str,
+) -> crate::error::Result<std::collections::HashSet<String>> {
+    stash.load(crate::meta::Field::Snapshots)?;
+    Ok(stash
+        .snapshots()
+        .iter()
+        .filter_map(|s| s.labels.get(label).cloned())
+        .collect())
+}
+
+/// Parse an RFC 3339 timestamp, like `2021-03-14T15:09:26.53+01:00`,
+/// into seconds and nanoseconds since the Unix epoch.
+///
+/// Timestamps without an offset are taken to be in UTC.
+#[cfg(any(feature = "restic", feature = "borg"))]
+pub(crate) fn parse_time(s: &str) -> Option<(u64, u32)> {
+    let num = |range: std::ops::Range<usize>| -> Option<i64> { s.get(range)?.parse().ok() };
+
+    let (year, month, day) = (num(0..4)?, num(5..7)?, num(8..10)?);
+    let (hour, min, sec) = (num(11..13)?, num(14..16)?, num(17..19)?);
+    if s.get(10..11)? != "T" && s.get(10..11)? != "t" {
+        return None;
+    }
+
+    let mut rest = s.get(19..)?;
+    let mut nanos = 0;
+    if let Some(fraction) = rest.strip_prefix('.') {
+        let digits = fraction
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(fraction.len());
+        for (i, c) in fraction[..digits].chars().take(9).enumerate() {
+            nanos += c.to_digit(10)? * 10u32.pow(8 - i as u32);
+        }
+        rest = &fraction[digits..];
+    }
+
+    let offset = match rest {
+        "" | "Z" | "z" => 0,
+        _ if rest.len() == 6 => {
+            let sign = match rest.get(..1)? {
+                "+" => 1,
+                "-" => -1,
+                _ => return None,
+            };
+            let hours: i64 = rest.get(1..3)?.parse().ok()?;
+            let mins: i64 = rest.get(4..6)?.parse().ok()?;
+            sign * (hours * 3600 + mins * 60)
+        }
+        _ => return None,
+    };
+
+    let days = crate::time::days_from_civil(year, month, day);
+    let secs = days * 86400 + hour * 3600 + min * 60 + sec - offset;
+    Some((secs.max(0) as u64, nanos))
+}
+
+#[cfg(all(test, any(feature = "restic", feature = "borg")))]
+mod tests {
+    #[test]
+    fn parses_timestamps() {
+        use super::parse_time;
+
+        assert_eq!(parse_time("1970-01-01T00:00:00Z"), Some((0, 0)));
+        assert_eq!(
+            parse_time("2021-03-14T15:09:26.5+01:00"),
+            Some((1_615_730_966, 500_000_000))
+        );
+        assert_eq!(
+            parse_time("2000-02-29T23:59:59.123456789123-00:30"),
+            Some((951_868_799 + 1800, 123_456_789))
+        );
+        assert_eq!(
+            parse_time("2021-03-14T14:09:26.000000"),
+            Some((1_615_730_966, 0))
+        );
+        assert_eq!(parse_time("2000-02-29 23:59:59"), None);
+    }
+}
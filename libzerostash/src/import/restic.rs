@@ -0,0 +1,782 @@
+//! Importing snapshots from local restic repositories.
+//!
+//! File contents are read blob by blob from the packs of the restic
+//! repository, and stored in the stash directly, so migrating doesn't
+//! need space for a restore. Both version 1 and version 2
+//! (compressed) repositories are supported.
+//!
+//! ```no_run
+//! use libzerostash::import::restic::Repository;
+//! # fn import(stash: &mut libzerostash::Stash) -> Result<(), Box<dyn std::error::Error>> {
+//!
+//! let repo = Repository::open("/mnt/restic", "password")?;
+//! let snapshots = repo.snapshots()?;
+//! repo.import(stash, &snapshots)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::chunks::ChunkPointer;
+use crate::error::ZerostashError;
+use crate::files::Entry;
+use crate::import::{imported, parse_time};
+use crate::snapshots::{Labels, Snapshot};
+use crate::stash::{Ingest, Stash};
+
+use aes::cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit, KeyIvInit, StreamCipher};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use poly1305::Poly1305;
+use serde::de::DeserializeOwned;
+use serde_bytes::ByteBuf;
+use thiserror::Error;
+
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryInto;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+type Aes256Ctr = ctr::Ctr128BE<aes::Aes256>;
+
+const IV_SIZE: usize = 16;
+const MAC_SIZE: usize = 16;
+
+// the high bits of Go's `os.FileMode`
+const GO_MODE_TYPE: u32 = 0xfff0_0000;
+const S_IFREG: u32 = 0o100_000;
+const S_IFLNK: u32 = 0o120_000;
+const S_IFIFO: u32 = 0o010_000;
+const S_IFCHR: u32 = 0o020_000;
+const S_IFBLK: u32 = 0o060_000;
+const S_IFSOCK: u32 = 0o140_000;
+
+/// The label imported snapshots keep their id in
+const RESTIC_ID: &str = "restic_id";
+
+// size and chunks of the files with a given restic content list
+type Seen = HashMap<Vec<String>, (u64, Vec<(u64, Arc<ChunkPointer>)>)>;
+
+#[derive(Error, Debug)]
+pub enum ResticError {
+    #[error("IO error: {source}")]
+    Io {
+        #[from]
+        source: io::Error,
+    },
+    #[error("Invalid JSON: {source}")]
+    Json {
+        #[from]
+        source: serde_json::Error,
+    },
+    #[error("No key of the repository matches the password")]
+    WrongPassword,
+    #[error("Authentication of {0} failed")]
+    Corrupt(String),
+    #[error("Unsupported repository version: {0}")]
+    Version(u32),
+    #[error("Blob {0} is missing from the index")]
+    MissingBlob(String),
+    #[error("Invalid repository: {0}")]
+    Invalid(String),
+    #[error("Stash error: {source}")]
+    Stash {
+        #[from]
+        source: ZerostashError,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, ResticError>;
+
+#[derive(Deserialize)]
+struct KeyFile {
+    kdf: String,
+    #[serde(rename = "N")]
+    n: u64,
+    r: u32,
+    p: u32,
+    salt: String,
+    data: String,
+}
+
+#[derive(Deserialize)]
+struct MasterKeyFile {
+    mac: MacKeyFile,
+    encrypt: String,
+}
+
+#[derive(Deserialize)]
+struct MacKeyFile {
+    k: String,
+    r: String,
+}
+
+#[derive(Deserialize)]
+struct Config {
+    version: u32,
+}
+
+#[derive(Deserialize)]
+struct SnapshotFile {
+    time: String,
+    tree: String,
+    paths: Vec<String>,
+    #[serde(default)]
+    hostname: Option<String>,
+    #[serde(default)]
+    tags: Option<Vec<String>>,
+}
+
+#[derive(Deserialize)]
+struct IndexFile {
+    packs: Vec<PackFile>,
+}
+
+#[derive(Deserialize)]
+struct PackFile {
+    id: String,
+    blobs: Vec<BlobFile>,
+}
+
+#[derive(Deserialize)]
+struct BlobFile {
+    id: String,
+    offset: u64,
+    length: u64,
+    #[serde(default)]
+    uncompressed_length: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct Tree {
+    nodes: Vec<Node>,
+}
+
+#[derive(Deserialize)]
+struct Node {
+    name: String,
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    mode: u32,
+    mtime: String,
+    #[serde(default)]
+    uid: u32,
+    #[serde(default)]
+    gid: u32,
+    #[serde(default)]
+    user: Option<String>,
+    #[serde(default)]
+    group: Option<String>,
+    #[serde(default)]
+    content: Option<Vec<String>>,
+    #[serde(default)]
+    subtree: Option<String>,
+    #[serde(default)]
+    linktarget: Option<String>,
+    #[serde(default)]
+    extended_attributes: Option<Vec<ExtendedAttribute>>,
+    /// The device number of devices, as Linux encodes them
+    #[serde(default)]
+    device: u64,
+}
+
+#[derive(Deserialize)]
+struct ExtendedAttribute {
+    name: String,
+    /// In base64, as Go encodes bytes in JSON
+    value: String,
+}
+
+impl Node {
+    /// The entry of a node of type `kind`, without any contents.
+    fn entry(&self, kind: u32, name: String) -> Result<Entry> {
+        let (unix_secs, unix_nanos) =
+            parse_time(&self.mtime).ok_or_else(|| bad_time(&self.mtime))?;
+
+        Ok(Entry {
+            unix_secs,
+            unix_nanos,
+            unix_perm: kind | (self.mode & !GO_MODE_TYPE & 0o7777),
+            unix_uid: self.uid,
+            unix_gid: self.gid,
+            unix_user: self.user.clone(),
+            unix_group: self.group.clone(),
+            size: 0,
+            readonly: self.mode & 0o200 == 0,
+            name,
+            symlink: None,
+            hardlink: None,
+            xattrs: self.xattrs()?,
+            device: None,
+            windows_attributes: None,
+            raw_name: None,
+            chunks: vec![],
+        })
+    }
+
+    fn xattrs(&self) -> Result<Option<BTreeMap<String, ByteBuf>>> {
+        let attrs = match &self.extended_attributes {
+            Some(attrs) if !attrs.is_empty() => attrs,
+            _ => return Ok(None),
+        };
+
+        attrs
+            .iter()
+            .map(|a| {
+                let value = BASE64
+                    .decode(&a.value)
+                    .map_err(|_| ResticError::Invalid(format!("bad attribute {}", a.name)))?;
+                Ok((a.name.clone(), ByteBuf::from(value)))
+            })
+            .collect::<Result<_>>()
+            .map(Some)
+    }
+}
+
+/// The major and minor numbers of a device number of Linux.
+fn linux_device(device: u64) -> (u32, u32) {
+    let major = ((device >> 8) & 0xfff) | ((device >> 32) & !0xfff);
+    let minor = (device & 0xff) | ((device >> 12) & !0xff);
+    (major as u32, minor as u32)
+}
+
+struct Key {
+    encrypt: [u8; 32],
+    mac_k: [u8; 16],
+    mac_r: [u8; 16],
+}
+
+impl Key {
+    fn from_bytes(encrypt: &[u8], mac_k: &[u8], mac_r: &[u8]) -> Result<Key> {
+        let invalid = |_| ResticError::Invalid("bad key length".into());
+        Ok(Key {
+            encrypt: encrypt.try_into().map_err(invalid)?,
+            mac_k: mac_k.try_into().map_err(invalid)?,
+            mac_r: mac_r.try_into().map_err(invalid)?,
+        })
+    }
+
+    /// Poly1305-AES of `data` with `iv` as the nonce
+    fn mac(&self, iv: &[u8], data: &[u8]) -> [u8; MAC_SIZE] {
+        let mut s = GenericArray::clone_from_slice(iv);
+        aes::Aes128::new(GenericArray::from_slice(&self.mac_k)).encrypt_block(&mut s);
+
+        let mut key = [0; 32];
+        key[..16].copy_from_slice(&self.mac_r);
+        key[16..].copy_from_slice(&s);
+
+        Poly1305::new(GenericArray::from_slice(&key))
+            .compute_unpadded(data)
+            .into()
+    }
+
+    /// Authenticate and decrypt `IV || ciphertext || MAC`.
+    fn open(&self, what: &str, data: &[u8]) -> Result<Vec<u8>> {
+        if data.len() < IV_SIZE + MAC_SIZE {
+            return Err(ResticError::Corrupt(what.into()));
+        }
+
+        let (iv, rest) = data.split_at(IV_SIZE);
+        let (ciphertext, mac) = rest.split_at(rest.len() - MAC_SIZE);
+        ring::constant_time::verify_slices_are_equal(&self.mac(iv, ciphertext), mac)
+            .map_err(|_| ResticError::Corrupt(what.into()))?;
+
+        let mut plaintext = ciphertext.to_vec();
+        Aes256Ctr::new(
+            GenericArray::from_slice(&self.encrypt),
+            GenericArray::from_slice(iv),
+        )
+        .apply_keystream(&mut plaintext);
+
+        Ok(plaintext)
+    }
+}
+
+struct BlobLocation {
+    pack: String,
+    offset: u64,
+    length: u64,
+    compressed: bool,
+}
+
+/// A snapshot in a restic repository
+#[derive(Clone, Debug)]
+pub struct ResticSnapshot {
+    pub id: String,
+    pub unix_secs: u64,
+    pub paths: Vec<String>,
+    pub hostname: Option<String>,
+    pub tags: Vec<String>,
+    tree: String,
+}
+
+pub struct Repository {
+    path: PathBuf,
+    key: Key,
+    version: u32,
+    index: HashMap<String, BlobLocation>,
+}
+
+impl Repository {
+    /// Open the repository at `path`, and load its index.
+    pub fn open(path: impl AsRef<Path>, password: impl AsRef<str>) -> Result<Repository> {
+        let path = path.as_ref().to_path_buf();
+        let key = find_key(&path, password.as_ref())?;
+
+        let config: Config = serde_json::from_slice(&key.open("config", &read(&path, "config")?)?)?;
+        if config.version != 1 && config.version != 2 {
+            return Err(ResticError::Version(config.version));
+        }
+
+        let mut repo = Repository {
+            path,
+            key,
+            version: config.version,
+            index: HashMap::new(),
+        };
+
+        for (id, _) in repo.list("index")? {
+            let index: IndexFile = repo.load_json("index", &id)?;
+            for pack in index.packs {
+                for blob in pack.blobs {
+                    repo.index.insert(
+                        blob.id,
+                        BlobLocation {
+                            pack: pack.id.clone(),
+                            offset: blob.offset,
+                            length: blob.length,
+                            compressed: blob.uncompressed_length.is_some(),
+                        },
+                    );
+                }
+            }
+        }
+        debug!("loaded {} blobs from the restic index", repo.index.len());
+
+        Ok(repo)
+    }
+
+    /// All snapshots of the repository, oldest first.
+    pub fn snapshots(&self) -> Result<Vec<ResticSnapshot>> {
+        let mut snapshots = vec![];
+        for (id, _) in self.list("snapshots")? {
+            let s: SnapshotFile = self.load_json("snapshots", &id)?;
+            snapshots.push(ResticSnapshot {
+                unix_secs: parse_time(&s.time).ok_or_else(|| bad_time(&s.time))?.0,
+                paths: s.paths,
+                hostname: s.hostname,
+                tags: s.tags.unwrap_or_default(),
+                tree: s.tree,
+                id,
+            });
+        }
+
+        snapshots.sort_by_key(|s| s.unix_secs);
+        Ok(snapshots)
+    }
+
+    /// Import `snapshots` into `stash`, keeping their time, paths and
+    /// tags, their host as the `hostname` label, and their id as the
+    /// `restic_id` label. Each snapshot is committed as soon as it's
+    /// imported, and those imported before are skipped, so an
+    /// interrupted import can be run again. Returns the new ones.
+    ///
+    /// Files, links and special files are imported, other kinds of
+    /// nodes are skipped.
+    pub fn import(
+        &self,
+        stash: &mut Stash,
+        snapshots: &[ResticSnapshot],
+    ) -> Result<Vec<Arc<Snapshot>>> {
+        // files are mostly unchanged between snapshots, so remember
+        // which chunks a content list ended up as
+        let mut seen = HashMap::new();
+        let done = imported(stash, RESTIC_ID)?;
+        let mut imported = vec![];
+
+        for snapshot in snapshots.iter() {
+            if done.contains(&snapshot.id) {
+                info!("skipping restic snapshot {}, imported already", snapshot.id);
+                continue;
+            }
+            debug!("importing restic snapshot {}", snapshot.id);
+            let mut ingest = stash.ingest()?;
+            self.import_tree(&mut ingest, &mut seen, &snapshot.tree, "")?;
+
+            let mut labels = Labels::new();
+            if let Some(host) = &snapshot.hostname {
+                labels.insert("hostname".into(), host.clone());
+            }
+            labels.insert(RESTIC_ID.into(), snapshot.id.clone());
+            imported.push(ingest.finish(
+                snapshot.unix_secs,
+                snapshot.paths.clone(),
+                snapshot.tags.clone(),
+                labels,
+            )?);
+        }
+
+        Ok(imported)
+    }
+
+    fn import_tree(
+        &self,
+        ingest: &mut Ingest<'_>,
+        seen: &mut Seen,
+        tree: &str,
+        prefix: &str,
+    ) -> Result<()> {
+        let tree: Tree = serde_json::from_slice(&self.load_blob(tree)?)?;
+
+        for node in tree.nodes {
+            let name = format!("{}/{}", prefix, node.name);
+            match (node.kind.as_str(), &node.subtree, &node.content) {
+                ("dir", Some(subtree), _) => self.import_tree(ingest, seen, subtree, &name)?,
+                ("file", _, content) => {
+                    let content = content.clone().unwrap_or_default();
+                    let mut entry = node.entry(S_IFREG, name)?;
+
+                    match seen.get(&content) {
+                        Some((size, chunks)) => {
+                            entry.size = *size;
+                            entry.chunks = chunks.clone();
+                            ingest.add_entry(Arc::new(entry));
+                        }
+                        None => {
+                            let mut blobs = Blobs {
+                                repo: self,
+                                ids: content.iter(),
+                                current: io::Cursor::new(vec![]),
+                            };
+                            let entry = ingest.add_stream(entry, &mut blobs)?;
+                            seen.insert(content, (entry.size, entry.chunks.clone()));
+                        }
+                    }
+                }
+                ("symlink", _, _) => ingest.add_entry(Arc::new(Entry {
+                    symlink: node.linktarget.clone(),
+                    ..node.entry(S_IFLNK, name)?
+                })),
+                ("fifo", _, _) => ingest.add_entry(Arc::new(node.entry(S_IFIFO, name)?)),
+                ("socket", _, _) => ingest.add_entry(Arc::new(node.entry(S_IFSOCK, name)?)),
+                (kind @ ("chardev" | "dev"), _, _) => ingest.add_entry(Arc::new(Entry {
+                    device: Some(linux_device(node.device)),
+                    ..node.entry(if kind == "dev" { S_IFBLK } else { S_IFCHR }, name)?
+                })),
+                (kind, _, _) => warn!("skipping {}: unsupported node type {}", name, kind),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn load_blob(&self, id: &str) -> Result<Vec<u8>> {
+        let location = self
+            .index
+            .get(id)
+            .ok_or_else(|| ResticError::MissingBlob(id.into()))?;
+
+        let path = self
+            .path
+            .join("data")
+            .join(location.pack.get(..2).unwrap_or_default())
+            .join(&location.pack);
+        let mut pack = fs::File::open(path)?;
+        let mut data = vec![0; location.length as usize];
+        pack.seek(SeekFrom::Start(location.offset))?;
+        pack.read_exact(&mut data)?;
+
+        let plaintext = self.key.open(id, &data)?;
+        match location.compressed {
+            true => Ok(zstd::stream::decode_all(&plaintext[..])?),
+            false => Ok(plaintext),
+        }
+    }
+
+    fn load_json<T: DeserializeOwned>(&self, kind: &str, id: &str) -> Result<T> {
+        let plaintext = self
+            .key
+            .open(id, &read(&self.path, Path::new(kind).join(id))?)?;
+
+        // version 2 prefixes compressed JSON with a version byte, but
+        // plain JSON can still be found in them
+        let json = match plaintext.first() {
+            Some(2) if self.version >= 2 => zstd::stream::decode_all(&plaintext[1..])?,
+            _ => plaintext,
+        };
+
+        Ok(serde_json::from_slice(&json)?)
+    }
+
+    fn list(&self, kind: &str) -> Result<Vec<(String, PathBuf)>> {
+        list(&self.path, kind)
+    }
+}
+
+/// The contents of a file, read a blob at a time.
+struct Blobs<'a, I> {
+    repo: &'a Repository,
+    ids: I,
+    current: io::Cursor<Vec<u8>>,
+}
+
+impl<'a, I: Iterator<Item = &'a String>> Read for Blobs<'a, I> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = self.current.read(buf)?;
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+            let id = match self.ids.next() {
+                Some(id) => id,
+                None => return Ok(0),
+            };
+            let blob = self
+                .repo
+                .load_blob(id)
+                .map_err(|e| io::Error::other(e.to_string()))?;
+            self.current = io::Cursor::new(blob);
+        }
+    }
+}
+
+fn read(repo: &Path, file: impl AsRef<Path>) -> Result<Vec<u8>> {
+    Ok(fs::read(repo.join(file))?)
+}
+
+fn list(repo: &Path, kind: &str) -> Result<Vec<(String, PathBuf)>> {
+    let mut files = vec![];
+    for entry in fs::read_dir(repo.join(kind))? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            files.push((
+                entry.file_name().to_string_lossy().into_owned(),
+                entry.path(),
+            ));
+        }
+    }
+
+    files.sort();
+    Ok(files)
+}
+
+fn find_key(repo: &Path, password: &str) -> Result<Key> {
+    for (id, path) in list(repo, "keys")? {
+        let file: KeyFile = serde_json::from_slice(&fs::read(path)?)?;
+        if file.kdf != "scrypt" || !file.n.is_power_of_two() {
+            warn!("skipping restic key {}: unsupported kdf", id);
+            continue;
+        }
+
+        let params = scrypt::Params::new(file.n.trailing_zeros() as u8, file.r, file.p, 64)
+            .map_err(|e| ResticError::Invalid(e.to_string()))?;
+        let mut derived = [0; 64];
+        scrypt::scrypt(
+            password.as_bytes(),
+            &decode(&file.salt)?,
+            &params,
+            &mut derived,
+        )
+        .map_err(|e| ResticError::Invalid(e.to_string()))?;
+
+        let user_key = Key::from_bytes(&derived[..32], &derived[32..48], &derived[48..])?;
+        let master = match user_key.open(&id, &decode(&file.data)?) {
+            Ok(master) => master,
+            Err(ResticError::Corrupt(_)) => continue,
+            Err(e) => return Err(e),
+        };
+
+        let master: MasterKeyFile = serde_json::from_slice(&master)?;
+        return Key::from_bytes(
+            &decode(&master.encrypt)?,
+            &decode(&master.mac.k)?,
+            &decode(&master.mac.r)?,
+        );
+    }
+
+    Err(ResticError::WrongPassword)
+}
+
+fn bad_time(s: &str) -> ResticError {
+    ResticError::Invalid(format!("bad timestamp: {}", s))
+}
+
+fn decode(s: &str) -> Result<Vec<u8>> {
+    BASE64
+        .decode(s)
+        .map_err(|e| ResticError::Invalid(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PASSWORD: &str = "restic";
+
+    fn seal(key: &Key, plaintext: &[u8]) -> Vec<u8> {
+        let iv = [7u8; IV_SIZE];
+        let mut ciphertext = plaintext.to_vec();
+        Aes256Ctr::new(
+            GenericArray::from_slice(&key.encrypt),
+            GenericArray::from_slice(&iv),
+        )
+        .apply_keystream(&mut ciphertext);
+
+        let mut out = iv.to_vec();
+        out.extend_from_slice(&ciphertext);
+        out.extend_from_slice(&key.mac(&iv, &ciphertext));
+        out
+    }
+
+    fn write(path: &Path, data: &[u8]) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, data).unwrap();
+    }
+
+    /// Write a version 2 repository with a single snapshot of
+    /// `/home/file`, and return the contents of the file.
+    fn create_repository(repo: &Path) -> Vec<u8> {
+        let master = Key {
+            encrypt: [1; 32],
+            mac_k: [2; 16],
+            mac_r: [3; 16],
+        };
+
+        let salt = [4u8; 16];
+        let params = scrypt::Params::new(10, 8, 1, 64).unwrap();
+        let mut derived = [0; 64];
+        scrypt::scrypt(PASSWORD.as_bytes(), &salt, &params, &mut derived).unwrap();
+        let user = Key::from_bytes(&derived[..32], &derived[32..48], &derived[48..]).unwrap();
+
+        let master_json = serde_json::json!({
+            "mac": { "k": BASE64.encode(master.mac_k), "r": BASE64.encode(master.mac_r) },
+            "encrypt": BASE64.encode(master.encrypt),
+        });
+        let key_json = serde_json::json!({
+            "kdf": "scrypt", "N": 1024, "r": 8, "p": 1,
+            "salt": BASE64.encode(salt),
+            "data": BASE64.encode(seal(&user, master_json.to_string().as_bytes())),
+        });
+        write(&repo.join("keys/k1"), key_json.to_string().as_bytes());
+        write(
+            &repo.join("config"),
+            &seal(
+                &master,
+                br#"{"version":2,"id":"x","chunker_polynomial":"1"}"#,
+            ),
+        );
+
+        let contents = (0..100_000u32).map(|i| (i % 13) as u8).collect::<Vec<_>>();
+        let (first, second) = contents.split_at(60_000);
+
+        // one blob compressed, one stored as is
+        let blob1 = seal(&master, &zstd::stream::encode_all(first, 0).unwrap());
+        let blob2 = seal(&master, second);
+        let home = serde_json::json!({ "nodes": [{
+            "name": "file", "type": "file", "mode": 0o640,
+            "mtime": "2021-03-14T15:09:26.5+01:00", "uid": 1000, "gid": 100,
+            "size": contents.len(), "content": ["b1", "b2"],
+            "extended_attributes": [{ "name": "user.origin", "value": "cmVzdGlj" }],
+        }]});
+        let root = serde_json::json!({ "nodes": [
+            { "name": "home", "type": "dir", "mode": 0x8000_01ed_u32,
+              "mtime": "2021-03-14T15:09:26Z", "subtree": "t2" },
+            { "name": "link", "type": "symlink", "mtime": "2021-03-14T15:09:26Z",
+              "linktarget": "home/file" },
+        ]});
+        let tree1 = seal(&master, root.to_string().as_bytes());
+        let tree2 = seal(&master, home.to_string().as_bytes());
+
+        let mut pack = vec![];
+        let mut blobs = vec![];
+        for (id, blob, uncompressed) in [
+            ("b1", &blob1, Some(first.len())),
+            ("b2", &blob2, None),
+            ("t1", &tree1, None),
+            ("t2", &tree2, None),
+        ] {
+            blobs.push(serde_json::json!({
+                "id": id, "type": "data", "offset": pack.len(), "length": blob.len(),
+                "uncompressed_length": uncompressed,
+            }));
+            pack.extend_from_slice(blob);
+        }
+        write(&repo.join("data/p1/p1pack"), &pack);
+
+        let index = serde_json::json!({ "packs": [{ "id": "p1pack", "blobs": blobs }] });
+        let mut compressed = vec![2];
+        compressed.extend(zstd::stream::encode_all(index.to_string().as_bytes(), 0).unwrap());
+        write(&repo.join("index/i1"), &seal(&master, &compressed));
+
+        let snapshot = serde_json::json!({
+            "time": "2021-03-14T15:09:26.123456789+01:00", "tree": "t1",
+            "paths": ["/home"], "hostname": "host", "tags": ["daily"],
+        });
+        write(
+            &repo.join("snapshots/s1"),
+            &seal(&master, snapshot.to_string().as_bytes()),
+        );
+
+        contents
+    }
+
+    #[test]
+    fn imports_snapshots_into_a_stash() {
+        use crate::backends::MemoryBackend;
+        use crate::stash::{RestoreOptions, StashKey};
+
+        let repo = std::env::temp_dir().join("0s_test_restic_repo");
+        let target = std::env::temp_dir().join("0s_test_restic_restore");
+        let _ = fs::remove_dir_all(&repo);
+        let contents = create_repository(&repo);
+
+        assert!(matches!(
+            Repository::open(&repo, "wrong"),
+            Err(ResticError::WrongPassword)
+        ));
+        let restic = Repository::open(&repo, PASSWORD).unwrap();
+        let snapshots = restic.snapshots().unwrap();
+        assert_eq!(snapshots.len(), 1);
+        assert_eq!(snapshots[0].unix_secs, 1_615_730_966);
+
+        let backend = Arc::new(MemoryBackend::default());
+        let key = || StashKey::open_stash("restic", "test").unwrap();
+        let mut stash = Stash::new(backend.clone(), key());
+        restic.import(&mut stash, &snapshots).unwrap();
+
+        // importing again only adds what's new
+        let mut stash = Stash::new(backend, key());
+        stash.read().unwrap();
+        assert!(restic.import(&mut stash, &snapshots).unwrap().is_empty());
+        assert_eq!(stash.snapshots().len(), 1);
+        let imported = &stash.snapshots()[0];
+        assert_eq!(imported.paths, vec!["/home"]);
+        assert_eq!(imported.tags, vec!["daily"]);
+        assert_eq!(imported.labels["hostname"], "host");
+        assert_eq!(imported.unix_secs, 1_615_730_966);
+        assert_eq!(imported.files.len(), 2);
+
+        let named = |name: &str| imported.files.iter().find(|f| f.name == name).unwrap();
+        assert_eq!(named("/link").symlink.as_deref(), Some("home/file"));
+        let file = named("/home/file");
+        assert_eq!(file.name, "/home/file");
+        assert_eq!(file.size, contents.len() as u64);
+        assert_eq!(
+            (file.unix_secs, file.unix_nanos),
+            (1_615_730_966, 500_000_000)
+        );
+        assert_eq!((file.unix_uid, file.unix_perm & 0o777), (1000, 0o640));
+        let xattrs = file.xattrs.as_ref().unwrap();
+        assert_eq!(xattrs["user.origin"].as_ref(), b"restic");
+
+        stash
+            .restore(imported, &target, &RestoreOptions::default())
+            .unwrap();
+        assert_eq!(fs::read(target.join("home/file")).unwrap(), contents);
+        assert_eq!(fs::read(target.join("link")).unwrap(), contents);
+
+        fs::remove_dir_all(&repo).unwrap();
+        fs::remove_dir_all(&target).unwrap();
+    }
+}
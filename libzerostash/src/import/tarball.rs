@@ -0,0 +1,379 @@
+//! Importing tar archives, like backups kept as tarballs.
+//!
+//! Members are streamed into the stash as they're read, so archives
+//! aren't unpacked to disk, and large members don't have to fit in
+//! memory. Archives compressed with gzip are decompressed by the
+//! `gzip` program, and those compressed with zstd need the `zstd`
+//! feature.
+//!
+//! ```no_run
+//! use libzerostash::import::tarball::Tarball;
+//! # fn import(stash: &mut libzerostash::Stash) -> Result<(), Box<dyn std::error::Error>> {
+//!
+//! let archive = std::fs::File::open("home-2019.tar.gz")?;
+//! Tarball::new(1_546_300_800)
+//!     .tag("home-2019")
+//!     .import(stash, archive)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::error::ZerostashError;
+use crate::files::Entry;
+use crate::snapshots::{Labels, Snapshot};
+use crate::stash::Stash;
+
+use crossbeam_utils::thread;
+use serde_bytes::ByteBuf;
+use thiserror::Error;
+
+use std::collections::{BTreeSet, HashMap};
+use std::io::{self, BufRead, BufReader, Read};
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+#[derive(Error, Debug)]
+pub enum TarError {
+    #[error("IO error: {source}")]
+    Io {
+        #[from]
+        source: io::Error,
+    },
+    #[error("Invalid archive: {0}")]
+    Invalid(String),
+    #[error("gzip failed: {0}")]
+    Gzip(String),
+    #[error("Stash error: {source}")]
+    Stash {
+        #[from]
+        source: ZerostashError,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, TarError>;
+
+/// A tar archive to import, and how its snapshot is recorded.
+#[derive(Clone, Debug, Default)]
+pub struct Tarball {
+    unix_secs: u64,
+    tags: Vec<String>,
+    labels: Labels,
+}
+
+impl Tarball {
+    /// The snapshot of the archive is taken at `unix_secs`, like when
+    /// it was created.
+    pub fn new(unix_secs: u64) -> Tarball {
+        Tarball {
+            unix_secs,
+            ..Tarball::default()
+        }
+    }
+
+    pub fn tag(mut self, tag: impl Into<String>) -> Tarball {
+        self.tags.push(tag.into());
+        self
+    }
+
+    pub fn label(mut self, key: impl Into<String>, value: impl Into<String>) -> Tarball {
+        self.labels.insert(key.into(), value.into());
+        self
+    }
+
+    /// Store the members of the archive `stream` yields as the files
+    /// of a new snapshot, and commit it. Compressed archives are
+    /// recognized by their first bytes.
+    ///
+    /// Paths are taken below the root, without `.` components.
+    /// Regular files, links and special files keep their metadata,
+    /// directories and other kinds of members are skipped.
+    pub fn import(&self, stash: &mut Stash, stream: impl Read + Send) -> Result<Arc<Snapshot>> {
+        let mut stream = BufReader::new(stream);
+        let magic = stream.fill_buf()?.to_vec();
+
+        if magic.starts_with(GZIP_MAGIC) {
+            return self.import_gzip(stash, stream);
+        }
+        if magic.starts_with(ZSTD_MAGIC) {
+            #[cfg(feature = "zstd")]
+            return self.import_tar(stash, zstd::stream::read::Decoder::with_buffer(stream)?);
+            #[cfg(not(feature = "zstd"))]
+            return Err(TarError::Invalid(
+                "compressed with zstd, which needs the `zstd` feature".into(),
+            ));
+        }
+        self.import_tar(stash, stream)
+    }
+
+    fn import_gzip(
+        &self,
+        stash: &mut Stash,
+        mut stream: impl Read + Send,
+    ) -> Result<Arc<Snapshot>> {
+        let mut child = Command::new("gzip")
+            .arg("-dc")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let mut stdin = child.stdin.take().unwrap();
+        let mut stdout = child.stdout.take().unwrap();
+
+        let (result, fed) = thread::scope(|s| {
+            // the input is closed once it's all fed
+            let feeder = s.spawn(move |_| io::copy(&mut stream, &mut stdin));
+            let result = self.import_tar(stash, &mut stdout).and_then(|snapshot| {
+                // what follows the end of the archive
+                io::copy(&mut stdout, &mut io::sink())?;
+                Ok(snapshot)
+            });
+            if result.is_err() {
+                let _ = child.kill();
+            }
+            (result, feeder.join().unwrap())
+        })
+        .unwrap();
+
+        let status = child.wait()?;
+        let snapshot = result?;
+        fed?;
+        if !status.success() {
+            return Err(TarError::Gzip(status.to_string()));
+        }
+        Ok(snapshot)
+    }
+
+    fn import_tar(&self, stash: &mut Stash, stream: impl Read) -> Result<Arc<Snapshot>> {
+        let mut ingest = stash.ingest()?;
+        let mut files = HashMap::<String, Arc<Entry>>::new();
+        let mut roots = BTreeSet::new();
+
+        let mut tar = tar::Archive::new(stream);
+        for member in tar.entries()? {
+            let mut member = member?;
+            let (name, raw_name) = stash_path(&member.path_bytes());
+            let kind = member.header().entry_type();
+            let mtime = member.header().mtime()?;
+
+            let (unix_secs, unix_nanos) = pax_mtime(&mut member)?.unwrap_or((mtime, 0));
+            let header = member.header();
+            let mode = header.mode()?;
+            // some archivers leave the owners blank
+            let (unix_uid, unix_gid) = (header.uid().unwrap_or(0), header.gid().unwrap_or(0));
+            let mut entry = Entry {
+                unix_secs,
+                unix_nanos,
+                unix_perm: 0o100_000 | (mode & 0o7777),
+                unix_uid: unix_uid as u32,
+                unix_gid: unix_gid as u32,
+                unix_user: header.username().ok().flatten().map(String::from),
+                unix_group: header.groupname().ok().flatten().map(String::from),
+                size: 0,
+                readonly: mode & 0o200 == 0,
+                name: name.clone(),
+                symlink: None,
+                hardlink: None,
+                xattrs: None,
+                device: None,
+                windows_attributes: None,
+                raw_name,
+                chunks: vec![],
+            };
+
+            let entry = match kind {
+                tar::EntryType::Regular
+                | tar::EntryType::Continuous
+                | tar::EntryType::GNUSparse => ingest.add_stream(entry, &mut member)?,
+                tar::EntryType::Link => {
+                    let target = member
+                        .link_name_bytes()
+                        .map(|t| stash_path(&t).0)
+                        .unwrap_or_default();
+                    let target = files.get(&target).ok_or_else(|| {
+                        TarError::Invalid(format!("dangling hard link: {}", name))
+                    })?;
+
+                    entry.size = target.size;
+                    entry.chunks = target.chunks.clone();
+                    entry.hardlink = Some(target.name.clone());
+                    let entry = Arc::new(entry);
+                    ingest.add_entry(entry.clone());
+                    entry
+                }
+                tar::EntryType::Symlink => {
+                    entry.unix_perm = 0o120_000 | (mode & 0o7777);
+                    entry.symlink = member
+                        .link_name()?
+                        .map(|t| t.to_string_lossy().into_owned());
+                    let entry = Arc::new(entry);
+                    ingest.add_entry(entry.clone());
+                    entry
+                }
+                tar::EntryType::Char | tar::EntryType::Block | tar::EntryType::Fifo => {
+                    let file_type = match kind {
+                        tar::EntryType::Char => 0o020_000,
+                        tar::EntryType::Block => 0o060_000,
+                        _ => 0o010_000,
+                    };
+                    entry.unix_perm = file_type | (mode & 0o7777);
+                    if !kind.is_fifo() {
+                        let major = header.device_major()?.unwrap_or_default();
+                        let minor = header.device_minor()?.unwrap_or_default();
+                        entry.device = Some((major, minor));
+                    }
+                    let entry = Arc::new(entry);
+                    ingest.add_entry(entry.clone());
+                    entry
+                }
+                tar::EntryType::Directory => continue,
+                _ => {
+                    warn!("skipping {}: unsupported member type {:?}", name, kind);
+                    continue;
+                }
+            };
+
+            if let Some(root) = name.split('/').nth(1) {
+                roots.insert(format!("/{}", root));
+            }
+            files.insert(name, entry);
+        }
+
+        Ok(ingest.finish(
+            self.unix_secs,
+            roots.into_iter().collect(),
+            self.tags.clone(),
+            self.labels.clone(),
+        )?)
+    }
+}
+
+/// The name of a member in the stash, below the root and without `.`
+/// or `..` components, and its bytes if they aren't UTF-8.
+fn stash_path(path: &[u8]) -> (String, Option<ByteBuf>) {
+    let mut bytes = vec![];
+    for part in path.split(|b| *b == b'/') {
+        if !matches!(part, b"" | b"." | b"..") {
+            bytes.push(b'/');
+            bytes.extend_from_slice(part);
+        }
+    }
+
+    match String::from_utf8(bytes) {
+        Ok(name) => (name, None),
+        Err(e) => {
+            let name = String::from_utf8_lossy(e.as_bytes()).into_owned();
+            (name, Some(ByteBuf::from(e.into_bytes())))
+        }
+    }
+}
+
+/// The precise modification time from a PAX header, if there's one.
+fn pax_mtime<R: Read>(member: &mut tar::Entry<'_, R>) -> Result<Option<(u64, u32)>> {
+    let extensions = match member.pax_extensions()? {
+        Some(extensions) => extensions,
+        None => return Ok(None),
+    };
+
+    for extension in extensions {
+        let extension = extension?;
+        if extension.key() != Ok("mtime") {
+            continue;
+        }
+
+        let value = extension.value().unwrap_or_default();
+        let mut parts = value.splitn(2, '.');
+        let secs = parts.next().and_then(|s| s.parse().ok());
+        let nanos = parts
+            .next()
+            .map(|f| format!("{:0<9}", &f[..f.len().min(9)]).parse().unwrap_or(0))
+            .unwrap_or(0);
+
+        return Ok(secs.map(|s| (s, nanos)));
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn compressed_archives_are_imported() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use crate::stash::StashKey;
+
+        let contents = (0..70_000u32).map(|i| (i % 13) as u8).collect::<Vec<_>>();
+        let mut tar = tar::Builder::new(vec![]);
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o640);
+        header.set_mtime(1_546_300_800);
+        tar.append_data(&mut header, "./etc/app.conf", &contents[..])
+            .unwrap();
+
+        let mut link = tar::Header::new_gnu();
+        link.set_entry_type(tar::EntryType::Symlink);
+        link.set_mode(0o777);
+        link.set_size(0);
+        tar.append_link(&mut link, "./etc/current", "app.conf")
+            .unwrap();
+        let archive = tar.into_inner().unwrap();
+
+        let mut archives = vec![archive.clone()];
+        #[cfg(feature = "zstd")]
+        archives.push(zstd::encode_all(&archive[..], 3).unwrap());
+        if let Ok(mut gzip) = Command::new("gzip")
+            .arg("-c")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+        {
+            use std::io::Write;
+            gzip.stdin.take().unwrap().write_all(&archive).unwrap();
+            archives.push(gzip.wait_with_output().unwrap().stdout);
+        }
+
+        let backend = Arc::new(MemoryBackend::default());
+        let key = || StashKey::open_stash("tarball", "test").unwrap();
+        let mut stash = Stash::new(backend.clone(), key());
+        for archive in archives.iter() {
+            Tarball::new(1_546_300_800)
+                .tag("2019")
+                .import(&mut stash, &archive[..])
+                .unwrap();
+        }
+        assert!(Tarball::new(0)
+            .import(&mut stash, &b"not a tarball"[..])
+            .is_err());
+
+        let mut stash = Stash::new(backend, key());
+        stash.read().unwrap();
+        assert_eq!(stash.snapshots().len(), archives.len());
+        for snapshot in stash.snapshots() {
+            assert_eq!(snapshot.paths, vec!["/etc"]);
+            assert_eq!(snapshot.tags, vec!["2019"]);
+
+            let mut files = snapshot.files.clone();
+            files.sort_by(|a, b| a.name.cmp(&b.name));
+            assert_eq!(files[0].name, "/etc/app.conf");
+            assert_eq!(files[0].unix_perm & 0o777, 0o640);
+            assert_eq!(files[1].symlink.as_deref(), Some("app.conf"));
+
+            let mut out = vec![];
+            stash.read_entry_to(&files[0], &mut out).unwrap();
+            assert_eq!(out, contents);
+        }
+    }
+
+    #[test]
+    fn parent_components_are_dropped() {
+        use super::*;
+
+        assert_eq!(stash_path(b"../x").0, "/x");
+        assert_eq!(stash_path(b"a/../../x").0, "/a/x");
+        assert_eq!(stash_path(b"/../etc/./passwd").0, "/etc/passwd");
+        assert_eq!(stash_path(b"..").0, "");
+    }
+}
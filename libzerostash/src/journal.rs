@@ -0,0 +1,452 @@
+//! Change journals, which list the files changed since an earlier
+//! run, so finding them doesn't need a walk over every file.
+//!
+//! NTFS keeps such a journal for each volume, which is read on
+//! Windows. Elsewhere there's no journal that outlives the process,
+//! so `changes` never knows, and the whole tree has to be scanned.
+//! On Linux, `watch::Watcher` follows changes with fanotify while
+//! it's running instead.
+//!
+//! The journal only tells which paths changed, so they're best
+//! stored with `Stash::backup_changes`.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// How far the journal of each volume was read, by volume.
+type Cursor = BTreeMap<String, Position>;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+struct Position {
+    /// Journals get a new id when they're recreated, which loses the
+    /// changes from before
+    journal: u64,
+    next: i64,
+}
+
+pub struct Journal {
+    path: PathBuf,
+    cursor: Cursor,
+    read: Cursor,
+}
+
+impl Journal {
+    /// Open the journal state at `path`, which remembers how far the
+    /// journal was read. A missing file is the same as having never
+    /// read it.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Journal> {
+        let path = path.as_ref().to_path_buf();
+        let cursor = match fs::read(&path) {
+            Ok(state) => serde_json::from_slice(&state)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Cursor::new(),
+            Err(e) => return Err(e),
+        };
+
+        Ok(Journal {
+            path,
+            cursor,
+            read: Cursor::new(),
+        })
+    }
+
+    /// Paths under `roots` that changed since the last `save`, or
+    /// `None` if the journal can't tell, because it wasn't read
+    /// before, or lost changes since.
+    pub fn changes(&mut self, roots: &[impl AsRef<Path>]) -> io::Result<Option<BTreeSet<PathBuf>>> {
+        let roots = roots
+            .iter()
+            .map(|r| r.as_ref().to_path_buf())
+            .collect::<Vec<_>>();
+        let (changes, read) = sys::read(&roots, &self.cursor)?;
+        self.read = read;
+
+        Ok(changes)
+    }
+
+    /// Remember how far `changes` read the journal. Only call this
+    /// after the changes were committed, or they'll be skipped.
+    pub fn save(&mut self) -> io::Result<()> {
+        let partial = self.path.with_extension("tmp");
+        fs::write(&partial, serde_json::to_vec(&self.read)?)?;
+        fs::rename(partial, &self.path)?;
+
+        self.cursor = self.read.clone();
+        Ok(())
+    }
+}
+
+#[cfg(not(windows))]
+mod sys {
+    use super::Cursor;
+    use std::collections::BTreeSet;
+    use std::io;
+    use std::path::PathBuf;
+
+    pub(super) fn read(
+        _roots: &[PathBuf],
+        _cursor: &Cursor,
+    ) -> io::Result<(Option<BTreeSet<PathBuf>>, Cursor)> {
+        Ok((None, Cursor::new()))
+    }
+}
+
+#[cfg(windows)]
+mod sys {
+    //! The NTFS update sequence number journal. Reading it needs
+    //! administrator rights.
+
+    use super::{Cursor, Position};
+
+    use std::collections::{BTreeSet, HashMap};
+    use std::ffi::OsString;
+    use std::fs;
+    use std::io;
+    use std::os::windows::ffi::{OsStrExt, OsStringExt};
+    use std::path::{Component, Path, PathBuf, Prefix};
+    use std::ptr;
+
+    type Handle = *mut std::ffi::c_void;
+
+    const GENERIC_READ: u32 = 0x8000_0000;
+    const FILE_SHARE_ALL: u32 = 0x1 | 0x2 | 0x4;
+    const OPEN_EXISTING: u32 = 3;
+    const FILE_FLAG_BACKUP_SEMANTICS: u32 = 0x0200_0000;
+    const INVALID_HANDLE_VALUE: Handle = -1isize as Handle;
+    const FSCTL_QUERY_USN_JOURNAL: u32 = 0x0009_00f4;
+    const FSCTL_READ_USN_JOURNAL: u32 = 0x0009_00bb;
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct JournalData {
+        journal_id: u64,
+        first_usn: i64,
+        next_usn: i64,
+        lowest_valid_usn: i64,
+        max_usn: i64,
+        maximum_size: u64,
+        allocation_delta: u64,
+    }
+
+    #[repr(C)]
+    struct ReadJournalData {
+        start_usn: i64,
+        reason_mask: u32,
+        return_only_on_close: u32,
+        timeout: u64,
+        bytes_to_wait_for: u64,
+        journal_id: u64,
+    }
+
+    #[repr(C)]
+    struct FileIdDescriptor {
+        size: u32,
+        kind: u32,
+        id: [u64; 2],
+    }
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn CreateFileW(
+            name: *const u16,
+            access: u32,
+            share: u32,
+            security: *mut std::ffi::c_void,
+            disposition: u32,
+            flags: u32,
+            template: Handle,
+        ) -> Handle;
+        fn OpenFileById(
+            volume: Handle,
+            id: *const FileIdDescriptor,
+            access: u32,
+            share: u32,
+            security: *mut std::ffi::c_void,
+            flags: u32,
+        ) -> Handle;
+        fn DeviceIoControl(
+            device: Handle,
+            code: u32,
+            input: *const std::ffi::c_void,
+            input_len: u32,
+            output: *mut std::ffi::c_void,
+            output_len: u32,
+            returned: *mut u32,
+            overlapped: *mut std::ffi::c_void,
+        ) -> i32;
+        fn GetFinalPathNameByHandleW(
+            file: Handle,
+            path: *mut u16,
+            path_len: u32,
+            flags: u32,
+        ) -> u32;
+        fn CloseHandle(handle: Handle) -> i32;
+    }
+
+    struct Volume(Handle);
+
+    impl Drop for Volume {
+        fn drop(&mut self) {
+            unsafe { CloseHandle(self.0) };
+        }
+    }
+
+    impl Volume {
+        fn open(letter: u8) -> io::Result<Volume> {
+            let name = wide(&format!("\\\\.\\{}:", letter as char));
+            let handle = unsafe {
+                CreateFileW(
+                    name.as_ptr(),
+                    GENERIC_READ,
+                    FILE_SHARE_ALL,
+                    ptr::null_mut(),
+                    OPEN_EXISTING,
+                    0,
+                    ptr::null_mut(),
+                )
+            };
+            if handle == INVALID_HANDLE_VALUE {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Volume(handle))
+        }
+
+        fn query(&self) -> io::Result<JournalData> {
+            let mut data = JournalData::default();
+            self.control(
+                FSCTL_QUERY_USN_JOURNAL,
+                ptr::null(),
+                0,
+                &mut data as *mut _ as *mut _,
+                std::mem::size_of::<JournalData>(),
+            )?;
+            Ok(data)
+        }
+
+        fn control(
+            &self,
+            code: u32,
+            input: *const std::ffi::c_void,
+            input_len: usize,
+            output: *mut std::ffi::c_void,
+            output_len: usize,
+        ) -> io::Result<usize> {
+            let mut returned = 0;
+            let ok = unsafe {
+                DeviceIoControl(
+                    self.0,
+                    code,
+                    input,
+                    input_len as u32,
+                    output,
+                    output_len as u32,
+                    &mut returned,
+                    ptr::null_mut(),
+                )
+            };
+            match ok {
+                0 => Err(io::Error::last_os_error()),
+                _ => Ok(returned as usize),
+            }
+        }
+
+        /// The path of the file with the reference number `id`.
+        fn path_of(&self, id: u64) -> Option<PathBuf> {
+            let descriptor = FileIdDescriptor {
+                size: std::mem::size_of::<FileIdDescriptor>() as u32,
+                kind: 0,
+                id: [id, 0],
+            };
+            let file = unsafe {
+                OpenFileById(
+                    self.0,
+                    &descriptor,
+                    0,
+                    FILE_SHARE_ALL,
+                    ptr::null_mut(),
+                    FILE_FLAG_BACKUP_SEMANTICS,
+                )
+            };
+            if file == INVALID_HANDLE_VALUE {
+                return None;
+            }
+
+            let mut path = vec![0u16; 32 * 1024];
+            let len =
+                unsafe { GetFinalPathNameByHandleW(file, path.as_mut_ptr(), path.len() as u32, 0) };
+            unsafe { CloseHandle(file) };
+
+            match len as usize {
+                0 => None,
+                len if len > path.len() => None,
+                len => Some(OsString::from_wide(&path[..len]).into()),
+            }
+        }
+    }
+
+    pub(super) fn read(
+        roots: &[PathBuf],
+        cursor: &Cursor,
+    ) -> io::Result<(Option<BTreeSet<PathBuf>>, Cursor)> {
+        // the roots as given, and where they really are, by volume
+        let mut volumes = HashMap::<u8, Vec<(PathBuf, PathBuf)>>::new();
+        for root in roots.iter() {
+            let real = fs::canonicalize(root)?;
+            let letter = match real.components().next() {
+                Some(Component::Prefix(p)) => match p.kind() {
+                    Prefix::VerbatimDisk(letter) | Prefix::Disk(letter) => letter,
+                    // shares have no journal that can be read
+                    _ => return Ok((None, Cursor::new())),
+                },
+                _ => return Ok((None, Cursor::new())),
+            };
+            volumes
+                .entry(letter)
+                .or_default()
+                .push((root.clone(), real));
+        }
+
+        let mut changes = Some(BTreeSet::new());
+        let mut read = Cursor::new();
+        for (letter, roots) in volumes {
+            let volume = Volume::open(letter)?;
+            let data = volume.query()?;
+            let key = (letter as char).to_string();
+            read.insert(
+                key.clone(),
+                Position {
+                    journal: data.journal_id,
+                    next: data.next_usn,
+                },
+            );
+
+            match cursor.get(&key) {
+                Some(p) if p.journal == data.journal_id && p.next >= data.lowest_valid_usn => {
+                    if let Some(changes) = changes.as_mut() {
+                        read_volume(&volume, &data, p.next, &roots, changes)?;
+                    }
+                }
+                _ => changes = None,
+            }
+        }
+
+        Ok((changes, read))
+    }
+
+    /// Add the paths under `roots` with records from `start` up to
+    /// where the journal was when it was queried.
+    fn read_volume(
+        volume: &Volume,
+        data: &JournalData,
+        start: i64,
+        roots: &[(PathBuf, PathBuf)],
+        changes: &mut BTreeSet<PathBuf>,
+    ) -> io::Result<()> {
+        let mut parents = HashMap::<u64, Option<PathBuf>>::new();
+        let mut buffer = vec![0u64; 8 * 1024];
+        let mut next = start;
+
+        while next < data.next_usn {
+            let request = ReadJournalData {
+                start_usn: next,
+                reason_mask: !0,
+                return_only_on_close: 0,
+                timeout: 0,
+                bytes_to_wait_for: 0,
+                journal_id: data.journal_id,
+            };
+            let len = volume.control(
+                FSCTL_READ_USN_JOURNAL,
+                &request as *const _ as *const _,
+                std::mem::size_of::<ReadJournalData>(),
+                buffer.as_mut_ptr() as *mut _,
+                buffer.len() * 8,
+            )?;
+            let bytes = unsafe { std::slice::from_raw_parts(buffer.as_ptr() as *const u8, len) };
+            if bytes.len() < 8 {
+                break;
+            }
+
+            let u16_at = |at: usize| u16::from_le_bytes([bytes[at], bytes[at + 1]]) as usize;
+            let u32_at = |at: usize| {
+                u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+                    as usize
+            };
+            let u64_at = |at: usize| (u32_at(at) as u64) | ((u32_at(at + 4) as u64) << 32);
+
+            let following = u64_at(0) as i64;
+            let mut pos = 8;
+            while pos + 60 <= bytes.len() {
+                let record_len = u32_at(pos);
+                if record_len < 60 || pos + record_len > bytes.len() {
+                    break;
+                }
+
+                // version 2 records, with 64 bit file references
+                if u16_at(pos + 4) == 2 {
+                    let parent = u64_at(pos + 16);
+                    let (name_len, name_at) = (u16_at(pos + 56), u16_at(pos + 58));
+                    let name = bytes[pos + name_at..pos + name_at + name_len]
+                        .chunks(2)
+                        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                        .collect::<Vec<_>>();
+
+                    let dir = parents
+                        .entry(parent)
+                        .or_insert_with(|| volume.path_of(parent));
+                    if let Some(dir) = dir {
+                        let path = dir.join(OsString::from_wide(&name));
+                        if let Some(path) = under_roots(roots, &path) {
+                            changes.insert(path);
+                        }
+                    }
+                }
+                pos += record_len;
+            }
+
+            if following <= next {
+                break;
+            }
+            next = following;
+        }
+
+        Ok(())
+    }
+
+    fn under_roots(roots: &[(PathBuf, PathBuf)], path: &Path) -> Option<PathBuf> {
+        roots.iter().find_map(|(root, real)| {
+            let rest = path.strip_prefix(real).ok()?;
+            Some(match rest.as_os_str().is_empty() {
+                true => root.clone(),
+                false => root.join(rest),
+            })
+        })
+    }
+
+    fn wide(s: &str) -> Vec<u16> {
+        std::ffi::OsStr::new(s)
+            .encode_wide()
+            .chain(std::iter::once(0))
+            .collect()
+    }
+}
+
+#[cfg(all(test, not(windows)))]
+mod tests {
+    #[test]
+    fn without_a_journal_everything_is_scanned() {
+        use super::Journal;
+        use std::fs;
+
+        let state = std::env::temp_dir().join("0s_test_journal");
+        let _ = fs::remove_file(&state);
+
+        let mut journal = Journal::open(&state).unwrap();
+        assert_eq!(journal.changes(&["tests/data"]).unwrap(), None);
+        journal.save().unwrap();
+
+        let mut journal = Journal::open(&state).unwrap();
+        assert_eq!(journal.changes(&["tests/data"]).unwrap(), None);
+    }
+}
@@ -0,0 +1,455 @@
+//! Keeping the master key of a stash in a key management service.
+//!
+//! Instead of deriving it from credentials, a random master key is
+//! encrypted ("wrapped") with a key that never leaves the service.
+//! Only the wrapped key is stored, and opening the stash needs the
+//! service to unwrap it, so access can be granted, audited and
+//! revoked centrally.
+//!
+//! The services are reached through their command line tools, which
+//! take care of authentication and TLS: `aws` for AWS KMS, `gcloud`
+//! for Cloud KMS, and `vault` for the transit engine of HashiCorp
+//! Vault. Hardware tokens, like smart cards, HSMs or YubiKeys, are
+//! reached through their PKCS#11 module, with `pkcs11-tool` of OpenSC.
+//!
+//! ```no_run
+//! use libzerostash::kms::{self, AwsKms};
+//! # fn open() -> Result<(), kms::KmsError> {
+//!
+//! let kms = AwsKms::new("alias/backups");
+//! let (key, wrapped) = kms::new_key(&kms)?;
+//! // store `wrapped` with the stash configuration, and later
+//! let key = kms::open_key(&kms, &wrapped)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::crypto::{StashKey, CRYPTO_DIGEST_SIZE};
+use crate::prompt::SecretPrompt;
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use secrecy::{ExposeSecret, Secret, SecretString};
+use serde_json::json;
+use thiserror::Error;
+use zeroize::Zeroize;
+
+use std::io::{self, Write};
+use std::process::{Command, Stdio};
+
+#[derive(Error, Debug)]
+pub enum KmsError {
+    #[error("IO error: {source}")]
+    Io {
+        #[from]
+        source: io::Error,
+    },
+    #[error("{0} failed: {1}")]
+    Command(String, String),
+    #[error("Invalid response: {0}")]
+    Invalid(String),
+}
+
+pub type Result<T> = std::result::Result<T, KmsError>;
+
+/// Encrypts and decrypts keys with a key held by a service.
+pub trait KeyWrapper {
+    fn wrap(&self, key: &[u8]) -> Result<Vec<u8>>;
+    fn unwrap(&self, wrapped: &[u8]) -> Result<Secret<Vec<u8>>>;
+
+    /// Ask `prompt` for what else unwrapping needs, like the PIN of
+    /// a token.
+    fn unlock(&mut self, _prompt: &dyn SecretPrompt) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Generate a master key for a new stash. Returns the key, and the
+/// wrapped form of it to store.
+pub fn new_key(wrapper: &dyn KeyWrapper) -> Result<(StashKey, Vec<u8>)> {
+    let key = StashKey::generate();
+    let wrapped = wrapper.wrap(key.expose())?;
+
+    Ok((key, wrapped))
+}
+
+/// Unwrap the master key `wrapped` by `new_key`.
+pub fn open_key(wrapper: &dyn KeyWrapper, wrapped: &[u8]) -> Result<StashKey> {
+    let key = wrapper.unwrap(wrapped)?;
+    if key.expose_secret().len() != CRYPTO_DIGEST_SIZE {
+        return Err(KmsError::Invalid("unwrapped key has the wrong size".into()));
+    }
+
+    let mut bytes = [0; CRYPTO_DIGEST_SIZE];
+    bytes.copy_from_slice(key.expose_secret());
+    Ok(StashKey::from_bytes(bytes))
+}
+
+/// A key in AWS KMS, by id, ARN or alias.
+pub struct AwsKms {
+    program: String,
+    key: String,
+}
+
+impl AwsKms {
+    pub fn new(key: impl Into<String>) -> AwsKms {
+        AwsKms {
+            program: "aws".into(),
+            key: key.into(),
+        }
+    }
+
+    /// The `aws` executable to run.
+    pub fn program(mut self, program: impl Into<String>) -> AwsKms {
+        self.program = program.into();
+        self
+    }
+}
+
+impl KeyWrapper for AwsKms {
+    fn wrap(&self, key: &[u8]) -> Result<Vec<u8>> {
+        let output = run(
+            Command::new(&self.program).args([
+                "kms",
+                "encrypt",
+                "--key-id",
+                &self.key,
+                "--plaintext",
+                "fileb:///dev/stdin",
+                "--output",
+                "text",
+                "--query",
+                "CiphertextBlob",
+            ]),
+            key,
+        )?;
+        decode(output.expose_secret())
+    }
+
+    fn unwrap(&self, wrapped: &[u8]) -> Result<Secret<Vec<u8>>> {
+        let output = run(
+            Command::new(&self.program).args([
+                "kms",
+                "decrypt",
+                "--key-id",
+                &self.key,
+                "--ciphertext-blob",
+                "fileb:///dev/stdin",
+                "--output",
+                "text",
+                "--query",
+                "Plaintext",
+            ]),
+            wrapped,
+        )?;
+        decode(output.expose_secret()).map(Secret::new)
+    }
+}
+
+/// A key in Google Cloud KMS, by its resource name, like
+/// `projects/p/locations/global/keyRings/r/cryptoKeys/k`.
+pub struct GcpKms {
+    program: String,
+    key: String,
+}
+
+impl GcpKms {
+    pub fn new(key: impl Into<String>) -> GcpKms {
+        GcpKms {
+            program: "gcloud".into(),
+            key: key.into(),
+        }
+    }
+
+    /// The `gcloud` executable to run.
+    pub fn program(mut self, program: impl Into<String>) -> GcpKms {
+        self.program = program.into();
+        self
+    }
+
+    fn command(&self, operation: &str, input: &str, output: &str) -> Command {
+        let mut command = Command::new(&self.program);
+        command.args([
+            "kms", operation, "--key", &self.key, input, "-", output, "-",
+        ]);
+        command
+    }
+}
+
+impl KeyWrapper for GcpKms {
+    fn wrap(&self, key: &[u8]) -> Result<Vec<u8>> {
+        let output = run(
+            &mut self.command("encrypt", "--plaintext-file", "--ciphertext-file"),
+            key,
+        )?;
+        Ok(output.expose_secret().clone())
+    }
+
+    fn unwrap(&self, wrapped: &[u8]) -> Result<Secret<Vec<u8>>> {
+        run(
+            &mut self.command("decrypt", "--ciphertext-file", "--plaintext-file"),
+            wrapped,
+        )
+    }
+}
+
+/// A key of the transit secrets engine in HashiCorp Vault. The
+/// address and token are taken from the environment, as `vault`
+/// itself does.
+pub struct VaultTransit {
+    program: String,
+    mount: String,
+    key: String,
+}
+
+impl VaultTransit {
+    pub fn new(key: impl Into<String>) -> VaultTransit {
+        VaultTransit {
+            program: "vault".into(),
+            mount: "transit".into(),
+            key: key.into(),
+        }
+    }
+
+    /// The `vault` executable to run.
+    pub fn program(mut self, program: impl Into<String>) -> VaultTransit {
+        self.program = program.into();
+        self
+    }
+
+    /// Where the transit engine is mounted, `transit` by default.
+    pub fn mount(mut self, mount: impl Into<String>) -> VaultTransit {
+        self.mount = mount.into();
+        self
+    }
+
+    /// Write the JSON `data` to `operation` on the key, and return
+    /// `field` of the response.
+    fn write(
+        &self,
+        operation: &str,
+        field: &str,
+        data: &serde_json::Value,
+    ) -> Result<Secret<Vec<u8>>> {
+        let path = format!("{}/{}/{}", self.mount, operation, self.key);
+        let field = format!("-field={}", field);
+
+        // data is read from stdin, so it doesn't show up in `ps`
+        let mut data = data.to_string().into_bytes();
+        let output = run(
+            Command::new(&self.program).args(["write", &field, &path, "-"]),
+            &data,
+        );
+        data.zeroize();
+        output
+    }
+}
+
+impl KeyWrapper for VaultTransit {
+    fn wrap(&self, key: &[u8]) -> Result<Vec<u8>> {
+        let mut plaintext = BASE64.encode(key);
+        let output = self.write("encrypt", "ciphertext", &json!({ "plaintext": plaintext }));
+        plaintext.zeroize();
+
+        let ciphertext = String::from_utf8_lossy(output?.expose_secret())
+            .trim()
+            .to_string();
+        if !ciphertext.starts_with("vault:") {
+            return Err(KmsError::Invalid(format!("bad ciphertext: {}", ciphertext)));
+        }
+        Ok(ciphertext.into_bytes())
+    }
+
+    fn unwrap(&self, wrapped: &[u8]) -> Result<Secret<Vec<u8>>> {
+        let ciphertext = String::from_utf8_lossy(wrapped);
+        let output = self.write("decrypt", "plaintext", &json!({ "ciphertext": ciphertext }))?;
+
+        decode(output.expose_secret()).map(Secret::new)
+    }
+}
+
+/// A private RSA key on a PKCS#11 token, by its hex id, like `03`
+/// for the PIV key management slot of a YubiKey with Yubico's
+/// `libykcs11`.
+///
+/// Keys are wrapped with RSA-OAEP by `pkcs11-tool`, with the public
+/// key of the token, and only unwrapping needs the PIN. The private
+/// key never leaves the token, but the master key it decrypts does:
+/// `pkcs11-tool` writes it to its output, which this process reads.
+pub struct Pkcs11 {
+    program: String,
+    module: String,
+    id: String,
+    pin: Option<SecretString>,
+}
+
+impl Pkcs11 {
+    /// The key `id` on the first token of `module`, the path of a
+    /// PKCS#11 library.
+    pub fn new(module: impl Into<String>, id: impl Into<String>) -> Pkcs11 {
+        Pkcs11 {
+            program: "pkcs11-tool".into(),
+            module: module.into(),
+            id: id.into(),
+            pin: None,
+        }
+    }
+
+    /// The `pkcs11-tool` executable to run.
+    pub fn program(mut self, program: impl Into<String>) -> Pkcs11 {
+        self.program = program.into();
+        self
+    }
+
+    /// Log in to the token with `pin`, instead of asking for it.
+    pub fn pin(mut self, pin: impl Into<String>) -> Pkcs11 {
+        self.pin = Some(SecretString::new(pin.into()));
+        self
+    }
+
+    fn command(&self, operation: &str) -> Command {
+        let mut command = Command::new(&self.program);
+        command
+            .args(["--module", &self.module, operation, "--id", &self.id])
+            .args(["-m", "RSA-PKCS-OAEP", "--hash-algorithm", "SHA256"])
+            .args(["--mgf", "MGF1-SHA256"])
+            .args(["--input-file", "/dev/stdin", "--output-file", "/dev/stdout"]);
+        command
+    }
+}
+
+impl KeyWrapper for Pkcs11 {
+    fn wrap(&self, key: &[u8]) -> Result<Vec<u8>> {
+        let output = run(&mut self.command("--encrypt"), key)?;
+        Ok(output.expose_secret().clone())
+    }
+
+    fn unwrap(&self, wrapped: &[u8]) -> Result<Secret<Vec<u8>>> {
+        let pin = self
+            .pin
+            .as_ref()
+            .ok_or_else(|| KmsError::Invalid("no PIN for the token".into()))?;
+
+        // the PIN is passed in the environment, so it doesn't show up
+        // in `ps`
+        let mut command = self.command("--decrypt");
+        command
+            .args(["--login", "--pin", "env:ZEROSTASH_PKCS11_PIN"])
+            .env("ZEROSTASH_PKCS11_PIN", pin.expose_secret());
+        run(&mut command, wrapped)
+    }
+
+    fn unlock(&mut self, prompt: &dyn SecretPrompt) -> Result<()> {
+        if self.pin.is_none() {
+            self.pin = Some(prompt.ask_secret("PIN of the token: ")?);
+        }
+        Ok(())
+    }
+}
+
+/// Run `command` with `input` on stdin, and return its output.
+fn run(command: &mut Command, input: &[u8]) -> Result<Secret<Vec<u8>>> {
+    let name = command.get_program().to_string_lossy().into_owned();
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // the input is small enough not to fill the pipe before the
+    // output is read
+    child.stdin.take().unwrap().write_all(input)?;
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(KmsError::Command(name, stderr.trim().to_string()));
+    }
+
+    Ok(Secret::new(output.stdout))
+}
+
+fn decode(output: &[u8]) -> Result<Vec<u8>> {
+    let text = String::from_utf8_lossy(output);
+    BASE64
+        .decode(text.trim())
+        .map_err(|_| KmsError::Invalid("expected base64".into()))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    #[test]
+    fn keys_are_wrapped_by_the_service() {
+        use super::*;
+        use std::fs;
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join("0s_test_kms");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        // stands in for aws, "encrypting" by encoding in base64 twice
+        let program = dir.join("aws");
+        fs::write(
+            &program,
+            "#!/bin/sh\n\
+             case \"$2\" in\n\
+             encrypt) base64 -w0 | base64 -w0 ;;\n\
+             decrypt) cat ;;\n\
+             esac\n",
+        )
+        .unwrap();
+        fs::set_permissions(&program, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let kms = AwsKms::new("alias/test").program(program.to_string_lossy());
+        let (key, wrapped) = new_key(&kms).unwrap();
+        assert_ne!(&wrapped[..], &key.expose()[..]);
+
+        let opened = open_key(&kms, &wrapped).unwrap();
+        assert_eq!(opened.expose(), key.expose());
+        assert!(open_key(&kms, b"short").is_err());
+
+        let kms = VaultTransit::new("test").program("/nonexistent/vault");
+        assert!(new_key(&kms).is_err());
+    }
+
+    #[test]
+    fn keys_are_wrapped_by_a_token() {
+        use super::*;
+        use crate::prompt::Callback;
+        use std::fs;
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join("0s_test_pkcs11");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        // stands in for pkcs11-tool, "encrypting" in base64
+        let program = dir.join("pkcs11-tool");
+        fs::write(
+            &program,
+            "#!/bin/sh
+             case \"$3\" in
+             --encrypt) base64 -w0 ;;
+             --decrypt) [ \"$ZEROSTASH_PKCS11_PIN\" = 1234 ] && base64 -d ;;
+             esac
+",
+        )
+        .unwrap();
+        fs::set_permissions(&program, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let token = || Pkcs11::new("libykcs11.so", "03").program(program.to_string_lossy());
+        let (key, wrapped) = new_key(&token()).unwrap();
+        assert!(open_key(&token(), &wrapped).is_err());
+        assert!(open_key(&token().pin("0000"), &wrapped).is_err());
+
+        let mut unlocked = token();
+        unlocked
+            .unlock(&Callback(|_: &str| Ok("1234".to_string())))
+            .unwrap();
+        assert_eq!(
+            open_key(&unlocked, &wrapped).unwrap().expose(),
+            key.expose()
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,23 +1,94 @@
+//! The storage engine of zerostash.
+//!
+//! # Features
+//!
+//! * `fs` (default): backing up and restoring local files,
+//!   watching them and reading change journals, and the `Directory`
+//!   backend
+//! * `config`: the TOML configuration format shared by frontends
+//! * `restic`: importing snapshots from restic repositories
+//! * `borg`: importing archives from Borg repositories
+//! * `tarball`: importing tar archives
+//! * `gateway`: a read-only HTTP server for stashes, and the `Remote`
+//!   backend to read it
+//! * `daemon`: an HTTP API to run backups, restores and verification
+//!   of a stash as a service
+//! * `cloud`: the `S3Backend` for S3 and compatible object storage,
+//!   `B2Backend` for Backblaze B2, `AzureBackend` for Azure Blob
+//!   Storage, `GcsBackend` for Google Cloud Storage, and
+//!   `WebDavBackend` for WebDAV servers, through `curl`
+//! * `sftp`: the `SftpBackend`, storing objects on SSH servers
+//! * `helper`: the `HelperBackend`, leaving storage to an external
+//!   program
+//! * `kms`: master keys wrapped by AWS KMS, Cloud KMS, Vault or a PKCS#11
+//!   token
+//! * `metrics`: run statistics for Prometheus
+//! * `fuse`: mounting snapshots as a read-only file system, on Linux
+//! * `tracing`: spans and events of backups, restores and backend
+//!   operations for `tracing` subscribers
+//! * `nightly`: the benchmarks, run with `cargo +nightly bench`
+//!
+//! Without any features, stashes are accessed through custom
+//! `Backend` implementations, and the crate builds for wasm32.
+
 #![deny(clippy::all)]
-#![feature(test)]
+#![cfg_attr(all(test, feature = "nightly"), feature(test))]
 
+#[macro_use]
+extern crate log;
 #[macro_use]
 extern crate serde_derive;
 
+#[macro_use]
+mod trace;
+
 pub mod backends;
+pub mod cache;
+pub mod cancel;
 pub mod chunks;
 pub mod compress;
+#[cfg(feature = "config")]
+pub mod config;
+pub mod cpu;
 pub mod crypto;
+pub mod error;
 pub mod files;
+pub mod format;
+#[cfg(all(feature = "fuse", target_os = "linux"))]
+pub mod fuse;
+#[cfg(feature = "gateway")]
+pub mod gateway;
+#[cfg(any(feature = "restic", feature = "borg", feature = "tarball"))]
+pub mod import;
+#[cfg(feature = "fs")]
+pub mod journal;
+#[cfg(feature = "kms")]
+pub mod kms;
+pub mod limits;
 pub mod meta;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod namespaces;
 pub mod objects;
+pub mod parity;
+pub mod progress;
+pub mod prompt;
+pub mod snapshots;
 pub mod stash;
+pub mod stats;
+#[cfg(test)]
+mod test_util;
+mod time;
+#[cfg(feature = "fs")]
+pub mod watch;
 
 pub mod rollsum;
 pub mod splitter;
 
-pub use crypto::StashKey;
+pub use crypto::{Cipher, Kdf, StashKey};
+pub use error::{ErrorKind, ZerostashError};
 pub use stash::Stash;
 
-// Use block size of 4MiB for now
+/// The size of metadata objects, and of data objects unless a stash
+/// was created with another one, see `Stash::set_object_size`
 pub const BLOCK_SIZE: usize = 4 * 1024 * 1024;
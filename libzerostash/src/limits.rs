@@ -0,0 +1,101 @@
+//! Process-wide limit on the number of open files.
+//!
+//! File descriptors are shared by every thread of the process, so
+//! workers reading source files, writing restored files and
+//! accessing the backend all draw from the same pool of permits
+//! instead of each of them having their own limit.
+//!
+//! Permits are only held while a file is open, and never while
+//! waiting for another permit, so there's no way to deadlock.
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex};
+
+const DEFAULT_LIMIT: usize = 256;
+
+static LIMIT: AtomicUsize = AtomicUsize::new(0);
+static OPEN: Mutex<usize> = Mutex::new(0);
+static RELEASED: Condvar = Condvar::new();
+
+/// The maximum number of files that will be open at the same time.
+///
+/// Unless set explicitly, it is derived from `RLIMIT_NOFILE`.
+pub fn max_open_files() -> usize {
+    match LIMIT.load(Ordering::Relaxed) {
+        0 => {
+            let limit = default_limit();
+            debug!("allowing {} open files", limit);
+            LIMIT.store(limit, Ordering::Relaxed);
+            limit
+        }
+        limit => limit,
+    }
+}
+
+pub fn set_max_open_files(limit: usize) {
+    LIMIT.store(limit.max(1), Ordering::Relaxed);
+    RELEASED.notify_all();
+}
+
+/// Wait until opening another file is within limits.
+///
+/// The returned permit should be dropped when the file is closed.
+pub fn open_file() -> Permit {
+    let mut open = OPEN.lock().unwrap();
+    while *open >= max_open_files() {
+        open = RELEASED.wait(open).unwrap();
+    }
+
+    *open += 1;
+    Permit(())
+}
+
+#[must_use]
+pub struct Permit(());
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        *OPEN.lock().unwrap() -= 1;
+        RELEASED.notify_one();
+    }
+}
+
+#[cfg(unix)]
+fn default_limit() -> usize {
+    // leave some room for stdio, directory handles of the walk, and
+    // anything else the process has open
+    const RESERVED: libc::rlim_t = 64;
+
+    let mut rlim = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut rlim) } != 0
+        || rlim.rlim_cur == libc::RLIM_INFINITY
+    {
+        return DEFAULT_LIMIT;
+    }
+
+    rlim.rlim_cur.saturating_sub(RESERVED).max(1) as usize
+}
+
+#[cfg(not(unix))]
+fn default_limit() -> usize {
+    DEFAULT_LIMIT
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn limit_is_derived_from_rlimit() {
+        use super::*;
+
+        let limit = max_open_files();
+        assert!(limit >= 1);
+
+        // permits are released on drop, so this can't block
+        for _ in 0..limit * 2 {
+            let _permit = open_file();
+        }
+    }
+}
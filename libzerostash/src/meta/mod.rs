@@ -1,32 +1,77 @@
-use crate::compress;
+use crate::compress::{self, Dictionary};
 use crate::objects::{ObjectId, WriteObject};
 
-use serde::{de::DeserializeOwned, Serialize};
+use serde::{
+    de::{self, DeserializeOwned},
+    ser::SerializeMap,
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use serde_cbor::ser::to_vec as serialize_to_vec;
 
 use std::collections::{HashMap, HashSet};
 use std::error::Error;
-use std::io::Cursor;
+use std::io::{self, Cursor};
+use std::sync::Mutex;
 
-type Encoder = compress::Encoder<WriteObject>;
+type Encoder = compress::StreamEncoder<WriteObject>;
 type Decoder<'b> =
-    serde_cbor::Deserializer<serde_cbor::de::IoRead<compress::Decoder<Cursor<&'b [u8]>>>>;
+    serde_cbor::Deserializer<serde_cbor::de::IoRead<compress::StreamDecoder<Cursor<&'b [u8]>>>>;
 pub type ObjectIndex = HashMap<Field, HashSet<ObjectId>>;
 
+/// The objects of a commit in the order of the chain, starting at
+/// the root, with the fields each holds.
+pub type Layout = Vec<(ObjectId, Vec<Field>)>;
+
 // Header size max 512b
 const HEADER_SIZE: usize = 512;
 
+/// The layout of the records written by this build. Headers from
+/// before it was recorded in them are version 1.
+pub const META_VERSION: u32 = 2;
+
+/// How much of the records a dictionary is trained on
+const SAMPLE_SIZE: usize = 4 * 1024 * 1024;
+
+/// Longer records are left out of the sample, as they hold more
+/// than the patterns of the small ones
+const MAX_SAMPLE: usize = 16 * 1024;
+
 mod reader;
 mod writer;
 
-pub use reader::Reader;
-pub use writer::Writer;
+pub use reader::{ReadError, Reader};
+pub use writer::{WriteError, Writer};
+
+/// The dictionary the streams of an object are compressed with, and
+/// the object it's stored in.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct DictionaryRef {
+    pub id: u32,
+    pub object: ObjectId,
+}
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub enum MetaObjectHeader {
     V1 {
+        /// The layout of the records in the object. Older builds
+        /// ignore it, and rely on the format to stop them instead
+        #[serde(default = "first_version")]
+        version: u32,
         next_object: Option<ObjectId>,
         offsets: Vec<FieldOffset>,
         end: usize,
+        /// LZ4 if missing
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        dictionary: Option<DictionaryRef>,
+        /// The object with the `Layout` of the commit, only in the
+        /// root, so readers can skip objects without the fields they
+        /// need instead of opening each to find the next
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        layout: Option<ObjectId>,
+        /// Where the `Layout` is stored in the root itself instead,
+        /// after the end of its records
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        layout_at: Option<u32>,
     },
 }
 
@@ -35,11 +80,56 @@ impl MetaObjectHeader {
         next_object: Option<ObjectId>,
         offsets: impl AsRef<[FieldOffset]>,
         end: usize,
+        dictionary: Option<DictionaryRef>,
+        layout: Option<ObjectId>,
     ) -> MetaObjectHeader {
         MetaObjectHeader::V1 {
+            version: META_VERSION,
             offsets: offsets.as_ref().to_vec(),
             next_object,
             end,
+            dictionary,
+            layout,
+            layout_at: None,
+        }
+    }
+
+    pub fn version(&self) -> u32 {
+        match self {
+            MetaObjectHeader::V1 { ref version, .. } => *version,
+        }
+    }
+
+    pub fn dictionary(&self) -> Option<DictionaryRef> {
+        match self {
+            MetaObjectHeader::V1 { ref dictionary, .. } => *dictionary,
+        }
+    }
+
+    pub fn layout(&self) -> Option<ObjectId> {
+        match self {
+            MetaObjectHeader::V1 { ref layout, .. } => *layout,
+        }
+    }
+
+    pub fn layout_at(&self) -> Option<u32> {
+        match self {
+            MetaObjectHeader::V1 { ref layout_at, .. } => *layout_at,
+        }
+    }
+
+    /// Refer to the layout stored at `at` in the object, instead of
+    /// to a layout object.
+    fn set_layout_at(&mut self, at: u32) {
+        match self {
+            MetaObjectHeader::V1 {
+                ref mut layout,
+                ref mut layout_at,
+                ..
+            } => {
+                *layout = None;
+                *layout_at = Some(at);
+            }
         }
     }
 
@@ -79,21 +169,58 @@ impl MetaObjectHeader {
     }
 }
 
+fn first_version() -> u32 {
+    1
+}
+
 pub trait MetaObjectField {
     type Item: DeserializeOwned;
 
-    fn serialize(&self, mw: &mut impl FieldWriter);
+    fn serialize(&self, mw: &mut impl FieldWriter) -> Result<(), WriteError>;
     fn deserialize(&self, mw: &mut impl FieldReader<Self::Item>);
 }
 
 pub trait FieldWriter {
-    fn write_next(&mut self, obj: impl Serialize);
+    fn write_next(&mut self, obj: impl Serialize) -> Result<(), WriteError>;
 }
 
 pub trait FieldReader<T> {
     fn read_next(&mut self) -> Result<T, Box<dyn Error>>;
 }
 
+/// Collects a sample of the records of fields, to train a
+/// dictionary on.
+#[derive(Default)]
+pub struct Sampler {
+    samples: Vec<Vec<u8>>,
+    size: usize,
+}
+
+impl Sampler {
+    pub fn sample(&mut self, field: &impl MetaObjectField) -> Result<(), WriteError> {
+        field.serialize(self)
+    }
+
+    pub fn train(self) -> io::Result<Dictionary> {
+        Dictionary::train(&self.samples)
+    }
+}
+
+impl FieldWriter for Sampler {
+    fn write_next(&mut self, obj: impl Serialize) -> Result<(), WriteError> {
+        if self.size >= SAMPLE_SIZE {
+            return Ok(());
+        }
+
+        let record = serialize_to_vec(&obj)?;
+        if record.len() <= MAX_SAMPLE {
+            self.size += record.len();
+            self.samples.push(record);
+        }
+        Ok(())
+    }
+}
+
 impl<'b, T> FieldReader<T> for Decoder<'b>
 where
     T: DeserializeOwned,
@@ -103,10 +230,18 @@ where
     }
 }
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
+/// Where the stream of a field starts in an object, stored like a
+/// derived enum would be, as `{"Files": 512}`.
+#[derive(Clone, Debug)]
 pub enum FieldOffset {
     Chunks(u32),
     Files(u32),
+    Snapshots(u32),
+    Format(u32),
+    Parity(u32),
+    Removed(u32),
+    /// A field of a newer build, by its name
+    Unknown(String, u32),
 }
 
 impl From<&FieldOffset> for u32 {
@@ -115,6 +250,11 @@ impl From<&FieldOffset> for u32 {
         *match fo {
             Chunks(o) => o,
             Files(o) => o,
+            Snapshots(o) => o,
+            Format(o) => o,
+            Parity(o) => o,
+            Removed(o) => o,
+            Unknown(_, o) => o,
         }
     }
 }
@@ -122,33 +262,146 @@ impl From<&FieldOffset> for u32 {
 impl FieldOffset {
     fn as_field(&self) -> Field {
         use FieldOffset::*;
-        match *self {
+        match self {
             Chunks(_) => Field::Chunks,
             Files(_) => Field::Files,
+            Snapshots(_) => Field::Snapshots,
+            Format(_) => Field::Format,
+            Parity(_) => Field::Parity,
+            Removed(_) => Field::Removed,
+            Unknown(name, _) => Field::Unknown(name.clone()),
         }
     }
 }
 
+impl Serialize for FieldOffset {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(1))?;
+        map.serialize_entry(self.as_field().name(), &u32::from(self))?;
+        map.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for FieldOffset {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let map = HashMap::<String, u32>::deserialize(deserializer)?;
+        if map.len() != 1 {
+            return Err(de::Error::invalid_length(map.len(), &"one field"));
+        }
+        let (name, offset) = map.into_iter().next().unwrap();
+        Ok(Field::from_name(&name).as_offset(offset))
+    }
+}
+
+/// The fields of the metadata, stored by name.
+///
+/// Readers skip the fields they don't know, so new ones can be added
+/// without breaking older builds, which keep them as they are when
+/// they commit. Changes that older builds can't skip over need a new
+/// `META_VERSION`.
 #[derive(Clone, Eq, PartialEq, Hash, Debug)]
 pub enum Field {
     Chunks,
     Files,
+    Snapshots,
+    Format,
+    /// The groups of data objects that have parity objects
+    Parity,
+    /// What a commit that only wrote what changed removed from the
+    /// fields of the commits before
+    Removed,
+    /// A field of a newer build, by its name
+    Unknown(String),
 }
 
 impl Field {
     fn as_offset(&self, offs: u32) -> FieldOffset {
         use Field::*;
-        match *self {
+        match self {
             Chunks => FieldOffset::Chunks(offs),
             Files => FieldOffset::Files(offs),
+            Snapshots => FieldOffset::Snapshots(offs),
+            Format => FieldOffset::Format(offs),
+            Parity => FieldOffset::Parity(offs),
+            Removed => FieldOffset::Removed(offs),
+            Unknown(name) => FieldOffset::Unknown(name.clone(), offs),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        use Field::*;
+        match self {
+            Chunks => "Chunks",
+            Files => "Files",
+            Snapshots => "Snapshots",
+            Format => "Format",
+            Parity => "Parity",
+            Removed => "Removed",
+            Unknown(name) => name,
+        }
+    }
+
+    fn from_name(name: &str) -> Field {
+        use Field::*;
+        match name {
+            "Chunks" => Chunks,
+            "Files" => Files,
+            "Snapshots" => Snapshots,
+            "Format" => Format,
+            "Parity" => Parity,
+            "Removed" => Removed,
+            _ => Unknown(name.into()),
+        }
+    }
+}
+
+impl Serialize for Field {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+impl<'de> Deserialize<'de> for Field {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(Field::from_name(&String::deserialize(deserializer)?))
+    }
+}
+
+/// The records of a field this build doesn't know, kept undecoded so
+/// they are written back when the metadata is.
+#[derive(Default)]
+pub struct UnknownField(Mutex<Vec<serde_cbor::Value>>);
+
+impl UnknownField {
+    pub fn new(records: Vec<serde_cbor::Value>) -> UnknownField {
+        UnknownField(Mutex::new(records))
+    }
+
+    pub fn records(&self) -> Vec<serde_cbor::Value> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+impl MetaObjectField for UnknownField {
+    type Item = serde_cbor::Value;
+
+    fn serialize(&self, mw: &mut impl FieldWriter) -> Result<(), WriteError> {
+        for record in self.0.lock().unwrap().iter() {
+            mw.write_next(record)?;
+        }
+        Ok(())
+    }
+
+    fn deserialize(&self, mw: &mut impl FieldReader<Self::Item>) {
+        let mut records = self.0.lock().unwrap();
+        while let Ok(record) = mw.read_next() {
+            records.push(record);
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    extern crate test;
-
     #[test]
     fn can_deserialize_fields() {
         use crate::backends;
@@ -163,7 +416,7 @@ mod tests {
         let key = Secret::new(*b"abcdef1234567890abcdef1234567890");
 
         let crypto = crypto::ObjectOperations::new(key);
-        let storage = Arc::new(backends::InMemoryBackend::default());
+        let storage = Arc::new(backends::MemoryBackend::default());
         let oid = ObjectId::new(&crypto);
         let mut mw = meta::Writer::new(oid, storage.clone(), crypto.clone()).unwrap();
 
@@ -174,15 +427,18 @@ mod tests {
             })
             .unwrap();
 
-        mw.write_field(meta::Field::Chunks, &chunks);
-        mw.seal_and_store();
+        mw.write_field(meta::Field::Chunks, &chunks).unwrap();
+        mw.seal_and_store().unwrap();
 
         let mut mr = meta::Reader::new(storage, crypto);
         let objects = mw.objects().get(&meta::Field::Chunks).unwrap();
         assert_eq!(objects.len(), 1);
 
         for id in objects.iter() {
-            mr.open(&id).unwrap();
+            let header = mr.open(id).unwrap();
+            assert_eq!(header.version(), meta::META_VERSION);
+            // only roots that are held refer to a layout
+            assert_eq!(header.layout(), None);
         }
 
         let mut chunks_restore = chunks::ChunkStore::default();
@@ -191,4 +447,59 @@ mod tests {
 
         assert_eq!(chunks_restore.index().len(), 1);
     }
+
+    #[test]
+    fn fields_of_newer_builds_are_kept_by_name() {
+        use super::*;
+
+        // as a build with one more field would derive them
+        #[derive(Serialize)]
+        enum Newer {
+            Extents(u32),
+        }
+        #[derive(Serialize)]
+        enum NewerField {
+            Extents,
+        }
+
+        let bytes = serialize_to_vec(&vec![Newer::Extents(600)]).unwrap();
+        let offsets: Vec<FieldOffset> = serde_cbor::from_slice(&bytes).unwrap();
+        assert_eq!(offsets[0].as_field(), Field::Unknown("Extents".into()));
+        assert_eq!(u32::from(&offsets[0]), 600);
+        assert_eq!(serialize_to_vec(&offsets).unwrap(), bytes);
+
+        let bytes = serialize_to_vec(&NewerField::Extents).unwrap();
+        let field: Field = serde_cbor::from_slice(&bytes).unwrap();
+        assert_eq!(field, Field::Unknown("Extents".into()));
+        assert_eq!(serialize_to_vec(&field).unwrap(), bytes);
+        assert_eq!(
+            serialize_to_vec(&Field::Files).unwrap(),
+            serialize_to_vec(&FieldOffset::Files(0).as_field()).unwrap()
+        );
+    }
+
+    #[test]
+    fn headers_without_a_version_are_the_first() {
+        use super::*;
+
+        #[derive(Serialize)]
+        enum Unversioned {
+            V1 {
+                next_object: Option<ObjectId>,
+                offsets: Vec<FieldOffset>,
+                end: usize,
+            },
+        }
+
+        let bytes = serialize_to_vec(&Unversioned::V1 {
+            next_object: None,
+            offsets: vec![FieldOffset::Files(HEADER_SIZE as u32)],
+            end: 1024,
+        })
+        .unwrap();
+        let header: MetaObjectHeader = serde_cbor::from_slice(&bytes).unwrap();
+        assert_eq!(header.version(), 1);
+        assert_eq!(header.fields(), vec![Field::Files]);
+        assert_eq!(header.end(), 1024);
+    }
 }
@@ -1,7 +1,7 @@
 use crate::backends::{Backend, BackendError};
-use crate::compress;
-use crate::crypto::CryptoProvider;
-use crate::meta::{Field, MetaObjectField, MetaObjectHeader, ObjectIndex};
+use crate::compress::{self, Dictionary};
+use crate::crypto::{chunk_hash, CryptoDigest, CryptoError, CryptoProvider};
+use crate::meta::{DictionaryRef, Field, Layout, MetaObjectField, MetaObjectHeader, META_VERSION};
 use crate::objects::{BlockBuffer, Object, ObjectId};
 
 use thiserror::Error;
@@ -22,19 +22,36 @@ pub enum ReadError {
         #[from]
         source: BackendError,
     },
+    #[error("Crypto error")]
+    Crypto {
+        #[from]
+        source: CryptoError,
+    },
     #[error("Failed to decode header")]
     InvalidHeader,
     #[error("No field found in header")]
     NoField,
     #[error("No header found in object")]
     NoHeader,
+    #[error("Invalid dictionary in object {}", .0.to_string())]
+    InvalidDictionary(ObjectId),
+    #[error("Invalid layout in object {}", .0.to_string())]
+    InvalidLayout(ObjectId),
+    #[error("No layout of a commit that reads objects of the ones before")]
+    NoLayout,
+    #[error(
+        "Metadata version {0} is newer than this build reads, which is {}",
+        META_VERSION
+    )]
+    TooNew(u32),
 }
 pub type Result<T> = std::result::Result<T, ReadError>;
 
 pub struct Reader<C> {
     inner: Object<BlockBuffer>,
     header: Option<MetaObjectHeader>,
-    objects: ObjectIndex,
+    digest: CryptoDigest,
+    dictionary: Option<(DictionaryRef, Arc<Dictionary>)>,
     backend: Arc<dyn Backend>,
     crypto: C,
 }
@@ -46,24 +63,96 @@ where
     pub fn new(backend: Arc<dyn Backend>, crypto: C) -> Reader<C> {
         Reader {
             inner: Object::default(),
-            objects: ObjectIndex::default(),
             header: None,
+            digest: CryptoDigest::default(),
+            dictionary: None,
             backend,
             crypto,
         }
     }
 
     pub fn open(&mut self, id: &ObjectId) -> Result<MetaObjectHeader> {
+        span!("open_metadata", object = %id.to_string());
         let obj = self.backend.read_object(id)?;
 
         self.inner.reset_cursor();
         self.inner.set_id(*id);
-        self.crypto.decrypt_object_into(&mut self.inner, &obj);
+        self.crypto.decrypt_object_into(&mut self.inner, &obj)?;
+        self.digest = chunk_hash(obj.buffer.as_ref());
 
         let mut de = serde_cbor::Deserializer::from_slice(self.inner.as_ref()).into_iter();
-        self.header = de.next().ok_or_else(|| ReadError::InvalidHeader)?.ok();
+        self.header = de.next().ok_or(ReadError::InvalidHeader)?.ok();
+
+        let header = self.header.clone().ok_or(ReadError::NoHeader)?;
+        if header.version() > META_VERSION {
+            return Err(ReadError::TooNew(header.version()));
+        }
+        if let Some(reference) = header.dictionary() {
+            self.load_dictionary(reference)?;
+        }
+        Ok(header)
+    }
+
+    /// Decode objects with the dictionary of `reference`, without
+    /// loading it again.
+    pub fn use_dictionary(&mut self, reference: DictionaryRef, dictionary: Arc<Dictionary>) {
+        self.dictionary = Some((reference, dictionary));
+    }
+
+    /// Load the dictionary of `reference`, unless it's the one in use.
+    pub fn load_dictionary(
+        &mut self,
+        reference: DictionaryRef,
+    ) -> Result<(DictionaryRef, Arc<Dictionary>)> {
+        match &self.dictionary {
+            Some((current, dictionary)) if *current == reference => {
+                return Ok((reference, dictionary.clone()))
+            }
+            _ => {}
+        }
+
+        let invalid = || ReadError::InvalidDictionary(reference.object);
+        let bytes = self.read_stored(&reference.object)?.ok_or_else(invalid)?;
+        let dictionary = Dictionary::from_bytes(bytes).map_err(|_| invalid())?;
+        if dictionary.id() != reference.id {
+            return Err(invalid());
+        }
+
+        let dictionary = Arc::new(dictionary);
+        self.dictionary = Some((reference, dictionary.clone()));
+        Ok((reference, dictionary))
+    }
 
-        self.header.clone().ok_or_else(|| ReadError::NoHeader)
+    /// Read the layout the root refers to, which lists the objects
+    /// holding each field, so only those need to be opened.
+    pub fn read_layout(&self, id: &ObjectId) -> Result<Layout> {
+        let invalid = || ReadError::InvalidLayout(*id);
+        let bytes = self.read_stored(id)?.ok_or_else(invalid)?;
+        serde_cbor::from_slice(&bytes).map_err(|_| invalid())
+    }
+
+    /// Read the layout stored at `at` in the last opened object, which
+    /// is the root of a commit.
+    pub fn inline_layout(&self, at: u32) -> Result<Layout> {
+        let invalid = || ReadError::InvalidLayout(self.inner.id);
+        let bytes = stored_at(self.inner.as_ref(), at as usize).ok_or_else(invalid)?;
+        serde_cbor::from_slice(bytes).map_err(|_| invalid())
+    }
+
+    /// The contents of an object that stores a length and as many
+    /// bytes, like dictionaries and layouts, if they fit.
+    fn read_stored(&self, id: &ObjectId) -> Result<Option<Vec<u8>>> {
+        let obj = self.backend.read_object(id)?;
+        let mut plain = Object::<BlockBuffer>::default();
+        plain.set_id(*id);
+        self.crypto.decrypt_object_into(&mut plain, &obj)?;
+
+        Ok(stored_at(plain.as_ref(), 0).map(<[u8]>::to_vec))
+    }
+
+    /// The hash of the last opened object, as it's stored.
+    pub fn digest(&self) -> CryptoDigest {
+        self.digest
     }
 
     pub fn read_into(
@@ -76,14 +165,21 @@ where
         match self.header {
             None => Err(ReadError::NoHeader),
             Some(ref header) => {
-                let frame_start = header
-                    .get_offset(&field)
-                    .ok_or_else(|| ReadError::NoField)? as usize;
+                let frame_start = header.get_offset(field).ok_or(ReadError::NoField)? as usize;
 
+                let dictionary = match (header.dictionary(), &self.dictionary) {
+                    (Some(_), Some((_, dictionary))) => Some(&**dictionary),
+                    _ => None,
+                };
                 let buffer: &[u8] = self.inner.as_ref();
-                let decompress =
-                    compress::destream(Cursor::new(&buffer[frame_start..header.end()]))?;
+                let frame = buffer
+                    .get(frame_start..header.end())
+                    .ok_or(ReadError::InvalidHeader)?;
+                let decompress = compress::destream_with(Cursor::new(frame), dictionary)?;
 
+                // every version so far has the records of the current
+                // one, later layouts are converted here by the version
+                // of `header`
                 let mut reader = serde_cbor::Deserializer::from_reader(decompress);
 
                 store.deserialize(&mut reader);
@@ -92,3 +188,10 @@ where
         }
     }
 }
+
+/// The bytes stored at `at` in `data`, after their length.
+fn stored_at(data: &[u8], at: usize) -> Option<&[u8]> {
+    let len = data.get(at..at + 4)?;
+    let len = u32::from_le_bytes([len[0], len[1], len[2], len[3]]) as usize;
+    data.get(at + 4..at + 4 + len)
+}
@@ -1,26 +1,72 @@
-use crate::backends::Backend;
-use crate::compress::{self, STREAM_BLOCK_SIZE};
-use crate::crypto::CryptoProvider;
+use crate::backends::{Backend, BackendError};
+use crate::compress::{Dictionary, Tuning};
+use crate::crypto::{chunk_hash, CryptoDigest, CryptoProvider};
 use crate::meta::{
-    Encoder, Field, FieldOffset, FieldWriter, MetaObjectField, MetaObjectHeader, ObjectIndex,
-    HEADER_SIZE,
+    DictionaryRef, Encoder, Field, FieldOffset, FieldWriter, Layout, MetaObjectField,
+    MetaObjectHeader, ObjectIndex, HEADER_SIZE,
 };
 use crate::objects::{ObjectId, WriteObject};
 
 use serde::Serialize;
 use serde_cbor::ser::to_vec as serialize_to_vec;
+use thiserror::Error;
 
 use std::collections::HashMap;
 use std::io::{self, Seek, SeekFrom, Write};
 use std::sync::Arc;
 
-pub type Result<T> = std::result::Result<T, io::Error>;
+#[derive(Error, Debug)]
+pub enum WriteError {
+    #[error("IO error: {source}")]
+    Io {
+        #[from]
+        source: io::Error,
+    },
+    #[error("Backend error: {source}")]
+    Backend {
+        #[from]
+        source: BackendError,
+    },
+    #[error("Failed to encode a record: {source}")]
+    Encode {
+        #[from]
+        source: serde_cbor::Error,
+    },
+    #[error("Header of {0} bytes doesn't fit in an object")]
+    HeaderTooLarge(usize),
+    #[error("Layout of {0} objects doesn't fit in an object")]
+    LayoutTooLarge(usize),
+}
+pub type Result<T> = std::result::Result<T, WriteError>;
+
+// zstd holds back up to a block of 128 KiB before writing any of it
+const ZSTD_BUFFER: usize = 256 * 1024;
 
+/// Writes the fields of a commit into a chain of metadata objects.
+///
+/// Once a write fails, the commit is incomplete, and the writer has
+/// to be dropped. What it stored is never referred to by a root, so
+/// readers keep seeing the commit before.
 pub struct Writer<C> {
     objects: ObjectIndex,
     offsets: Vec<FieldOffset>,
     encoder: WriteState,
     current_field: Option<Field>,
+    sealed: Vec<(ObjectId, CryptoDigest)>,
+    layout: Layout,
+    /// Where the layout is stored with the root, if it's held
+    layout_object: ObjectId,
+    root: ObjectId,
+    hold_root: bool,
+    held_root: Option<WriteObject>,
+    /// The header of the held root, until it's encrypted
+    root_header: Option<MetaObjectHeader>,
+    /// If the layout is stored in the root
+    layout_inline: bool,
+    /// If objects of the commits before are part of this one
+    reused: bool,
+    tuning: Tuning,
+    dictionary: Option<(DictionaryRef, Arc<Dictionary>)>,
     backend: Arc<dyn Backend>,
     crypto: C,
 }
@@ -29,22 +75,27 @@ impl<C> FieldWriter for Writer<C>
 where
     C: CryptoProvider,
 {
-    fn write_next(&mut self, obj: impl Serialize) {
-        let writer = self.encoder.writer().unwrap();
+    fn write_next(&mut self, obj: impl Serialize) -> Result<()> {
+        let writer = self.encoder.writer()?;
         let capacity = writer.capacity();
         let position = writer.position();
 
-        let record = serialize_to_vec(&obj).unwrap();
+        let record = serialize_to_vec(&obj)?;
 
-        if capacity - position < STREAM_BLOCK_SIZE {
-            self.seal_and_store();
+        let margin = match self.dictionary {
+            Some(_) => self.tuning.stream_block_size.max(ZSTD_BUFFER),
+            None => self.tuning.stream_block_size,
+        };
+        if capacity - position < margin {
+            self.seal_and_store()?;
         }
 
         if record.len() + position > capacity - 64 {
-            self.seal_and_store();
+            self.seal_and_store()?;
         }
 
-        self.encoder.start().unwrap().write_all(&record).unwrap();
+        self.start()?.write_all(&record)?;
+        Ok(())
     }
 }
 
@@ -67,6 +118,17 @@ where
             offsets: vec![],
             objects: HashMap::new(),
             current_field: None,
+            sealed: vec![],
+            layout: vec![],
+            layout_object: ObjectId::new(&crypto),
+            root: root_object_id,
+            hold_root: false,
+            held_root: None,
+            root_header: None,
+            layout_inline: false,
+            reused: false,
+            tuning: Tuning::default(),
+            dictionary: None,
             backend,
             crypto,
         })
@@ -76,64 +138,253 @@ where
         &self.objects
     }
 
-    pub fn write_field(&mut self, f: Field, obj: &impl MetaObjectField) {
+    /// The ids and hashes of the sealed objects, starting at the root.
+    pub fn sealed(&self) -> &[(ObjectId, CryptoDigest)] {
+        &self.sealed
+    }
+
+    /// The objects sealed so far, with the fields each holds.
+    pub fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    /// Compress the streams at the level and block size of `tuning`.
+    pub fn set_tuning(&mut self, tuning: Tuning) {
+        self.tuning = tuning;
+    }
+
+    /// Compress the streams of all objects but the root with
+    /// `dictionary`, which is stored in `reference.object`.
+    pub fn use_dictionary(&mut self, reference: DictionaryRef, dictionary: Arc<Dictionary>) {
+        self.dictionary = Some((reference, dictionary));
+    }
+
+    /// Store `dictionary` in an object of its own, and use it.
+    pub fn store_dictionary(&mut self, dictionary: Arc<Dictionary>) -> Result<DictionaryRef> {
+        let mut object = WriteObject::default();
+        object.reserve_tag();
+        object.set_id(ObjectId::new(&self.crypto));
+        object.write_all(&(dictionary.as_bytes().len() as u32).to_le_bytes())?;
+        object.write_all(dictionary.as_bytes())?;
+        object.finalize(&self.crypto);
+        self.crypto.encrypt_object(&mut object);
+        self.backend.write_object(&object)?;
+
+        let reference = DictionaryRef {
+            id: dictionary.id(),
+            object: object.id,
+        };
+        self.use_dictionary(reference, dictionary);
+        Ok(reference)
+    }
+
+    /// Keep the root object back until `store_root`, so readers don't
+    /// see it before everything it refers to.
+    pub fn hold_root(&mut self) {
+        self.hold_root = true;
+    }
+
+    /// Encrypt the root object held back by `hold_root`, once all
+    /// the others are sealed, so `sealed` has its hash.
+    ///
+    /// The layout is stored after the records of the root, instead of
+    /// in an object of its own, if it fits there, which saves writing
+    /// one more object with every commit. Not if objects are reused
+    /// though, as builds that don't look for it there need it to
+    /// find those.
+    pub fn seal_root(&mut self) -> Result<()> {
+        let (mut header, root) = match (self.root_header.take(), self.held_root.as_mut()) {
+            (Some(header), Some(root)) => (header, root),
+            _ => return Ok(()),
+        };
+
+        let layout = serialize_to_vec(&self.layout)?;
+        let end = header.end();
+        if !self.reused && end + 4 + layout.len() <= root.capacity() {
+            root.seek(SeekFrom::Start(end as u64))?;
+            root.write_all(&(layout.len() as u32).to_le_bytes())?;
+            root.write_all(&layout)?;
+
+            header.set_layout_at(end as u32);
+            let header_bytes = serialize_to_vec(&header)?;
+            if header_bytes.len() >= HEADER_SIZE {
+                return Err(WriteError::HeaderTooLarge(header_bytes.len()));
+            }
+            root.write_head(&header_bytes);
+            self.layout_inline = true;
+        }
+
+        self.crypto.encrypt_object(root);
+        let digest = chunk_hash(root.buffer.as_ref());
+        let id = root.id;
+        for sealed in self.sealed.iter_mut().filter(|(sealed, _)| *sealed == id) {
+            sealed.1 = digest;
+        }
+        Ok(())
+    }
+
+    /// Store the root object held back by `hold_root`, after the
+    /// layout it refers to, unless it holds that itself.
+    pub fn store_root(&mut self) -> Result<()> {
+        self.seal_root()?;
+        if let Some(root) = self.held_root.take() {
+            if !self.layout_inline {
+                self.store_layout()?;
+            }
+            self.backend.write_object(&root)?;
+        }
+        Ok(())
+    }
+
+    /// Store the layout of the sealed objects. Readers walk the chain
+    /// instead if it's missing, so a layout too large for an object
+    /// is left out, unless objects were reused, which aren't on the
+    /// chain.
+    fn store_layout(&mut self) -> Result<()> {
+        let bytes = serialize_to_vec(&self.layout)?;
+
+        let mut object = WriteObject::default();
+        object.reserve_tag();
+        if bytes.len() + 4 > object.capacity() {
+            if self.reused {
+                return Err(WriteError::LayoutTooLarge(self.layout.len()));
+            }
+            warn!("layout of {} objects doesn't fit", self.layout.len());
+            return Ok(());
+        }
+
+        object.set_id(self.layout_object);
+        object.write_all(&(bytes.len() as u32).to_le_bytes())?;
+        object.write_all(&bytes)?;
+        object.finalize(&self.crypto);
+        self.crypto.encrypt_object(&mut object);
+        self.backend.write_object(&object)?;
+        Ok(())
+    }
+
+    /// Make `objects` of the commits before part of this one, with the
+    /// fields to read from each, after the objects sealed so far.
+    ///
+    /// They're only in the layout and the sealed objects, not on the
+    /// chain, so the root has to be held.
+    pub fn reuse(
+        &mut self,
+        objects: impl IntoIterator<Item = (ObjectId, CryptoDigest, Vec<Field>)>,
+    ) {
+        for (id, digest, fields) in objects {
+            self.sealed.push((id, digest));
+            self.layout.push((id, fields));
+            self.reused = true;
+        }
+    }
+
+    /// Seal the current object, unless nothing was written to it, so
+    /// the next field starts in an object of its own.
+    pub fn start_object(&mut self) -> Result<()> {
+        if self.offsets.is_empty() {
+            return Ok(());
+        }
+        self.seal(true)
+    }
+
+    pub fn write_field(&mut self, f: Field, obj: &impl MetaObjectField) -> Result<()> {
         // book keeping
-        self.offsets
-            .push(f.as_offset(self.encoder.writer().unwrap().position() as u32));
-        self.objects
-            .entry(f.clone())
-            .or_default()
-            .insert(self.encoder.writer().unwrap().id);
+        let object = self.encoder.writer()?;
+        let (position, id) = (object.position(), object.id);
+        self.offsets.push(f.as_offset(position as u32));
+        self.objects.entry(f.clone()).or_default().insert(id);
 
-        self.encoder.start().unwrap();
+        self.start()?;
 
         // clean up
         self.current_field = Some(f);
-        obj.serialize(self);
+        obj.serialize(self)?;
         self.current_field = None;
 
-        // skip to next multiple of STREAM_BLOCK_SIZE
-        let object = self.encoder.writer().unwrap();
-        let skip = STREAM_BLOCK_SIZE - (object.position() - HEADER_SIZE) % STREAM_BLOCK_SIZE;
+        // skip to next multiple of the stream block size
+        let object = self.encoder.writer()?;
+        let block_size = self.tuning.stream_block_size;
+        let skip = block_size - (object.position() - HEADER_SIZE) % block_size;
 
         if skip + object.position() < object.capacity() {
-            let mut object = self.encoder.finish().unwrap();
-            object.seek(SeekFrom::Current(skip as i64)).unwrap();
+            let mut object = self.encoder.finish()?;
+            object.seek(SeekFrom::Current(skip as i64))?;
             self.encoder = WriteState::Parked(object);
+            Ok(())
         } else {
-            self.seal_and_store();
+            self.seal_and_store()
         }
     }
 
-    pub fn seal_and_store(&mut self) {
-        let mut object = self.encoder.finish().unwrap();
+    /// Start a stream in the current object. The root is always LZ4,
+    /// so builds without dictionaries can still read its format.
+    fn start(&mut self) -> Result<&mut WriteState> {
+        let id = self.encoder.writer()?.id;
+        let dictionary = match &self.dictionary {
+            Some((_, dictionary)) if id != self.root => Some(&**dictionary),
+            _ => None,
+        };
+        Ok(self.encoder.start(&self.tuning, dictionary)?)
+    }
+
+    pub fn seal_and_store(&mut self) -> Result<()> {
+        self.seal(self.current_field.is_some())
+    }
+
+    /// Seal the current object, which refers to the next one if
+    /// `continued`.
+    fn seal(&mut self, continued: bool) -> Result<()> {
+        let mut object = self.encoder.finish()?;
         let end = object.position();
+        span!("seal_metadata", object = %object.id.to_string(), bytes = end);
 
         // fill the end of the object with random & other stuff
         object.finalize(&self.crypto);
         let next_object_id = ObjectId::new(&self.crypto);
 
         let object_header = MetaObjectHeader::new(
-            self.current_field.clone().map(|_| next_object_id),
+            Some(next_object_id).filter(|_| continued),
             &self.offsets,
             end,
+            self.dictionary
+                .as_ref()
+                .filter(|_| object.id != self.root)
+                .map(|(reference, _)| *reference),
+            Some(self.layout_object).filter(|_| self.hold_root && object.id == self.root),
         );
-        let header_bytes = serialize_to_vec(&object_header).expect("failed to write header");
-
-        // ok, this is pretty rough, but it also shouldn't happen, so yolo
-        assert!(header_bytes.len() < HEADER_SIZE);
+        let header_bytes = serialize_to_vec(&object_header)?;
+        if header_bytes.len() >= HEADER_SIZE {
+            return Err(WriteError::HeaderTooLarge(header_bytes.len()));
+        }
         object.write_head(&header_bytes);
 
-        // encrypt & store
-        self.crypto.encrypt_object(&mut object);
-        self.backend.write_object(&object).unwrap();
+        // encrypt & store, but the root only once `seal_root` knows
+        // the layout
+        let id = object.id;
+        let held = self.hold_root && id == self.root;
+        let digest = if held {
+            CryptoDigest::default()
+        } else {
+            self.crypto.encrypt_object(&mut object);
+            chunk_hash(object.buffer.as_ref())
+        };
+        trace!("sealed metadata object {}", id.to_string());
+        self.sealed.push((id, digest));
+        self.layout
+            .push((id, self.offsets.iter().map(FieldOffset::as_field).collect()));
+        if held {
+            // the root is kept as it is, so carry on in a new object
+            let mut next = WriteObject::default();
+            next.reserve_tag();
+            self.held_root = Some(std::mem::replace(&mut object, next));
+            self.root_header = Some(object_header);
+        } else {
+            self.backend.write_object(&object)?;
+        }
 
         // track which objects are holding what kind of data
         for fo in self.offsets.drain(..) {
-            self.objects
-                .entry(fo.as_field())
-                .or_default()
-                .insert(object.id);
+            self.objects.entry(fo.as_field()).or_default().insert(id);
         }
 
         // start cleaning up and bookkeeping
@@ -141,13 +392,14 @@ where
 
         // re-initialize the object
         object.clear();
-        object.seek(SeekFrom::Start(HEADER_SIZE as u64)).unwrap();
+        object.seek(SeekFrom::Start(HEADER_SIZE as u64))?;
         self.encoder = WriteState::Parked(object);
 
         // make sure we register the currently written field in the new object
         if let Some(f) = &self.current_field {
             self.offsets.push(f.as_offset(HEADER_SIZE as u32));
         }
+        Ok(())
     }
 }
 
@@ -157,50 +409,38 @@ enum WriteState {
     Encoding(Encoder),
 }
 
+/// The object is only `Idle` while it moves between states, or
+/// after that failed.
+fn failed() -> io::Error {
+    io::Error::other("an earlier write failed")
+}
+
 impl WriteState {
-    fn start(&mut self) -> Result<&mut Self> {
+    fn start(&mut self, tuning: &Tuning, dictionary: Option<&Dictionary>) -> io::Result<&mut Self> {
         use WriteState::*;
 
-        match self {
-            Idle => unreachable!(),
-            Parked(_) => {
-                let mut tmp = WriteState::Idle;
-                std::mem::swap(&mut tmp, self);
-
-                let encoder = match tmp {
-                    Parked(w) => compress::stream(w)?,
-                    _ => unreachable!(),
-                };
-
-                std::mem::replace(self, WriteState::Encoding(encoder));
-                Ok(self)
-            }
-            Encoding(_) => Ok(self),
+        match std::mem::replace(self, Idle) {
+            Idle => return Err(failed()),
+            Parked(w) => *self = Encoding(tuning.stream(w, dictionary)?),
+            encoding => *self = encoding,
         }
+        Ok(self)
     }
 
-    fn finish(&mut self) -> Result<WriteObject> {
+    fn finish(&mut self) -> io::Result<WriteObject> {
         use WriteState::*;
 
-        let mut encoder = WriteState::Idle;
-        std::mem::swap(self, &mut encoder);
-
-        match encoder {
-            Idle => unreachable!(),
+        match std::mem::replace(self, Idle) {
+            Idle => Err(failed()),
             Parked(w) => Ok(w),
-            Encoding(e) => {
-                let (object, err) = e.finish();
-                err?;
-
-                Ok(object)
-            }
+            Encoding(e) => e.finish(),
         }
     }
 
-    fn writer(&self) -> Result<&WriteObject> {
+    fn writer(&self) -> io::Result<&WriteObject> {
         use WriteState::*;
         match self {
-            Idle => unreachable!(),
+            Idle => Err(failed()),
             Parked(w) => Ok(w),
             Encoding(e) => Ok(e.writer()),
         }
@@ -209,12 +449,12 @@ impl WriteState {
 
 impl Write for WriteState {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        use io::{Error, ErrorKind};
+        use io::Error;
         use WriteState::*;
 
         match self {
-            Idle => Err(Error::new(ErrorKind::Other, "Uninitialized")),
-            Parked(_) => Err(Error::new(ErrorKind::Other, "Inactive")),
+            Idle => Err(Error::other("Uninitialized")),
+            Parked(_) => Err(Error::other("Inactive")),
             Encoding(e) => e.write(buf),
         }
     }
@@ -0,0 +1,332 @@
+//! Run statistics in the Prometheus text format.
+//!
+//! `Metrics` is a `Progress`, so registering it with
+//! `Stash::set_progress` counts the bytes processed and transferred,
+//! new and deduplicated chunks, the time spent in each stage, and the
+//! requests to the backend that failed. The outcome of each run is
+//! recorded with `success` or `failure`.
+//!
+//! Backups usually run from a scheduler rather than as a daemon, so
+//! the metrics are either written for the textfile collector of
+//! `node_exporter` with `write_textfile`, or sent to a Pushgateway
+//! with `push`. A `Daemon` serves them on `/metrics`, to be scraped.
+
+use crate::progress::Progress;
+use crate::stats::{Stage, STAGES};
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+#[derive(Default)]
+struct Runs {
+    total: u64,
+    errors: u64,
+    last_duration: Duration,
+    last_success: Option<u64>,
+}
+
+pub struct Metrics {
+    stash: String,
+    processed: AtomicU64,
+    uploaded: AtomicU64,
+    downloaded: AtomicU64,
+    /// Chunks, and their bytes, that were new, and deduplicated
+    chunks: [AtomicU64; 2],
+    deduplicated_bytes: AtomicU64,
+    stage_nanos: [AtomicU64; STAGES.len()],
+    backend_errors: Mutex<BTreeMap<&'static str, u64>>,
+    runs: Mutex<Runs>,
+}
+
+impl Metrics {
+    /// Metrics of runs on `stash`, which ends up in the `stash` label.
+    pub fn new(stash: impl Into<String>) -> Metrics {
+        Metrics {
+            stash: stash.into(),
+            processed: AtomicU64::default(),
+            uploaded: AtomicU64::default(),
+            downloaded: AtomicU64::default(),
+            chunks: Default::default(),
+            deduplicated_bytes: AtomicU64::default(),
+            stage_nanos: Default::default(),
+            backend_errors: Mutex::default(),
+            runs: Mutex::default(),
+        }
+    }
+
+    /// Record a run that finished at `unix_secs`.
+    pub fn success(&self, duration: Duration, unix_secs: u64) {
+        let mut runs = self.runs.lock().unwrap();
+        runs.total += 1;
+        runs.last_duration = duration;
+        runs.last_success = Some(unix_secs);
+    }
+
+    pub fn failure(&self, duration: Duration) {
+        let mut runs = self.runs.lock().unwrap();
+        runs.total += 1;
+        runs.errors += 1;
+        runs.last_duration = duration;
+    }
+
+    /// Bytes of files processed per byte uploaded. Objects are
+    /// uploaded whole, so this is only accurate for larger runs.
+    pub fn dedup_ratio(&self) -> f64 {
+        match self.uploaded.load(Ordering::Relaxed) {
+            0 => 0.0,
+            uploaded => self.processed.load(Ordering::Relaxed) as f64 / uploaded as f64,
+        }
+    }
+
+    /// The metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let runs = self.runs.lock().unwrap();
+        let stash = escape(&self.stash);
+        let label = format!("{{stash=\"{}\"}}", stash);
+        let with = |key: &str, value: &str| {
+            format!("{{stash=\"{}\",{}=\"{}\"}}", stash, key, escape(value))
+        };
+        let plain = |value: String| vec![(label.clone(), value)];
+        let mut out = String::new();
+
+        // each value is of the labels it's paired with
+        let mut metric = |name: &str, kind: &str, help: &str, values: Vec<(String, String)>| {
+            let _ = writeln!(out, "# HELP zerostash_{} {}", name, help);
+            let _ = writeln!(out, "# TYPE zerostash_{} {}", name, kind);
+            for (label, value) in values {
+                let _ = writeln!(out, "zerostash_{}{} {}", name, label, value);
+            }
+        };
+
+        metric(
+            "processed_bytes_total",
+            "counter",
+            "Bytes of files stored or restored.",
+            plain(self.processed.load(Ordering::Relaxed).to_string()),
+        );
+        metric(
+            "uploaded_bytes_total",
+            "counter",
+            "Bytes written to the backend.",
+            plain(self.uploaded.load(Ordering::Relaxed).to_string()),
+        );
+        metric(
+            "downloaded_bytes_total",
+            "counter",
+            "Bytes read from the backend.",
+            plain(self.downloaded.load(Ordering::Relaxed).to_string()),
+        );
+        metric(
+            "dedup_ratio",
+            "gauge",
+            "Bytes processed per byte uploaded.",
+            plain(self.dedup_ratio().to_string()),
+        );
+        metric(
+            "chunks_total",
+            "counter",
+            "Chunks split off files, by if they were new or deduplicated.",
+            vec![
+                (
+                    with("chunk", "new"),
+                    self.chunks[1].load(Ordering::Relaxed).to_string(),
+                ),
+                (
+                    with("chunk", "deduplicated"),
+                    self.chunks[0].load(Ordering::Relaxed).to_string(),
+                ),
+            ],
+        );
+        metric(
+            "deduplicated_bytes_total",
+            "counter",
+            "Bytes of chunks that were already stored.",
+            plain(self.deduplicated_bytes.load(Ordering::Relaxed).to_string()),
+        );
+        metric(
+            "stage_seconds_total",
+            "counter",
+            "Time spent in each stage, summed over threads.",
+            STAGES
+                .iter()
+                .map(|stage| {
+                    let nanos = self.stage_nanos[*stage as usize].load(Ordering::Relaxed);
+                    (
+                        with("stage", stage.name()),
+                        Duration::from_nanos(nanos).as_secs_f64().to_string(),
+                    )
+                })
+                .collect(),
+        );
+        metric(
+            "backend_errors_total",
+            "counter",
+            "Requests to the backend that failed, by operation.",
+            self.backend_errors
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(operation, errors)| (with("operation", operation), errors.to_string()))
+                .collect(),
+        );
+        metric(
+            "runs_total",
+            "counter",
+            "Finished runs.",
+            plain(runs.total.to_string()),
+        );
+        metric(
+            "errors_total",
+            "counter",
+            "Runs that failed.",
+            plain(runs.errors.to_string()),
+        );
+        metric(
+            "last_run_duration_seconds",
+            "gauge",
+            "Duration of the last run.",
+            plain(runs.last_duration.as_secs_f64().to_string()),
+        );
+        if let Some(secs) = runs.last_success {
+            metric(
+                "last_success_timestamp_seconds",
+                "gauge",
+                "Time the last successful run finished.",
+                plain(secs.to_string()),
+            );
+        }
+
+        out
+    }
+
+    /// Write the metrics to `path` for the textfile collector. The
+    /// file is replaced atomically, so it's never read half written.
+    pub fn write_textfile(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let partial = path.with_extension("prom.tmp");
+
+        fs::write(&partial, self.render())?;
+        fs::rename(partial, path)
+    }
+
+    /// Replace the metrics of `job` on the Pushgateway at `address`,
+    /// like `pushgateway.local:9091`.
+    pub fn push(&self, address: &str, job: &str) -> io::Result<()> {
+        let body = self.render();
+        let mut stream = TcpStream::connect(address)?;
+        write!(
+            stream,
+            "PUT /metrics/job/{} HTTP/1.1\r\nHost: {}\r\n\
+             Content-Type: text/plain; version=0.0.4\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+            job,
+            address,
+            body.len(),
+            body
+        )?;
+        stream.flush()?;
+
+        let mut status = String::new();
+        BufReader::new(stream).read_line(&mut status)?;
+        match status.split(' ').nth(1) {
+            Some(code) if code.starts_with('2') => Ok(()),
+            _ => Err(io::Error::other(format!(
+                "pushgateway returned {}",
+                status.trim()
+            ))),
+        }
+    }
+}
+
+impl Progress for Metrics {
+    fn item(&self, bytes: u64) {
+        self.processed.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn transfer(&self, stage: Stage, bytes: u64) {
+        let counter = match stage {
+            Stage::Upload => &self.uploaded,
+            _ => &self.downloaded,
+        };
+        counter.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn chunk(&self, bytes: u64, new: bool) {
+        self.chunks[new as usize].fetch_add(1, Ordering::Relaxed);
+        if !new {
+            self.deduplicated_bytes.fetch_add(bytes, Ordering::Relaxed);
+        }
+    }
+
+    fn time(&self, stage: Stage, elapsed: Duration) {
+        self.stage_nanos[stage as usize].fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    fn backend_error(&self, operation: &'static str) {
+        *self
+            .backend_errors
+            .lock()
+            .unwrap()
+            .entry(operation)
+            .or_default() += 1;
+    }
+}
+
+fn escape(label: &str) -> String {
+    label
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn metrics_are_rendered_for_prometheus() {
+        use super::*;
+
+        let metrics = Metrics::new("home \"main\"");
+        metrics.item(4096);
+        metrics.transfer(Stage::Upload, 1024);
+        metrics.chunk(100, true);
+        metrics.chunk(200, false);
+        metrics.time(Stage::Compress, Duration::from_millis(250));
+        metrics.time(Stage::Compress, Duration::from_millis(250));
+        metrics.backend_error("read");
+        metrics.failure(Duration::from_secs(3));
+        metrics.success(Duration::from_millis(1500), 1_615_730_966);
+
+        let text = metrics.render();
+        let value = |name: &str| {
+            text.lines()
+                .find(|l| l.starts_with(&format!("zerostash_{}{{", name)))
+                .and_then(|l| l.rsplit(' ').next())
+                .map(String::from)
+        };
+
+        assert!(text.contains("{stash=\"home \\\"main\\\"\"}"));
+        assert!(
+            text.contains("zerostash_chunks_total{stash=\"home \\\"main\\\"\",chunk=\"new\"} 1")
+        );
+        assert_eq!(value("deduplicated_bytes_total").unwrap(), "200");
+        assert!(text.contains("stage=\"compress\"} 0.5\n"));
+        assert!(text.contains("operation=\"read\"} 1\n"));
+        assert_eq!(value("uploaded_bytes_total").unwrap(), "1024");
+        assert_eq!(value("dedup_ratio").unwrap(), "4");
+        assert_eq!(value("runs_total").unwrap(), "2");
+        assert_eq!(value("errors_total").unwrap(), "1");
+        assert_eq!(value("last_run_duration_seconds").unwrap(), "1.5");
+        assert_eq!(
+            value("last_success_timestamp_seconds").unwrap(),
+            "1615730966"
+        );
+        assert!(text.contains("# TYPE zerostash_runs_total counter"));
+    }
+}
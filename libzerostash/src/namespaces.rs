@@ -0,0 +1,398 @@
+//! Namespaces for writers sharing a stash.
+//!
+//! Everyone who can write to a stash has its master key, so the
+//! stash alone can't stop one writer from committing metadata that
+//! drops the snapshots of another. Instead, every writer has a
+//! signing key of their own, and signs a manifest of each snapshot
+//! they take. The manifests of a namespace, a path prefix, form a
+//! chain, each naming the one before it.
+//!
+//! A `Policy`, signed by the owner of the stash, lists who may write
+//! under which prefix. Auditing the snapshots against it finds
+//! snapshots that are unsigned, signed by someone not allowed in the
+//! namespace, changed after signing, or missing from a chain. The
+//! heads of the chains returned by an audit can be kept to also catch
+//! the latest snapshots of a namespace disappearing, next time.
+
+use crate::crypto::{self, CryptoDigest};
+use crate::snapshots::Snapshot;
+
+use itertools::Itertools;
+use ring::rand::SystemRandom;
+use ring::signature::{self, Ed25519KeyPair, KeyPair, UnparsedPublicKey};
+use thiserror::Error;
+
+use std::collections::{BTreeMap, HashSet};
+use std::convert::TryFrom;
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+#[derive(Error, Debug)]
+pub enum NamespaceError {
+    #[error("Invalid signing key")]
+    InvalidKey,
+    #[error("Invalid public key: {0}")]
+    InvalidPublicKey(String),
+    #[error("Policy isn't signed by the owner")]
+    UntrustedPolicy,
+    #[error("Path {path} is outside of namespace {namespace}")]
+    OutsideNamespace { namespace: String, path: String },
+    #[error("Snapshot {0} isn't signed")]
+    Unsigned(u64),
+    #[error("Snapshot {0} doesn't match its signature")]
+    BadSignature(u64),
+    #[error("Snapshot {snapshot} was written by someone not allowed in {namespace}")]
+    NotAllowed { snapshot: u64, namespace: String },
+    #[error("A snapshot before {snapshot} in namespace {namespace} is missing")]
+    Missing { snapshot: u64, namespace: String },
+    #[error("The latest snapshots of namespace {0} are missing")]
+    RolledBack(String),
+}
+
+pub type Result<T> = std::result::Result<T, NamespaceError>;
+
+/// The public half of a `WriterKey`, in hex when serialized.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub struct PublicKey([u8; 32]);
+
+impl PublicKey {
+    fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        UnparsedPublicKey::new(&signature::ED25519, &self.0)
+            .verify(message, signature)
+            .is_ok()
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}", self.0.iter().format(""))
+    }
+}
+
+impl From<PublicKey> for String {
+    fn from(key: PublicKey) -> String {
+        key.to_string()
+    }
+}
+
+impl TryFrom<String> for PublicKey {
+    type Error = NamespaceError;
+
+    fn try_from(hex: String) -> Result<PublicKey> {
+        match from_hex(&hex) {
+            Some(bytes) if bytes.len() == 32 => {
+                let mut key = [0; 32];
+                key.copy_from_slice(&bytes);
+                Ok(PublicKey(key))
+            }
+            _ => Err(NamespaceError::InvalidPublicKey(hex)),
+        }
+    }
+}
+
+/// The Ed25519 key a writer signs snapshots with, or the owner signs
+/// the policy with.
+pub struct WriterKey(Ed25519KeyPair);
+
+impl WriterKey {
+    /// Generate a key, in the PKCS#8 form `from_pkcs8` reads, to be
+    /// kept as safe as stash credentials.
+    pub fn generate() -> Result<Vec<u8>> {
+        let document = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+            .map_err(|_| NamespaceError::InvalidKey)?;
+        Ok(document.as_ref().to_vec())
+    }
+
+    pub fn from_pkcs8(pkcs8: &[u8]) -> Result<WriterKey> {
+        Ed25519KeyPair::from_pkcs8(pkcs8)
+            .map(WriterKey)
+            .map_err(|_| NamespaceError::InvalidKey)
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        let mut key = [0; 32];
+        key.copy_from_slice(self.0.public_key().as_ref());
+        PublicKey(key)
+    }
+
+    fn sign(&self, message: &[u8]) -> Vec<u8> {
+        self.0.sign(message).as_ref().to_vec()
+    }
+}
+
+impl fmt::Debug for WriterKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "WriterKey({})", self.public_key())
+    }
+}
+
+/// Who signs the snapshots of a backup, and the namespace they go in.
+#[derive(Clone, Debug)]
+pub struct Writer {
+    pub namespace: String,
+    pub key: Arc<WriterKey>,
+}
+
+impl Writer {
+    /// Check that every one of `paths` is in the namespace.
+    pub fn check_paths(&self, paths: &[impl AsRef<Path>]) -> Result<()> {
+        match paths
+            .iter()
+            .find(|p| !p.as_ref().starts_with(&self.namespace))
+        {
+            Some(path) => Err(NamespaceError::OutsideNamespace {
+                namespace: self.namespace.clone(),
+                path: path.as_ref().to_string_lossy().into_owned(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Sign `snapshot`, following the manifest `previous` in the
+    /// namespace.
+    pub(crate) fn sign(
+        &self,
+        snapshot: &Snapshot,
+        previous: Option<&SignedManifest>,
+    ) -> Result<SignedManifest> {
+        self.check_paths(&snapshot.paths)?;
+
+        let mut manifest = SignedManifest {
+            namespace: self.namespace.clone(),
+            writer: self.key.public_key(),
+            snapshot: snapshot.digest(),
+            previous: previous.map(SignedManifest::hash),
+            signature: vec![],
+        };
+        manifest.signature = self.key.sign(&manifest.message());
+        Ok(manifest)
+    }
+}
+
+/// A writer's signature of a snapshot.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedManifest {
+    pub namespace: String,
+    pub writer: PublicKey,
+    /// `Snapshot::digest` of the signed snapshot
+    pub snapshot: CryptoDigest,
+    /// `hash` of the manifest before this one in the namespace
+    pub previous: Option<CryptoDigest>,
+    #[serde(with = "serde_bytes")]
+    pub signature: Vec<u8>,
+}
+
+impl SignedManifest {
+    fn message(&self) -> Vec<u8> {
+        let mut message = b"0smanifest".to_vec();
+        message.extend_from_slice(self.namespace.as_bytes());
+        message.push(0);
+        message.extend_from_slice(&self.writer.0);
+        message.extend_from_slice(&self.snapshot);
+        if let Some(previous) = &self.previous {
+            message.extend_from_slice(previous);
+        }
+        message
+    }
+
+    /// Identifies the manifest in the chain of its namespace.
+    pub fn hash(&self) -> CryptoDigest {
+        let mut content = self.message();
+        content.extend_from_slice(&self.signature);
+        crypto::chunk_hash(&content)
+    }
+}
+
+/// The writers allowed in each namespace.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Policy {
+    /// Public keys of the writers, by path prefix
+    pub namespaces: BTreeMap<String, Vec<PublicKey>>,
+}
+
+/// A policy with the signature of the stash owner, as it's kept in a
+/// file. The policy comes last, so this can be written as TOML.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SignedPolicy {
+    pub owner: PublicKey,
+    signature: String,
+    pub policy: Policy,
+}
+
+impl Policy {
+    pub fn sign(self, owner: &WriterKey) -> SignedPolicy {
+        let signature = owner.sign(&self.message());
+        SignedPolicy {
+            policy: self,
+            owner: owner.public_key(),
+            signature: format!("{:02x}", signature.iter().format("")),
+        }
+    }
+
+    fn message(&self) -> Vec<u8> {
+        let mut message = b"0spolicy".to_vec();
+        message.extend_from_slice(serde_json::to_string(self).unwrap().as_bytes());
+        message
+    }
+
+    /// Check the signed manifests of `snapshots`, which are in the
+    /// order they were taken, against the policy.
+    ///
+    /// `heads` are the heads of the namespace chains returned by an
+    /// earlier audit, which have to be still there. Returns the
+    /// current heads.
+    pub fn audit(
+        &self,
+        snapshots: &[Arc<Snapshot>],
+        heads: &BTreeMap<String, CryptoDigest>,
+    ) -> Result<BTreeMap<String, CryptoDigest>> {
+        let mut current = BTreeMap::<String, CryptoDigest>::new();
+        let mut seen = HashSet::new();
+
+        for snapshot in snapshots.iter() {
+            let manifest = snapshot
+                .manifest
+                .as_ref()
+                .ok_or(NamespaceError::Unsigned(snapshot.id))?;
+            let namespace = &manifest.namespace;
+
+            let allowed = self
+                .namespaces
+                .get(namespace)
+                .is_some_and(|writers| writers.contains(&manifest.writer));
+            if !allowed {
+                return Err(NamespaceError::NotAllowed {
+                    snapshot: snapshot.id,
+                    namespace: namespace.clone(),
+                });
+            }
+
+            if manifest.snapshot != snapshot.digest()
+                || !manifest
+                    .writer
+                    .verify(&manifest.message(), &manifest.signature)
+            {
+                return Err(NamespaceError::BadSignature(snapshot.id));
+            }
+
+            if let Some(path) = snapshot
+                .paths
+                .iter()
+                .find(|p| !Path::new(p).starts_with(namespace))
+            {
+                return Err(NamespaceError::OutsideNamespace {
+                    namespace: namespace.clone(),
+                    path: path.clone(),
+                });
+            }
+
+            if manifest.previous.as_ref() != current.get(namespace) {
+                return Err(NamespaceError::Missing {
+                    snapshot: snapshot.id,
+                    namespace: namespace.clone(),
+                });
+            }
+
+            let hash = manifest.hash();
+            seen.insert(hash);
+            current.insert(namespace.clone(), hash);
+        }
+
+        match heads.iter().find(|(_, head)| !seen.contains(*head)) {
+            Some((namespace, _)) => Err(NamespaceError::RolledBack(namespace.clone())),
+            None => Ok(current),
+        }
+    }
+}
+
+impl SignedPolicy {
+    /// The policy, if it's signed by `owner`.
+    pub fn verify(&self, owner: &PublicKey) -> Result<&Policy> {
+        let valid =
+            from_hex(&self.signature).is_some_and(|s| owner.verify(&self.policy.message(), &s));
+
+        match &self.owner == owner && valid {
+            true => Ok(&self.policy),
+            false => Err(NamespaceError::UntrustedPolicy),
+        }
+    }
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+
+    (0..hex.len() / 2)
+        .map(|i| u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(all(test, feature = "fs"))]
+mod tests {
+    #[test]
+    fn audit_finds_pruned_and_foreign_snapshots() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use crate::stash::{BackupOptions, Stash, StashKey};
+
+        let key = |pkcs8: &[u8]| Arc::new(WriterKey::from_pkcs8(pkcs8).unwrap());
+        let owner = key(&WriterKey::generate().unwrap());
+        let alice = key(&WriterKey::generate().unwrap());
+        let mallory = key(&WriterKey::generate().unwrap());
+
+        let mut policy = Policy::default();
+        policy
+            .namespaces
+            .insert("tests/data".into(), vec![alice.public_key()]);
+        let signed = policy.sign(&owner);
+        let policy = signed.verify(&owner.public_key()).unwrap().clone();
+        assert!(signed.verify(&alice.public_key()).is_err());
+
+        let stash_key = StashKey::open_stash("namespaces", "test").unwrap();
+        let mut stash = Stash::new(Arc::new(MemoryBackend::default()), stash_key);
+        let writer = |key: &Arc<WriterKey>| BackupOptions {
+            writer: Some(Writer {
+                namespace: "tests/data".into(),
+                key: key.clone(),
+            }),
+            ..BackupOptions::default()
+        };
+        for path in ["tests/data/100_random_1k", "tests/data/10k_random_blob"] {
+            stash.backup(&[path], &writer(&alice)).unwrap();
+        }
+        assert!(stash.backup(&["src"], &writer(&alice)).is_err());
+
+        let snapshots = stash.snapshots();
+        let heads = policy.audit(&snapshots, &BTreeMap::new()).unwrap();
+        assert_eq!(heads.len(), 1);
+
+        // dropping a snapshot breaks the chain, or rolls back the head
+        assert!(matches!(
+            policy.audit(&snapshots[1..], &BTreeMap::new()),
+            Err(NamespaceError::Missing { .. })
+        ));
+        assert!(matches!(
+            policy.audit(&snapshots[..1], &heads),
+            Err(NamespaceError::RolledBack(_))
+        ));
+
+        stash
+            .backup(&["tests/data/10k_random_blob"], &writer(&mallory))
+            .unwrap();
+        assert!(matches!(
+            policy.audit(&stash.snapshots(), &heads),
+            Err(NamespaceError::NotAllowed { snapshot: 3, .. })
+        ));
+
+        stash
+            .backup(&["tests/data/10k_random_blob"], &BackupOptions::default())
+            .unwrap();
+        assert!(matches!(
+            policy.audit(&stash.snapshots()[3..], &BTreeMap::new()),
+            Err(NamespaceError::Unsigned(4))
+        ));
+    }
+}
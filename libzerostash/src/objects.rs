@@ -1,17 +1,22 @@
+#[cfg(feature = "async")]
+use crate::backends::AsyncBackend;
 use crate::backends::{Backend, BackendError};
 use crate::chunks::ChunkPointer;
 
-use crate::compress;
+use crate::compress::{Compression, Tuning};
 use crate::crypto::*;
+use crate::stats::{Collector, Stage};
 use crate::BLOCK_SIZE;
 
 use itertools::Itertools;
 use thiserror::Error;
 
+use std::collections::HashSet;
 use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::mem::size_of;
 use std::string::ToString;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
 
 #[derive(Error, Debug)]
 pub enum ObjectError {
@@ -25,6 +30,8 @@ pub enum ObjectError {
         #[from]
         source: BackendError,
     },
+    #[error("Quota of {0} bytes exceeded")]
+    QuotaExceeded(u64),
 }
 
 pub type Result<T> = std::result::Result<T, ObjectError>;
@@ -32,6 +39,33 @@ pub type Result<T> = std::result::Result<T, ObjectError>;
 pub trait ObjectStore: Clone + Send {
     fn store_chunk(&mut self, hash: &CryptoDigest, data: &[u8]) -> Result<Arc<ChunkPointer>>;
     fn flush(&mut self) -> Result<()>;
+
+    /// Compress the chunks stored from now on with `compression`,
+    /// for stores that compress at all.
+    fn set_compression(&mut self, _compression: Compression) {}
+
+    /// How the chunks stored next are compressed, for stores that
+    /// compress them, so they can be compressed ahead on other
+    /// threads, and stored with `store_compressed`.
+    fn compression(&self) -> Option<(Compression, Tuning)> {
+        None
+    }
+
+    /// Store a chunk compressed as `compression` says. Only called
+    /// on stores that compress.
+    fn store_compressed(
+        &mut self,
+        _hash: &CryptoDigest,
+        _packed: &[u8],
+    ) -> Result<Arc<ChunkPointer>> {
+        unreachable!("the store doesn't compress")
+    }
+
+    /// If the object `id` is still being filled by this store or one
+    /// of its clones, so it's not written yet.
+    fn is_open(&self, _id: &ObjectId) -> bool {
+        false
+    }
 }
 
 #[derive(Debug, Default, Clone, Copy, Hash, PartialEq, Eq, Serialize, Deserialize)]
@@ -54,6 +88,19 @@ impl ObjectId {
         id
     }
 
+    /// Parse the hex form returned by `to_string`.
+    pub fn from_hex(hex: &str) -> Option<ObjectId> {
+        if hex.len() != 2 * CRYPTO_DIGEST_SIZE || !hex.is_ascii() {
+            return None;
+        }
+
+        let mut id = ObjectId::default();
+        for (i, byte) in id.0.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).ok()?;
+        }
+        Some(id)
+    }
+
     #[inline(always)]
     pub fn reset(&mut self, random: &impl Random) {
         random.fill(&mut self.0);
@@ -67,6 +114,7 @@ impl AsRef<[u8]> for ObjectId {
     }
 }
 
+#[allow(clippy::to_string_trait_impl)]
 impl ToString for ObjectId {
     #[inline(always)]
     fn to_string(&self) -> String {
@@ -106,10 +154,22 @@ impl AsRef<[u8]> for ReadBuffer {
     }
 }
 
+impl BlockBuffer {
+    pub fn with_size(size: usize) -> BlockBuffer {
+        BlockBuffer(vec![0; size].into_boxed_slice())
+    }
+}
+
 impl Default for BlockBuffer {
     #[inline]
     fn default() -> BlockBuffer {
-        BlockBuffer(vec![0; BLOCK_SIZE].into_boxed_slice())
+        BlockBuffer::with_size(BLOCK_SIZE)
+    }
+}
+
+impl From<Vec<u8>> for BlockBuffer {
+    fn from(data: Vec<u8>) -> BlockBuffer {
+        BlockBuffer(data.into_boxed_slice())
     }
 }
 
@@ -360,6 +420,213 @@ pub struct Storage<C> {
     crypto: C,
     object: WriteObject,
     capacity: usize,
+    scratch: Vec<u8>,
+    compression: Compression,
+    tuning: Tuning,
+    stats: Arc<Collector>,
+    /// The objects this store and its clones are filling, or are
+    /// still uploading
+    open: Arc<Mutex<HashSet<ObjectId>>>,
+    uploads: Option<Arc<Uploads>>,
+}
+
+/// Uploads sealed objects on threads of their own, or as tasks of an
+/// async backend, while the workers fill the next ones. Sealing waits
+/// for an upload to finish once enough are in flight, and their
+/// buffers are reused.
+struct Uploads {
+    uploader: Uploader,
+    state: Arc<(Mutex<UploadState>, Condvar)>,
+}
+
+enum Uploader {
+    /// Each thread holds one object in flight
+    Threads {
+        sender: Option<crossbeam_channel::Sender<WriteObject>>,
+        threads: Vec<JoinHandle<()>>,
+    },
+    /// Tasks on a runtime, up to `in_flight` at once
+    #[cfg(feature = "async")]
+    Tasks {
+        backend: Arc<dyn AsyncBackend>,
+        runtime: tokio::runtime::Handle,
+        in_flight: usize,
+        stats: Arc<Collector>,
+        open: Arc<Mutex<HashSet<ObjectId>>>,
+    },
+}
+
+#[derive(Default)]
+struct UploadState {
+    pending: usize,
+    /// The first upload that failed, reported by the next seal or
+    /// flush
+    error: Option<BackendError>,
+    spare: Vec<WriteObject>,
+}
+
+impl Uploads {
+    fn start(
+        threads: usize,
+        backend: Arc<dyn Backend>,
+        stats: Arc<Collector>,
+        open: Arc<Mutex<HashSet<ObjectId>>>,
+    ) -> Uploads {
+        let (sender, receiver) = crossbeam_channel::bounded::<WriteObject>(0);
+        let state = Arc::new((Mutex::new(UploadState::default()), Condvar::new()));
+        let threads = (0..threads)
+            .map(|_| {
+                let (receiver, backend, stats, open, state) = (
+                    receiver.clone(),
+                    backend.clone(),
+                    stats.clone(),
+                    open.clone(),
+                    state.clone(),
+                );
+                std::thread::spawn(move || {
+                    for object in receiver.iter() {
+                        let result =
+                            stats.time(Stage::Upload, || backend.write_data_object(&object));
+                        Uploads::finish(object, result, &stats, &open, &state);
+                    }
+                })
+            })
+            .collect();
+
+        Uploads {
+            uploader: Uploader::Threads {
+                sender: Some(sender),
+                threads,
+            },
+            state,
+        }
+    }
+
+    #[cfg(feature = "async")]
+    fn start_tasks(
+        in_flight: usize,
+        backend: Arc<dyn AsyncBackend>,
+        runtime: tokio::runtime::Handle,
+        stats: Arc<Collector>,
+        open: Arc<Mutex<HashSet<ObjectId>>>,
+    ) -> Uploads {
+        Uploads {
+            uploader: Uploader::Tasks {
+                backend,
+                runtime,
+                in_flight,
+                stats,
+                open,
+            },
+            state: Arc::new((Mutex::new(UploadState::default()), Condvar::new())),
+        }
+    }
+
+    /// Count an upload as done, and keep the buffer of `object` for
+    /// the next one.
+    fn finish(
+        object: WriteObject,
+        result: std::result::Result<(), BackendError>,
+        stats: &Collector,
+        open: &Mutex<HashSet<ObjectId>>,
+        state: &(Mutex<UploadState>, Condvar),
+    ) {
+        if result.is_ok() {
+            let size = object.buffer.as_ref().len() as u64;
+            stats.add_transfer(Stage::Upload, size);
+            open.lock().unwrap().remove(&object.id);
+        }
+
+        let (state, done) = state;
+        let mut state = state.lock().unwrap();
+        state.pending -= 1;
+        if let Err(e) = result {
+            state.error.get_or_insert(e);
+        }
+        state.spare.push(object);
+        done.notify_all();
+    }
+
+    /// An empty object of `size` bytes whose buffer was uploaded
+    /// before, if any.
+    fn spare(&self, size: usize) -> WriteObject {
+        let mut state = self.state.0.lock().unwrap();
+        state
+            .spare
+            .pop()
+            .unwrap_or_else(|| Object::with_id(ObjectId::default(), BlockBuffer::with_size(size)))
+    }
+
+    fn send(&self, object: WriteObject) -> Result<()> {
+        {
+            let (state, _done) = &*self.state;
+            let mut state = state.lock().unwrap();
+            #[cfg(feature = "async")]
+            if let Uploader::Tasks { in_flight, .. } = &self.uploader {
+                while state.pending >= *in_flight && state.error.is_none() {
+                    state = _done.wait(state).unwrap();
+                }
+            }
+            if let Some(e) = state.error.take() {
+                return Err(e.into());
+            }
+            state.pending += 1;
+        }
+
+        match &self.uploader {
+            Uploader::Threads { sender, .. } => sender.as_ref().unwrap().send(object).unwrap(),
+            #[cfg(feature = "async")]
+            Uploader::Tasks {
+                backend,
+                runtime,
+                stats,
+                open,
+                ..
+            } => {
+                let (backend, stats, open, state) = (
+                    backend.clone(),
+                    stats.clone(),
+                    open.clone(),
+                    self.state.clone(),
+                );
+                runtime.spawn(async move {
+                    let start = std::time::Instant::now();
+                    let result = backend.write_data_object(&object).await;
+                    stats.add_time(Stage::Upload, start.elapsed());
+                    Uploads::finish(object, result, &stats, &open, &state);
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Wait for all objects sent to be uploaded.
+    fn wait(&self) -> Result<()> {
+        let (state, done) = &*self.state;
+        let mut state = state.lock().unwrap();
+        while state.pending > 0 {
+            state = done.wait(state).unwrap();
+        }
+        match state.error.take() {
+            Some(e) => Err(e.into()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Uploads {
+    fn drop(&mut self) {
+        match &mut self.uploader {
+            Uploader::Threads { sender, threads } => {
+                drop(sender.take());
+                for thread in threads.drain(..) {
+                    let _ = thread.join();
+                }
+            }
+            #[cfg(feature = "async")]
+            Uploader::Tasks { .. } => {}
+        }
+    }
 }
 
 impl<C> Clone for Storage<C>
@@ -369,12 +636,19 @@ where
     fn clone(&self) -> Storage<C> {
         let mut object = self.object.clone();
         object.id.reset(&self.crypto);
+        self.open.lock().unwrap().insert(object.id);
 
         Storage {
             object,
             backend: self.backend.clone(),
             crypto: self.crypto.clone(),
             capacity: self.capacity,
+            scratch: Vec::new(),
+            compression: self.compression,
+            tuning: self.tuning,
+            stats: self.stats.clone(),
+            open: self.open.clone(),
+            uploads: self.uploads.clone(),
         }
     }
 }
@@ -383,18 +657,83 @@ impl<C> Storage<C>
 where
     C: CryptoProvider,
 {
-    pub fn new(backend: Arc<dyn Backend>, crypto: C) -> Storage<C> {
+    pub fn new(backend: Arc<dyn Backend>, crypto: C, stats: Arc<Collector>) -> Storage<C> {
         let mut object = WriteObject::default();
         object.id.reset(&crypto);
 
         let capacity = object.capacity();
+        let open = Arc::new(Mutex::new(HashSet::from([object.id])));
         Storage {
             object,
             backend,
             crypto,
             capacity,
+            scratch: Vec::new(),
+            compression: Compression::default(),
+            tuning: Tuning::default(),
+            stats,
+            open,
+            uploads: None,
         }
     }
+
+    /// Upload objects on `threads` threads of their own, so chunks
+    /// are compressed and encrypted into the next object while the
+    /// last ones upload. Each thread holds one object in flight, so
+    /// this takes that many objects of memory on top of the ones being
+    /// filled. With 0 threads, objects are uploaded as they're filled.
+    pub fn uploads(mut self, threads: usize) -> Storage<C> {
+        self.uploads = match threads {
+            0 => None,
+            threads => Some(Arc::new(Uploads::start(
+                threads,
+                self.backend.clone(),
+                self.stats.clone(),
+                self.open.clone(),
+            ))),
+        };
+        self
+    }
+
+    /// Upload objects through `backend` as tasks on `runtime`, instead
+    /// of on threads, with up to `in_flight` of them at once. Like
+    /// with `uploads`, each takes an object of memory.
+    #[cfg(feature = "async")]
+    pub fn async_uploads(
+        mut self,
+        backend: Arc<dyn AsyncBackend>,
+        runtime: tokio::runtime::Handle,
+        in_flight: usize,
+    ) -> Storage<C> {
+        self.uploads = Some(Arc::new(Uploads::start_tasks(
+            in_flight.max(1),
+            backend,
+            runtime,
+            self.stats.clone(),
+            self.open.clone(),
+        )));
+        self
+    }
+
+    /// Fill objects of `size` bytes, instead of `BLOCK_SIZE`.
+    pub fn object_size(mut self, size: usize) -> Storage<C> {
+        let id = self.object.id;
+        self.object = Object::with_id(id, BlockBuffer::with_size(size));
+        self.capacity = size;
+        self
+    }
+
+    /// Compress chunks with `compression`, instead of LZ4.
+    pub fn compression(mut self, compression: Compression) -> Storage<C> {
+        self.compression = compression;
+        self
+    }
+
+    /// Compress chunks at the chunk level of `tuning`.
+    pub fn tuning(mut self, tuning: Tuning) -> Storage<C> {
+        self.tuning = tuning;
+        self
+    }
 }
 
 impl<C> ObjectStore for Storage<C>
@@ -402,19 +741,76 @@ where
     C: CryptoProvider,
 {
     fn store_chunk(&mut self, hash: &CryptoDigest, data: &[u8]) -> Result<Arc<ChunkPointer>> {
-        let mut compressed = compress::block(&data)?;
-        let size = compressed.len();
+        // the scratch buffer is reused between chunks to avoid
+        // allocating in the hot path
+        let (stats, scratch, compression) = (&self.stats, &mut self.scratch, self.compression);
+        let tuning = &self.tuning;
+        let size = stats.time(Stage::Compress, || {
+            tuning.compress_into(compression, scratch, data)
+        })?;
+        self.write_scratch(hash, size)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.seal()?;
+        match &self.uploads {
+            Some(uploads) => uploads.wait(),
+            None => Ok(()),
+        }
+    }
+
+    fn set_compression(&mut self, compression: Compression) {
+        self.compression = compression;
+    }
+
+    fn compression(&self) -> Option<(Compression, Tuning)> {
+        Some((self.compression, self.tuning))
+    }
+
+    fn store_compressed(
+        &mut self,
+        hash: &CryptoDigest,
+        packed: &[u8],
+    ) -> Result<Arc<ChunkPointer>> {
+        self.store_packed(hash, packed)
+    }
+
+    fn is_open(&self, id: &ObjectId) -> bool {
+        self.open.lock().unwrap().contains(id)
+    }
+}
+
+impl<C> Storage<C>
+where
+    C: CryptoProvider,
+{
+    /// Store a chunk as it was compressed before, like when it's
+    /// moved from another object.
+    pub(crate) fn store_packed(
+        &mut self,
+        hash: &CryptoDigest,
+        packed: &[u8],
+    ) -> Result<Arc<ChunkPointer>> {
+        self.scratch.clear();
+        self.scratch.extend_from_slice(packed);
+        self.write_scratch(hash, packed.len())
+    }
+
+    /// Encrypt the `size` bytes of the scratch buffer into the object
+    /// being filled.
+    fn write_scratch(&mut self, hash: &CryptoDigest, size: usize) -> Result<Arc<ChunkPointer>> {
         let mut offs = self.object.position();
         if offs + size > self.capacity {
-            self.flush()?;
+            self.seal()?;
             offs = self.object.position();
         }
 
-        let tag = self
-            .crypto
-            .encrypt_chunk(&self.object, hash, &mut compressed);
+        let (crypto, object, scratch) = (&self.crypto, &self.object, &mut self.scratch);
+        let tag = self.stats.time(Stage::Encrypt, || {
+            crypto.encrypt_chunk(object, hash, scratch)
+        });
 
-        self.object.write_all(&compressed)?;
+        self.object.write_all(&self.scratch)?;
 
         Ok(Arc::new(ChunkPointer {
             offs: offs as u32,
@@ -425,17 +821,101 @@ where
         }))
     }
 
-    fn flush(&mut self) -> Result<()> {
+    /// Upload the object being filled, or hand it to an upload
+    /// thread, and start the next one.
+    fn seal(&mut self) -> Result<()> {
+        span!(
+            "seal",
+            object = %self.object.id.to_string(),
+            bytes = self.object.position()
+        );
+        trace!(
+            "storing object {} with {} bytes of chunks",
+            self.object.id.to_string(),
+            self.object.position()
+        );
         self.object.finalize(&self.crypto);
-        self.backend.write_object(&self.object)?;
+        match &self.uploads {
+            Some(uploads) => {
+                let object = std::mem::replace(&mut self.object, uploads.spare(self.capacity));
+                uploads.send(object)?;
+            }
+            None => {
+                let (backend, object) = (&self.backend, &self.object);
+                self.stats
+                    .time(Stage::Upload, || backend.write_data_object(object))?;
+                self.stats
+                    .add_transfer(Stage::Upload, self.object.buffer.as_ref().len() as u64);
+                self.open.lock().unwrap().remove(&self.object.id);
+            }
+        }
 
+        let mut open = self.open.lock().unwrap();
         self.object.id.reset(&self.crypto);
+        open.insert(self.object.id);
         self.object.reset_cursor();
 
         Ok(())
     }
 }
 
+/// The part of an object that holds some of its chunks.
+pub(crate) struct ObjectRange {
+    object: Arc<ReadObject>,
+    start: u32,
+}
+
+impl ObjectRange {
+    pub(crate) fn whole(object: Arc<ReadObject>) -> ObjectRange {
+        ObjectRange { object, start: 0 }
+    }
+
+    /// Read the part of object `id` that holds all of `chunks`, or
+    /// all of it if the backend can't do range reads.
+    pub(crate) fn read<'a>(
+        backend: &dyn Backend,
+        id: &ObjectId,
+        chunks: impl IntoIterator<Item = &'a ChunkPointer>,
+    ) -> std::result::Result<ObjectRange, BackendError> {
+        let (start, end) = chunks.into_iter().fold((u32::MAX, 0), |(start, end), cp| {
+            (start.min(cp.offs), end.max(cp.offs + cp.size))
+        });
+
+        if start >= end || !backend.capabilities().range_reads {
+            return Ok(ObjectRange::whole(backend.read_object(id)?));
+        }
+
+        let data = backend.read_range(id, start as u64, (end - start) as usize)?;
+        Ok(ObjectRange {
+            object: Arc::new(Object::with_id(*id, ReadBuffer::new(data))),
+            start,
+        })
+    }
+
+    /// The number of bytes read.
+    #[cfg_attr(not(feature = "fs"), allow(dead_code))]
+    pub(crate) fn size(&self) -> usize {
+        self.object.buffer.as_ref().len()
+    }
+
+    /// Decrypt `chunk`, which has to be in the range, into `target`.
+    pub(crate) fn decrypt_chunk(
+        &self,
+        crypto: &impl CryptoProvider,
+        target: &mut [u8],
+        chunk: &ChunkPointer,
+    ) -> crate::crypto::Result<usize> {
+        let moved = ChunkPointer {
+            offs: chunk.offs - self.start,
+            size: chunk.size,
+            file: chunk.file,
+            hash: chunk.hash,
+            tag: chunk.tag,
+        };
+        crypto.decrypt_chunk(target, &self.object, &moved)
+    }
+}
+
 #[derive(Clone, Default)]
 pub struct NullStorage(pub Arc<Mutex<usize>>);
 
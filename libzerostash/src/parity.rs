@@ -0,0 +1,264 @@
+//! Reed-Solomon erasure coding of objects, so that as many of a group
+//! of data objects and its parity objects as there are parity objects
+//! can be lost, and rebuilt from the rest.
+//!
+//! The code is systematic: the data objects are stored as they are,
+//! and each parity object is a combination of them over GF(2^8), with
+//! the coefficients of a Cauchy matrix. Every square submatrix of a
+//! Cauchy matrix is invertible, so any of the objects of a group that
+//! are as many as its data objects recover the others.
+
+use std::fmt;
+use std::str::FromStr;
+
+/// How many parity objects are written for each group of data
+/// objects, in the form `data+parity`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Scheme {
+    pub data: u8,
+    pub parity: u8,
+}
+
+impl Scheme {
+    pub fn check(&self) -> Result<(), String> {
+        if self.data == 0 || self.parity == 0 {
+            return Err(format!("{} needs data and parity objects", self));
+        }
+        if self.data as usize + self.parity as usize > 256 {
+            return Err(format!("{} has more than 256 objects in a group", self));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Scheme {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}+{}", self.data, self.parity)
+    }
+}
+
+impl FromStr for Scheme {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("parity should be DATA+PARITY, like 10+2: {}", s);
+        let (data, parity) = s.split_once('+').ok_or_else(invalid)?;
+        let scheme = Scheme {
+            data: data.trim().parse().map_err(|_| invalid())?,
+            parity: parity.trim().parse().map_err(|_| invalid())?,
+        };
+        scheme.check()?;
+        Ok(scheme)
+    }
+}
+
+/// The `parity` parity shards of the `data` shards, which are as
+/// long as the longest of them, the rest padded with zeros.
+pub fn encode(data: &[&[u8]], parity: usize) -> Vec<Vec<u8>> {
+    let len = data.iter().map(|d| d.len()).max().unwrap_or(0);
+    (0..parity)
+        .map(|row| {
+            let mut shard = vec![0; len];
+            for (column, shard_data) in data.iter().enumerate() {
+                mul_add(coefficient(data.len(), row, column), shard_data, &mut shard);
+            }
+            shard
+        })
+        .collect()
+}
+
+/// Fill in the missing shards, `data` data shards followed by the
+/// parity shards written for them. Returns `false` if fewer than
+/// `data` shards are left, and nothing can be recovered.
+pub fn reconstruct(shards: &mut [Option<Vec<u8>>], data: usize) -> bool {
+    let present = shards
+        .iter()
+        .enumerate()
+        .filter_map(|(i, s)| s.as_ref().map(|_| i))
+        .take(data)
+        .collect::<Vec<_>>();
+    if present.len() < data {
+        return false;
+    }
+    if shards[..data].iter().all(Option::is_some) {
+        return fill_parity(shards, data);
+    }
+
+    // the rows of the code that produced the present shards, which
+    // are inverted to find the data shards from them
+    let rows = present
+        .iter()
+        .map(|&shard| {
+            (0..data)
+                .map(|column| {
+                    if shard < data {
+                        (shard == column) as u8
+                    } else {
+                        coefficient(data, shard - data, column)
+                    }
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    let inverse = invert(rows);
+
+    let len = present
+        .iter()
+        .map(|&i| shards[i].as_ref().unwrap().len())
+        .max()
+        .unwrap();
+    for missing in 0..data {
+        if shards[missing].is_some() {
+            continue;
+        }
+        let mut shard = vec![0; len];
+        for (&from, c) in present.iter().zip(inverse[missing].iter()) {
+            mul_add(*c, shards[from].as_ref().unwrap(), &mut shard);
+        }
+        shards[missing] = Some(shard);
+    }
+
+    fill_parity(shards, data)
+}
+
+/// Encode the missing parity shards again, from all data shards.
+fn fill_parity(shards: &mut [Option<Vec<u8>>], data: usize) -> bool {
+    if shards[data..].iter().all(Option::is_some) {
+        return true;
+    }
+
+    let (data_shards, parity_shards) = shards.split_at_mut(data);
+    let data_shards = data_shards
+        .iter()
+        .map(|s| s.as_deref().unwrap())
+        .collect::<Vec<_>>();
+    let parity = encode(&data_shards, parity_shards.len());
+    for (shard, encoded) in parity_shards.iter_mut().zip(parity) {
+        shard.get_or_insert(encoded);
+    }
+    true
+}
+
+/// The coefficient of data shard `column` in parity shard `row`.
+fn coefficient(data: usize, row: usize, column: usize) -> u8 {
+    inverse_of(((data + row) as u8) ^ column as u8)
+}
+
+/// Invert the square `matrix`, which has to be invertible, by
+/// Gauss-Jordan elimination.
+fn invert(mut matrix: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+    let n = matrix.len();
+    let mut inverse = (0..n)
+        .map(|i| (0..n).map(|j| (i == j) as u8).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+
+    for column in 0..n {
+        let pivot = (column..n).find(|&r| matrix[r][column] != 0).unwrap();
+        matrix.swap(column, pivot);
+        inverse.swap(column, pivot);
+
+        let scale = inverse_of(matrix[column][column]);
+        for j in 0..n {
+            matrix[column][j] = mul(matrix[column][j], scale);
+            inverse[column][j] = mul(inverse[column][j], scale);
+        }
+        for row in (0..n).filter(|&r| r != column) {
+            let factor = matrix[row][column];
+            if factor == 0 {
+                continue;
+            }
+            for j in 0..n {
+                matrix[row][j] ^= mul(factor, matrix[column][j]);
+                inverse[row][j] ^= mul(factor, inverse[column][j]);
+            }
+        }
+    }
+    inverse
+}
+
+/// `dst += c * src`, bytewise, with `src` padded with zeros.
+fn mul_add(c: u8, src: &[u8], dst: &mut [u8]) {
+    let mut table = [0; 256];
+    for (b, product) in table.iter_mut().enumerate() {
+        *product = mul(c, b as u8);
+    }
+    for (d, s) in dst.iter_mut().zip(src) {
+        *d ^= table[*s as usize];
+    }
+}
+
+/// Multiplication in GF(2^8) with the AES reduction polynomial.
+fn mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            product ^= a;
+        }
+        let carry = a & 0x80 != 0;
+        a <<= 1;
+        if carry {
+            a ^= 0x1b;
+        }
+        b >>= 1;
+    }
+    product
+}
+
+/// `1 / a`, as `a` to the power of 254.
+fn inverse_of(a: u8) -> u8 {
+    let mut inverse = 1;
+    for _ in 0..254 {
+        inverse = mul(inverse, a);
+    }
+    inverse
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn any_lost_shards_up_to_the_parity_are_recovered() {
+        use super::*;
+        use itertools::Itertools;
+
+        let data = (0..5u8)
+            .map(|i| {
+                (0..100)
+                    .map(|j| i.wrapping_mul(37) ^ j)
+                    .collect::<Vec<u8>>()
+            })
+            .collect::<Vec<_>>();
+        let refs = data.iter().map(Vec::as_slice).collect::<Vec<_>>();
+        let parity = encode(&refs, 3);
+        let all = data
+            .iter()
+            .chain(parity.iter())
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for lost in (0..all.len()).combinations(3) {
+            let mut shards = all.iter().cloned().map(Some).collect::<Vec<_>>();
+            for i in lost.iter() {
+                shards[*i] = None;
+            }
+            assert!(reconstruct(&mut shards, 5));
+            assert_eq!(
+                shards.into_iter().map(Option::unwrap).collect::<Vec<_>>(),
+                all
+            );
+        }
+
+        let mut too_many = all.iter().cloned().map(Some).collect::<Vec<_>>();
+        too_many[..4].iter_mut().for_each(|s| *s = None);
+        assert!(!reconstruct(&mut too_many, 5));
+
+        assert_eq!(
+            "10+2".parse(),
+            Ok(Scheme {
+                data: 10,
+                parity: 2
+            })
+        );
+        assert!("10+0".parse::<Scheme>().is_err());
+        assert!("200+100".parse::<Scheme>().is_err());
+    }
+}
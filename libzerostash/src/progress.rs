@@ -0,0 +1,243 @@
+//! Progress reporting of long running operations.
+//!
+//! A frontend implements `Progress` once, and registers it with
+//! `Stash::set_progress`. Backups, restores, verification, and
+//! reading and writing metadata all report through the same trait.
+//!
+//! Events arrive from worker threads, so implementations should be
+//! cheap, and must not block.
+
+use crate::stats::Stage;
+
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Phase {
+    ReadMetadata,
+    Store,
+    Restore,
+    Verify,
+    Commit,
+}
+
+pub trait Progress: Send + Sync {
+    /// A new phase started. `total` is the number of items it will
+    /// process, if known in advance.
+    fn phase(&self, _phase: Phase, _total: Option<u64>) {}
+
+    /// An item has been processed: a file when storing or restoring,
+    /// an object when verifying. `bytes` is its size.
+    fn item(&self, _bytes: u64) {}
+
+    /// `bytes` were moved to or from the backend, during either the
+    /// `Upload` or the `Download` stage. Uploads are of whole data
+    /// objects.
+    fn transfer(&self, _stage: Stage, _bytes: u64) {}
+
+    /// The walk of a backup found another file to store, before the
+    /// workers get to it.
+    fn discovered(&self) {}
+
+    /// Work on the file `name` started, when storing or restoring.
+    fn file(&self, _name: &str) {}
+
+    /// A chunk of `bytes` was split off a file, which is `new`, or
+    /// was already stored and is deduplicated.
+    fn chunk(&self, _bytes: u64, _new: bool) {}
+
+    /// `elapsed` was spent in `stage` on one of the threads.
+    fn time(&self, _stage: Stage, _elapsed: Duration) {}
+
+    /// A request to the backend failed, after any retries, other than
+    /// for an object that isn't there. `operation` is like `read` or
+    /// `write`.
+    fn backend_error(&self, _operation: &'static str) {}
+}
+
+/// Ignores all progress.
+impl Progress for () {}
+
+/// Reports to both, like to metrics and a progress bar.
+impl<A: Progress, B: Progress> Progress for (A, B) {
+    fn phase(&self, phase: Phase, total: Option<u64>) {
+        self.0.phase(phase, total);
+        self.1.phase(phase, total);
+    }
+
+    fn item(&self, bytes: u64) {
+        self.0.item(bytes);
+        self.1.item(bytes);
+    }
+
+    fn transfer(&self, stage: Stage, bytes: u64) {
+        self.0.transfer(stage, bytes);
+        self.1.transfer(stage, bytes);
+    }
+
+    fn discovered(&self) {
+        self.0.discovered();
+        self.1.discovered();
+    }
+
+    fn file(&self, name: &str) {
+        self.0.file(name);
+        self.1.file(name);
+    }
+
+    fn chunk(&self, bytes: u64, new: bool) {
+        self.0.chunk(bytes, new);
+        self.1.chunk(bytes, new);
+    }
+
+    fn time(&self, stage: Stage, elapsed: Duration) {
+        self.0.time(stage, elapsed);
+        self.1.time(stage, elapsed);
+    }
+
+    fn backend_error(&self, operation: &'static str) {
+        self.0.backend_error(operation);
+        self.1.backend_error(operation);
+    }
+}
+
+impl<P: Progress + ?Sized> Progress for Arc<P> {
+    fn phase(&self, phase: Phase, total: Option<u64>) {
+        (**self).phase(phase, total)
+    }
+
+    fn item(&self, bytes: u64) {
+        (**self).item(bytes)
+    }
+
+    fn transfer(&self, stage: Stage, bytes: u64) {
+        (**self).transfer(stage, bytes)
+    }
+
+    fn discovered(&self) {
+        (**self).discovered()
+    }
+
+    fn file(&self, name: &str) {
+        (**self).file(name)
+    }
+
+    fn chunk(&self, bytes: u64, new: bool) {
+        (**self).chunk(bytes, new)
+    }
+
+    fn time(&self, stage: Stage, elapsed: Duration) {
+        (**self).time(stage, elapsed)
+    }
+
+    fn backend_error(&self, operation: &'static str) {
+        (**self).backend_error(operation)
+    }
+}
+
+/// Reports to a progress that can be replaced while it's shared, like
+/// by the backend of a stash, which outlives `Stash::set_progress`.
+/// Clones report to the same. Only errors are reported.
+#[derive(Clone)]
+pub(crate) struct Shared(Arc<RwLock<Arc<dyn Progress>>>);
+
+impl Shared {
+    pub(crate) fn new() -> Shared {
+        Shared(Arc::new(RwLock::new(Arc::new(()))))
+    }
+
+    pub(crate) fn set(&self, progress: Arc<dyn Progress>) {
+        *self.0.write().unwrap() = progress;
+    }
+}
+
+impl Progress for Shared {
+    fn backend_error(&self, operation: &'static str) {
+        self.0.read().unwrap().backend_error(operation)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn collector_forwards_progress() {
+        use super::*;
+        use crate::stats::Collector;
+        use std::sync::atomic::{AtomicU64, Ordering};
+        use std::sync::Arc;
+
+        #[derive(Default)]
+        struct Counter {
+            items: AtomicU64,
+            bytes: AtomicU64,
+        }
+
+        impl Progress for Counter {
+            fn item(&self, _bytes: u64) {
+                self.items.fetch_add(1, Ordering::Relaxed);
+            }
+
+            fn transfer(&self, _stage: Stage, bytes: u64) {
+                self.bytes.fetch_add(bytes, Ordering::Relaxed);
+            }
+        }
+
+        let counter = Arc::new(Counter::default());
+        let stats = Collector::new(counter.clone());
+        stats.add_file(10);
+        stats.add_file(20);
+        stats.add_transfer(Stage::Upload, 100);
+
+        assert_eq!(counter.items.load(Ordering::Relaxed), 2);
+        assert_eq!(counter.bytes.load(Ordering::Relaxed), 100);
+        assert_eq!(stats.summary(Default::default()).bytes, 30);
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn backups_report_files_and_chunks() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use crate::stash::{BackupOptions, Stash, StashKey};
+        use std::sync::atomic::{AtomicU64, Ordering};
+        use std::sync::{Arc, Mutex};
+
+        #[derive(Default)]
+        struct Events {
+            discovered: AtomicU64,
+            names: Mutex<Vec<String>>,
+            chunks: [AtomicU64; 2],
+        }
+
+        impl Progress for Events {
+            fn discovered(&self) {
+                self.discovered.fetch_add(1, Ordering::Relaxed);
+            }
+
+            fn file(&self, name: &str) {
+                self.names.lock().unwrap().push(name.into());
+            }
+
+            fn chunk(&self, _bytes: u64, new: bool) {
+                self.chunks[new as usize].fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let key = StashKey::open_stash("progress", "test").unwrap();
+        let mut stash = Stash::new(Arc::new(MemoryBackend::default()), key);
+        let events = Arc::new(Events::default());
+        stash.set_progress(Arc::new((events.clone(), events.clone())));
+
+        let paths = ["tests/data/100_random_1k"];
+        stash.backup(&paths, &BackupOptions::default()).unwrap();
+        stash.backup(&paths, &BackupOptions::default()).unwrap();
+
+        // each event reached both
+        assert_eq!(events.discovered.load(Ordering::Relaxed), 400);
+        assert_eq!(events.names.lock().unwrap().len(), 400);
+        // the second backup only finds chunks the first stored
+        let chunks = 2 * stash.chunk_index().len() as u64;
+        assert_eq!(events.chunks[1].load(Ordering::Relaxed), chunks);
+        assert_eq!(events.chunks[0].load(Ordering::Relaxed), chunks);
+    }
+}
@@ -0,0 +1,303 @@
+//! Asking the user for credentials and confirmations.
+//!
+//! The library never reads the terminal itself. Anything that needs
+//! input from the user goes through a `SecretPrompt`, so frontends
+//! decide how to ask: a terminal prompt, `pinentry`, or a dialog of an
+//! embedding GUI through `Callback`.
+//!
+//! A prompt that was dismissed by the user should fail with
+//! `io::ErrorKind::Interrupted`, which is reported as `Cancelled`.
+
+use crate::crypto::{shamir::Share, StashKey};
+use crate::error::{Result, ZerostashError};
+
+use secrecy::{ExposeSecret, SecretString};
+
+use std::convert::TryFrom;
+use std::io;
+
+pub trait SecretPrompt: Send + Sync {
+    /// Ask for a value that can be shown while typing, like a
+    /// username.
+    fn ask(&self, prompt: &str) -> io::Result<String>;
+
+    /// Ask for a value that must not be shown, like a password.
+    fn ask_secret(&self, prompt: &str) -> io::Result<SecretString>;
+
+    /// Ask for a yes or no answer.
+    fn confirm(&self, prompt: &str) -> io::Result<bool>;
+}
+
+/// Answers every prompt by calling a function with the prompt text.
+///
+/// Confirmations are accepted if the answer is "y" or "yes".
+pub struct Callback<F>(pub F);
+
+impl<F> SecretPrompt for Callback<F>
+where
+    F: Fn(&str) -> io::Result<String> + Send + Sync,
+{
+    fn ask(&self, prompt: &str) -> io::Result<String> {
+        (self.0)(prompt)
+    }
+
+    fn ask_secret(&self, prompt: &str) -> io::Result<SecretString> {
+        (self.0)(prompt).map(SecretString::new)
+    }
+
+    fn confirm(&self, prompt: &str) -> io::Result<bool> {
+        let answer = (self.0)(prompt)?.trim().to_lowercase();
+        Ok(answer == "y" || answer == "yes")
+    }
+}
+
+/// Asks through a `pinentry` program, using the Assuan protocol of
+/// GnuPG.
+#[cfg(not(target_arch = "wasm32"))]
+pub struct Pinentry {
+    program: String,
+    description: Option<String>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Pinentry {
+    pub fn new() -> Pinentry {
+        Pinentry::with_program("pinentry")
+    }
+
+    pub fn with_program(program: impl Into<String>) -> Pinentry {
+        Pinentry {
+            program: program.into(),
+            description: None,
+        }
+    }
+
+    /// Text shown above the prompt in the dialog.
+    pub fn description(mut self, description: impl Into<String>) -> Pinentry {
+        self.description = Some(description.into());
+        self
+    }
+
+    /// Run a session with `commands`, and return the data sent back
+    /// for the last one.
+    fn session(&self, commands: &[String]) -> io::Result<String> {
+        use std::io::{BufRead, BufReader, Write};
+        use std::process::{Command, Stdio};
+
+        let mut child = Command::new(&self.program)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let mut stdin = child.stdin.take().unwrap();
+        let mut stdout = BufReader::new(child.stdout.take().unwrap());
+
+        let mut data = String::new();
+        let mut response = |data: &mut String| -> io::Result<()> {
+            let mut line = String::new();
+            loop {
+                line.clear();
+                if stdout.read_line(&mut line)? == 0 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+
+                let line = line.trim_end_matches(&['\r', '\n'][..]);
+                if line == "OK" || line.starts_with("OK ") {
+                    return Ok(());
+                } else if let Some(d) = line.strip_prefix("D ") {
+                    data.push_str(&unescape(d));
+                } else if line.starts_with("ERR ") {
+                    return Err(pinentry_error(line));
+                }
+            }
+        };
+
+        // greeting
+        response(&mut data)?;
+
+        let mut result = Ok(());
+        for command in commands.iter() {
+            data.clear();
+            result = writeln!(stdin, "{}", command).and_then(|_| response(&mut data));
+            if result.is_err() {
+                break;
+            }
+        }
+
+        let _ = writeln!(stdin, "BYE");
+        let _ = child.wait();
+
+        result.map(|_| data)
+    }
+
+    fn commands(&self, prompt: &str, last: &str) -> Vec<String> {
+        let mut commands = vec![];
+        if let Some(desc) = &self.description {
+            commands.push(format!("SETDESC {}", escape(desc)));
+        }
+        commands.push(format!("SETPROMPT {}", escape(prompt)));
+        commands.push(last.into());
+        commands
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Default for Pinentry {
+    fn default() -> Pinentry {
+        Pinentry::new()
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl SecretPrompt for Pinentry {
+    fn ask(&self, prompt: &str) -> io::Result<String> {
+        self.session(&self.commands(prompt, "GETPIN"))
+    }
+
+    fn ask_secret(&self, prompt: &str) -> io::Result<SecretString> {
+        self.ask(prompt).map(SecretString::new)
+    }
+
+    fn confirm(&self, prompt: &str) -> io::Result<bool> {
+        let commands = vec![format!("SETDESC {}", escape(prompt)), "CONFIRM".into()];
+        match self.session(&commands) {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn pinentry_error(line: &str) -> io::Error {
+    // the low 16 bits of the code are the gpg-error code, 99 is
+    // GPG_ERR_CANCELED
+    let code = line[4..]
+        .split(' ')
+        .next()
+        .and_then(|c| c.parse::<u32>().ok())
+        .unwrap_or_default();
+
+    match code & 0xffff {
+        99 => io::Error::new(io::ErrorKind::Interrupted, line.to_string()),
+        _ => io::Error::other(line.to_string()),
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn escape(s: &str) -> String {
+    s.replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn unescape(s: &str) -> String {
+    let mut out = Vec::with_capacity(s.len());
+    let bytes = s.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => {
+                out.push(b);
+                i += 3;
+                continue;
+            }
+            _ => out.push(bytes[i]),
+        }
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Ask for a username and password.
+pub fn ask_credentials(prompt: &dyn SecretPrompt) -> Result<(String, SecretString)> {
+    let username = prompt.ask("Username: ").map_err(interrupted)?;
+    let password = prompt.ask_secret("Password: ").map_err(interrupted)?;
+
+    Ok((username, password))
+}
+
+/// Ask for shares of a master key, one by one, until there are as
+/// many as their threshold.
+pub fn ask_shares(prompt: &dyn SecretPrompt) -> Result<Vec<Share>> {
+    let mut shares: Vec<Share> = vec![];
+    loop {
+        let answer = prompt
+            .ask_secret(&format!("Share {}: ", shares.len() + 1))
+            .map_err(interrupted)?;
+        shares.push(Share::try_from(answer.expose_secret().clone())?);
+
+        if shares.len() >= shares[0].threshold() as usize {
+            return Ok(shares);
+        }
+    }
+}
+
+/// Ask for a username and password, and derive the key of the
+/// stash from them with the default `Kdf`.
+pub fn ask_stash_key(prompt: &dyn SecretPrompt) -> Result<StashKey> {
+    let (username, password) = ask_credentials(prompt)?;
+
+    Ok(StashKey::open_stash(username, password.expose_secret())?)
+}
+
+fn interrupted(e: io::Error) -> ZerostashError {
+    match e.kind() {
+        io::ErrorKind::Interrupted => ZerostashError::Cancelled,
+        _ => e.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn callback_answers_prompts() {
+        use super::*;
+
+        let prompt = Callback(|p: &str| match p {
+            "Username: " => Ok("user".to_string()),
+            "Password: " => Ok("password".to_string()),
+            _ => Ok("Yes\n".to_string()),
+        });
+        assert!(prompt.confirm("Wipe the stash?").unwrap());
+
+        let key = ask_stash_key(&prompt).unwrap();
+        let expected = StashKey::open_stash("user", "password").unwrap();
+        assert_eq!(
+            key.root_object_id().unwrap(),
+            expected.root_object_id().unwrap()
+        );
+
+        let shares = crate::crypto::shamir::split(&expected, 2, 3).unwrap();
+        let answers = shares.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let prompt = Callback(move |p: &str| match p {
+            "Share 1: " => Ok(answers[2].clone()),
+            "Share 2: " => Ok(answers[0].clone()),
+            _ => Err(io::ErrorKind::InvalidInput.into()),
+        });
+        assert_eq!(ask_shares(&prompt).unwrap().len(), 2);
+
+        let dismissed = Callback(|_: &str| Err(io::ErrorKind::Interrupted.into()));
+        assert!(matches!(
+            ask_stash_key(&dismissed),
+            Err(ZerostashError::Cancelled)
+        ));
+    }
+
+    #[test]
+    #[cfg(not(target_arch = "wasm32"))]
+    fn assuan_escaping_roundtrips() {
+        use super::*;
+
+        let text = "100% sure\nreally";
+        assert_eq!(escape(text), "100%25 sure%0Areally");
+        assert_eq!(unescape(&escape(text)), text);
+        assert_eq!(unescape("trailing %2"), "trailing %2");
+    }
+}
@@ -1,36 +1,39 @@
 #![allow(unused)]
 
+use crate::splitter::ChunkSizes;
+
 use seahash::SeaHasher;
 use std::hash::Hasher;
 
 const ROLLSUM_CHAR_OFFSET: u32 = 31;
-const BLOBBITS: u32 = (13);
-const BLOBSIZE: u32 = (1 << BLOBBITS);
 const WINDOWBITS: u32 = (6);
 const WINDOWSIZE: u32 = (1 << WINDOWBITS);
 
 pub trait Rollsum {
-    fn new() -> Self;
+    fn new(sizes: &ChunkSizes) -> Self;
     fn find_offset(&mut self, buf: &[u8]) -> usize;
 }
 
-#[derive(Default)]
-pub struct SeaSplit;
+pub struct SeaSplit {
+    sizes: ChunkSizes,
+}
 
 impl Rollsum for SeaSplit {
-    fn new() -> Self {
-        Self::default()
+    fn new(sizes: &ChunkSizes) -> Self {
+        SeaSplit { sizes: *sizes }
     }
 
     fn find_offset(&mut self, buf: &[u8]) -> usize {
         let mut hasher = SeaHasher::default();
+        let buf = &buf[..buf.len().min(self.sizes.max)];
+        let mask = (1u64 << self.sizes.mask_bits) - 1;
 
         let mut last = 0;
         for limit in (0..buf.len()).step_by(16) {
             hasher.write(&buf[last..limit]);
             let output = hasher.finish();
 
-            if (output & ((u64::from(BLOBSIZE)) - 1)) == ((u64::from(BLOBSIZE)) - 1) {
+            if limit >= self.sizes.min && (output & mask) == mask {
                 return limit + 1;
             } else {
                 last = limit;
@@ -41,6 +44,7 @@ impl Rollsum for SeaSplit {
 }
 
 pub struct BupSplit {
+    mask: u32,
     s1: u32,
     s2: u32,
     window: [u8; WINDOWSIZE as usize],
@@ -53,7 +57,7 @@ impl BupSplit {
         self.s1 = self.s1.wrapping_add(u32::from(add.wrapping_sub(drop)));
         self.s2 = self.s2.wrapping_add(
             self.s1
-                .wrapping_sub((WINDOWSIZE * (u32::from(drop) + ROLLSUM_CHAR_OFFSET)) as u32),
+                .wrapping_sub(WINDOWSIZE * (u32::from(drop) + ROLLSUM_CHAR_OFFSET)),
         );
     }
 
@@ -71,8 +75,9 @@ impl BupSplit {
 }
 
 impl Rollsum for BupSplit {
-    fn new() -> Self {
+    fn new(sizes: &ChunkSizes) -> Self {
         BupSplit {
+            mask: (1 << sizes.mask_bits) - 1,
             s1: WINDOWSIZE * ROLLSUM_CHAR_OFFSET,
             s2: WINDOWSIZE * (WINDOWSIZE - 1) * ROLLSUM_CHAR_OFFSET,
             wofs: 0,
@@ -84,7 +89,7 @@ impl Rollsum for BupSplit {
         for (i, v) in buf.iter().enumerate() {
             self.roll(*v);
 
-            if (self.s2 & (BLOBSIZE - 1)) == (BLOBSIZE - 1) {
+            if (self.s2 & self.mask) == self.mask {
                 return i + 1;
             }
         }
@@ -94,6 +99,7 @@ impl Rollsum for BupSplit {
 
 #[cfg(test)]
 mod tests {
+    #[cfg(feature = "nightly")]
     extern crate test;
     const SELFTEST_SIZE: usize = 100000;
     use super::WINDOWSIZE;
@@ -101,9 +107,10 @@ mod tests {
 
     fn rollsum_sum(buf: &[u8], ofs: usize, len: usize) -> u32 {
         use super::{BupSplit, Rollsum};
-        let mut r = BupSplit::new();
-        for count in ofs..len {
-            r.roll(buf[count]);
+        use crate::splitter::Chunker;
+        let mut r = BupSplit::new(&Chunker::SeaSplit.default_sizes());
+        for byte in &buf[ofs..len] {
+            r.roll(*byte);
         }
         r.digest()
     }
@@ -136,6 +143,7 @@ mod tests {
         assert_ne!(sum3a, sum3b);
     }
 
+    #[cfg(feature = "nightly")]
     #[bench]
     fn bench_rollsum(b: &mut test::Bencher) {
         let mut buf = [0; SELFTEST_SIZE];
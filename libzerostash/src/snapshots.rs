@@ -0,0 +1,263 @@
+use crate::crypto::{self, CryptoDigest};
+use crate::files::Entry;
+use crate::meta::{FieldReader, FieldWriter, MetaObjectField, WriteError};
+use crate::namespaces::{NamespaceError, SignedManifest, Writer};
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Key/value metadata of a snapshot, like the host or the policy of
+/// the backup.
+pub type Labels = BTreeMap<String, String>;
+
+/// Labels written as `KEY=VALUE`, like on the command line.
+pub fn parse_labels(labels: &[impl AsRef<str>]) -> Result<Labels, String> {
+    labels
+        .iter()
+        .map(|label| match label.as_ref().split_once('=') {
+            Some((key, value)) if !key.is_empty() => Ok((key.into(), value.into())),
+            _ => Err(format!("expected KEY=VALUE, not {}", label.as_ref())),
+        })
+        .collect()
+}
+
+/// The state of the backed up paths at the time of a backup run.
+#[derive(Clone)]
+pub struct Snapshot {
+    pub id: u64,
+    pub unix_secs: u64,
+    pub paths: Vec<String>,
+    pub tags: Vec<String>,
+    pub labels: Labels,
+    pub files: Vec<Arc<Entry>>,
+    /// The writer's signature, in stashes shared through namespaces
+    pub manifest: Option<SignedManifest>,
+}
+
+impl Snapshot {
+    pub fn size(&self) -> u64 {
+        self.files.iter().map(|f| f.size).sum()
+    }
+
+    /// If the snapshot has all `tags`, and all `labels` with the same
+    /// values.
+    pub fn matches(&self, tags: &[impl AsRef<str>], labels: &Labels) -> bool {
+        tags.iter()
+            .all(|tag| self.tags.iter().any(|t| t == tag.as_ref()))
+            && labels
+                .iter()
+                .all(|(key, value)| self.labels.get(key) == Some(value))
+    }
+
+    /// Identifies the snapshot by its time, paths and contents,
+    /// independently of its id and the key of its stash.
+    pub fn digest(&self) -> CryptoDigest {
+        let mut content = self.unix_secs.to_le_bytes().to_vec();
+        for path in self.paths.iter() {
+            content.extend_from_slice(path.as_bytes());
+            content.push(0);
+        }
+
+        let mut files = self.files.iter().collect::<Vec<_>>();
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+        for file in files {
+            content.extend_from_slice(file.name.as_bytes());
+            content.push(0);
+            for (_, cp) in file.chunks.iter() {
+                content.extend_from_slice(&cp.hash);
+            }
+        }
+
+        crypto::chunk_hash(&content)
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct SnapshotStore(Arc<Mutex<Vec<Arc<Snapshot>>>>);
+
+impl SnapshotStore {
+    /// All snapshots, oldest first
+    pub fn list(&self) -> Vec<Arc<Snapshot>> {
+        self.0.lock().unwrap().clone()
+    }
+
+    pub fn get(&self, id: u64) -> Option<Arc<Snapshot>> {
+        self.0.lock().unwrap().iter().find(|s| s.id == id).cloned()
+    }
+
+    pub fn latest(&self) -> Option<Arc<Snapshot>> {
+        self.0.lock().unwrap().last().cloned()
+    }
+
+    pub fn push(
+        &self,
+        paths: Vec<String>,
+        tags: Vec<String>,
+        labels: Labels,
+        files: Vec<Arc<Entry>>,
+    ) -> Arc<Snapshot> {
+        let unix_secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+
+        self.push_at(unix_secs, paths, tags, labels, files)
+    }
+
+    /// Add a snapshot taken at `unix_secs`, e.g. when importing from
+    /// another tool.
+    pub fn push_at(
+        &self,
+        unix_secs: u64,
+        paths: Vec<String>,
+        tags: Vec<String>,
+        labels: Labels,
+        files: Vec<Arc<Entry>>,
+    ) -> Arc<Snapshot> {
+        let mut snapshots = self.0.lock().unwrap();
+        let snapshot = Arc::new(Snapshot {
+            id: snapshots.last().map(|s| s.id + 1).unwrap_or(1),
+            unix_secs,
+            paths,
+            tags,
+            labels,
+            files,
+            manifest: None,
+        });
+
+        snapshots.push(snapshot.clone());
+        snapshot
+    }
+
+    /// Sign snapshot `id` as `writer`, following the last snapshot
+    /// signed in the same namespace.
+    pub fn sign(&self, id: u64, writer: &Writer) -> Result<Arc<Snapshot>, NamespaceError> {
+        let manifest = {
+            let snapshots = self.0.lock().unwrap();
+            let pos = snapshots.iter().position(|s| s.id == id).unwrap();
+            let previous = snapshots[..pos]
+                .iter()
+                .rev()
+                .filter_map(|s| s.manifest.as_ref())
+                .find(|m| m.namespace == writer.namespace);
+            writer.sign(&snapshots[pos], previous)?
+        };
+
+        Ok(self.set_manifest(id, manifest))
+    }
+
+    /// Remove the snapshots `keep` is false for, and return them.
+    pub(crate) fn retain(&self, keep: impl Fn(&Snapshot) -> bool) -> Vec<Arc<Snapshot>> {
+        let mut removed = vec![];
+        self.0.lock().unwrap().retain(|s| {
+            keep(s) || {
+                removed.push(s.clone());
+                false
+            }
+        });
+        removed
+    }
+
+    /// Replace the files of every snapshot by what `f` returns for
+    /// them.
+    pub(crate) fn map_files(&self, mut f: impl FnMut(&Arc<Entry>) -> Arc<Entry>) {
+        for snapshot in self.0.lock().unwrap().iter_mut() {
+            let files = snapshot.files.iter().map(&mut f).collect();
+            Arc::make_mut(snapshot).files = files;
+        }
+    }
+
+    /// A store of `snapshots`, like the ones a commit added.
+    pub(crate) fn with(snapshots: Vec<Arc<Snapshot>>) -> SnapshotStore {
+        SnapshotStore(Arc::new(Mutex::new(snapshots)))
+    }
+
+    /// Put the snapshots back in the order they were taken, which
+    /// reading the ones replaced by later commits changes.
+    pub(crate) fn sort(&self) {
+        self.0.lock().unwrap().sort_by_key(|s| s.id);
+    }
+
+    /// Attach a manifest signed elsewhere, like in the stash a
+    /// snapshot was copied from.
+    pub(crate) fn set_manifest(&self, id: u64, manifest: SignedManifest) -> Arc<Snapshot> {
+        let mut snapshots = self.0.lock().unwrap();
+        let snapshot = snapshots.iter_mut().find(|s| s.id == id).unwrap();
+        Arc::make_mut(snapshot).manifest = Some(manifest);
+        snapshot.clone()
+    }
+}
+
+// A snapshot can hold more files than fit in a metadata object, so
+// files are serialized as separate records following their snapshot
+#[derive(Serialize, Deserialize)]
+pub enum SnapshotRecord {
+    Snapshot {
+        id: u64,
+        unix_secs: u64,
+        paths: Vec<String>,
+        // added after the first release of the format
+        #[serde(default)]
+        tags: Vec<String>,
+        #[serde(default, skip_serializing_if = "Labels::is_empty")]
+        labels: Labels,
+        #[serde(default)]
+        manifest: Option<Box<SignedManifest>>,
+    },
+    File(Arc<Entry>),
+}
+
+impl MetaObjectField for SnapshotStore {
+    type Item = SnapshotRecord;
+
+    fn serialize(&self, mw: &mut impl FieldWriter) -> Result<(), WriteError> {
+        for s in self.0.lock().unwrap().iter() {
+            mw.write_next(SnapshotRecord::Snapshot {
+                id: s.id,
+                unix_secs: s.unix_secs,
+                paths: s.paths.clone(),
+                tags: s.tags.clone(),
+                labels: s.labels.clone(),
+                manifest: s.manifest.clone().map(Box::new),
+            })?;
+
+            for f in s.files.iter() {
+                mw.write_next(SnapshotRecord::File(f.clone()))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn deserialize(&self, mw: &mut impl FieldReader<Self::Item>) {
+        // a field that spans multiple objects is read in pieces, so
+        // the first files of a piece may belong to the last snapshot
+        // read before
+        let mut snapshots = self.0.lock().unwrap();
+        while let Ok(record) = mw.read_next() {
+            match record {
+                SnapshotRecord::Snapshot {
+                    id,
+                    unix_secs,
+                    paths,
+                    tags,
+                    labels,
+                    manifest,
+                } => snapshots.push(Arc::new(Snapshot {
+                    id,
+                    unix_secs,
+                    paths,
+                    tags,
+                    labels,
+                    files: vec![],
+                    manifest: manifest.map(|m| *m),
+                })),
+                SnapshotRecord::File(f) => {
+                    if let Some(s) = snapshots.last_mut() {
+                        Arc::make_mut(s).files.push(f);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -1,10 +1,519 @@
+use crate::compress::CompressionRules;
 use crate::crypto::{chunk_hash, CryptoDigest};
-use crate::rollsum::Rollsum;
+use crate::rollsum::{Rollsum, SeaSplit};
+use crate::BLOCK_SIZE;
 
+use crossbeam_utils::thread;
+use zeroize::Zeroize;
+
+use std::fmt;
 use std::marker::PhantomData;
+use std::sync::Arc;
+
+/// Files are chunked in segments of about this size on all threads,
+/// if they're at least two segments large
+const SEGMENT_SIZE: usize = 16 * 1024 * 1024;
+
+/// How many chunks past the end of its segment a thread cuts, for the
+/// next segment to meet its cuts in
+const SYNC_CHUNKS: usize = 8;
+
+/// The start, hash and contents of a chunk
+pub type Chunk<'file> = (u64, CryptoDigest, &'file [u8]);
+
+/// How files are split into chunks. It's recorded in the stash, as
+/// chunks only deduplicate against the ones split the same way.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Chunker {
+    #[serde(rename = "seasplit-13")]
+    SeaSplit,
+    #[serde(rename = "fastcdc")]
+    FastCdc,
+    /// Blocks of the target size, for databases and disk images,
+    /// which change in place
+    #[serde(rename = "fixed")]
+    Fixed,
+    /// A buzhash with a table derived from the stash key, so the
+    /// sizes of objects don't tell where chunks were cut
+    #[serde(rename = "buzhash")]
+    Buzhash,
+}
+
+impl Default for Chunker {
+    /// The chunker of stashes from before it could be selected
+    fn default() -> Chunker {
+        Chunker::SeaSplit
+    }
+}
+
+impl Chunker {
+    pub const ALL: [Chunker; 4] = [
+        Chunker::SeaSplit,
+        Chunker::FastCdc,
+        Chunker::Fixed,
+        Chunker::Buzhash,
+    ];
+
+    /// The name of the chunker in the format of the stash
+    pub fn name(self) -> &'static str {
+        match self {
+            Chunker::SeaSplit => "seasplit-13",
+            Chunker::FastCdc => "fastcdc",
+            Chunker::Fixed => "fixed",
+            Chunker::Buzhash => "buzhash",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Chunker> {
+        Chunker::ALL.iter().copied().find(|c| c.name() == name)
+    }
+
+    /// The sizes of stashes that don't record any.
+    pub fn default_sizes(self) -> ChunkSizes {
+        match self {
+            Chunker::SeaSplit => ChunkSizes {
+                min: 0,
+                target: 8 * 1024,
+                max: MAX_CHUNK_SIZE,
+                mask_bits: 13,
+            },
+            Chunker::FastCdc | Chunker::Buzhash => ChunkSizes {
+                min: 2 * 1024,
+                target: 8 * 1024,
+                max: 64 * 1024,
+                mask_bits: 13,
+            },
+            Chunker::Fixed => ChunkSizes::fixed(8 * 1024),
+        }
+    }
+}
+
+/// A chunker, and the sizes it cuts chunks at.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Chunking {
+    pub chunker: Chunker,
+    pub sizes: ChunkSizes,
+    /// The table of `Chunker::Buzhash`, which the stash derives from
+    /// its key
+    pub table: Option<Arc<BuzhashTable>>,
+    /// The number of threads large files are chunked on, and new
+    /// chunks compressed on. The chunks are the same as on one thread.
+    pub threads: usize,
+}
+
+impl Default for Chunking {
+    fn default() -> Chunking {
+        Chunking::new(Chunker::default())
+    }
+}
+
+impl Chunking {
+    /// `chunker` with its default sizes
+    pub fn new(chunker: Chunker) -> Chunking {
+        Chunking {
+            chunker,
+            sizes: chunker.default_sizes(),
+            table: None,
+            threads: 1,
+        }
+    }
+
+    /// The chunks of `data`, as `FileSplitter` yields them.
+    ///
+    /// Panics for `Chunker::Buzhash` without a table.
+    pub fn split<'file>(
+        &self,
+        data: &'file [u8],
+    ) -> Box<dyn Iterator<Item = Chunk<'file>> + 'file> {
+        if self.threads > 1 && data.len() >= 2 * SEGMENT_SIZE {
+            return Box::new(self.split_segments(data, SEGMENT_SIZE).into_iter());
+        }
+
+        match self.chunker {
+            Chunker::SeaSplit => Box::new(FileSplitter::<SeaSplit>::new(data, &self.sizes)),
+            Chunker::FastCdc => Box::new(FileSplitter::<FastCdc>::new(data, &self.sizes)),
+            Chunker::Fixed => Box::new(FileSplitter::<FixedSplit>::new(data, &self.sizes)),
+            Chunker::Buzhash => {
+                let table = self.table.clone().expect("buzhash needs a table");
+                Box::new(BuzhashSplitter {
+                    data,
+                    cur: 0,
+                    buzhash: Buzhash::new(&self.sizes, table),
+                })
+            }
+        }
+    }
+
+    /// Chunk `data` in segments on all threads, and stitch them
+    /// together where the chunks of one segment meet a cut of the
+    /// next. A cut only depends on where the chunk starts, so from
+    /// there on the chunks are the ones of a single run.
+    fn split_segments<'file>(&self, data: &'file [u8], segment: usize) -> Vec<Chunk<'file>> {
+        let sequential = Chunking {
+            threads: 1,
+            ..self.clone()
+        };
+        // fixed blocks only meet at multiples of their size
+        let segment = (segment / self.sizes.max).max(1) * self.sizes.max;
+        let starts = (0..data.len()).step_by(segment).collect::<Vec<_>>();
+        let threads = self.threads.min(starts.len());
+
+        let mut runs = thread::scope(|s| {
+            let handles = (0..threads)
+                .map(|t| {
+                    let (sequential, starts) = (&sequential, &starts);
+                    s.spawn(move |_| {
+                        starts
+                            .iter()
+                            .enumerate()
+                            .skip(t)
+                            .step_by(threads)
+                            .map(|(i, start)| (i, sequential.cut_segment(data, *start, segment)))
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            handles
+                .into_iter()
+                .flat_map(|h| h.join().unwrap())
+                .collect::<Vec<_>>()
+        })
+        .unwrap();
+        runs.sort_by_key(|(i, _)| *i);
+
+        let mut runs = runs.into_iter().map(|(_, run)| run);
+        let mut chunks = runs.next().unwrap_or_default();
+        for run in runs {
+            let meets = |start: u64| run.binary_search_by_key(&start, |c| c.0);
+            let first = run[0].0;
+            let met = chunks
+                .iter()
+                .enumerate()
+                .filter(|(_, c)| c.0 >= first)
+                .find_map(|(i, c)| meets(c.0).ok().map(|j| (i, j)));
+            if let Some((i, j)) = met {
+                chunks.truncate(i);
+                chunks.extend_from_slice(&run[j..]);
+                continue;
+            }
+
+            // carry on from the last chunk on one thread, until a cut
+            // of `run`, or the end
+            let end = chunks.last().map_or(0, |c| c.0 as usize + c.2.len());
+            let rest = sequential
+                .split(&data[end..])
+                .map(|(start, hash, chunk)| (start + end as u64, hash, chunk));
+            for chunk in rest {
+                if let Ok(j) = meets(chunk.0) {
+                    chunks.extend_from_slice(&run[j..]);
+                    break;
+                }
+                chunks.push(chunk);
+            }
+        }
+
+        chunks
+    }
+
+    /// The chunks of `data` from `start`, until `SYNC_CHUNKS` past
+    /// the segment.
+    fn cut_segment<'file>(
+        &self,
+        data: &'file [u8],
+        start: usize,
+        segment: usize,
+    ) -> Vec<Chunk<'file>> {
+        let end = (start + segment) as u64;
+        self.split(&data[start..])
+            .map(|(offset, hash, chunk)| (offset + start as u64, hash, chunk))
+            .scan(0, |past, chunk| {
+                if chunk.0 >= end {
+                    *past += 1;
+                }
+                (*past <= SYNC_CHUNKS).then_some(chunk)
+            })
+            .collect()
+    }
+}
+
+/// How each file of a backup is split: with the chunking of the
+/// stash, or in fixed-size blocks if it matches any of `fixed`. The
+/// chunks of the file are compressed as `compression` has it.
+#[derive(Clone, Debug)]
+pub struct ChunkingRules {
+    pub default: Chunking,
+    pub fixed: Vec<glob::Pattern>,
+    /// The size of the fixed blocks
+    pub block_size: usize,
+    pub compression: CompressionRules,
+}
+
+impl From<Chunking> for ChunkingRules {
+    fn from(default: Chunking) -> ChunkingRules {
+        ChunkingRules {
+            block_size: default.sizes.target,
+            fixed: vec![],
+            compression: CompressionRules::default(),
+            default,
+        }
+    }
+}
+
+impl Default for ChunkingRules {
+    fn default() -> ChunkingRules {
+        Chunking::default().into()
+    }
+}
+
+impl ChunkingRules {
+    pub fn for_path(&self, name: &str) -> Chunking {
+        if self.fixed.iter().any(|p| p.matches(name)) {
+            Chunking {
+                chunker: Chunker::Fixed,
+                sizes: ChunkSizes::fixed(self.block_size),
+                table: None,
+                threads: self.default.threads,
+            }
+        } else {
+            self.default.clone()
+        }
+    }
+}
+
+/// Chunks have to fit in an object, even if they don't compress.
+pub const MAX_CHUNK_SIZE: usize = BLOCK_SIZE / 2;
+
+/// Bounds on the size of chunks. Like the chunker, they're recorded in
+/// the stash, so media libraries and disk images can use much larger
+/// chunks than source trees.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ChunkSizes {
+    /// No chunk is cut shorter than this, but the last one of a file
+    pub min: usize,
+    /// After this, FastCDC finds cut points more easily
+    pub target: usize,
+    /// Chunks are cut here at the latest
+    pub max: usize,
+    /// Cut points are where this many bits of the hash match, so
+    /// chunks are about `2^mask_bits` bytes past `min`
+    pub mask_bits: u32,
+}
+
+impl ChunkSizes {
+    /// Every chunk is `size` bytes, but the last one of a file.
+    pub fn fixed(size: usize) -> ChunkSizes {
+        ChunkSizes {
+            min: size,
+            target: size,
+            max: size,
+            mask_bits: 13,
+        }
+    }
+
+    /// Check that the sizes can be used to split files, or say why
+    /// not.
+    pub fn check(&self) -> Result<(), String> {
+        if self.min > self.target || self.target > self.max {
+            return Err(format!(
+                "{} <= {} <= {} doesn't hold",
+                self.min, self.target, self.max
+            ));
+        }
+        if self.max == 0 || self.max > MAX_CHUNK_SIZE {
+            return Err(format!(
+                "the maximum has to be within 1..={}",
+                MAX_CHUNK_SIZE
+            ));
+        }
+        if !(4..=30).contains(&self.mask_bits) {
+            return Err(format!("{} mask bits isn't within 4..=30", self.mask_bits));
+        }
+
+        Ok(())
+    }
+}
+
+/// The gear table, from a fixed seed, as chunk boundaries depend on it
+const GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    // splitmix64
+    let mut table = [0; 256];
+    let mut state: u64 = 0x3073_6661_7374_6364;
+    let mut i = 0;
+    while i < table.len() {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// FastCDC, with a gear hash and normalized chunking, as in "FastCDC:
+/// a Fast and Efficient Content-Defined Chunking Approach for Data
+/// Deduplication" by Xia et al.
+pub struct FastCdc {
+    sizes: ChunkSizes,
+    /// Cut points are harder to find before the target size, and
+    /// easier after, which keeps chunk sizes close to it
+    mask_hard: u64,
+    mask_easy: u64,
+}
+
+/// The top bits of the gear hash depend on the most bytes.
+fn gear_mask(bits: u32) -> u64 {
+    !0 << (64 - bits)
+}
+
+impl Rollsum for FastCdc {
+    fn new(sizes: &ChunkSizes) -> Self {
+        FastCdc {
+            sizes: *sizes,
+            mask_hard: gear_mask(sizes.mask_bits + 2),
+            mask_easy: gear_mask(sizes.mask_bits - 2),
+        }
+    }
+
+    fn find_offset(&mut self, buf: &[u8]) -> usize {
+        if buf.len() <= self.sizes.min {
+            return buf.len();
+        }
+        let end = buf.len().min(self.sizes.max);
+        let normal = end.min(self.sizes.target);
+
+        let mut hash = 0u64;
+        let mut i = self.sizes.min;
+        while i < normal {
+            hash = (hash << 1).wrapping_add(GEAR[buf[i] as usize]);
+            if hash & self.mask_hard == 0 {
+                return i + 1;
+            }
+            i += 1;
+        }
+        while i < end {
+            hash = (hash << 1).wrapping_add(GEAR[buf[i] as usize]);
+            if hash & self.mask_easy == 0 {
+                return i + 1;
+            }
+            i += 1;
+        }
+        end
+    }
+}
+
+/// Cuts at the target size, wherever that is in the content.
+pub struct FixedSplit {
+    size: usize,
+}
+
+impl Rollsum for FixedSplit {
+    fn new(sizes: &ChunkSizes) -> Self {
+        FixedSplit { size: sizes.target }
+    }
+
+    fn find_offset(&mut self, buf: &[u8]) -> usize {
+        buf.len().min(self.size)
+    }
+}
+
+/// The values of the bytes in a buzhash. They give away where
+/// chunks are cut, so they are as secret as the key.
+#[derive(Clone, PartialEq, Eq)]
+pub struct BuzhashTable([u32; 256]);
+
+impl BuzhashTable {
+    pub(crate) fn new(values: [u32; 256]) -> BuzhashTable {
+        BuzhashTable(values)
+    }
+}
+
+impl Drop for BuzhashTable {
+    fn drop(&mut self) {
+        self.0[..].zeroize();
+    }
+}
+
+impl fmt::Debug for BuzhashTable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("BuzhashTable")
+    }
+}
+
+/// The number of bytes the buzhash is taken over
+const BUZHASH_WINDOW: usize = 64;
+
+/// Cuts where the buzhash of the last `BUZHASH_WINDOW` bytes has
+/// `mask_bits` zero bits.
+pub struct Buzhash {
+    sizes: ChunkSizes,
+    mask: u32,
+    table: Arc<BuzhashTable>,
+}
+
+impl Buzhash {
+    pub fn new(sizes: &ChunkSizes, table: Arc<BuzhashTable>) -> Buzhash {
+        Buzhash {
+            sizes: *sizes,
+            mask: (1 << sizes.mask_bits) - 1,
+            table,
+        }
+    }
+
+    fn find_offset(&self, buf: &[u8]) -> usize {
+        let end = buf.len().min(self.sizes.max);
+        // the window ends at the minimum size, or the first full one
+        let from = self.sizes.min.saturating_sub(BUZHASH_WINDOW);
+        if end <= from + BUZHASH_WINDOW {
+            return end;
+        }
+
+        let table = &self.table.0;
+        let mut hash = buf[from..from + BUZHASH_WINDOW]
+            .iter()
+            .fold(0u32, |h, b| h.rotate_left(1) ^ table[*b as usize]);
+        for i in from + BUZHASH_WINDOW..end {
+            if hash & self.mask == 0 {
+                return i;
+            }
+            hash = hash.rotate_left(1)
+                ^ table[buf[i - BUZHASH_WINDOW] as usize].rotate_left(BUZHASH_WINDOW as u32)
+                ^ table[buf[i] as usize];
+        }
+        end
+    }
+}
+
+struct BuzhashSplitter<'file> {
+    data: &'file [u8],
+    cur: usize,
+    buzhash: Buzhash,
+}
+
+impl<'file> Iterator for BuzhashSplitter<'file> {
+    type Item = Chunk<'file>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.cur >= self.data.len() {
+            return None;
+        }
+
+        let start = self.cur;
+        let end = self.buzhash.find_offset(&self.data[start..]);
+        let data = &self.data[start..start + end];
+        self.cur += end;
+
+        Some((start as u64, chunk_hash(data), data))
+    }
+}
 
 pub struct FileSplitter<'file, RS> {
     data: &'file [u8],
+    sizes: ChunkSizes,
     cur: usize,
     _rs: PhantomData<RS>,
 }
@@ -13,9 +522,10 @@ impl<'file, RS> FileSplitter<'file, RS>
 where
     RS: Rollsum,
 {
-    pub fn new(data: &'file [u8]) -> FileSplitter<'file, RS> {
+    pub fn new(data: &'file [u8], sizes: &ChunkSizes) -> FileSplitter<'file, RS> {
         FileSplitter {
             data,
+            sizes: *sizes,
             _rs: PhantomData,
             cur: 0,
         }
@@ -26,7 +536,7 @@ impl<'file, RS> Iterator for FileSplitter<'file, RS>
 where
     RS: Rollsum,
 {
-    type Item = (u64, CryptoDigest, &'file [u8]);
+    type Item = Chunk<'file>;
 
     fn next(&mut self) -> Option<Self::Item> {
         if self.cur >= self.data.len() {
@@ -34,7 +544,7 @@ where
         }
 
         let start = self.cur;
-        let end = RS::new().find_offset(&self.data[start..]);
+        let end = RS::new(&self.sizes).find_offset(&self.data[start..]);
         let data = &self.data[start..start + end];
         self.cur += end;
 
@@ -42,14 +552,16 @@ where
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "fs"))]
 mod tests {
+    #[cfg(feature = "nightly")]
     extern crate test;
     const PATH: &str = "tests/data/10k_random_blob";
 
+    #[cfg(feature = "nightly")]
     #[bench]
     fn bench_chunk_iter(b: &mut test::Bencher) {
-        use super::FileSplitter;
+        use super::{Chunker, FileSplitter};
         use crate::rollsum::SeaSplit;
         use memmap::MmapOptions;
         use std::fs::File;
@@ -58,17 +570,15 @@ mod tests {
         let mmap = unsafe { MmapOptions::new().map(&file).unwrap() };
 
         b.iter(|| {
-            FileSplitter::<SeaSplit>::new(&mmap)
+            FileSplitter::<SeaSplit>::new(&mmap, &Chunker::SeaSplit.default_sizes())
                 .map(|(_, _, c)| c.len())
                 .sum::<usize>()
         });
-
-        println!("asdf");
     }
 
     #[test]
     fn check_chunk_iterator_sum() {
-        use super::FileSplitter;
+        use super::{Chunker, FileSplitter};
         use crate::rollsum::SeaSplit;
         use memmap::MmapOptions;
         use std::fs::File;
@@ -77,9 +587,123 @@ mod tests {
         let metadata = file.metadata().unwrap();
         let mmap = unsafe { MmapOptions::new().map(&file).unwrap() };
 
-        let size: usize = FileSplitter::<SeaSplit>::new(&mmap)
+        let size: usize = FileSplitter::<SeaSplit>::new(&mmap, &Chunker::SeaSplit.default_sizes())
             .map(|(_, _, c)| c.len())
             .sum();
         assert_eq!(size as u64, metadata.len());
     }
+
+    #[test]
+    fn fastcdc_cuts_depend_on_the_content() {
+        use super::*;
+        use std::collections::HashSet;
+
+        let data = crate::test_util::pseudo_random(1_000_000, 7);
+
+        let chunking = Chunking::new(Chunker::FastCdc);
+        let sizes = chunking.sizes;
+        let chunks = chunking.split(&data).collect::<Vec<_>>();
+        assert_eq!(chunks.iter().map(|c| c.2.len()).sum::<usize>(), data.len());
+        assert!(chunks[..chunks.len() - 1]
+            .iter()
+            .all(|c| c.2.len() > sizes.min && c.2.len() <= sizes.max));
+        let average = data.len() / chunks.len();
+        assert!(average > sizes.target / 2 && average < sizes.target * 2);
+
+        // inserting at the front only changes the first chunk
+        let shifted = [&b"inserted"[..], &data].concat();
+        let hashes = |chunks: &[Chunk]| chunks.iter().map(|c| c.1).collect::<HashSet<_>>();
+        let after = chunking.split(&shifted).collect::<Vec<_>>();
+        assert_eq!(
+            hashes(&chunks).intersection(&hashes(&after)).count(),
+            chunks.len() - 1
+        );
+
+        for chunker in Chunker::ALL.iter() {
+            assert_eq!(Chunker::from_name(chunker.name()), Some(*chunker));
+        }
+    }
+
+    #[test]
+    fn matching_files_are_split_in_fixed_blocks() {
+        use super::*;
+
+        let rules = ChunkingRules {
+            fixed: vec![glob::Pattern::new("*.img").unwrap()],
+            block_size: 4096,
+            ..ChunkingRules::default()
+        };
+        assert_eq!(rules.for_path("notes.txt"), Chunking::default());
+
+        let chunking = rules.for_path("vm/disk.img");
+        assert_eq!(chunking.chunker, Chunker::Fixed);
+        let data = vec![0; 10_000];
+        let lengths = chunking.split(&data).map(|c| c.2.len()).collect::<Vec<_>>();
+        assert_eq!(lengths, vec![4096, 4096, 1808]);
+    }
+
+    #[test]
+    fn buzhash_cuts_depend_on_the_key() {
+        use super::*;
+        use crate::crypto::{ConvergenceSecret, StashKey};
+
+        let data = crate::test_util::pseudo_random(1_000_000, 11);
+
+        let split = |key: &StashKey| {
+            let chunking = Chunking {
+                table: Some(Arc::new(key.buzhash_table().unwrap())),
+                ..Chunking::new(Chunker::Buzhash)
+            };
+            chunking.split(&data).map(|c| c.2.len()).collect::<Vec<_>>()
+        };
+        let key = StashKey::generate();
+        let lengths = split(&key);
+        let sizes = Chunker::Buzhash.default_sizes();
+        assert_eq!(lengths.iter().sum::<usize>(), data.len());
+        assert!(lengths[..lengths.len() - 1]
+            .iter()
+            .all(|l| *l >= sizes.min && *l <= sizes.max));
+        assert_eq!(split(&key), lengths);
+        assert_ne!(split(&StashKey::generate()), lengths);
+
+        // stashes of a family cut alike
+        let secret = ConvergenceSecret::generate();
+        let mut family = [StashKey::generate(), StashKey::generate()];
+        for key in family.iter_mut() {
+            key.set_convergence(Some(secret.clone()));
+        }
+        assert_eq!(split(&family[0]), split(&family[1]));
+    }
+
+    #[test]
+    fn segments_are_stitched_into_the_same_chunks() {
+        use super::*;
+        use crate::crypto::StashKey;
+
+        let data = crate::test_util::pseudo_random(1_000_000, 5);
+
+        let sizes = ChunkSizes {
+            min: 256,
+            target: 1024,
+            max: 8192,
+            mask_bits: 10,
+        };
+        for chunker in Chunker::ALL.iter() {
+            let chunking = Chunking {
+                sizes: match chunker {
+                    Chunker::Fixed => ChunkSizes::fixed(1000),
+                    _ => sizes,
+                },
+                table: Some(Arc::new(StashKey::generate().buzhash_table().unwrap())),
+                ..Chunking::new(*chunker)
+            };
+            let parallel = Chunking {
+                threads: 3,
+                ..chunking.clone()
+            };
+
+            let chunks = chunking.split(&data).collect::<Vec<_>>();
+            assert_eq!(parallel.split_segments(&data, 64 * 1024), chunks);
+        }
+    }
 }
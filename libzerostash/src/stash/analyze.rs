@@ -0,0 +1,188 @@
+use crate::chunks::{ChunkPointer, ChunkStore};
+use crate::compress::{Compression, Tuning};
+use crate::crypto::CryptoDigest;
+use crate::error::{Result, ZerostashError};
+use crate::files::FileStore;
+use crate::meta;
+use crate::objects::{self, ObjectStore};
+use crate::progress::Phase;
+use crate::stash::{store, BackupOptions, Stash};
+use crate::stats::Collector;
+
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// What backing up some paths would add to a stash.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Analysis {
+    pub files: u64,
+    /// Bytes read from the files
+    pub bytes: u64,
+    /// Unique chunks the stash doesn't have yet
+    pub new_chunks: u64,
+    pub new_bytes: u64,
+    /// Bytes of chunks that are already stored, or repeat within the
+    /// files
+    pub dedup_bytes: u64,
+    /// Size of the new chunks after compression
+    pub compressed_bytes: u64,
+    /// Size of the objects the new chunks fill, which is what gets
+    /// uploaded
+    pub upload_bytes: u64,
+}
+
+#[derive(Default)]
+struct Counts {
+    chunks: u64,
+    bytes: u64,
+    compressed: u64,
+}
+
+/// Takes the place of object storage, counting the chunks the stash
+/// doesn't have instead of storing them.
+#[derive(Clone)]
+struct Counter {
+    existing: ChunkStore,
+    counts: Arc<Mutex<Counts>>,
+    compression: Compression,
+    tuning: Tuning,
+    scratch: Vec<u8>,
+}
+
+impl ObjectStore for Counter {
+    fn store_chunk(
+        &mut self,
+        hash: &CryptoDigest,
+        data: &[u8],
+    ) -> objects::Result<Arc<ChunkPointer>> {
+        if self.existing.index().get(hash).is_none() {
+            let compressed =
+                self.tuning
+                    .compress_into(self.compression, &mut self.scratch, data)?;
+
+            let mut counts = self.counts.lock().unwrap();
+            counts.chunks += 1;
+            counts.bytes += data.len() as u64;
+            counts.compressed += compressed as u64;
+        }
+
+        // only ever seen by the index of the analysis
+        Ok(Arc::default())
+    }
+
+    fn flush(&mut self) -> objects::Result<()> {
+        Ok(())
+    }
+
+    fn set_compression(&mut self, compression: Compression) {
+        self.compression = compression;
+    }
+}
+
+impl Stash {
+    /// Chunk `paths` the way `backup` would, and report how much of
+    /// the data is new to the stash, without storing anything.
+    ///
+    /// Nothing is uploaded, so this is useful for planning capacity
+    /// before adding a new data set.
+    pub fn analyze(
+        &mut self,
+        paths: &[impl AsRef<Path>],
+        options: &BackupOptions,
+    ) -> Result<Analysis> {
+        self.load(meta::Field::Chunks)?;
+        let rules = self.chunking_rules(options)?;
+        let filter = options.filter()?;
+
+        let stats = Collector::new(self.progress.clone());
+        let start = Instant::now();
+        self.progress.phase(Phase::Store, None);
+
+        // a separate index, so chunks repeating in `paths` are only
+        // counted once, and the stash isn't touched
+        let mut chunks = ChunkStore::default();
+        let mut files = FileStore::default();
+        let mut counter = Counter {
+            existing: self.chunks.clone(),
+            counts: Arc::default(),
+            compression: self.compression,
+            tuning: self.tuning,
+            scratch: vec![],
+        };
+
+        for path in paths.iter() {
+            store::recursive(
+                options.threads.unwrap_or(self.threads),
+                self.schedule,
+                options.symlinks,
+                &filter,
+                &rules,
+                &mut chunks,
+                &mut files,
+                &mut counter,
+                None,
+                &stats,
+                &options.cancel,
+                path,
+            )?;
+        }
+
+        if options.cancel.is_cancelled() {
+            return Err(ZerostashError::Cancelled);
+        }
+
+        let summary = stats.summary(start.elapsed());
+        let counts = counter.counts.lock().unwrap();
+        let size = self.object_size as u64;
+        let objects = counts.compressed.div_ceil(size);
+        Ok(Analysis {
+            files: summary.files,
+            bytes: summary.bytes,
+            new_chunks: counts.chunks,
+            new_bytes: counts.bytes,
+            dedup_bytes: summary.bytes - counts.bytes,
+            compressed_bytes: counts.compressed,
+            upload_bytes: objects * size,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn analysis_counts_new_data_only() {
+        use super::*;
+        use crate::backends::NullBackend;
+        use crate::stash::StashKey;
+
+        let backend = Arc::new(NullBackend::default());
+        let key = StashKey::open_stash("analyze", "test").unwrap();
+        let mut stash = Stash::new(backend.clone(), key);
+        let options = BackupOptions::default();
+
+        let analysis = stash
+            .analyze(&["tests/data/100_random_1k"], &options)
+            .unwrap();
+        assert_eq!(analysis.files, 100);
+        assert_eq!(analysis.bytes, 1_024_000);
+        assert_eq!(analysis.new_bytes, analysis.bytes);
+        assert_eq!(analysis.upload_bytes, crate::BLOCK_SIZE as u64);
+        assert_eq!(backend.len(), 0);
+
+        stash
+            .backup(&["tests/data/100_random_1k"], &options)
+            .unwrap();
+        let chunks = stash.chunk_index().len();
+        let analysis = stash
+            .analyze(
+                &["tests/data/100_random_1k", "tests/data/10k_random_blob"],
+                &options,
+            )
+            .unwrap();
+        assert_eq!(analysis.files, 101);
+        assert_eq!(analysis.new_bytes, 10 * 1024);
+        assert_eq!(analysis.dedup_bytes, 1_024_000);
+        assert_eq!(stash.chunk_index().len(), chunks);
+    }
+}
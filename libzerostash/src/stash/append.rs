@@ -0,0 +1,167 @@
+//! Append-only writers, which hold only the public key of a stash.
+//!
+//! A writer commits to a stash of its own, with a random master key
+//! that is sealed to the public key in a *drop* object. The drops of
+//! a stash are numbered without gaps, so readers find them without
+//! listing the backend, and only the master key of the stash opens
+//! them. A compromised writer can't read anything but the data it
+//! wrote itself since it started.
+
+use crate::backends::{Backend, BackendError};
+use crate::crypto::{chunk_hash, PublicKey};
+use crate::error::{Result, ZerostashError};
+use crate::objects::{BlockBuffer, Object, ObjectId};
+use crate::stash::{sync, Stash, StashKey, Synced};
+
+use std::sync::Arc;
+
+/// Drop objects start with this, followed by the sealed key.
+pub(super) const MAGIC: &[u8] = b"0s-drop";
+
+impl Stash {
+    /// A writer that adds snapshots to the stash of `public_key` in
+    /// `backend`, but can't read it.
+    ///
+    /// The writer doesn't see the chunks of the stash, so everything
+    /// it commits is stored again, and only deduplicated when a
+    /// reader collects it with `collect_drops`.
+    pub fn append_only(backend: Arc<dyn Backend>, public_key: PublicKey) -> Stash {
+        let mut stash = Stash::new(backend, StashKey::generate());
+        stash.recipient = Some(public_key);
+        stash
+    }
+
+    /// The key to give to append-only writers of this stash.
+    pub fn public_key(&self) -> Result<PublicKey> {
+        Ok(self.master_key.public_key()?)
+    }
+
+    /// The stashes committed by append-only writers, in the order
+    /// they were started.
+    pub fn drops(&self) -> Result<Vec<Stash>> {
+        let public_key = self.master_key.public_key()?;
+        let mut drops = vec![];
+
+        for n in 0.. {
+            let id = drop_id(&public_key, n);
+            let object = match self.backend.read_object(&id) {
+                Ok(object) => object,
+                Err(BackendError::NoObjectFound) => break,
+                Err(e) => return Err(e.into()),
+            };
+
+            let key = object
+                .buffer
+                .as_ref()
+                .strip_prefix(MAGIC)
+                .and_then(|sealed| self.master_key.unseal_key(sealed).ok())
+                .ok_or(ZerostashError::Corrupt { object: id })?;
+            let mut drop = Stash::new(self.backend.clone(), key);
+            drop.read_fields(&[])?;
+            drops.push(drop);
+        }
+
+        Ok(drops)
+    }
+
+    /// Copy the snapshots of all `drops` that aren't in the stash
+    /// yet, and commit it.
+    pub fn collect_drops(&mut self) -> Result<Synced> {
+        let mut collected = Synced::default();
+        for mut drop in self.drops()? {
+            let synced = sync(&mut drop, self)?;
+            collected.snapshots += synced.snapshots;
+            collected.chunks += synced.chunks;
+        }
+
+        Ok(collected)
+    }
+
+    /// Seal the key of an append-only writer in the next free drop,
+    /// after its first commit.
+    pub(crate) fn store_drop(&mut self) -> Result<()> {
+        let public_key = match self.recipient.take() {
+            Some(key) => key,
+            None => return Ok(()),
+        };
+
+        let id = drop_id(&public_key, first_free(&*self.backend, &public_key)?);
+        let data = [MAGIC, &public_key.seal_key(&self.master_key)].concat();
+        self.backend
+            .write_object(&Object::with_id(id, BlockBuffer::from(data)))?;
+
+        Ok(())
+    }
+}
+
+fn drop_id(public_key: &PublicKey, n: u64) -> ObjectId {
+    let name = format!("_0s_drop{}{}", public_key, n);
+    ObjectId::from_bytes(chunk_hash(name.as_bytes()))
+}
+
+/// The number of the first drop that isn't written yet. As there are
+/// no gaps, it's found by doubling, then bisecting.
+fn first_free(backend: &dyn Backend, public_key: &PublicKey) -> Result<u64> {
+    let taken = |n| match backend.read_object(&drop_id(public_key, n)) {
+        Ok(_) => Ok(true),
+        Err(BackendError::NoObjectFound) => Ok(false),
+        Err(e) => Err(ZerostashError::from(e)),
+    };
+    if !taken(0)? {
+        return Ok(0);
+    }
+
+    let (mut low, mut high) = (0, 1);
+    while taken(high)? {
+        low = high;
+        high *= 2;
+    }
+    while high - low > 1 {
+        let mid = low + (high - low) / 2;
+        if taken(mid)? {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+
+    Ok(high)
+}
+
+#[cfg(all(test, feature = "fs"))]
+mod tests {
+    #[test]
+    fn writers_only_append() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use crate::stash::BackupOptions;
+
+        let backend = Arc::new(MemoryBackend::default());
+        let owner = || StashKey::open_stash("owner", "test").unwrap();
+        let public_key = owner().public_key().unwrap();
+
+        for path in ["tests/data/100_random_1k", "tests/data/10k_random_blob"] {
+            let mut writer = Stash::append_only(backend.clone(), public_key);
+            writer.backup(&[path], &BackupOptions::default()).unwrap();
+            // later commits of the same writer keep its drop
+            writer.commit().unwrap();
+        }
+        assert_eq!(first_free(&*backend, &public_key).unwrap(), 2);
+
+        // another stash's key doesn't open the drops
+        let other = Stash::new(
+            backend.clone(),
+            StashKey::open_stash("other", "test").unwrap(),
+        );
+        assert!(other.drops().unwrap().is_empty());
+
+        let mut stash = Stash::new(backend.clone(), owner());
+        assert_eq!(stash.collect_drops().unwrap().snapshots, 2);
+        assert_eq!(stash.snapshots().len(), 2);
+
+        // collecting again finds nothing new
+        let mut stash = Stash::new(backend, owner());
+        stash.read().unwrap();
+        assert_eq!(stash.collect_drops().unwrap(), Synced::default());
+    }
+}
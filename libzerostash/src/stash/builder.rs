@@ -0,0 +1,427 @@
+#[cfg(feature = "async")]
+use crate::backends::AsyncBackend;
+use crate::backends::Backend;
+use crate::compress::{Compression, CompressionRule, Tuning};
+use crate::crypto::{shamir, Cipher, ConvergenceSecret, Kdf, PublicKey};
+use crate::error::{Result, ZerostashError};
+use crate::limits;
+use crate::progress::Progress;
+use crate::stash::{ChunkSizes, Chunker, Schedule, Stash, StashKey};
+
+use secrecy::{ExposeSecret, SecretString};
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Assembles a `Stash` from its parts, and checks that the
+/// configuration makes sense before anything is read or written.
+///
+/// ```no_run
+/// use libzerostash::{backends::Directory, stash::StashBuilder};
+/// use std::sync::Arc;
+///
+/// let stash = StashBuilder::new()
+///     .backend(Arc::new(Directory::new("/tmp/stash").unwrap()))
+///     .credentials("user", "password")
+///     .threads(4)
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Default)]
+pub struct StashBuilder {
+    backend: Option<Arc<dyn Backend>>,
+    key: Option<StashKey>,
+    credentials: Option<(String, SecretString)>,
+    shares: Option<Vec<shamir::Share>>,
+    kdf: Option<Kdf>,
+    cipher: Option<Cipher>,
+    compression: Option<Compression>,
+    compression_rules: Option<Vec<CompressionRule>>,
+    tuning: Option<Tuning>,
+    metadata_dictionary: bool,
+    delta_commits: u32,
+    immutable: bool,
+    convergence: Option<ConvergenceSecret>,
+    chunker: Option<Chunker>,
+    chunk_sizes: Option<ChunkSizes>,
+    object_size: Option<usize>,
+    public_key: Option<PublicKey>,
+    threads: Option<usize>,
+    parallel_chunking: bool,
+    uploads: usize,
+    #[cfg(feature = "async")]
+    async_backend: Option<(Arc<dyn AsyncBackend>, tokio::runtime::Handle)>,
+    downloads: usize,
+    memory_limit: Option<u64>,
+    quota: Option<u64>,
+    schedule: Schedule,
+    file_cache: Option<PathBuf>,
+    index_cache: Option<PathBuf>,
+    find_index: Option<PathBuf>,
+    checkpoints: Option<Duration>,
+    max_open_files: Option<usize>,
+    progress: Option<Arc<dyn Progress>>,
+    upload_limit: Option<u64>,
+    download_limit: Option<u64>,
+}
+
+impl StashBuilder {
+    pub fn new() -> StashBuilder {
+        StashBuilder::default()
+    }
+
+    pub fn backend(mut self, backend: Arc<dyn Backend>) -> Self {
+        self.backend = Some(backend);
+        self
+    }
+
+    /// The key derived from the credentials. If the stash has a key
+    /// object for them, it's opened with the master key in it.
+    pub fn key(mut self, key: StashKey) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    /// Open the stash with the key derived from `user` and `password`
+    /// by the `Kdf` stored in the stash, instead of a `key`.
+    pub fn credentials(mut self, user: impl Into<String>, password: impl Into<String>) -> Self {
+        self.credentials = Some((user.into(), SecretString::new(password.into())));
+        self
+    }
+
+    /// Open the stash with the master key recovered from `shares`,
+    /// made by `Stash::split_key`, instead of a `key`.
+    pub fn shares(mut self, shares: Vec<shamir::Share>) -> Self {
+        self.shares = Some(shares);
+        self
+    }
+
+    /// How keys are derived from the `credentials` of a new stash.
+    /// By default, this is calibrated to take about a second.
+    pub fn kdf(mut self, kdf: Kdf) -> Self {
+        self.kdf = Some(kdf);
+        self
+    }
+
+    /// The cipher of a new stash, instead of the fastest one on this
+    /// machine. Existing stashes keep the one they were created with.
+    pub fn cipher(mut self, cipher: Cipher) -> Self {
+        self.cipher = Some(cipher);
+        self
+    }
+
+    /// The chunker of a new stash. Existing stashes keep the one they
+    /// were created with.
+    pub fn chunker(mut self, chunker: Chunker) -> Self {
+        self.chunker = Some(chunker);
+        self
+    }
+
+    /// The compression of the chunks of a new stash, instead of LZ4.
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// The compression of the files matching each of `rules`. See
+    /// `Stash::set_compression_rules`.
+    pub fn compression_rules(mut self, rules: Vec<CompressionRule>) -> Self {
+        self.compression_rules = Some(rules);
+        self
+    }
+
+    /// The compression levels and metadata block size. See
+    /// `Stash::set_tuning`.
+    pub fn tuning(mut self, tuning: Tuning) -> Self {
+        self.tuning = Some(tuning);
+        self
+    }
+
+    /// Compress the metadata with a trained dictionary. See
+    /// `Stash::set_metadata_dictionary`.
+    pub fn metadata_dictionary(mut self, train: bool) -> Self {
+        self.metadata_dictionary = train;
+        self
+    }
+
+    /// Only write what changed to the metadata, for up to `commits`
+    /// commits in a row. See `Stash::set_delta_commits`.
+    pub fn delta_commits(mut self, commits: u32) -> Self {
+        self.delta_commits = commits;
+        self
+    }
+
+    /// Never delete or overwrite objects. See `Stash::set_immutable`.
+    pub fn immutable(mut self, immutable: bool) -> Self {
+        self.immutable = immutable;
+        self
+    }
+
+    /// The chunk sizes of a new stash, instead of the defaults of the
+    /// chunker.
+    pub fn chunk_sizes(mut self, sizes: ChunkSizes) -> Self {
+        self.chunk_sizes = Some(sizes);
+        self
+    }
+
+    /// The size of the data objects of a new stash, instead of
+    /// `BLOCK_SIZE`. See `Stash::set_object_size`.
+    pub fn object_size(mut self, size: usize) -> Self {
+        self.object_size = Some(size);
+        self
+    }
+
+    /// Derive chunk keys from their content and the secret of a
+    /// family of stashes. See `Stash::set_convergence`.
+    pub fn convergent(mut self, convergence: ConvergenceSecret) -> Self {
+        self.convergence = Some(convergence);
+        self
+    }
+
+    /// Only add to the stash of `public_key`, without a key to read
+    /// it. See `Stash::append_only`.
+    pub fn append_only(mut self, public_key: PublicKey) -> Self {
+        self.public_key = Some(public_key);
+        self
+    }
+
+    /// Number of worker threads. Defaults to the available
+    /// parallelism of the machine.
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = Some(threads);
+        self
+    }
+
+    /// Chunk and compress large files on all worker threads. See
+    /// `Stash::set_parallel_chunking`.
+    pub fn parallel_chunking(mut self, parallel: bool) -> Self {
+        self.parallel_chunking = parallel;
+        self
+    }
+
+    /// Upload objects on this many threads of their own. See
+    /// `Stash::set_uploads`.
+    pub fn uploads(mut self, threads: usize) -> Self {
+        self.uploads = threads;
+        self
+    }
+
+    /// Upload the data objects of backups through `backend`, as tasks
+    /// on `runtime`. See `Stash::set_async_backend`.
+    #[cfg(feature = "async")]
+    pub fn async_backend(
+        mut self,
+        backend: Arc<dyn AsyncBackend>,
+        runtime: tokio::runtime::Handle,
+    ) -> Self {
+        self.async_backend = Some((backend, runtime));
+        self
+    }
+
+    /// Read objects on this many threads of their own when restoring.
+    /// See `Stash::set_downloads`.
+    pub fn downloads(mut self, threads: usize) -> Self {
+        self.downloads = threads;
+        self
+    }
+
+    /// Keep operations within about this many bytes of memory. See
+    /// `Stash::set_memory_limit`.
+    pub fn memory_limit(mut self, bytes: u64) -> Self {
+        self.memory_limit = Some(bytes);
+        self
+    }
+
+    /// Refuse to store more than this many bytes of chunks. See
+    /// `Stash::set_quota`.
+    pub fn quota(mut self, bytes: u64) -> Self {
+        self.quota = Some(bytes);
+        self
+    }
+
+    pub fn schedule(mut self, schedule: Schedule) -> Self {
+        self.schedule = schedule;
+        self
+    }
+
+    /// Use a local cache at `path` to skip reading unchanged files.
+    pub fn file_cache(mut self, path: impl Into<PathBuf>) -> Self {
+        self.file_cache = Some(path.into());
+        self
+    }
+
+    /// Keep the chunk index at `path` on the local disk. See
+    /// `Stash::use_index_cache`.
+    pub fn index_cache(mut self, path: impl Into<PathBuf>) -> Self {
+        self.index_cache = Some(path.into());
+        self
+    }
+
+    /// Keep an index of the files of all snapshots at `path` on the
+    /// local disk, for searches. See `Stash::use_find_index`.
+    pub fn find_index(mut self, path: impl Into<PathBuf>) -> Self {
+        self.find_index = Some(path.into());
+        self
+    }
+
+    /// Save the file cache this often while storing files. See
+    /// `Stash::set_checkpoints`.
+    pub fn checkpoints(mut self, every: Duration) -> Self {
+        self.checkpoints = Some(every);
+        self
+    }
+
+    /// Limit on simultaneously open files. This is a process-wide
+    /// setting, see the `limits` module.
+    pub fn max_open_files(mut self, limit: usize) -> Self {
+        self.max_open_files = Some(limit);
+        self
+    }
+
+    /// Write at most `bytes` per second to the backend. This can be
+    /// changed later through `Stash::throttle`.
+    pub fn upload_limit(mut self, bytes: u64) -> Self {
+        self.upload_limit = Some(bytes);
+        self
+    }
+
+    /// Read at most `bytes` per second from the backend.
+    pub fn download_limit(mut self, bytes: u64) -> Self {
+        self.download_limit = Some(bytes);
+        self
+    }
+
+    pub fn progress(mut self, progress: Arc<dyn Progress>) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    pub fn build(self) -> Result<Stash> {
+        let backend = self
+            .backend
+            .ok_or_else(|| ZerostashError::Config("no backend set".into()))?;
+
+        if self.threads == Some(0) {
+            return Err(ZerostashError::Config("threads must be at least 1".into()));
+        }
+        if self.max_open_files == Some(0) {
+            return Err(ZerostashError::Config(
+                "max_open_files must be at least 1".into(),
+            ));
+        }
+
+        let mut stash = match (self.public_key, self.shares, self.key, self.credentials) {
+            (Some(public_key), None, None, None) => Stash::append_only(backend, public_key),
+            // the shares hold the master key itself
+            (None, Some(shares), None, None) => Stash::new(backend, shamir::combine(&shares)?),
+            (None, None, Some(key), _) => Stash::open(backend, key)?,
+            (None, None, None, Some((user, password))) => {
+                Stash::open_with_credentials(backend, &user, password.expose_secret(), self.kdf)?
+            }
+            (None, None, None, None) => return Err(ZerostashError::Config("no key set".into())),
+            _ => {
+                return Err(ZerostashError::Config(
+                    "only one of a key, shares or a public key can be set".into(),
+                ))
+            }
+        };
+        stash.set_schedule(self.schedule);
+        stash.set_parallel_chunking(self.parallel_chunking);
+        stash.set_uploads(self.uploads);
+        #[cfg(feature = "async")]
+        if let Some((backend, runtime)) = self.async_backend {
+            stash.set_async_backend(backend, runtime);
+        }
+        stash.set_downloads(self.downloads);
+        stash.set_memory_limit(self.memory_limit);
+        if let Some(quota) = self.quota {
+            stash.set_quota(Some(quota));
+        }
+        if let Some(cipher) = self.cipher {
+            stash.set_cipher(cipher);
+        }
+        if let Some(compression) = self.compression {
+            stash.set_compression(compression);
+        }
+        if let Some(rules) = self.compression_rules {
+            stash.set_compression_rules(rules)?;
+        }
+        if let Some(tuning) = self.tuning {
+            stash.set_tuning(tuning)?;
+        }
+        stash.set_metadata_dictionary(self.metadata_dictionary)?;
+        stash.set_delta_commits(self.delta_commits);
+        if self.immutable {
+            stash.set_immutable()?;
+        }
+        if let Some(chunker) = self.chunker {
+            stash.set_chunker(chunker);
+        }
+        if let Some(sizes) = self.chunk_sizes {
+            stash.set_chunk_sizes(sizes)?;
+        }
+        if let Some(size) = self.object_size {
+            stash.set_object_size(size)?;
+        }
+        if let Some(convergence) = self.convergence {
+            stash.set_convergence(convergence);
+        }
+        stash.throttle().set_upload(self.upload_limit);
+        stash.throttle().set_download(self.download_limit);
+
+        if let Some(threads) = self.threads {
+            stash.set_threads(threads);
+        }
+        if let Some(path) = self.file_cache {
+            stash.use_file_cache(path)?;
+        }
+        if let Some(path) = self.index_cache {
+            stash.use_index_cache(path);
+        }
+        if let Some(path) = self.find_index {
+            stash.use_find_index(path);
+        }
+        stash.set_checkpoints(self.checkpoints);
+        if let Some(progress) = self.progress {
+            stash.set_progress(progress);
+        }
+        if let Some(limit) = self.max_open_files {
+            limits::set_max_open_files(limit);
+        }
+
+        Ok(stash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn builder_validates_configuration() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+
+        let key = || StashKey::open_stash("builder", "test").unwrap();
+        let backend = Arc::new(MemoryBackend::default());
+
+        assert!(StashBuilder::new().key(key()).build().is_err());
+        assert!(StashBuilder::new()
+            .backend(backend.clone())
+            .build()
+            .is_err());
+        assert!(StashBuilder::new()
+            .backend(backend.clone())
+            .key(key())
+            .threads(0)
+            .build()
+            .is_err());
+
+        let stash = StashBuilder::new()
+            .backend(backend)
+            .key(key())
+            .threads(3)
+            .build()
+            .unwrap();
+        assert_eq!(stash.threads(), 3);
+    }
+}
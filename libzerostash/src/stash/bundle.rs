@@ -0,0 +1,315 @@
+use crate::error::{Result, ZerostashError};
+use crate::format::MAX_OBJECT_SIZE;
+use crate::meta;
+use crate::objects::{BlockBuffer, Object, ObjectId};
+use crate::stash::Stash;
+use crate::BLOCK_SIZE;
+
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::io::{Read, Write};
+
+const MAGIC: &[u8; 8] = b"0sbundle";
+/// Every object is `BLOCK_SIZE` bytes
+const VERSION: u32 = 1;
+/// Every object is preceded by its size, for stashes with objects of
+/// another size
+const SIZED_VERSION: u32 = 2;
+
+/// Header of a bundle, followed by `count` objects, each an id and
+/// its contents. The root object comes last.
+struct Header {
+    version: u32,
+    since: u64,
+    count: u64,
+    root: ObjectId,
+}
+
+impl Header {
+    const SIZE: usize = 8 + 4 + 8 + 8 + 32;
+
+    fn write(&self, out: &mut impl Write) -> Result<()> {
+        out.write_all(MAGIC)?;
+        out.write_all(&self.version.to_le_bytes())?;
+        out.write_all(&self.since.to_le_bytes())?;
+        out.write_all(&self.count.to_le_bytes())?;
+        out.write_all(self.root.as_ref())?;
+        Ok(())
+    }
+
+    fn read(input: &mut impl Read) -> Result<Header> {
+        let mut buf = [0; Header::SIZE];
+        input.read_exact(&mut buf)?;
+
+        if &buf[..8] != MAGIC {
+            return Err(ZerostashError::Bundle("not a bundle".into()));
+        }
+        let version = u32::from_le_bytes(buf[8..12].try_into().unwrap());
+        if version != VERSION && version != SIZED_VERSION {
+            return Err(ZerostashError::Bundle(format!(
+                "unsupported version {}",
+                version
+            )));
+        }
+
+        Ok(Header {
+            version,
+            since: u64::from_le_bytes(buf[12..20].try_into().unwrap()),
+            count: u64::from_le_bytes(buf[20..28].try_into().unwrap()),
+            root: ObjectId::from_bytes(&buf[28..]),
+        })
+    }
+}
+
+impl Stash {
+    /// Write everything stored since snapshot `since` to `out` as a
+    /// single bundle, which `apply_bundle` adds to another copy of
+    /// the stash that already has that snapshot. Without `since`, the
+    /// bundle holds the whole stash.
+    ///
+    /// Objects are bundled in their encrypted form, so a bundle needs
+    /// the same care as the stash itself. Metadata is rewritten in
+    /// full on every commit, so it's always included. Returns the
+    /// number of objects written.
+    pub fn export_bundle(&mut self, since: Option<u64>, out: &mut impl Write) -> Result<u64> {
+        self.load(meta::Field::Files)?;
+        self.load(meta::Field::Chunks)?;
+        self.load(meta::Field::Snapshots)?;
+
+        let since = since.unwrap_or(0);
+        if since > 0 && self.snapshots.get(since).is_none() {
+            return Err(ZerostashError::Bundle(format!("no snapshot {}", since)));
+        }
+
+        // objects the other copy already has
+        let mut present = HashSet::new();
+        for snapshot in self.snapshots.list().iter().filter(|s| s.id <= since) {
+            for file in snapshot.files.iter() {
+                present.extend(file.chunks.iter().map(|(_, cp)| cp.file));
+            }
+        }
+
+        let mut objects = HashSet::new();
+        self.chunks.index().for_each(|_, cp| {
+            if !present.contains(&cp.file) {
+                objects.insert(cp.file);
+            }
+        });
+        let mut objects = objects.into_iter().collect::<Vec<_>>();
+
+        // the root goes last, so an interrupted `apply_bundle` leaves
+        // the old metadata in place
+        let mut metadata = self.metadata_objects()?;
+        metadata.reverse();
+        objects.extend(metadata);
+        // and the signature right before it
+        let signature = match self.generation {
+            0 => None,
+            generation => Some(self.master_key.signature_object_id(generation)?),
+        };
+        if let Some(id) = signature {
+            objects.insert(objects.len() - 1, id);
+        }
+
+        let root = self.master_key.root_object_id()?;
+        // bundles older builds can apply, where possible
+        let version = if self.object_size == BLOCK_SIZE {
+            VERSION
+        } else {
+            SIZED_VERSION
+        };
+        Header {
+            version,
+            since,
+            count: objects.len() as u64,
+            root,
+        }
+        .write(out)?;
+
+        for id in objects.iter() {
+            let object = self.backend.read_object(id)?;
+            let mut data = object.buffer.as_ref().to_vec();
+            out.write_all(id.as_ref())?;
+            if version == SIZED_VERSION {
+                out.write_all(&(data.len() as u32).to_le_bytes())?;
+                out.write_all(&data)?;
+                continue;
+            }
+
+            // every object of the first version takes a full block
+            if Some(*id) == signature {
+                data.resize(BLOCK_SIZE, 0);
+            }
+            if data.len() != BLOCK_SIZE {
+                return Err(ZerostashError::Corrupt { object: *id });
+            }
+            out.write_all(&data)?;
+        }
+
+        debug!(
+            "exported {} objects since snapshot {}",
+            objects.len(),
+            since
+        );
+        Ok(objects.len() as u64)
+    }
+
+    /// Store the objects of a bundle made by `export_bundle`, and read
+    /// the updated metadata. Returns the number of objects stored.
+    ///
+    /// Unless the bundle holds the whole stash, this stash needs to
+    /// have the snapshot the bundle was exported since.
+    pub fn apply_bundle(&mut self, input: &mut impl Read) -> Result<u64> {
+        let header = Header::read(input)?;
+        if header.root != self.master_key.root_object_id()? {
+            return Err(ZerostashError::Bundle(
+                "bundle was exported from a different stash".into(),
+            ));
+        }
+
+        if header.since > 0 {
+            self.read_fields(&[meta::Field::Snapshots])?;
+            if self.snapshots.get(header.since).is_none() {
+                return Err(ZerostashError::Bundle(format!(
+                    "missing snapshot {} the bundle is based on",
+                    header.since
+                )));
+            }
+        }
+
+        let mut id = [0; 32];
+        let mut size = [0; 4];
+        let mut object = Object::new(BlockBuffer::default());
+        for _ in 0..header.count {
+            input.read_exact(&mut id)?;
+            if header.version == SIZED_VERSION {
+                input.read_exact(&mut size)?;
+                let size = u32::from_le_bytes(size) as usize;
+                if size > MAX_OBJECT_SIZE {
+                    return Err(ZerostashError::Bundle(format!(
+                        "object of {} bytes is too large",
+                        size
+                    )));
+                }
+                object = Object::with_id(ObjectId::default(), BlockBuffer::with_size(size));
+            }
+            input.read_exact(object.buffer.as_mut())?;
+            object.set_id(ObjectId::from_bytes(id));
+            self.backend.write_object(&object)?;
+        }
+
+        if header.count == 0 || object.id != header.root {
+            return Err(ZerostashError::Bundle("truncated bundle".into()));
+        }
+
+        // reading appends to what's in memory
+        self.chunks = Default::default();
+        self.files = Default::default();
+        self.snapshots = Default::default();
+        self.read()?;
+        Ok(header.count)
+    }
+
+    /// Ids of the committed metadata objects, starting at the root,
+    /// then the dictionary they are compressed with and their layout.
+    fn metadata_objects(&self) -> Result<Vec<ObjectId>> {
+        let mut metareader =
+            meta::Reader::new(self.backend.clone(), self.master_key.get_meta_crypto()?);
+        let root = self.master_key.root_object_id()?;
+        let mut next_object = Some(root);
+        let mut ids = vec![];
+        let mut dictionary = self.dictionary.as_ref().map(|(reference, _)| *reference);
+        let mut layout = None;
+
+        while let Some(id) = next_object {
+            let header = metareader
+                .open(&id)
+                .map_err(|e| ZerostashError::reading(id, id == root, e))?;
+            next_object = header.next_object();
+            dictionary = dictionary.or_else(|| header.dictionary());
+            layout = layout.or_else(|| header.layout());
+            ids.push(id);
+
+            // objects reused from earlier commits are only listed there
+            if let Some(stored) = layout.filter(|_| id == root) {
+                if let Ok(listed) = metareader.read_layout(&stored) {
+                    ids = listed.into_iter().map(|(id, _)| id).collect();
+                    break;
+                }
+            }
+        }
+
+        ids.extend(dictionary.map(|reference| reference.object));
+        ids.extend(layout);
+        Ok(ids)
+    }
+}
+
+#[cfg(all(test, feature = "fs"))]
+mod tests {
+    #[test]
+    fn bundles_replicate_new_snapshots() {
+        use crate::backends::MemoryBackend;
+        use crate::stash::{BackupOptions, CancelToken, Stash, StashKey};
+        use std::sync::Arc;
+
+        let key = || StashKey::open_stash("bundle", "test").unwrap();
+        let mut origin = Stash::new(Arc::new(MemoryBackend::default()), key());
+        origin
+            .backup(&["tests/data/100_random_1k"], &BackupOptions::default())
+            .unwrap();
+
+        let mut full = vec![];
+        let full_count = origin.export_bundle(None, &mut full).unwrap();
+        let mut copy = Stash::new(Arc::new(MemoryBackend::default()), key());
+        assert_eq!(copy.apply_bundle(&mut &full[..]).unwrap(), full_count);
+        assert_eq!(copy.snapshots().len(), 1);
+
+        origin
+            .backup(&["tests/data/10k_random_blob"], &BackupOptions::default())
+            .unwrap();
+        let mut patch = vec![];
+        origin.export_bundle(Some(1), &mut patch).unwrap();
+
+        // with nothing new, only the metadata and its signature are
+        // bundled
+        let metadata = origin.metadata_objects().unwrap().len() as u64;
+        assert_eq!(
+            origin.export_bundle(Some(2), &mut vec![]).unwrap(),
+            metadata + 1
+        );
+
+        // a copy without the base snapshot can't take the patch
+        let mut empty = Stash::new(Arc::new(MemoryBackend::default()), key());
+        assert!(empty.apply_bundle(&mut &patch[..]).is_err());
+
+        copy.apply_bundle(&mut &patch[..]).unwrap();
+        assert_eq!(copy.snapshots().len(), 2);
+        assert_eq!(
+            copy.verify(&CancelToken::default()).unwrap(),
+            origin.chunk_index().len() as u64
+        );
+
+        let truncated = &patch[..patch.len() - 1];
+        assert!(copy.apply_bundle(&mut &truncated[..]).is_err());
+
+        // objects of other sizes are bundled with their size
+        let mut small = Stash::new(Arc::new(MemoryBackend::default()), key());
+        small
+            .set_object_size(crate::format::MIN_OBJECT_SIZE)
+            .unwrap();
+        small.set_chunker(crate::splitter::Chunker::FastCdc);
+        small
+            .backup(&["tests/data/100_random_1k"], &BackupOptions::default())
+            .unwrap();
+        let mut sized = vec![];
+        let count = small.export_bundle(None, &mut sized).unwrap();
+        let mut copy = Stash::new(Arc::new(MemoryBackend::default()), key());
+        assert_eq!(copy.apply_bundle(&mut &sized[..]).unwrap(), count);
+        assert_eq!(copy.object_size(), crate::format::MIN_OBJECT_SIZE);
+        assert_eq!(
+            copy.verify(&CancelToken::default()).unwrap(),
+            small.chunk_index().len() as u64
+        );
+    }
+}
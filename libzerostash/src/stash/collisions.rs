@@ -0,0 +1,31 @@
+use std::str::FromStr;
+
+/// What restores do with files whose paths name a file that's
+/// already restored under another path, as they do on file systems
+/// that ignore case, like those of Windows and macOS, or normalize
+/// Unicode, like APFS.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Collisions {
+    /// Stop the restore with `ZerostashError::Collision`
+    Error,
+    /// Restore the later file as `name~1`, or the first number that's
+    /// free, before the extension
+    #[default]
+    Rename,
+    /// Leave the later file out
+    Skip,
+}
+
+impl FromStr for Collisions {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(Collisions::Error),
+            "rename" => Ok(Collisions::Rename),
+            "skip" => Ok(Collisions::Skip),
+            _ => Err(format!("unknown collision policy: {}", s)),
+        }
+    }
+}
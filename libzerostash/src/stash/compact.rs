@@ -0,0 +1,117 @@
+use crate::error::Result;
+use crate::meta;
+use crate::objects::ObjectId;
+use crate::stash::Stash;
+
+use std::collections::HashSet;
+
+/// What `Stash::compact` retired.
+#[derive(Clone, Debug, Default)]
+pub struct Compacted {
+    /// The number of earlier commits whose metadata was retired
+    pub commits: u64,
+    /// Their metadata and signature objects, which are marked to be
+    /// collected
+    pub objects: Vec<ObjectId>,
+}
+
+impl Stash {
+    /// Rewrite the metadata in a fresh set of objects, and retire
+    /// those of the commits before, then commit.
+    ///
+    /// Every commit writes all the metadata anew, but leaves what the
+    /// ones before wrote in the backend. The retired objects are
+    /// deleted by `collect_garbage`, once readers that opened an
+    /// earlier commit had time to finish. Only the last commit is
+    /// known whole, the layouts of the ones before, and commits from
+    /// before they were signed, stay behind.
+    pub fn compact(&mut self) -> Result<Compacted> {
+        self.check_mutable("compact metadata")?;
+        self.load(meta::Field::Files)?;
+        self.load(meta::Field::Chunks)?;
+        self.load(meta::Field::Snapshots)?;
+
+        let root = self.master_key.root_object_id()?;
+        let mut retired = self
+            .layout
+            .iter()
+            .map(|(id, _)| *id)
+            .collect::<HashSet<_>>();
+        // the layouts of earlier commits aren't recorded anywhere
+        if !self.layout.is_empty() {
+            let (_, header) = self.open_root(&root)?;
+            retired.extend(header.layout());
+        }
+        let mut commits = 0;
+        for generation in (1..=self.generation).rev() {
+            match self.commit_objects(generation)? {
+                Some(objects) => retired.extend(objects),
+                // collected by an earlier compaction
+                None => break,
+            }
+            commits += 1;
+        }
+        retired.remove(&root);
+        if let Some((reference, _)) = &self.dictionary {
+            retired.remove(&reference.object);
+        }
+
+        debug!(
+            "retiring {} metadata objects of {} commits",
+            retired.len(),
+            commits
+        );
+        self.mark_garbage(retired.iter().copied());
+        self.write_commit(false)?;
+
+        Ok(Compacted {
+            commits,
+            objects: retired.into_iter().collect(),
+        })
+    }
+}
+
+#[cfg(all(test, feature = "fs"))]
+mod tests {
+    #[test]
+    fn metadata_of_earlier_commits_is_retired() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use crate::stash::{BackupOptions, GcOptions, StashKey};
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        let backend = MemoryBackend::default();
+        let key = || StashKey::open_stash("compact", "test").unwrap();
+        let mut stash = Stash::new(Arc::new(backend.clone()), key());
+        for _ in 0..3 {
+            stash
+                .backup(&["tests/data/100_random_1k"], &BackupOptions::default())
+                .unwrap();
+        }
+
+        let mut stash = Stash::new(Arc::new(backend.clone()), key());
+        stash.read().unwrap();
+        let compacted = stash.compact().unwrap();
+        assert_eq!(compacted.commits, 3);
+        let now = GcOptions {
+            grace: Duration::ZERO,
+            ..GcOptions::default()
+        };
+        let collected = stash.collect_garbage(&now).unwrap();
+        assert_eq!(collected.deleted.len(), compacted.objects.len());
+        assert!(compacted.objects.iter().all(|o| !backend.contains(o)));
+        // only the signature of each commit, as small stashes fit in
+        // the root, with their layout
+        assert_eq!(compacted.objects.len(), 3);
+
+        // the signatures of the retired commits are gone, so only
+        // the compaction and the collection are walked
+        assert_eq!(stash.compact().unwrap().commits, 2);
+
+        let mut stash = Stash::new(Arc::new(backend), key());
+        stash.read().unwrap();
+        assert_eq!(stash.snapshots().len(), 3);
+        assert_eq!(stash.generation(), 6);
+    }
+}
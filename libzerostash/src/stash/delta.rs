@@ -0,0 +1,373 @@
+use crate::chunks::ChunkStore;
+use crate::crypto::CryptoDigest;
+use crate::error::Result;
+use crate::files::{Entry, FileStore};
+use crate::meta::{self, Field, FieldReader, FieldWriter, MetaObjectField, WriteError};
+use crate::objects::ObjectId;
+use crate::snapshots::{Snapshot, SnapshotStore};
+use crate::stash::Stash;
+
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+/// The fields a commit can write only the changes to.
+const FIELDS: [Field; 3] = [Field::Chunks, Field::Files, Field::Snapshots];
+
+/// Commits with more objects than this are written in full, so the
+/// layout of the next one surely fits in an object.
+const MAX_REUSED: usize = 16 * 1024;
+
+/// Removed chunks are stored in batches of this many hashes.
+const HASHES_PER_BATCH: usize = 1024;
+
+/// The files and snapshots of the commit that was read or written,
+/// which the changes of the next are found against. Changes to the
+/// chunk index are tracked by the index itself.
+#[derive(Default)]
+pub(super) struct Base {
+    files: Option<Vec<Arc<Entry>>>,
+    snapshots: Option<Vec<Arc<Snapshot>>>,
+}
+
+/// A record of the `Removed` field.
+#[derive(Serialize, Deserialize)]
+pub(super) enum Removal {
+    /// Packed hashes of chunks
+    Chunks(serde_bytes::ByteBuf),
+    File(Arc<Entry>),
+    Snapshot(u64),
+}
+
+/// What a commit that only wrote what changed removed from the fields
+/// of the commits before.
+#[derive(Default)]
+pub(super) struct Removed {
+    chunks: Mutex<Vec<CryptoDigest>>,
+    files: Mutex<Vec<Arc<Entry>>>,
+    snapshots: Mutex<Vec<u64>>,
+}
+
+impl Removed {
+    fn is_empty(&self) -> bool {
+        self.chunks.lock().unwrap().is_empty()
+            && self.files.lock().unwrap().is_empty()
+            && self.snapshots.lock().unwrap().is_empty()
+    }
+
+    /// Remove what was read from the stores of `fields`, which holds
+    /// what the commits before wrote to them.
+    pub(super) fn apply(
+        &self,
+        fields: &[Field],
+        chunks: &ChunkStore,
+        files: &FileStore,
+        snapshots: &SnapshotStore,
+    ) {
+        let removed = std::mem::take(&mut *self.chunks.lock().unwrap());
+        if fields.contains(&Field::Chunks) && !removed.is_empty() {
+            let removed = removed.into_iter().collect::<HashSet<_>>();
+            chunks.index().retain(|hash| !removed.contains(hash));
+        }
+        let removed = std::mem::take(&mut *self.files.lock().unwrap());
+        if fields.contains(&Field::Files) {
+            for file in removed {
+                files.index().remove(&file);
+            }
+        }
+        let removed = std::mem::take(&mut *self.snapshots.lock().unwrap());
+        if fields.contains(&Field::Snapshots) && !removed.is_empty() {
+            snapshots.retain(|s| !removed.contains(&s.id));
+        }
+    }
+}
+
+impl MetaObjectField for Removed {
+    type Item = Removal;
+
+    fn serialize(&self, mw: &mut impl FieldWriter) -> std::result::Result<(), WriteError> {
+        for batch in self.chunks.lock().unwrap().chunks(HASHES_PER_BATCH) {
+            let hashes = batch.concat();
+            mw.write_next(Removal::Chunks(serde_bytes::ByteBuf::from(hashes)))?;
+        }
+        for file in self.files.lock().unwrap().iter() {
+            mw.write_next(Removal::File(file.clone()))?;
+        }
+        for id in self.snapshots.lock().unwrap().iter() {
+            mw.write_next(Removal::Snapshot(*id))?;
+        }
+        Ok(())
+    }
+
+    fn deserialize(&self, mw: &mut impl FieldReader<Self::Item>) {
+        while let Ok(removal) = mw.read_next() {
+            match removal {
+                Removal::Chunks(hashes) => {
+                    let mut chunks = self.chunks.lock().unwrap();
+                    for hash in hashes.chunks_exact(32) {
+                        let mut digest = CryptoDigest::default();
+                        digest.copy_from_slice(hash);
+                        chunks.push(digest);
+                    }
+                }
+                Removal::File(file) => self.files.lock().unwrap().push(file),
+                Removal::Snapshot(id) => self.snapshots.lock().unwrap().push(id),
+            }
+        }
+    }
+}
+
+/// What changed since the commit that was read or written, on top of
+/// whose objects the next commit only writes that.
+pub(super) struct Changes {
+    reused: Vec<(ObjectId, CryptoDigest, Vec<Field>)>,
+    removed: Removed,
+    chunks: ChunkStore,
+    files: FileStore,
+    snapshots: SnapshotStore,
+}
+
+/// If `field` has to be read to load `fields`, which need what
+/// commits that only wrote what changed removed from them.
+pub(super) fn needs(fields: &[Field], field: &Field) -> bool {
+    fields.contains(field)
+        || (*field == Field::Removed && fields.iter().any(|f| FIELDS.contains(f)))
+}
+
+impl Stash {
+    /// Only write what changed to the metadata at commits, for up to
+    /// `commits` commits in a row, after which all of it is written
+    /// again. By default, with 0, all of it is written every time.
+    ///
+    /// A commit then costs about as much as what changed, instead of
+    /// the whole stash. It refers to the metadata objects of the
+    /// commits before, which are kept until `Stash::compact` writes
+    /// all of the metadata anew. Reading the stash reads them all,
+    /// and builds from before this can't. The files and snapshots of
+    /// each commit are remembered in memory, to find what changed.
+    pub fn set_delta_commits(&mut self, commits: u32) {
+        self.delta_commits = commits;
+    }
+
+    pub fn delta_commits(&self) -> u32 {
+        self.delta_commits
+    }
+
+    /// How many commits in a row only wrote what changed, as of the
+    /// one that was read or written.
+    pub fn deltas(&self) -> u32 {
+        self.deltas
+    }
+
+    /// Put `field` in order once it's loaded, and remember it as it
+    /// is, so the next commit can write only what changes.
+    pub(super) fn field_loaded(&mut self, field: &Field) {
+        if self.deltas > 0 && *field == Field::Snapshots {
+            self.snapshots.sort();
+        }
+        if self.delta_commits == 0 {
+            return;
+        }
+        match field {
+            Field::Chunks => self.chunks.track_changes(),
+            Field::Files => {
+                let files = self.files.index().iter().map(|f| f.key().clone());
+                self.base.files = Some(files.collect());
+            }
+            Field::Snapshots => self.base.snapshots = Some(self.snapshots.list()),
+            _ => {}
+        }
+    }
+
+    /// What changed since the commit that was read or written, if the
+    /// next commit can write only that, on top of its objects.
+    pub(super) fn changes(&self) -> Result<Option<Changes>> {
+        let root = self.master_key.root_object_id()?;
+        let based = self.deltas < self.delta_commits
+            && self.generation > 0
+            && self.meta_version == meta::META_VERSION
+            && self.layout.len() <= MAX_REUSED
+            // the root is overwritten, so it can't hold anything else
+            && matches!(self.layout.first(), Some((id, fields)) if *id == root && fields[..] == [Field::Format]);
+        let (base_files, base_snapshots) = match (&self.base.files, &self.base.snapshots) {
+            (Some(files), Some(snapshots)) if based => (files, snapshots),
+            _ => return Ok(None),
+        };
+        let (removed_chunks, chunks) = match self.chunks.tracked_changes() {
+            Some(changes) => changes,
+            None => return Ok(None),
+        };
+
+        let mut reused = vec![];
+        for (id, fields) in self.layout[1..].iter() {
+            let digest = match self.digests.get(id) {
+                Some(digest) => *digest,
+                // not signed
+                None => return Ok(None),
+            };
+            let fields = fields
+                .iter()
+                .filter(|f| needs(&FIELDS, f))
+                .cloned()
+                .collect::<Vec<_>>();
+            if !fields.is_empty() {
+                reused.push((*id, digest, fields));
+            }
+        }
+
+        // anything replaced since is a new `Arc`
+        let before = base_files.iter().map(Arc::as_ptr).collect::<HashSet<_>>();
+        let mut now = HashSet::new();
+        let mut files = FileStore::default();
+        for file in self.files.index().iter() {
+            now.insert(Arc::as_ptr(file.key()));
+            if !before.contains(&Arc::as_ptr(file.key())) {
+                files.insert(file.key().clone());
+            }
+        }
+        let removed_files = base_files
+            .iter()
+            .filter(|f| !now.contains(&Arc::as_ptr(f)))
+            .cloned()
+            .collect();
+
+        let current = self.snapshots.list();
+        let before = base_snapshots
+            .iter()
+            .map(Arc::as_ptr)
+            .collect::<HashSet<_>>();
+        let now = current.iter().map(Arc::as_ptr).collect::<HashSet<_>>();
+        let snapshots = current
+            .iter()
+            .filter(|s| !before.contains(&Arc::as_ptr(s)))
+            .cloned()
+            .collect();
+        let removed_snapshots = base_snapshots
+            .iter()
+            .filter(|s| !now.contains(&Arc::as_ptr(s)))
+            .map(|s| s.id)
+            .collect();
+
+        Ok(Some(Changes {
+            reused,
+            removed: Removed {
+                chunks: Mutex::new(removed_chunks),
+                files: Mutex::new(removed_files),
+                snapshots: Mutex::new(removed_snapshots),
+            },
+            chunks,
+            files,
+            snapshots: SnapshotStore::with(snapshots),
+        }))
+    }
+
+    /// Write `changes` with `mw`, after the objects they're on top of.
+    /// What was removed goes first, so readers drop it before reading
+    /// what replaced it.
+    pub(super) fn write_changes(
+        &self,
+        mw: &mut meta::Writer<impl crate::crypto::CryptoProvider>,
+        changes: Changes,
+    ) -> Result<()> {
+        debug!(
+            "committing {} new files and {} new chunks on top of {} objects",
+            changes.files.index().len(),
+            changes.chunks.index().len(),
+            changes.reused.len()
+        );
+        mw.reuse(changes.reused);
+        if !changes.removed.is_empty() {
+            mw.write_field(Field::Removed, &changes.removed)?;
+        }
+        if !changes.files.index().is_empty() {
+            mw.write_field(Field::Files, &changes.files)?;
+        }
+        if !changes.chunks.index().is_empty() {
+            mw.write_field(Field::Chunks, &changes.chunks)?;
+        }
+        if !changes.snapshots.list().is_empty() {
+            mw.write_field(Field::Snapshots, &changes.snapshots)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn commits_write_only_what_changed() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use crate::snapshots::Labels;
+        use crate::stash::{Retention, StashKey};
+
+        let backend = Arc::new(MemoryBackend::default());
+        let key = || StashKey::open_stash("delta", "test").unwrap();
+        let add = |stash: &mut Stash, name: &str| {
+            let mut ingest = stash.ingest().unwrap();
+            ingest
+                .add_file(Entry::from_stream(name), name.as_bytes())
+                .unwrap();
+            let secs = u64::from(name.as_bytes()[0]);
+            ingest.finish(secs, vec![], vec![], Labels::new()).unwrap();
+        };
+        let read = || {
+            let mut stash = Stash::new(backend.clone(), key());
+            stash.set_delta_commits(2);
+            stash.read().unwrap();
+            stash
+        };
+        let names = |stash: &Stash| {
+            let mut names = stash
+                .file_index()
+                .iter()
+                .map(|f| f.key().name.clone())
+                .collect::<Vec<_>>();
+            names.sort();
+            names
+        };
+
+        let mut stash = Stash::new(backend.clone(), key());
+        stash.set_delta_commits(2);
+        add(&mut stash, "a");
+        assert_eq!(stash.deltas(), 0);
+        let objects = stash.layout.clone();
+
+        // on top of the objects of the first
+        add(&mut stash, "b");
+        assert_eq!(stash.deltas(), 1);
+        assert!(objects[1..].iter().all(|o| stash.layout.contains(o)));
+        let mut stash = read();
+        assert_eq!(stash.deltas(), 1);
+        assert_eq!(names(&stash), vec!["a", "b"]);
+        assert_eq!(stash.chunk_index().len(), 2);
+
+        // what's removed is removed once read too
+        let last = Retention {
+            last: 1,
+            ..Retention::default()
+        };
+        stash.prune(&last).unwrap();
+        assert_eq!(stash.deltas(), 2);
+        let mut stash = read();
+        assert_eq!(names(&stash), vec!["b"]);
+        assert_eq!(stash.chunk_index().len(), 1);
+        let ids = stash.snapshots().iter().map(|s| s.id).collect::<Vec<_>>();
+        assert_eq!(ids, vec![2]);
+
+        // all of it again, after as many as it's set to
+        add(&mut stash, "c");
+        assert_eq!(stash.deltas(), 0);
+        assert!(!stash.layout.iter().any(|o| objects[1..].contains(o)));
+        add(&mut stash, "d");
+        assert_eq!(stash.deltas(), 1);
+        stash.compact().unwrap();
+        assert_eq!(stash.deltas(), 0);
+
+        let stash = read();
+        assert_eq!(names(&stash), vec!["b", "c", "d"]);
+        assert_eq!(stash.snapshots().len(), 3);
+        assert_eq!(stash.chunk_index().len(), 3);
+        let ids = stash.snapshots().iter().map(|s| s.id).collect::<Vec<_>>();
+        assert_eq!(ids, vec![2, 3, 4]);
+    }
+}
@@ -0,0 +1,168 @@
+use crate::files::Entry;
+use crate::snapshots::Snapshot;
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// A path that's different in two snapshots.
+#[derive(Clone)]
+pub enum Change {
+    Added(Arc<Entry>),
+    Removed(Arc<Entry>),
+    /// The contents or the metadata of the file changed
+    Modified {
+        from: Arc<Entry>,
+        to: Arc<Entry>,
+    },
+}
+
+impl Change {
+    pub fn name(&self) -> &str {
+        match self {
+            Change::Added(entry) | Change::Removed(entry) => &entry.name,
+            Change::Modified { to, .. } => &to.name,
+        }
+    }
+
+    /// How many bytes larger the file got, negative if it shrank.
+    pub fn size_delta(&self) -> i64 {
+        match self {
+            Change::Added(entry) => entry.size as i64,
+            Change::Removed(entry) => -(entry.size as i64),
+            Change::Modified { from, to } => to.size as i64 - from.size as i64,
+        }
+    }
+
+    /// If the contents are different, and not only the metadata, like
+    /// the modification time.
+    pub fn is_content_changed(&self) -> bool {
+        match self {
+            Change::Added(_) | Change::Removed(_) => true,
+            Change::Modified { from, to } => {
+                from.size != to.size
+                    || !from
+                        .chunks
+                        .iter()
+                        .map(|(_, cp)| cp.hash)
+                        .eq(to.chunks.iter().map(|(_, cp)| cp.hash))
+            }
+        }
+    }
+}
+
+/// The changes from one snapshot to another, sorted by path.
+#[derive(Clone, Default)]
+pub struct Diff {
+    pub changes: Vec<Change>,
+}
+
+impl Diff {
+    pub fn added(&self) -> usize {
+        self.count(|c| matches!(c, Change::Added(_)))
+    }
+
+    pub fn removed(&self) -> usize {
+        self.count(|c| matches!(c, Change::Removed(_)))
+    }
+
+    pub fn modified(&self) -> usize {
+        self.count(|c| matches!(c, Change::Modified { .. }))
+    }
+
+    /// How many bytes larger the later snapshot is.
+    pub fn size_delta(&self) -> i64 {
+        self.changes.iter().map(Change::size_delta).sum()
+    }
+
+    fn count(&self, kind: impl Fn(&Change) -> bool) -> usize {
+        self.changes.iter().filter(|c| kind(c)).count()
+    }
+}
+
+/// What changed in snapshot `to` since `from`, by path. Only the
+/// metadata of the snapshots is compared, so no data object is read.
+pub fn diff(from: &Snapshot, to: &Snapshot) -> Diff {
+    let mut before = by_name(from);
+    let after = by_name(to);
+
+    let mut changes = vec![];
+    for (name, entry) in after {
+        match before.remove(name) {
+            None => changes.push(Change::Added(entry)),
+            Some(old) if old != entry => changes.push(Change::Modified {
+                from: old,
+                to: entry,
+            }),
+            Some(_) => {}
+        }
+    }
+    changes.extend(before.into_values().map(Change::Removed));
+    changes.sort_by(|a, b| a.name().cmp(b.name()));
+
+    Diff { changes }
+}
+
+fn by_name(snapshot: &Snapshot) -> BTreeMap<&str, Arc<Entry>> {
+    snapshot
+        .files
+        .iter()
+        .map(|f| (f.name.as_str(), f.clone()))
+        .collect()
+}
+
+#[cfg(all(test, feature = "fs"))]
+mod tests {
+    #[test]
+    fn snapshots_are_compared_by_path() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use crate::snapshots::Labels;
+        use crate::stash::{Stash, StashKey};
+
+        let key = StashKey::open_stash("diff", "test").unwrap();
+        let mut stash = Stash::new(Arc::new(MemoryBackend::default()), key);
+        let mut snapshot = |files: Vec<(&str, &[u8], u64)>| {
+            let mut ingest = stash.ingest().unwrap();
+            for (name, data, unix_secs) in files {
+                let entry = Entry {
+                    unix_secs,
+                    unix_nanos: 0,
+                    ..Entry::from_stream(name)
+                };
+                ingest.add_file(entry, data).unwrap();
+            }
+            ingest.finish(0, vec![], vec![], Labels::new()).unwrap()
+        };
+
+        let first = snapshot(vec![
+            ("kept", b"same", 1),
+            ("touched", b"same", 1),
+            ("grown", b"short", 1),
+            ("gone", b"bye", 1),
+        ]);
+        let second = snapshot(vec![
+            ("kept", b"same", 1),
+            ("touched", b"same", 2),
+            ("grown", b"much longer", 2),
+            ("new", b"hello", 2),
+        ]);
+
+        let diff = diff(&first, &second);
+        let changes = diff
+            .changes
+            .iter()
+            .map(|c| (c.name(), c.size_delta(), c.is_content_changed()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            changes,
+            vec![
+                ("gone", -3, true),
+                ("grown", 6, true),
+                ("new", 5, true),
+                ("touched", 0, false),
+            ]
+        );
+        assert_eq!((diff.added(), diff.removed(), diff.modified()), (1, 1, 2));
+        assert_eq!(diff.size_delta(), 8);
+    }
+}
@@ -0,0 +1,151 @@
+use crate::chunks::{self, ChunkPointer};
+use crate::crypto::CryptoDigest;
+use crate::error::{Result, ZerostashError};
+use crate::objects::{self, ObjectStore};
+use crate::progress::Phase;
+use crate::stash::{store, BackupOptions, Stash};
+use crate::stats::{Collector, Summary};
+use crate::{files, meta};
+
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// What a backup would store, without storing it.
+#[derive(Clone, Debug)]
+pub struct DryRun {
+    /// The chunks a backup would upload, and the files that are new
+    /// or changed, with how many of their bytes are in new chunks.
+    /// Nothing is compressed, so bytes are counted before compression
+    pub stats: chunks::Stats,
+    /// The files and bytes walked
+    pub summary: Summary,
+}
+
+/// Remembers the chunks it's given, instead of writing them.
+#[derive(Clone, Default)]
+struct DryStorage(Arc<Mutex<HashSet<CryptoDigest>>>);
+
+impl ObjectStore for DryStorage {
+    fn store_chunk(
+        &mut self,
+        hash: &CryptoDigest,
+        data: &[u8],
+    ) -> objects::Result<Arc<ChunkPointer>> {
+        self.0.lock().unwrap().insert(*hash);
+        Ok(Arc::new(ChunkPointer {
+            size: data.len() as u32,
+            hash: *hash,
+            ..ChunkPointer::default()
+        }))
+    }
+
+    fn flush(&mut self) -> objects::Result<()> {
+        Ok(())
+    }
+}
+
+impl Stash {
+    /// Walk `paths` as `backup` would with `options`, and report
+    /// which files and chunks it would upload, without writing any
+    /// objects.
+    ///
+    /// Files the file cache knows are unchanged are skipped as by a
+    /// backup, everything else is read and split, and its chunks are
+    /// looked up in the chunk index. The indexes and the cache are
+    /// left as they were. Unlike `analyze`, which only estimates the
+    /// size of new data, this tells which files it's in.
+    pub fn dry_run(
+        &mut self,
+        paths: &[impl AsRef<Path>],
+        options: &BackupOptions,
+    ) -> Result<DryRun> {
+        self.load(meta::Field::Chunks)?;
+
+        let threads = options.threads.unwrap_or(self.threads);
+        let rules = self.chunking_rules(options)?;
+        let filter = options.filter()?;
+        let cache = self.file_cache.as_ref().map(|c| {
+            let mut cache = c.scratch();
+            cache.set_epoch(self.epoch);
+            cache
+        });
+        let earlier = self.chunks.take_stats();
+
+        let stats = Collector::new(self.progress.clone());
+        let start = Instant::now();
+        self.progress.phase(Phase::Store, None);
+
+        let mut storage = DryStorage::default();
+        let mut run = files::FileStore::default();
+        for path in paths.iter() {
+            store::recursive(
+                threads,
+                self.schedule,
+                options.symlinks,
+                &filter,
+                &rules,
+                &mut self.chunks,
+                &mut run,
+                &mut storage,
+                cache.as_ref(),
+                &stats,
+                &options.cancel,
+                path,
+            )?;
+        }
+
+        // the chunks that would be new only ever point nowhere
+        let new = storage.0.lock().unwrap();
+        self.chunks.index().retain(|hash| !new.contains(hash));
+        let dry = self.chunks.take_stats();
+        self.chunks.put_stats(earlier);
+
+        if options.cancel.is_cancelled() {
+            return Err(ZerostashError::Cancelled);
+        }
+
+        Ok(DryRun {
+            stats: dry,
+            summary: stats.summary(start.elapsed()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn dry_runs_write_nothing() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use crate::stash::StashKey;
+
+        let backend = MemoryBackend::default();
+        let key = || StashKey::open_stash("dry run", "test").unwrap();
+        let mut stash = Stash::new(Arc::new(backend.clone()), key());
+        let paths = ["tests/data/100_random_1k"];
+        let options = BackupOptions::default();
+
+        let dry = stash.dry_run(&paths, &options).unwrap();
+        assert!(backend.is_empty());
+        assert!(stash.chunk_index().is_empty());
+        assert_eq!(dry.stats.files.len(), 100);
+        assert_eq!(dry.stats.new_bytes, dry.stats.logical_bytes);
+        assert_eq!(dry.summary.files, 100);
+
+        let snapshot = stash.backup(&paths, &options).unwrap();
+        let stored = stash.chunk_index().len();
+        assert_eq!(stored as u64, dry.stats.new_chunks);
+        assert_eq!(stash.snapshots().len(), 1);
+        assert_eq!(snapshot.files.len(), 100);
+
+        // everything is stored now
+        let objects = backend.len();
+        let dry = stash.dry_run(&paths, &options).unwrap();
+        assert_eq!(dry.stats.new_chunks, 0);
+        assert_eq!(dry.stats.reused_chunks as usize, stored);
+        assert_eq!(backend.len(), objects);
+        assert_eq!(stash.chunk_index().len(), stored);
+    }
+}
@@ -0,0 +1,118 @@
+use crate::error::Result;
+use crate::meta;
+use crate::stash::Stash;
+
+use serde_json::{json, Value};
+
+use std::collections::BTreeMap;
+
+impl Stash {
+    /// Describe the internal state of the stash as JSON, for bug
+    /// reports and debugging broken stashes.
+    ///
+    /// The dump contains the metadata layout, the objects referenced
+    /// by the chunk index, and the snapshot manifests. Keys, chunk
+    /// hashes and authentication tags are never included, but file
+    /// names are.
+    pub fn dump(&mut self) -> Result<Value> {
+        self.load(meta::Field::Files)?;
+        self.load(meta::Field::Chunks)?;
+        self.load(meta::Field::Snapshots)?;
+
+        let layout = self
+            .layout
+            .iter()
+            .map(|(id, fields)| {
+                json!({
+                    "object": id.to_string(),
+                    "fields": fields.iter().map(|f| format!("{:?}", f)).collect::<Vec<_>>(),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        // (chunks, bytes) stored in each object
+        let mut objects = BTreeMap::<String, (u64, u64)>::new();
+        self.chunks.index().for_each(|_, cp| {
+            let entry = objects.entry(cp.file.to_string()).or_default();
+            entry.0 += 1;
+            entry.1 += cp.size as u64;
+        });
+        let objects = objects
+            .into_iter()
+            .map(|(id, (chunks, bytes))| json!({ "id": id, "chunks": chunks, "bytes": bytes }))
+            .collect::<Vec<_>>();
+
+        let snapshots = self
+            .snapshots
+            .list()
+            .iter()
+            .map(|s| {
+                let files = s
+                    .files
+                    .iter()
+                    .map(|f| {
+                        json!({
+                            "name": f.name,
+                            "size": f.size,
+                            "unix_secs": f.unix_secs,
+                            "chunks": f.chunks.len(),
+                        })
+                    })
+                    .collect::<Vec<_>>();
+
+                json!({
+                    "id": s.id,
+                    "unix_secs": s.unix_secs,
+                    "paths": s.paths,
+                    "tags": s.tags,
+                    "labels": s.labels,
+                    "files": files,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        Ok(json!({
+            "root": self.master_key.root_object_id()?.to_string(),
+            "layout": layout,
+            "objects": objects,
+            "snapshots": snapshots,
+            "totals": {
+                "files": self.files.index().len(),
+                "chunks": self.chunks.index().len(),
+            },
+        }))
+    }
+}
+
+#[cfg(all(test, feature = "fs"))]
+mod tests {
+    #[test]
+    fn dump_describes_the_stash() {
+        use crate::backends::MemoryBackend;
+        use crate::stash::{BackupOptions, Stash, StashKey};
+        use std::sync::Arc;
+
+        let backend = Arc::new(MemoryBackend::default());
+        let key = || StashKey::open_stash("dump", "test").unwrap();
+        let mut stash = Stash::new(backend.clone(), key());
+        stash
+            .backup(&["tests/data/100_random_1k"], &BackupOptions::default())
+            .unwrap();
+
+        let mut stash = Stash::new(backend, key());
+        stash.read().unwrap();
+        let dump = stash.dump().unwrap();
+
+        assert_eq!(dump["totals"]["files"], 100);
+        assert_eq!(dump["snapshots"][0]["files"].as_array().unwrap().len(), 100);
+        assert!(!dump["layout"].as_array().unwrap().is_empty());
+
+        let chunks = dump["objects"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|o| o["chunks"].as_u64().unwrap())
+            .sum::<u64>();
+        assert_eq!(dump["totals"]["chunks"], chunks);
+    }
+}
@@ -0,0 +1,142 @@
+use crate::error::Result;
+use crate::format::FormatError;
+use crate::meta;
+use crate::stash::Stash;
+
+use std::collections::HashSet;
+
+impl Stash {
+    /// Deduplicate the next backups against the chunks of `family`,
+    /// another stash of the same convergence family in the same
+    /// backend, and return how many chunks it has that this one
+    /// doesn't. Equal chunks are encrypted the same way in both, so
+    /// files refer to the objects of `family` instead of storing
+    /// them again.
+    ///
+    /// Objects of `family` that files refer to are recorded on
+    /// commit, and are never deleted by this stash. Collecting
+    /// garbage in `family` doesn't know about them, so it has to keep
+    /// every object: the stash that wrote them is best left unpruned,
+    /// like one that only serves as the index of a fleet.
+    pub fn share_chunks(&mut self, family: &mut Stash) -> Result<u64> {
+        let convergence = match (self.convergence(), family.convergence()) {
+            (Some(a), Some(b)) if a == b && self.cipher() == family.cipher() => a.fingerprint(),
+            (Some(c), _) | (None, Some(c)) => {
+                return Err(FormatError::Family(c.fingerprint()).into())
+            }
+            (None, None) => return Err(FormatError::Family("none".into()).into()),
+        };
+        self.load(meta::Field::Chunks)?;
+        family.load(meta::Field::Chunks)?;
+
+        let mut objects = HashSet::new();
+        family.chunks.index().for_each(|hash, cp| {
+            if self.chunks.index().get(hash).is_none() {
+                self.chunks.index().insert(*hash, &cp);
+                self.borrowed.insert(*hash);
+                objects.insert(cp.file);
+            }
+        });
+
+        // the objects have to be readable from here
+        if let Some(object) = objects.iter().next() {
+            self.backend.read_range(object, 0, 1)?;
+        }
+        debug!(
+            "sharing {} chunks in {} objects of family {}",
+            self.borrowed.len(),
+            objects.len(),
+            convergence
+        );
+        Ok(self.borrowed.len() as u64)
+    }
+
+    /// Drop the borrowed chunks no file refers to from the index, and
+    /// record the objects of the ones that are.
+    pub(crate) fn settle_borrowed(&mut self) {
+        if self.borrowed.is_empty() {
+            return;
+        }
+
+        let live = self.live_chunks();
+        let borrowed = std::mem::take(&mut self.borrowed);
+        for hash in borrowed.iter().filter(|hash| live.contains(*hash)) {
+            if let Some(cp) = self.chunks.index().get(hash) {
+                self.shared.insert(cp.file);
+            }
+        }
+        self.chunks
+            .index()
+            .retain(|hash| live.contains(hash) || !borrowed.contains(hash));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn families_share_a_chunk_index() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use crate::crypto::ConvergenceSecret;
+        use crate::error::ZerostashError;
+        use crate::files::Entry;
+        use crate::snapshots::Labels;
+        use crate::stash::{GcOptions, Retention, StashKey};
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        let family = ConvergenceSecret::generate();
+        let backend = MemoryBackend::default();
+        let key = |user| StashKey::open_stash(user, "test").unwrap();
+        let member = |user| {
+            let mut stash = Stash::new(Arc::new(backend.clone()), key(user));
+            stash.set_convergence(family.clone());
+            stash
+        };
+        let backup = |stash: &mut Stash, data: &[u8]| {
+            let mut ingest = stash.ingest().unwrap();
+            let file = ingest.add_file(Entry::from_stream("file"), data).unwrap();
+            ingest.finish(0, vec![], vec![], Labels::new()).unwrap();
+            file.chunks[0].1.clone()
+        };
+        let mut image = member("image");
+        let shared = backup(&mut image, b"the same on every machine");
+
+        let mut laptop = member("laptop");
+        assert_eq!(laptop.share_chunks(&mut image).unwrap(), 1);
+        assert_eq!(
+            backup(&mut laptop, b"the same on every machine").file,
+            shared.file
+        );
+
+        // borrowed chunks no file refers to aren't kept
+        let mut desktop = member("desktop");
+        desktop.share_chunks(&mut image).unwrap();
+        backup(&mut desktop, b"only on the desktop");
+        let mut desktop = member("desktop");
+        desktop.read().unwrap();
+        assert!(desktop.chunk_index().get(&shared.hash).is_none());
+
+        // and the object the laptop borrowed is never collected
+        let mut laptop = member("laptop");
+        laptop.read().unwrap();
+        backup(&mut laptop, b"changed on the laptop");
+        let last = Retention {
+            last: 1,
+            ..Retention::default()
+        };
+        assert!(laptop.prune(&last).unwrap().objects.is_empty());
+        let now = GcOptions {
+            grace: Duration::ZERO,
+            ..GcOptions::default()
+        };
+        assert!(laptop.collect_garbage(&now).unwrap().deleted.is_empty());
+        assert!(backend.contains(&shared.file));
+
+        let mut stranger = Stash::new(Arc::new(backend.clone()), key("stranger"));
+        assert!(matches!(
+            stranger.share_chunks(&mut image),
+            Err(ZerostashError::Incompatible { .. })
+        ));
+    }
+}
@@ -0,0 +1,310 @@
+use crate::cache::{self, CacheError};
+use crate::compress;
+use crate::crypto::CryptoDigest;
+use crate::error::Result;
+use crate::files::Entry;
+use crate::meta;
+use crate::snapshots::Snapshot;
+use crate::stash::Stash;
+
+use regex::Regex;
+use serde::Deserialize;
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufReader, BufWriter};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+const FIND_INDEX_VERSION: u8 = 1;
+
+/// What `Stash::find` looks for. Only entries matching every
+/// criterion that's set are found.
+#[derive(Clone, Debug, Default)]
+pub struct Query {
+    /// Glob patterns the path has to match any of
+    pub patterns: Vec<glob::Pattern>,
+    pub regex: Option<Regex>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    /// Modified at or after this time, in seconds since the epoch
+    pub newer_than: Option<u64>,
+    /// Modified before this time, in seconds since the epoch
+    pub older_than: Option<u64>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    /// Only search this snapshot, instead of all of them
+    pub snapshot: Option<u64>,
+}
+
+impl Query {
+    pub fn matches(&self, entry: &Entry) -> bool {
+        (self.patterns.is_empty() || self.patterns.iter().any(|p| p.matches(&entry.name)))
+            && self.regex.as_ref().is_none_or(|r| r.is_match(&entry.name))
+            && self.min_size.is_none_or(|s| entry.size >= s)
+            && self.max_size.is_none_or(|s| entry.size <= s)
+            && self.newer_than.is_none_or(|t| entry.unix_secs >= t)
+            && self.older_than.is_none_or(|t| entry.unix_secs < t)
+            && self.uid.is_none_or(|u| entry.unix_uid == u)
+            && self.gid.is_none_or(|g| entry.unix_gid == g)
+    }
+}
+
+/// A version of a file, and the ids of the snapshots it's in.
+#[derive(Serialize, Deserialize)]
+pub struct Found {
+    pub entry: Arc<Entry>,
+    pub snapshots: Vec<u64>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq)]
+struct Header {
+    version: u8,
+    stash: CryptoDigest,
+    commit: CryptoDigest,
+}
+
+impl Stash {
+    /// Keep an index of the files of all snapshots at `path` on the
+    /// local disk, for `find` to search instead of the snapshots,
+    /// which it then doesn't read from the metadata. It holds each
+    /// version of a file once, with the snapshots it's in, so it's
+    /// much smaller than the snapshots when few files change between
+    /// them.
+    ///
+    /// It's rebuilt by the first search after the stash was committed
+    /// to. Like the file cache, it's in plaintext.
+    pub fn use_find_index(&mut self, path: impl Into<PathBuf>) {
+        self.find_index = Some(path.into());
+    }
+
+    /// Search the snapshots for files matching `query`. Versions of a
+    /// file that are the same in several snapshots are only found
+    /// once, sorted by path, then by modification time.
+    pub fn find(&mut self, query: &Query) -> Result<Vec<Found>> {
+        let versions = if self.loaded.contains(&meta::Field::Snapshots) {
+            versions(&self.snapshots.list())
+        } else if let Some(versions) = self.open_find_index()? {
+            versions
+        } else {
+            self.load(meta::Field::Snapshots)?;
+            let versions = versions(&self.snapshots.list());
+            self.save_find_index(&versions)?;
+            versions
+        };
+
+        Ok(versions
+            .into_iter()
+            .filter(|f| query.matches(&f.entry))
+            .filter_map(|mut f| {
+                if let Some(id) = query.snapshot {
+                    f.snapshots.retain(|s| *s == id);
+                }
+                (!f.snapshots.is_empty()).then_some(f)
+            })
+            .collect())
+    }
+
+    /// The versions in the find index, if it's of the commit that was
+    /// read.
+    fn open_find_index(&self) -> Result<Option<Vec<Found>>> {
+        let (path, commit) = match (&self.find_index, &self.commit_digest) {
+            (Some(path), Some(commit)) => (path, *commit),
+            _ => return Ok(None),
+        };
+        let file = match fs::File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        let decoder = compress::destream(BufReader::new(file))?;
+        let mut de = serde_cbor::Deserializer::from_reader(decoder);
+        let expected = Header {
+            version: FIND_INDEX_VERSION,
+            stash: self.stash_id()?,
+            commit,
+        };
+        if Header::deserialize(&mut de).ok() != Some(expected) {
+            debug!("rebuilding find index {:?} of another commit", path);
+            return Ok(None);
+        }
+
+        let mut versions = vec![];
+        loop {
+            match Found::deserialize(&mut de) {
+                Ok(found) => versions.push(found),
+                Err(e) if e.is_eof() => break,
+                Err(_) => return Err(CacheError::InvalidFormat.into()),
+            }
+        }
+
+        debug!(
+            "read {} versions from find index {:?}",
+            versions.len(),
+            path
+        );
+        Ok(Some(versions))
+    }
+
+    /// Save `versions` to the find index, as of the commit that was
+    /// read or written.
+    fn save_find_index(&self, versions: &[Found]) -> Result<()> {
+        let (path, commit) = match (&self.find_index, &self.commit_digest) {
+            (Some(path), Some(commit)) => (path, *commit),
+            _ => return Ok(()),
+        };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(".tmp");
+
+        {
+            let file = cache::open_private(tmp.as_ref())?;
+            let mut encoder = compress::stream(BufWriter::new(file))?;
+            let header = Header {
+                version: FIND_INDEX_VERSION,
+                stash: self.stash_id()?,
+                commit,
+            };
+
+            serde_cbor::to_writer(&mut encoder, &header).map_err(|_| CacheError::InvalidFormat)?;
+            for found in versions {
+                serde_cbor::to_writer(&mut encoder, found)
+                    .map_err(|_| CacheError::InvalidFormat)?;
+            }
+
+            let (mut writer, result) = encoder.finish();
+            result?;
+            io::Write::flush(&mut writer)?;
+        }
+
+        fs::rename(&tmp, path)?;
+        debug!("saved {} versions to find index {:?}", versions.len(), path);
+        Ok(())
+    }
+}
+
+/// Every version of a file in `snapshots`, with the snapshots it's
+/// in, sorted by path, then by modification time.
+fn versions(snapshots: &[Arc<Snapshot>]) -> Vec<Found> {
+    let mut found = HashMap::<Arc<Entry>, Vec<u64>>::new();
+    for snapshot in snapshots {
+        for entry in snapshot.files.iter() {
+            found.entry(entry.clone()).or_default().push(snapshot.id);
+        }
+    }
+
+    let mut found = found
+        .into_iter()
+        .map(|(entry, snapshots)| Found { entry, snapshots })
+        .collect::<Vec<_>>();
+    found.sort_by(|a, b| {
+        (&a.entry.name, a.entry.unix_secs, a.entry.unix_nanos).cmp(&(
+            &b.entry.name,
+            b.entry.unix_secs,
+            b.entry.unix_nanos,
+        ))
+    });
+    found
+}
+
+#[cfg(all(test, feature = "fs"))]
+mod tests {
+    #[test]
+    fn find_across_snapshots() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use crate::stash::{BackupOptions, StashKey};
+
+        let key = StashKey::open_stash("find", "test").unwrap();
+        let mut stash = Stash::new(Arc::new(MemoryBackend::default()), key);
+        for _ in 0..2 {
+            stash
+                .backup(&["tests/data/100_random_1k"], &BackupOptions::default())
+                .unwrap();
+        }
+        stash
+            .backup(&["tests/data/10k_random_blob"], &BackupOptions::default())
+            .unwrap();
+
+        let query = Query {
+            regex: Some(Regex::new("/1[0-9]$").unwrap()),
+            ..Query::default()
+        };
+        let found = stash.find(&query).unwrap();
+        assert_eq!(found.len(), 10);
+        assert!(found.iter().all(|f| f.snapshots == vec![1, 2]));
+
+        let query = Query {
+            patterns: vec![glob::Pattern::new("*blob").unwrap()],
+            min_size: Some(1),
+            ..Query::default()
+        };
+        let found = stash.find(&query).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].snapshots, vec![3]);
+
+        let query = Query {
+            snapshot: Some(1),
+            max_size: Some(0),
+            ..Query::default()
+        };
+        assert!(stash.find(&query).unwrap().is_empty());
+    }
+
+    #[test]
+    fn the_find_index_is_searched_instead_of_the_snapshots() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use crate::stash::{BackupOptions, StashKey};
+
+        let path = std::env::temp_dir().join("0s_test_find_index");
+        let _ = fs::remove_file(&path);
+        let backend = MemoryBackend::default();
+        let key = || StashKey::open_stash("find index", "test").unwrap();
+        let open = || {
+            let mut stash = Stash::new(Arc::new(backend.clone()), key());
+            stash.use_find_index(&path);
+            stash.read_fields(&[]).unwrap();
+            stash
+        };
+        let backup = |data: &str| {
+            let mut stash = Stash::new(Arc::new(backend.clone()), key());
+            let _ = stash.read();
+            stash.backup(&[data], &BackupOptions::default()).unwrap();
+        };
+        let query = Query {
+            patterns: vec![glob::Pattern::new("*blob").unwrap()],
+            ..Query::default()
+        };
+
+        backup("tests/data/100_random_1k");
+        backup("tests/data/10k_random_blob");
+        let mut stash = open();
+        assert_eq!(stash.find(&Query::default()).unwrap().len(), 101);
+        assert!(stash.loaded.contains(&meta::Field::Snapshots));
+        assert!(path.exists());
+
+        // found without reading the snapshots
+        let mut stash = open();
+        let found = stash.find(&query).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].snapshots, vec![2]);
+        let query_1 = Query {
+            snapshot: Some(1),
+            ..query.clone()
+        };
+        assert!(stash.find(&query_1).unwrap().is_empty());
+        assert!(!stash.loaded.contains(&meta::Field::Snapshots));
+
+        // a commit from elsewhere leaves it behind, until it's rebuilt
+        backup("tests/data/10k_random_blob");
+        let mut stash = open();
+        assert_eq!(stash.find(&query).unwrap()[0].snapshots, vec![2, 3]);
+        assert!(stash.loaded.contains(&meta::Field::Snapshots));
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,376 @@
+use crate::backends::BackendError;
+use crate::cancel::CancelToken;
+use crate::crypto::CryptoDigest;
+use crate::error::{Result, ZerostashError};
+use crate::format::Garbage;
+use crate::meta;
+use crate::objects::ObjectId;
+use crate::stash::{Group, Stash};
+
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, Debug)]
+pub struct GcOptions {
+    /// Only find what would be deleted
+    pub dry_run: bool,
+    /// How long objects are kept after they're found unreferenced,
+    /// as backups that started before may still refer to them
+    pub grace: Duration,
+    /// Stops deleting objects once cancelled. What's left is kept as
+    /// garbage for the next run, and `collect_garbage` commits what
+    /// it did, then fails with `Cancelled`
+    pub cancel: CancelToken,
+}
+
+impl Default for GcOptions {
+    fn default() -> GcOptions {
+        GcOptions {
+            dry_run: false,
+            grace: Duration::from_secs(24 * 60 * 60),
+            cancel: CancelToken::default(),
+        }
+    }
+}
+
+/// What `Stash::collect_garbage` found.
+#[derive(Clone, Debug, Default)]
+pub struct Collected {
+    /// The objects deleted, or that would be in a dry run
+    pub deleted: Vec<ObjectId>,
+    /// Unreferenced objects still in their grace period
+    pub pending: Vec<ObjectId>,
+    /// Chunks no file refers to, which are left out of the index
+    pub chunks: usize,
+}
+
+impl Stash {
+    /// Delete the data objects that none of the chunks of the files
+    /// in the stash are in, once they've been unreferenced for the
+    /// grace period, then commit.
+    ///
+    /// Objects are found unreferenced by `prune`, or here, when all
+    /// the chunks in them are in files that are gone. Objects that
+    /// still have any chunk that's referred to are kept whole.
+    pub fn collect_garbage(&mut self, options: &GcOptions) -> Result<Collected> {
+        self.check_mutable("collect garbage")?;
+        self.load(meta::Field::Files)?;
+        self.load(meta::Field::Chunks)?;
+        self.load(meta::Field::Snapshots)?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        // mark
+        let live = self.live_chunks();
+        let mut unreferenced = vec![];
+        let mut kept = HashSet::new();
+        self.chunks.index().for_each(|hash, cp| {
+            if live.contains(hash) {
+                kept.insert(cp.file);
+            } else {
+                unreferenced.push(cp.file);
+            }
+        });
+        let chunks = unreferenced.len();
+        if !options.dry_run && chunks > 0 {
+            self.chunks.index().retain(|hash| live.contains(hash));
+        }
+
+        // sweep
+        let mut candidates = self
+            .garbage
+            .iter()
+            .map(|g| (g.object, g.unix_secs))
+            .collect::<HashMap<_, _>>();
+        for object in unreferenced {
+            candidates.entry(object).or_insert(now);
+        }
+        candidates.retain(|object, _| !kept.contains(object));
+        // objects borrowed from the family are forgotten, not deleted
+        let shared = &mut self.shared;
+        candidates.retain(|object, _| {
+            if !shared.contains(object) {
+                return true;
+            }
+            if !options.dry_run {
+                shared.remove(object);
+            }
+            false
+        });
+
+        let mut collected = Collected {
+            chunks,
+            ..Collected::default()
+        };
+        let mut garbage = vec![];
+        for (object, unix_secs) in candidates {
+            if options.cancel.is_cancelled() {
+                garbage.push(Garbage { object, unix_secs });
+                continue;
+            }
+            if now.saturating_sub(unix_secs) < options.grace.as_secs() {
+                collected.pending.push(object);
+                garbage.push(Garbage { object, unix_secs });
+                continue;
+            }
+
+            if !options.dry_run {
+                match self.backend.delete_object(&object) {
+                    Ok(()) | Err(BackendError::NoObjectFound) => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            collected.deleted.push(object);
+        }
+
+        // the parity of groups whose data objects are all gone
+        self.load(meta::Field::Parity)?;
+        let waiting = garbage.iter().map(|g| g.object).collect::<HashSet<_>>();
+        let used = |group: &Group| {
+            group
+                .data
+                .iter()
+                .any(|d| kept.contains(d) || waiting.contains(d))
+        };
+        let retired = if options.cancel.is_cancelled() {
+            vec![]
+        } else if options.dry_run {
+            self.groups
+                .list()
+                .into_iter()
+                .filter(|g| !used(g))
+                .collect()
+        } else {
+            self.groups.retain(used)
+        };
+        for object in retired.iter().flat_map(|g| g.parity.iter()) {
+            if !options.dry_run {
+                match self.backend.delete_object(object) {
+                    Ok(()) | Err(BackendError::NoObjectFound) => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            collected.deleted.push(*object);
+        }
+
+        debug!(
+            "collected {} objects, {} waiting",
+            collected.deleted.len(),
+            collected.pending.len()
+        );
+        garbage.sort_by_key(|g| g.unix_secs);
+        if !options.dry_run && (chunks > 0 || garbage != self.garbage || !retired.is_empty()) {
+            if chunks > 0 || !collected.deleted.is_empty() {
+                self.new_epoch();
+            }
+            self.garbage = garbage;
+            self.commit()?;
+        }
+
+        if options.cancel.is_cancelled() {
+            return Err(ZerostashError::Cancelled);
+        }
+        Ok(collected)
+    }
+
+    /// The chunks the files of the file index and the snapshots
+    /// refer to.
+    pub(crate) fn live_chunks(&self) -> HashSet<CryptoDigest> {
+        let mut live = HashSet::new();
+        self.files.index().iter().for_each(|f| {
+            live.extend(f.key().chunks.iter().map(|(_, cp)| cp.hash));
+        });
+        for snapshot in self.snapshots.list() {
+            for file in snapshot.files.iter() {
+                live.extend(file.chunks.iter().map(|(_, cp)| cp.hash));
+            }
+        }
+        live
+    }
+
+    /// Record `objects` as unreferenced from now, to be collected
+    /// after the grace period.
+    pub(crate) fn mark_garbage(&mut self, objects: impl IntoIterator<Item = ObjectId>) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let marked = self
+            .garbage
+            .iter()
+            .map(|g| g.object)
+            .collect::<HashSet<_>>();
+        let before = self.garbage.len();
+        for object in objects {
+            if !marked.contains(&object) {
+                self.garbage.push(Garbage {
+                    object,
+                    unix_secs: now,
+                });
+            }
+        }
+        if self.garbage.len() > before {
+            self.new_epoch();
+        }
+    }
+
+    /// Start a new epoch, as files may point to chunks in objects that
+    /// were rewritten or go away, and drop the file cache, which does.
+    pub(crate) fn new_epoch(&mut self) {
+        self.epoch += 1;
+        if let Some(cache) = &mut self.file_cache {
+            cache.set_epoch(self.epoch);
+        }
+    }
+}
+
+#[cfg(all(test, feature = "fs"))]
+mod tests {
+    #[test]
+    fn unreferenced_objects_are_deleted_after_the_grace_period() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use crate::files::Entry;
+        use crate::snapshots::Labels;
+        use crate::stash::{CancelToken, Retention, StashKey};
+        use std::sync::Arc;
+
+        let backend = MemoryBackend::default();
+        let key = || StashKey::open_stash("gc", "test").unwrap();
+        let mut stash = Stash::new(Arc::new(backend.clone()), key());
+        for data in [&b"old"[..], b"new"].iter() {
+            let mut ingest = stash.ingest().unwrap();
+            ingest.add_file(Entry::from_stream("file"), data).unwrap();
+            ingest.finish(0, vec![], vec![], Labels::new()).unwrap();
+        }
+        let old = {
+            let first = &stash.snapshots()[0];
+            first.files[0].chunks[0].1.file
+        };
+        let last = Retention {
+            last: 1,
+            ..Retention::default()
+        };
+        assert_eq!(stash.prune(&last).unwrap().objects, vec![old]);
+
+        // marked by the prune, and kept through a reopen
+        let mut stash = Stash::new(Arc::new(backend.clone()), key());
+        stash.read().unwrap();
+        let collected = stash.collect_garbage(&GcOptions::default()).unwrap();
+        assert_eq!(collected.pending, vec![old]);
+        assert!(collected.deleted.is_empty());
+
+        let now = GcOptions {
+            grace: Duration::ZERO,
+            ..GcOptions::default()
+        };
+        let dry_run = GcOptions {
+            dry_run: true,
+            ..now.clone()
+        };
+        assert_eq!(stash.collect_garbage(&dry_run).unwrap().deleted, vec![old]);
+        assert!(backend.contains(&old));
+        assert_eq!(stash.collect_garbage(&now).unwrap().deleted, vec![old]);
+        assert!(!backend.contains(&old));
+
+        let mut stash = Stash::new(Arc::new(backend), key());
+        stash.read().unwrap();
+        let collected = stash.collect_garbage(&now).unwrap();
+        assert!(collected.deleted.is_empty() && collected.pending.is_empty());
+        // what's left is still readable
+        assert_eq!(stash.verify(&CancelToken::default()).unwrap(), 1);
+    }
+
+    #[test]
+    fn collected_objects_are_dropped_from_the_file_cache() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use crate::stash::{BackupOptions, CancelToken, Retention, StashKey};
+        use std::sync::Arc;
+
+        let backend = MemoryBackend::default();
+        let key = || StashKey::open_stash("gc cache", "test").unwrap();
+        let cache = std::env::temp_dir().join("0s_test_gc_file_cache");
+        let other = std::env::temp_dir().join("0s_test_gc_other");
+        let _ = std::fs::remove_file(&cache);
+        std::fs::create_dir_all(&other).unwrap();
+        std::fs::write(other.join("file"), b"other").unwrap();
+        let data = "tests/data/100_random_1k";
+
+        let mut stash = Stash::new(Arc::new(backend.clone()), key());
+        stash.use_file_cache(&cache).unwrap();
+        stash.backup(&[data], &BackupOptions::default()).unwrap();
+        stash.backup(&[&other], &BackupOptions::default()).unwrap();
+        let last = Retention {
+            last: 1,
+            ..Retention::default()
+        };
+        stash.prune(&last).unwrap();
+        let now = GcOptions {
+            grace: Duration::ZERO,
+            ..GcOptions::default()
+        };
+        assert!(!stash.collect_garbage(&now).unwrap().deleted.is_empty());
+
+        // the files are read and stored again, instead of taken from
+        // the cache with the chunks that are gone
+        let mut stash = Stash::new(Arc::new(backend), key());
+        stash.read().unwrap();
+        stash.use_file_cache(&cache).unwrap();
+        stash.backup(&[data], &BackupOptions::default()).unwrap();
+        std::fs::remove_file(&cache).unwrap();
+        std::fs::remove_dir_all(&other).unwrap();
+        assert_eq!(
+            stash.verify(&CancelToken::default()).unwrap() as usize,
+            stash.chunk_index().len()
+        );
+    }
+
+    #[test]
+    fn cancelled_collections_keep_the_garbage() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use crate::files::Entry;
+        use crate::snapshots::Labels;
+        use crate::stash::{Retention, StashKey};
+        use std::sync::Arc;
+
+        let backend = MemoryBackend::default();
+        let key = || StashKey::open_stash("gc cancel", "test").unwrap();
+        let mut stash = Stash::new(Arc::new(backend.clone()), key());
+        for data in [&b"old"[..], b"new"].iter() {
+            let mut ingest = stash.ingest().unwrap();
+            ingest.add_file(Entry::from_stream("file"), data).unwrap();
+            ingest.finish(0, vec![], vec![], Labels::new()).unwrap();
+        }
+        let old = stash.snapshots()[0].files[0].chunks[0].1.file;
+        let last = Retention {
+            last: 1,
+            ..Retention::default()
+        };
+        stash.prune(&last).unwrap();
+
+        let cancelled = GcOptions {
+            grace: Duration::ZERO,
+            ..GcOptions::default()
+        };
+        cancelled.cancel.cancel();
+        assert!(matches!(
+            stash.collect_garbage(&cancelled),
+            Err(ZerostashError::Cancelled)
+        ));
+        assert!(backend.contains(&old));
+
+        // the object is still garbage for the next run
+        let mut stash = Stash::new(Arc::new(backend.clone()), key());
+        stash.read().unwrap();
+        let now = GcOptions {
+            grace: Duration::ZERO,
+            ..GcOptions::default()
+        };
+        assert_eq!(stash.collect_garbage(&now).unwrap().deleted, vec![old]);
+        assert!(!backend.contains(&old));
+    }
+}
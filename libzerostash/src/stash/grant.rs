@@ -0,0 +1,298 @@
+//! Grants, which give read access to some of the files of a stash.
+//!
+//! The key of a grant is derived from the master key and the name of
+//! the grant, so it reveals nothing else. It opens an object of its
+//! own, which holds the files of the grant, and the keys of their
+//! chunks. Only convergent chunk keys are one way from the key of the
+//! data, so stashes without them can't grant anything.
+
+use crate::backends::{Backend, BackendError};
+use crate::crypto::Cipher;
+use crate::error::{Result, ZerostashError};
+use crate::files::Entry;
+use crate::meta;
+use crate::objects::{BlockBuffer, Object};
+use crate::snapshots::{Labels, Snapshot, SnapshotStore};
+use crate::stash::{Stash, StashKey};
+
+use zeroize::Zeroize;
+
+use std::collections::HashSet;
+use std::sync::Arc;
+
+/// Grant objects start with this and a version: 1 is followed by what
+/// the grant gives access to, sealed, and 0 marks a revoked grant.
+pub(super) const MAGIC: &[u8] = b"0s-grant";
+
+/// What a grant gives access to, sealed in its object.
+#[derive(Serialize, Deserialize)]
+pub(super) struct Granted {
+    cipher: Cipher,
+    snapshots: Vec<GrantedSnapshot>,
+    /// The hashes of the chunks, each followed by its key
+    keys: serde_bytes::ByteBuf,
+}
+
+/// A snapshot with the files under the prefix of the grant.
+#[derive(Serialize, Deserialize)]
+struct GrantedSnapshot {
+    id: u64,
+    unix_secs: u64,
+    paths: Vec<String>,
+    tags: Vec<String>,
+    labels: Labels,
+    files: Vec<Arc<Entry>>,
+}
+
+impl Stash {
+    /// Give read access to the files of `snapshots` under `prefix`, or
+    /// of all snapshots without any, to whoever holds the key that's
+    /// returned.
+    ///
+    /// The key is derived from the master key and `name`, and opens
+    /// the stash with `Stash::open` like any other, but with only the
+    /// files that were granted, as they are now, and nothing can be
+    /// changed. Granting under the same name again replaces what it
+    /// gives access to. The chunks stay where they are, so this needs
+    /// convergent chunk keys, see `Stash::set_convergence`.
+    pub fn grant(
+        &mut self,
+        name: &str,
+        snapshots: &[u64],
+        prefix: Option<&str>,
+    ) -> Result<StashKey> {
+        self.check_mutable("grant access")?;
+        self.load(meta::Field::Files)?;
+        self.load(meta::Field::Snapshots)?;
+
+        let all = self.snapshots.list();
+        let mut granted = vec![];
+        for id in snapshots {
+            match all.iter().find(|s| s.id == *id) {
+                Some(snapshot) => granted.push(snapshot.clone()),
+                None => return Err(ZerostashError::Config(format!("no snapshot has id {}", id))),
+            }
+        }
+        if snapshots.is_empty() {
+            granted = all;
+        }
+
+        let prefix = prefix.unwrap_or_default().trim_matches('/');
+        let snapshots = granted
+            .iter()
+            .map(|s| GrantedSnapshot {
+                id: s.id,
+                unix_secs: s.unix_secs,
+                paths: s.paths.clone(),
+                tags: s.tags.clone(),
+                labels: s.labels.clone(),
+                files: s
+                    .files
+                    .iter()
+                    .filter(|f| is_under(&f.name, prefix))
+                    .cloned()
+                    .collect(),
+            })
+            .collect::<Vec<_>>();
+        let hashes = snapshots
+            .iter()
+            .flat_map(|s| s.files.iter())
+            .flat_map(|f| f.chunks.iter().map(|(_, cp)| cp.hash))
+            .collect::<HashSet<_>>();
+        let keys = self.master_key.chunk_keys(hashes.iter()).ok_or_else(|| {
+            ZerostashError::Config(
+                "only stashes with convergent chunk keys can grant access".into(),
+            )
+        })?;
+
+        let key = self.master_key.grant_key(name)?;
+        let id = key.grant_object_id()?;
+        let mut plain = serde_cbor::to_vec(&Granted {
+            cipher: self.master_key.cipher(),
+            snapshots,
+            keys: serde_bytes::ByteBuf::from(keys.to_vec()),
+        })
+        .map_err(std::io::Error::other)?;
+        let sealed = key.seal(b"_0s_grant", &plain);
+        plain.zeroize();
+        let data = [MAGIC, &[1], &sealed?].concat();
+        self.backend
+            .write_object(&Object::with_id(id, BlockBuffer::from(data)))?;
+
+        debug!(
+            "granted {} chunks of {} snapshots as {}",
+            hashes.len(),
+            granted.len(),
+            name
+        );
+        Ok(key)
+    }
+
+    /// End the grant named `name`, so its key opens nothing anymore.
+    pub fn revoke_grant(&mut self, name: &str) -> Result<()> {
+        self.check_mutable("revoke a grant")?;
+        let id = self.master_key.grant_key(name)?.grant_object_id()?;
+        let revoked = [MAGIC, &[0]].concat();
+        self.backend
+            .write_object(&Object::with_id(id, BlockBuffer::from(revoked)))?;
+
+        Ok(())
+    }
+
+    /// If the stash was opened through a grant, and only holds what it
+    /// gives access to.
+    pub fn is_granted(&self) -> bool {
+        self.granted
+    }
+
+    /// The stash with what a grant gives access to, opened with its
+    /// `key`.
+    pub(super) fn granted(backend: Arc<dyn Backend>, mut key: StashKey, granted: Granted) -> Stash {
+        key.set_cipher(granted.cipher);
+        key.set_granted(&granted.keys);
+
+        let mut stash = Stash::new(backend, key);
+        let mut snapshots = vec![];
+        for s in granted.snapshots {
+            for file in s.files.iter() {
+                stash.files.insert(file.clone());
+            }
+            snapshots.push(Arc::new(Snapshot {
+                id: s.id,
+                unix_secs: s.unix_secs,
+                paths: s.paths,
+                tags: s.tags,
+                labels: s.labels,
+                files: s.files,
+                manifest: None,
+            }));
+        }
+        stash.snapshots = SnapshotStore::with(snapshots);
+        stash.loaded.extend([
+            meta::Field::Files,
+            meta::Field::Chunks,
+            meta::Field::Snapshots,
+            meta::Field::Parity,
+        ]);
+        stash.granted = true;
+        stash
+    }
+}
+
+/// What the grant that `key` opens gives access to, if there's one.
+pub(super) fn read_grant(backend: &dyn Backend, key: &StashKey) -> Result<Option<Granted>> {
+    let id = key.grant_object_id()?;
+    let object = match backend.read_object(&id) {
+        Ok(object) => object,
+        Err(BackendError::NoObjectFound) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    let sealed = match object.buffer.as_ref().strip_prefix(MAGIC) {
+        Some([0]) => return Err(ZerostashError::WrongPassphrase),
+        Some([1, sealed @ ..]) => sealed,
+        _ => return Err(ZerostashError::Corrupt { object: id }),
+    };
+    let mut plain = key
+        .open(b"_0s_grant", sealed)
+        .map_err(|_| ZerostashError::Corrupt { object: id })?;
+    let granted = serde_cbor::from_slice(&plain);
+    plain.zeroize();
+    granted
+        .map(Some)
+        .map_err(|_| ZerostashError::Corrupt { object: id })
+}
+
+/// If the file `name` is `prefix`, or in it.
+fn is_under(name: &str, prefix: &str) -> bool {
+    let name = name.trim_start_matches('/');
+    prefix.is_empty()
+        || name == prefix
+        || name
+            .strip_prefix(prefix)
+            .is_some_and(|rest| rest.starts_with('/'))
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn grants_open_only_what_they_give() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use crate::crypto::ConvergenceSecret;
+        use std::io::Read;
+
+        let backend = Arc::new(MemoryBackend::default());
+        let key = || StashKey::open_stash("grant", "test").unwrap();
+        let add = |stash: &mut Stash, files: &[(&str, &[u8])]| {
+            let mut ingest = stash.ingest().unwrap();
+            for (name, data) in files {
+                ingest.add_file(Entry::from_stream(*name), data).unwrap();
+            }
+            ingest.finish(0, vec![], vec![], Labels::new()).unwrap();
+        };
+        let read = |stash: &mut Stash, name: &str| {
+            let mut data = vec![];
+            stash.open_file(name)?.read_to_end(&mut data)?;
+            Ok::<_, ZerostashError>(data)
+        };
+
+        let mut stash = Stash::new(backend.clone(), key());
+        add(&mut stash, &[("srv/www/index.html", b"tuesday")]);
+        // the chunk keys would give away the key of the data
+        assert!(stash.grant("contractor", &[], None).is_err());
+
+        let mut stash = Stash::new(backend.clone(), StashKey::generate());
+        stash.set_convergence(ConvergenceSecret::generate());
+        add(
+            &mut stash,
+            &[
+                ("srv/www/index.html", b"tuesday"),
+                ("srv/db/data", b"secret"),
+            ],
+        );
+        add(&mut stash, &[("srv/www/index.html", b"wednesday")]);
+        assert!(stash.grant("contractor", &[3], None).is_err());
+        let granted = stash.grant("contractor", &[1], Some("/srv/www")).unwrap();
+
+        let mut opened = Stash::open(backend.clone(), granted).unwrap();
+        opened.read().unwrap();
+        assert!(opened.is_granted());
+        let ids = opened.snapshots().iter().map(|s| s.id).collect::<Vec<_>>();
+        assert_eq!(ids, vec![1]);
+        assert_eq!(opened.file_index().len(), 1);
+        assert_eq!(read(&mut opened, "srv/www/index.html").unwrap(), b"tuesday");
+        assert!(matches!(
+            opened.commit(),
+            Err(ZerostashError::Granted("commit"))
+        ));
+        assert!(matches!(
+            opened.grant("subcontractor", &[], None),
+            Err(ZerostashError::Granted("grant access"))
+        ));
+
+        // the chunks of other files don't decrypt
+        let other = stash
+            .file_index()
+            .iter()
+            .find(|f| f.key().name == "srv/db/data")
+            .map(|f| f.key().clone())
+            .unwrap();
+        opened.files.insert(other);
+        assert!(read(&mut opened, "srv/db/data").is_err());
+
+        let mut immutable = Stash::new(Arc::new(MemoryBackend::default()), key());
+        immutable.set_immutable().unwrap();
+        assert!(matches!(
+            immutable.grant("auditor", &[], None),
+            Err(ZerostashError::Immutable("grant access"))
+        ));
+
+        stash.revoke_grant("contractor").unwrap();
+        let granted = stash.master_key.grant_key("contractor").unwrap();
+        assert!(matches!(
+            Stash::open(backend, granted),
+            Err(ZerostashError::WrongPassphrase)
+        ));
+    }
+}
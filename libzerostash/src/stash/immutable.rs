@@ -0,0 +1,132 @@
+use crate::backends::{probe_id, BackendError, ImmutableBackend};
+use crate::error::{Result, ZerostashError};
+use crate::stash::Stash;
+
+use std::sync::Arc;
+
+impl Stash {
+    /// Never delete or overwrite objects of the stash from now on,
+    /// but its root and lock, so commits only add to it. Pruning,
+    /// collecting garbage and compacting fail with `Immutable`.
+    ///
+    /// This keeps a client from destroying history by mistake. To
+    /// hold against a compromised client, its backend credentials
+    /// have to be restricted the same way, like to a bucket that
+    /// keeps every version of an object, or through a server with an
+    /// `ImmutableBackend`. Roots that are rolled back then leave the
+    /// newer commits for `newer_commits` to find.
+    pub fn set_immutable(&mut self) -> Result<()> {
+        if self.immutable {
+            return Ok(());
+        }
+
+        // the parity writer goes in front of the new backend
+        let parity = self.parity();
+        self.use_parity(None)?;
+        let mutable = vec![
+            self.master_key.root_object_id()?,
+            self.master_key.lock_object_id()?,
+            probe_id(),
+        ];
+        self.backend = Arc::new(ImmutableBackend::new(self.backend.clone(), mutable));
+        self.use_parity(parity)?;
+
+        self.immutable = true;
+        Ok(())
+    }
+
+    pub fn is_immutable(&self) -> bool {
+        self.immutable
+    }
+
+    /// Fail with `Immutable` if the stash is, before doing `what`.
+    pub(crate) fn check_mutable(&self, what: &'static str) -> Result<()> {
+        if self.granted {
+            return Err(ZerostashError::Granted(what));
+        }
+        if self.immutable {
+            return Err(ZerostashError::Immutable(what));
+        }
+        Ok(())
+    }
+
+    /// The number of the next commit. Immutable stashes skip those a
+    /// commit interrupted before it stored its root signed, as the
+    /// signatures can't be overwritten.
+    pub(crate) fn next_generation(&self) -> Result<u64> {
+        let mut generation = self.generation + 1;
+        if !self.immutable {
+            return Ok(generation);
+        }
+        loop {
+            let signature = self.master_key.signature_object_id(generation)?;
+            match self.backend.read_range(&signature, 0, 1) {
+                Ok(_) | Err(BackendError::Archived) => generation += 1,
+                Err(BackendError::NoObjectFound) => return Ok(generation),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "fs"))]
+mod tests {
+    #[test]
+    fn immutable_stashes_only_grow() {
+        use super::*;
+        use crate::backends::{Backend, MemoryBackend};
+        use crate::files::Entry;
+        use crate::objects::{BlockBuffer, Object};
+        use crate::snapshots::Labels;
+        use crate::stash::{GcOptions, Retention, StashKey};
+
+        let backend = MemoryBackend::default();
+        let key = || StashKey::open_stash("immutable", "test").unwrap();
+        let mut stash = Stash::new(Arc::new(backend.clone()), key());
+        stash.set_immutable().unwrap();
+        let backup = |stash: &mut Stash, data: &[u8]| {
+            let mut ingest = stash.ingest().unwrap();
+            ingest.add_file(Entry::from_stream("file"), data).unwrap();
+            ingest.finish(0, vec![], vec![], Labels::new()).unwrap();
+        };
+        backup(&mut stash, b"first");
+        let root = key().root_object_id().unwrap();
+        let first = backend.read_object(&root).unwrap();
+
+        // a commit that signed, but didn't store its root
+        let interrupted = key().signature_object_id(2).unwrap();
+        backend
+            .write_object(&Object::with_id(interrupted, BlockBuffer::from(vec![0; 8])))
+            .unwrap();
+        backup(&mut stash, b"second");
+        assert_eq!(stash.generation(), 3);
+
+        let last = Retention {
+            last: 1,
+            ..Retention::default()
+        };
+        assert!(matches!(
+            stash.prune(&last),
+            Err(ZerostashError::Immutable(_))
+        ));
+        assert!(stash.collect_garbage(&GcOptions::default()).is_err());
+        assert!(stash.compact().is_err());
+
+        let mut reader = Stash::new(Arc::new(backend.clone()), key());
+        reader.read().unwrap();
+        assert_eq!(reader.snapshots().len(), 2);
+        assert!(reader.newer_commits().unwrap().is_empty());
+
+        // the backend serves the first root again
+        backend
+            .write_object(&Object::with_id(
+                root,
+                BlockBuffer::from(first.buffer.as_ref().to_vec()),
+            ))
+            .unwrap();
+        let mut rolled_back = Stash::new(Arc::new(backend), key());
+        rolled_back.read().unwrap();
+        assert_eq!(rolled_back.snapshots().len(), 1);
+        assert_eq!(rolled_back.newer_commits().unwrap(), vec![3]);
+    }
+}
@@ -0,0 +1,535 @@
+use crate::cancel::CancelToken;
+use crate::chunks::{self, ChunkPointer, ChunkStore};
+use crate::compress::{Compression, CompressionRules, Tuning};
+use crate::crypto::{CryptoDigest, ObjectOperations};
+use crate::error::Result;
+use crate::files::Entry;
+use crate::meta;
+use crate::objects::{self, ObjectStore};
+use crate::snapshots::{Labels, Snapshot};
+use crate::splitter::{Chunk, Chunking};
+use crate::stash::Stash;
+use crate::stats::{Collector, Stage};
+
+use crossbeam_utils::thread;
+
+use std::collections::{HashMap, HashSet};
+use std::io::Read;
+use std::sync::Arc;
+
+/// Streams are read in blocks of at least this size, or four of the
+/// largest chunks
+const STREAM_BLOCK: usize = 16 * 1024 * 1024;
+
+/// With the threads of the chunking, chunks are compressed in batches
+/// of about this many bytes
+const COMPRESS_BATCH: usize = 16 * 1024 * 1024;
+
+/// Stores files from memory instead of the local file system, for
+/// importers and other sources that don't have the data on disk.
+///
+/// Nothing is committed until `finish` records the added files as a
+/// snapshot.
+pub struct Ingest<'a> {
+    stash: &'a mut Stash,
+    storage: objects::Storage<ObjectOperations>,
+    chunking: Chunking,
+    compression: CompressionRules,
+    files: Vec<Arc<Entry>>,
+}
+
+impl Stash {
+    pub fn ingest(&mut self) -> Result<Ingest<'_>> {
+        self.load(meta::Field::Files)?;
+        self.load(meta::Field::Chunks)?;
+        self.load(meta::Field::Snapshots)?;
+
+        let storage = objects::Storage::new(
+            self.backend.clone(),
+            self.master_key.get_object_crypto()?,
+            Arc::new(Collector::new(self.progress.clone())),
+        )
+        .object_size(self.object_size)
+        .compression(self.compression)
+        .tuning(self.tuning);
+        let storage = self.upload_with(storage, self.backup_threads(1).1);
+
+        Ok(Ingest {
+            chunking: self.run_chunking()?,
+            compression: self.run_compression()?,
+            stash: self,
+            storage,
+            files: vec![],
+        })
+    }
+}
+
+impl Ingest<'_> {
+    /// Store `data` as the contents of `entry`. The chunks of
+    /// `entry` are replaced.
+    pub fn add_file(&mut self, mut entry: Entry, data: &[u8]) -> Result<Arc<Entry>> {
+        entry.size = data.len() as u64;
+        entry.chunks.clear();
+        self.storage
+            .set_compression(self.compression.for_path(&entry.name));
+        store_data(
+            &self.chunking,
+            &self.stash.chunks,
+            &mut self.storage,
+            &Collector::default(),
+            &CancelToken::default(),
+            &mut entry,
+            data,
+        )?;
+        self.stash.progress.item(entry.size);
+
+        let entry = Arc::new(entry);
+        self.files.push(entry.clone());
+        Ok(entry)
+    }
+
+    /// Store what `reader` yields until its end as the contents of
+    /// `entry`, like `add_file`, but only holding a block of it in
+    /// memory at a time. The chunks are the same as of the whole
+    /// contents, so they deduplicate against earlier streams too.
+    pub fn add_stream(&mut self, mut entry: Entry, reader: &mut impl Read) -> Result<Arc<Entry>> {
+        entry.chunks.clear();
+        self.storage
+            .set_compression(self.compression.for_path(&entry.name));
+        entry.size = store_stream(
+            &self.chunking,
+            &self.stash.chunks,
+            &mut self.storage,
+            &Collector::default(),
+            &mut entry,
+            reader,
+        )?;
+        self.stash.progress.item(entry.size);
+
+        let entry = Arc::new(entry);
+        self.files.push(entry.clone());
+        Ok(entry)
+    }
+
+    /// Add an entry whose chunks are already stored, like a file
+    /// that's unchanged since an earlier snapshot.
+    pub fn add_entry(&mut self, entry: Arc<Entry>) {
+        self.files.push(entry);
+    }
+
+    /// Record the added files as a snapshot taken at `unix_secs`, and
+    /// commit the stash.
+    pub fn finish(
+        mut self,
+        unix_secs: u64,
+        paths: Vec<String>,
+        tags: Vec<String>,
+        labels: Labels,
+    ) -> Result<Arc<Snapshot>> {
+        self.storage.flush()?;
+
+        for f in self.files.iter() {
+            self.stash.files.insert(f.clone());
+        }
+        let snapshot = self
+            .stash
+            .snapshots
+            .push_at(unix_secs, paths, tags, labels, self.files);
+
+        self.stash.commit()?;
+        Ok(snapshot)
+    }
+}
+
+/// Split `data` into chunks with `chunking`, store the ones not yet
+/// in `chunkindex`, and add them to `entry`. Once `cancel` is
+/// cancelled, no more chunks are stored, and `entry` is left with
+/// only some of them.
+///
+/// With more than one thread in `chunking`, new chunks are
+/// compressed on all of them, in batches. They're encrypted into the
+/// object in order, which is fast, but can only be done once it's
+/// known where the ones before them went.
+pub(crate) fn store_data(
+    chunking: &Chunking,
+    chunkindex: &ChunkStore,
+    objectstore: &mut impl ObjectStore,
+    stats: &Collector,
+    cancel: &CancelToken,
+    entry: &mut Entry,
+    data: &[u8],
+) -> std::result::Result<(), objects::ObjectError> {
+    let mut counts = chunks::Stats::default();
+    let mut splitter = chunking.split(data);
+    let threads = chunking.threads;
+    let chunks = &mut entry.chunks;
+    let mut store = |batch: &mut Vec<_>| {
+        store_batch(
+            chunkindex,
+            objectstore,
+            stats,
+            &mut counts,
+            threads,
+            batch,
+            0,
+            chunks,
+        )
+    };
+    let mut batch = vec![];
+    while let Some(chunk) = stats.time(Stage::Chunk, || splitter.next()) {
+        if cancel.is_cancelled() {
+            return Ok(());
+        }
+        batch.push(chunk);
+        if is_full(&batch, threads) {
+            store(&mut batch)?;
+        }
+    }
+    store(&mut batch)?;
+    chunkindex.record(&entry.name, counts);
+
+    Ok(())
+}
+
+/// Like `store_data`, for the contents `reader` yields, returning
+/// their size.
+///
+/// A cut only depends on where its chunk starts, so each block is
+/// split from the last cut of the one before, whose last chunk may
+/// have been cut short by the end of the block.
+pub(crate) fn store_stream(
+    chunking: &Chunking,
+    chunkindex: &ChunkStore,
+    objectstore: &mut impl ObjectStore,
+    stats: &Collector,
+    entry: &mut Entry,
+    reader: &mut impl Read,
+) -> std::result::Result<u64, objects::ObjectError> {
+    let block = STREAM_BLOCK.max(4 * chunking.sizes.max);
+    let threads = chunking.threads;
+    let mut counts = chunks::Stats::default();
+    let mut buffer = Vec::with_capacity(block);
+    let mut offset = 0;
+
+    loop {
+        let wanted = (block - buffer.len()) as u64;
+        reader.by_ref().take(wanted).read_to_end(&mut buffer)?;
+        let end = buffer.len() < block;
+
+        let mut consumed = 0;
+        let mut splitter = chunking.split(&buffer);
+        let chunks = &mut entry.chunks;
+        let mut store = |batch: &mut Vec<_>| {
+            store_batch(
+                chunkindex,
+                objectstore,
+                stats,
+                &mut counts,
+                threads,
+                batch,
+                offset,
+                chunks,
+            )
+        };
+        let mut batch = vec![];
+        while let Some(chunk) = stats.time(Stage::Chunk, || splitter.next()) {
+            let (start, _, data) = chunk;
+            if !end && start as usize + data.len() == buffer.len() {
+                break;
+            }
+            consumed = start as usize + data.len();
+            batch.push(chunk);
+            if is_full(&batch, threads) {
+                store(&mut batch)?;
+            }
+        }
+        store(&mut batch)?;
+
+        drop(splitter);
+        offset += consumed as u64;
+        buffer.drain(..consumed);
+        if end {
+            break;
+        }
+    }
+    chunkindex.record(&entry.name, counts);
+
+    Ok(offset)
+}
+
+/// If `batch` is to be stored before more chunks are added to it.
+fn is_full(batch: &[Chunk], threads: usize) -> bool {
+    threads < 2 || batch.iter().map(|(_, _, data)| data.len()).sum::<usize>() >= COMPRESS_BATCH
+}
+
+/// Store the chunks of `batch`, and add them to `stored` by where
+/// they start, `offset` bytes on. The new ones are compressed on
+/// `threads` threads first, if the store compresses.
+#[allow(clippy::too_many_arguments)]
+fn store_batch(
+    chunkindex: &ChunkStore,
+    objectstore: &mut impl ObjectStore,
+    stats: &Collector,
+    counts: &mut chunks::Stats,
+    threads: usize,
+    batch: &mut Vec<Chunk>,
+    offset: u64,
+    stored: &mut Vec<(u64, Arc<ChunkPointer>)>,
+) -> std::result::Result<(), objects::ObjectError> {
+    let packed = match objectstore.compression() {
+        Some(compression) if threads > 1 && batch.len() > 1 => {
+            compress_batch(chunkindex, stats, compression, threads, batch)?
+        }
+        _ => HashMap::new(),
+    };
+
+    for (start, hash, data) in batch.drain(..) {
+        let packed = packed.get(&hash).map(Vec::as_slice);
+        let chunkptr = store_chunk(chunkindex, objectstore, stats, counts, &hash, data, packed)?;
+        stored.push((offset + start, chunkptr));
+    }
+    Ok(())
+}
+
+/// Compress the chunks of `batch` that aren't in `chunkindex` yet on
+/// `threads` threads, by their hash.
+fn compress_batch(
+    chunkindex: &ChunkStore,
+    stats: &Collector,
+    (compression, tuning): (Compression, Tuning),
+    threads: usize,
+    batch: &[Chunk],
+) -> std::result::Result<HashMap<CryptoDigest, Vec<u8>>, objects::ObjectError> {
+    let mut seen = HashSet::new();
+    let new = batch
+        .iter()
+        .filter(|(_, hash, _)| chunkindex.index().get(hash).is_none() && seen.insert(*hash))
+        .collect::<Vec<_>>();
+    let threads = threads.min(new.len());
+
+    thread::scope(|s| {
+        let handles = (0..threads)
+            .map(|t| {
+                let new = &new;
+                s.spawn(move |_| {
+                    new.iter()
+                        .skip(t)
+                        .step_by(threads)
+                        .map(|(_, hash, data)| {
+                            let mut packed = vec![];
+                            stats.time(Stage::Compress, || {
+                                tuning.compress_into(compression, &mut packed, data)
+                            })?;
+                            Ok((*hash, packed))
+                        })
+                        .collect::<std::result::Result<Vec<_>, objects::ObjectError>>()
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut packed = HashMap::new();
+        for handle in handles {
+            packed.extend(handle.join().unwrap()?);
+        }
+        Ok(packed)
+    })
+    .unwrap()
+}
+
+/// Store the chunk `data` if it's not in `chunkindex` yet, from what
+/// it was compressed to, if it was.
+fn store_chunk(
+    chunkindex: &ChunkStore,
+    objectstore: &mut impl ObjectStore,
+    stats: &Collector,
+    counts: &mut chunks::Stats,
+    hash: &CryptoDigest,
+    data: &[u8],
+    packed: Option<&[u8]>,
+) -> std::result::Result<Arc<ChunkPointer>, objects::ObjectError> {
+    let mut stored = None;
+    let chunkptr = chunkindex.push(*hash, || {
+        let chunkptr = match packed {
+            Some(packed) => objectstore.store_compressed(hash, packed)?,
+            None => objectstore.store_chunk(hash, data)?,
+        };
+        stored = Some(chunkptr.size);
+        Ok(chunkptr)
+    })?;
+    counts.add_chunk(data.len(), stored);
+    stats.add_chunk(data.len() as u64, stored.is_some());
+
+    Ok(chunkptr)
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn ingested_files_are_deduplicated() {
+        use crate::backends::MemoryBackend;
+        use crate::files::Entry;
+        use crate::stash::{Labels, Stash, StashKey};
+        use std::sync::Arc;
+
+        let backend = Arc::new(MemoryBackend::default());
+        let key = || StashKey::open_stash("ingest", "test").unwrap();
+        let mut stash = Stash::new(backend.clone(), key());
+        let data = crate::test_util::pseudo_random(200_000, 1);
+        let entry = |name: &str| Entry {
+            unix_secs: 0,
+            unix_nanos: 0,
+            unix_perm: 0o644,
+            unix_uid: 0,
+            unix_gid: 0,
+            unix_user: None,
+            unix_group: None,
+            size: 0,
+            readonly: false,
+            name: name.into(),
+            symlink: None,
+            hardlink: None,
+            xattrs: None,
+            device: None,
+            windows_attributes: None,
+            raw_name: None,
+            chunks: vec![],
+        };
+
+        let mut ingest = stash.ingest().unwrap();
+        let first = ingest.add_file(entry("/a"), &data).unwrap();
+        let second = ingest.add_file(entry("/b"), &data).unwrap();
+        let snapshot = ingest
+            .finish(
+                1_600_000_000,
+                vec!["/".into()],
+                vec!["imported".into()],
+                Labels::new(),
+            )
+            .unwrap();
+
+        assert_eq!(snapshot.id, 1);
+        assert_eq!(first.size, data.len() as u64);
+        assert!(first.chunks == second.chunks);
+        assert_eq!(stash.chunk_index().len(), first.chunks.len());
+
+        let mut stash = Stash::new(backend, key());
+        stash.read().unwrap();
+        let snapshots = stash.snapshots();
+        assert_eq!(snapshots.len(), 1);
+        assert_eq!(snapshots[0].unix_secs, 1_600_000_000);
+        assert_eq!(snapshots[0].tags, vec!["imported"]);
+        assert_eq!(snapshots[0].files.len(), 2);
+    }
+
+    #[test]
+    fn streams_are_chunked_like_files() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use crate::stash::StashKey;
+
+        let key = StashKey::open_stash("ingest", "test").unwrap();
+        let mut stash = Stash::new(Arc::new(MemoryBackend::default()), key);
+        // more than two blocks, so chunks are cut across them
+        let data = crate::test_util::pseudo_random(2 * STREAM_BLOCK + 100_000, 3);
+
+        let mut ingest = stash.ingest().unwrap();
+        let file = ingest.add_file(Entry::from_stream("file"), &data).unwrap();
+        let stream = ingest
+            .add_stream(Entry::from_stream("stream"), &mut data.as_slice())
+            .unwrap();
+        ingest
+            .finish(1_600_000_000, vec!["stream".into()], vec![], Labels::new())
+            .unwrap();
+
+        assert_eq!(stream.size, data.len() as u64);
+        assert!(stream.chunks == file.chunks);
+        assert_eq!(stream.unix_perm, 0o100_644);
+    }
+
+    #[test]
+    fn chunks_are_compressed_on_all_threads() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use crate::stash::StashKey;
+        use std::io::Read;
+
+        let key = || StashKey::open_stash("ingest", "test").unwrap();
+        let words: [&[u8]; 4] = [b"zero ", b"stash ", b"chunks ", b"threads "];
+        let data = crate::test_util::pseudo_random(500_000, 5)
+            .into_iter()
+            .flat_map(|i| words[i as usize % 4].iter().copied())
+            .collect::<Vec<_>>();
+        let store = |backend: &MemoryBackend, threads| {
+            let mut stash = Stash::new(Arc::new(backend.clone()), key());
+            stash.set_chunker(crate::stash::Chunker::Fixed);
+            stash.set_threads(threads);
+            stash.set_parallel_chunking(threads > 1);
+            let mut ingest = stash.ingest().unwrap();
+            let file = ingest.add_file(Entry::from_stream("file"), &data).unwrap();
+            let stream = ingest
+                .add_stream(Entry::from_stream("stream"), &mut data.as_slice())
+                .unwrap();
+            ingest.finish(0, vec![], vec![], Labels::new()).unwrap();
+            (file, stream)
+        };
+        let chunks = |entry: &Entry| {
+            entry
+                .chunks
+                .iter()
+                .map(|(start, cp)| (*start, cp.hash, cp.size))
+                .collect::<Vec<_>>()
+        };
+
+        // the same chunks, compressed the same
+        let (file, _) = store(&MemoryBackend::default(), 1);
+        let backend = MemoryBackend::default();
+        let (parallel, stream) = store(&backend, 4);
+        assert!(file.chunks.len() > 100);
+        assert_eq!(chunks(&parallel), chunks(&file));
+        assert_eq!(chunks(&stream), chunks(&file));
+        let stored = file
+            .chunks
+            .iter()
+            .map(|(_, cp)| cp.size as usize)
+            .sum::<usize>();
+        assert!(stored < data.len() / 2);
+
+        let mut stash = Stash::new(Arc::new(backend), key());
+        stash.read().unwrap();
+        for name in ["file", "stream"].iter() {
+            let mut read = vec![];
+            stash
+                .open_file(name)
+                .unwrap()
+                .read_to_end(&mut read)
+                .unwrap();
+            assert_eq!(read, data);
+        }
+    }
+
+    #[test]
+    fn cancelled_files_store_no_more_chunks() {
+        use super::*;
+        use crate::objects::NullStorage;
+
+        let cancel = CancelToken::new();
+        cancel.cancel();
+        let chunkindex = ChunkStore::default();
+        let mut storage = NullStorage::default();
+        let mut entry = Entry::from_stream("file");
+        store_data(
+            &Chunking::default(),
+            &chunkindex,
+            &mut storage,
+            &Collector::default(),
+            &cancel,
+            &mut entry,
+            &[1; 100_000],
+        )
+        .unwrap();
+
+        assert!(entry.chunks.is_empty());
+        assert_eq!(*storage.0.lock().unwrap(), 0);
+        assert!(chunkindex.index().is_empty());
+    }
+}
@@ -0,0 +1,523 @@
+use crate::backends::{Backend, BackendError};
+use crate::crypto::{chunk_hash, shamir, Kdf};
+use crate::error::{Result, ZerostashError};
+use crate::objects::{BlockBuffer, Object, ObjectId};
+use crate::stash::{grant, Stash, StashKey};
+
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Key objects start with this and a version: 1 is followed by the
+/// wrapped master key, and 0 marks credentials that were changed.
+pub(super) const MAGIC: &[u8] = b"0s-key";
+
+/// KDF objects start with this, followed by the `Kdf` in CBOR.
+pub(super) const KDF_MAGIC: &[u8] = b"0s-kdf";
+
+/// The slot of the credentials a stash was opened with, if it has no
+/// slots stored yet.
+const PRIMARY: &str = "primary";
+
+/// A key object that opens the stash, by the name it was added as.
+#[derive(Serialize, Deserialize)]
+struct Slot {
+    name: String,
+    key_object: ObjectId,
+}
+
+/// Credentials of a new stash, stored when it's first committed.
+pub(crate) struct NewCredentials {
+    user: String,
+    kdf: Kdf,
+    credentials: StashKey,
+}
+
+impl Stash {
+    /// Open the stash in `backend` with the key derived from its
+    /// `credentials` by `StashKey::open_stash`.
+    ///
+    /// If there's a key object for them, the master key is the one it
+    /// wraps. The key of a grant opens what it gives access to.
+    /// Otherwise it's the credentials' key itself, like in stashes
+    /// from before key objects.
+    pub fn open(backend: Arc<dyn Backend>, credentials: StashKey) -> Result<Stash> {
+        let id = credentials.key_object_id()?;
+        let master_key = match backend.read_object(&id) {
+            Ok(object) => unwrap_master(&credentials, &id, object.buffer.as_ref())?,
+            Err(BackendError::NoObjectFound) => match grant::read_grant(&*backend, &credentials)? {
+                Some(granted) => return Ok(Stash::granted(backend, credentials, granted)),
+                None => credentials,
+            },
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut stash = Stash::new(backend, master_key);
+        stash.key_object = Some(id);
+        Ok(stash)
+    }
+
+    /// Start a stash in `backend` with a random master key, wrapped
+    /// by `credentials` in a key object.
+    pub fn create(backend: Arc<dyn Backend>, credentials: StashKey) -> Result<Stash> {
+        if exists(&*backend, &credentials)? {
+            return Err(ZerostashError::Exists);
+        }
+
+        let mut stash = Stash::new(backend, StashKey::generate());
+        stash.key_object = Some(stash.write_key_object(&credentials)?);
+        Ok(stash)
+    }
+
+    /// Open the stash in `backend` with the key derived from `user`
+    /// and `password` by the `Kdf` stored for `user`.
+    ///
+    /// If there's no such stash, a new one derives the key with
+    /// `kdf`, or parameters calibrated to take about a second. They
+    /// are stored with a key object on the first commit.
+    pub(crate) fn open_with_credentials(
+        backend: Arc<dyn Backend>,
+        user: &str,
+        password: &str,
+        kdf: Option<Kdf>,
+    ) -> Result<Stash> {
+        if let Some(stored) = Stash::kdf(&*backend, user)? {
+            return Stash::open(backend, StashKey::derive(user, password, &stored)?);
+        }
+
+        let legacy = StashKey::open_stash(user, password)?;
+        if exists(&*backend, &legacy)? {
+            return Stash::open(backend, legacy);
+        }
+
+        let kdf = match kdf {
+            Some(kdf) => kdf,
+            None => Kdf::calibrate(Duration::from_secs(1))?,
+        };
+        let credentials = StashKey::derive(user, password, &kdf)?;
+        let mut stash = Stash::new(backend, StashKey::generate());
+        stash.new_credentials = Some(NewCredentials {
+            user: user.into(),
+            kdf,
+            credentials,
+        });
+        Ok(stash)
+    }
+
+    /// The `Kdf` stored in `backend` for the credentials of `user`.
+    /// Stashes without one use `Kdf::default()`.
+    pub fn kdf(backend: &dyn Backend, user: &str) -> Result<Option<Kdf>> {
+        let id = kdf_object_id(user);
+        let object = match backend.read_object(&id) {
+            Ok(object) => object,
+            Err(BackendError::NoObjectFound) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        object
+            .buffer
+            .as_ref()
+            .strip_prefix(KDF_MAGIC)
+            .and_then(|data| serde_cbor::from_slice(data).ok())
+            .map(Some)
+            .ok_or(ZerostashError::Corrupt { object: id })
+    }
+
+    /// Like `change_password`, with the key derived from `user` and
+    /// `password` by `kdf`, which is stored for opening the stash.
+    pub fn change_credentials(&mut self, user: &str, password: &str, kdf: &Kdf) -> Result<()> {
+        let credentials = StashKey::derive(user, password, kdf)?;
+        self.write_kdf(user, kdf)?;
+        self.change_password(&credentials)
+    }
+
+    /// Make `credentials` the ones that open the stash, instead of
+    /// the ones it was opened with.
+    ///
+    /// Only the key object is written, the master key and everything
+    /// encrypted by it stay the same. So anyone who had the old
+    /// credentials could have kept the master key, and still read
+    /// the stash with it.
+    pub fn change_password(&mut self, credentials: &StashKey) -> Result<()> {
+        self.store_new_credentials()?;
+        let old = match self.key_object {
+            Some(id) => id,
+            None => self.master_key.key_object_id()?,
+        };
+
+        let new = self.write_key_object(credentials)?;
+        if new != old {
+            self.revoke(old)?;
+        }
+
+        self.key_object = Some(new);
+        if let Some(mut slots) = self.stored_slots()? {
+            for slot in slots.iter_mut().filter(|s| s.key_object == old) {
+                slot.key_object = new;
+            }
+            self.write_slots(&slots)?;
+        }
+        Ok(())
+    }
+
+    /// Split the master key into `shares` shares, any `threshold` of
+    /// which open the stash through `StashBuilder::shares`.
+    ///
+    /// The shares open the stash whatever its credentials are later,
+    /// as they hold the master key itself.
+    pub fn split_key(&self, threshold: u8, shares: u8) -> Result<Vec<shamir::Share>> {
+        Ok(shamir::split(&self.master_key, threshold, shares)?)
+    }
+
+    /// The names of the key slots, whose credentials open the stash.
+    /// Until others are added, the credentials it was opened with
+    /// are the only slot, `primary`.
+    pub fn slots(&mut self) -> Result<Vec<String>> {
+        Ok(self.all_slots()?.into_iter().map(|s| s.name).collect())
+    }
+
+    /// Let `credentials` open the stash too, as the slot `name`.
+    pub fn add_slot(&mut self, name: &str, credentials: &StashKey) -> Result<()> {
+        let mut slots = self.all_slots()?;
+        if slots.iter().any(|s| s.name == name) {
+            return Err(ZerostashError::SlotExists(name.into()));
+        }
+        let id = credentials.key_object_id()?;
+        if slots.iter().any(|s| s.key_object == id) {
+            return Err(ZerostashError::Exists);
+        }
+
+        self.write_key_object(credentials)?;
+        slots.push(Slot {
+            name: name.into(),
+            key_object: id,
+        });
+        self.write_slots(&slots)
+    }
+
+    /// Like `add_slot`, with the key derived from `user` and
+    /// `password` by `kdf`, which is stored for opening the stash.
+    pub fn add_credentials(
+        &mut self,
+        name: &str,
+        user: &str,
+        password: &str,
+        kdf: &Kdf,
+    ) -> Result<()> {
+        let credentials = StashKey::derive(user, password, kdf)?;
+        self.write_kdf(user, kdf)?;
+        self.add_slot(name, &credentials)
+    }
+
+    /// Stop the credentials of slot `name` from opening the stash.
+    ///
+    /// Like with `change_password`, someone who had them could have
+    /// kept the master key.
+    pub fn revoke_slot(&mut self, name: &str) -> Result<()> {
+        let mut slots = self.all_slots()?;
+        let index = slots
+            .iter()
+            .position(|s| s.name == name)
+            .ok_or_else(|| ZerostashError::NoSuchSlot(name.into()))?;
+        if slots.len() == 1 {
+            return Err(ZerostashError::LastSlot);
+        }
+
+        let slot = slots.remove(index);
+        self.revoke(slot.key_object)?;
+        self.write_slots(&slots)
+    }
+
+    fn all_slots(&mut self) -> Result<Vec<Slot>> {
+        self.store_new_credentials()?;
+        if let Some(slots) = self.stored_slots()? {
+            return Ok(slots);
+        }
+
+        let key_object = match self.key_object {
+            Some(id) => id,
+            None => self.master_key.key_object_id()?,
+        };
+        Ok(vec![Slot {
+            name: PRIMARY.into(),
+            key_object,
+        }])
+    }
+
+    /// The key objects that open the stash, and the object listing
+    /// them, which some of them may not be written to yet.
+    pub(super) fn key_objects(&self) -> Result<Vec<ObjectId>> {
+        let mut objects = vec![
+            self.master_key.slots_object_id()?,
+            self.key_object.unwrap_or(self.master_key.key_object_id()?),
+        ];
+        for slot in self.stored_slots()?.unwrap_or_default() {
+            objects.push(slot.key_object);
+        }
+        Ok(objects)
+    }
+
+    fn stored_slots(&self) -> Result<Option<Vec<Slot>>> {
+        let id = self.master_key.slots_object_id()?;
+        let object = match self.backend.read_object(&id) {
+            Ok(object) => object,
+            Err(BackendError::NoObjectFound) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        self.master_key
+            .open(b"_0s_slots", object.buffer.as_ref())
+            .ok()
+            .and_then(|data| serde_cbor::from_slice(&data).ok())
+            .map(Some)
+            .ok_or(ZerostashError::Corrupt { object: id })
+    }
+
+    fn write_slots(&self, slots: &[Slot]) -> Result<()> {
+        let data = serde_cbor::to_vec(&slots).unwrap();
+        let sealed = self.master_key.seal(b"_0s_slots", &data)?;
+        self.backend.write_object(&Object::with_id(
+            self.master_key.slots_object_id()?,
+            BlockBuffer::from(sealed),
+        ))?;
+
+        Ok(())
+    }
+
+    /// Mark the key object `id` as revoked.
+    fn revoke(&self, id: ObjectId) -> Result<()> {
+        let revoked = [MAGIC, &[0]].concat();
+        self.backend
+            .write_object(&Object::with_id(id, BlockBuffer::from(revoked)))?;
+
+        Ok(())
+    }
+
+    /// Write the KDF and key object of a new stash, if it has any
+    /// that aren't stored yet.
+    pub(crate) fn store_new_credentials(&mut self) -> Result<()> {
+        // kept until they're stored, so a failed commit stores them
+        // on the next one
+        if let Some(new) = &self.new_credentials {
+            self.write_kdf(&new.user, &new.kdf)?;
+            let key_object = self.write_key_object(&new.credentials)?;
+            self.key_object = Some(key_object);
+            self.new_credentials = None;
+        }
+        Ok(())
+    }
+
+    fn write_kdf(&self, user: &str, kdf: &Kdf) -> Result<()> {
+        let data = [KDF_MAGIC, &serde_cbor::to_vec(kdf).unwrap()].concat();
+        self.backend.write_object(&Object::with_id(
+            kdf_object_id(user),
+            BlockBuffer::from(data),
+        ))?;
+
+        Ok(())
+    }
+
+    fn write_key_object(&self, credentials: &StashKey) -> Result<ObjectId> {
+        let id = credentials.key_object_id()?;
+        let data = [MAGIC, &[1], &credentials.wrap_key(&self.master_key)?].concat();
+        self.backend
+            .write_object(&Object::with_id(id, BlockBuffer::from(data)))?;
+
+        Ok(id)
+    }
+}
+
+/// The KDF parameters can't depend on the password, so they are
+/// found by the user name.
+fn kdf_object_id(user: &str) -> ObjectId {
+    ObjectId::from_bytes(chunk_hash(format!("_0s_kdf{}", user).as_bytes()))
+}
+
+fn exists(backend: &dyn Backend, credentials: &StashKey) -> Result<bool> {
+    for id in [credentials.key_object_id()?, credentials.root_object_id()?] {
+        match backend.read_object(&id) {
+            Err(BackendError::NoObjectFound) => {}
+            Ok(_) => return Ok(true),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(false)
+}
+
+fn unwrap_master(credentials: &StashKey, id: &ObjectId, data: &[u8]) -> Result<StashKey> {
+    match data.strip_prefix(MAGIC) {
+        Some([0]) => Err(ZerostashError::WrongPassphrase),
+        Some([1, wrapped @ ..]) => credentials
+            .unwrap_key(wrapped)
+            .map_err(|_| ZerostashError::Corrupt { object: *id }),
+        _ => Err(ZerostashError::Corrupt { object: *id }),
+    }
+}
+
+#[cfg(all(test, feature = "fs"))]
+mod tests {
+    #[test]
+    fn passwords_are_changed_without_rewriting_data() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+
+        let backend = Arc::new(MemoryBackend::default());
+        let old = || StashKey::open_stash("user", "old").unwrap();
+        let new = || StashKey::open_stash("user", "new").unwrap();
+
+        let mut stash = Stash::create(backend.clone(), old()).unwrap();
+        stash.add_recursive(2, "tests/data/100_random_1k").unwrap();
+        stash.commit().unwrap();
+        assert!(matches!(
+            Stash::create(backend.clone(), old()),
+            Err(ZerostashError::Exists)
+        ));
+
+        let objects = backend.len();
+        stash.change_password(&new()).unwrap();
+        // the new key object, and the old one revoked
+        assert_eq!(backend.len(), objects + 1);
+
+        let mut stash = Stash::open(backend.clone(), new()).unwrap();
+        assert_eq!(stash.read().unwrap().file_index().len(), 100);
+        assert!(matches!(
+            Stash::open(backend, old()),
+            Err(ZerostashError::WrongPassphrase)
+        ));
+    }
+
+    #[test]
+    fn stashes_without_key_objects_are_converted() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+
+        let backend = Arc::new(MemoryBackend::default());
+        let old = || StashKey::open_stash("user", "old").unwrap();
+
+        let mut stash = Stash::new(backend.clone(), old());
+        stash.add_recursive(2, "tests/data/100_random_1k").unwrap();
+        stash.commit().unwrap();
+
+        let mut stash = Stash::open(backend.clone(), old()).unwrap();
+        let new = StashKey::open_stash("user", "new").unwrap();
+        stash.change_password(&new).unwrap();
+
+        let mut stash = Stash::open(backend.clone(), new).unwrap();
+        assert_eq!(stash.read().unwrap().file_index().len(), 100);
+        assert!(Stash::open(backend, old()).is_err());
+    }
+    #[test]
+    fn slots_open_the_stash_independently() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+
+        let backend = Arc::new(MemoryBackend::default());
+        let key = |password| StashKey::open_stash("user", password).unwrap();
+
+        let mut stash = Stash::create(backend.clone(), key("primary")).unwrap();
+        stash.add_recursive(2, "tests/data/100_random_1k").unwrap();
+        stash.commit().unwrap();
+        assert_eq!(stash.slots().unwrap(), ["primary"]);
+
+        stash.add_slot("recovery", &key("recovery")).unwrap();
+        assert!(matches!(
+            stash.add_slot("recovery", &key("other")),
+            Err(ZerostashError::SlotExists(_))
+        ));
+        assert!(matches!(
+            stash.add_slot("again", &key("recovery")),
+            Err(ZerostashError::Exists)
+        ));
+
+        let mut stash = Stash::open(backend.clone(), key("recovery")).unwrap();
+        assert_eq!(stash.read().unwrap().file_index().len(), 100);
+        assert_eq!(stash.slots().unwrap(), ["primary", "recovery"]);
+        assert!(Stash::open(backend.clone(), key("primary")).is_ok());
+
+        stash.revoke_slot("primary").unwrap();
+        assert!(matches!(
+            Stash::open(backend.clone(), key("primary")),
+            Err(ZerostashError::WrongPassphrase)
+        ));
+        assert!(matches!(
+            stash.revoke_slot("recovery"),
+            Err(ZerostashError::LastSlot)
+        ));
+        assert!(matches!(
+            stash.revoke_slot("primary"),
+            Err(ZerostashError::NoSuchSlot(_))
+        ));
+
+        // the slot follows a password change
+        stash.change_password(&key("new")).unwrap();
+        let mut stash = Stash::open(backend, key("new")).unwrap();
+        assert_eq!(stash.slots().unwrap(), ["recovery"]);
+        assert_eq!(stash.read().unwrap().file_index().len(), 100);
+    }
+
+    #[test]
+    fn shares_open_the_stash() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use crate::stash::StashBuilder;
+
+        let backend = Arc::new(MemoryBackend::default());
+        let mut stash = Stash::new(
+            backend.clone(),
+            StashKey::open_stash("user", "old").unwrap(),
+        );
+        stash.add_recursive(2, "tests/data/100_random_1k").unwrap();
+        stash.commit().unwrap();
+        let shares = stash.split_key(2, 3).unwrap();
+        // the shares keep working after the credentials change
+        stash
+            .change_password(&StashKey::open_stash("user", "new").unwrap())
+            .unwrap();
+
+        let open = |shares: &[shamir::Share]| {
+            StashBuilder::new()
+                .backend(backend.clone())
+                .shares(shares.to_vec())
+                .build()
+        };
+        let mut stash = open(&shares[1..]).unwrap();
+        assert_eq!(stash.read().unwrap().file_index().len(), 100);
+        assert!(open(&shares[..1]).is_err());
+    }
+
+    #[test]
+    fn kdf_parameters_are_stored_with_the_stash() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+
+        let light = |iterations| Kdf::Argon2id {
+            memory_kib: 8,
+            iterations,
+            parallelism: 1,
+        };
+        let backend = Arc::new(MemoryBackend::default());
+
+        let mut stash =
+            Stash::open_with_credentials(backend.clone(), "user", "old", Some(light(1))).unwrap();
+        stash.add_recursive(2, "tests/data/100_random_1k").unwrap();
+        stash.commit().unwrap();
+        assert_eq!(Stash::kdf(&*backend, "user").unwrap(), Some(light(1)));
+
+        // the stored parameters are used over new ones
+        let mut stash =
+            Stash::open_with_credentials(backend.clone(), "user", "old", Some(light(2))).unwrap();
+        assert_eq!(stash.read().unwrap().file_index().len(), 100);
+
+        stash.change_credentials("user", "new", &light(2)).unwrap();
+        let mut stash = Stash::open_with_credentials(backend.clone(), "user", "new", None).unwrap();
+        assert_eq!(stash.read().unwrap().file_index().len(), 100);
+
+        // stashes from before stored parameters use the default
+        let legacy = Arc::new(MemoryBackend::default());
+        let mut stash = Stash::new(legacy.clone(), StashKey::open_stash("user", "old").unwrap());
+        stash.add_recursive(2, "tests/data/100_random_1k").unwrap();
+        stash.commit().unwrap();
+        let mut stash = Stash::open_with_credentials(legacy.clone(), "user", "old", None).unwrap();
+        assert_eq!(stash.read().unwrap().file_index().len(), 100);
+        assert_eq!(Stash::kdf(&*legacy, "user").unwrap(), None);
+    }
+}
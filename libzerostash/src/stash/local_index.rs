@@ -0,0 +1,148 @@
+use crate::chunks::ChunkStore;
+use crate::crypto::{self, CryptoDigest};
+use crate::error::Result;
+use crate::objects::ObjectId;
+use crate::stash::Stash;
+
+use std::path::PathBuf;
+
+impl Stash {
+    /// Keep the chunk index at `path` on the local disk, and map it
+    /// into memory, instead of reading all of it from the metadata
+    /// into memory, which for a large stash takes a while, and a lot of
+    /// memory.
+    ///
+    /// Only changes are added to it at commits. It's rebuilt from the
+    /// metadata when it's missing, or the stash was changed from
+    /// elsewhere since. Like the file cache, it holds the chunk
+    /// pointers in plaintext.
+    pub fn use_index_cache(&mut self, path: impl Into<PathBuf>) {
+        self.index_cache = Some(path.into());
+    }
+
+    /// Open the local index for the commit that was read, if there's
+    /// one, instead of loading the chunk index.
+    pub(super) fn open_local_index(&mut self) -> Result<bool> {
+        let (path, commit) = match (&self.index_cache, &self.commit_digest) {
+            (Some(path), Some(commit)) => (path, commit),
+            _ => return Ok(false),
+        };
+        let stash = self.stash_id()?;
+        match ChunkStore::open_local(path, &stash, commit)? {
+            Some(store) => {
+                debug!("opened local index {:?}", path);
+                store.put_stats(self.chunks.take_stats());
+                self.chunks = store;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Save the chunk index to the local index, as of the commit that
+    /// was read or written.
+    pub(super) fn save_local_index(&mut self) -> Result<()> {
+        let (path, commit) = match (&self.index_cache, &self.commit_digest) {
+            (Some(path), Some(commit)) => (path.clone(), *commit),
+            _ => return Ok(()),
+        };
+        let stash = self.stash_id()?;
+        if self.chunks.save_local(&path, &stash, &commit)? {
+            debug!(
+                "saved {} chunks to local index {:?}",
+                self.chunks.index().len(),
+                path
+            );
+            self.open_local_index()?;
+        }
+        Ok(())
+    }
+
+    pub(super) fn stash_id(&self) -> Result<CryptoDigest> {
+        Ok(crypto::chunk_hash(
+            self.master_key.root_object_id()?.as_ref(),
+        ))
+    }
+}
+
+/// Identifies a commit by the metadata objects it wrote, in order.
+pub(super) fn commit_digest(objects: &[(ObjectId, CryptoDigest)]) -> CryptoDigest {
+    let mut bytes = Vec::with_capacity(objects.len() * 64);
+    for (id, digest) in objects {
+        bytes.extend_from_slice(id.as_ref());
+        bytes.extend_from_slice(digest);
+    }
+    crypto::chunk_hash(&bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn the_index_is_kept_on_disk() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use crate::files::Entry;
+        use crate::meta::Field;
+        use crate::snapshots::Labels;
+        use crate::stash::StashKey;
+        use std::fs;
+        use std::sync::Arc;
+
+        let path = std::env::temp_dir().join("0s_test_local_index");
+        let _ = fs::remove_file(&path);
+        let backend = MemoryBackend::default();
+        let key = || StashKey::open_stash("local index", "test").unwrap();
+        let open = || {
+            let mut stash = Stash::new(Arc::new(backend.clone()), key());
+            stash.use_index_cache(&path);
+            stash.read_fields(&[Field::Chunks]).unwrap();
+            stash
+        };
+        let add = |stash: &mut Stash, name: &str, data: &[u8]| {
+            let mut ingest = stash.ingest().unwrap();
+            ingest.add_file(Entry::from_stream(name), data).unwrap();
+            ingest.finish(0, vec![], vec![], Labels::new()).unwrap();
+        };
+        let contents = crate::test_util::pseudo_random(200_000, 7);
+
+        let mut stash = Stash::new(Arc::new(backend.clone()), key());
+        stash.use_index_cache(&path);
+        stash.set_chunker(crate::stash::Chunker::Fixed);
+        add(&mut stash, "a", &contents);
+        let chunks = stash.chunk_index().len();
+        assert!(chunks > 8);
+        assert!(path.exists());
+
+        // reopened from the disk, and only the change is saved
+        let mut stash = open();
+        assert!(stash.chunk_index().is_mapped());
+        assert_eq!(stash.chunk_index().len(), chunks);
+        add(&mut stash, "b", b"a little more");
+        let mut delta = path.clone().into_os_string();
+        delta.push(".delta");
+        assert!(std::path::Path::new(&delta).exists());
+        let stored = stash.stored_bytes();
+
+        let mut all = vec![];
+        let stash = open();
+        stash.chunk_index().for_each(|hash, _| all.push(*hash));
+        assert_eq!(all.len(), chunks + 1);
+        assert_eq!(stash.stored_bytes(), stored);
+        let mut stash = open();
+        stash.read().unwrap();
+        let mut read = vec![];
+        std::io::Read::read_to_end(&mut stash.open_file("a").unwrap(), &mut read).unwrap();
+        assert_eq!(read, contents);
+
+        // commits from elsewhere leave it behind, until it's rebuilt
+        let mut elsewhere = Stash::new(Arc::new(backend.clone()), key());
+        elsewhere.read().unwrap();
+        add(&mut elsewhere, "c", b"from elsewhere");
+        let stash = open();
+        assert_eq!(stash.chunk_index().len(), chunks + 2);
+        assert!(stash.chunk_index().is_mapped());
+
+        fs::remove_file(&path).unwrap();
+        let _ = fs::remove_file(&delta);
+    }
+}
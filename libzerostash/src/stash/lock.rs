@@ -0,0 +1,486 @@
+//! Cooperative locks, so writers of a stash don't overwrite each
+//! other's metadata, and objects readers use aren't deleted under
+//! them.
+//!
+//! The holders of the lock are listed in an object of their own,
+//! sealed with a key derived from the master key. Backends can't
+//! replace an object atomically, so a new holder writes the list with
+//! itself added, waits for anyone racing it to do the same, then
+//! reads the list back to check that it's still in it.
+//!
+//! While it holds the lock, a process refreshes it on a thread of its
+//! own, so long backups or `watch` don't look stale to others.
+
+use crate::backends::{Backend, BackendError};
+use crate::crypto::StashKey;
+use crate::error::{Result, ZerostashError};
+use crate::objects::{BlockBuffer, Object};
+use crate::stash::Stash;
+
+use crossbeam_channel::RecvTimeoutError;
+use getrandom::getrandom;
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How long a new holder waits before checking that it holds the
+/// lock.
+const SETTLE: Duration = Duration::from_millis(200);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LockKind {
+    /// Any number of readers can hold it, while nobody holds it
+    /// exclusively
+    Shared,
+    /// Only one writer can hold it, and no reader
+    Exclusive,
+}
+
+/// A process that holds the lock of a stash.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Holder {
+    pub id: u64,
+    pub kind: LockKind,
+    pub host: String,
+    pub pid: u32,
+    /// When it took or last refreshed the lock
+    pub unix_secs: u64,
+}
+
+impl Holder {
+    /// If the holder is gone, which it is if it didn't refresh the
+    /// lock for `stale`, or is a process of this host that exited.
+    pub fn is_stale(&self, stale: Duration) -> bool {
+        now().saturating_sub(self.unix_secs) >= stale.as_secs()
+            || (self.host == hostname() && !is_running(self.pid))
+    }
+
+    fn conflicts(&self, kind: LockKind) -> bool {
+        self.kind == LockKind::Exclusive || kind == LockKind::Exclusive
+    }
+}
+
+impl fmt::Display for Holder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.kind {
+            LockKind::Shared => "shared",
+            LockKind::Exclusive => "exclusive",
+        };
+        write!(
+            f,
+            "{} lock of process {} on {}, refreshed {}s ago",
+            kind,
+            self.pid,
+            self.host,
+            now().saturating_sub(self.unix_secs)
+        )
+    }
+}
+
+impl Stash {
+    /// Take the lock of the stash, failing with `Locked` if another
+    /// process holds it in a way that conflicts, unless its lock is
+    /// stale. Taking the lock again only changes its kind.
+    ///
+    /// Locks are cooperative: commits fail while another process
+    /// holds the lock exclusively, but nothing else checks them.
+    /// The lock is refreshed in the background until it's released,
+    /// at the latest when the stash is dropped.
+    pub fn lock(&mut self, kind: LockKind) -> Result<()> {
+        // nothing else writes the list meanwhile
+        drop(self.keeper.take());
+        let result = self.take_lock(kind);
+        if let Some(holder) = &self.lock {
+            self.keeper = Some(Keeper::start(
+                self.backend.clone(),
+                StashKey::from_bytes(*self.master_key.expose()),
+                holder.id,
+                self.stale_locks,
+            ));
+        }
+        result
+    }
+
+    fn take_lock(&mut self, kind: LockKind) -> Result<()> {
+        let held = self.lock.clone();
+        let id = match &held {
+            Some(held) => held.id,
+            None => {
+                let mut id = [0; 8];
+                getrandom(&mut id).unwrap();
+                u64::from_le_bytes(id)
+            }
+        };
+        let holder = Holder {
+            id,
+            kind,
+            host: hostname(),
+            pid: std::process::id(),
+            unix_secs: now(),
+        };
+
+        let mut holders = self.live_locks()?;
+        holders.retain(|h| h.id != id);
+        if let Some(other) = holders.iter().find(|h| h.conflicts(kind)) {
+            return Err(ZerostashError::Locked(other.to_string()));
+        }
+        holders.push(holder.clone());
+        self.write_locks(&holders)?;
+
+        // a holder racing this one either overwrote the list, or is
+        // in it next to this one
+        thread::sleep(SETTLE);
+        let mut holders = self.live_locks()?;
+        let raced = holders
+            .iter()
+            .find(|h| h.id != id && h.conflicts(kind))
+            .map(|h| h.to_string());
+        if let Some(other) = raced {
+            holders.retain(|h| h.id != id);
+            holders.extend(held);
+            self.write_locks(&holders)?;
+            return Err(ZerostashError::Locked(other));
+        }
+        if !self.locks()?.iter().any(|h| h.id == id) {
+            return Err(ZerostashError::Locked(
+                "another process took it at the same time".into(),
+            ));
+        }
+
+        self.lock = Some(holder);
+        Ok(())
+    }
+
+    /// Record that the holder of the lock is still there, so it
+    /// doesn't get stale, which also happens in the background.
+    /// Fails with `Locked` if it was removed as stale meanwhile.
+    pub fn refresh_lock(&mut self) -> Result<()> {
+        let holder = match &mut self.lock {
+            Some(holder) => holder,
+            None => return Ok(()),
+        };
+        holder.unix_secs = now();
+
+        let _io = self.keeper.as_ref().map(|k| k.io.lock().unwrap());
+        if refresh(&*self.backend, &self.master_key, holder.id)? {
+            Ok(())
+        } else {
+            Err(lost())
+        }
+    }
+
+    /// Release the lock, if it's held.
+    pub fn unlock(&mut self) -> Result<()> {
+        drop(self.keeper.take());
+        let holder = match self.lock.take() {
+            Some(holder) => holder,
+            None => return Ok(()),
+        };
+
+        let mut holders = self.live_locks()?;
+        holders.retain(|h| h.id != holder.id);
+        self.write_locks(&holders)
+    }
+
+    /// The holders of the lock, stale ones included.
+    pub fn locks(&self) -> Result<Vec<Holder>> {
+        read_holders(&*self.backend, &self.master_key)
+    }
+
+    /// Remove the stale holders of the lock, or all of them, as when
+    /// a crashed process left its lock behind. Returns the ones
+    /// removed.
+    pub fn force_unlock(&mut self, all: bool) -> Result<Vec<Holder>> {
+        let stale = self.stale_locks;
+        let (removed, kept) = self
+            .locks()?
+            .into_iter()
+            .partition::<Vec<_>, _>(|h| all || h.is_stale(stale));
+        if all {
+            drop(self.keeper.take());
+            self.lock = None;
+        }
+        if !removed.is_empty() {
+            self.write_locks(&kept)?;
+        }
+        Ok(removed)
+    }
+
+    /// Holders older than `stale` are ignored, and can be removed by
+    /// `force_unlock`. Defaults to an hour.
+    pub fn set_stale_locks(&mut self, stale: Duration) {
+        self.stale_locks = stale;
+    }
+
+    /// Fail if another process holds the lock exclusively, or the
+    /// lock this one took was removed, before overwriting the
+    /// metadata. Append-only writers don't lock, as they write
+    /// stashes of their own.
+    pub(crate) fn check_lock(&self) -> Result<()> {
+        if self.recipient.is_some() {
+            return Ok(());
+        }
+        let ours = self.lock.as_ref().map(|h| h.id);
+        let holders = self.live_locks()?;
+        if let Some(other) = holders
+            .iter()
+            .find(|h| Some(h.id) != ours && h.kind == LockKind::Exclusive)
+        {
+            return Err(ZerostashError::Locked(other.to_string()));
+        }
+        match ours {
+            Some(id) if !holders.iter().any(|h| h.id == id) => Err(lost()),
+            _ => Ok(()),
+        }
+    }
+
+    fn live_locks(&self) -> Result<Vec<Holder>> {
+        let stale = self.stale_locks;
+        let mut holders = self.locks()?;
+        holders.retain(|h| !h.is_stale(stale));
+        Ok(holders)
+    }
+
+    fn write_locks(&self, holders: &[Holder]) -> Result<()> {
+        let id = self.master_key.lock_object_id()?;
+        if holders.is_empty() {
+            return match self.backend.delete_object(&id) {
+                Ok(()) | Err(BackendError::NoObjectFound) => Ok(()),
+                // backends that can't delete keep an empty list
+                Err(BackendError::Unsupported(_)) => self.store_locks(holders),
+                Err(e) => Err(e.into()),
+            };
+        }
+        self.store_locks(holders)
+    }
+
+    fn store_locks(&self, holders: &[Holder]) -> Result<()> {
+        store_holders(&*self.backend, &self.master_key, holders)
+    }
+}
+
+/// Refreshes the holder of the lock every third of the time it takes
+/// to get stale, until it's dropped. Stops once the holder is gone
+/// from the list, which `check_lock` then reports.
+pub(crate) struct Keeper {
+    stop: Option<crossbeam_channel::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+    /// Held while the list is rewritten
+    io: Arc<Mutex<()>>,
+}
+
+impl Keeper {
+    fn start(backend: Arc<dyn Backend>, key: StashKey, id: u64, stale: Duration) -> Keeper {
+        let (stop, stopped) = crossbeam_channel::bounded::<()>(0);
+        let io = Arc::new(Mutex::new(()));
+        let interval = (stale / 3).max(MIN_REFRESH);
+
+        let thread = {
+            let io = io.clone();
+            thread::spawn(move || loop {
+                match stopped.recv_timeout(interval) {
+                    Err(RecvTimeoutError::Timeout) => {}
+                    _ => return,
+                }
+
+                let _io = io.lock().unwrap();
+                match refresh(&*backend, &key, id) {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        warn!("{}", lost());
+                        return;
+                    }
+                    Err(e) => warn!("failed to refresh the lock of the stash: {}", e),
+                }
+            })
+        };
+
+        Keeper {
+            stop: Some(stop),
+            thread: Some(thread),
+            io,
+        }
+    }
+}
+
+impl Drop for Keeper {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Set the time of the holder `id` to now, if it's still listed.
+fn refresh(backend: &dyn Backend, key: &StashKey, id: u64) -> Result<bool> {
+    let mut holders = read_holders(backend, key)?;
+    match holders.iter_mut().find(|h| h.id == id) {
+        Some(holder) => holder.unix_secs = now(),
+        None => return Ok(false),
+    }
+    store_holders(backend, key, &holders)?;
+    Ok(true)
+}
+
+fn read_holders(backend: &dyn Backend, key: &StashKey) -> Result<Vec<Holder>> {
+    let id = key.lock_object_id()?;
+    let object = match backend.read_object(&id) {
+        Ok(object) => object,
+        Err(BackendError::NoObjectFound) => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+
+    key.open(b"_0s_lock", object.buffer.as_ref())
+        .ok()
+        .and_then(|data| serde_cbor::from_slice(&data).ok())
+        .ok_or(ZerostashError::Corrupt { object: id })
+}
+
+fn store_holders(backend: &dyn Backend, key: &StashKey, holders: &[Holder]) -> Result<()> {
+    let data = serde_cbor::to_vec(&holders).unwrap();
+    let sealed = key.seal(b"_0s_lock", &data)?;
+    backend.write_object(&Object::with_id(
+        key.lock_object_id()?,
+        BlockBuffer::from(sealed),
+    ))?;
+
+    Ok(())
+}
+
+fn lost() -> ZerostashError {
+    ZerostashError::Locked("the lock of this process was removed as stale".into())
+}
+
+impl Drop for Stash {
+    fn drop(&mut self) {
+        if let Err(e) = self.unlock() {
+            warn!("the lock of the stash is left behind: {}", e);
+        }
+    }
+}
+
+/// The default of `Stash::set_stale_locks`
+pub(crate) const STALE_LOCKS: Duration = Duration::from_secs(60 * 60);
+
+/// Locks aren't refreshed more often than this, however soon they get
+/// stale.
+const MIN_REFRESH: Duration = Duration::from_millis(100);
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(unix)]
+fn hostname() -> String {
+    let mut name = [0u8; 256];
+    let len = match unsafe { libc::gethostname(name.as_mut_ptr() as *mut libc::c_char, name.len()) }
+    {
+        0 => name.iter().position(|b| *b == 0).unwrap_or(name.len()),
+        _ => 0,
+    };
+    String::from_utf8_lossy(&name[..len]).into_owned()
+}
+
+#[cfg(not(unix))]
+fn hostname() -> String {
+    String::new()
+}
+
+#[cfg(unix)]
+fn is_running(pid: u32) -> bool {
+    // signal 0 only checks that the process exists
+    let exists = unsafe { libc::kill(pid as libc::pid_t, 0) } == 0;
+    exists || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn is_running(_pid: u32) -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn writers_exclude_each_other() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use crate::stash::StashKey;
+        use std::sync::Arc;
+
+        let backend = MemoryBackend::default();
+        let key = || StashKey::open_stash("lock", "test").unwrap();
+        let mut first = Stash::new(Arc::new(backend.clone()), key());
+        let mut second = Stash::new(Arc::new(backend.clone()), key());
+
+        first.lock(LockKind::Exclusive).unwrap();
+        assert!(matches!(
+            second.lock(LockKind::Shared),
+            Err(ZerostashError::Locked(_))
+        ));
+        assert!(second.commit().is_err());
+        first.commit().unwrap();
+        first.unlock().unwrap();
+
+        // readers share the lock, and keep writers out
+        second.lock(LockKind::Shared).unwrap();
+        first.lock(LockKind::Shared).unwrap();
+        assert_eq!(first.locks().unwrap().len(), 2);
+        assert!(first.lock(LockKind::Exclusive).is_err());
+        second.commit().unwrap();
+
+        // a holder that didn't refresh its lock is stale
+        first.set_stale_locks(Duration::ZERO);
+        first.lock(LockKind::Exclusive).unwrap();
+        assert_eq!(first.force_unlock(true).unwrap().len(), 1);
+        drop(first);
+        assert!(second.locks().unwrap().is_empty());
+    }
+
+    #[test]
+    fn stale_locks_are_taken_over_unless_refreshed() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use crate::stash::StashKey;
+        use std::sync::Arc;
+
+        let backend = MemoryBackend::default();
+        let key = || StashKey::open_stash("lock", "test").unwrap();
+        let mut first = Stash::new(Arc::new(backend.clone()), key());
+        let mut second = Stash::new(Arc::new(backend.clone()), key());
+
+        // as if the holder stopped refreshing its lock long ago
+        let backdate = |stash: &Stash| {
+            let mut holders = stash.locks().unwrap();
+            for holder in holders.iter_mut() {
+                holder.unix_secs -= STALE_LOCKS.as_secs() + 1;
+            }
+            stash.store_locks(&holders).unwrap();
+        };
+
+        first.lock(LockKind::Exclusive).unwrap();
+        backdate(&first);
+        second.lock(LockKind::Exclusive).unwrap();
+        assert!(matches!(first.commit(), Err(ZerostashError::Locked(_))));
+        assert!(first.refresh_lock().is_err());
+        drop(second);
+        first.unlock().unwrap();
+
+        // the lock is refreshed in the background while it's held
+        first.set_stale_locks(Duration::from_secs(3));
+        first.lock(LockKind::Exclusive).unwrap();
+        backdate(&first);
+        thread::sleep(Duration::from_millis(1500));
+        let mut second = Stash::new(Arc::new(backend.clone()), key());
+        assert!(matches!(
+            second.lock(LockKind::Shared),
+            Err(ZerostashError::Locked(_))
+        ));
+        first.commit().unwrap();
+    }
+}
@@ -0,0 +1,89 @@
+use crate::crypto::{self, CryptoDigest};
+use crate::error::Result;
+use crate::snapshots::Snapshot;
+use crate::stash::Stash;
+
+use itertools::Itertools;
+use serde_json::json;
+
+use std::io::Write;
+
+impl Stash {
+    /// Write the files of `snapshot` to `out` as newline-delimited
+    /// JSON, one object per file. Returns the number of files.
+    ///
+    /// Each line carries the metadata of the file and the hashes of
+    /// its chunks, keyed by the offset they start at. `hash` is the
+    /// hash of the chunk hashes, which identifies the contents. These
+    /// are hashes of the plaintext, so the manifest should be kept as
+    /// safe as the files themselves.
+    pub fn export_manifest(&self, snapshot: &Snapshot, mut out: impl Write) -> Result<u64> {
+        for file in snapshot.files.iter() {
+            let chunks = file
+                .chunks
+                .iter()
+                .map(|(offset, cp)| json!([offset, hex(&cp.hash)]))
+                .collect::<Vec<_>>();
+            let hashes = file
+                .chunks
+                .iter()
+                .flat_map(|(_, cp)| cp.hash.iter().copied())
+                .collect::<Vec<_>>();
+
+            let line = json!({
+                "snapshot": snapshot.id,
+                "name": file.name,
+                "size": file.size,
+                "unix_secs": file.unix_secs,
+                "unix_nanos": file.unix_nanos,
+                "unix_perm": file.unix_perm,
+                "unix_uid": file.unix_uid,
+                "unix_gid": file.unix_gid,
+                "readonly": file.readonly,
+                "hash": hex(&crypto::chunk_hash(&hashes)),
+                "chunks": chunks,
+            });
+            writeln!(out, "{}", line)?;
+        }
+        out.flush()?;
+
+        Ok(snapshot.files.len() as u64)
+    }
+}
+
+fn hex(digest: &CryptoDigest) -> String {
+    format!("{:02x}", digest.iter().format(""))
+}
+
+#[cfg(all(test, feature = "fs"))]
+mod tests {
+    #[test]
+    fn manifest_lists_every_file() {
+        use crate::backends::MemoryBackend;
+        use crate::stash::{BackupOptions, Stash, StashKey};
+        use std::sync::Arc;
+
+        let key = StashKey::open_stash("manifest", "test").unwrap();
+        let mut stash = Stash::new(Arc::new(MemoryBackend::default()), key);
+        let snapshot = stash
+            .backup(&["tests/data/100_random_1k"], &BackupOptions::default())
+            .unwrap();
+
+        let mut out = vec![];
+        assert_eq!(stash.export_manifest(&snapshot, &mut out).unwrap(), 100);
+
+        let lines = std::str::from_utf8(&out)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(lines.len(), 100);
+
+        for line in lines.iter() {
+            assert_eq!(line["snapshot"], snapshot.id);
+            assert_eq!(line["size"], 10240);
+            assert_eq!(line["hash"].as_str().unwrap().len(), 64);
+            assert_eq!(line["chunks"][0][0], 0);
+        }
+    }
+}
@@ -0,0 +1,164 @@
+use crate::stash::Stash;
+
+/// Rough size of an entry of the chunk index, with the map's own
+/// overhead
+const CHUNK_ENTRY: u64 = 160;
+/// Rough size of an entry of the file index, without its chunks
+const FILE_ENTRY: u64 = 256;
+/// Size of a reference from a file to one of its chunks
+const FILE_CHUNK: u64 = 16;
+/// What a backup worker holds besides the object it fills: a small
+/// file read into memory
+const SMALL_FILE: u64 = 128 * 1024;
+/// Objects a reader of files keeps, without a limit
+pub(crate) const CACHED_OBJECTS: usize = 16;
+
+impl Stash {
+    /// Keep backups, restores and readers of this stash within about
+    /// `limit` bytes of memory, or don't limit them with `None`.
+    ///
+    /// The indexes have to be in memory, so what they take is
+    /// estimated first, and the rest is for buffers. Backups run on
+    /// fewer worker and upload threads, restores on fewer threads, and
+    /// readers cache fewer objects until they fit, but never on fewer
+    /// than one. If the indexes alone take more than the limit, a
+    /// warning is logged, and everything runs on one thread.
+    pub fn set_memory_limit(&mut self, limit: Option<u64>) {
+        self.memory_limit = limit;
+    }
+
+    /// About how many bytes of memory the loaded indexes take.
+    pub fn index_memory(&self) -> u64 {
+        let chunks = self.chunks.index().len() as u64;
+        let files = self.files.index().len() as u64;
+        let references = self
+            .files
+            .index()
+            .iter()
+            .map(|f| f.key().chunks.len() as u64)
+            .sum::<u64>();
+        chunks * CHUNK_ENTRY + files * FILE_ENTRY + references * FILE_CHUNK
+    }
+
+    /// What a backup worker holds: the object it fills, and a small
+    /// file.
+    fn backup_worker(&self) -> u64 {
+        self.object_size as u64 + SMALL_FILE
+    }
+
+    /// What a restore worker holds: the object it reads, and the
+    /// chunk it decrypts.
+    #[cfg(feature = "fs")]
+    fn restore_worker(&self) -> u64 {
+        self.object_size as u64 + crate::BLOCK_SIZE as u64
+    }
+
+    /// Memory left for buffers within the limit, if there's one.
+    fn free_memory(&self) -> Option<u64> {
+        let limit = self.memory_limit?;
+        let index = self.index_memory();
+        if index > limit {
+            warn!(
+                "the indexes take about {} bytes, more than the memory limit of {}",
+                index, limit
+            );
+        }
+        Some(limit.saturating_sub(index))
+    }
+
+    /// The worker and upload threads of a backup that fit within the
+    /// memory limit, out of `threads` and the uploads set.
+    pub(crate) fn backup_threads(&self, threads: usize) -> (usize, usize) {
+        let free = match self.free_memory() {
+            Some(free) => free,
+            None => return (threads, self.uploads),
+        };
+        let object = self.object_size as u64;
+        let workers = threads.min((free / self.backup_worker()) as usize).max(1);
+        let left = free.saturating_sub(workers as u64 * self.backup_worker());
+        let uploads = self.uploads.min((left / object) as usize);
+        if (workers, uploads) != (threads, self.uploads) {
+            debug!(
+                "backing up on {} workers and {} upload threads to fit the memory limit",
+                workers, uploads
+            );
+        }
+        (workers, uploads)
+    }
+
+    /// The restore workers and download threads that fit within the
+    /// memory limit, out of `threads` and the ones set.
+    #[cfg(feature = "fs")]
+    pub(crate) fn restore_threads(&self, threads: usize) -> (usize, usize) {
+        let free = match self.free_memory() {
+            Some(free) => free,
+            None => return (threads, self.downloads),
+        };
+        let workers = threads.min((free / self.restore_worker()) as usize).max(1);
+        let left = free.saturating_sub(workers as u64 * self.restore_worker());
+        let downloads = self
+            .downloads
+            .min((left / self.object_size as u64) as usize);
+        (workers, downloads)
+    }
+
+    /// The objects a reader of files caches within the memory limit.
+    pub(crate) fn cached_objects(&self) -> usize {
+        match self.free_memory() {
+            Some(free) => CACHED_OBJECTS
+                .min((free / self.object_size as u64) as usize)
+                .max(1),
+            None => CACHED_OBJECTS,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn memory_limits_reduce_concurrency() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use crate::files::Entry;
+        use crate::snapshots::Labels;
+        use crate::stash::StashKey;
+        use std::sync::Arc;
+
+        let key = StashKey::open_stash("memory", "test").unwrap();
+        let mut stash = Stash::new(Arc::new(MemoryBackend::default()), key);
+        stash.set_uploads(4);
+        assert_eq!(stash.backup_threads(8), (8, 4));
+        assert_eq!(stash.cached_objects(), CACHED_OBJECTS);
+
+        let mut ingest = stash.ingest().unwrap();
+        ingest
+            .add_file(Entry::from_stream("file"), &[7; 1000])
+            .unwrap();
+        ingest.finish(0, vec![], vec![], Labels::new()).unwrap();
+        let index = stash.index_memory();
+        assert!(index > 0);
+
+        // room for the indexes, two workers and two uploads, or
+        // three workers
+        let block = crate::BLOCK_SIZE as u64;
+        let worker = stash.backup_worker();
+        stash.set_memory_limit(Some(index + 2 * worker + 2 * block));
+        assert_eq!(stash.backup_threads(2), (2, 2));
+        assert_eq!(stash.backup_threads(8), (3, 0));
+        #[cfg(feature = "fs")]
+        assert_eq!(stash.restore_threads(8), (2, 0));
+        assert_eq!(stash.cached_objects(), 4);
+
+        // larger objects take more of it
+        stash.set_object_size(16 * block as usize).unwrap();
+        assert_eq!(stash.backup_threads(8), (1, 0));
+        assert_eq!(stash.cached_objects(), 1);
+
+        // too little for anything still runs
+        stash.set_memory_limit(Some(1));
+        assert_eq!(stash.backup_threads(8), (1, 0));
+        #[cfg(feature = "fs")]
+        assert_eq!(stash.restore_threads(8), (1, 0));
+        assert_eq!(stash.cached_objects(), 1);
+    }
+}
@@ -1,113 +1,1481 @@
-use crate::{backends::Backend, chunks, files, meta, objects};
-pub use crate::{crypto::StashKey, meta::ObjectIndex};
+#[cfg(feature = "async")]
+use crate::backends::AsyncBackend;
+use crate::backends::{Backend, BackendError, Retrieval, Throttle, ThrottledBackend};
+use crate::crypto;
+use crate::progress::{self, Phase, Progress};
+#[cfg(feature = "fs")]
+use crate::stats;
+use crate::{cache, chunks, compress, files, format, meta, objects, snapshots};
+pub use crate::{
+    cancel::CancelToken,
+    compress::Compression,
+    crypto::{Cipher, ConvergenceSecret, Kdf, PublicKey, StashKey},
+    error::{Result, ZerostashError},
+    meta::{Field, ObjectIndex},
+    namespaces::Writer,
+    snapshots::{Labels, Snapshot},
+    splitter::{ChunkSizes, Chunker, Chunking, ChunkingRules},
+    stats::Summary,
+};
+#[cfg(feature = "fs")]
+pub use analyze::Analysis;
+pub use builder::StashBuilder;
+pub use collisions::Collisions;
+pub use compact::Compacted;
+pub use diff::{diff, Change, Diff};
+#[cfg(feature = "fs")]
+pub use dry_run::DryRun;
+pub use find::{Found, Query};
+pub use gc::{Collected, GcOptions};
+pub use ingest::Ingest;
+pub use lock::{Holder, LockKind};
+pub use orphans::Orphans;
+pub use parity::{Group, Repaired};
+pub use prune::{Pruned, Retention};
+#[cfg(any(feature = "gateway", feature = "fuse"))]
+pub(crate) use reader::ChunkReader;
+pub use reader::FileReader;
+pub use remap::Remap;
+pub use repack::{RepackOptions, Repacked};
+pub use schedule::Schedule;
+#[cfg(feature = "fs")]
+pub use status::status;
+pub use stream::{Recv, Stream};
+pub use symlinks::Symlinks;
+pub use sync::{sync, Synced};
+pub use usage::{SnapshotUsage, Usage};
+pub use verify::{Verified, VerifyLevel, VerifyOptions};
+#[cfg(feature = "fs")]
+pub use watch::{Trigger, WatchOptions};
 
-use std::path::Path;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
+#[cfg(feature = "fs")]
+use std::time::Instant;
 
+#[cfg(feature = "fs")]
+mod analyze;
+mod append;
+mod builder;
+mod bundle;
+mod collisions;
+mod compact;
+mod delta;
+mod diff;
+#[cfg(feature = "fs")]
+mod dry_run;
+mod dump;
+mod family;
+mod find;
+mod gc;
+mod grant;
+mod immutable;
+mod ingest;
+mod keys;
+mod local_index;
+mod lock;
+mod manifest;
+mod memory;
+mod orphans;
+mod parity;
+mod prune;
+mod quota;
+mod reader;
+mod remap;
+mod repack;
+#[cfg(feature = "fs")]
 pub(crate) mod restore;
+mod schedule;
+mod signature;
+#[cfg(feature = "fs")]
+mod status;
+#[cfg(feature = "fs")]
 pub(crate) mod store;
+mod stream;
+mod symlinks;
+mod sync;
+mod usage;
+mod verify;
+#[cfg(feature = "fs")]
+mod watch;
+mod zip;
 
-pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+pub type FileIterator<'a> = Box<dyn Iterator<Item = Arc<files::Entry>> + 'a>;
+
+#[derive(Clone, Debug, Default)]
+pub struct BackupOptions {
+    /// Number of worker threads, instead of the stash default
+    pub threads: Option<usize>,
+    /// Stop after the files currently being stored
+    pub cancel: CancelToken,
+    /// Sign the snapshot as a writer in a namespace
+    pub writer: Option<Writer>,
+    /// Split files matching any of these glob patterns in fixed-size
+    /// blocks, like databases and disk images. `*` does so for the
+    /// whole backup
+    pub fixed_size: Vec<String>,
+    /// The size of fixed blocks, instead of the target size of the
+    /// stash
+    pub block_size: Option<usize>,
+    /// Which symbolic links to store what they point to of, instead
+    /// of their targets
+    pub symlinks: Symlinks,
+    /// Rules of which files to walk, like `- target/` or `+ *.rs`,
+    /// where the first one that matches applies. See `files::Filter`
+    pub filter: Vec<String>,
+    /// Names of ignore files, like `.gitignore`, whose patterns leave
+    /// out files in their directory and below
+    pub ignore_files: Vec<String>,
+    /// Leave out cache directories tagged with `CACHEDIR.TAG`
+    pub exclude_caches: bool,
+    /// Don't enter other file systems mounted below the paths, like
+    /// `/proc` or network shares
+    pub one_file_system: bool,
+    /// Tags of the snapshot, like `pre-upgrade`
+    pub tags: Vec<String>,
+    /// Key/value labels of the snapshot, like the host or the ticket
+    /// it was taken for
+    pub labels: Labels,
+}
+
+impl BackupOptions {
+    #[cfg(feature = "fs")]
+    fn filter(&self) -> Result<files::Filter> {
+        Ok(files::Filter::new(&self.filter)?
+            .ignore_files(&self.ignore_files)
+            .exclude_caches(self.exclude_caches)
+            .one_file_system(self.one_file_system))
+    }
+}
+
+/// What a commit wrote.
+#[derive(Clone, Debug)]
+pub struct Committed {
+    /// The metadata objects of the commit
+    pub objects: ObjectIndex,
+    /// How the files stored since the last commit deduplicated
+    pub stats: chunks::Stats,
+}
+
+/// How far objects in archival storage are in being retrieved.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Retrieved {
+    pub ready: usize,
+    pub pending: usize,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct RestoreOptions {
+    /// Number of worker threads, instead of the stash default
+    pub threads: Option<usize>,
+    /// Only restore files matching any of these glob patterns
+    pub patterns: Vec<String>,
+    /// Stop after the files currently being restored
+    pub cancel: CancelToken,
+    /// Restore the owners of files too, which only root can
+    pub owners: bool,
+    /// Extended attributes not to restore, by namespace like
+    /// `security`, or by name like `security.selinux`
+    pub skip_xattrs: Vec<String>,
+    /// Rewrite the paths of files by the first rule that matches,
+    /// before restoring them below the target
+    pub remap: Vec<Remap>,
+    /// What to do with paths that name the same file in the target
+    pub collisions: Collisions,
+}
 
 pub struct Stash {
     backend: Arc<dyn Backend>,
     chunks: chunks::ChunkStore,
     files: files::FileStore,
+    snapshots: snapshots::SnapshotStore,
+    /// Fields of newer builds, kept to be written back
+    unknown: HashMap<meta::Field, meta::UnknownField>,
+    file_cache: Option<cache::FileCache>,
+    /// Where the chunk index is kept on disk, see `use_index_cache`
+    index_cache: Option<PathBuf>,
+    /// Where the files of all snapshots are indexed for `find`, see
+    /// `use_find_index`
+    find_index: Option<PathBuf>,
+    /// Identifies the commit that was read or written last
+    commit_digest: Option<crypto::CryptoDigest>,
+    checkpoints: Option<Duration>,
+    schedule: Schedule,
+    chunking: Chunking,
+    /// The size of new data objects
+    object_size: usize,
+    compression: Compression,
+    compression_rules: Vec<compress::CompressionRule>,
+    /// Data objects waiting to be collected
+    garbage: Vec<format::Garbage>,
+    /// Data objects of the family that files refer to
+    shared: HashSet<objects::ObjectId>,
+    /// Chunks of the family put in the index by `share_chunks`
+    borrowed: HashSet<crypto::CryptoDigest>,
+    /// If the rules are set, instead of read from the stash
+    rules_set: bool,
+    /// Writes the parity of new data objects, wrapping `backend`
+    parity: Option<Arc<parity::ParityBackend>>,
+    /// If the parity is set, instead of read from the stash
+    parity_set: bool,
+    groups: parity::ParityStore,
+    tuning: compress::Tuning,
+    /// The dictionary of the metadata, and if one is trained for it
+    dictionary: Option<(meta::DictionaryRef, Arc<compress::Dictionary>)>,
+    train_dictionary: bool,
+    threads: usize,
+    parallel_chunking: bool,
+    uploads: usize,
+    /// Uploads the data objects of backups, with the runtime to run
+    /// them on, see `set_async_backend`
+    #[cfg(feature = "async")]
+    async_backend: Option<(Arc<dyn AsyncBackend>, tokio::runtime::Handle)>,
+    downloads: usize,
+    memory_limit: Option<u64>,
+    /// The most bytes the chunks may take up as they're stored
+    quota: Option<u64>,
+    /// If the quota is set, instead of read from the stash
+    quota_set: bool,
+    /// Bytes of the chunks as they're stored, as of the last commit
+    stored: u64,
+    /// Bumped whenever data objects are rewritten or go away, see
+    /// `new_epoch`
+    epoch: u64,
     master_key: StashKey,
+    /// The key object of the credentials the stash was opened with
+    key_object: Option<objects::ObjectId>,
+    new_credentials: Option<keys::NewCredentials>,
+    /// The stash an append-only writer seals its key for
+    recipient: Option<crypto::PublicKey>,
+    progress: Arc<dyn Progress>,
+    /// What the backend reports its errors to
+    reports: progress::Shared,
+    layout: meta::Layout,
+    /// The most commits in a row that only write what changed
+    delta_commits: u32,
+    /// How many commits in a row did up to the one read or written
+    deltas: u32,
+    base: delta::Base,
+    /// The oldest layout of the metadata objects that were read
+    meta_version: u32,
+    generation: u64,
+    min_generation: u64,
+    digests: HashMap<objects::ObjectId, crypto::CryptoDigest>,
+    loaded: HashSet<meta::Field>,
+    throttle: Throttle,
+    /// The lock this process holds
+    lock: Option<lock::Holder>,
+    /// Keeps `lock` from getting stale
+    keeper: Option<lock::Keeper>,
+    stale_locks: Duration,
+    /// If objects are never deleted or overwritten
+    immutable: bool,
+    /// If the stash was opened through a grant, with only what it
+    /// gives access to
+    granted: bool,
 }
 
 impl Stash {
+    /// A stash in `backend` encrypted with `master_key`, as it is.
+    /// Use `Stash::open` to find the master key through a key object.
     pub fn new(backend: Arc<dyn Backend>, master_key: StashKey) -> Stash {
         let chunks = chunks::ChunkStore::default();
         let files = files::FileStore::default();
+        let throttle = Throttle::new();
+        let reports = progress::Shared::new();
 
         Stash {
-            backend,
+            backend: Arc::new(
+                ThrottledBackend::new(backend, throttle.clone())
+                    .report_to(Arc::new(reports.clone())),
+            ),
             chunks,
             files,
+            snapshots: snapshots::SnapshotStore::default(),
+            unknown: HashMap::new(),
+            file_cache: None,
+            index_cache: None,
+            find_index: None,
+            commit_digest: None,
+            checkpoints: None,
+            schedule: Schedule::default(),
+            chunking: Chunking::default(),
+            object_size: crate::BLOCK_SIZE,
+            compression: Compression::default(),
+            compression_rules: vec![],
+            garbage: vec![],
+            shared: HashSet::new(),
+            borrowed: HashSet::new(),
+            rules_set: false,
+            parity: None,
+            parity_set: false,
+            groups: parity::ParityStore::default(),
+            tuning: compress::Tuning::default(),
+            dictionary: None,
+            train_dictionary: false,
+            threads: std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(1),
+            parallel_chunking: false,
+            uploads: 0,
+            #[cfg(feature = "async")]
+            async_backend: None,
+            downloads: 0,
+            memory_limit: None,
+            quota: None,
+            quota_set: false,
+            stored: 0,
+            epoch: 0,
             master_key,
+            key_object: None,
+            new_credentials: None,
+            recipient: None,
+            progress: Arc::new(()),
+            reports,
+            layout: vec![],
+            delta_commits: 0,
+            deltas: 0,
+            base: delta::Base::default(),
+            meta_version: meta::META_VERSION,
+            generation: 0,
+            min_generation: 0,
+            digests: HashMap::new(),
+            loaded: HashSet::new(),
+            throttle,
+            lock: None,
+            keeper: None,
+            stale_locks: lock::STALE_LOCKS,
+            immutable: false,
+            granted: false,
+        }
+    }
+
+    /// Use a local cache at `path` to skip reading unchanged files.
+    ///
+    /// The cache is only updated on disk after a successful commit,
+    /// and at checkpoints.
+    pub fn use_file_cache(&mut self, path: impl AsRef<Path>) -> Result<()> {
+        let stash_id = crypto::chunk_hash(self.master_key.root_object_id()?.as_ref());
+        self.file_cache = Some(cache::FileCache::open(path, stash_id)?);
+
+        Ok(())
+    }
+
+    /// Save the file cache this often while files are stored, so a
+    /// backup that's interrupted resumes after the files it stored,
+    /// without reading or uploading them again.
+    ///
+    /// Only files whose objects are written are saved, and the
+    /// metadata of the stash isn't touched before the commit, so an
+    /// interrupted backup leaves nothing half written behind. Needs
+    /// a file cache.
+    pub fn set_checkpoints(&mut self, every: Option<Duration>) {
+        self.checkpoints = every;
+    }
+
+    /// Set the order in which files are processed by `add_recursive`.
+    pub fn set_schedule(&mut self, schedule: Schedule) {
+        self.schedule = schedule;
+    }
+
+    /// Set the default number of worker threads.
+    pub fn set_threads(&mut self, threads: usize) {
+        self.threads = threads.max(1);
+    }
+
+    /// The bandwidth limits of the backend, which apply to running
+    /// backups and restores as soon as they're changed.
+    pub fn throttle(&self) -> &Throttle {
+        &self.throttle
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    /// Chunk large files in segments on all worker threads, like
+    /// disk images that would otherwise keep a single thread busy, and
+    /// compress the new chunks of files and streams on all of them.
+    pub fn set_parallel_chunking(&mut self, parallel: bool) {
+        self.parallel_chunking = parallel;
+    }
+
+    /// Upload full objects on `threads` threads of their own, while
+    /// the workers go on chunking, compressing and encrypting into the
+    /// next ones. Each upload thread holds one object in flight, and
+    /// sealing another waits until one is free, so a backup takes at
+    /// most `threads` more objects of memory than without. With 0,
+    /// the default, each worker uploads its objects itself.
+    pub fn set_uploads(&mut self, threads: usize) {
+        self.uploads = threads;
+    }
+
+    /// Upload the data objects of backups through `backend`, as tasks
+    /// on `runtime`, keeping as many in flight as `set_uploads` asks
+    /// for without a thread for each. `backend` has to store objects
+    /// where the backend of the stash does, which everything else
+    /// still goes through.
+    ///
+    /// Stashes with parity, or set immutable, upload on threads
+    /// instead, as their backend has to see every data object.
+    #[cfg(feature = "async")]
+    pub fn set_async_backend(
+        &mut self,
+        backend: Arc<dyn AsyncBackend>,
+        runtime: tokio::runtime::Handle,
+    ) {
+        let backend = ThrottledBackend::new(backend, self.throttle.clone())
+            .report_to(Arc::new(self.reports.clone()));
+        self.async_backend = Some((Arc::new(backend), runtime));
+    }
+
+    /// Upload the objects `storage` fills on `uploads` threads, or as
+    /// that many tasks of the async backend.
+    pub(crate) fn upload_with<C: crypto::CryptoProvider>(
+        &self,
+        storage: objects::Storage<C>,
+        uploads: usize,
+    ) -> objects::Storage<C> {
+        #[cfg(feature = "async")]
+        if let Some((backend, runtime)) = &self.async_backend {
+            if uploads > 0 && self.parity.is_none() && !self.immutable {
+                return storage.async_uploads(backend.clone(), runtime.clone(), uploads);
+            }
+        }
+        storage.uploads(uploads)
+    }
+
+    /// Read objects on `threads` threads of their own when restoring,
+    /// while the workers decrypt and write the ones read before, so
+    /// restores from remotes with a high latency keep this many
+    /// requests in flight. Each holds one object in memory. With 0,
+    /// the default, each worker reads its objects itself.
+    pub fn set_downloads(&mut self, threads: usize) {
+        self.downloads = threads;
+    }
+
+    /// Encrypt a new stash with `cipher`, instead of the fastest one
+    /// on this machine. Reading a stash switches to the cipher it was
+    /// created with.
+    pub fn set_cipher(&mut self, cipher: Cipher) {
+        self.master_key.set_cipher(cipher);
+    }
+
+    pub fn cipher(&self) -> Cipher {
+        self.master_key.cipher()
+    }
+
+    /// Derive the chunk keys of a new stash from `convergence`, so it
+    /// can share chunks with other stashes of the same family.
+    ///
+    /// This is off by default, because anyone holding the secret can
+    /// tell whether the stash has a chunk of content they can guess.
+    /// The secret is recorded in the stash, and reading it fails if it
+    /// was created with another one, or without.
+    pub fn set_convergence(&mut self, convergence: ConvergenceSecret) {
+        self.master_key.set_convergence(Some(convergence));
+    }
+
+    pub fn convergence(&self) -> Option<&ConvergenceSecret> {
+        self.master_key.convergence()
+    }
+
+    /// Compress the chunks of a new stash with `compression`, instead
+    /// of LZ4. Reading a stash switches to the compression it was
+    /// created with.
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.compression = compression;
+    }
+
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    /// Compress the files matching one of `rules` with the
+    /// compression of the first that matches, instead of that of the
+    /// stash. The rules are recorded in the stash at the next commit,
+    /// and read with it unless they are set.
+    pub fn set_compression_rules(&mut self, rules: Vec<compress::CompressionRule>) -> Result<()> {
+        compress::CompressionRules::new(self.compression, &rules)
+            .map_err(ZerostashError::Config)?;
+        self.compression_rules = rules;
+        self.rules_set = true;
+        Ok(())
+    }
+
+    pub fn compression_rules(&self) -> &[compress::CompressionRule] {
+        &self.compression_rules
+    }
+
+    /// Compress at the levels and block size of `tuning`. Unlike the
+    /// compression, this isn't recorded, and can change between runs.
+    pub fn set_tuning(&mut self, tuning: compress::Tuning) -> Result<()> {
+        tuning.check().map_err(ZerostashError::Config)?;
+        self.tuning = tuning;
+        Ok(())
+    }
+
+    pub fn tuning(&self) -> compress::Tuning {
+        self.tuning
+    }
+
+    /// Compress the metadata in zstd, with a dictionary trained on a
+    /// sample of its records at the next commit. The dictionary is
+    /// recorded in the stash, and kept from then on.
+    pub fn set_metadata_dictionary(&mut self, train: bool) -> Result<()> {
+        if train && !cfg!(feature = "zstd") {
+            return Err(ZerostashError::Config(
+                "metadata dictionaries need the zstd feature".into(),
+            ));
         }
+        self.train_dictionary = train;
+        Ok(())
+    }
+
+    pub fn metadata_dictionary(&self) -> bool {
+        self.dictionary.is_some()
+    }
+
+    /// Split the files of a new stash with `chunker`, at its default
+    /// sizes. Reading a stash switches to the chunker and sizes it was
+    /// created with, so its chunks keep deduplicating.
+    pub fn set_chunker(&mut self, chunker: Chunker) {
+        self.chunking = Chunking::new(chunker);
+    }
+
+    pub fn chunker(&self) -> Chunker {
+        self.chunking.chunker
+    }
+
+    /// Cut the chunks of a new stash at `sizes`, instead of the
+    /// defaults of the chunker.
+    pub fn set_chunk_sizes(&mut self, sizes: ChunkSizes) -> Result<()> {
+        sizes.check().map_err(format::FormatError::ChunkSizes)?;
+        self.chunking.sizes = sizes;
+        Ok(())
+    }
+
+    pub fn chunk_sizes(&self) -> ChunkSizes {
+        self.chunking.sizes
+    }
+
+    /// Store the data of a new stash in objects of `size` bytes,
+    /// instead of `BLOCK_SIZE`, a power of two from 1 to 64 MiB.
+    /// Larger objects take fewer requests to backends of high
+    /// latency, smaller ones less memory and space for small stashes.
+    /// Reading a stash switches to the size it was created with.
+    ///
+    /// Chunks have to fit in half an object, so small objects need
+    /// small enough chunk sizes.
+    pub fn set_object_size(&mut self, size: usize) -> Result<()> {
+        format::check_object_size(size)?;
+        self.object_size = size;
+        Ok(())
+    }
+
+    pub fn object_size(&self) -> usize {
+        self.object_size
     }
 
+    /// Check that chunks of up to `max` bytes fit in an object.
+    fn check_fit(&self, max: usize) -> Result<()> {
+        if max > self.object_size / 2 {
+            return Err(format::FormatError::ChunkSizes(format!(
+                "chunks of up to {} bytes don't fit in objects of {}",
+                max, self.object_size
+            ))
+            .into());
+        }
+        Ok(())
+    }
+
+    /// The chunking of the stash to split files with, with the
+    /// buzhash table of its key, and its threads.
+    fn run_chunking(&self) -> Result<Chunking> {
+        self.check_fit(self.chunking.sizes.max)?;
+        let mut chunking = self.chunking.clone();
+        if chunking.chunker == Chunker::Buzhash {
+            chunking.table = Some(Arc::new(self.master_key.buzhash_table()?));
+        }
+        if self.parallel_chunking {
+            chunking.threads = self.threads;
+        }
+        Ok(chunking)
+    }
+
+    /// The compression of each file stored by this run.
+    fn run_compression(&self) -> Result<compress::CompressionRules> {
+        compress::CompressionRules::new(self.compression, &self.compression_rules)
+            .map_err(ZerostashError::Config)
+    }
+
+    /// Report the progress of all further operations to `progress`.
+    pub fn set_progress(&mut self, progress: Arc<dyn Progress>) {
+        self.reports.set(progress.clone());
+        self.progress = progress;
+    }
+
+    /// Read all metadata of the stash into memory.
     pub fn read(&mut self) -> Result<&Self> {
+        self.read_fields(&[
+            meta::Field::Files,
+            meta::Field::Chunks,
+            meta::Field::Snapshots,
+        ])
+    }
+
+    /// Read the metadata of the stash, but only load `fields` into
+    /// memory.
+    ///
+    /// The rest is loaded when an operation first needs it, so listing
+    /// files doesn't have to pay for loading the chunk index.
+    pub fn read_fields(&mut self, fields: &[meta::Field]) -> Result<&Self> {
+        // all a grant gives access to is read when it's opened
+        if self.granted {
+            return Ok(self);
+        }
+        let root = self.master_key.root_object_id()?;
+        debug!("reading metadata fields {:?}", fields);
+        // the local index is only known to be of the commit once all
+        // of it is read
+        let local_index = self.index_cache.is_some() && fields.contains(&meta::Field::Chunks);
+        let fields = fields
+            .iter()
+            .filter(|f| !local_index || **f != meta::Field::Chunks)
+            .cloned()
+            .collect::<Vec<_>>();
+        let fields = &fields[..];
+        self.progress.phase(Phase::ReadMetadata, None);
+
+        self.layout.clear();
+        self.loaded.clear();
+        self.unknown.clear();
+        self.borrowed.clear();
+        self.groups.clear();
+
+        let (mut metareader, root_header) = self.open_root(&root)?;
+        let mut next_object = Some((root, root_header, metareader.digest(), None));
+        let mut generation = 0;
+        let mut chunking = Chunking::default();
+        let mut object_size = crate::BLOCK_SIZE;
+        let mut compression = Compression::default();
+        let mut compression_rules = vec![];
+        let mut garbage = vec![];
+        let mut shared = vec![];
+        let mut parity = None;
+        let mut quota = None;
+        let mut stored = 0;
+        let mut epoch = 0;
+        let mut dictionary = None;
+        let mut signed = None;
+        let mut digests = vec![];
+        let mut meta_version = meta::META_VERSION;
+        let mut deltas = 0;
+        let mut known = None;
+        while let Some((id, header, digest, listed)) = next_object {
+            let error = |e| ZerostashError::reading(id, id == root, e);
+
+            // objects reused from earlier commits are only read for
+            // the fields the layout lists
+            let present = listed.unwrap_or_else(|| header.fields());
+            trace!("metadata object {} holds {:?}", id.to_string(), present);
+            if id == root && present.contains(&meta::Field::Format) {
+                let mut recorded = format::FormatField::default();
+                metareader
+                    .read_into(meta::Field::Format, &mut recorded)
+                    .map_err(error)?;
+                if let Some(format) = recorded.get() {
+                    format.check()?;
+                    if format.cipher()? != self.master_key.cipher() {
+                        return Err(format::FormatError::Cipher(format.cipher).into());
+                    }
+                    if let Some(family) = self.master_key.convergence() {
+                        if format.convergence.as_ref() != Some(family) {
+                            return Err(format::FormatError::Family(family.fingerprint()).into());
+                        }
+                    }
+                    chunking = format.chunking()?;
+                    object_size = format.object_size;
+                    compression = format.compression()?;
+                    compression_rules = format.compression_rules;
+                    garbage = format.garbage;
+                    shared = format.shared;
+                    parity = format.parity;
+                    quota = format.quota;
+                    stored = format.stored_bytes;
+                    epoch = format.epoch;
+                    dictionary = format.dictionary;
+                    deltas = format.deltas;
+                    self.master_key.set_convergence(format.convergence);
+                    generation = format.generation;
+                }
+            }
+            if id == root {
+                signed = self.signed_objects(generation)?;
+                known = match (&signed, header.layout_at(), header.layout()) {
+                    (Some(signed), Some(at), _) => {
+                        let layout = metareader.inline_layout(at).map_err(error)?;
+                        Some(signed_layout(layout, signed, generation)?)
+                    }
+                    (Some(signed), None, Some(layout)) => {
+                        self.stored_layout(&metareader, layout, signed, generation)?
+                    }
+                    _ => None,
+                }
+                .map(|layout| layout.into_iter().skip(1));
+                // objects of the commits before aren't on the chain
+                if deltas > 0 && known.is_none() {
+                    return Err(error(meta::ReadError::NoLayout));
+                }
+            }
+
+            // nothing is decoded from an object that wasn't signed
+            if let Some(signed) = &signed {
+                if signed.get(digests.len()) != Some(&(id, digest)) {
+                    return Err(ZerostashError::Tampered(format!(
+                        "object {} isn't part of commit {}",
+                        id.to_string(),
+                        generation
+                    )));
+                }
+            }
+            digests.push((id, digest));
+
+            for field in present.iter().filter(|f| delta::needs(fields, f)) {
+                read_field(
+                    &mut metareader,
+                    field,
+                    fields,
+                    &mut self.chunks,
+                    &mut self.files,
+                    &mut self.snapshots,
+                    &mut self.groups,
+                    &mut self.unknown,
+                )
+                .map_err(error)?;
+            }
+
+            self.layout.push((id, present));
+            meta_version = meta_version.min(header.version());
+
+            // with the layout, objects without any of `fields` are
+            // only recorded with the hash they were signed with, and
+            // checked when they are loaded
+            let next = match (known.as_mut(), &signed) {
+                (Some(known), Some(signed)) => {
+                    let mut next = None;
+                    for (skipped, present) in known.by_ref() {
+                        if present.iter().any(|f| delta::needs(fields, f)) {
+                            next = Some((skipped, Some(present)));
+                            break;
+                        }
+                        digests.push(signed[digests.len()]);
+                        self.layout.push((skipped, present));
+                    }
+                    next
+                }
+                _ => header.next_object().map(|next| (next, None)),
+            };
+            next_object = match next {
+                Some((next, listed)) => {
+                    let header = metareader
+                        .open(&next)
+                        .map_err(|e| ZerostashError::reading(next, false, e))?;
+                    Some((next, header, metareader.digest(), listed))
+                }
+                None => None,
+            };
+        }
+
+        if signed.is_some_and(|signed| signed.len() != digests.len()) {
+            return Err(ZerostashError::Tampered(format!(
+                "commit {} is missing objects",
+                generation
+            )));
+        }
+        self.dictionary = match dictionary {
+            Some(reference) => Some(
+                metareader
+                    .load_dictionary(reference)
+                    .map_err(|e| ZerostashError::reading(reference.object, false, e))?,
+            ),
+            None => None,
+        };
+        self.generation = generation;
+        self.meta_version = meta_version;
+        self.deltas = deltas;
+        self.chunking = chunking;
+        self.object_size = object_size;
+        self.compression = compression;
+        if !self.rules_set {
+            self.compression_rules = compression_rules;
+        }
+        self.garbage = garbage;
+        self.shared = shared.into_iter().collect();
+        if !self.parity_set {
+            self.use_parity(parity)?;
+        }
+        if !self.quota_set {
+            self.quota = quota;
+        }
+        self.stored = stored;
+        self.epoch = epoch;
+        self.commit_digest = Some(local_index::commit_digest(&digests));
+        self.digests = digests.into_iter().collect();
+
+        self.loaded.extend(fields.iter().cloned());
+        for field in fields {
+            self.field_loaded(field);
+        }
+        self.chunks.set_quota(self.quota);
+        if local_index {
+            self.load(meta::Field::Chunks)?;
+        }
+        Ok(self)
+    }
+
+    /// The layout of commit `generation` stored in `id`, if it's
+    /// there, which has to list the objects that were `signed`.
+    fn stored_layout(
+        &self,
+        metareader: &meta::Reader<impl crypto::CryptoProvider>,
+        id: objects::ObjectId,
+        signed: &[(objects::ObjectId, crypto::CryptoDigest)],
+        generation: u64,
+    ) -> Result<Option<meta::Layout>> {
+        let layout = match metareader.read_layout(&id) {
+            Ok(layout) => layout,
+            Err(meta::ReadError::Backend {
+                source: BackendError::NoObjectFound,
+            }) => {
+                debug!("no layout stored, walking all metadata objects");
+                return Ok(None);
+            }
+            Err(e) => return Err(ZerostashError::reading(id, false, e)),
+        };
+
+        signed_layout(layout, signed, generation).map(Some)
+    }
+
+    /// Open the root object with the cipher the stash was created
+    /// with, which is only known by trying each.
+    fn open_root(
+        &mut self,
+        root: &objects::ObjectId,
+    ) -> Result<(
+        meta::Reader<impl crypto::CryptoProvider>,
+        meta::MetaObjectHeader,
+    )> {
+        let preferred = self.master_key.cipher();
+        let others = Cipher::ALL.iter().copied().filter(|c| *c != preferred);
+
+        let mut failed = None;
+        for cipher in std::iter::once(preferred).chain(others) {
+            self.master_key.set_cipher(cipher);
+            let mut reader =
+                meta::Reader::new(self.backend.clone(), self.master_key.get_meta_crypto()?);
+            match reader.open(root) {
+                Ok(header) => return Ok((reader, header)),
+                Err(e @ meta::ReadError::Crypto { .. }) => failed = Some(e),
+                Err(e) => {
+                    self.master_key.set_cipher(preferred);
+                    return Err(ZerostashError::reading(*root, true, e));
+                }
+            }
+        }
+
+        self.master_key.set_cipher(preferred);
+        Err(ZerostashError::reading(*root, true, failed.unwrap()))
+    }
+
+    /// Load `field` if it was skipped when reading the stash.
+    pub(crate) fn load(&mut self, field: meta::Field) -> Result<()> {
+        if self.loaded.contains(&field) {
+            return Ok(());
+        }
+
+        if field == meta::Field::Chunks && self.open_local_index()? {
+            self.chunks.set_quota(self.quota);
+            self.field_loaded(&field);
+            self.loaded.insert(field);
+            return Ok(());
+        }
+
+        debug!("loading metadata field {:?}", field);
+        self.progress.phase(Phase::ReadMetadata, None);
         let mut metareader =
             meta::Reader::new(self.backend.clone(), self.master_key.get_meta_crypto()?);
-        let mut next_object = Some(self.master_key.root_object_id()?);
+        if let Some((reference, dictionary)) = &self.dictionary {
+            metareader.use_dictionary(*reference, dictionary.clone());
+        }
 
-        while let Some(header) = match next_object {
-            Some(ref o) => Some(metareader.open(o)?),
-            None => None,
-        } {
-            next_object = header.next_object();
-            for field in header.fields().iter() {
-                use self::meta::Field::*;
-                match field {
-                    Chunks => metareader.read_into(field, &mut self.chunks)?,
-                    Files => metareader.read_into(field, &mut self.files)?,
-                };
+        let (chunks, files, snapshots, groups, unknown) = (
+            &mut self.chunks,
+            &mut self.files,
+            &mut self.snapshots,
+            &mut self.groups,
+            &mut self.unknown,
+        );
+        let fields = [field.clone()];
+        let needed = self
+            .layout
+            .iter()
+            .filter(|(_, present)| present.iter().any(|f| delta::needs(&fields, f)));
+        for (id, present) in needed {
+            metareader
+                .open(id)
+                .map_err(|e| ZerostashError::reading(*id, false, e))?;
+            // the object may have been swapped since the stash was read
+            if self.digests.get(id) != Some(&metareader.digest()) {
+                return Err(ZerostashError::Tampered(format!(
+                    "object {} changed since the stash was read",
+                    id.to_string()
+                )));
+            }
+            for present in present.iter().filter(|f| delta::needs(&fields, f)) {
+                read_field(
+                    &mut metareader,
+                    present,
+                    &fields,
+                    chunks,
+                    files,
+                    snapshots,
+                    groups,
+                    unknown,
+                )
+                .map_err(|e| ZerostashError::reading(*id, false, e))?;
             }
         }
 
-        Ok(self)
+        if field == meta::Field::Chunks {
+            self.save_local_index()?;
+            self.chunks.set_quota(self.quota);
+        }
+        self.field_loaded(&field);
+        self.loaded.insert(field);
+        Ok(())
     }
 
-    pub fn list<'a>(&'a self, glob: &'a [impl AsRef<str>]) -> restore::FileIterator<'a> {
+    pub fn list<'a>(&'a self, glob: &'a [impl AsRef<str>]) -> Result<FileIterator<'a>> {
         let matchers = glob
             .iter()
-            .map(|g| glob::Pattern::new(g.as_ref()).unwrap())
-            .collect::<Vec<glob::Pattern>>();
+            .map(|g| glob::Pattern::new(g.as_ref()))
+            .collect::<std::result::Result<Vec<glob::Pattern>, _>>()?;
         let base_iter = self.file_index().into_iter().map(|r| r.key().clone());
 
-        match glob.len() {
-            i if i == 0 => Box::new(base_iter),
+        Ok(match glob.len() {
+            0 => Box::new(base_iter),
             _ => Box::new(base_iter.filter(move |f| matchers.iter().any(|m| m.matches(&f.name)))),
-        }
+        })
     }
 
+    #[cfg(feature = "fs")]
     pub fn restore_by_glob(
         &mut self,
         threads: usize,
         pattern: &[impl AsRef<str>],
         target: impl AsRef<Path>,
-    ) -> Result<()> {
+    ) -> Result<Summary> {
+        let options = RestoreOptions {
+            threads: Some(threads),
+            patterns: pattern.iter().map(|p| p.as_ref().into()).collect(),
+            ..RestoreOptions::default()
+        };
+        self.checkout(target, &options)
+    }
+
+    /// Restore the files of the index that match the patterns of
+    /// `options` under `target`, of whichever snapshot they are.
+    #[cfg(feature = "fs")]
+    pub fn checkout(
+        &mut self,
+        target: impl AsRef<Path>,
+        options: &RestoreOptions,
+    ) -> Result<Summary> {
+        self.load(meta::Field::Files)?;
+
+        let files = self.list(&options.patterns)?.collect::<Vec<_>>();
+        self.restore_files(files, target, options)
+    }
+
+    /// Back up `paths`, and commit the result as a new snapshot.
+    ///
+    /// If cancelled, nothing is committed, and `Cancelled` is
+    /// returned. Chunks that were already stored stay in the chunk
+    /// index, so a later backup with this `Stash` won't upload them
+    /// again.
+    #[cfg(feature = "fs")]
+    pub fn backup(
+        &mut self,
+        paths: &[impl AsRef<Path>],
+        options: &BackupOptions,
+    ) -> Result<Arc<Snapshot>> {
+        if let Some(writer) = &options.writer {
+            writer.check_paths(paths)?;
+        }
+        let files = self.store_run(paths, options)?;
+
+        let paths = paths
+            .iter()
+            .map(|p| p.as_ref().to_string_lossy().into_owned())
+            .collect();
+        let snapshot = self.push_snapshot(paths, files, options)?;
+
+        self.commit()?;
+        Ok(snapshot)
+    }
+
+    /// Commit a new snapshot of the paths of `base`, which only
+    /// stores the `changed` paths again. Everything else is taken
+    /// from `base`, and changed paths that no longer exist are left
+    /// out.
+    #[cfg(feature = "fs")]
+    pub fn backup_changes(
+        &mut self,
+        base: &Snapshot,
+        changed: &[impl AsRef<Path>],
+        options: &BackupOptions,
+    ) -> Result<Arc<Snapshot>> {
+        if let Some(writer) = &options.writer {
+            writer.check_paths(&base.paths)?;
+        }
+        let existing = changed
+            .iter()
+            .filter(|p| p.as_ref().exists())
+            .collect::<Vec<_>>();
+        let mut files = self.store_run(&existing, options)?;
+
+        files.extend(
+            base.files
+                .iter()
+                .filter(|f| {
+                    !changed
+                        .iter()
+                        .any(|p| Path::new(&f.name).starts_with(p.as_ref()))
+                })
+                .cloned(),
+        );
+        let snapshot = self.push_snapshot(base.paths.clone(), files, options)?;
+
+        self.commit()?;
+        Ok(snapshot)
+    }
+
+    /// Record a new snapshot, signed if `options` has a writer.
+    #[cfg(feature = "fs")]
+    fn push_snapshot(
+        &mut self,
+        paths: Vec<String>,
+        files: Vec<Arc<files::Entry>>,
+        options: &BackupOptions,
+    ) -> Result<Arc<Snapshot>> {
+        let snapshot =
+            self.snapshots
+                .push(paths, options.tags.clone(), options.labels.clone(), files);
+        match &options.writer {
+            Some(writer) => Ok(self.snapshots.sign(snapshot.id, writer)?),
+            None => Ok(snapshot),
+        }
+    }
+
+    /// Store `paths`, and return the files stored in this run.
+    #[cfg(feature = "fs")]
+    fn store_run(
+        &mut self,
+        paths: &[impl AsRef<Path>],
+        options: &BackupOptions,
+    ) -> Result<Vec<Arc<files::Entry>>> {
+        self.load(meta::Field::Files)?;
+        self.load(meta::Field::Chunks)?;
+        self.load(meta::Field::Snapshots)?;
+
+        let threads = options.threads.unwrap_or(self.threads);
+        let rules = self.chunking_rules(options)?;
+
+        // collect the files of this run separately, so the snapshot
+        // knows exactly what it contains
+        let mut run = files::FileStore::default();
+        for path in paths.iter() {
+            self.store_path(threads, options, &rules, &mut run, path)?;
+        }
+
+        if options.cancel.is_cancelled() {
+            return Err(ZerostashError::Cancelled);
+        }
+
+        let files = run
+            .index()
+            .iter()
+            .map(|f| f.key().clone())
+            .collect::<Vec<_>>();
+        for f in files.iter() {
+            self.files.insert(f.clone());
+        }
+
+        Ok(files)
+    }
+
+    /// Ask the backend to bring the contents of the files matching
+    /// `pattern` back from archival storage, so they can be restored.
+    ///
+    /// Retrieval can take hours, calling this again tells how far it
+    /// got. Metadata is never archived, so this works right away.
+    pub fn retrieve_archived(&mut self, pattern: &[impl AsRef<str>]) -> Result<Retrieved> {
+        self.load(meta::Field::Files)?;
+
+        let objects = self
+            .list(pattern)?
+            .flat_map(|f| f.chunks.iter().map(|(_, cp)| cp.file).collect::<Vec<_>>())
+            .collect::<HashSet<_>>();
+
+        let mut retrieved = Retrieved::default();
+        for id in objects.iter() {
+            match self.backend.retrieve_object(id)? {
+                Retrieval::Ready => retrieved.ready += 1,
+                Retrieval::Pending => retrieved.pending += 1,
+            }
+        }
+        Ok(retrieved)
+    }
+
+    /// Restore the files of `snapshot` under `target`, with their
+    /// paths rewritten by `options.remap`.
+    ///
+    /// If cancelled, files that were already created are left in
+    /// place, and `Cancelled` is returned.
+    #[cfg(feature = "fs")]
+    pub fn restore(
+        &mut self,
+        snapshot: &Snapshot,
+        target: impl AsRef<Path>,
+        options: &RestoreOptions,
+    ) -> Result<Summary> {
+        let matchers = options
+            .patterns
+            .iter()
+            .map(|g| glob::Pattern::new(g))
+            .collect::<std::result::Result<Vec<glob::Pattern>, _>>()?;
+        let files = snapshot
+            .files
+            .iter()
+            .filter(move |f| matchers.is_empty() || matchers.iter().any(|m| m.matches(&f.name)))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        self.restore_files(files, target, options)
+    }
+
+    #[cfg(feature = "fs")]
+    fn restore_files(
+        &self,
+        files: Vec<Arc<files::Entry>>,
+        target: impl AsRef<Path>,
+        options: &RestoreOptions,
+    ) -> Result<Summary> {
+        let files = files
+            .iter()
+            .map(|f| remap::remapped(&options.remap, f))
+            .collect::<Vec<_>>();
+
+        let stats = stats::Collector::new(self.progress.clone());
+        let start = Instant::now();
+        self.progress
+            .phase(Phase::Restore, Some(files.len() as u64));
+
         restore::from_iter(
-            threads,
-            self.list(pattern),
+            self.restore_threads(options.threads.unwrap_or(self.threads)),
+            Box::new(files.into_iter()),
             self.backend.clone(),
             self.master_key.get_object_crypto()?,
+            &stats,
+            &options.cancel,
+            options.owners,
+            &options.skip_xattrs,
+            options.collisions,
             target,
-        );
+        )?;
 
-        Ok(())
+        if options.cancel.is_cancelled() {
+            return Err(ZerostashError::Cancelled);
+        }
+
+        Ok(stats.summary(start.elapsed()))
+    }
+
+    /// All snapshots of the stash, oldest first.
+    pub fn snapshots(&self) -> Vec<Arc<Snapshot>> {
+        self.snapshots.list()
+    }
+
+    /// The snapshots with all `tags` and `labels`, oldest first.
+    pub fn snapshots_matching(
+        &self,
+        tags: &[impl AsRef<str>],
+        labels: &Labels,
+    ) -> Vec<Arc<Snapshot>> {
+        self.snapshots
+            .list()
+            .into_iter()
+            .filter(|s| s.matches(tags, labels))
+            .collect()
     }
 
-    pub fn add_recursive(&mut self, threads: usize, path: impl AsRef<Path>) -> Result<()> {
-        let mut objstore =
-            objects::Storage::new(self.backend.clone(), self.master_key.get_object_crypto()?);
+    #[cfg(feature = "fs")]
+    pub fn add_recursive(&mut self, threads: usize, path: impl AsRef<Path>) -> Result<Summary> {
+        self.load(meta::Field::Files)?;
+        self.load(meta::Field::Chunks)?;
+
+        let mut files = self.files.clone();
+        let options = BackupOptions::default();
+        let rules = self.chunking_rules(&options)?;
+        self.store_path(threads, &options, &rules, &mut files, path)
+    }
+
+    /// How `backup` with `options` splits and compresses each file.
+    #[cfg(feature = "fs")]
+    fn chunking_rules(&self, options: &BackupOptions) -> Result<ChunkingRules> {
+        let mut rules = ChunkingRules::from(self.run_chunking()?);
+        rules.compression = self.run_compression()?;
+        rules.fixed = options
+            .fixed_size
+            .iter()
+            .map(|g| glob::Pattern::new(g))
+            .collect::<std::result::Result<Vec<glob::Pattern>, _>>()?;
+        if let Some(size) = options.block_size {
+            ChunkSizes::fixed(size)
+                .check()
+                .map_err(format::FormatError::ChunkSizes)?;
+            self.check_fit(size)?;
+            rules.block_size = size;
+        }
+
+        Ok(rules)
+    }
+
+    #[cfg(feature = "fs")]
+    fn store_path(
+        &mut self,
+        threads: usize,
+        options: &BackupOptions,
+        rules: &ChunkingRules,
+        files: &mut files::FileStore,
+        path: impl AsRef<Path>,
+    ) -> Result<Summary> {
+        let stats = Arc::new(stats::Collector::new(self.progress.clone()));
+        let start = Instant::now();
+        self.progress.phase(Phase::Store, None);
+
+        let (threads, uploads) = self.backup_threads(threads);
+        let objstore = objects::Storage::new(
+            self.backend.clone(),
+            self.master_key.get_object_crypto()?,
+            stats.clone(),
+        )
+        .object_size(self.object_size)
+        .compression(self.compression)
+        .tuning(self.tuning);
+        let mut objstore = self.upload_with(objstore, uploads);
+        if let Some(cache) = &mut self.file_cache {
+            cache.set_epoch(self.epoch);
+            cache.set_checkpoints(self.checkpoints);
+        }
 
         store::recursive(
             threads,
+            self.schedule,
+            options.symlinks,
+            &options.filter()?,
+            rules,
             &mut self.chunks,
-            &mut self.files,
+            files,
             &mut objstore,
+            self.file_cache.as_ref(),
+            &stats,
+            &options.cancel,
             path,
-        );
+        )?;
 
-        Ok(())
+        Ok(stats.summary(start.elapsed()))
+    }
+
+    pub fn commit(&mut self) -> Result<Committed> {
+        self.write_commit(true)
     }
 
-    pub fn commit(&mut self) -> Result<ObjectIndex> {
+    /// Commit, writing only what changed if `changes_only`, and the
+    /// stash is set to.
+    pub(super) fn write_commit(&mut self, changes_only: bool) -> Result<Committed> {
+        if self.granted {
+            return Err(ZerostashError::Granted("commit"));
+        }
+        self.check_lock()?;
+        // writing out a partially loaded index would lose data
+        self.load(meta::Field::Files)?;
+        self.load(meta::Field::Chunks)?;
+        self.load(meta::Field::Snapshots)?;
+        self.load(meta::Field::Parity)?;
+        let unknown = self
+            .layout
+            .iter()
+            .flat_map(|(_, fields)| fields.iter())
+            .filter(|f| matches!(f, meta::Field::Unknown(_)))
+            .cloned()
+            .collect::<HashSet<_>>();
+        for field in unknown {
+            self.load(field)?;
+        }
+        self.store_new_credentials()?;
+        self.finish_parity()?;
+        self.settle_borrowed();
+
         let mut mw = meta::Writer::new(
             self.master_key.root_object_id()?,
             self.backend.clone(),
             self.master_key.get_meta_crypto()?,
         )?;
+        mw.set_tuning(self.tuning);
+        if self.dictionary.is_none() && self.train_dictionary {
+            self.dictionary = self.new_dictionary(&mut mw)?;
+        }
+        if let Some((reference, dictionary)) = &self.dictionary {
+            mw.use_dictionary(*reference, dictionary.clone());
+        }
+        mw.hold_root();
+        let generation = self.next_generation()?;
+        let changes = if changes_only { self.changes()? } else { None };
+        let deltas = changes.as_ref().map_or(0, |_| self.deltas + 1);
 
-        mw.write_field(meta::Field::Files, &self.files);
-        mw.write_field(meta::Field::Chunks, &self.chunks);
-        mw.seal_and_store();
+        debug!(
+            "committing {} files and {} chunks",
+            self.files.index().len(),
+            self.chunks.index().len()
+        );
+        self.progress.phase(Phase::Commit, None);
+        let stored = self.chunks.index().stored_bytes();
+        // the format goes first, so it ends up in the root object
+        mw.write_field(
+            meta::Field::Format,
+            &format::FormatField::new(format::Format {
+                generation,
+                quota: self.quota,
+                stored_bytes: stored,
+                epoch: self.epoch,
+                ..format::Format::with_cipher(self.master_key.cipher())
+                    .with_chunking(&self.chunking)
+                    .with_object_size(self.object_size)
+                    .with_compression(self.compression)
+                    .with_compression_rules(&self.compression_rules)
+                    .with_garbage(&self.garbage)
+                    .with_shared(self.shared.iter().copied())
+                    .with_parity(self.parity())
+                    .with_dictionary(self.dictionary.as_ref().map(|(reference, _)| *reference))
+                    .convergent(self.master_key.convergence().cloned())
+                    .with_deltas(deltas)
+            }),
+        )?;
+        // alone, so the commits after can reuse the other objects
+        if self.delta_commits > 0 {
+            mw.start_object()?;
+        }
+        match changes {
+            Some(changes) => self.write_changes(&mut mw, changes)?,
+            None => {
+                mw.write_field(meta::Field::Files, &self.files)?;
+                mw.write_field(meta::Field::Chunks, &self.chunks)?;
+                mw.write_field(meta::Field::Snapshots, &self.snapshots)?;
+            }
+        }
+        if !self.groups.is_empty() {
+            mw.write_field(meta::Field::Parity, &self.groups)?;
+        }
+        for (field, records) in self.unknown.iter() {
+            mw.write_field(field.clone(), records)?;
+        }
+        mw.seal_and_store()?;
+        mw.seal_root()?;
+
+        // the root goes last, after the signature of what it refers to
+        self.store_signature(generation, mw.sealed())?;
+        mw.store_root()?;
+        self.generation = generation;
+        self.meta_version = meta::META_VERSION;
+        self.stored = stored;
+        self.deltas = deltas;
+        self.layout = mw.layout().clone();
+        self.digests = mw.sealed().iter().copied().collect();
+        self.commit_digest = Some(local_index::commit_digest(mw.sealed()));
+        self.save_local_index()?;
+        // what was collected since counts against the quota no more
+        self.chunks.set_quota(self.quota);
+        self.store_drop()?;
+        for field in [
+            meta::Field::Files,
+            meta::Field::Chunks,
+            meta::Field::Snapshots,
+        ]
+        .iter()
+        {
+            self.field_loaded(field);
+        }
+
+        if let Some(cache) = &self.file_cache {
+            cache.save()?;
+        }
+
+        Ok(Committed {
+            objects: mw.objects().clone(),
+            stats: self.chunks.take_stats(),
+        })
+    }
 
-        Ok(mw.objects().clone())
+    /// The oldest layout of the metadata of the stash, which is
+    /// `meta::META_VERSION` once it's migrated.
+    pub fn metadata_version(&self) -> u32 {
+        self.meta_version
+    }
+
+    /// Rewrite the metadata of the stash in the layout of this build,
+    /// if any of it is older. Returns if it was rewritten.
+    ///
+    /// Readers stay able to read older layouts, so this is only
+    /// needed to use what the newer one brings, or before the support
+    /// for an old one is dropped.
+    pub fn migrate(&mut self) -> Result<bool> {
+        if self.meta_version >= meta::META_VERSION {
+            return Ok(false);
+        }
+
+        debug!(
+            "migrating metadata from version {} to {}",
+            self.meta_version,
+            meta::META_VERSION
+        );
+        self.commit()?;
+        Ok(true)
+    }
+
+    /// Train a dictionary on a sample of the files and snapshots, and
+    /// store it with `mw`. With too small a sample, the metadata stays
+    /// in LZ4 until a later commit.
+    fn new_dictionary(
+        &self,
+        mw: &mut meta::Writer<impl crypto::CryptoProvider>,
+    ) -> Result<Option<(meta::DictionaryRef, Arc<compress::Dictionary>)>> {
+        let mut sampler = meta::Sampler::default();
+        sampler.sample(&self.files)?;
+        sampler.sample(&self.snapshots)?;
+
+        let dictionary = match sampler.train() {
+            Ok(dictionary) => Arc::new(dictionary),
+            Err(e) => {
+                debug!("not training a metadata dictionary: {}", e);
+                return Ok(None);
+            }
+        };
+        let reference = mw.store_dictionary(dictionary.clone())?;
+        Ok(Some((reference, dictionary)))
     }
 
     pub fn file_index(&self) -> &files::FileIndex {
@@ -117,4 +1485,902 @@ impl Stash {
     pub fn chunk_index(&self) -> &chunks::ChunkIndex {
         self.chunks.index()
     }
+
+    pub fn backend(&self) -> Arc<dyn Backend> {
+        self.backend.clone()
+    }
+}
+
+/// Read `field` of the open object into its store. The removals of a
+/// commit that only wrote what changed are applied to `fields`.
+#[allow(clippy::too_many_arguments)]
+fn read_field(
+    reader: &mut meta::Reader<impl crypto::CryptoProvider>,
+    field: &meta::Field,
+    fields: &[meta::Field],
+    chunks: &mut chunks::ChunkStore,
+    files: &mut files::FileStore,
+    snapshots: &mut snapshots::SnapshotStore,
+    groups: &mut parity::ParityStore,
+    unknown: &mut HashMap<meta::Field, meta::UnknownField>,
+) -> std::result::Result<(), meta::ReadError> {
+    match field {
+        meta::Field::Chunks => reader.read_into(field, chunks)?,
+        meta::Field::Files => reader.read_into(field, files)?,
+        meta::Field::Snapshots => reader.read_into(field, snapshots)?,
+        meta::Field::Parity => reader.read_into(field, groups)?,
+        meta::Field::Removed => {
+            let mut removed = delta::Removed::default();
+            reader.read_into(field, &mut removed)?;
+            removed.apply(fields, chunks, files, snapshots);
+        }
+        // checked when the root object is opened
+        meta::Field::Format => {}
+        meta::Field::Unknown(_) => {
+            reader.read_into(field, unknown.entry(field.clone()).or_default())?
+        }
+    };
+
+    Ok(())
+}
+
+/// `layout`, if it lists the objects commit `generation` `signed`.
+fn signed_layout(
+    layout: meta::Layout,
+    signed: &[(objects::ObjectId, crypto::CryptoDigest)],
+    generation: u64,
+) -> Result<meta::Layout> {
+    if !layout
+        .iter()
+        .map(|(id, _)| id)
+        .eq(signed.iter().map(|(id, _)| id))
+    {
+        return Err(ZerostashError::Tampered(format!(
+            "the layout of commit {} doesn't list the objects it signed",
+            generation
+        )));
+    }
+    Ok(layout)
+}
+
+#[cfg(all(test, feature = "fs"))]
+mod tests {
+    #[test]
+    fn interrupted_backups_resume() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+
+        let backend = Arc::new(MemoryBackend::default());
+        let key = || StashKey::open_stash("resume", "test").unwrap();
+        let cache = std::env::temp_dir().join("0s_test_resume_cache");
+        let _ = std::fs::remove_file(&cache);
+
+        // interrupted before the commit
+        let mut stash = Stash::new(backend.clone(), key());
+        stash.use_file_cache(&cache).unwrap();
+        stash.set_checkpoints(Some(Duration::ZERO));
+        stash.add_recursive(2, "tests/data/100_random_1k").unwrap();
+        drop(stash);
+
+        let mut stash = Stash::new(backend, key());
+        stash.use_file_cache(&cache).unwrap();
+        stash.add_recursive(2, "tests/data/100_random_1k").unwrap();
+        let committed = stash.commit().unwrap();
+        std::fs::remove_file(&cache).unwrap();
+
+        // nothing was read or uploaded again
+        assert!(committed.stats.files.is_empty());
+        assert_eq!(committed.stats.new_chunks, 0);
+        assert_eq!(stash.file_index().len(), 100);
+        let file = stash.file_index().iter().next().unwrap().key().clone();
+        let mut data = vec![];
+        stash.read_entry_to(&file, &mut data).unwrap();
+        assert_eq!(data, std::fs::read(&file.name).unwrap());
+    }
+
+    #[test]
+    fn fields_are_loaded_on_demand() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+
+        let backend = Arc::new(MemoryBackend::default());
+        let key = || StashKey::open_stash("lazy", "test").unwrap();
+        let mut stash = Stash::new(backend.clone(), key());
+        stash.add_recursive(2, "tests/data/100_random_1k").unwrap();
+        stash.commit().unwrap();
+        let chunks = stash.chunk_index().len();
+
+        let mut stash = Stash::new(backend, key());
+        stash.read_fields(&[Field::Files]).unwrap();
+        assert_eq!(stash.file_index().len(), 100);
+        assert!(stash.chunk_index().is_empty());
+
+        stash.commit().unwrap();
+        assert_eq!(stash.chunk_index().len(), chunks);
+    }
+
+    #[test]
+    fn failed_commits_can_be_tried_again() {
+        use super::*;
+        use crate::backends::{self, MemoryBackend};
+        use crate::chunks::ChunkPointer;
+        use crate::objects::{ObjectId, ReadObject, WriteObject};
+        use std::io;
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        #[derive(Default)]
+        struct Full {
+            inner: MemoryBackend,
+            full: AtomicBool,
+        }
+
+        // only metadata objects fail
+        impl Backend for Full {
+            fn write_object(&self, object: &WriteObject) -> backends::Result<()> {
+                if self.full.load(Ordering::SeqCst) {
+                    return Err(io::Error::from(io::ErrorKind::StorageFull).into());
+                }
+                self.inner.write_object(object)
+            }
+
+            fn write_data_object(&self, object: &WriteObject) -> backends::Result<()> {
+                self.inner.write_data_object(object)
+            }
+
+            fn read_object(&self, id: &ObjectId) -> backends::Result<Arc<ReadObject>> {
+                self.inner.read_object(id)
+            }
+        }
+
+        let backend = Arc::new(Full::default());
+        let kdf = Kdf::Argon2id {
+            memory_kib: 8,
+            iterations: 1,
+            parallelism: 1,
+        };
+        let open =
+            || Stash::open_with_credentials(backend.clone(), "user", "test", Some(kdf)).unwrap();
+        let mut stash = open();
+        stash.add_recursive(2, "tests/data/100_random_1k").unwrap();
+        // enough of a chunk index to take more than the root
+        for i in 0..200_000u32 {
+            stash
+                .chunks
+                .push(crypto::chunk_hash(&i.to_le_bytes()), || {
+                    Ok(Arc::new(ChunkPointer::default()))
+                })
+                .unwrap();
+        }
+
+        // nothing panics, and what's in memory is kept
+        backend.full.store(true, Ordering::SeqCst);
+        assert!(matches!(
+            stash.commit(),
+            Err(ZerostashError::Backend { .. })
+        ));
+        assert_eq!(stash.file_index().len(), 100);
+        assert!(matches!(
+            open().read(),
+            Err(ZerostashError::WrongPassphrase)
+        ));
+
+        // and committed once there's room
+        backend.full.store(false, Ordering::SeqCst);
+        stash.commit().unwrap();
+        assert_eq!(open().read().unwrap().file_index().len(), 100);
+    }
+
+    #[test]
+    fn reading_skips_objects_without_the_fields() {
+        use super::*;
+        use crate::backends::{self, MemoryBackend};
+        use crate::chunks::ChunkPointer;
+        use crate::objects::{ObjectId, ReadObject, WriteObject};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        #[derive(Default)]
+        struct CountingBackend {
+            inner: MemoryBackend,
+            reads: AtomicUsize,
+        }
+
+        impl Backend for CountingBackend {
+            fn write_object(&self, object: &WriteObject) -> backends::Result<()> {
+                self.inner.write_object(object)
+            }
+
+            fn read_object(&self, id: &ObjectId) -> backends::Result<Arc<ReadObject>> {
+                self.reads.fetch_add(1, Ordering::SeqCst);
+                self.inner.read_object(id)
+            }
+        }
+
+        let backend = Arc::new(CountingBackend::default());
+        let key = || StashKey::open_stash("layout", "test").unwrap();
+        let mut stash = Stash::new(backend.clone(), key());
+        stash.add_recursive(2, "tests/data/100_random_1k").unwrap();
+        // enough of a chunk index to take objects of its own
+        for i in 0..200_000u32 {
+            stash
+                .chunks
+                .push(crypto::chunk_hash(&i.to_le_bytes()), || {
+                    Ok(Arc::new(ChunkPointer::default()))
+                })
+                .unwrap();
+        }
+        stash.commit().unwrap();
+        let chunks = stash.chunk_index().len();
+
+        let reads = || backend.reads.swap(0, Ordering::SeqCst);
+        let mut stash = Stash::new(backend.clone(), key());
+        reads();
+        stash.read().unwrap();
+        let all = reads();
+
+        let mut stash = Stash::new(backend.clone(), key());
+        stash.read_fields(&[Field::Files]).unwrap();
+        assert!(reads() < all);
+        assert_eq!(stash.file_index().len(), 100);
+
+        // the skipped objects are still there to load
+        stash.load(Field::Chunks).unwrap();
+        assert_eq!(stash.chunk_index().len(), chunks);
+    }
+
+    #[test]
+    fn commits_keep_the_layout_in_the_root() {
+        use super::*;
+        use crate::backends::{self, MemoryBackend};
+        use crate::objects::{ObjectId, ReadObject, WriteObject};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        #[derive(Default)]
+        struct CountingBackend {
+            inner: MemoryBackend,
+            writes: AtomicUsize,
+        }
+
+        impl Backend for CountingBackend {
+            fn write_object(&self, object: &WriteObject) -> backends::Result<()> {
+                self.writes.fetch_add(1, Ordering::SeqCst);
+                self.inner.write_object(object)
+            }
+
+            fn read_object(&self, id: &ObjectId) -> backends::Result<Arc<ReadObject>> {
+                self.inner.read_object(id)
+            }
+        }
+
+        let backend = Arc::new(CountingBackend::default());
+        let key = || StashKey::open_stash("layout", "test").unwrap();
+        let mut stash = Stash::new(backend.clone(), key());
+        stash.add_recursive(2, "tests/data/100_random_1k").unwrap();
+        stash.commit().unwrap();
+
+        // only the signature and the root
+        backend.writes.store(0, Ordering::SeqCst);
+        stash.commit().unwrap();
+        assert_eq!(backend.writes.load(Ordering::SeqCst), 2);
+
+        let mut stash = Stash::new(backend.clone(), key());
+        let (_, header) = stash.open_root(&key().root_object_id().unwrap()).unwrap();
+        assert!(header.layout_at().is_some());
+        assert_eq!(header.layout(), None);
+        stash.read().unwrap();
+        assert_eq!(stash.file_index().len(), 100);
+        assert_eq!(stash.layout.len(), 1);
+    }
+
+    #[test]
+    fn old_metadata_is_migrated() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+
+        let backend = Arc::new(MemoryBackend::default());
+        let key = || StashKey::open_stash("migrate", "test").unwrap();
+        let mut stash = Stash::new(backend.clone(), key());
+        stash.add_recursive(2, "tests/data/100_random_1k").unwrap();
+        stash.commit().unwrap();
+
+        let mut stash = Stash::new(backend.clone(), key());
+        stash.read_fields(&[Field::Snapshots]).unwrap();
+        assert_eq!(stash.metadata_version(), meta::META_VERSION);
+        assert!(!stash.migrate().unwrap());
+
+        // as if the stash was read from objects of the first layout
+        stash.meta_version = 1;
+        assert!(stash.migrate().unwrap());
+        assert_eq!(stash.metadata_version(), meta::META_VERSION);
+
+        let mut stash = Stash::new(backend, key());
+        stash.read().unwrap();
+        assert_eq!(stash.file_index().len(), 100);
+    }
+
+    #[test]
+    fn unknown_fields_are_kept_through_commits() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use serde_cbor::Value;
+
+        let backend = Arc::new(MemoryBackend::default());
+        let key = || StashKey::open_stash("unknown", "test").unwrap();
+        let extents = Field::Unknown("Extents".into());
+        let records = vec![Value::Text("newer".into()), Value::Integer(42)];
+
+        // as a newer build with one more field would commit
+        let mut stash = Stash::new(backend.clone(), key());
+        stash.add_recursive(2, "tests/data/100_random_1k").unwrap();
+        stash
+            .unknown
+            .insert(extents.clone(), meta::UnknownField::new(records.clone()));
+        stash.commit().unwrap();
+
+        let mut stash = Stash::new(backend.clone(), key());
+        stash.read_fields(&[Field::Snapshots]).unwrap();
+        stash.commit().unwrap();
+
+        let mut stash = Stash::new(backend, key());
+        stash.read().unwrap();
+        assert_eq!(stash.file_index().len(), 100);
+        stash.load(extents.clone()).unwrap();
+        assert_eq!(stash.unknown[&extents].records(), records);
+    }
+
+    #[test]
+    fn stashes_keep_their_format() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+
+        let backend = Arc::new(MemoryBackend::default());
+        let key = || StashKey::open_stash("cipher", "test").unwrap();
+        let mut stash = Stash::new(backend.clone(), key());
+        stash.set_cipher(Cipher::Aes256Gcm);
+        stash.set_compression(Compression::None);
+        stash.set_chunker(Chunker::FastCdc);
+        let sizes = ChunkSizes {
+            min: 512,
+            target: 1024,
+            max: 4096,
+            mask_bits: 10,
+        };
+        stash.set_chunk_sizes(sizes).unwrap();
+        assert!(stash
+            .set_chunk_sizes(ChunkSizes { min: 8192, ..sizes })
+            .is_err());
+        stash.add_recursive(2, "tests/data/100_random_1k").unwrap();
+        stash.commit().unwrap();
+
+        let mut stash = Stash::new(backend, key());
+        stash.set_cipher(Cipher::ChaCha20Poly1305);
+        stash.read().unwrap();
+        assert_eq!(stash.cipher(), Cipher::Aes256Gcm);
+        assert_eq!(stash.compression(), Compression::None);
+        assert_eq!(stash.chunker(), Chunker::FastCdc);
+        assert_eq!(stash.chunk_sizes(), sizes);
+        assert_eq!(stash.file_index().len(), 100);
+        // every chunk opens with the recorded cipher
+        assert!(stash.verify(&CancelToken::default()).unwrap() > 0);
+    }
+
+    #[test]
+    fn stashes_can_use_xchacha20_poly1305() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+
+        let backend = Arc::new(MemoryBackend::default());
+        let key = || StashKey::open_stash("xchacha", "test").unwrap();
+        let mut stash = Stash::new(backend.clone(), key());
+        stash.set_cipher(Cipher::XChaCha20Poly1305);
+        stash.add_recursive(2, "tests/data/100_random_1k").unwrap();
+        stash.commit().unwrap();
+
+        let mut stash = Stash::new(backend, key());
+        stash.set_cipher(Cipher::Aes256Gcm);
+        stash.read().unwrap();
+        assert_eq!(stash.cipher(), Cipher::XChaCha20Poly1305);
+        assert_eq!(stash.file_index().len(), 100);
+        assert!(stash.verify(&CancelToken::default()).unwrap() > 0);
+    }
+
+    #[test]
+    fn stashes_keep_their_object_size() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use crate::format::MIN_OBJECT_SIZE;
+
+        let backend = Arc::new(MemoryBackend::default());
+        let key = || StashKey::open_stash("object size", "test").unwrap();
+        let mut stash = Stash::new(backend.clone(), key());
+        assert!(stash.set_object_size(3 * MIN_OBJECT_SIZE).is_err());
+        stash.set_object_size(MIN_OBJECT_SIZE).unwrap();
+        // the chunks of the default chunker don't fit
+        assert!(stash.add_recursive(2, "tests/data/100_random_1k").is_err());
+
+        stash.set_chunker(Chunker::FastCdc);
+        stash.add_recursive(2, "tests/data/100_random_1k").unwrap();
+        stash.commit().unwrap();
+        let mut objects = vec![];
+        stash.chunk_index().for_each(|_, cp| objects.push(cp.file));
+        let object = backend.read_object(&objects[0]).unwrap();
+        assert_eq!(object.buffer.as_ref().len(), MIN_OBJECT_SIZE);
+
+        let mut stash = Stash::new(backend, key());
+        stash.read().unwrap();
+        assert_eq!(stash.object_size(), MIN_OBJECT_SIZE);
+        assert!(stash.verify(&CancelToken::default()).unwrap() > 0);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn metadata_keeps_its_dictionary() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use crate::files::Entry;
+
+        let backend = Arc::new(MemoryBackend::default());
+        let key = || StashKey::open_stash("dictionary", "test").unwrap();
+        let mut stash = Stash::new(backend.clone(), key());
+        stash.set_metadata_dictionary(true).unwrap();
+
+        // enough files for the metadata to outgrow the root object
+        let mut ingest = stash.ingest().unwrap();
+        for i in 0..100_000 {
+            ingest.add_entry(Arc::new(Entry {
+                unix_secs: 1_600_000_000 + i,
+                unix_nanos: 0,
+                unix_perm: 0o644,
+                unix_uid: 1000,
+                unix_gid: 1000,
+                unix_user: None,
+                unix_group: None,
+                size: i * 31,
+                readonly: false,
+                name: format!("home/user/project-{}/notes-{}.txt", i / 100, i),
+                symlink: None,
+                hardlink: None,
+                xattrs: None,
+                device: None,
+                windows_attributes: None,
+                raw_name: None,
+                chunks: vec![],
+            }));
+        }
+        ingest
+            .finish(1_600_000_000, vec![], vec![], Labels::new())
+            .unwrap();
+        assert!(stash.metadata_dictionary());
+
+        let mut stash = Stash::new(backend, key());
+        stash.read().unwrap();
+        assert!(stash.metadata_dictionary());
+        assert!(stash.layout.len() > 1);
+        assert_eq!(stash.file_index().len(), 100_000);
+        assert_eq!(stash.snapshots()[0].files.len(), 100_000);
+
+        // later commits reuse the dictionary
+        let reference = stash.dictionary.as_ref().map(|(reference, _)| *reference);
+        stash.commit().unwrap();
+        assert_eq!(
+            stash.dictionary.as_ref().map(|(reference, _)| *reference),
+            reference
+        );
+    }
+
+    #[test]
+    fn compression_rules_are_kept_with_the_stash() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use crate::compress::CompressionRule;
+        use crate::files::Entry;
+
+        let backend = Arc::new(MemoryBackend::default());
+        let key = || StashKey::open_stash("rules", "test").unwrap();
+        let mut stash = Stash::new(backend.clone(), key());
+        let rules = vec![CompressionRule::new("videos/**", Compression::None)];
+        stash.set_compression_rules(rules.clone()).unwrap();
+        assert!(stash
+            .set_compression_rules(vec![CompressionRule::new("[", Compression::None)])
+            .is_err());
+
+        let entry = |name: &str| Entry {
+            unix_secs: 0,
+            unix_nanos: 0,
+            unix_perm: 0o644,
+            unix_uid: 0,
+            unix_gid: 0,
+            unix_user: None,
+            unix_group: None,
+            size: 0,
+            readonly: false,
+            name: name.into(),
+            symlink: None,
+            hardlink: None,
+            xattrs: None,
+            device: None,
+            windows_attributes: None,
+            raw_name: None,
+            chunks: vec![],
+        };
+        let stored = |entry: &Entry| entry.chunks.iter().map(|(_, c)| c.size).sum::<u32>();
+
+        // different words, so the files don't share chunks
+        let data = |word: &str| {
+            (0..10_000)
+                .flat_map(|i| format!("{} {}\n", word, i % 100).into_bytes())
+                .collect::<Vec<_>>()
+        };
+        let mut ingest = stash.ingest().unwrap();
+        let video = ingest
+            .add_file(entry("videos/a.mkv"), &data("frame"))
+            .unwrap();
+        let notes = ingest.add_file(entry("notes.txt"), &data("note")).unwrap();
+        ingest
+            .finish(1_600_000_000, vec![], vec![], Labels::new())
+            .unwrap();
+        assert!(stored(&video) as u64 > video.size);
+        assert!((stored(&notes) as u64) < notes.size / 10);
+
+        let mut stash = Stash::new(backend.clone(), key());
+        stash.read().unwrap();
+        assert_eq!(stash.compression_rules(), &rules[..]);
+
+        // rules that are set replace the recorded ones
+        let mut stash = Stash::new(backend, key());
+        stash.set_compression_rules(vec![]).unwrap();
+        stash.read().unwrap();
+        assert!(stash.compression_rules().is_empty());
+    }
+
+    #[test]
+    fn wrong_credentials_are_reported() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+
+        let backend = Arc::new(MemoryBackend::default());
+        let mut stash = Stash::new(
+            backend.clone(),
+            StashKey::open_stash("user", "right").unwrap(),
+        );
+        stash.commit().unwrap();
+
+        let mut stash = Stash::new(backend, StashKey::open_stash("user", "wrong").unwrap());
+        match stash.read() {
+            Err(ZerostashError::WrongPassphrase) => (),
+            _ => panic!("expected WrongPassphrase"),
+        }
+    }
+
+    #[test]
+    fn backup_and_restore_snapshots() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use std::fs;
+
+        let backend = Arc::new(MemoryBackend::default());
+        let key = || StashKey::open_stash("snapshot", "test").unwrap();
+        let mut stash = Stash::new(backend.clone(), key());
+
+        let first = stash
+            .backup(&["tests/data/100_random_1k"], &BackupOptions::default())
+            .unwrap();
+        let labelled = BackupOptions {
+            tags: vec!["pre-upgrade".into()],
+            labels: snapshots::parse_labels(&["host=db1", "ticket=OPS-12"]).unwrap(),
+            ..BackupOptions::default()
+        };
+        let second = stash
+            .backup(&["tests/data/10k_random_blob"], &labelled)
+            .unwrap();
+        assert_eq!((first.id, second.id), (1, 2));
+        assert_eq!(first.files.len(), 100);
+        assert_eq!(second.size(), 10 * 1024);
+
+        let mut stash = Stash::new(backend, key());
+        stash.read().unwrap();
+        let snapshots = stash.snapshots();
+        assert_eq!(snapshots.len(), 2);
+        assert_eq!(snapshots[0].paths, vec!["tests/data/100_random_1k"]);
+        assert_eq!(snapshots[0].files.len(), 100);
+        assert_eq!(snapshots[1].tags, vec!["pre-upgrade"]);
+        assert_eq!(snapshots[1].labels["ticket"], "OPS-12");
+
+        let host = |host: &str| snapshots::parse_labels(&[host]).unwrap();
+        let matching = |tags: &[&str], labels| {
+            stash
+                .snapshots_matching(tags, &labels)
+                .iter()
+                .map(|s| s.id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(matching(&[], Labels::new()), vec![1, 2]);
+        assert_eq!(matching(&["pre-upgrade"], host("host=db1")), vec![2]);
+        assert_eq!(matching(&[], host("host=db2")), Vec::<u64>::new());
+        assert_eq!(matching(&["nightly"], Labels::new()), Vec::<u64>::new());
+        assert!(snapshots::parse_labels(&["no-value"]).is_err());
+
+        let target = std::env::temp_dir().join("0s_test_snapshot_restore");
+        let options = RestoreOptions {
+            patterns: vec!["*/1?".into()],
+            ..RestoreOptions::default()
+        };
+        let summary = stash.restore(&snapshots[0], &target, &options).unwrap();
+        let restored = snapshots[0]
+            .files
+            .iter()
+            .filter(|f| glob::Pattern::new("*/1?").unwrap().matches(&f.name))
+            .count();
+        assert_eq!(summary.files, restored as u64);
+        assert_eq!(restored, 10);
+        assert!(stash.verify(&CancelToken::default()).unwrap() > 0);
+        fs::remove_dir_all(&target).unwrap();
+
+        // into another directory, from the whole index
+        let options = RestoreOptions {
+            patterns: vec!["*/10".into()],
+            remap: vec!["tests/data/100_random_1k=drill".parse().unwrap()],
+            ..RestoreOptions::default()
+        };
+        let summary = stash.checkout(&target, &options).unwrap();
+        assert_eq!(summary.files, 1);
+        assert_eq!(
+            fs::read(target.join("drill/10")).unwrap(),
+            fs::read("tests/data/100_random_1k/10").unwrap()
+        );
+
+        fs::remove_dir_all(&target).unwrap();
+    }
+
+    #[test]
+    fn filters_select_what_is_backed_up() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use itertools::Itertools;
+        use std::fs;
+
+        let source = std::env::temp_dir().join("0s_test_filter_source");
+        let _ = fs::remove_dir_all(&source);
+        for dir in ["src/.cache", "target/debug", "node_modules/x"] {
+            fs::create_dir_all(source.join(dir)).unwrap();
+        }
+        for file in [
+            "src/main.rs",
+            "src/a.tmp",
+            "src/debug.log",
+            "target/debug/0s",
+            "node_modules/x/y",
+        ] {
+            fs::write(source.join(file), file).unwrap();
+        }
+        fs::write(source.join("src/.ignore"), "*.log\n").unwrap();
+        fs::write(
+            source.join("src/.cache/CACHEDIR.TAG"),
+            "Signature: 8a477f597d28d172789f06886806bc55",
+        )
+        .unwrap();
+
+        let key = StashKey::open_stash("filter", "test").unwrap();
+        let mut stash = Stash::new(Arc::new(MemoryBackend::default()), key);
+        let options = BackupOptions {
+            filter: vec!["target/".into(), "- node_modules/".into(), "*.tmp".into()],
+            ignore_files: vec![".ignore".into()],
+            exclude_caches: true,
+            ..BackupOptions::default()
+        };
+        let snapshot = stash.backup(&[&source], &options).unwrap();
+        let names = snapshot
+            .files
+            .iter()
+            .map(|f| f.name.clone())
+            .sorted()
+            .collect::<Vec<_>>();
+        let expected = ["src/.ignore", "src/main.rs"]
+            .iter()
+            .map(|f| source.join(f).to_str().unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(names, expected);
+
+        let options = BackupOptions {
+            filter: vec!["re:(".into()],
+            ..BackupOptions::default()
+        };
+        assert!(matches!(
+            stash.backup(&[&source], &options),
+            Err(ZerostashError::Filter { .. })
+        ));
+
+        fs::remove_dir_all(&source).unwrap();
+    }
+
+    #[test]
+    fn cancelled_backup_commits_nothing() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+
+        let backend = Arc::new(MemoryBackend::default());
+        let key = || StashKey::open_stash("cancel", "test").unwrap();
+        let mut stash = Stash::new(backend.clone(), key());
+
+        let options = BackupOptions::default();
+        options.cancel.cancel();
+        let result = stash.backup(&["tests/data/100_random_1k"], &options);
+        assert!(matches!(result, Err(ZerostashError::Cancelled)));
+        assert!(stash.snapshots().is_empty());
+        assert_eq!(stash.list(&[] as &[String]).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn objects_upload_while_the_next_ones_fill() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use crate::files::Entry;
+        use std::io::Read;
+
+        let backend = Arc::new(MemoryBackend::default());
+        let key = || StashKey::open_stash("uploads", "test").unwrap();
+        let mut stash = Stash::new(backend.clone(), key());
+        stash.set_uploads(2);
+        let data = crate::test_util::pseudo_random(3 * crate::BLOCK_SIZE, 7);
+
+        let mut ingest = stash.ingest().unwrap();
+        ingest.add_file(Entry::from_stream("big"), &data).unwrap();
+        ingest.finish(0, vec![], vec![], Labels::new()).unwrap();
+
+        let mut stash = Stash::new(backend, key());
+        stash.read().unwrap();
+        let verified = stash
+            .verify_with(&VerifyOptions::default(), &CancelToken::default())
+            .unwrap();
+        assert!(verified.objects > 3);
+        assert!(verified.missing.is_empty());
+        let mut read = vec![];
+        stash
+            .open_file("big")
+            .unwrap()
+            .read_to_end(&mut read)
+            .unwrap();
+        assert!(read == data);
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn objects_upload_as_tasks_of_an_async_backend() {
+        use super::*;
+        use crate::backends::{self, BoxFuture, MemoryBackend};
+        use crate::files::Entry;
+        use crate::objects::{ObjectId, ReadObject, WriteObject};
+        use std::io::Read;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        // writes only finish once 3 of them are in flight
+        struct Gathering {
+            inner: MemoryBackend,
+            started: AtomicUsize,
+        }
+        impl AsyncBackend for Gathering {
+            fn write_object<'a>(
+                &'a self,
+                object: &'a WriteObject,
+            ) -> BoxFuture<'a, backends::Result<()>> {
+                Box::pin(async move {
+                    self.started.fetch_add(1, Ordering::SeqCst);
+                    while self.started.load(Ordering::SeqCst) < 3 {
+                        tokio::time::sleep(Duration::from_millis(1)).await;
+                    }
+                    self.inner.write_object(object)
+                })
+            }
+
+            fn read_object<'a>(
+                &'a self,
+                id: &'a ObjectId,
+            ) -> BoxFuture<'a, backends::Result<Arc<ReadObject>>> {
+                Box::pin(async move { self.inner.read_object(id) })
+            }
+        }
+
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_time()
+            .build()
+            .unwrap();
+        let backend = MemoryBackend::default();
+        let key = || StashKey::open_stash("async", "test").unwrap();
+        let mut stash = Stash::new(Arc::new(backend.clone()), key());
+        stash.set_uploads(8);
+        let gathering = Arc::new(Gathering {
+            inner: backend.clone(),
+            started: AtomicUsize::new(0),
+        });
+        stash.set_async_backend(gathering.clone(), runtime.handle().clone());
+        let data = crate::test_util::pseudo_random(3 * crate::BLOCK_SIZE, 7);
+
+        let mut ingest = stash.ingest().unwrap();
+        ingest.add_file(Entry::from_stream("big"), &data).unwrap();
+        ingest.finish(0, vec![], vec![], Labels::new()).unwrap();
+        assert!(gathering.started.load(Ordering::SeqCst) > 3);
+
+        let mut stash = Stash::new(Arc::new(backend), key());
+        stash.read().unwrap();
+        let mut read = vec![];
+        stash
+            .open_file("big")
+            .unwrap()
+            .read_to_end(&mut read)
+            .unwrap();
+        assert!(read == data);
+    }
+
+    #[test]
+    fn opening_a_newer_stash_fails() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use crate::format::{Format, FormatError, FormatField, FORMAT_VERSION};
+
+        let backend = Arc::new(MemoryBackend::default());
+        let key = || StashKey::open_stash("format", "test").unwrap();
+        let mut stash = Stash::new(backend.clone(), key());
+        stash.commit().unwrap();
+        Stash::new(backend.clone(), key()).read().unwrap();
+
+        let master_key = key();
+        let mut mw = meta::Writer::new(
+            master_key.root_object_id().unwrap(),
+            backend.clone(),
+            master_key.get_meta_crypto().unwrap(),
+        )
+        .unwrap();
+        let newer = Format {
+            version: FORMAT_VERSION + 2,
+            min_version: FORMAT_VERSION + 1,
+            ..Format::default()
+        };
+        mw.write_field(meta::Field::Format, &FormatField::new(newer))
+            .unwrap();
+        mw.write_field(meta::Field::Files, &files::FileStore::default())
+            .unwrap();
+        mw.seal_and_store().unwrap();
+
+        let mut stash = Stash::new(backend, key());
+        match stash.read() {
+            Err(ZerostashError::Incompatible {
+                source: FormatError::TooNew { required, .. },
+            }) => assert_eq!(required, FORMAT_VERSION + 1),
+            _ => panic!("expected an incompatible format error"),
+        }
+    }
+
+    #[test]
+    fn commits_report_deduplication() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use std::fs;
+
+        let backend = Arc::new(MemoryBackend::default());
+        let mut stash = Stash::new(backend, StashKey::open_stash("stats", "test").unwrap());
+        stash
+            .add_recursive(2, "tests/data/10k_random_blob")
+            .unwrap();
+        let first = stash.commit().unwrap().stats;
+        assert!(first.new_chunks > 0);
+        assert_eq!(first.reused_chunks, 0);
+        assert_eq!(first.logical_bytes, 10 * 1024);
+        assert_eq!(first.new_bytes, first.logical_bytes);
+        assert!(first.stored_bytes > 0);
+        assert_eq!(first.files.len(), 1);
+        assert_eq!(first.dedup_ratio(), 0.0);
+
+        // a copy of the file under another name is all reused
+        let dir = std::env::temp_dir().join("0s_test_commit_stats");
+        fs::create_dir_all(&dir).unwrap();
+        fs::copy("tests/data/10k_random_blob", dir.join("copy")).unwrap();
+        stash.add_recursive(2, &dir).unwrap();
+        let second = stash.commit().unwrap().stats;
+        assert_eq!(second.new_chunks, 0);
+        assert_eq!(second.reused_chunks, first.new_chunks);
+        assert_eq!(second.stored_bytes, 0);
+        assert_eq!(second.files[0].dedup_ratio(), 1.0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,183 @@
+use crate::backends::{self, BackendError};
+use crate::error::Result;
+use crate::meta;
+use crate::objects::ObjectId;
+use crate::stash::{append, grant, keys, Stash};
+
+use std::collections::HashSet;
+use std::io;
+
+/// What `Stash::orphans` found in the backend.
+#[derive(Clone, Debug, Default)]
+pub struct Orphans {
+    /// Objects in the backend that nothing in the stash refers to
+    pub unreferenced: Vec<ObjectId>,
+    /// Objects the stash refers to that aren't in the backend
+    pub missing: Vec<ObjectId>,
+}
+
+impl Orphans {
+    pub fn is_empty(&self) -> bool {
+        self.unreferenced.is_empty() && self.missing.is_empty()
+    }
+}
+
+impl Stash {
+    /// Cross-reference the objects the backend lists with the ones
+    /// the stash refers to, and report both kinds of strays, without
+    /// changing anything.
+    ///
+    /// The stash refers to the data objects in the chunk index, the
+    /// metadata of the last commit, and the parity of its groups.
+    /// What it keeps without needing it, like the metadata of earlier
+    /// commits, garbage in its grace period, keys and the lock, is
+    /// neither unreferenced nor missing. Objects of other stashes in
+    /// the same storage, like those of append-only writers, are
+    /// unreferenced. The signature of every earlier commit is read,
+    /// and fails with `Unsupported` on backends that can't list
+    /// their objects.
+    pub fn orphans(&mut self) -> Result<Orphans> {
+        self.load(meta::Field::Chunks)?;
+        self.load(meta::Field::Parity)?;
+
+        let mut referenced = HashSet::new();
+        self.chunks.index().for_each(|_, cp| {
+            referenced.insert(cp.file);
+        });
+        referenced.extend(self.digests.keys().copied());
+        if let Some((reference, _)) = &self.dictionary {
+            referenced.insert(reference.object);
+        }
+        for group in self.groups.list() {
+            referenced.extend(group.parity);
+        }
+        if self.generation > 0 {
+            referenced.insert(self.master_key.signature_object_id(self.generation)?);
+            referenced.extend(self.stored_layout_object()?);
+        }
+
+        let mut known = self
+            .garbage
+            .iter()
+            .map(|g| g.object)
+            .collect::<HashSet<_>>();
+        for generation in (1..self.generation).rev() {
+            match self.commit_objects(generation)? {
+                Some(objects) => known.extend(objects),
+                // collected by a compaction
+                None => break,
+            }
+        }
+        known.extend(self.key_objects()?);
+        known.insert(self.master_key.lock_object_id()?);
+        known.insert(backends::probe_id());
+
+        let listed = self
+            .backend
+            .list_objects()?
+            .into_iter()
+            .collect::<HashSet<_>>();
+        let mut orphans = Orphans {
+            missing: referenced
+                .iter()
+                .filter(|id| !listed.contains(id))
+                .copied()
+                .collect(),
+            ..Orphans::default()
+        };
+        for id in listed {
+            if referenced.contains(&id) || known.contains(&id) || self.is_credentials(&id)? {
+                continue;
+            }
+            orphans.unreferenced.push(id);
+        }
+
+        orphans.unreferenced.sort_by_key(|id| id.to_string());
+        orphans.missing.sort_by_key(|id| id.to_string());
+        debug!(
+            "{} objects unreferenced, {} missing",
+            orphans.unreferenced.len(),
+            orphans.missing.len()
+        );
+        Ok(orphans)
+    }
+
+    /// The object the layout of the last commit is stored in, if any.
+    fn stored_layout_object(&mut self) -> Result<Option<ObjectId>> {
+        let root = self.master_key.root_object_id()?;
+        let (_, header) = self.open_root(&root)?;
+        Ok(header.layout())
+    }
+
+    /// Whether object `id` is a key, KDF, grant or drop object, which
+    /// other credentials and writers of the stash leave, and which
+    /// start with their name in plain text.
+    fn is_credentials(&self, id: &ObjectId) -> Result<bool> {
+        let longest = grant::MAGIC.len();
+        let head = match self.backend.read_range(id, 0, longest) {
+            Ok(head) => head,
+            Err(BackendError::Io { source }) if source.kind() == io::ErrorKind::UnexpectedEof => {
+                return Ok(false)
+            }
+            Err(e) => return Err(e.into()),
+        };
+        Ok([keys::MAGIC, keys::KDF_MAGIC, append::MAGIC, grant::MAGIC]
+            .iter()
+            .any(|magic| head.starts_with(magic)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn strays_are_found_both_ways() {
+        use super::*;
+        use crate::backends::{Backend, MemoryBackend};
+        use crate::crypto::Kdf;
+        use crate::files::Entry;
+        use crate::objects::{BlockBuffer, Object};
+        use crate::snapshots::Labels;
+        use std::sync::Arc;
+
+        let backend = MemoryBackend::default();
+        let kdf = Kdf::Argon2id {
+            memory_kib: 8,
+            iterations: 1,
+            parallelism: 1,
+        };
+        let mut stash =
+            Stash::open_with_credentials(Arc::new(backend.clone()), "user", "orphans", Some(kdf))
+                .unwrap();
+        let add = |stash: &mut Stash, name: &str| {
+            let mut ingest = stash.ingest().unwrap();
+            ingest
+                .add_file(Entry::from_stream(name), name.as_bytes())
+                .unwrap();
+            ingest.finish(0, vec![], vec![], Labels::new()).unwrap();
+        };
+        add(&mut stash, "a");
+        // the key and the KDF are known
+        assert!(stash.orphans().unwrap().is_empty());
+        // small commits keep their layout in the root, so the first
+        // one leaves nothing behind
+        assert_eq!(stash.stored_layout_object().unwrap(), None);
+        add(&mut stash, "b");
+        assert!(stash.orphans().unwrap().is_empty());
+
+        let stray = ObjectId::from_bytes([7; 32]);
+        backend
+            .write_object(&Object::with_id(stray, BlockBuffer::default()))
+            .unwrap();
+        let mut data = vec![];
+        stash.chunk_index().for_each(|_, cp| data.push(cp.file));
+        backend.remove(&data[0]);
+
+        // and as read by another
+        let mut stash =
+            Stash::open_with_credentials(Arc::new(backend), "user", "orphans", None).unwrap();
+        stash.read().unwrap();
+        let orphans = stash.orphans().unwrap();
+        assert_eq!(orphans.unreferenced, vec![stray]);
+        assert_eq!(orphans.missing, vec![data[0]]);
+    }
+}
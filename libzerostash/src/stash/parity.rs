@@ -0,0 +1,378 @@
+use crate::backends::{self, Backend, BackendError, Capabilities, Retrieval};
+use crate::cancel::CancelToken;
+use crate::crypto::{CryptoProvider, ObjectOperations};
+use crate::error::{Result, ZerostashError};
+use crate::meta::{self, FieldReader, FieldWriter, MetaObjectField, WriteError};
+use crate::objects::{ObjectId, ReadObject, WriteObject};
+use crate::parity::{self, Scheme};
+use crate::stash::{Stash, VerifyOptions};
+use crate::BLOCK_SIZE;
+
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::sync::{Arc, Mutex};
+
+/// Data objects, and the parity objects written for them.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Group {
+    pub data: Vec<ObjectId>,
+    pub parity: Vec<ObjectId>,
+}
+
+/// The `Parity` metadata field
+#[derive(Default)]
+pub(crate) struct ParityStore(Mutex<Vec<Group>>);
+
+impl ParityStore {
+    pub(crate) fn list(&self) -> Vec<Group> {
+        self.0.lock().unwrap().clone()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.lock().unwrap().is_empty()
+    }
+
+    pub(crate) fn clear(&self) {
+        self.0.lock().unwrap().clear()
+    }
+
+    fn extend(&self, groups: Vec<Group>) {
+        self.0.lock().unwrap().extend(groups)
+    }
+
+    /// Keep the groups `keep` returns true for, and return the rest.
+    pub(crate) fn retain(&self, keep: impl Fn(&Group) -> bool) -> Vec<Group> {
+        let mut groups = self.0.lock().unwrap();
+        let (kept, removed) = groups.drain(..).partition(|g| keep(g));
+        *groups = kept;
+        removed
+    }
+}
+
+impl MetaObjectField for ParityStore {
+    type Item = Group;
+
+    fn serialize(&self, mw: &mut impl FieldWriter) -> std::result::Result<(), WriteError> {
+        for group in self.0.lock().unwrap().iter() {
+            mw.write_next(group)?;
+        }
+        Ok(())
+    }
+
+    fn deserialize(&self, mw: &mut impl FieldReader<Self::Item>) {
+        let mut groups = self.0.lock().unwrap();
+        while let Ok(group) = mw.read_next() {
+            groups.push(group);
+        }
+    }
+}
+
+/// Passes everything through to the backend it wraps, and writes
+/// parity objects for each group of data objects written through it.
+///
+/// The data objects of a group are kept in memory until it's full.
+pub(crate) struct ParityBackend {
+    inner: Arc<dyn Backend>,
+    scheme: Scheme,
+    random: ObjectOperations,
+    pending: Mutex<Vec<(ObjectId, Vec<u8>)>>,
+    groups: Mutex<Vec<Group>>,
+}
+
+impl ParityBackend {
+    fn new(inner: Arc<dyn Backend>, scheme: Scheme, random: ObjectOperations) -> ParityBackend {
+        ParityBackend {
+            inner,
+            scheme,
+            random,
+            pending: Mutex::default(),
+            groups: Mutex::default(),
+        }
+    }
+
+    /// Write the parity of the data objects waiting for their group
+    /// to fill, and return the groups written since the last call.
+    fn finish(&self) -> backends::Result<Vec<Group>> {
+        let pending = mem::take(&mut *self.pending.lock().unwrap());
+        if !pending.is_empty() {
+            self.write_group(pending)?;
+        }
+        Ok(mem::take(&mut *self.groups.lock().unwrap()))
+    }
+
+    fn write_group(&self, data: Vec<(ObjectId, Vec<u8>)>) -> backends::Result<()> {
+        let shards = data.iter().map(|(_, d)| d.as_slice()).collect::<Vec<_>>();
+        let mut parity = vec![];
+        for shard in parity::encode(&shards, self.scheme.parity as usize) {
+            // as large as the largest data object
+            let object = WriteObject::with_id(ObjectId::new(&self.random), shard.into());
+            self.inner.write_data_object(&object)?;
+            parity.push(object.id);
+        }
+
+        self.groups.lock().unwrap().push(Group {
+            data: data.into_iter().map(|(id, _)| id).collect(),
+            parity,
+        });
+        Ok(())
+    }
+}
+
+impl Backend for ParityBackend {
+    fn write_object(&self, object: &WriteObject) -> backends::Result<()> {
+        self.inner.write_object(object)
+    }
+
+    fn read_object(&self, id: &ObjectId) -> backends::Result<Arc<ReadObject>> {
+        self.inner.read_object(id)
+    }
+
+    fn write_data_object(&self, object: &WriteObject) -> backends::Result<()> {
+        self.inner.write_data_object(object)?;
+
+        let full = {
+            let mut pending = self.pending.lock().unwrap();
+            pending.push((object.id, object.buffer.as_ref().to_vec()));
+            if pending.len() < self.scheme.data as usize {
+                return Ok(());
+            }
+            mem::take(&mut *pending)
+        };
+        self.write_group(full)
+    }
+
+    fn retrieve_object(&self, id: &ObjectId) -> backends::Result<Retrieval> {
+        self.inner.retrieve_object(id)
+    }
+
+    fn read_range(&self, id: &ObjectId, offset: u64, len: usize) -> backends::Result<Vec<u8>> {
+        self.inner.read_range(id, offset, len)
+    }
+
+    fn delete_object(&self, id: &ObjectId) -> backends::Result<()> {
+        self.inner.delete_object(id)
+    }
+
+    fn list_objects(&self) -> backends::Result<Vec<ObjectId>> {
+        self.inner.list_objects()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.inner.capabilities()
+    }
+
+    fn probe(&self) -> backends::Result<Capabilities> {
+        self.inner.probe()
+    }
+}
+
+/// What `Stash::repair` did.
+#[derive(Clone, Debug, Default)]
+pub struct Repaired {
+    /// Data objects rebuilt from the rest of their group
+    pub rebuilt: Vec<ObjectId>,
+    /// Missing or corrupt data objects that couldn't be rebuilt, as
+    /// they have no parity, or too much of their group is lost
+    pub lost: Vec<ObjectId>,
+}
+
+impl Stash {
+    /// Write parity objects for each `scheme.data` data objects from
+    /// now on, or stop writing them with `None`. The scheme is
+    /// recorded in the stash on the next commit, and used by later
+    /// writers too.
+    ///
+    /// Each commit closes the group it's in, so backups smaller than
+    /// a group have relatively more parity.
+    pub fn set_parity(&mut self, scheme: Option<Scheme>) -> Result<()> {
+        if let Some(scheme) = &scheme {
+            scheme.check().map_err(ZerostashError::Config)?;
+        }
+        self.parity_set = true;
+        self.use_parity(scheme)
+    }
+
+    /// The parity scheme of new data objects.
+    pub fn parity(&self) -> Option<Scheme> {
+        self.parity.as_ref().map(|p| p.scheme)
+    }
+
+    pub(crate) fn use_parity(&mut self, scheme: Option<Scheme>) -> Result<()> {
+        if self.parity() == scheme {
+            return Ok(());
+        }
+        if let Some(writer) = self.parity.take() {
+            self.groups.extend(writer.finish()?);
+            self.backend = writer.inner.clone();
+        }
+        if let Some(scheme) = scheme {
+            let writer = Arc::new(ParityBackend::new(
+                self.backend.clone(),
+                scheme,
+                self.master_key.get_object_crypto()?,
+            ));
+            self.backend = writer.clone();
+            self.parity = Some(writer);
+        }
+        Ok(())
+    }
+
+    /// Write the parity of the data objects of the last group, which
+    /// a commit closes.
+    pub(crate) fn finish_parity(&mut self) -> Result<()> {
+        if let Some(writer) = &self.parity {
+            self.groups.extend(writer.finish()?);
+        }
+        Ok(())
+    }
+
+    /// Rebuild the data objects a `verify` finds missing or corrupt
+    /// from the rest of their parity group, and write them back.
+    pub fn repair(&mut self, cancel: &CancelToken) -> Result<Repaired> {
+        let verified = self.verify_with(&VerifyOptions::default(), cancel)?;
+        let mut bad = verified
+            .missing
+            .iter()
+            .chain(verified.corrupt.iter())
+            .copied()
+            .collect::<HashSet<_>>();
+        if bad.is_empty() {
+            return Ok(Repaired::default());
+        }
+        self.load(meta::Field::Parity)?;
+
+        let mut chunks = HashMap::<_, Vec<_>>::new();
+        self.chunks.index().for_each(|_, cp| {
+            if bad.contains(&cp.file) {
+                chunks.entry(cp.file).or_default().push(cp);
+            }
+        });
+        let crypto = self.master_key.get_object_crypto()?;
+        let mut buffer = vec![0; BLOCK_SIZE];
+
+        let mut repaired = Repaired::default();
+        for group in self.groups.list() {
+            if !group.data.iter().any(|id| bad.contains(id)) {
+                continue;
+            }
+            if cancel.is_cancelled() {
+                return Err(ZerostashError::Cancelled);
+            }
+
+            let mut shards = vec![];
+            for id in group.data.iter().chain(group.parity.iter()) {
+                if bad.contains(id) {
+                    shards.push(None);
+                    continue;
+                }
+                shards.push(match self.backend.read_object(id) {
+                    Ok(object) => Some(object.buffer.as_ref().to_vec()),
+                    Err(BackendError::NoObjectFound) => None,
+                    Err(e) => return Err(e.into()),
+                });
+            }
+            if !parity::reconstruct(&mut shards, group.data.len()) {
+                continue;
+            }
+
+            for (id, shard) in group.data.iter().zip(shards) {
+                let shard = match shard {
+                    Some(shard) if bad.contains(id) => shard,
+                    _ => continue,
+                };
+                let object = WriteObject::with_id(*id, shard.into());
+
+                // a corrupt parity object rebuilds garbage
+                let read = ReadObject::from(&object);
+                let intact = chunks
+                    .get(id)
+                    .into_iter()
+                    .flatten()
+                    .all(|cp| crypto.decrypt_chunk(&mut buffer, &read, cp).is_ok());
+                if intact {
+                    self.backend.write_object(&object)?;
+                    bad.remove(id);
+                    repaired.rebuilt.push(*id);
+                }
+            }
+        }
+
+        debug!(
+            "rebuilt {} objects, {} lost",
+            repaired.rebuilt.len(),
+            bad.len()
+        );
+        repaired.lost = bad.into_iter().collect();
+        Ok(repaired)
+    }
+}
+
+#[cfg(all(test, feature = "fs"))]
+mod tests {
+    #[test]
+    fn lost_objects_are_rebuilt_from_their_group() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use crate::files::Entry;
+        use crate::snapshots::Labels;
+        use crate::stash::StashKey;
+
+        let backend = MemoryBackend::default();
+        let key = || StashKey::open_stash("parity", "test").unwrap();
+        let mut stash = Stash::new(Arc::new(backend.clone()), key());
+        stash
+            .set_parity(Some(Scheme { data: 2, parity: 1 }))
+            .unwrap();
+
+        // random enough not to compress, in more than three objects
+        let data = crate::test_util::pseudo_random(3 * BLOCK_SIZE, 1);
+        let mut ingest = stash.ingest().unwrap();
+        ingest.add_file(Entry::from_stream("file"), &data).unwrap();
+        ingest.finish(0, vec![], vec![], Labels::new()).unwrap();
+
+        // the parity scheme and groups are kept in the stash
+        let mut stash = Stash::new(Arc::new(backend.clone()), key());
+        stash.read().unwrap();
+        assert_eq!(stash.parity(), Some(Scheme { data: 2, parity: 1 }));
+        stash.load(meta::Field::Parity).unwrap();
+        let groups = stash.groups.list();
+        assert!(groups.len() >= 2);
+        assert!(groups
+            .iter()
+            .all(|g| g.data.len() <= 2 && g.parity.len() == 1));
+        let mut objects = HashSet::new();
+        stash.chunk_index().for_each(|_, cp| {
+            objects.insert(cp.file);
+        });
+        let grouped = groups.iter().flat_map(|g| g.data.iter().copied());
+        assert_eq!(grouped.collect::<HashSet<_>>(), objects);
+
+        let (gone, changed) = (groups[0].data[1], groups[1].data[0]);
+        let mut offs = 0;
+        stash.chunk_index().for_each(|_, cp| {
+            if cp.file == changed {
+                offs = cp.offs as usize;
+            }
+        });
+        backend.remove(&gone);
+        let mut corrupt = backend.remove(&changed).unwrap();
+        corrupt[offs] ^= 1;
+        let mut object = WriteObject::default();
+        object.set_id(changed);
+        object.buffer.as_mut().copy_from_slice(&corrupt);
+        backend.write_object(&object).unwrap();
+
+        let cancel = CancelToken::default();
+        let repaired = stash.repair(&cancel).unwrap();
+        let rebuilt = repaired.rebuilt.into_iter().collect::<HashSet<_>>();
+        assert_eq!(rebuilt, vec![gone, changed].into_iter().collect());
+        assert!(repaired.lost.is_empty());
+        assert!(stash.verify(&cancel).is_ok());
+
+        // one parity object doesn't make up for two lost ones
+        backend.remove(&groups[0].data[0]);
+        backend.remove(&groups[0].data[1]);
+        let repaired = stash.repair(&cancel).unwrap();
+        assert_eq!(repaired.lost.len(), 2);
+    }
+}
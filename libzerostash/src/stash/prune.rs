@@ -0,0 +1,278 @@
+use crate::error::{Result, ZerostashError};
+use crate::meta;
+use crate::objects::ObjectId;
+use crate::snapshots::Snapshot;
+use crate::stash::Stash;
+use crate::time::civil_from_days;
+
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+/// Which snapshots `Stash::prune` keeps. Each rule keeps the latest
+/// snapshot of as many periods that have one, counting back from the
+/// latest, and a snapshot is kept if any rule keeps it. Periods are in
+/// UTC, and weeks start on Monday.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Retention {
+    /// The latest snapshots
+    pub last: usize,
+    pub daily: usize,
+    pub weekly: usize,
+    pub monthly: usize,
+    pub yearly: usize,
+}
+
+impl Retention {
+    /// If no rule keeps anything.
+    pub fn is_empty(&self) -> bool {
+        *self == Retention::default()
+    }
+
+    /// The ids of the `snapshots` to keep.
+    pub fn kept(&self, snapshots: &[Arc<Snapshot>]) -> HashSet<u64> {
+        let mut newest = snapshots.iter().collect::<Vec<_>>();
+        newest.sort_by_key(|s| Reverse((s.unix_secs, s.id)));
+
+        let rules: [(usize, Period); 5] = [
+            (self.last, |s| s.id as i64),
+            (self.daily, days),
+            // the epoch was a Thursday
+            (self.weekly, |s| (days(s) + 3).div_euclid(7)),
+            (self.monthly, |s| {
+                let (year, month, _) = civil_from_days(days(s));
+                year * 12 + month
+            }),
+            (self.yearly, |s| civil_from_days(days(s)).0),
+        ];
+
+        let mut kept = HashSet::new();
+        for (count, period) in rules {
+            let mut last = None;
+            let mut left = count;
+            for snapshot in newest.iter() {
+                if left == 0 {
+                    break;
+                }
+                let current = period(snapshot);
+                if last != Some(current) {
+                    kept.insert(snapshot.id);
+                    last = Some(current);
+                    left -= 1;
+                }
+            }
+        }
+        kept
+    }
+}
+
+/// Which period a snapshot is in, as a number that's different for
+/// each.
+type Period = fn(&Snapshot) -> i64;
+
+fn days(snapshot: &Snapshot) -> i64 {
+    (snapshot.unix_secs / 86400) as i64
+}
+
+/// What `Stash::prune` removed.
+#[derive(Clone, Debug, Default)]
+pub struct Pruned {
+    /// The ids of the removed snapshots
+    pub snapshots: Vec<u64>,
+    /// Versions of files that only removed snapshots had
+    pub files: usize,
+    /// Chunks no file refers to anymore, which are left out of the
+    /// chunk index
+    pub chunks: usize,
+    /// Data objects none of the remaining chunks are in, which are
+    /// marked to be collected
+    pub objects: Vec<ObjectId>,
+}
+
+impl Stash {
+    /// Remove the snapshots `retention` doesn't keep, and the files
+    /// and chunks only they referred to, then commit.
+    ///
+    /// Snapshots signed in a namespace are always kept, as audits
+    /// check that none of them is missing. The data of the removed
+    /// chunks stays in its objects until `collect_garbage` deletes
+    /// them.
+    pub fn prune(&mut self, retention: &Retention) -> Result<Pruned> {
+        if retention.is_empty() {
+            return Err(ZerostashError::Config(
+                "the retention policy doesn't keep any snapshot".into(),
+            ));
+        }
+        self.check_mutable("prune snapshots")?;
+        self.load(meta::Field::Files)?;
+        self.load(meta::Field::Chunks)?;
+        self.load(meta::Field::Snapshots)?;
+
+        let kept = retention.kept(&self.snapshots.list());
+        let removed = self
+            .snapshots
+            .retain(|s| kept.contains(&s.id) || s.manifest.is_some());
+        if removed.is_empty() {
+            return Ok(Pruned::default());
+        }
+
+        // files that aren't in any snapshot, like ones stored without
+        // one, stay too
+        let kept_files = self
+            .snapshots
+            .list()
+            .iter()
+            .flat_map(|s| s.files.iter().cloned())
+            .collect::<HashSet<_>>();
+        let removed_files = removed
+            .iter()
+            .flat_map(|s| s.files.iter())
+            .filter(|f| !kept_files.contains(*f))
+            .collect::<HashSet<_>>();
+        self.files.index().retain(|f, _| !removed_files.contains(f));
+
+        let referenced = self
+            .files
+            .index()
+            .iter()
+            .flat_map(|f| {
+                f.key()
+                    .chunks
+                    .iter()
+                    .map(|(_, cp)| cp.hash)
+                    .collect::<Vec<_>>()
+            })
+            .collect::<HashSet<_>>();
+        let chunks = self.chunks.index().retain(|hash| referenced.contains(hash));
+        let mut objects = chunks.iter().map(|cp| cp.file).collect::<HashSet<_>>();
+        self.chunks.index().for_each(|_, cp| {
+            objects.remove(&cp.file);
+        });
+        // objects borrowed from the family are forgotten, not deleted
+        objects.retain(|object| !self.shared.remove(object));
+        self.mark_garbage(objects.iter().copied());
+
+        debug!(
+            "pruning {} snapshots, {} chunks",
+            removed.len(),
+            chunks.len()
+        );
+        self.commit()?;
+
+        Ok(Pruned {
+            snapshots: removed.iter().map(|s| s.id).collect(),
+            files: removed_files.len(),
+            chunks: chunks.len(),
+            objects: objects.into_iter().collect(),
+        })
+    }
+}
+
+#[cfg(all(test, feature = "fs"))]
+mod tests {
+    #[test]
+    fn retention_keeps_the_latest_of_each_period() {
+        use super::*;
+        use crate::snapshots::{Labels, SnapshotStore};
+
+        let day = 86400;
+        let store = SnapshotStore::default();
+        // 2021-01-04 is a Monday
+        let monday = 1_609_718_400;
+        for (i, secs) in [
+            monday - 40 * day,
+            monday - 7 * day,
+            monday - day,
+            monday,
+            monday + 3600,
+            monday + day,
+        ]
+        .iter()
+        .enumerate()
+        {
+            store.push_at(*secs, vec![i.to_string()], vec![], Labels::new(), vec![]);
+        }
+        let snapshots = store.list();
+        let kept = |retention: Retention| {
+            let mut kept = retention.kept(&snapshots).into_iter().collect::<Vec<_>>();
+            kept.sort_unstable();
+            kept
+        };
+
+        assert_eq!(
+            kept(Retention {
+                last: 2,
+                ..Retention::default()
+            }),
+            vec![5, 6]
+        );
+        assert_eq!(
+            kept(Retention {
+                daily: 3,
+                ..Retention::default()
+            }),
+            vec![3, 5, 6]
+        );
+        assert_eq!(
+            kept(Retention {
+                weekly: 2,
+                ..Retention::default()
+            }),
+            vec![3, 6]
+        );
+        assert_eq!(
+            kept(Retention {
+                monthly: 3,
+                yearly: 1,
+                ..Retention::default()
+            }),
+            vec![1, 2, 6]
+        );
+        assert!(Retention::default().is_empty());
+    }
+
+    #[test]
+    fn pruning_drops_what_only_removed_snapshots_refer_to() {
+        use crate::backends::MemoryBackend;
+        use crate::files::Entry;
+        use crate::snapshots::Labels;
+        use crate::stash::{Retention, Stash, StashKey};
+        use std::sync::Arc;
+
+        let backend = Arc::new(MemoryBackend::default());
+        let key = || StashKey::open_stash("prune", "test").unwrap();
+        let mut stash = Stash::new(backend.clone(), key());
+        for (i, data) in [&b"old"[..], b"kept", b"kept"].iter().enumerate() {
+            let mut ingest = stash.ingest().unwrap();
+            let entry = Entry {
+                unix_secs: 0,
+                unix_nanos: 0,
+                ..Entry::from_stream("file")
+            };
+            ingest.add_file(entry, data).unwrap();
+            ingest
+                .finish(i as u64, vec![], vec![], Labels::new())
+                .unwrap();
+        }
+        assert_eq!(stash.chunk_index().len(), 2);
+
+        let last = Retention {
+            last: 2,
+            ..Retention::default()
+        };
+        assert!(stash.prune(&Retention::default()).is_err());
+        let pruned = stash.prune(&last).unwrap();
+        assert_eq!(pruned.snapshots, vec![1]);
+        assert_eq!((pruned.files, pruned.chunks), (1, 1));
+        // each backup wrote its own object
+        assert_eq!(pruned.objects.len(), 1);
+        assert_eq!(stash.prune(&last).unwrap().snapshots, Vec::<u64>::new());
+
+        let mut stash = Stash::new(backend, key());
+        stash.read().unwrap();
+        let ids = stash.snapshots().iter().map(|s| s.id).collect::<Vec<_>>();
+        assert_eq!(ids, vec![2, 3]);
+        assert_eq!(stash.file_index().len(), 1);
+        assert_eq!(stash.chunk_index().len(), 1);
+    }
+}
@@ -0,0 +1,84 @@
+use crate::meta;
+use crate::stash::Stash;
+
+impl Stash {
+    /// Refuse to store chunks past `quota` bytes, as they're stored,
+    /// compressed and encrypted, or don't limit them with `None`.
+    ///
+    /// Writes that would go over fail with `QuotaExceeded`, and what
+    /// was stored before can still be committed. The quota is
+    /// recorded in the stash at the next commit, and read with it
+    /// unless it's set, so a server that sets it at every open keeps
+    /// its users within it. The metadata and the padding of objects
+    /// aren't counted.
+    pub fn set_quota(&mut self, quota: Option<u64>) {
+        self.quota = quota;
+        self.quota_set = true;
+        self.chunks.set_quota(quota);
+    }
+
+    pub fn quota(&self) -> Option<u64> {
+        self.quota
+    }
+
+    /// Bytes of the chunks as they're stored, which are counted
+    /// against the quota. Until the chunk index is loaded, this is
+    /// what the last commit recorded, so it's cheap to check.
+    pub fn stored_bytes(&self) -> u64 {
+        if self.loaded.contains(&meta::Field::Chunks) {
+            self.chunks.index().stored_bytes()
+        } else {
+            self.stored
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn quotas_refuse_new_chunks() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use crate::error::ZerostashError;
+        use crate::files::Entry;
+        use crate::snapshots::Labels;
+        use crate::stash::StashKey;
+        use std::sync::Arc;
+
+        let backend = MemoryBackend::default();
+        let key = || StashKey::open_stash("quota", "test").unwrap();
+        let small = crate::test_util::pseudo_random(10_000, 3);
+        let large = crate::test_util::pseudo_random(100_000, 4);
+
+        let mut stash = Stash::new(Arc::new(backend.clone()), key());
+        stash.set_quota(Some(50_000));
+        let mut ingest = stash.ingest().unwrap();
+        ingest
+            .add_file(Entry::from_stream("small"), &small)
+            .unwrap();
+        assert!(matches!(
+            ingest.add_file(Entry::from_stream("large"), &large),
+            Err(ZerostashError::QuotaExceeded(50_000))
+        ));
+        // what's already there deduplicates
+        ingest.add_file(Entry::from_stream("copy"), &small).unwrap();
+        ingest.finish(0, vec![], vec![], Labels::new()).unwrap();
+        let stored = stash.stored_bytes();
+        assert!(stored > 0 && stored <= 50_000);
+
+        // the quota and the usage are read with the stash
+        let mut stash = Stash::new(Arc::new(backend.clone()), key());
+        stash.read_fields(&[meta::Field::Snapshots]).unwrap();
+        assert_eq!(stash.quota(), Some(50_000));
+        assert_eq!(stash.stored_bytes(), stored);
+        assert_eq!(stash.stats().unwrap().stored_bytes, stored);
+
+        stash.set_quota(Some(200_000));
+        let mut ingest = stash.ingest().unwrap();
+        ingest
+            .add_file(Entry::from_stream("large"), &large)
+            .unwrap();
+        ingest.finish(0, vec![], vec![], Labels::new()).unwrap();
+        assert!(stash.stored_bytes() > stored);
+    }
+}
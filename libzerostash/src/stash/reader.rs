@@ -0,0 +1,376 @@
+use crate::backends::{Backend, BackendError};
+use crate::chunks::ChunkPointer;
+use crate::compress;
+use crate::crypto::ObjectOperations;
+use crate::error::{Result, ZerostashError};
+use crate::files::Entry;
+use crate::meta::Field;
+use crate::objects::{ObjectId, ObjectRange, ReadObject};
+use crate::stash::Stash;
+use crate::BLOCK_SIZE;
+
+use lru::LruCache;
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::sync::{Arc, Mutex};
+
+/// Reads parts of files straight from their chunks, for serving and
+/// exporting files without restoring them to disk first.
+#[derive(Clone)]
+pub(crate) struct ChunkReader {
+    backend: Arc<dyn Backend>,
+    crypto: ObjectOperations,
+    objects: Arc<Mutex<LruCache<ObjectId, Arc<ReadObject>>>>,
+}
+
+/// A stored file that's read and seeked like one on disk, but only
+/// fetches the chunks that reads get to. The chunk read last is kept,
+/// so reading in small pieces doesn't decrypt it again.
+pub struct FileReader {
+    reader: ChunkReader,
+    entry: Arc<Entry>,
+    position: u64,
+    /// The contents of a chunk, and where it starts in the file
+    chunk: Option<(u64, Vec<u8>)>,
+}
+
+impl FileReader {
+    pub fn entry(&self) -> &Arc<Entry> {
+        &self.entry
+    }
+
+    /// The size of the file.
+    pub fn len(&self) -> u64 {
+        self.entry.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entry.size == 0
+    }
+
+    /// The start and end of the chunk with byte `position`.
+    fn chunk_at(&self, position: u64) -> Option<(u64, u64)> {
+        let chunks = &self.entry.chunks;
+        let i = chunks.partition_point(|(start, _)| *start <= position);
+        let start = chunks.get(i.checked_sub(1)?)?.0;
+        let end = chunks.get(i).map(|(s, _)| *s).unwrap_or(self.entry.size);
+        Some((start, end))
+    }
+}
+
+impl Read for FileReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.entry.size || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let (start, end) = self
+            .chunk_at(self.position)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no chunk at position"))?;
+        if !matches!(&self.chunk, Some((cached, _)) if *cached == start) {
+            let mut plain = Vec::with_capacity((end - start) as usize);
+            self.reader.copy(&self.entry, start, end - 1, &mut plain)?;
+            self.chunk = Some((start, plain));
+        }
+
+        let plain = &self.chunk.as_ref().unwrap().1;
+        let from = (self.position - start) as usize;
+        let n = buf.len().min(plain.len().saturating_sub(from));
+        buf[..n].copy_from_slice(&plain[from..from + n]);
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for FileReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(offset) => {
+                self.position = offset;
+                return Ok(offset);
+            }
+            SeekFrom::End(offset) => (self.entry.size, offset),
+            SeekFrom::Current(offset) => (self.position, offset),
+        };
+
+        self.position = base
+            .checked_add_signed(offset)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before the start"))?;
+        Ok(self.position)
+    }
+}
+
+impl Stash {
+    pub(crate) fn chunk_reader(&self) -> Result<ChunkReader> {
+        Ok(ChunkReader {
+            backend: self.backend.clone(),
+            crypto: self.master_key.get_object_crypto()?,
+            objects: Arc::new(Mutex::new(LruCache::new(self.cached_objects()))),
+        })
+    }
+
+    /// Write the contents of the file called `path` in the index to
+    /// `out`, of its latest version if there are several, without
+    /// creating any file. Returns the number of bytes written.
+    pub fn read_to(&mut self, path: &str, out: impl Write) -> Result<u64> {
+        self.load(Field::Files)?;
+        let entry = self.latest(path)?;
+        self.read_entry_to(&entry, out)
+    }
+
+    /// The latest version of the file called `path` in the index.
+    fn latest(&self, path: &str) -> Result<Arc<Entry>> {
+        self.file_index()
+            .iter()
+            .map(|f| f.key().clone())
+            .filter(|f| f.name == path)
+            .max_by_key(|f| (f.unix_secs, f.unix_nanos))
+            .ok_or_else(|| ZerostashError::NoSuchFile(path.into()))
+    }
+
+    /// A reader of the file called `path` in the index, of its latest
+    /// version if there are several.
+    pub fn open_file(&mut self, path: &str) -> Result<FileReader> {
+        self.load(Field::Files)?;
+        let entry = self.latest(path)?;
+        self.open_entry(entry)
+    }
+
+    /// A reader of `entry`, like a file of a snapshot.
+    pub fn open_entry(&self, entry: Arc<Entry>) -> Result<FileReader> {
+        Ok(self.chunk_reader()?.open(entry))
+    }
+
+    /// Write the contents of `entry`, like a file of a snapshot, to
+    /// `out`, a chunk at a time.
+    pub fn read_entry_to(&self, entry: &Entry, mut out: impl Write) -> Result<u64> {
+        if entry.size > 0 {
+            self.chunk_reader()?
+                .copy(entry, 0, entry.size - 1, &mut out)?;
+        }
+        out.flush()?;
+        Ok(entry.size)
+    }
+}
+
+impl ChunkReader {
+    pub(crate) fn open(&self, entry: Arc<Entry>) -> FileReader {
+        FileReader {
+            reader: self.clone(),
+            entry,
+            position: 0,
+            chunk: None,
+        }
+    }
+
+    /// The part of object `id` with `chunks`. Whole objects are kept
+    /// for the next reads, unless the backend can read less.
+    fn object<'a>(
+        &self,
+        id: &ObjectId,
+        chunks: impl IntoIterator<Item = &'a ChunkPointer>,
+    ) -> io::Result<ObjectRange> {
+        if let Some(object) = self.objects.lock().unwrap().get(id) {
+            return Ok(ObjectRange::whole(object.clone()));
+        }
+
+        let error = |e: BackendError| io::Error::other(e.to_string());
+        if self.backend.capabilities().range_reads {
+            return ObjectRange::read(self.backend.as_ref(), id, chunks).map_err(error);
+        }
+
+        let object = self.backend.read_object(id).map_err(error)?;
+        self.objects.lock().unwrap().put(*id, object.clone());
+        Ok(ObjectRange::whole(object))
+    }
+
+    /// Write bytes `start..=end` of `entry` to `out`, reading only
+    /// the chunks that overlap them.
+    pub(crate) fn copy(
+        &self,
+        entry: &Entry,
+        start: u64,
+        end: u64,
+        out: &mut dyn Write,
+    ) -> io::Result<()> {
+        let mut buffer = vec![0; BLOCK_SIZE];
+        let mut plain = vec![];
+
+        let wanted = entry
+            .chunks
+            .iter()
+            .enumerate()
+            .map(|(i, (chunk_start, cp))| {
+                let chunk_end = entry
+                    .chunks
+                    .get(i + 1)
+                    .map(|(s, _)| *s)
+                    .unwrap_or(entry.size);
+                (*chunk_start, chunk_end, cp.as_ref())
+            })
+            .filter(|(chunk_start, chunk_end, _)| *chunk_end > start && *chunk_start <= end)
+            .collect::<Vec<_>>();
+
+        let mut object: Option<(ObjectId, ObjectRange)> = None;
+        for (i, (chunk_start, chunk_end, cp)) in wanted.iter().enumerate() {
+            // read the following chunks in the same object together
+            let range = match object {
+                Some((id, ref range)) if id == cp.file => range,
+                _ => {
+                    let run = wanted[i..]
+                        .iter()
+                        .take_while(|(_, _, next)| next.file == cp.file)
+                        .map(|(_, _, next)| *next);
+                    &object.insert((cp.file, self.object(&cp.file, run)?)).1
+                }
+            };
+
+            let len = range
+                .decrypt_chunk(&self.crypto, &mut buffer, cp)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "corrupt chunk"))?;
+            plain.resize((chunk_end - chunk_start) as usize, 0);
+            compress::unpack_into(&mut plain, &buffer[..len])?;
+
+            let from = start.saturating_sub(*chunk_start) as usize;
+            let to = ((end + 1).min(*chunk_end) - chunk_start) as usize;
+            out.write_all(&plain[from..to])?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn chunks_are_read_with_ranges() {
+        use super::*;
+        use crate::backends::{Capabilities, MemoryBackend};
+        use crate::objects::WriteObject;
+        use crate::stash::{BackupOptions, StashKey};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        struct Counting {
+            inner: MemoryBackend,
+            ranges: bool,
+            read: AtomicUsize,
+        }
+
+        impl Backend for Counting {
+            fn write_object(&self, object: &WriteObject) -> crate::backends::Result<()> {
+                self.inner.write_object(object)
+            }
+
+            fn read_object(&self, id: &ObjectId) -> crate::backends::Result<Arc<ReadObject>> {
+                let object = self.inner.read_object(id)?;
+                self.read
+                    .fetch_add(object.buffer.as_ref().len(), Ordering::SeqCst);
+                Ok(object)
+            }
+
+            fn read_range(
+                &self,
+                id: &ObjectId,
+                offset: u64,
+                len: usize,
+            ) -> crate::backends::Result<Vec<u8>> {
+                let data = self.inner.read_range(id, offset, len)?;
+                self.read.fetch_add(data.len(), Ordering::SeqCst);
+                Ok(data)
+            }
+
+            fn capabilities(&self) -> Capabilities {
+                Capabilities {
+                    range_reads: self.ranges,
+                    ..self.inner.capabilities()
+                }
+            }
+        }
+
+        const PATH: &str = "tests/data/10k_random_blob";
+        let expected = std::fs::read(PATH).unwrap();
+
+        let read = [true, false].map(|ranges| {
+            let backend = Arc::new(Counting {
+                inner: MemoryBackend::default(),
+                ranges,
+                read: AtomicUsize::new(0),
+            });
+            let key = StashKey::open_stash("reader", "test").unwrap();
+            let mut stash = Stash::new(backend.clone(), key);
+            let snapshot = stash.backup(&[PATH], &BackupOptions::default()).unwrap();
+            let entry = snapshot
+                .files
+                .iter()
+                .find(|f| f.name.ends_with(PATH))
+                .unwrap();
+            backend.read.store(0, Ordering::SeqCst);
+
+            let mut out = vec![];
+            let reader = stash.chunk_reader().unwrap();
+            reader.copy(entry, 100, 5000, &mut out).unwrap();
+            assert_eq!(out, &expected[100..=5000]);
+            backend.read.load(Ordering::SeqCst)
+        });
+
+        assert!(read[0] < BLOCK_SIZE && read[1] >= BLOCK_SIZE);
+    }
+
+    #[test]
+    fn files_are_read_at_any_position() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use crate::snapshots::Labels;
+        use crate::stash::StashKey;
+
+        // enough for a few chunks
+        let contents = crate::test_util::pseudo_random(1_000_000, 1);
+        let key = StashKey::open_stash("seek", "test").unwrap();
+        let mut stash = Stash::new(Arc::new(MemoryBackend::default()), key);
+        let mut ingest = stash.ingest().unwrap();
+        let entry = ingest
+            .add_file(Entry::from_stream("blob"), &contents)
+            .unwrap();
+        ingest.finish(0, vec![], vec![], Labels::new()).unwrap();
+        assert!(entry.chunks.len() > 1);
+
+        let mut file = stash.open_entry(entry).unwrap();
+        assert_eq!(file.len(), contents.len() as u64);
+        for position in [999_990, 0, 123_457, 500_000].iter() {
+            let mut buf = [0; 4096];
+            file.seek(SeekFrom::Start(*position)).unwrap();
+            let n = file.read(&mut buf).unwrap();
+            let position = *position as usize;
+            assert!(n > 0);
+            assert_eq!(buf[..n], contents[position..position + n]);
+        }
+
+        file.seek(SeekFrom::End(-300_000)).unwrap();
+        let mut rest = vec![];
+        file.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, &contents[700_000..]);
+        assert!(file.seek(SeekFrom::Current(-2_000_000)).is_err());
+        assert_eq!(file.read(&mut [0; 16]).unwrap(), 0);
+    }
+
+    #[test]
+    fn files_are_streamed_to_writers() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use crate::stash::StashKey;
+
+        const PATH: &str = "tests/data/10k_random_blob";
+        let key = StashKey::open_stash("stream", "test").unwrap();
+        let mut stash = Stash::new(Arc::new(MemoryBackend::default()), key);
+        stash.add_recursive(1, PATH).unwrap();
+
+        let mut out = vec![];
+        let written = stash.read_to(PATH, &mut out).unwrap();
+        assert_eq!(out, std::fs::read(PATH).unwrap());
+        assert_eq!(written, out.len() as u64);
+        assert!(matches!(
+            stash.read_to("missing", io::sink()),
+            Err(ZerostashError::NoSuchFile(_))
+        ));
+    }
+}
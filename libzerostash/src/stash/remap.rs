@@ -0,0 +1,94 @@
+#[cfg(feature = "fs")]
+use crate::files::Entry;
+
+use std::str::FromStr;
+#[cfg(feature = "fs")]
+use std::sync::Arc;
+
+/// A rewrite of the start of paths, to restore files somewhere else
+/// than where they were backed up, written as `FROM=TO`. An empty
+/// `TO` strips `FROM`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Remap {
+    pub from: String,
+    pub to: String,
+}
+
+impl Remap {
+    /// `name` starting with `to` instead of `from`, if it starts with
+    /// it. Only whole components match, so `/home/a` doesn't rewrite
+    /// `/home/ab`.
+    pub fn apply(&self, name: &str) -> Option<String> {
+        let rest = name.strip_prefix(self.from.trim_end_matches('/'))?;
+        if !rest.is_empty() && !rest.starts_with('/') {
+            return None;
+        }
+
+        let to = self.to.trim_end_matches('/');
+        if to.is_empty() {
+            Some(rest.trim_start_matches('/').into())
+        } else {
+            Some(format!("{}{}", to, rest))
+        }
+    }
+}
+
+impl FromStr for Remap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((from, to)) if !from.is_empty() => Ok(Remap {
+                from: from.into(),
+                to: to.into(),
+            }),
+            _ => Err(format!("expected FROM=TO, not {}", s)),
+        }
+    }
+}
+
+/// `entry` with its name, and the first path of its hard link, by
+/// the first of `remaps` that matches each.
+#[cfg(feature = "fs")]
+pub(crate) fn remapped(remaps: &[Remap], entry: &Arc<Entry>) -> Arc<Entry> {
+    let apply = |name: &str| remaps.iter().find_map(|r| r.apply(name));
+    let name = apply(&entry.name);
+    let hardlink = entry.hardlink.as_deref().and_then(apply);
+    if name.is_none() && hardlink.is_none() {
+        return entry.clone();
+    }
+
+    Arc::new(Entry {
+        name: name.unwrap_or_else(|| entry.name.clone()),
+        hardlink: hardlink.or_else(|| entry.hardlink.clone()),
+        ..(**entry).clone()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn remaps_rewrite_whole_components() {
+        use super::*;
+
+        let remap = "/home/alice=/scratch/alice".parse::<Remap>().unwrap();
+        assert_eq!(
+            remap.apply("/home/alice/notes.txt").as_deref(),
+            Some("/scratch/alice/notes.txt")
+        );
+        assert_eq!(
+            remap.apply("/home/alice").as_deref(),
+            Some("/scratch/alice")
+        );
+        assert_eq!(remap.apply("/home/alicia/notes.txt"), None);
+
+        let strip = "/srv/www/=".parse::<Remap>().unwrap();
+        assert_eq!(
+            strip.apply("/srv/www/index.html").as_deref(),
+            Some("index.html")
+        );
+
+        assert!("no-separator".parse::<Remap>().is_err());
+        assert!("=/to".parse::<Remap>().is_err());
+    }
+}
@@ -0,0 +1,263 @@
+use crate::cancel::CancelToken;
+use crate::chunks::ChunkPointer;
+use crate::error::{Result, ZerostashError};
+use crate::files::Entry;
+use crate::meta;
+use crate::objects::{self, ObjectId, ObjectRange, ObjectStore};
+use crate::progress::Phase;
+use crate::stash::Stash;
+use crate::stats::Collector;
+use crate::BLOCK_SIZE;
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+#[derive(Clone, Debug)]
+pub struct RepackOptions {
+    /// Only find which objects would be rewritten
+    pub dry_run: bool,
+    /// Rewrite the objects that files refer to less than this share
+    /// of, from 0 to 1
+    pub threshold: f64,
+    /// Stops rewriting objects once cancelled. What's rewritten is
+    /// committed, then `repack` fails with `Cancelled`
+    pub cancel: CancelToken,
+}
+
+impl Default for RepackOptions {
+    fn default() -> RepackOptions {
+        RepackOptions {
+            dry_run: false,
+            threshold: 0.5,
+            cancel: CancelToken::default(),
+        }
+    }
+}
+
+/// What `Stash::repack` rewrote.
+#[derive(Clone, Debug, Default)]
+pub struct Repacked {
+    /// The sparse objects rewritten, or that would be in a dry run,
+    /// which are marked to be collected
+    pub objects: Vec<ObjectId>,
+    /// The chunks moved out of them
+    pub chunks: u64,
+    /// Bytes of them that no chunk files refer to took
+    pub reclaimed: u64,
+}
+
+impl Stash {
+    /// Move the chunks that files refer to out of sparse data
+    /// objects into new ones, then commit.
+    ///
+    /// Pruning leaves objects with only some of their chunks in use,
+    /// which `collect_garbage` has to keep whole. Objects whose live
+    /// chunks take less than the threshold of them are rewritten, if
+    /// their chunks fit in fewer objects. Files and snapshots then
+    /// point to the new objects, and the old ones are marked to be
+    /// collected after the grace period. Chunks are moved as they're
+    /// compressed and encrypted, only the nonce of the new object
+    /// changes.
+    ///
+    /// Objects borrowed from a family are left alone. Like collecting
+    /// garbage, this can't know about the stashes that borrow objects
+    /// from this one.
+    pub fn repack(&mut self, options: &RepackOptions) -> Result<Repacked> {
+        self.check_mutable("repack objects")?;
+        self.load(meta::Field::Files)?;
+        self.load(meta::Field::Chunks)?;
+        self.load(meta::Field::Snapshots)?;
+
+        let live = self.live_chunks();
+        let garbage = self
+            .garbage
+            .iter()
+            .map(|g| g.object)
+            .collect::<HashSet<_>>();
+        let mut objects = HashMap::<ObjectId, Vec<Arc<ChunkPointer>>>::new();
+        self.chunks.index().for_each(|hash, cp| {
+            if live.contains(hash) && !self.shared.contains(&cp.file) && !garbage.contains(&cp.file)
+            {
+                objects.entry(cp.file).or_default().push(cp);
+            }
+        });
+
+        let size = self.object_size as u64;
+        let used = |chunks: &[Arc<ChunkPointer>]| chunks.iter().map(|cp| u64::from(cp.size)).sum();
+        let sparse = objects
+            .into_iter()
+            .filter(|(_, chunks)| (used(chunks) as f64) < options.threshold * size as f64)
+            .collect::<Vec<_>>();
+        let packed = sparse.iter().map(|(_, c)| used(c)).sum::<u64>();
+        // rewriting objects into as many is only churn
+        if packed.div_ceil(size) >= sparse.len() as u64 {
+            return Ok(Repacked::default());
+        }
+
+        let mut repacked = Repacked::default();
+        debug!(
+            "repacking {} chunks of {} objects",
+            sparse.iter().map(|(_, c)| c.len()).sum::<usize>(),
+            sparse.len()
+        );
+        if options.dry_run {
+            for (id, chunks) in sparse.iter() {
+                repacked.objects.push(*id);
+                repacked.chunks += chunks.len() as u64;
+                repacked.reclaimed += size.saturating_sub(used(chunks));
+            }
+            return Ok(repacked);
+        }
+
+        let crypto = self.master_key.get_object_crypto()?;
+        let storage = objects::Storage::new(
+            self.backend.clone(),
+            self.master_key.get_object_crypto()?,
+            Arc::new(Collector::new(self.progress.clone())),
+        )
+        .object_size(self.object_size)
+        .compression(self.compression)
+        .tuning(self.tuning);
+        let mut storage = self.upload_with(storage, self.backup_threads(1).1);
+        let mut buffer = vec![0; BLOCK_SIZE];
+        let mut moved = HashMap::new();
+
+        self.progress.phase(Phase::Store, Some(sparse.len() as u64));
+        for (id, chunks) in sparse.iter() {
+            if options.cancel.is_cancelled() {
+                break;
+            }
+
+            let range = ObjectRange::read(self.backend.as_ref(), id, chunks.iter().map(|c| &**c))?;
+            for cp in chunks.iter() {
+                let len = range
+                    .decrypt_chunk(&crypto, &mut buffer, cp)
+                    .map_err(|_| ZerostashError::Corrupt { object: *id })?;
+                moved.insert(cp.hash, storage.store_packed(&cp.hash, &buffer[..len])?);
+            }
+
+            repacked.objects.push(*id);
+            repacked.chunks += chunks.len() as u64;
+            repacked.reclaimed += size.saturating_sub(used(chunks));
+            self.progress.item(range.size() as u64);
+        }
+        if repacked.objects.is_empty() {
+            return Err(ZerostashError::Cancelled);
+        }
+        storage.flush()?;
+
+        // point everything to where the chunks are now
+        for (hash, cp) in moved.iter() {
+            self.chunks.index().insert(*hash, cp);
+        }
+        let repoint = |entry: &Arc<Entry>| {
+            if !entry
+                .chunks
+                .iter()
+                .any(|(_, cp)| moved.contains_key(&cp.hash))
+            {
+                return entry.clone();
+            }
+            let mut entry = (**entry).clone();
+            for (_, cp) in entry.chunks.iter_mut() {
+                if let Some(pointer) = moved.get(&cp.hash) {
+                    *cp = pointer.clone();
+                }
+            }
+            Arc::new(entry)
+        };
+        let files = self
+            .files
+            .index()
+            .iter()
+            .map(|f| f.key().clone())
+            .collect::<Vec<_>>();
+        for file in files {
+            let entry = repoint(&file);
+            if !Arc::ptr_eq(&entry, &file) {
+                self.files.index().remove(&file);
+                self.files.insert(entry);
+            }
+        }
+        self.snapshots.map_files(repoint);
+
+        self.mark_garbage(repacked.objects.iter().copied());
+        self.commit()?;
+
+        if options.cancel.is_cancelled() {
+            return Err(ZerostashError::Cancelled);
+        }
+        Ok(repacked)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn sparse_objects_are_repacked() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use crate::snapshots::Labels;
+        use crate::stash::{GcOptions, Retention, StashKey};
+        use std::time::Duration;
+
+        let backend = MemoryBackend::default();
+        let key = || StashKey::open_stash("repack", "test").unwrap();
+        let mut stash = Stash::new(Arc::new(backend.clone()), key());
+        let random = |seed| crate::test_util::pseudo_random(100_000, seed);
+        let (a, b, c) = (random(7), random(8), random(9));
+        let backup = |stash: &mut Stash, files: &[(&str, &[u8])]| {
+            let mut ingest = stash.ingest().unwrap();
+            for (name, data) in files {
+                ingest.add_file(Entry::from_stream(*name), data).unwrap();
+            }
+            ingest.finish(0, vec![], vec![], Labels::new()).unwrap();
+        };
+        backup(&mut stash, &[("a", &a), ("b", &b)]);
+        // a single object is as dense as it gets
+        assert!(stash
+            .repack(&RepackOptions::default())
+            .unwrap()
+            .objects
+            .is_empty());
+
+        // both objects are left with one file each
+        backup(&mut stash, &[("a", &a), ("c", &c)]);
+        let last = Retention {
+            last: 1,
+            ..Retention::default()
+        };
+        stash.prune(&last).unwrap();
+        let dry_run = RepackOptions {
+            dry_run: true,
+            ..RepackOptions::default()
+        };
+        let planned = stash.repack(&dry_run).unwrap();
+        assert_eq!(planned.objects.len(), 2);
+        assert!(planned.objects.iter().all(|o| backend.contains(o)));
+
+        let repacked = stash.repack(&RepackOptions::default()).unwrap();
+        assert_eq!(repacked.objects.len(), 2);
+        assert!(repacked.reclaimed > 0);
+        let files = &stash.snapshots()[0].files;
+        assert!(files
+            .iter()
+            .flat_map(|f| f.chunks.iter())
+            .all(|(_, cp)| !repacked.objects.contains(&cp.file)));
+
+        let now = GcOptions {
+            grace: Duration::ZERO,
+            ..GcOptions::default()
+        };
+        stash.collect_garbage(&now).unwrap();
+        assert!(repacked.objects.iter().all(|o| !backend.contains(o)));
+
+        let mut stash = Stash::new(Arc::new(backend), key());
+        stash.read().unwrap();
+        assert!(stash.newer_commits().unwrap().is_empty());
+        assert_eq!(
+            stash.verify(&CancelToken::default()).unwrap() as usize,
+            stash.chunk_index().len()
+        );
+    }
+}
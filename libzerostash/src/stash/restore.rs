@@ -1,131 +1,831 @@
 #![allow(unused)]
 
 use crate::backends::Backend;
+use crate::cancel::CancelToken;
 use crate::chunks::ChunkPointer;
 use crate::compress;
 use crate::crypto::CryptoProvider;
+use crate::error::{Result, ZerostashError};
 use crate::files::{self, FileIndex};
+use crate::limits;
 use crate::objects::*;
+use crate::stash::{Collisions, FileIterator};
+use crate::stats::{Collector, Stage};
+use crate::BLOCK_SIZE;
 
 use crossbeam_utils::thread;
 use itertools::Itertools;
 use memmap::MmapOptions;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::env;
 use std::error::Error;
 use std::fs;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
-use std::time::UNIX_EPOCH;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, UNIX_EPOCH};
 
-type ThreadWork = (PathBuf, Arc<files::Entry>);
+/// The parts of the restored files that are read from an object with
+/// a single read.
+///
+/// Files are split up by objects, so workers can restore multiple
+/// parts of the same file concurrently, and each object is only
+/// downloaded once, however many files it has chunks of. Parts far
+/// apart in an object are read separately, and concurrently too.
+struct ThreadWork {
+    object: ObjectId,
+    parts: Vec<Part>,
+}
+
+/// The chunks of a file in an object, by where they start in the file.
+struct Part {
+    filename: Arc<PathBuf>,
+    size: u64,
+    chunks: Vec<(u64, Arc<ChunkPointer>)>,
+}
+
+impl Part {
+    /// The bytes of the object it needs.
+    fn span(&self) -> (u32, u32) {
+        self.chunks
+            .iter()
+            .fold((u32::MAX, 0), |(start, end), (_, cp)| {
+                (start.min(cp.offs), end.max(cp.offs + cp.size))
+            })
+    }
+}
+
+/// Parts of an object closer than this are read with a single range
+/// read, rather than one each.
+const RANGE_GAP: u32 = 256 * 1024;
 
 type Sender = crossbeam_channel::Sender<ThreadWork>;
 type Receiver = crossbeam_channel::Receiver<ThreadWork>;
 
-pub type FileIterator<'a> = Box<(dyn Iterator<Item = Arc<files::Entry>> + 'a)>;
+/// The first error of the workers, after which the rest of the work
+/// is skipped.
+type Failed = Mutex<Option<ZerostashError>>;
 
+/// Restore the files of `iter` below `target` on `num_threads`
+/// threads, and read the objects on `downloads` threads of their own,
+/// or, with 0, on the workers.
+#[allow(clippy::too_many_arguments)]
 pub fn from_iter(
-    num_threads: usize,
+    (num_threads, downloads): (usize, usize),
     iter: FileIterator,
     backend: Arc<dyn Backend>,
     crypto: impl CryptoProvider,
+    stats: &Collector,
+    cancel: &CancelToken,
+    owners: bool,
+    skip_xattrs: &[String],
+    collisions: Collisions,
     target: impl AsRef<Path>,
-) {
-    thread::scope(move |s| {
+) -> Result<()> {
+    // hard links are only made to files that are restored as well,
+    // the others get their own copy
+    let entries = iter.collect::<Vec<_>>();
+    let firsts = entries
+        .iter()
+        .filter_map(|e| e.hardlink.as_deref())
+        .collect::<HashSet<_>>();
+    let linked = entries
+        .iter()
+        .filter(|e| firsts.contains(e.name.as_str()))
+        .map(|e| e.name.clone())
+        .collect::<HashSet<_>>();
+    let is_link = |md: &files::Entry| md.hardlink.as_ref().is_some_and(|f| linked.contains(f));
+    let basedir = target.as_ref().to_owned();
+    let mut claimed = Claimed::new(collisions);
+    let failed = Failed::default();
+
+    let restored = thread::scope(|s| {
+        let failed = &failed;
         // need to set up threads here and stuff
         let (sender, receiver) = crossbeam_channel::bounded::<ThreadWork>(2 * num_threads);
 
-        for range in 0..(num_threads - 1) {
-            let backend = backend.clone();
-            let crypto = crypto.clone();
-            let receiver = receiver.clone();
+        // the current thread is only dispatching work, but make sure
+        // there's always at least one worker
+        if downloads == 0 {
+            for _ in 1..num_threads.max(2) {
+                let backend = backend.clone();
+                let crypto = crypto.clone();
+                let receiver = receiver.clone();
+
+                s.spawn(move |_| {
+                    process_packet_loop(receiver, backend, crypto, stats, cancel, failed)
+                });
+            }
+        } else {
+            // each download holds a single object in flight, until a
+            // worker takes it
+            let (fetched, to_write) = crossbeam_channel::bounded(0);
+            for _ in 0..downloads {
+                let backend = backend.clone();
+                let receiver = receiver.clone();
+                let fetched = fetched.clone();
+
+                s.spawn(move |_| fetch_loop(receiver, fetched, backend, stats, cancel, failed));
+            }
+            for _ in 1..num_threads.max(2) {
+                let crypto = crypto.clone();
+                let to_write = to_write.clone();
 
-            s.spawn(move |_| process_packet_loop(receiver, backend, crypto));
+                s.spawn(move |_| write_loop(to_write, crypto, stats, cancel, failed));
+            }
         }
 
-        for md in iter {
-            let path = get_path(&md.name);
+        // only the objects with chunks of the restored files are
+        // read, in the order the files first need them
+        let mut work: Vec<ThreadWork> = vec![];
+        let mut planned = HashMap::new();
+        let mut restored = vec![];
+        for md in entries.iter().cloned() {
+            if cancel.is_cancelled() {
+                break;
+            }
+
+            let path = get_path(md.path());
+            if !is_below(&path) {
+                warn!("not restoring {:?} outside the target", md.name);
+                continue;
+            }
 
             // if there's no parent, then the entire thing is root.
             // if what we're trying to extract is root, then what happens?
-            let mut basedir = target.as_ref().to_owned();
             if let Some(parent) = path.parent() {
                 // create the file and parent directory
-                fs::create_dir_all(basedir.join(parent)).unwrap();
+                fs::create_dir_all(long_path(basedir.join(parent)))?;
+            }
+
+            let filename = match claimed.claim(long_path(basedir.join(&path)), &md.name)? {
+                Some(filename) => Arc::new(filename),
+                None => continue,
+            };
+            trace!("restoring {:?}", filename);
+            stats.start_file(&md.name);
+            stats.add_file(md.size);
+
+            // links are only created once all files are written, so
+            // none of them is written through a link, and special
+            // files have nothing to write
+            if md.symlink.is_some() || md.is_special() || is_link(&md) {
+                restored.push((filename, md));
+                continue;
             }
 
-            let filename = basedir.join(&path);
+            // the file needs to exist with the right size before
+            // workers can start writing its parts
+            stats.time(Stage::Write, || {
+                let _permit = limits::open_file();
+                // nor through a link an earlier restore left there
+                if fs::symlink_metadata(filename.as_ref()).is_ok_and(|m| m.file_type().is_symlink())
+                {
+                    fs::remove_file(filename.as_ref())?;
+                }
+                fs::OpenOptions::new()
+                    .create(true)
+                    .truncate(false)
+                    .write(true)
+                    .open(filename.as_ref())
+                    .and_then(|fd| fd.set_len(md.size))
+            })?;
+            claimed.claimed(&filename, &md.name);
 
-            sender.send((filename, md.clone()));
+            let object_ordered = md.chunks.iter().fold(HashMap::new(), |mut a, c| {
+                a.entry(c.1.file).or_insert_with(Vec::new).push(c.clone());
+                a
+            });
+
+            for (object, chunks) in object_ordered {
+                let i = *planned.entry(object).or_insert_with(|| {
+                    work.push(ThreadWork {
+                        object,
+                        parts: vec![],
+                    });
+                    work.len() - 1
+                });
+                work[i].parts.push(Part {
+                    filename: filename.clone(),
+                    size: md.size,
+                    chunks,
+                });
+            }
+            restored.push((filename, md));
+        }
+
+        debug!(
+            "restoring {} files from {} objects",
+            restored.len(),
+            work.len()
+        );
+        let ranges = backend.capabilities().range_reads;
+        for work in work.into_iter().flat_map(|w| runs(w, ranges)) {
+            if cancel.is_cancelled() || failed.lock().unwrap().is_some() {
+                break;
+            }
+            sender.send(work).unwrap();
         }
+
+        Ok::<_, ZerostashError>(restored)
     })
-    .unwrap();
+    .unwrap()?;
+    if let Some(e) = failed.into_inner().unwrap() {
+        return Err(e);
+    }
+
+    // writing the contents would change the times, so the metadata
+    // goes last, once all workers are done
+    if cancel.is_cancelled() {
+        return Ok(());
+    }
+    // the files that were renamed are linked to by their new path
+    let paths = restored
+        .iter()
+        .map(|(filename, md)| (md.name.clone(), filename.clone()))
+        .collect::<HashMap<_, _>>();
+    for (filename, md) in restored {
+        // files restored since the first pass may be in the way
+        let filename = match claimed.claim(filename.as_ref().clone(), &md.name)? {
+            Some(filename) => filename,
+            None => continue,
+        };
+
+        if let (true, Some(first)) = (is_link(&md), &md.hardlink) {
+            let first = match paths.get(first) {
+                Some(path) => path.as_ref().clone(),
+                None => long_path(basedir.join(get_path(first))),
+            };
+            if let Err(e) = hard_link(&first, &filename) {
+                warn!("can't restore the hard link {:?}: {}", filename, e);
+            }
+            continue;
+        }
+        if let Some(link) = &md.symlink {
+            if let Err(e) = symlink(link, &filename) {
+                warn!("can't restore the link {:?}: {}", filename, e);
+                continue;
+            }
+        }
+        if md.is_special() {
+            // only root can create devices
+            if let Err(e) = mknod(&filename, &md) {
+                warn!("can't restore the special file {:?}: {}", filename, e);
+                continue;
+            }
+        }
+        if md.symlink.is_some() || md.is_special() {
+            claimed.claimed(&filename, &md.name);
+        }
+        if let Err(e) = set_metadata(&filename, &md, owners) {
+            warn!("can't restore the metadata of {:?}: {}", filename, e);
+        }
+        #[cfg(unix)]
+        set_xattrs(&filename, &md, skip_xattrs);
+    }
+
+    Ok(())
+}
+
+/// The files a restore created, by what identifies them on disk, so
+/// paths that name one of them on file systems that ignore case or
+/// normalize Unicode are found, whichever way they do it.
+struct Claimed {
+    policy: Collisions,
+    files: HashMap<Identity, String>,
+}
+
+impl Claimed {
+    fn new(policy: Collisions) -> Claimed {
+        Claimed {
+            policy,
+            files: HashMap::new(),
+        }
+    }
+
+    /// Where the file called `name` is restored: at `path`, unless
+    /// that's a file restored for another name, in which case the
+    /// policy decides.
+    fn claim(&mut self, path: PathBuf, name: &str) -> Result<Option<PathBuf>> {
+        let other = match identity(&path).and_then(|id| self.files.get(&id)) {
+            Some(other) if other != name => other,
+            _ => return Ok(Some(path)),
+        };
+
+        match self.policy {
+            Collisions::Error => Err(ZerostashError::Collision(format!(
+                "{} and {} are the same file in the target",
+                other, name
+            ))),
+            Collisions::Skip => {
+                warn!(
+                    "skipping {}: it's the same file as {} in the target",
+                    name, other
+                );
+                Ok(None)
+            }
+            Collisions::Rename => {
+                let renamed = (1..)
+                    .map(|n| numbered(&path, n))
+                    .find(|p| fs::symlink_metadata(p).is_err())
+                    .unwrap();
+                warn!(
+                    "restoring {} as {:?}: it's the same file as {} in the target",
+                    name, renamed, other
+                );
+                Ok(Some(renamed))
+            }
+        }
+    }
+
+    /// Remember that `path` is where `name` was restored.
+    fn claimed(&mut self, path: &Path, name: &str) {
+        if let Some(id) = identity(path) {
+            self.files.insert(id, name.to_string());
+        }
+    }
 }
 
-fn process_packet_loop(r: Receiver, backend: Arc<dyn Backend>, crypto: impl CryptoProvider) {
-    // Since resources here are all managed by RAII, and they all
-    // implement Drop, we can simply go through the Arc<_>s,
-    // mmap them, open the corresponding objects to extract details,
-    // and everything will be cleaned up on Drop.
-    //
-    // In fact, every layer of these for loops is also managing a
-    // corresponding resource.
+/// `path` with `~n` after its stem.
+fn numbered(path: &Path, n: usize) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{}~{}.{}", stem, n, ext.to_string_lossy()),
+        None => format!("{}~{}", stem, n),
+    };
+    path.with_file_name(name)
+}
+
+#[cfg(unix)]
+type Identity = (u64, u64);
+
+/// The device and inode of the file at `path`, without following
+/// links.
+#[cfg(unix)]
+fn identity(path: &Path) -> Option<Identity> {
+    use std::os::unix::fs::MetadataExt;
+
+    let metadata = fs::symlink_metadata(path).ok()?;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+type Identity = PathBuf;
+
+/// The path of the file at `path` as the file system spells it.
+#[cfg(not(unix))]
+fn identity(path: &Path) -> Option<Identity> {
+    fs::canonicalize(path).ok()
+}
+
+/// The extended attributes of `entry`, except those in `skip`. They
+/// go after the owners, as changing those drops capabilities.
+#[cfg(unix)]
+fn set_xattrs(path: &Path, entry: &files::Entry, skip: &[String]) {
+    let skipped = |name: &str| {
+        skip.iter().any(|s| {
+            name.strip_prefix(s.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+        })
+    };
+
+    for (name, value) in entry.xattrs.iter().flatten() {
+        if skipped(name) {
+            continue;
+        }
+        if let Err(e) = files::xattrs::write(path, name, value) {
+            warn!("can't restore the attribute {} of {:?}: {}", name, path, e);
+        }
+    }
+}
+
+/// Create a link at `path` to `target`, replacing a file that's in
+/// the way, but not a directory.
+#[cfg(unix)]
+fn symlink(target: &str, path: &Path) -> std::io::Result<()> {
+    remove_file(path)?;
+    std::os::unix::fs::symlink(target, path)
+}
+
+fn hard_link(first: &Path, path: &Path) -> std::io::Result<()> {
+    remove_file(path)?;
+    fs::hard_link(first, path)
+}
+
+#[cfg(windows)]
+fn symlink(target: &str, path: &Path) -> std::io::Result<()> {
+    use std::os::windows::fs::{symlink_dir, symlink_file};
+
+    remove_file(path)?;
+    // Windows needs to know if the link is to a directory, which
+    // junctions are restored as too
+    let target = PathBuf::from(target.replace('/', "\\"));
+    if path.parent().is_some_and(|p| p.join(&target).is_dir()) {
+        symlink_dir(&target, path)
+    } else {
+        symlink_file(&target, path)
+    }
+}
+
+fn remove_file(path: &Path) -> std::io::Result<()> {
+    match fs::symlink_metadata(path) {
+        // links to directories on Windows are removed as directories
+        #[cfg(windows)]
+        Ok(md) if md.is_symlink() && path.is_dir() => fs::remove_dir(path),
+        Ok(md) if !md.is_dir() => fs::remove_file(path),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+fn symlink(_target: &str, _path: &Path) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "symbolic links are only restored on Unix and Windows",
+    ))
+}
+
+/// Create the FIFO, socket or device of `entry` at `path`, replacing
+/// a file that's in the way.
+#[cfg(unix)]
+fn mknod(path: &Path, entry: &files::Entry) -> std::io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+
+    remove_file(path)?;
+    let (major, minor) = entry.device.unwrap_or_default();
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    let result = unsafe {
+        libc::mknod(
+            path.as_ptr(),
+            entry.unix_perm as libc::mode_t,
+            libc::makedev(major as _, minor as _),
+        )
+    };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn mknod(_path: &Path, _entry: &files::Entry) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "special files are only restored on Unix",
+    ))
+}
+
+/// The owners of `entry` on this system, by name if they exist.
+#[cfg(unix)]
+fn owner_ids(entry: &files::Entry) -> (u32, u32) {
+    let uid = entry.unix_user.as_deref().and_then(files::owners::uid);
+    let gid = entry.unix_group.as_deref().and_then(files::owners::gid);
+    (uid.unwrap_or(entry.unix_uid), gid.unwrap_or(entry.unix_gid))
+}
+
+/// Files from Windows have no mode or owners, which are left to the
+/// defaults of this system.
+#[cfg(unix)]
+fn is_from_windows(entry: &files::Entry) -> bool {
+    entry.unix_perm == 0
+}
+
+#[cfg(unix)]
+fn is_root() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// The times and owners of a link or special file itself, without
+/// following or opening it.
+#[cfg(unix)]
+fn set_link_metadata(path: &Path, entry: &files::Entry, owners: bool) -> std::io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+
+    if owners && is_root() && !is_from_windows(entry) {
+        let (uid, gid) = owner_ids(entry);
+        std::os::unix::fs::lchown(path, Some(uid), Some(gid))?;
+    }
+
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    let time = libc::timespec {
+        tv_sec: entry.unix_secs as libc::time_t,
+        tv_nsec: entry.unix_nanos as libc::c_long,
+    };
+    let times = [time, time];
+    let result = unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            path.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Give the restored file at `path` the modification time and
+/// permissions of `entry`, and with `owners`, its owners, by name if
+/// they exist on this system. Only root can change owners, so they
+/// are left alone otherwise.
+fn set_metadata(path: &Path, entry: &files::Entry, owners: bool) -> std::io::Result<()> {
+    #[cfg(unix)]
+    if entry.symlink.is_some() {
+        return set_link_metadata(path, entry, owners);
+    }
+    // opening a FIFO would block, so its times are set by path too
+    #[cfg(unix)]
+    if entry.is_special() {
+        use std::os::unix::fs::PermissionsExt;
+
+        set_link_metadata(path, entry, owners)?;
+        return fs::set_permissions(path, fs::Permissions::from_mode(entry.unix_perm & 0o7777));
+    }
+    // the times of links themselves aren't set on Windows
+    #[cfg(not(unix))]
+    if entry.symlink.is_some() {
+        return Ok(());
+    }
+
+    let mtime = UNIX_EPOCH + Duration::new(entry.unix_secs, entry.unix_nanos);
+    fs::OpenOptions::new()
+        .write(true)
+        .open(path)?
+        .set_modified(mtime)?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{chown, PermissionsExt};
+
+        if is_from_windows(entry) {
+            if entry.readonly {
+                let mut permissions = fs::metadata(path)?.permissions();
+                permissions.set_readonly(true);
+                fs::set_permissions(path, permissions)?;
+            }
+            return Ok(());
+        }
+
+        if owners && is_root() {
+            let (uid, gid) = owner_ids(entry);
+            chown(path, Some(uid), Some(gid))?;
+        }
+        fs::set_permissions(path, fs::Permissions::from_mode(entry.unix_perm & 0o7777))?;
+    }
+    // the attributes go last, as a read-only file can't be opened
+    // for its times
+    #[cfg(not(unix))]
+    match entry.windows_attributes {
+        #[cfg(windows)]
+        Some(attributes) => files::attributes::write(path, attributes)?,
+        _ => {
+            let mut permissions = fs::metadata(path)?.permissions();
+            permissions.set_readonly(entry.readonly);
+            fs::set_permissions(path, permissions)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Split the parts of `work` into the runs that are read with a
+/// single read each. Small files only need a few chunks of an object,
+/// and with range reads, the parts far apart in it are read
+/// separately.
+fn runs(work: ThreadWork, ranges: bool) -> Vec<ThreadWork> {
+    let mut parts = work.parts;
+    parts.sort_by_key(|p| p.span());
+
+    let mut runs: Vec<ThreadWork> = vec![];
+    let mut end = 0u32;
+    for part in parts {
+        let (start, part_end) = part.span();
+        match runs.last_mut() {
+            Some(run) if !ranges || start <= end.saturating_add(RANGE_GAP) => {
+                end = end.max(part_end);
+                run.parts.push(part);
+            }
+            _ => {
+                end = part_end;
+                runs.push(ThreadWork {
+                    object: work.object,
+                    parts: vec![part],
+                });
+            }
+        }
+    }
+    runs
+}
+
+fn process_packet_loop(
+    r: Receiver,
+    backend: Arc<dyn Backend>,
+    crypto: impl CryptoProvider,
+    stats: &Collector,
+    cancel: &CancelToken,
+    failed: &Failed,
+) {
     let mut buffer = WriteObject::default();
+    for work in r.iter() {
+        // keep draining the queue, so the dispatcher doesn't block
+        if cancel.is_cancelled() || failed.lock().unwrap().is_some() {
+            continue;
+        }
+
+        let written = fetch(backend.as_ref(), &work, stats)
+            .and_then(|range| write(&work, &range, &crypto, &mut buffer, stats));
+        if let Err(e) = written {
+            fail(failed, &work, e);
+        }
+    }
+}
 
-    // This loop is managing an mmap of a file that's written
-    for (filename, metadata) in r.iter() {
-        if metadata.size == 0 {
+/// Read the objects of the work from `r`, and hand them to the
+/// workers writing them.
+fn fetch_loop(
+    r: Receiver,
+    s: crossbeam_channel::Sender<(ThreadWork, ObjectRange)>,
+    backend: Arc<dyn Backend>,
+    stats: &Collector,
+    cancel: &CancelToken,
+    failed: &Failed,
+) {
+    for work in r.iter() {
+        if cancel.is_cancelled() || failed.lock().unwrap().is_some() {
             continue;
         }
-        let fd = fs::OpenOptions::new()
-            .create(true)
-            .write(true)
-            .read(true)
-            .open(filename)
-            .unwrap();
-        fd.set_len(metadata.size).unwrap();
-
-        let object_ordered = metadata.chunks.iter().fold(HashMap::new(), |mut a, c| {
-            a.entry(c.1.file).or_insert_with(Vec::new).push(c);
-            a
-        });
-
-        let mut mmap = unsafe {
-            MmapOptions::new()
-                .len(metadata.size as usize)
-                .map_mut(&fd)
-                .expect("mmap")
+
+        let range = match fetch(backend.as_ref(), &work, stats) {
+            Ok(range) => range,
+            Err(e) => {
+                fail(failed, &work, e);
+                continue;
+            }
         };
+        if s.send((work, range)).is_err() {
+            return;
+        }
+    }
+}
 
-        // This loop manages the object we're reading from
-        for (objectid, cs) in object_ordered.iter() {
-            let object = backend.read_object(objectid).expect("object read");
+fn write_loop(
+    r: crossbeam_channel::Receiver<(ThreadWork, ObjectRange)>,
+    crypto: impl CryptoProvider,
+    stats: &Collector,
+    cancel: &CancelToken,
+    failed: &Failed,
+) {
+    let mut buffer = WriteObject::default();
+    for (work, range) in r.iter() {
+        if cancel.is_cancelled() || failed.lock().unwrap().is_some() {
+            continue;
+        }
+
+        if let Err(e) = write(&work, &range, &crypto, &mut buffer, stats) {
+            fail(failed, &work, e);
+        }
+    }
+}
 
-            // This loop will extract & decrypt & decompress from the object
-            for (i, (start, cp)) in cs.iter().enumerate() {
-                let start = *start as usize;
-                let mut target: &mut [u8] = buffer.buffer.as_mut();
+/// Keep `e` if it's the first error, so the restore stops.
+fn fail(failed: &Failed, work: &ThreadWork, e: ZerostashError) {
+    warn!(
+        "failed to restore from object {}: {}",
+        work.object.to_string(),
+        e
+    );
+    failed.lock().unwrap().get_or_insert(e);
+}
 
-                let len = crypto.decrypt_chunk(&mut target, &object, cp);
-                compress::decompress_into(&mut mmap[start..], &target[..len]).unwrap();
-            }
+/// Read the chunks of all parts of `work` from its object.
+fn fetch(backend: &dyn Backend, work: &ThreadWork, stats: &Collector) -> Result<ObjectRange> {
+    let chunks = work
+        .parts
+        .iter()
+        .flat_map(|p| p.chunks.iter().map(|(_, cp)| cp.as_ref()));
+    let range = stats.time(Stage::Download, || {
+        ObjectRange::read(backend, &work.object, chunks)
+    })?;
+    stats.add_transfer(Stage::Download, range.size() as u64);
+    Ok(range)
+}
+
+/// Decrypt and decompress the chunks of each part into its file.
+///
+/// Files are mapped into memory, and chunks written at where they
+/// are in the file, so the parts of a file can be written in any
+/// order, by any worker.
+fn write(
+    work: &ThreadWork,
+    object: &ObjectRange,
+    crypto: &impl CryptoProvider,
+    buffer: &mut WriteObject,
+    stats: &Collector,
+) -> Result<()> {
+    for part in work.parts.iter() {
+        let write_start = Instant::now();
+        let mut mmap = {
+            let _permit = limits::open_file();
+            let fd = fs::OpenOptions::new()
+                .write(true)
+                .read(true)
+                .open(part.filename.as_ref())?;
+
+            unsafe { MmapOptions::new().len(part.size as usize).map_mut(&fd)? }
+        };
+        stats.add_time(Stage::Write, write_start.elapsed());
+
+        // This loop will extract & decrypt & decompress from the object
+        for (start, cp) in part.chunks.iter() {
+            let start = *start as usize;
+            let target: &mut [u8] = buffer.buffer.as_mut();
+
+            let len = stats
+                .time(Stage::Decrypt, || object.decrypt_chunk(crypto, target, cp))
+                .map_err(|_| ZerostashError::Corrupt {
+                    object: work.object,
+                })?;
+            stats.time(Stage::Decompress, || {
+                compress::unpack_into(&mut mmap[start..], &target[..len])
+            })?;
         }
     }
+
+    Ok(())
 }
 
+/// Where the file called `filename` in the index is restored, below
+/// the target. Absolute paths lose their root, and the drive of paths
+/// from Windows becomes a directory named after its letter, on any
+/// system. Parent components are dropped, so nothing ends up outside
+/// the target.
 fn get_path(filename: impl AsRef<Path>) -> PathBuf {
-    let path = filename.as_ref();
-    let mut cs = path.components();
+    use std::path::{Component, Prefix};
 
-    if let Some(std::path::Component::RootDir) = cs.next() {
-        cs.as_path().to_owned()
-    } else {
-        path.to_owned()
+    let mut path = PathBuf::new();
+    for (i, component) in filename.as_ref().components().enumerate() {
+        match component {
+            Component::Prefix(prefix) => match prefix.kind() {
+                Prefix::Disk(drive) | Prefix::VerbatimDisk(drive) => {
+                    path.push((drive as char).to_string())
+                }
+                Prefix::UNC(server, share) | Prefix::VerbatimUNC(server, share) => {
+                    path.push(server);
+                    path.push(share);
+                }
+                _ => {}
+            },
+            Component::RootDir | Component::ParentDir => {}
+            // other systems see drives as relative paths
+            Component::Normal(name) if i == 0 => match name.to_str().and_then(drive_letter) {
+                Some(drive) => path.push(drive),
+                None => path.push(name),
+            },
+            component => path.push(component),
+        }
+    }
+
+    path
+}
+
+/// If `path` names something inside the directory it's relative to,
+/// and not the directory itself, or anything outside.
+fn is_below(path: &Path) -> bool {
+    use std::path::Component;
+
+    path.components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+        && path.components().any(|c| matches!(c, Component::Normal(_)))
+}
+
+fn drive_letter(name: &str) -> Option<&str> {
+    name.strip_suffix(':')
+        .filter(|d| d.len() == 1 && d.as_bytes()[0].is_ascii_alphabetic())
+}
+
+/// Paths on Windows are limited to `MAX_PATH`, unless they're
+/// absolute, and start with `\\?\`.
+#[cfg(windows)]
+fn long_path(path: PathBuf) -> PathBuf {
+    match std::path::absolute(&path) {
+        Ok(absolute) if !absolute.as_os_str().to_string_lossy().starts_with(r"\\") => {
+            let mut long = std::ffi::OsString::from(r"\\?\");
+            long.push(absolute.as_os_str());
+            PathBuf::from(long)
+        }
+        _ => path,
     }
 }
 
+#[cfg(not(windows))]
+fn long_path(path: PathBuf) -> PathBuf {
+    path
+}
+
 #[cfg(test)]
 mod tests {
     #[test]
@@ -134,5 +834,529 @@ mod tests {
 
         assert_eq!(Path::new("home/a/b"), get_path("/home/a/b").as_path());
         assert_eq!(Path::new("./a/b"), get_path("./a/b").as_path());
+        assert_eq!(Path::new("C/Users/a"), get_path("C:/Users/a").as_path());
+        assert_eq!(Path::new("home/C:"), get_path("/home/C:").as_path());
+        assert_eq!(Path::new("escaped"), get_path("/../escaped").as_path());
+        assert_eq!(Path::new("a/x"), get_path("a/../../x").as_path());
+        assert!(!is_below(&get_path("/..")));
+    }
+
+    #[test]
+    fn parent_components_stay_inside_the_target() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use crate::snapshots::Labels;
+        use crate::stash::{Stash, StashKey};
+
+        let parent = env::temp_dir().join("0s_test_restore_parent");
+        let target = parent.join("target");
+        let _ = fs::remove_dir_all(&parent);
+
+        let key = StashKey::open_stash("restore parent", "test").unwrap();
+        let mut stash = Stash::new(Arc::new(MemoryBackend::default()), key);
+        let mut ingest = stash.ingest().unwrap();
+        for name in ["/../escaped.txt", "/.."].iter() {
+            ingest
+                .add_file(files::Entry::from_stream(*name), &b"outside"[..])
+                .unwrap();
+        }
+        ingest.finish(0, vec![], vec![], Labels::new()).unwrap();
+
+        stash.restore_by_glob(1, &[] as &[&str], &target).unwrap();
+        assert_eq!(fs::read(target.join("escaped.txt")).unwrap(), b"outside");
+        assert!(!parent.join("escaped.txt").exists());
+        assert!(target.is_dir());
+
+        fs::remove_dir_all(&parent).unwrap();
+    }
+
+    #[test]
+    fn restore_roundtrip_with_any_thread_count() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use crate::stash::{Stash, StashKey};
+
+        const PATH_100: &str = "tests/data/100_random_1k";
+
+        let key = StashKey::open_stash("restore", "test").unwrap();
+        let mut stash = Stash::new(Arc::new(MemoryBackend::default()), key);
+        stash.add_recursive(4, PATH_100).unwrap();
+
+        // and with objects read on threads of their own
+        for (threads, downloads) in [(1, 0), (4, 0), (2, 3)].iter() {
+            stash.set_downloads(*downloads);
+            let target = env::temp_dir().join(format!("0s_test_restore_{}", threads));
+            let summary = stash
+                .restore_by_glob(*threads, &[] as &[&str], &target)
+                .unwrap();
+            assert_eq!(summary.files, 100);
+
+            for entry in fs::read_dir(PATH_100).unwrap() {
+                let entry = entry.unwrap();
+                let restored = target.join(get_path(entry.path()));
+                assert_eq!(fs::read(entry.path()).unwrap(), fs::read(restored).unwrap());
+            }
+
+            fs::remove_dir_all(&target).unwrap();
+        }
+    }
+
+    #[test]
+    fn failures_are_returned() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use crate::stash::{Stash, StashKey};
+
+        const PATH_100: &str = "tests/data/100_random_1k";
+        let target = env::temp_dir().join("0s_test_restore_failed");
+
+        for downloads in [0, 2].iter() {
+            let backend = Arc::new(MemoryBackend::default());
+            let key = StashKey::open_stash("restore failed", "test").unwrap();
+            let mut stash = Stash::new(backend.clone(), key);
+            stash.add_recursive(4, PATH_100).unwrap();
+            stash.commit().unwrap();
+
+            // the metadata is read already, but no chunk can be
+            for id in backend.ids() {
+                backend.remove(&id);
+            }
+            stash.set_downloads(*downloads);
+            assert!(matches!(
+                stash.restore_by_glob(4, &[] as &[&str], &target),
+                Err(ZerostashError::Backend { .. })
+            ));
+
+            fs::remove_dir_all(&target).unwrap();
+        }
+
+        // nothing can be created below a file
+        fs::write(&target, b"in the way").unwrap();
+        let key = StashKey::open_stash("restore failed", "test").unwrap();
+        let mut stash = Stash::new(Arc::new(MemoryBackend::default()), key);
+        stash.add_recursive(4, PATH_100).unwrap();
+        assert!(matches!(
+            stash.restore_by_glob(4, &[] as &[&str], &target),
+            Err(ZerostashError::Io { .. })
+        ));
+        fs::remove_file(&target).unwrap();
+    }
+
+    #[test]
+    fn objects_are_read_once_for_all_files() {
+        use super::*;
+        use crate::backends::{self, Capabilities, MemoryBackend};
+        use crate::stash::{Stash, StashKey};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        struct CountingBackend {
+            inner: MemoryBackend,
+            ranges: bool,
+            reads: AtomicUsize,
+            read: AtomicUsize,
+        }
+
+        impl Backend for CountingBackend {
+            fn write_object(&self, object: &WriteObject) -> backends::Result<()> {
+                self.inner.write_object(object)
+            }
+
+            fn read_object(&self, id: &ObjectId) -> backends::Result<Arc<ReadObject>> {
+                let object = self.inner.read_object(id)?;
+                self.reads.fetch_add(1, Ordering::SeqCst);
+                self.read
+                    .fetch_add(object.buffer.as_ref().len(), Ordering::SeqCst);
+                Ok(object)
+            }
+
+            fn read_range(
+                &self,
+                id: &ObjectId,
+                offset: u64,
+                len: usize,
+            ) -> backends::Result<Vec<u8>> {
+                let data = self.inner.read_range(id, offset, len)?;
+                self.reads.fetch_add(1, Ordering::SeqCst);
+                self.read.fetch_add(data.len(), Ordering::SeqCst);
+                Ok(data)
+            }
+
+            fn capabilities(&self) -> Capabilities {
+                Capabilities {
+                    range_reads: self.ranges,
+                    ..self.inner.capabilities()
+                }
+            }
+        }
+
+        const PATH_100: &str = "tests/data/100_random_1k";
+        let target = env::temp_dir().join("0s_test_restore_objects");
+
+        for ranges in [false, true].iter() {
+            let backend = Arc::new(CountingBackend {
+                inner: MemoryBackend::default(),
+                ranges: *ranges,
+                reads: AtomicUsize::new(0),
+                read: AtomicUsize::new(0),
+            });
+            let key = StashKey::open_stash("restore", "test").unwrap();
+            let mut stash = Stash::new(backend.clone(), key);
+            stash.add_recursive(4, PATH_100).unwrap();
+            let objects = stash
+                .file_index()
+                .iter()
+                .flat_map(|f| {
+                    f.key()
+                        .chunks
+                        .iter()
+                        .map(|(_, cp)| cp.file)
+                        .collect::<Vec<_>>()
+                })
+                .collect::<HashSet<_>>();
+
+            backend.reads.store(0, Ordering::SeqCst);
+            stash.restore_by_glob(4, &["*"], &target).unwrap();
+            assert_eq!(backend.reads.load(Ordering::SeqCst), objects.len());
+            stash.set_downloads(4);
+            backend.reads.store(0, Ordering::SeqCst);
+            stash.restore_by_glob(4, &["*"], &target).unwrap();
+            assert_eq!(backend.reads.load(Ordering::SeqCst), objects.len());
+            stash.set_downloads(0);
+
+            // a single file only needs its own chunks
+            let file = stash.file_index().iter().next().unwrap().key().clone();
+            backend.read.store(0, Ordering::SeqCst);
+            stash.restore_by_glob(1, &[&file.name], &target).unwrap();
+            let read = backend.read.load(Ordering::SeqCst) as u64;
+            assert_eq!(read < 2 * file.size, *ranges);
+
+            fs::remove_dir_all(&target).unwrap();
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn restore_keeps_permissions_and_times() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use crate::stash::{Stash, StashKey};
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let source = env::temp_dir().join("0s_test_metadata_source");
+        let target = env::temp_dir().join("0s_test_metadata_target");
+        let _ = fs::remove_dir_all(&source);
+        fs::create_dir_all(&source).unwrap();
+        let path = source.join("script.sh");
+        fs::write(&path, b"#!/bin/sh\n").unwrap();
+        let mtime = UNIX_EPOCH + Duration::new(1_600_000_000, 123_456_789);
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o750)).unwrap();
+
+        let key = StashKey::open_stash("metadata", "test").unwrap();
+        let mut stash = Stash::new(Arc::new(MemoryBackend::default()), key);
+        stash.add_recursive(1, &source).unwrap();
+        let entry = stash.file_index().iter().next().unwrap().key().clone();
+        assert_eq!(entry.unix_user, files::owners::user(entry.unix_uid));
+
+        stash.restore_by_glob(1, &[] as &[&str], &target).unwrap();
+        let restored = fs::metadata(target.join(get_path(&path))).unwrap();
+        assert_eq!(restored.permissions().mode() & 0o7777, 0o750);
+        assert_eq!(restored.modified().unwrap(), mtime);
+        assert_eq!(restored.uid(), entry.unix_uid);
+
+        fs::remove_dir_all(&source).unwrap();
+        fs::remove_dir_all(&target).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn restore_keeps_extended_attributes() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use crate::stash::{BackupOptions, RestoreOptions, Stash, StashKey};
+
+        let source = env::temp_dir().join("0s_test_xattrs_source");
+        let target = env::temp_dir().join("0s_test_xattrs_target");
+        let _ = fs::remove_dir_all(&source);
+        let _ = fs::remove_dir_all(&target);
+        fs::create_dir_all(&source).unwrap();
+        let path = source.join("labelled");
+        fs::write(&path, b"contents").unwrap();
+        if files::xattrs::write(&path, "user.kept", b"yes").is_err() {
+            // the file system of the temporary directory has none
+            fs::remove_dir_all(&source).unwrap();
+            return;
+        }
+        files::xattrs::write(&path, "user.skipped", b"").unwrap();
+
+        let key = StashKey::open_stash("xattrs", "test").unwrap();
+        let mut stash = Stash::new(Arc::new(MemoryBackend::default()), key);
+        stash.backup(&[&source], &BackupOptions::default()).unwrap();
+        let entry = stash.file_index().iter().next().unwrap().key().clone();
+        let xattrs = entry.xattrs.as_ref().unwrap();
+        assert_eq!(xattrs["user.kept"].as_ref(), b"yes");
+        assert!(xattrs["user.skipped"].is_empty());
+
+        let options = RestoreOptions {
+            skip_xattrs: vec!["user.skipped".into()],
+            ..RestoreOptions::default()
+        };
+        stash
+            .restore(&stash.snapshots()[0], &target, &options)
+            .unwrap();
+        let restored = files::xattrs::read(&target.join(get_path(&path))).unwrap();
+        assert_eq!(restored.keys().collect::<Vec<_>>(), vec!["user.kept"]);
+
+        fs::remove_dir_all(&source).unwrap();
+        fs::remove_dir_all(&target).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_are_restored_as_links() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use crate::stash::{BackupOptions, Stash, StashKey, Symlinks};
+        use std::os::unix::fs::symlink;
+
+        let source = env::temp_dir().join("0s_test_symlinks_source");
+        let target = env::temp_dir().join("0s_test_symlinks_target");
+        let _ = fs::remove_dir_all(&source);
+        let _ = fs::remove_dir_all(&target);
+        fs::create_dir_all(source.join("sub")).unwrap();
+        fs::write(source.join("a.txt"), b"contents").unwrap();
+        fs::write(source.join("sub/b.txt"), b"more contents").unwrap();
+        symlink("a.txt", source.join("link.txt")).unwrap();
+        symlink("sub", source.join("linked")).unwrap();
+
+        let key = || StashKey::open_stash("symlinks", "test").unwrap();
+        let mut stash = Stash::new(Arc::new(MemoryBackend::default()), key());
+        stash.add_recursive(2, &source).unwrap();
+        let links = stash
+            .file_index()
+            .iter()
+            .filter_map(|f| f.key().symlink.clone())
+            .sorted()
+            .collect::<Vec<_>>();
+        assert_eq!(links, vec!["a.txt", "sub"]);
+        assert_eq!(stash.file_index().len(), 4);
+
+        stash.restore_by_glob(2, &[] as &[&str], &target).unwrap();
+        let restored = target.join(get_path(&source));
+        assert_eq!(
+            fs::read_link(restored.join("link.txt")).unwrap(),
+            Path::new("a.txt")
+        );
+        assert_eq!(
+            fs::read(restored.join("linked/b.txt")).unwrap(),
+            b"more contents"
+        );
+
+        // following them stores what they point to instead, but
+        // only walks the directory once
+        let mut stash = Stash::new(Arc::new(MemoryBackend::default()), key());
+        let options = BackupOptions {
+            symlinks: Symlinks::All,
+            ..BackupOptions::default()
+        };
+        stash.backup(&[&source], &options).unwrap();
+        assert!(stash.file_index().iter().all(|f| f.key().symlink.is_none()));
+        assert_eq!(stash.file_index().len(), 3);
+
+        fs::remove_dir_all(&source).unwrap();
+        fs::remove_dir_all(&target).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn special_files_are_recreated() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use crate::stash::{Stash, StashKey};
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+        use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+        let source = env::temp_dir().join("0s_test_special_source");
+        let target = env::temp_dir().join("0s_test_special_target");
+        let _ = fs::remove_dir_all(&source);
+        let _ = fs::remove_dir_all(&target);
+        fs::create_dir_all(&source).unwrap();
+        let fifo = CString::new(source.join("fifo").as_os_str().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o640) }, 0);
+        let _socket = std::os::unix::net::UnixListener::bind(source.join("socket")).unwrap();
+
+        let key = StashKey::open_stash("special", "test").unwrap();
+        let mut stash = Stash::new(Arc::new(MemoryBackend::default()), key);
+        stash.add_recursive(2, &source).unwrap();
+        stash.add_recursive(1, "/dev/null").unwrap();
+        let null = stash
+            .file_index()
+            .iter()
+            .find(|f| f.key().name == "/dev/null")
+            .map(|f| f.key().clone())
+            .unwrap();
+        assert!(null.is_special() && null.chunks.is_empty());
+        assert_eq!(null.device, Some((1, 3)));
+        assert!(stash.file_index().iter().all(|f| f.key().is_special()));
+
+        stash.restore_by_glob(2, &[] as &[&str], &target).unwrap();
+        let restored = target.join(get_path(&source));
+        let metadata = fs::symlink_metadata(restored.join("fifo")).unwrap();
+        assert!(metadata.file_type().is_fifo());
+        assert_eq!(metadata.mode() & 0o777, 0o640);
+        assert!(fs::symlink_metadata(restored.join("socket"))
+            .unwrap()
+            .file_type()
+            .is_socket());
+        // only root can create devices
+        if is_root() {
+            let dev = fs::symlink_metadata(target.join("dev/null")).unwrap();
+            assert!(dev.file_type().is_char_device());
+            assert_eq!(dev.rdev(), fs::metadata("/dev/null").unwrap().rdev());
+        }
+
+        fs::remove_dir_all(&source).unwrap();
+        fs::remove_dir_all(&target).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn hard_links_are_read_once_and_restored_as_links() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use crate::stash::{Stash, StashKey};
+        use std::os::unix::fs::MetadataExt;
+
+        let source = env::temp_dir().join("0s_test_hardlinks_source");
+        let target = env::temp_dir().join("0s_test_hardlinks_target");
+        let _ = fs::remove_dir_all(&source);
+        let _ = fs::remove_dir_all(&target);
+        fs::create_dir_all(&source).unwrap();
+        fs::write(source.join("a"), b"shared contents").unwrap();
+        fs::hard_link(source.join("a"), source.join("b")).unwrap();
+
+        let key = StashKey::open_stash("hardlinks", "test").unwrap();
+        let mut stash = Stash::new(Arc::new(MemoryBackend::default()), key);
+        stash.add_recursive(2, &source).unwrap();
+        let entries = stash
+            .file_index()
+            .iter()
+            .map(|f| f.key().clone())
+            .sorted_by_key(|f| f.hardlink.is_some())
+            .collect::<Vec<_>>();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].hardlink.as_ref(), Some(&entries[0].name));
+        assert!(entries[1].chunks == entries[0].chunks);
+
+        stash.restore_by_glob(2, &[] as &[&str], &target).unwrap();
+        let restored = target.join(get_path(&source));
+        let a = fs::metadata(restored.join("a")).unwrap();
+        assert_eq!(a.ino(), fs::metadata(restored.join("b")).unwrap().ino());
+        assert_eq!(a.nlink(), 2);
+        fs::remove_dir_all(&target).unwrap();
+
+        // without the first path, the link is restored as a copy
+        let pattern = entries[1].name.clone();
+        stash.restore_by_glob(1, &[pattern], &target).unwrap();
+        assert_eq!(
+            fs::read(target.join(get_path(&entries[1].name))).unwrap(),
+            b"shared contents"
+        );
+
+        fs::remove_dir_all(&source).unwrap();
+        fs::remove_dir_all(&target).unwrap();
+    }
+
+    #[test]
+    fn colliding_paths_follow_the_policy() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use crate::stash::{Labels, RestoreOptions, Stash, StashKey};
+
+        let key = StashKey::open_stash("collisions", "test").unwrap();
+        let mut stash = Stash::new(Arc::new(MemoryBackend::default()), key);
+        // both name the same file anywhere, like `a` and `A` do where
+        // case is ignored
+        let mut ingest = stash.ingest().unwrap();
+        ingest
+            .add_file(files::Entry::from_stream("same/file.txt"), b"first")
+            .unwrap();
+        ingest
+            .add_file(files::Entry::from_stream("same//file.txt"), b"second")
+            .unwrap();
+        let snapshot = ingest
+            .finish(0, vec!["same".into()], vec![], Labels::new())
+            .unwrap();
+
+        let target = env::temp_dir().join("0s_test_collisions");
+        let read = |name: &str| fs::read(target.join("same").join(name)).ok();
+        for collisions in [Collisions::Rename, Collisions::Skip, Collisions::Error] {
+            let _ = fs::remove_dir_all(&target);
+            let options = RestoreOptions {
+                collisions,
+                ..RestoreOptions::default()
+            };
+            let restored = stash.restore(&snapshot, &target, &options);
+
+            match collisions {
+                Collisions::Rename => {
+                    assert_eq!(restored.unwrap().files, 2);
+                    let mut contents = vec![read("file.txt"), read("file~1.txt")];
+                    contents.sort();
+                    assert_eq!(
+                        contents,
+                        vec![Some(b"first".to_vec()), Some(b"second".to_vec())]
+                    );
+                }
+                Collisions::Skip => {
+                    assert_eq!(restored.unwrap().files, 1);
+                    assert!(read("file.txt").is_some() && read("file~1.txt").is_none());
+                }
+                Collisions::Error => {
+                    assert!(matches!(restored, Err(ZerostashError::Collision(_))))
+                }
+            }
+        }
+
+        fs::remove_dir_all(&target).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn names_that_are_not_utf8_are_restored_exactly() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use crate::stash::{Stash, StashKey};
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let source = env::temp_dir().join("0s_test_raw_names_source");
+        let target = env::temp_dir().join("0s_test_raw_names_target");
+        let _ = fs::remove_dir_all(&source);
+        let _ = fs::remove_dir_all(&target);
+        fs::create_dir_all(&source).unwrap();
+        // Latin-1, as older systems wrote it
+        let name = OsStr::from_bytes(b"caf\xe9.txt");
+        fs::write(source.join(name), b"latin-1").unwrap();
+
+        let key = StashKey::open_stash("raw names", "test").unwrap();
+        let mut stash = Stash::new(Arc::new(MemoryBackend::default()), key);
+        stash.add_recursive(1, &source).unwrap();
+        let entry = stash.file_index().iter().next().unwrap().key().clone();
+        assert!(entry.name.ends_with("caf\u{fffd}.txt"));
+        assert_eq!(entry.path(), source.join(name));
+
+        stash.restore_by_glob(1, &[] as &[&str], &target).unwrap();
+        let restored = target.join(get_path(&source)).join(name);
+        assert_eq!(fs::read(restored).unwrap(), b"latin-1");
+
+        fs::remove_dir_all(&source).unwrap();
+        fs::remove_dir_all(&target).unwrap();
     }
 }
@@ -0,0 +1,30 @@
+use std::str::FromStr;
+
+/// The order in which files are handed to the workers. Schedules
+/// other than the walk reorder the files a window of 65536 at a time,
+/// as they're found.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Schedule {
+    /// Process files as the directory walk finds them
+    #[default]
+    Walk,
+    /// Process the smallest files first
+    SmallFirst,
+    /// Round-robin between size classes, so a few huge files don't
+    /// hold up everything else
+    Interleave,
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "walk" => Ok(Schedule::Walk),
+            "small-first" => Ok(Schedule::SmallFirst),
+            "interleave" => Ok(Schedule::Interleave),
+            _ => Err(format!("unknown schedule: {}", s)),
+        }
+    }
+}
@@ -0,0 +1,220 @@
+//! Signatures over the metadata of a stash, so a backend that swaps or
+//! rolls back objects can't present a forged snapshot list.
+//!
+//! Every commit signs the hashes of the metadata objects it wrote
+//! with an Ed25519 key derived from the master key. The signature is
+//! kept in an object of its own for the number of the commit, which
+//! the root records. The root is stored last, so an interrupted
+//! commit leaves the previous one and its signature in place.
+
+use crate::backends::BackendError;
+use crate::crypto::CryptoDigest;
+use crate::error::{Result, ZerostashError};
+use crate::objects::{BlockBuffer, Object, ObjectId};
+use crate::stash::Stash;
+
+use std::convert::TryInto;
+
+/// Signature objects start with this, then the signature, and the
+/// length of the statement it signs.
+const MAGIC: &[u8] = b"0s-sig";
+const SIGNATURE_SIZE: usize = 64;
+
+/// What a commit signs.
+#[derive(Serialize, Deserialize)]
+struct Statement {
+    generation: u64,
+    objects: Vec<(ObjectId, CryptoDigest)>,
+}
+
+impl Stash {
+    /// The number of the last commit to the stash, as of when it was
+    /// read. Stashes from before commits were signed start at 0.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Fail reading the stash if it's older than commit `generation`,
+    /// as it is when the backend rolled it back.
+    ///
+    /// This is the only way to notice a backend serving an earlier,
+    /// validly signed commit, so frontends should remember the last
+    /// generation they saw.
+    pub fn expect_generation(&mut self, generation: u64) {
+        self.min_generation = generation;
+    }
+
+    /// The signed commits after the one that was read, which a root
+    /// the backend rolled back leaves. So does a commit interrupted
+    /// before it stored its root, until the next one. Signatures are
+    /// only sure to be kept if the stash is immutable, see
+    /// `Stash::set_immutable`.
+    pub fn newer_commits(&self) -> Result<Vec<u64>> {
+        let mut newer = vec![];
+        for generation in self.generation + 1.. {
+            match self.read_statement(generation) {
+                Ok(Some(_)) => newer.push(generation),
+                Ok(None) => break,
+                // not signed by a commit of the stash
+                Err(ZerostashError::Tampered(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(newer)
+    }
+
+    /// Sign `objects` of commit `generation`, before storing its root.
+    pub(crate) fn store_signature(
+        &self,
+        generation: u64,
+        objects: &[(ObjectId, CryptoDigest)],
+    ) -> Result<()> {
+        let statement = serde_cbor::to_vec(&Statement {
+            generation,
+            objects: objects.to_vec(),
+        })
+        .expect("failed to write signature");
+        let signature = self.master_key.sign(&statement)?;
+
+        let data = [
+            MAGIC,
+            &signature,
+            &(statement.len() as u32).to_le_bytes(),
+            &statement,
+        ]
+        .concat();
+        let id = self.master_key.signature_object_id(generation)?;
+        self.backend
+            .write_object(&Object::with_id(id, BlockBuffer::from(data)))?;
+
+        Ok(())
+    }
+
+    /// The metadata objects commit `generation` signed, in order, or
+    /// nothing for stashes from before signing.
+    pub(crate) fn signed_objects(
+        &self,
+        generation: u64,
+    ) -> Result<Option<Vec<(ObjectId, CryptoDigest)>>> {
+        if generation < self.min_generation {
+            return Err(ZerostashError::Tampered(format!(
+                "the stash was rolled back to commit {} from {}",
+                generation, self.min_generation
+            )));
+        }
+        if generation == 0 {
+            return Ok(None);
+        }
+
+        let statement = self
+            .read_statement(generation)?
+            .ok_or_else(|| missing(generation))?;
+        if statement.generation != generation {
+            return Err(ZerostashError::Tampered(format!(
+                "the signature of commit {} is for commit {}",
+                generation, statement.generation
+            )));
+        }
+        Ok(Some(statement.objects))
+    }
+
+    /// The metadata objects of commit `generation`, with its
+    /// signature, or nothing if the signature was collected.
+    pub(crate) fn commit_objects(&self, generation: u64) -> Result<Option<Vec<ObjectId>>> {
+        let signature = self.master_key.signature_object_id(generation)?;
+        Ok(self.read_statement(generation)?.map(|statement| {
+            statement
+                .objects
+                .into_iter()
+                .map(|(id, _)| id)
+                .chain(Some(signature))
+                .collect()
+        }))
+    }
+
+    fn read_statement(&self, generation: u64) -> Result<Option<Statement>> {
+        let missing = || missing(generation);
+        let id = self.master_key.signature_object_id(generation)?;
+        let object = match self.backend.read_object(&id) {
+            Ok(object) => object,
+            Err(BackendError::NoObjectFound) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        // bundles pad the object to a full block
+        let data = object
+            .buffer
+            .as_ref()
+            .strip_prefix(MAGIC)
+            .ok_or_else(missing)?;
+        if data.len() < SIGNATURE_SIZE + 4 {
+            return Err(missing());
+        }
+        let (signature, rest) = data.split_at(SIGNATURE_SIZE);
+        let len = u32::from_le_bytes(rest[..4].try_into().unwrap()) as usize;
+        let statement = rest[4..].get(..len).ok_or_else(missing)?;
+
+        if !self.master_key.verify(statement, signature)? {
+            return Err(missing());
+        }
+        serde_cbor::from_slice(statement)
+            .map(Some)
+            .map_err(|_| missing())
+    }
+}
+
+fn missing(generation: u64) -> ZerostashError {
+    ZerostashError::Tampered(format!("no valid signature for commit {}", generation))
+}
+
+#[cfg(all(test, feature = "fs"))]
+mod tests {
+    #[test]
+    fn forged_metadata_is_detected() {
+        use super::*;
+        use crate::backends::{Backend, MemoryBackend};
+        use crate::stash::{BackupOptions, StashKey};
+        use std::sync::Arc;
+
+        let backend = Arc::new(MemoryBackend::default());
+        let key = || StashKey::open_stash("signed", "test").unwrap();
+        let mut stash = Stash::new(backend.clone(), key());
+        stash
+            .backup(&["tests/data/100_random_1k"], &BackupOptions::default())
+            .unwrap();
+        let root = key().root_object_id().unwrap();
+        let first = backend.read_object(&root).unwrap();
+        stash
+            .backup(&["tests/data/10k_random_blob"], &BackupOptions::default())
+            .unwrap();
+
+        let mut reader = Stash::new(backend.clone(), key());
+        reader.read().unwrap();
+        assert_eq!(reader.generation(), 2);
+
+        // the previous commit is still validly signed, but a rollback
+        let mut rolled_back = Stash::new(backend.clone(), key());
+        backend
+            .write_object(&Object::with_id(
+                root,
+                BlockBuffer::from(first.buffer.as_ref().to_vec()),
+            ))
+            .unwrap();
+        rolled_back.read().unwrap();
+        assert_eq!(rolled_back.snapshots().len(), 1);
+        let mut rolled_back = Stash::new(backend.clone(), key());
+        rolled_back.expect_generation(2);
+        assert!(matches!(
+            rolled_back.read(),
+            Err(ZerostashError::Tampered(_))
+        ));
+
+        // a root without its signature
+        let missing = key().signature_object_id(1).unwrap();
+        backend
+            .write_object(&Object::with_id(missing, BlockBuffer::from(vec![0; 8])))
+            .unwrap();
+        let mut swapped = Stash::new(backend, key());
+        assert!(matches!(swapped.read(), Err(ZerostashError::Tampered(_))));
+    }
+}
@@ -0,0 +1,152 @@
+use crate::files::Entry;
+use crate::snapshots::Snapshot;
+use crate::stash::{Change, Diff};
+
+use walkdir::WalkDir;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+/// What changed below `path` since `snapshot` was taken, like
+/// `git status`. Only the metadata of the files is compared to the
+/// snapshot, so nothing is read, from the files or from the stash.
+///
+/// `path` is as it was backed up, so files in the snapshot outside of
+/// it aren't removed. Modified files have the metadata they have now,
+/// and if their size and modification time are the same, the chunks
+/// they had, so only files that were probably written to have their
+/// contents changed. Links aren't followed, and files that can't be
+/// read are left out.
+pub fn status(snapshot: &Snapshot, path: impl AsRef<Path>) -> Diff {
+    let path = path.as_ref();
+    let root = path.to_string_lossy();
+    let root = root.trim_end_matches('/');
+    let mut before = snapshot
+        .files
+        .iter()
+        .filter(|f| {
+            f.name == root
+                || f.name
+                    .strip_prefix(root)
+                    .is_some_and(|rest| rest.starts_with('/'))
+        })
+        .map(|f| (f.name.as_str(), f.clone()))
+        .collect::<BTreeMap<_, _>>();
+
+    let mut changes = vec![];
+    for file in WalkDir::new(path).into_iter().filter_map(|e| e.ok()) {
+        let file_type = file.file_type();
+        let entry = if file_type.is_symlink() {
+            Entry::from_symlink(file.path())
+        } else if file_type.is_file() {
+            fs::File::open(file.path())
+                .map_err(Into::into)
+                .and_then(|f| Entry::from_file(&f, file.path()))
+        } else if file_type.is_dir() {
+            continue;
+        } else {
+            Entry::from_special(file.path())
+        };
+        let mut entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                warn!("skipping {:?}: {}", file.path(), e);
+                continue;
+            }
+        };
+
+        match before.remove(entry.name.as_str()) {
+            None => changes.push(Change::Added(Arc::new(entry))),
+            Some(old) => {
+                entry.hardlink = old.hardlink.clone();
+                if entry.size == old.size
+                    && (entry.unix_secs, entry.unix_nanos) == (old.unix_secs, old.unix_nanos)
+                {
+                    entry.chunks = old.chunks.clone();
+                }
+                if entry != *old {
+                    changes.push(Change::Modified {
+                        from: old,
+                        to: Arc::new(entry),
+                    });
+                }
+            }
+        }
+    }
+    changes.extend(before.into_values().map(Change::Removed));
+    changes.sort_by(|a, b| a.name().cmp(b.name()));
+
+    Diff { changes }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn the_tree_is_compared_to_a_snapshot() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use crate::stash::{BackupOptions, Stash, StashKey};
+        use std::env;
+        use std::time::{Duration, UNIX_EPOCH};
+
+        let dir = env::temp_dir().join("0s_test_status");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        let touch = |name: &str, data: &[u8], secs| {
+            let path = dir.join(name);
+            fs::write(&path, data).unwrap();
+            fs::File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(UNIX_EPOCH + Duration::from_secs(secs))
+                .unwrap();
+        };
+        touch("kept", b"same", 1);
+        touch("touched", b"same", 1);
+        touch("sub/grown", b"short", 1);
+        touch("gone", b"bye", 1);
+
+        let key = StashKey::open_stash("status", "test").unwrap();
+        let mut stash = Stash::new(Arc::new(MemoryBackend::default()), key);
+        let snapshot = stash.backup(&[&dir], &BackupOptions::default()).unwrap();
+        assert!(status(&snapshot, &dir).changes.is_empty());
+
+        touch("touched", b"same", 2);
+        touch("sub/grown", b"much longer", 2);
+        touch("new", b"hello", 2);
+        fs::remove_file(dir.join("gone")).unwrap();
+        let mut permissions = fs::metadata(dir.join("kept")).unwrap().permissions();
+        permissions.set_readonly(true);
+        fs::set_permissions(dir.join("kept"), permissions).unwrap();
+
+        let diff = status(&snapshot, &dir);
+        let changes = diff
+            .changes
+            .iter()
+            .map(|c| {
+                let name = c.name().strip_prefix(&*dir.to_string_lossy()).unwrap();
+                (name.to_string(), c.size_delta(), c.is_content_changed())
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            changes,
+            vec![
+                ("/gone".to_string(), -3, true),
+                ("/kept".to_string(), 0, false),
+                ("/new".to_string(), 5, true),
+                ("/sub/grown".to_string(), 6, true),
+                ("/touched".to_string(), 0, true),
+            ]
+        );
+        assert_eq!((diff.added(), diff.removed(), diff.modified()), (1, 1, 3));
+
+        // paths that weren't backed up aren't compared
+        let sub = status(&snapshot, dir.join("sub"));
+        assert_eq!(sub.changes.len(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
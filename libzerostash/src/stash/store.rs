@@ -1,53 +1,240 @@
+use crate::cache::{FileCache, FileState};
+use crate::cancel::CancelToken;
 use crate::chunks::ChunkStore;
-use crate::files::{self, FileStore};
-use crate::objects::ObjectStore;
-use crate::rollsum::SeaSplit;
-use crate::splitter::FileSplitter;
+use crate::files::{self, FileStore, Filter};
+use crate::limits;
+use crate::objects::{ObjectError, ObjectStore};
+use crate::splitter::ChunkingRules;
+use crate::stash::{ingest, Schedule, Symlinks};
+use crate::stats::{Collector, Stage};
 
 use crossbeam_utils::thread;
-use memmap::MmapOptions;
+use dashmap::DashMap;
+use memmap::{Mmap, MmapOptions};
 use walkdir::{DirEntry, WalkDir};
 
+use std::collections::HashSet;
+use std::error::Error;
 use std::fs;
+use std::io::{self, Read};
 use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+// Files smaller than this are read into a buffer, larger ones are mmap-ed
+const MMAP_THRESHOLD: u64 = 128 * 1024;
+
+// Schedules other than the walk reorder this many files at a time
+const SCHEDULE_WINDOW: usize = 64 * 1024;
+
+// Upper bounds of the size classes used for interleaving
+const SIZE_CLASSES: [u64; 3] = [64 * 1024, 1024 * 1024, 16 * 1024 * 1024];
+
+/// Files that are stored, with chunks in objects that may not be
+/// written yet, so they can't go in the file cache before.
+type Unsettled = Vec<(FileState, Arc<files::Entry>)>;
+
+/// The first error of the workers, after which the rest of the files
+/// are skipped.
+type Failed = Mutex<Option<ObjectError>>;
 
 type Sender = crossbeam_channel::Sender<DirEntry>;
 type Receiver = crossbeam_channel::Receiver<DirEntry>;
 
-#[allow(unused)]
+/// Files with more than one hard link, so that only the first path
+/// the workers see of each is read, and the others refer to it.
+#[derive(Default)]
+struct Hardlinks {
+    /// The first path of each device and inode
+    first: DashMap<(u64, u64), String>,
+    /// The entries of the first paths, once they are stored
+    stored: DashMap<String, Arc<files::Entry>>,
+    /// Later paths, which get the chunks of the first one at the end
+    pending: Mutex<Vec<files::Entry>>,
+}
+
+impl Hardlinks {
+    /// The first path of the file `name` is, if it's another one.
+    fn first_path(&self, metadata: &fs::Metadata, name: &str) -> Option<String> {
+        let key = link_key(metadata)?;
+        let first = self.first.entry(key).or_insert_with(|| name.to_string());
+        (*first != name).then(|| first.clone())
+    }
+
+    fn stored(&self, metadata: &fs::Metadata, entry: &Arc<files::Entry>) {
+        if link_key(metadata).is_some() {
+            self.stored.insert(entry.name.clone(), entry.clone());
+        }
+    }
+
+    /// Add the later paths to `fileindex`, with the chunks of the
+    /// first one.
+    fn finish(self, fileindex: &mut FileStore) {
+        let stored = self.stored;
+        for mut entry in self.pending.into_inner().unwrap() {
+            let first = entry.hardlink.as_ref().and_then(|name| stored.get(name));
+            match first {
+                Some(first) => {
+                    entry.size = first.size;
+                    entry.chunks = first.chunks.clone();
+                    drop(first);
+                    fileindex.push(entry);
+                }
+                None => warn!("skipping {:?}: its first link wasn't stored", entry.name),
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn link_key(metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+
+    (metadata.nlink() > 1).then(|| inode(metadata)).flatten()
+}
+
+#[cfg(not(unix))]
+fn link_key(_metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// FIFOs, sockets and devices.
+#[cfg(unix)]
+fn is_special(file_type: fs::FileType) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+
+    file_type.is_fifo()
+        || file_type.is_socket()
+        || file_type.is_char_device()
+        || file_type.is_block_device()
+}
+
+#[cfg(not(unix))]
+fn is_special(_file_type: fs::FileType) -> bool {
+    false
+}
+
+/// The device and inode of a file, which identify it.
+#[cfg(unix)]
+fn inode(metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn inode(_metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+#[allow(unused, clippy::too_many_arguments)]
 pub fn recursive(
     num_threads: usize,
+    schedule: Schedule,
+    symlinks: Symlinks,
+    filter: &Filter,
+    rules: &ChunkingRules,
     chunkindex: &mut ChunkStore,
     fileindex: &mut FileStore,
     objectstore: &mut (impl ObjectStore),
+    cache: Option<&FileCache>,
+    stats: &Collector,
+    cancel: &CancelToken,
     path: impl AsRef<Path>,
-) {
-    thread::scope(|s| {
+) -> Result<(), ObjectError> {
+    span!("backup", path = ?path.as_ref(), threads = num_threads);
+    let hardlinks = Hardlinks::default();
+    let failed = Failed::default();
+    let unsettled = thread::scope(|s| {
         let (sender, r) = crossbeam_channel::bounded::<DirEntry>(16 * num_threads);
 
-        for i in 0..(num_threads - 1) {
-            let receiver = r.clone();
-            let chunkindex = chunkindex.clone();
-            let fileindex = fileindex.clone();
-            let objectstore = objectstore.clone();
+        // the current thread is walking the tree, but make sure
+        // there's always at least one worker
+        let workers = (1..num_threads.max(2))
+            .map(|_| {
+                let receiver = r.clone();
+                let chunkindex = chunkindex.clone();
+                let fileindex = fileindex.clone();
+                let objectstore = objectstore.clone();
+                let (hardlinks, failed) = (&hardlinks, &failed);
 
-            s.spawn(move |_| process_file_loop(receiver, chunkindex, fileindex, objectstore));
-        }
+                s.spawn(move |_| {
+                    process_file_loop(
+                        receiver,
+                        rules,
+                        chunkindex,
+                        fileindex,
+                        objectstore,
+                        cache,
+                        hardlinks,
+                        stats,
+                        cancel,
+                        failed,
+                    )
+                })
+            })
+            .collect::<Vec<_>>();
 
         // we need sender to go out of scope
         // otherwise the channels never close
-        process_path(num_threads, sender, path);
+        process_path(
+            num_threads,
+            schedule,
+            symlinks,
+            filter,
+            sender,
+            stats,
+            cancel,
+            path,
+        );
+
+        workers
+            .into_iter()
+            .flat_map(|w| w.join().unwrap())
+            .collect::<Unsettled>()
     })
-    .unwrap()
+    .unwrap();
+
+    // every object is written now
+    if let Some(cache) = cache {
+        for (state, entry) in unsettled {
+            cache.insert(state, entry);
+        }
+    }
+    hardlinks.finish(fileindex);
+
+    match failed.into_inner().unwrap() {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn process_file_loop(
     receiver: Receiver,
+    rules: &ChunkingRules,
     chunkindex: ChunkStore,
     mut fileindex: FileStore,
     mut objectstore: impl ObjectStore,
-) {
+    cache: Option<&FileCache>,
+    hardlinks: &Hardlinks,
+    stats: &Collector,
+    cancel: &CancelToken,
+    failed: &Failed,
+) -> Unsettled {
+    let mut buffer = Vec::with_capacity(MMAP_THRESHOLD as usize);
+    let mut unsettled = Unsettled::new();
+
     for file in receiver.iter() {
+        if let Some(cache) = cache {
+            settle(cache, &mut unsettled, &objectstore);
+        }
+
+        // keep draining the queue, so the walk doesn't block
+        if cancel.is_cancelled() || failed.lock().unwrap().is_some() {
+            continue;
+        }
+
         let path = file.path();
 
         if file
@@ -55,113 +242,751 @@ fn process_file_loop(
             .components()
             .any(|c| c == std::path::Component::ParentDir)
         {
-            println!("skipping because contains parent {:?}", path);
+            warn!("skipping {:?}: path contains `..`", path);
+            continue;
+        }
+        stats.start_file(&path.to_string_lossy());
+        span!("file", path = ?path);
+
+        // links that aren't followed only record their target, and
+        // special files their metadata
+        let special = if file.file_type().is_symlink() {
+            Some(files::Entry::from_symlink(path))
+        } else if is_special(file.file_type()) {
+            Some(files::Entry::from_special(path))
+        } else {
+            None
+        };
+        if let Some(entry) = special {
+            match entry {
+                Ok(entry) if fileindex.has_changed(&entry) => {
+                    push_entry(&mut fileindex, None, &mut unsettled, entry);
+                }
+                Ok(_) => trace!("{:?} is already in the index", path),
+                Err(e) => warn!("skipping {:?}: {}", path, e),
+            }
+            stats.add_file(0);
             continue;
         }
 
-        let osfile = fs::File::open(path).unwrap();
-        let mut entry = files::Entry::from_file(&osfile, path).unwrap();
+        let read_start = Instant::now();
+        let permit = limits::open_file();
+        // files may vanish or change between the walk and here
+        let (osfile, metadata, mut entry) = match open(path) {
+            Ok(opened) => opened,
+            Err(e) => {
+                warn!("skipping {:?}: {}", path, e);
+                stats.add_time(Stage::Read, read_start.elapsed());
+                continue;
+            }
+        };
+        stats.add_file(entry.size);
+        event!(bytes = entry.size, "reading");
+
+        if let Some(first) = hardlinks.first_path(&metadata, &entry.name) {
+            trace!("{:?} is a hard link of {:?}", path, first);
+            entry.hardlink = Some(first);
+            hardlinks.pending.lock().unwrap().push(entry);
+            stats.add_time(Stage::Read, read_start.elapsed());
+            continue;
+        }
 
         if !fileindex.has_changed(&entry) {
+            trace!("{:?} is already in the index", path);
+            stats.add_time(Stage::Read, read_start.elapsed());
+            hardlinks.stored(&metadata, &Arc::new(entry));
             continue;
         }
 
+        let state = cache.map(|_| FileState::from_metadata(&metadata));
+        if let (Some(cache), Some(state)) = (cache, &state) {
+            let cached = cache
+                .get(&entry.name, state)
+                .filter(|cached| is_current(&chunkindex, cached));
+            if let Some(cached) = cached {
+                trace!("{:?} is unchanged since the last run", path);
+
+                // the chunks are already stored, but the index needs
+                // to know about them for deduplication
+                for (_, ptr) in cached.chunks.iter() {
+                    chunkindex.push(ptr.hash, || Ok(ptr.clone())).unwrap();
+                }
+
+                stats.add_time(Stage::Read, read_start.elapsed());
+                hardlinks.stored(&metadata, &cached);
+                fileindex.insert(cached);
+                continue;
+            }
+        }
+
         if entry.size == 0 {
-            fileindex.push(entry);
+            stats.add_time(Stage::Read, read_start.elapsed());
+            let entry = push_entry(&mut fileindex, state, &mut unsettled, entry);
+            hardlinks.stored(&metadata, &entry);
             continue;
         }
 
-        let mmap = unsafe {
-            // avoid an unnecessary fstat() by passing `len`
-            // directly from the previous call
-            MmapOptions::new()
-                .len(entry.size as usize)
-                .map(&osfile)
-                .unwrap()
+        let mut mmap = None;
+        let read = if entry.size < MMAP_THRESHOLD {
+            buffer.resize(entry.size as usize, 0);
+            (&osfile).read_exact(&mut buffer)
+        } else {
+            map_sequential(&osfile, entry.size as usize).map(|m| mmap = Some(m))
+        };
+        stats.add_time(Stage::Read, read_start.elapsed());
+        if let Err(e) = read {
+            warn!("skipping {:?}: {}", path, e);
+            continue;
+        }
+        let data: &[u8] = match &mmap {
+            Some(mmap) => mmap,
+            None => &buffer,
         };
 
-        for (start, hash, data) in FileSplitter::<SeaSplit>::new(&mmap) {
-            let chunkptr = chunkindex
-                .push(hash, || objectstore.store_chunk(&hash, data))
-                .unwrap();
+        // the data is in memory or mapped, so the file can be closed
+        // before storing chunks, which may need to open objects
+        drop(osfile);
+        drop(permit);
+
+        objectstore.set_compression(rules.compression.for_path(&entry.name));
+        let stored = ingest::store_data(
+            &rules.for_path(&entry.name),
+            &chunkindex,
+            &mut objectstore,
+            stats,
+            cancel,
+            &mut entry,
+            data,
+        );
+        if let Err(e) = stored {
+            warn!("failed to store {:?}: {}", path, e);
+            failed.lock().unwrap().get_or_insert(e);
+            continue;
+        }
 
-            entry.chunks.push((start, chunkptr));
+        // a file cut short is left out, so it's never cached
+        if cancel.is_cancelled() {
+            continue;
         }
 
-        fileindex.push(entry);
+        let entry = push_entry(&mut fileindex, state, &mut unsettled, entry);
+        hardlinks.stored(&metadata, &entry);
     }
 
-    objectstore.flush().unwrap();
+    if let Err(e) = objectstore.flush() {
+        failed.lock().unwrap().get_or_insert(e);
+        // nothing since the last object is written
+        return Unsettled::new();
+    }
+    if let Some(cache) = cache {
+        settle(cache, &mut unsettled, &objectstore);
+    }
+    unsettled
 }
 
-fn process_path(threads: usize, sender: Sender, path: impl AsRef<Path>) {
-    for entry in WalkDir::new(path.as_ref())
-        .max_open(threads)
+fn push_entry(
+    fileindex: &mut FileStore,
+    state: Option<FileState>,
+    unsettled: &mut Unsettled,
+    entry: files::Entry,
+) -> Arc<files::Entry> {
+    let entry = Arc::new(entry);
+    if let Some(state) = state {
+        unsettled.push((state, entry.clone()));
+    }
+
+    fileindex.insert(entry.clone());
+    entry
+}
+
+/// Cache the files whose chunks are all written, then save the cache
+/// if it's time for a checkpoint, so an interrupted run can resume
+/// after them.
+fn settle(cache: &FileCache, unsettled: &mut Unsettled, objectstore: &impl ObjectStore) {
+    let (open, written) = unsettled.drain(..).partition::<Unsettled, _>(|(_, entry)| {
+        entry
+            .chunks
+            .iter()
+            .any(|(_, cp)| objectstore.is_open(&cp.file))
+    });
+    *unsettled = open;
+    for (state, entry) in written {
+        cache.insert(state, entry);
+    }
+
+    if let Err(e) = cache.checkpoint() {
+        warn!("can't save the file cache: {}", e);
+    }
+}
+
+/// If the chunks of a cached entry are where the index has them, and
+/// not moved since.
+///
+/// Chunks missing from the index were stored by an interrupted run,
+/// and are taken as they are.
+fn is_current(chunkindex: &ChunkStore, entry: &files::Entry) -> bool {
+    entry
+        .chunks
+        .iter()
+        .all(|(_, ptr)| match chunkindex.index().get(&ptr.hash) {
+            Some(current) => current == *ptr,
+            None => true,
+        })
+}
+
+/// Open the file at `path` to store it.
+fn open(path: &Path) -> Result<(fs::File, fs::Metadata, files::Entry), Box<dyn Error>> {
+    let file = fs::File::open(path)?;
+    let metadata = file.metadata()?;
+    let entry = files::Entry::from_file(&file, path)?;
+    Ok((file, metadata, entry))
+}
+
+fn map_sequential(file: &fs::File, len: usize) -> io::Result<Mmap> {
+    // avoid an unnecessary fstat() by passing `len`
+    // directly from the previous call
+    let mmap = unsafe { MmapOptions::new().len(len).map(file)? };
+
+    // the splitter reads through the file exactly once, front to
+    // back, so the kernel can read ahead aggressively
+    #[cfg(unix)]
+    unsafe {
+        libc::madvise(
+            mmap.as_ptr() as *mut libc::c_void,
+            len,
+            libc::MADV_SEQUENTIAL,
+        );
+    }
+
+    Ok(mmap)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn process_path(
+    threads: usize,
+    schedule: Schedule,
+    symlinks: Symlinks,
+    filter: &Filter,
+    sender: Sender,
+    stats: &Collector,
+    cancel: &CancelToken,
+    path: impl AsRef<Path>,
+) {
+    let path = path.as_ref();
+    let walk = |follow| {
+        WalkDir::new(path)
+            .max_open(threads)
+            .follow_links(follow)
+            .same_file_system(filter.is_one_file_system())
+    };
+
+    // walks always enter a path that links to a directory, so it's
+    // either only recorded as a link, or its entry is of what it
+    // points to
+    let root_is_link = fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_symlink());
+    let (root, walk) = match symlinks {
+        Symlinks::Never if root_is_link => (None, walk(false).max_depth(0)),
+        Symlinks::Roots if root_is_link => (
+            Some(walk(true).max_depth(0).into_iter()),
+            walk(false).min_depth(1),
+        ),
+        Symlinks::All => (None, walk(true)),
+        _ => (None, walk(false)),
+    };
+
+    // when following links, the walk sees what they point to, and
+    // excluded directories aren't entered, but the path itself is
+    // always stored
+    let mut ignores = filter.ignores();
+    let mut visited = HashSet::new();
+    let walk = walk.into_iter().filter_entry(move |e| {
+        let is_dir = e.file_type().is_dir();
+        if symlinks == Symlinks::All && is_dir {
+            let key = e.metadata().ok().as_ref().and_then(inode);
+            if key.is_some_and(|key| !visited.insert(key)) {
+                debug!("skipping {:?}: the directory was already walked", e.path());
+                return false;
+            }
+        }
+
+        e.depth() == 0
+            || (filter.includes(e.path(), is_dir) && !ignores.is_ignored(e.path(), is_dir))
+    });
+    let entries = root
         .into_iter()
+        .flatten()
+        .chain(walk)
         .filter_map(Result::ok)
-        .filter(|e| e.path().is_file())
-    {
+        .filter(|e| {
+            let file_type = e.file_type();
+            file_type.is_file() || file_type.is_symlink() || is_special(file_type)
+        });
+
+    // the files stream from the walk to the workers, so memory stays
+    // bounded however many there are
+    let (sized, window) = match schedule {
+        Schedule::Walk => (false, 1),
+        _ => (true, SCHEDULE_WINDOW),
+    };
+    let files = entries.filter_map(|e| {
+        let size = if sized { e.metadata().ok()?.len() } else { 0 };
+        Some((size, e))
+    });
+    let mut files = scheduled(schedule, files, window);
+
+    while let Some(entry) = stats.time(Stage::Walk, || files.next()) {
+        if cancel.is_cancelled() {
+            return;
+        }
+        stats.add_discovered();
         sender.send(entry).unwrap();
     }
 }
 
+/// `files` in the order of `schedule`, reordering `window` of them
+/// at a time.
+fn scheduled<T>(
+    schedule: Schedule,
+    mut files: impl Iterator<Item = (u64, T)>,
+    window: usize,
+) -> impl Iterator<Item = T> {
+    std::iter::from_fn(move || {
+        let files = files.by_ref().take(window).collect::<Vec<_>>();
+        (!files.is_empty()).then(|| order(schedule, files))
+    })
+    .flatten()
+}
+
+fn order<T>(schedule: Schedule, mut files: Vec<(u64, T)>) -> Vec<T> {
+    match schedule {
+        Schedule::Walk => files.into_iter().map(|(_, f)| f).collect(),
+        Schedule::SmallFirst => {
+            files.sort_by_key(|(size, _)| *size);
+            files.into_iter().map(|(_, f)| f).collect()
+        }
+        Schedule::Interleave => {
+            let mut classes = (0..=SIZE_CLASSES.len())
+                .map(|_| vec![])
+                .collect::<Vec<Vec<T>>>();
+            let total = files.len();
+
+            // reverse, so popping from the back yields walk order
+            for (size, f) in files.into_iter().rev() {
+                let class = SIZE_CLASSES
+                    .iter()
+                    .position(|limit| size < *limit)
+                    .unwrap_or(SIZE_CLASSES.len());
+                classes[class].push(f);
+            }
+
+            let mut result = Vec::with_capacity(total);
+            while result.len() < total {
+                for class in classes.iter_mut() {
+                    result.extend(class.pop());
+                }
+            }
+
+            result
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    #[cfg(feature = "nightly")]
     extern crate test;
 
     const PATH_100: &str = "tests/data/100_random_1k";
 
+    #[test]
+    fn test_schedule_order() {
+        use super::*;
+
+        let files = vec![
+            (10_000_000, "big"),
+            (100, "small"),
+            (2_000_000, "large"),
+            (200_000, "medium"),
+            (50, "tiny"),
+        ];
+
+        assert_eq!(
+            order(Schedule::Walk, files.clone()),
+            vec!["big", "small", "large", "medium", "tiny"]
+        );
+        assert_eq!(
+            order(Schedule::SmallFirst, files.clone()),
+            vec!["tiny", "small", "medium", "large", "big"]
+        );
+        assert_eq!(
+            order(Schedule::Interleave, files.clone()),
+            vec!["small", "medium", "big", "tiny", "large"]
+        );
+
+        // windows are ordered one after the other
+        assert_eq!(
+            scheduled(Schedule::SmallFirst, files.into_iter(), 3).collect::<Vec<_>>(),
+            vec!["small", "large", "big", "tiny", "medium"]
+        );
+    }
+
     #[test]
     fn test_stats_add_up() {
+        use crate::cancel::CancelToken;
         use crate::chunks::*;
         use crate::files::*;
         use crate::objects::*;
-        use crate::stash::store;
+        use crate::stash::store::{self, ChunkingRules, Schedule, Symlinks};
+        use crate::stats::Collector;
 
         let mut cs = ChunkStore::default();
         let mut fs = FileStore::default();
         let mut s = NullStorage::default();
 
-        store::recursive(4, &mut cs, &mut fs, &mut s, PATH_100);
+        store::recursive(
+            4,
+            Schedule::default(),
+            Symlinks::Never,
+            &Filter::default(),
+            &ChunkingRules::default(),
+            &mut cs,
+            &mut fs,
+            &mut s,
+            None,
+            &Collector::default(),
+            &CancelToken::default(),
+            PATH_100,
+        )
+        .unwrap();
 
         assert_eq!(100, fs.index().len());
-        assert_eq!(1_024_000u64, fs.index().iter().map(|f| f.key().size).sum());
+        assert_eq!(
+            1_024_000u64,
+            fs.index().iter().map(|f| f.key().size).sum::<u64>()
+        );
     }
 
+    #[test]
+    fn test_cancelled_walk_stores_nothing() {
+        use crate::cancel::CancelToken;
+        use crate::chunks::*;
+        use crate::files::*;
+        use crate::objects::*;
+        use crate::stash::store::{self, ChunkingRules, Schedule, Symlinks};
+        use crate::stats::Collector;
+
+        let cancel = CancelToken::new();
+        cancel.cancel();
+
+        let mut fs = FileStore::default();
+        let mut s = NullStorage::default();
+        store::recursive(
+            4,
+            Schedule::default(),
+            Symlinks::Never,
+            &Filter::default(),
+            &ChunkingRules::default(),
+            &mut ChunkStore::default(),
+            &mut fs,
+            &mut s,
+            None,
+            &Collector::default(),
+            &cancel,
+            PATH_100,
+        )
+        .unwrap();
+
+        assert_eq!(0, fs.index().len());
+        assert_eq!(0, *s.0.lock().unwrap());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlink_policies() {
+        use crate::cancel::CancelToken;
+        use crate::chunks::*;
+        use crate::files::*;
+        use crate::objects::*;
+        use crate::stash::store::{self, ChunkingRules, Schedule, Symlinks};
+        use crate::stats::Collector;
+        use std::os::unix::fs::symlink;
+
+        let dir = std::env::temp_dir().join("0s_test_symlink_policies");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("data/sub")).unwrap();
+        std::fs::write(dir.join("data/sub/file"), b"contents").unwrap();
+        symlink("..", dir.join("data/sub/loop")).unwrap();
+        symlink("data", dir.join("root")).unwrap();
+
+        let walk = |symlinks| {
+            let mut fs = FileStore::default();
+            store::recursive(
+                2,
+                Schedule::default(),
+                symlinks,
+                &Filter::default(),
+                &ChunkingRules::default(),
+                &mut ChunkStore::default(),
+                &mut fs,
+                &mut NullStorage::default(),
+                None,
+                &Collector::default(),
+                &CancelToken::default(),
+                dir.join("root"),
+            )
+            .unwrap();
+            let mut files = fs
+                .index()
+                .iter()
+                .map(|f| {
+                    let name = f.key().name.strip_prefix(dir.to_str().unwrap()).unwrap();
+                    (name.to_string(), f.key().symlink.is_some())
+                })
+                .collect::<Vec<_>>();
+            files.sort();
+            files
+        };
+
+        let entry = |name: &str, link| (name.to_string(), link);
+        assert_eq!(walk(Symlinks::Never), vec![entry("/root", true)]);
+        assert_eq!(
+            walk(Symlinks::Roots),
+            vec![
+                entry("/root/sub/file", false),
+                entry("/root/sub/loop", true)
+            ]
+        );
+        // the loop leads back to where the walk started
+        assert_eq!(walk(Symlinks::All), vec![entry("/root/sub/file", false)]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_file_cache_skips_unchanged_files() {
+        use crate::cache::FileCache;
+        use crate::cancel::CancelToken;
+        use crate::chunks::*;
+        use crate::files::*;
+        use crate::objects::*;
+        use crate::stash::store::{self, ChunkingRules, Schedule, Symlinks};
+        use crate::stats::Collector;
+
+        let path = std::env::temp_dir().join("0s_test_store_cache");
+        let cache = FileCache::open(&path, [0; 32]).unwrap();
+
+        let mut s = NullStorage::default();
+        store::recursive(
+            4,
+            Schedule::default(),
+            Symlinks::Never,
+            &Filter::default(),
+            &ChunkingRules::default(),
+            &mut ChunkStore::default(),
+            &mut FileStore::default(),
+            &mut s,
+            Some(&cache),
+            &Collector::default(),
+            &CancelToken::default(),
+            PATH_100,
+        )
+        .unwrap();
+        assert_eq!(1_024_000, *s.0.lock().unwrap());
+        cache.save().unwrap();
+
+        let cache = FileCache::open(&path, [0; 32]).unwrap();
+        let mut fs = FileStore::default();
+        let mut s = NullStorage::default();
+        store::recursive(
+            4,
+            Schedule::default(),
+            Symlinks::Never,
+            &Filter::default(),
+            &ChunkingRules::default(),
+            &mut ChunkStore::default(),
+            &mut fs,
+            &mut s,
+            Some(&cache),
+            &Collector::default(),
+            &CancelToken::default(),
+            PATH_100,
+        )
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(100, fs.index().len());
+        assert_eq!(0, *s.0.lock().unwrap());
+    }
+
+    #[test]
+    fn test_file_cache_rereads_moved_chunks() {
+        use crate::cache::FileCache;
+        use crate::cancel::CancelToken;
+        use crate::chunks::*;
+        use crate::files::*;
+        use crate::objects::*;
+        use crate::stash::store::{self, ChunkingRules, Schedule, Symlinks};
+        use crate::stats::Collector;
+
+        let path = std::env::temp_dir().join("0s_test_store_cache_moved");
+        let cache = FileCache::open(&path, [0; 32]).unwrap();
+        let run = |cs: &mut ChunkStore, cache: &FileCache| {
+            let mut s = NullStorage::default();
+            store::recursive(
+                4,
+                Schedule::default(),
+                Symlinks::Never,
+                &Filter::default(),
+                &ChunkingRules::default(),
+                cs,
+                &mut FileStore::default(),
+                &mut s,
+                Some(cache),
+                &Collector::default(),
+                &CancelToken::default(),
+                PATH_100,
+            )
+            .unwrap();
+            let stored = *s.0.lock().unwrap();
+            stored
+        };
+        assert_eq!(1_024_000, run(&mut ChunkStore::default(), &cache));
+        cache.save().unwrap();
+
+        // the chunks the cache points to are somewhere else now, like
+        // after a repack
+        let cache = FileCache::open(&path, [0; 32]).unwrap();
+        let mut cs = ChunkStore::default();
+        let moved = ChunkPointer {
+            offs: 1,
+            ..ChunkPointer::default()
+        };
+        cs.index().insert(moved.hash, &moved);
+        assert_eq!(1_024_000, run(&mut cs, &cache));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_large_file_chunks_cover_file() {
+        use crate::cancel::CancelToken;
+        use crate::chunks::*;
+        use crate::crypto::chunk_hash;
+        use crate::files::*;
+        use crate::objects::*;
+        use crate::stash::store::{self, ChunkingRules, Schedule, Symlinks};
+        use crate::stats::Collector;
+
+        let dir = std::env::temp_dir().join("0s_test_large_file");
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let size = 4 * super::MMAP_THRESHOLD as usize;
+        let data = (0..size / 32)
+            .flat_map(|i| chunk_hash(&i.to_le_bytes()).to_vec())
+            .collect::<Vec<u8>>();
+        std::fs::write(dir.join("large"), &data).unwrap();
+
+        let mut cs = ChunkStore::default();
+        let mut fs = FileStore::default();
+        let mut s = NullStorage::default();
+
+        store::recursive(
+            4,
+            Schedule::default(),
+            Symlinks::Never,
+            &Filter::default(),
+            &ChunkingRules::default(),
+            &mut cs,
+            &mut fs,
+            &mut s,
+            None,
+            &Collector::default(),
+            &CancelToken::default(),
+            &dir,
+        )
+        .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let file = fs.index().iter().next().unwrap().key().clone();
+        assert_eq!(size as u64, file.size);
+        assert_eq!(size, *s.0.lock().unwrap());
+    }
+
+    #[cfg(feature = "nightly")]
     #[bench]
     fn bench_chunk_saturated_e2e(b: &mut test::Bencher) {
+        use crate::cancel::CancelToken;
         use crate::chunks::*;
         use crate::files::*;
         use crate::objects::*;
-        use crate::stash::store;
+        use crate::stash::store::{self, ChunkingRules, Schedule, Symlinks};
+        use crate::stats::Collector;
 
         let mut cs = ChunkStore::default();
         let mut os = NullStorage::default();
         let mut fs = FileStore::default();
 
         // first build up the file index
-        store::recursive(4, &mut cs, &mut fs, &mut os, PATH_100);
+        store::recursive(
+            4,
+            Schedule::default(),
+            Symlinks::Never,
+            &Filter::default(),
+            &ChunkingRules::default(),
+            &mut cs,
+            &mut fs,
+            &mut os,
+            None,
+            &Collector::default(),
+            &CancelToken::default(),
+            PATH_100,
+        )
+        .unwrap();
 
         b.iter(|| {
-            store::recursive(4, &mut cs, &mut fs, &mut os, PATH_100);
+            store::recursive(
+                4,
+                Schedule::default(),
+                Symlinks::Never,
+                &Filter::default(),
+                &ChunkingRules::default(),
+                &mut cs,
+                &mut fs,
+                &mut os,
+                None,
+                &Collector::default(),
+                &CancelToken::default(),
+                PATH_100,
+            )
+            .unwrap();
         })
     }
 
+    #[cfg(feature = "nightly")]
     #[bench]
     fn bench_chunk_e2e(b: &mut test::Bencher) {
+        use crate::cancel::CancelToken;
         use crate::chunks::*;
         use crate::files::*;
         use crate::objects::*;
-        use crate::stash::store;
+        use crate::stash::store::{self, ChunkingRules, Schedule, Symlinks};
+        use crate::stats::Collector;
 
         b.iter(|| {
             store::recursive(
                 4,
+                Schedule::default(),
+                Symlinks::Never,
+                &Filter::default(),
+                &ChunkingRules::default(),
                 &mut ChunkStore::default(),
                 &mut FileStore::default(),
                 &mut NullStorage::default(),
+                None,
+                &Collector::default(),
+                &CancelToken::default(),
                 PATH_100,
             )
+            .unwrap()
         })
     }
 }
@@ -0,0 +1,264 @@
+use crate::error::Result;
+use crate::files::Entry;
+use crate::meta;
+use crate::snapshots::Snapshot;
+use crate::stash::{FileReader, Stash};
+
+use crossbeam_channel::{Receiver, Sender, TryRecvError};
+
+use std::future::Future;
+use std::io::{self, Read};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+/// Items that wait in a stream for the consumer to take them
+const BUFFER: usize = 64;
+/// The size of the pieces of files read by a stream
+const PIECE: usize = 64 * 1024;
+/// Pieces of a file that wait in a stream, so at most 1 MiB is read
+/// ahead
+const PIECES: usize = 16;
+
+/// Items produced on a thread of their own, for async applications to
+/// take one at a time.
+///
+/// Only a few items are produced ahead, then the thread waits for the
+/// consumer to take them, and stops once the stream is dropped.
+/// `poll_next` is that of `futures::Stream`, which it's trivial to
+/// implement with, and the stream is also an `Iterator` that blocks,
+/// for everyone else.
+pub struct Stream<T> {
+    receiver: Receiver<T>,
+    waker: Arc<Mutex<Option<Waker>>>,
+}
+
+// nothing in a stream is pinned, the items are moved out
+impl<T> Unpin for Stream<T> {}
+
+/// The producing side of a `Stream`.
+struct Feed<T> {
+    sender: Option<Sender<T>>,
+    waker: Arc<Mutex<Option<Waker>>>,
+}
+
+impl<T> Feed<T> {
+    /// Wait for room in the stream for `item`. Returns false once the
+    /// stream is dropped, and the rest isn't needed.
+    fn send(&self, item: T) -> bool {
+        let sent = self.sender.as_ref().is_some_and(|s| s.send(item).is_ok());
+        self.wake();
+        sent
+    }
+
+    fn wake(&self) {
+        if let Some(waker) = self.waker.lock().unwrap().take() {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Drop for Feed<T> {
+    fn drop(&mut self) {
+        // the stream only ends once it's woken after the disconnect
+        self.sender.take();
+        self.wake();
+    }
+}
+
+impl<T: Send + 'static> Stream<T> {
+    fn spawn(buffer: usize, produce: impl FnOnce(Feed<T>) + Send + 'static) -> Stream<T> {
+        let (sender, receiver) = crossbeam_channel::bounded(buffer);
+        let waker = Arc::new(Mutex::new(None));
+        let feed = Feed {
+            sender: Some(sender),
+            waker: waker.clone(),
+        };
+        thread::spawn(move || produce(feed));
+
+        Stream { receiver, waker }
+    }
+
+    fn from_iter(iter: impl Iterator<Item = T> + Send + 'static) -> Stream<T> {
+        Stream::spawn(BUFFER, move |feed| {
+            for item in iter {
+                if !feed.send(item) {
+                    return;
+                }
+            }
+        })
+    }
+}
+
+impl<T> Stream<T> {
+    /// The next item, or `None` at the end, once it's there.
+    pub fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        match self.receiver.try_recv() {
+            Ok(item) => return Poll::Ready(Some(item)),
+            Err(TryRecvError::Disconnected) => return Poll::Ready(None),
+            Err(TryRecvError::Empty) => {}
+        }
+
+        // look again, in case the item came before the waker
+        *self.waker.lock().unwrap() = Some(cx.waker().clone());
+        match self.receiver.try_recv() {
+            Ok(item) => Poll::Ready(Some(item)),
+            Err(TryRecvError::Disconnected) => Poll::Ready(None),
+            Err(TryRecvError::Empty) => Poll::Pending,
+        }
+    }
+
+    /// A future of the next item, or of `None` at the end. The
+    /// `Iterator` waits for it instead.
+    pub fn recv(&mut self) -> Recv<'_, T> {
+        Recv(self)
+    }
+}
+
+impl<T> Iterator for Stream<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.receiver.recv().ok()
+    }
+}
+
+/// The future returned by `Stream::recv`.
+pub struct Recv<'a, T>(&'a mut Stream<T>);
+
+impl<T> Future for Recv<'_, T> {
+    type Output = Option<T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        Pin::new(&mut *self.0).poll_next(cx)
+    }
+}
+
+impl Stash {
+    /// Stream the snapshots, oldest first.
+    pub fn snapshot_stream(&mut self) -> Result<Stream<Arc<Snapshot>>> {
+        self.load(meta::Field::Snapshots)?;
+        Ok(Stream::from_iter(self.snapshots().into_iter()))
+    }
+
+    /// Stream the files in the index that match any of the `glob`
+    /// patterns, or all of them without any, like `list`.
+    pub fn file_stream(&mut self, glob: &[impl AsRef<str>]) -> Result<Stream<Arc<Entry>>> {
+        self.load(meta::Field::Files)?;
+        let matchers = glob
+            .iter()
+            .map(|g| glob::Pattern::new(g.as_ref()))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+
+        // a stream being consumed shouldn't hold locks on the index,
+        // only the matching is left for its thread
+        let files = self
+            .files
+            .index()
+            .iter()
+            .map(|f| f.key().clone())
+            .collect::<Vec<_>>();
+        Ok(Stream::from_iter(files.into_iter().filter(move |f| {
+            matchers.is_empty() || matchers.iter().any(|m| m.matches(&f.name))
+        })))
+    }
+}
+
+impl FileReader {
+    /// Stream the rest of the file in pieces, read ahead only as far
+    /// as the consumer keeps up.
+    pub fn into_stream(mut self) -> Stream<io::Result<Vec<u8>>> {
+        Stream::spawn(PIECES, move |feed| loop {
+            let mut piece = vec![0; PIECE];
+            let read = match self.read(&mut piece) {
+                Ok(0) => return,
+                Ok(read) => read,
+                Err(e) => {
+                    feed.send(Err(e));
+                    return;
+                }
+            };
+            piece.truncate(read);
+            if !feed.send(Ok(piece)) {
+                return;
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+    use std::sync::Arc;
+    use std::task::{Context, Poll, Wake, Waker};
+    use std::thread::{self, Thread};
+
+    struct Unpark(Thread);
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    /// Run `future` to completion on the current thread.
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = Box::pin(future);
+        let waker = Waker::from(Arc::new(Unpark(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+
+        loop {
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(output) => return output,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    #[test]
+    fn streams_are_consumed_incrementally() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use crate::snapshots::Labels;
+        use crate::stash::StashKey;
+
+        let key = StashKey::open_stash("stream", "test").unwrap();
+        let mut stash = Stash::new(Arc::new(MemoryBackend::default()), key);
+        let contents = (0..3 * PIECE + 7).map(|i| i as u8).collect::<Vec<_>>();
+        for name in &["a.txt", "b.txt", "c.bin"] {
+            let mut ingest = stash.ingest().unwrap();
+            ingest
+                .add_file(Entry::from_stream(*name), &contents)
+                .unwrap();
+            ingest.finish(0, vec![], vec![], Labels::new()).unwrap();
+        }
+
+        let mut snapshots = stash.snapshot_stream().unwrap();
+        let mut count = 0;
+        while block_on(snapshots.recv()).is_some() {
+            count += 1;
+        }
+        assert_eq!(count, 3);
+
+        let mut names = stash
+            .file_stream(&["*.txt"])
+            .unwrap()
+            .map(|f| f.name.clone())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["a.txt", "b.txt"]);
+
+        let mut pieces = stash.open_file("c.bin").unwrap().into_stream();
+        let mut read = vec![];
+        while let Some(piece) = block_on(pieces.recv()) {
+            read.extend(piece.unwrap());
+        }
+        assert_eq!(read, contents);
+
+        // and streams can be left before their end
+        let mut pieces = stash.open_file("c.bin").unwrap().into_stream();
+        assert_eq!(block_on(pieces.recv()).unwrap().unwrap().len(), PIECE);
+        drop(pieces);
+    }
+}
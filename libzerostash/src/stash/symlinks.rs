@@ -0,0 +1,29 @@
+use std::str::FromStr;
+
+/// How backups treat symbolic links.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Symlinks {
+    /// Store links as links, including the paths of the backup
+    #[default]
+    Never,
+    /// Store what the paths of the backup point to, if they are
+    /// links, but links below them as links
+    Roots,
+    /// Store what all links point to. Directories that were already
+    /// walked through another link are skipped, so loops end
+    All,
+}
+
+impl FromStr for Symlinks {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "never" => Ok(Symlinks::Never),
+            "roots" => Ok(Symlinks::Roots),
+            "all" => Ok(Symlinks::All),
+            _ => Err(format!("unknown symlink policy: {}", s)),
+        }
+    }
+}
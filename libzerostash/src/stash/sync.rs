@@ -0,0 +1,329 @@
+use crate::chunks::ChunkPointer;
+use crate::compress;
+use crate::crypto::{CryptoDigest, CryptoProvider};
+use crate::error::{Result, ZerostashError};
+use crate::format;
+use crate::meta;
+use crate::objects::{self, BlockBuffer, Object, ObjectId, ObjectStore};
+use crate::progress::Phase;
+use crate::snapshots::Snapshot;
+use crate::stash::Stash;
+use crate::stats::Collector;
+use crate::BLOCK_SIZE;
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+/// Chunks by the object holding them, with their size before
+/// compression
+type Missing = HashMap<ObjectId, HashMap<CryptoDigest, (Arc<ChunkPointer>, usize)>>;
+
+/// What `sync` copied to the destination.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Synced {
+    pub snapshots: u64,
+    /// Chunks the destination didn't have yet
+    pub chunks: u64,
+}
+
+/// Copy the snapshots of `src` that `dst` doesn't have yet, and
+/// commit `dst`. The snapshots get new ids in `dst`.
+///
+/// Only chunks missing from `dst` are transferred. If both stashes
+/// use the same key, or the same cipher and convergence secret, the
+/// objects holding them are copied as they are. Otherwise the chunks
+/// are decrypted, and stored again with the key of `dst`.
+///
+/// Snapshots are matched by their time, paths and contents, so
+/// syncing again only copies the snapshots taken since.
+pub fn sync(src: &mut Stash, dst: &mut Stash) -> Result<Synced> {
+    copy(src, dst, |_| true)
+}
+
+impl Stash {
+    /// Copy the snapshots with the ids in `snapshots` to `dst`, like
+    /// `sync`, and commit `dst`. Snapshots `dst` already has, and ids
+    /// the stash has no snapshot with, are skipped.
+    ///
+    /// This adds an off-site copy of some snapshots after the fact,
+    /// or moves them off a failing backend to another.
+    pub fn copy_to(&mut self, dst: &mut Stash, snapshots: &[u64]) -> Result<Synced> {
+        copy(self, dst, |s| snapshots.contains(&s.id))
+    }
+}
+
+fn copy(src: &mut Stash, dst: &mut Stash, wanted: impl Fn(&Snapshot) -> bool) -> Result<Synced> {
+    src.load(meta::Field::Snapshots)?;
+    dst.load(meta::Field::Files)?;
+    dst.load(meta::Field::Chunks)?;
+    dst.load(meta::Field::Snapshots)?;
+
+    let present = dst
+        .snapshots
+        .list()
+        .iter()
+        .map(|s| s.digest())
+        .collect::<HashSet<_>>();
+    let snapshots = src
+        .snapshots
+        .list()
+        .into_iter()
+        .filter(|s| wanted(s) && !present.contains(&s.digest()))
+        .collect::<Vec<_>>();
+    if snapshots.is_empty() {
+        return Ok(Synced::default());
+    }
+
+    let mut missing = Missing::new();
+    for file in snapshots.iter().flat_map(|s| s.files.iter()) {
+        for (i, (start, cp)) in file.chunks.iter().enumerate() {
+            if dst.chunks.index().get(&cp.hash).is_some() {
+                continue;
+            }
+
+            let end = file.chunks.get(i + 1).map(|(s, _)| *s).unwrap_or(file.size);
+            missing
+                .entry(cp.file)
+                .or_default()
+                .insert(cp.hash, (cp.clone(), (end - start) as usize));
+        }
+    }
+
+    let chunks = missing.values().map(|c| c.len() as u64).sum();
+    debug!(
+        "syncing {} snapshots, with {} chunks in {} objects",
+        snapshots.len(),
+        chunks,
+        missing.len()
+    );
+    dst.progress.phase(Phase::Store, Some(missing.len() as u64));
+
+    let same_family = src.convergence().is_some()
+        && src.convergence() == dst.convergence()
+        && src.cipher() == dst.cipher();
+    if same_family || src.master_key.root_object_id()? == dst.master_key.root_object_id()? {
+        copy_objects(src, dst, missing)?;
+    } else {
+        reencrypt_chunks(src, dst, missing)?;
+    }
+
+    for snapshot in snapshots.iter() {
+        let files = snapshot
+            .files
+            .iter()
+            .map(|f| {
+                let mut entry = (**f).clone();
+                for (_, cp) in entry.chunks.iter_mut() {
+                    if let Some(pointer) = dst.chunks.index().get(&cp.hash) {
+                        *cp = pointer;
+                    }
+                }
+
+                let entry = Arc::new(entry);
+                dst.files.insert(entry.clone());
+                entry
+            })
+            .collect();
+
+        let copy = dst.snapshots.push_at(
+            snapshot.unix_secs,
+            snapshot.paths.clone(),
+            snapshot.tags.clone(),
+            snapshot.labels.clone(),
+            files,
+        );
+        // the manifest doesn't depend on where the chunks are stored
+        if let Some(manifest) = &snapshot.manifest {
+            dst.snapshots.set_manifest(copy.id, manifest.clone());
+        }
+    }
+
+    dst.commit()?;
+    Ok(Synced {
+        snapshots: snapshots.len() as u64,
+        chunks,
+    })
+}
+
+/// Copy the encrypted objects, which `dst` can read with the same
+/// chunk keys. They keep the size of `src`.
+fn copy_objects(src: &Stash, dst: &Stash, missing: Missing) -> Result<()> {
+    for (id, chunks) in missing {
+        let read = src.backend.read_object(&id)?;
+        let size = read.buffer.as_ref().len();
+        if format::check_object_size(size).is_err() {
+            return Err(ZerostashError::Corrupt { object: id });
+        }
+
+        let object = Object::with_id(id, BlockBuffer::from(read.buffer.as_ref().to_vec()));
+        dst.backend.write_data_object(&object)?;
+
+        for (hash, (cp, _)) in chunks {
+            dst.chunks.index().insert(hash, &cp);
+        }
+        dst.progress.item(size as u64);
+    }
+
+    Ok(())
+}
+
+/// Decrypt the chunks with the key of `src`, and store them in new
+/// objects of `dst`.
+fn reencrypt_chunks(src: &Stash, dst: &Stash, missing: Missing) -> Result<()> {
+    let crypto = src.master_key.get_object_crypto()?;
+    let storage = objects::Storage::new(
+        dst.backend.clone(),
+        dst.master_key.get_object_crypto()?,
+        Arc::new(Collector::new(dst.progress.clone())),
+    )
+    .object_size(dst.object_size)
+    .compression(dst.compression)
+    .tuning(dst.tuning);
+    let mut storage = dst.upload_with(storage, dst.backup_threads(1).1);
+    let mut buffer = vec![0; BLOCK_SIZE];
+    let mut plain = vec![];
+
+    for (id, chunks) in missing.iter() {
+        let object = src.backend.read_object(id)?;
+        for (hash, (cp, size)) in chunks.iter() {
+            let len = crypto
+                .decrypt_chunk(&mut buffer, &object, cp)
+                .map_err(|_| ZerostashError::Corrupt { object: *id })?;
+            plain.resize(*size, 0);
+            compress::unpack_into(&mut plain, &buffer[..len])?;
+
+            dst.chunks
+                .push(*hash, || storage.store_chunk(hash, &plain))?;
+        }
+        dst.progress.item(object.buffer.as_ref().len() as u64);
+    }
+
+    if !missing.is_empty() {
+        storage.flush()?;
+    }
+    Ok(())
+}
+
+#[cfg(all(test, feature = "fs"))]
+mod tests {
+    #[test]
+    fn sync_copies_only_missing_chunks() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use crate::stash::{BackupOptions, CancelToken, StashKey};
+
+        let key = |user| StashKey::open_stash(user, "test").unwrap();
+        let mut src = Stash::new(Arc::new(MemoryBackend::default()), key("src"));
+        src.backup(&["tests/data/100_random_1k"], &BackupOptions::default())
+            .unwrap();
+
+        // with the same key, objects are copied
+        let copy_backend = Arc::new(MemoryBackend::default());
+        let mut copy = Stash::new(copy_backend.clone(), key("src"));
+        let synced = sync(&mut src, &mut copy).unwrap();
+        assert_eq!(synced.snapshots, 1);
+        assert_eq!(synced.chunks, src.chunk_index().len() as u64);
+        assert_eq!(sync(&mut src, &mut copy).unwrap(), Synced::default());
+
+        // with another key, chunks are encrypted again, except the
+        // ones the other stash has from a snapshot of its own
+        let dir = std::env::temp_dir().join("0s_test_sync");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::copy("tests/data/10k_random_blob", dir.join("blob")).unwrap();
+        let mut other = Stash::new(Arc::new(MemoryBackend::default()), key("other"));
+        other.backup(&[&dir], &BackupOptions::default()).unwrap();
+        let first = src.chunk_index().len() as u64;
+        src.backup(&["tests/data/10k_random_blob"], &BackupOptions::default())
+            .unwrap();
+
+        let synced = sync(&mut src, &mut other).unwrap();
+        assert_eq!(synced.snapshots, 2);
+        assert_eq!(synced.chunks, first);
+        assert_eq!(other.snapshots().len(), 3);
+        assert_eq!(
+            other.verify(&CancelToken::default()).unwrap(),
+            src.chunk_index().len() as u64
+        );
+
+        let mut copy = Stash::new(copy_backend, key("src"));
+        copy.read().unwrap();
+        assert_eq!(copy.snapshots()[0].files.len(), 100);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn only_chosen_snapshots_are_copied() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use crate::stash::{BackupOptions, StashKey};
+
+        let key = |user| StashKey::open_stash(user, "test").unwrap();
+        let mut src = Stash::new(Arc::new(MemoryBackend::default()), key("src"));
+        src.backup(&["tests/data/100_random_1k"], &BackupOptions::default())
+            .unwrap();
+        src.backup(&["tests/data/10k_random_blob"], &BackupOptions::default())
+            .unwrap();
+        let second = src.snapshots()[1].clone();
+
+        let mut dst = Stash::new(Arc::new(MemoryBackend::default()), key("dst"));
+        let copied = src.copy_to(&mut dst, &[second.id, 42]).unwrap();
+        assert_eq!(copied.snapshots, 1);
+        assert_eq!(dst.snapshots().len(), 1);
+        assert_eq!(dst.snapshots()[0].digest(), second.digest());
+        assert_eq!(dst.chunk_index().len() as u64, copied.chunks);
+        assert_eq!(
+            src.copy_to(&mut dst, &[second.id]).unwrap(),
+            Synced::default()
+        );
+
+        // the rest follows with a sync
+        assert_eq!(sync(&mut src, &mut dst).unwrap().snapshots, 1);
+        assert_eq!(dst.snapshots().len(), 2);
+    }
+
+    #[test]
+    fn stashes_of_a_family_share_objects() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use crate::crypto::ConvergenceSecret;
+        use crate::format::FormatError;
+        use crate::stash::{BackupOptions, CancelToken, StashKey};
+
+        let family = ConvergenceSecret::generate();
+        let key = |user| StashKey::open_stash(user, "test").unwrap();
+        let mut src = Stash::new(Arc::new(MemoryBackend::default()), key("laptop"));
+        src.set_convergence(family.clone());
+        src.backup(&["tests/data/100_random_1k"], &BackupOptions::default())
+            .unwrap();
+
+        let backend = Arc::new(MemoryBackend::default());
+        let mut dst = Stash::new(backend.clone(), key("desktop"));
+        dst.set_convergence(family.clone());
+        let synced = sync(&mut src, &mut dst).unwrap();
+        assert_eq!(synced.chunks, src.chunk_index().len() as u64);
+
+        // the objects are copied as they are
+        let hash = src.snapshots()[0].files[0].chunks[0].1.hash;
+        assert_eq!(
+            dst.chunk_index().get(&hash).unwrap().file,
+            src.chunk_index().get(&hash).unwrap().file
+        );
+
+        // the secret is recorded, and can't be swapped
+        let mut dst = Stash::new(backend.clone(), key("desktop"));
+        dst.read().unwrap();
+        assert_eq!(dst.convergence(), Some(&family));
+        assert!(dst.verify(&CancelToken::default()).unwrap() > 0);
+
+        let mut other = Stash::new(backend, key("desktop"));
+        other.set_convergence(ConvergenceSecret::generate());
+        assert!(matches!(
+            other.read(),
+            Err(ZerostashError::Incompatible {
+                source: FormatError::Family(_)
+            })
+        ));
+    }
+}
@@ -0,0 +1,188 @@
+use crate::crypto::CryptoDigest;
+use crate::error::Result;
+use crate::files::Entry;
+use crate::meta;
+use crate::stash::Stash;
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+/// How much a stash holds, and how well it deduplicates and
+/// compresses, found from its indexes alone.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Usage {
+    /// Bytes of every version of every file a restore could bring
+    /// back: the files of each snapshot, and those of the file index
+    /// that aren't in any
+    pub logical_bytes: u64,
+    /// Bytes of the distinct chunks files refer to, before
+    /// compression
+    pub unique_bytes: u64,
+    /// Bytes of all chunks in the chunk index as they're stored,
+    /// compressed and encrypted. Chunks that are unreferenced, but
+    /// not collected yet, are counted
+    pub stored_bytes: u64,
+    pub chunks: u64,
+    /// Data objects the chunks are in
+    pub objects: u64,
+    /// Oldest first
+    pub snapshots: Vec<SnapshotUsage>,
+}
+
+/// What one snapshot takes up of a stash.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SnapshotUsage {
+    pub id: u64,
+    pub unix_secs: u64,
+    pub files: u64,
+    /// Bytes of its files
+    pub logical_bytes: u64,
+    /// Stored bytes of the distinct chunks of its files
+    pub stored_bytes: u64,
+    /// Stored bytes of the chunks no other snapshot refers to, about
+    /// what removing it alone would free
+    pub exclusive_bytes: u64,
+}
+
+impl Usage {
+    /// How many times the unique data is referred to, counting
+    /// repeats across files and snapshots.
+    pub fn dedup_ratio(&self) -> f64 {
+        ratio(self.logical_bytes, self.unique_bytes)
+    }
+
+    /// How much larger the unique data is than what's stored of it.
+    pub fn compression_ratio(&self) -> f64 {
+        ratio(self.unique_bytes, self.stored_bytes)
+    }
+}
+
+fn ratio(a: u64, b: u64) -> f64 {
+    if b == 0 {
+        return 1.0;
+    }
+    a as f64 / b as f64
+}
+
+impl Stash {
+    /// Sizes, counts and ratios of what the stash holds, and of each
+    /// snapshot. Only the indexes are read, never the data objects,
+    /// so this is cheap enough to run for monitoring.
+    pub fn stats(&mut self) -> Result<Usage> {
+        self.load(meta::Field::Files)?;
+        self.load(meta::Field::Chunks)?;
+        self.load(meta::Field::Snapshots)?;
+
+        let mut usage = Usage::default();
+        let mut objects = HashSet::new();
+        let mut stored = HashMap::new();
+        self.chunks.index().for_each(|hash, cp| {
+            usage.stored_bytes += u64::from(cp.size);
+            objects.insert(cp.file);
+            stored.insert(*hash, u64::from(cp.size));
+        });
+        usage.chunks = stored.len() as u64;
+        usage.objects = objects.len() as u64;
+
+        // the plain size of a chunk is only known from where it starts
+        // in a file, and where the next one does
+        let mut unique = HashMap::new();
+        let mut add_chunks = |entry: &Entry| {
+            let ends = entry.chunks.iter().skip(1).map(|(start, _)| *start);
+            for ((start, cp), end) in entry.chunks.iter().zip(ends.chain(Some(entry.size))) {
+                unique.insert(cp.hash, end.saturating_sub(*start));
+            }
+        };
+
+        let snapshots = self.snapshots.list();
+        let mut in_snapshots = HashSet::new();
+        let mut referrers = HashMap::<CryptoDigest, u64>::new();
+        let mut chunksets = vec![];
+        for snapshot in snapshots.iter() {
+            let mut hashes = HashSet::new();
+            for file in snapshot.files.iter() {
+                add_chunks(file);
+                in_snapshots.insert(file.clone());
+                hashes.extend(file.chunks.iter().map(|(_, cp)| cp.hash));
+            }
+            for hash in hashes.iter() {
+                *referrers.entry(*hash).or_default() += 1;
+            }
+            chunksets.push(hashes);
+        }
+
+        let size = |hash: &CryptoDigest| stored.get(hash).copied().unwrap_or_default();
+        for (snapshot, hashes) in snapshots.iter().zip(chunksets) {
+            let logical_bytes = snapshot.files.iter().map(|f| f.size).sum();
+            usage.logical_bytes += logical_bytes;
+            usage.snapshots.push(SnapshotUsage {
+                id: snapshot.id,
+                unix_secs: snapshot.unix_secs,
+                files: snapshot.files.len() as u64,
+                logical_bytes,
+                stored_bytes: hashes.iter().map(size).sum(),
+                exclusive_bytes: hashes
+                    .iter()
+                    .filter(|hash| referrers[*hash] == 1)
+                    .map(size)
+                    .sum(),
+            });
+        }
+
+        for file in self.files.index().iter() {
+            let file: &Arc<Entry> = file.key();
+            add_chunks(file);
+            if !in_snapshots.contains(file) {
+                usage.logical_bytes += file.size;
+            }
+        }
+        usage.unique_bytes = unique.values().sum();
+
+        Ok(usage)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn stats_count_what_snapshots_share() {
+        use super::*;
+        use crate::backends::MemoryBackend;
+        use crate::snapshots::Labels;
+        use crate::stash::StashKey;
+
+        let key = StashKey::open_stash("usage", "test").unwrap();
+        let mut stash = Stash::new(Arc::new(MemoryBackend::default()), key);
+        let data = crate::test_util::pseudo_random(100_000, 5);
+
+        let mut ingest = stash.ingest().unwrap();
+        ingest.add_file(Entry::from_stream("a"), &data).unwrap();
+        ingest.add_file(Entry::from_stream("b"), &data).unwrap();
+        ingest.finish(1, vec![], vec![], Labels::new()).unwrap();
+        let mut ingest = stash.ingest().unwrap();
+        ingest.add_file(Entry::from_stream("a"), &data).unwrap();
+        ingest
+            .add_file(Entry::from_stream("c"), b"only here")
+            .unwrap();
+        ingest.finish(2, vec![], vec![], Labels::new()).unwrap();
+
+        let usage = stash.stats().unwrap();
+        let len = data.len() as u64;
+        assert_eq!(usage.unique_bytes, len + 9);
+        assert_eq!(usage.chunks as usize, stash.chunk_index().len());
+        assert!(usage.objects >= 1);
+        assert_eq!(usage.snapshots.len(), 2);
+
+        let (first, second) = (&usage.snapshots[0], &usage.snapshots[1]);
+        assert_eq!((first.files, first.logical_bytes), (2, 2 * len));
+        assert_eq!(first.exclusive_bytes, 0);
+        assert_eq!(second.logical_bytes, len + 9);
+        assert!(second.exclusive_bytes > 0);
+        assert_eq!(
+            second.stored_bytes,
+            first.stored_bytes + second.exclusive_bytes
+        );
+        assert_eq!(usage.logical_bytes, 3 * len + 9);
+        assert!(usage.dedup_ratio() > 2.9);
+    }
+}
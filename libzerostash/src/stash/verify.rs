@@ -0,0 +1,284 @@
+use crate::backends::BackendError;
+use crate::cancel::CancelToken;
+use crate::compress;
+use crate::crypto::{chunk_hash, CryptoDigest, CryptoProvider};
+use crate::error::{Result, ZerostashError};
+use crate::meta;
+use crate::objects::ObjectId;
+use crate::progress::Phase;
+use crate::stash::Stash;
+use crate::stats::Stage;
+
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet};
+use std::hash::BuildHasher;
+use std::str::FromStr;
+
+/// How thoroughly `Stash::verify_with` checks the data objects. Each
+/// level does what the ones before it do.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum VerifyLevel {
+    /// That every object the chunk index refers to is in the backend
+    Exists,
+    /// That the chunks in the objects decrypt, so they're not changed
+    #[default]
+    Authenticate,
+    /// That the chunks decompress to the contents they're hashed to
+    Rehash,
+}
+
+impl FromStr for VerifyLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "exists" => Ok(VerifyLevel::Exists),
+            "authenticate" => Ok(VerifyLevel::Authenticate),
+            "rehash" => Ok(VerifyLevel::Rehash),
+            _ => Err(format!("unknown verify level: {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct VerifyOptions {
+    pub level: VerifyLevel,
+    /// Only read this many objects, picked at random, above the
+    /// `Exists` level. All of them are still checked to exist.
+    pub sample: Option<usize>,
+}
+
+/// What `Stash::verify_with` found.
+#[derive(Clone, Debug, Default)]
+pub struct Verified {
+    /// The objects checked to exist
+    pub objects: u64,
+    /// The chunks read back, in the objects that were read
+    pub chunks: u64,
+    /// Objects the chunk index refers to that aren't in the backend
+    pub missing: Vec<ObjectId>,
+    /// Objects with chunks that don't decrypt, or don't match their
+    /// hash
+    pub corrupt: Vec<ObjectId>,
+}
+
+impl Verified {
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty() && self.corrupt.is_empty()
+    }
+}
+
+impl Stash {
+    /// Check that every chunk in the index can be read back and
+    /// authenticated. Returns the number of chunks checked.
+    pub fn verify(&mut self, cancel: &CancelToken) -> Result<u64> {
+        let verified = self.verify_with(&VerifyOptions::default(), cancel)?;
+        if let Some(object) = verified.corrupt.first() {
+            return Err(ZerostashError::Corrupt { object: *object });
+        }
+        if !verified.missing.is_empty() {
+            return Err(BackendError::NoObjectFound.into());
+        }
+
+        Ok(verified.chunks)
+    }
+
+    /// Check the data objects the chunk index refers to, as far as
+    /// `options.level` asks, and list the ones that are missing or
+    /// corrupt. Objects in archival storage count as there, and are
+    /// left unread.
+    pub fn verify_with(
+        &mut self,
+        options: &VerifyOptions,
+        cancel: &CancelToken,
+    ) -> Result<Verified> {
+        self.load(meta::Field::Chunks)?;
+        if options.level == VerifyLevel::Rehash {
+            self.load(meta::Field::Files)?;
+            self.load(meta::Field::Snapshots)?;
+        }
+
+        let mut by_object = HashMap::<_, Vec<_>>::new();
+        self.chunks
+            .index()
+            .for_each(|_, cp| by_object.entry(cp.file).or_default().push(cp));
+
+        let mut verified = Verified::default();
+        self.progress
+            .phase(Phase::Verify, Some(by_object.len() as u64));
+
+        let mut present = vec![];
+        for id in by_object.keys() {
+            if cancel.is_cancelled() {
+                return Err(ZerostashError::Cancelled);
+            }
+
+            match self.backend.read_range(id, 0, 1) {
+                Ok(_) | Err(BackendError::Archived) => present.push(*id),
+                Err(BackendError::NoObjectFound) => verified.missing.push(*id),
+                Err(e) => return Err(e.into()),
+            }
+            verified.objects += 1;
+            if options.level == VerifyLevel::Exists {
+                self.progress.item(self.object_size as u64);
+            }
+        }
+        if options.level == VerifyLevel::Exists {
+            return Ok(verified);
+        }
+
+        if let Some(sample) = options.sample {
+            let order = RandomState::new();
+            present.sort_by_cached_key(|id| order.hash_one(id));
+            present.truncate(sample);
+        }
+        let sizes = match options.level {
+            VerifyLevel::Rehash => self.plain_sizes(),
+            _ => HashMap::new(),
+        };
+
+        let crypto = self.master_key.get_object_crypto()?;
+        let mut buffer = vec![0; crate::BLOCK_SIZE];
+        let mut plain = vec![];
+        for id in present {
+            if cancel.is_cancelled() {
+                return Err(ZerostashError::Cancelled);
+            }
+
+            let object = match self.backend.read_object(&id) {
+                Ok(object) => object,
+                Err(BackendError::NoObjectFound) => {
+                    verified.missing.push(id);
+                    continue;
+                }
+                Err(BackendError::Archived) => continue,
+                Err(e) => return Err(e.into()),
+            };
+            let size = object.buffer.as_ref().len() as u64;
+            self.progress.transfer(Stage::Download, size);
+
+            let chunks = &by_object[&id];
+            let intact = chunks.iter().all(|cp| {
+                let len = match crypto.decrypt_chunk(&mut buffer, &object, cp) {
+                    Ok(len) => len,
+                    Err(_) => return false,
+                };
+                // chunks no file refers to have no known size, and
+                // are only authenticated
+                match sizes.get(&cp.hash) {
+                    Some(size) => {
+                        plain.resize(*size, 0);
+                        compress::unpack_into(&mut plain, &buffer[..len]).is_ok()
+                            && chunk_hash(&plain) == cp.hash
+                    }
+                    None => true,
+                }
+            });
+            if intact {
+                verified.chunks += chunks.len() as u64;
+            } else {
+                verified.corrupt.push(id);
+            }
+            self.progress.item(size);
+        }
+
+        debug!(
+            "verified {} chunks, {} objects missing, {} corrupt",
+            verified.chunks,
+            verified.missing.len(),
+            verified.corrupt.len()
+        );
+        Ok(verified)
+    }
+
+    /// The plain size of each chunk, from the files that have it.
+    fn plain_sizes(&self) -> HashMap<CryptoDigest, usize> {
+        let mut files = self
+            .files
+            .index()
+            .iter()
+            .map(|f| f.key().clone())
+            .collect::<HashSet<_>>();
+        for snapshot in self.snapshots.list() {
+            files.extend(snapshot.files.iter().cloned());
+        }
+
+        let mut sizes = HashMap::new();
+        for file in files {
+            for (i, (start, cp)) in file.chunks.iter().enumerate() {
+                let end = file.chunks.get(i + 1).map(|(s, _)| *s).unwrap_or(file.size);
+                sizes.insert(cp.hash, (end - start) as usize);
+            }
+        }
+        sizes
+    }
+}
+
+#[cfg(all(test, feature = "fs"))]
+mod tests {
+    #[test]
+    fn missing_and_corrupt_objects_are_listed() {
+        use super::*;
+        use crate::backends::{Backend, MemoryBackend};
+        use crate::files::Entry;
+        use crate::objects::WriteObject;
+        use crate::snapshots::Labels;
+        use crate::stash::StashKey;
+        use std::sync::Arc;
+
+        let backend = MemoryBackend::default();
+        let key = StashKey::open_stash("verify", "test").unwrap();
+        let mut stash = Stash::new(Arc::new(backend.clone()), key);
+        // each backup writes its own object
+        for data in [&b"gone"[..], b"changed", b"intact"].iter() {
+            let mut ingest = stash.ingest().unwrap();
+            ingest.add_file(Entry::from_stream("file"), data).unwrap();
+            ingest.finish(0, vec![], vec![], Labels::new()).unwrap();
+        }
+
+        let cancel = CancelToken::default();
+        let all = stash
+            .verify_with(&VerifyOptions::default(), &cancel)
+            .unwrap();
+        assert!(all.is_ok());
+        assert_eq!((all.objects, all.chunks), (3, 3));
+
+        let chunk = |n: usize| stash.snapshots()[n].files[0].chunks[0].1.clone();
+        let (gone, changed) = (chunk(0).file, chunk(1));
+        backend.remove(&gone);
+        let mut object = WriteObject::default();
+        object.set_id(changed.file);
+        object
+            .buffer
+            .as_mut()
+            .copy_from_slice(backend.read_object(&changed.file).unwrap().buffer.as_ref());
+        object.buffer.as_mut()[changed.offs as usize] ^= 1;
+        backend.write_object(&object).unwrap();
+        let changed = changed.file;
+
+        let exists = VerifyOptions {
+            level: VerifyLevel::Exists,
+            ..VerifyOptions::default()
+        };
+        let found = stash.verify_with(&exists, &cancel).unwrap();
+        assert_eq!((found.missing, found.corrupt.len()), (vec![gone], 0));
+
+        for level in [VerifyLevel::Authenticate, VerifyLevel::Rehash] {
+            let options = VerifyOptions {
+                level,
+                ..VerifyOptions::default()
+            };
+            let found = stash.verify_with(&options, &cancel).unwrap();
+            assert_eq!((found.missing, found.corrupt), (vec![gone], vec![changed]));
+            assert_eq!((found.objects, found.chunks), (3, 1));
+        }
+        assert!(stash.verify(&cancel).is_err());
+
+        let none = VerifyOptions {
+            sample: Some(0),
+            ..VerifyOptions::default()
+        };
+        let found = stash.verify_with(&none, &cancel).unwrap();
+        assert_eq!((found.chunks, found.corrupt.len()), (0, 0));
+    }
+}
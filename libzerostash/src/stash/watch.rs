@@ -0,0 +1,138 @@
+use crate::error::{Result, ZerostashError};
+use crate::stash::{BackupOptions, Stash};
+use crate::watch::Watcher;
+
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Asks a running `watch` to commit the changes it collected so far,
+/// without waiting for them to settle. Cheaply cloneable, like
+/// `CancelToken`.
+#[derive(Clone, Debug, Default)]
+pub struct Trigger(Arc<AtomicBool>);
+
+impl Trigger {
+    pub fn new() -> Trigger {
+        Trigger::default()
+    }
+
+    /// Commit now. Safe to call from a signal handler thread.
+    pub fn pull(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    fn is_pulled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn reset(&self) -> bool {
+        self.0.swap(false, Ordering::Relaxed)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct WatchOptions {
+    /// Commit once nothing changed for this long
+    pub debounce: Duration,
+    /// Commit what changed without waiting for `debounce`
+    pub now: Trigger,
+    /// Options of every backup, including the token that stops
+    /// watching
+    pub backup: BackupOptions,
+}
+
+impl Default for WatchOptions {
+    fn default() -> WatchOptions {
+        WatchOptions {
+            debounce: Duration::from_secs(10),
+            now: Trigger::default(),
+            backup: BackupOptions::default(),
+        }
+    }
+}
+
+impl Stash {
+    /// Back up `paths`, then commit a snapshot of each batch of
+    /// changes under them, until cancelled.
+    ///
+    /// Only the changed files are read again, so snapshots are cheap
+    /// even for large trees. A batch ends once nothing changed for
+    /// `debounce`, so files that are being written in bursts are
+    /// only stored when they settled, or once `options.now` is
+    /// pulled. Pulling it while nothing changed commits nothing.
+    pub fn watch(&mut self, paths: &[impl AsRef<Path>], options: &WatchOptions) -> Result<()> {
+        // start watching first, so changes during the initial backup
+        // are picked up by the next one
+        let mut watcher = Watcher::new(paths)?;
+        let cancel = &options.backup.cancel;
+
+        let mut snapshot = match self.backup(paths, &options.backup) {
+            Err(ZerostashError::Cancelled) => return Ok(()),
+            result => result?,
+        };
+        info!("committed snapshot {}", snapshot.id);
+
+        while !cancel.is_cancelled() {
+            let now = &options.now;
+            let changed =
+                watcher.wait_until(Duration::from_secs(1), options.debounce, || now.is_pulled())?;
+            if now.reset() && changed.is_empty() {
+                debug!("nothing changed to commit");
+            }
+            if changed.is_empty() {
+                continue;
+            }
+
+            let changed = changed.into_iter().collect::<Vec<_>>();
+            debug!("{} paths changed", changed.len());
+            snapshot = match self.backup_changes(&snapshot, &changed, &options.backup) {
+                Err(ZerostashError::Cancelled) => return Ok(()),
+                result => result?,
+            };
+            info!("committed snapshot {}", snapshot.id);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn changes_are_committed_on_top_of_the_base() {
+        use crate::backends::MemoryBackend;
+        use crate::stash::{BackupOptions, Stash, StashKey};
+        use std::fs;
+        use std::sync::Arc;
+
+        let dir = std::env::temp_dir().join("0s_test_backup_changes");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        for name in ["a", "b", "sub/c"].iter() {
+            fs::write(dir.join(name), name.as_bytes()).unwrap();
+        }
+
+        let key = StashKey::open_stash("watch", "test").unwrap();
+        let mut stash = Stash::new(Arc::new(MemoryBackend::default()), key);
+        let options = BackupOptions::default();
+        let base = stash.backup(&[&dir], &options).unwrap();
+
+        fs::write(dir.join("a"), b"changed").unwrap();
+        fs::remove_dir_all(dir.join("sub")).unwrap();
+        let snapshot = stash
+            .backup_changes(&base, &[dir.join("a"), dir.join("sub")], &options)
+            .unwrap();
+
+        let mut files = snapshot
+            .files
+            .iter()
+            .map(|f| (f.name.clone(), f.size))
+            .collect::<Vec<_>>();
+        files.sort();
+        let name = |n: &str| dir.join(n).to_string_lossy().into_owned();
+        assert_eq!(files, vec![(name("a"), 7), (name("b"), 1)]);
+        assert_eq!(snapshot.paths, base.paths);
+    }
+}
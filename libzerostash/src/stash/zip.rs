@@ -0,0 +1,308 @@
+use crate::error::Result;
+use crate::files::Entry;
+use crate::snapshots::Snapshot;
+use crate::stash::Stash;
+use crate::time;
+
+use std::io::{self, Write};
+use std::sync::Arc;
+
+const LOCAL_HEADER: u32 = 0x0403_4b50;
+const DATA_DESCRIPTOR: u32 = 0x0807_4b50;
+const CENTRAL_HEADER: u32 = 0x0201_4b50;
+const ZIP64_END: u32 = 0x0606_4b50;
+const ZIP64_LOCATOR: u32 = 0x0706_4b50;
+const END: u32 = 0x0605_4b50;
+
+/// Zip64, the version every entry needs
+const VERSION: u16 = 45;
+/// Sizes follow the data, names are UTF-8
+const FLAGS: u16 = (1 << 3) | (1 << 11);
+
+/// Counts the bytes written, for the offsets in the central directory.
+struct Counter<W> {
+    inner: W,
+    written: u64,
+}
+
+impl<W: Write> Write for Counter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Computes the CRC-32 of the contents passing through.
+struct Crc<'a>(&'a mut dyn Write, u32);
+
+impl Write for Crc<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.0.write(buf)?;
+        self.1 = crc32(self.1, &buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+struct Written {
+    name: String,
+    entry: Arc<Entry>,
+    crc: u32,
+    offset: u64,
+}
+
+impl Stash {
+    /// Write the files of `snapshot` under `prefix` to `out` as a zip
+    /// archive, straight from their chunks. Returns the number of
+    /// files in the archive.
+    ///
+    /// The archive is written front to back, so `out` can be a
+    /// socket or a pipe. Members are named relative to the parent of
+    /// `prefix`, so the archive unpacks into a single directory.
+    /// Files are stored without compression, which zip64 lets grow
+    /// past 4GiB.
+    pub fn export_zip(&self, snapshot: &Snapshot, prefix: &str, out: impl Write) -> Result<u64> {
+        let reader = self.chunk_reader()?;
+        let prefix = prefix.trim_matches('/');
+        let parent = prefix.rfind('/').map(|i| i + 1).unwrap_or(0);
+
+        let mut files = snapshot
+            .files
+            .iter()
+            .filter_map(|f| {
+                let name = f.name.trim_start_matches('/');
+                let inside = prefix.is_empty()
+                    || name == prefix
+                    || name
+                        .strip_prefix(prefix)
+                        .is_some_and(|rest| rest.starts_with('/'));
+                match inside {
+                    true => Some((name[parent..].to_string(), f.clone())),
+                    false => None,
+                }
+            })
+            .collect::<Vec<_>>();
+        files.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut out = Counter {
+            inner: out,
+            written: 0,
+        };
+        let mut written = vec![];
+
+        for (name, entry) in files {
+            let offset = out.written;
+            let (dos_time, dos_date) = dos_datetime(entry.unix_secs);
+
+            put32(&mut out, LOCAL_HEADER)?;
+            put16(&mut out, VERSION)?;
+            put16(&mut out, FLAGS)?;
+            put16(&mut out, 0)?; // stored
+            put16(&mut out, dos_time)?;
+            put16(&mut out, dos_date)?;
+            put32(&mut out, 0)?; // crc, in the descriptor
+            put32(&mut out, u32::MAX)?;
+            put32(&mut out, u32::MAX)?;
+            put16(&mut out, name.len() as u16)?;
+            put16(&mut out, 20)?;
+            out.write_all(name.as_bytes())?;
+            put16(&mut out, 0x0001)?; // zip64 sizes, in the descriptor
+            put16(&mut out, 16)?;
+            put64(&mut out, 0)?;
+            put64(&mut out, 0)?;
+
+            let mut crc = Crc(&mut out, !0);
+            if entry.size > 0 {
+                reader.copy(&entry, 0, entry.size - 1, &mut crc)?;
+            }
+            let crc = !crc.1;
+
+            put32(&mut out, DATA_DESCRIPTOR)?;
+            put32(&mut out, crc)?;
+            put64(&mut out, entry.size)?;
+            put64(&mut out, entry.size)?;
+
+            written.push(Written {
+                name,
+                entry,
+                crc,
+                offset,
+            });
+        }
+
+        let directory_start = out.written;
+        for file in written.iter() {
+            let (dos_time, dos_date) = dos_datetime(file.entry.unix_secs);
+
+            put32(&mut out, CENTRAL_HEADER)?;
+            put16(&mut out, 3 << 8 | VERSION)?; // made on unix
+            put16(&mut out, VERSION)?;
+            put16(&mut out, FLAGS)?;
+            put16(&mut out, 0)?;
+            put16(&mut out, dos_time)?;
+            put16(&mut out, dos_date)?;
+            put32(&mut out, file.crc)?;
+            put32(&mut out, u32::MAX)?;
+            put32(&mut out, u32::MAX)?;
+            put16(&mut out, file.name.len() as u16)?;
+            put16(&mut out, 28)?;
+            put16(&mut out, 0)?; // comment
+            put16(&mut out, 0)?; // disk
+            put16(&mut out, 0)?; // internal attributes
+            put32(&mut out, (file.entry.unix_perm & 0o177_777) << 16)?;
+            put32(&mut out, u32::MAX)?;
+            out.write_all(file.name.as_bytes())?;
+            put16(&mut out, 0x0001)?;
+            put16(&mut out, 24)?;
+            put64(&mut out, file.entry.size)?;
+            put64(&mut out, file.entry.size)?;
+            put64(&mut out, file.offset)?;
+        }
+
+        let zip64_end = out.written;
+        let count = written.len() as u64;
+        put32(&mut out, ZIP64_END)?;
+        put64(&mut out, 44)?;
+        put16(&mut out, 3 << 8 | VERSION)?;
+        put16(&mut out, VERSION)?;
+        put32(&mut out, 0)?;
+        put32(&mut out, 0)?;
+        put64(&mut out, count)?;
+        put64(&mut out, count)?;
+        put64(&mut out, zip64_end - directory_start)?;
+        put64(&mut out, directory_start)?;
+
+        put32(&mut out, ZIP64_LOCATOR)?;
+        put32(&mut out, 0)?;
+        put64(&mut out, zip64_end)?;
+        put32(&mut out, 1)?;
+
+        put32(&mut out, END)?;
+        put16(&mut out, 0)?;
+        put16(&mut out, 0)?;
+        put16(&mut out, u16::MAX)?;
+        put16(&mut out, u16::MAX)?;
+        put32(&mut out, u32::MAX)?;
+        put32(&mut out, u32::MAX)?;
+        put16(&mut out, 0)?;
+        out.flush()?;
+
+        Ok(count)
+    }
+}
+
+fn put16(out: &mut impl Write, v: u16) -> io::Result<()> {
+    out.write_all(&v.to_le_bytes())
+}
+
+fn put32(out: &mut impl Write, v: u32) -> io::Result<()> {
+    out.write_all(&v.to_le_bytes())
+}
+
+fn put64(out: &mut impl Write, v: u64) -> io::Result<()> {
+    out.write_all(&v.to_le_bytes())
+}
+
+/// MS-DOS time and date fields in UTC. Dates before 1980 can't be
+/// represented, and are clamped to its start.
+fn dos_datetime(unix_secs: u64) -> (u16, u16) {
+    let secs = unix_secs.max(315_532_800);
+    let (year, month, day) = time::civil_from_days((secs / 86400) as i64);
+    let secs = secs % 86400;
+
+    let time = ((secs / 3600) << 11) | ((secs / 60 % 60) << 5) | ((secs % 60) / 2);
+    let date = (((year - 1980).min(127) as u64) << 9) | ((month as u64) << 5) | day as u64;
+    (time as u16, date as u16)
+}
+
+fn crc32(mut crc: u32, buf: &[u8]) -> u32 {
+    const TABLE: [u32; 256] = {
+        let mut table = [0; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut c = i as u32;
+            let mut k = 0;
+            while k < 8 {
+                c = if c & 1 != 0 {
+                    0xedb8_8320 ^ (c >> 1)
+                } else {
+                    c >> 1
+                };
+                k += 1;
+            }
+            table[i] = c;
+            i += 1;
+        }
+        table
+    };
+
+    for b in buf.iter() {
+        crc = TABLE[((crc ^ *b as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+#[cfg(all(test, feature = "fs"))]
+mod tests {
+    #[test]
+    fn crc32_matches_the_reference() {
+        assert_eq!(!super::crc32(!0, b"123456789"), 0xcbf4_3926);
+        assert_eq!(super::dos_datetime(0), (0, (1 << 5) | 1));
+    }
+
+    #[test]
+    fn zip_export_contains_the_subtree() {
+        use crate::backends::MemoryBackend;
+        use crate::stash::{BackupOptions, Stash, StashKey};
+        use std::convert::TryInto;
+        use std::sync::Arc;
+
+        let key = StashKey::open_stash("zip", "test").unwrap();
+        let mut stash = Stash::new(Arc::new(MemoryBackend::default()), key);
+        let snapshot = stash
+            .backup(
+                &["tests/data/100_random_1k", "tests/data/10k_random_blob"],
+                &BackupOptions::default(),
+            )
+            .unwrap();
+
+        let mut zip = vec![];
+        let count = stash
+            .export_zip(&snapshot, "tests/data/100_random_1k/", &mut zip)
+            .unwrap();
+        assert_eq!(count, 100);
+
+        // walk the central directory through the zip64 end record
+        let u16_at = |i: usize| u16::from_le_bytes(zip[i..i + 2].try_into().unwrap()) as usize;
+        let u64_at = |i: usize| u64::from_le_bytes(zip[i..i + 8].try_into().unwrap()) as usize;
+        let zip64_end = u64_at(zip.len() - 22 - 20 + 8);
+        assert_eq!(u64_at(zip64_end + 32), 100);
+
+        let mut pos = u64_at(zip64_end + 48);
+        for _ in 0..100 {
+            let name_len = u16_at(pos + 28);
+            let name = std::str::from_utf8(&zip[pos + 46..pos + 46 + name_len]).unwrap();
+            assert!(name.starts_with("100_random_1k/"));
+
+            let extra = pos + 46 + name_len;
+            let (size, offset) = (u64_at(extra + 4), u64_at(extra + 20));
+            let local_name = &zip[offset + 30..offset + 30 + name_len];
+            assert_eq!(local_name, name.as_bytes());
+
+            let data = offset + 30 + name_len + 20;
+            let original = std::fs::read(format!("tests/data/{}", name)).unwrap();
+            assert!(zip[data..data + size] == original[..]);
+            let crc = u32::from_le_bytes(zip[pos + 16..pos + 20].try_into().unwrap());
+            assert_eq!(crc, !super::crc32(!0, &original));
+            pos = extra + 28;
+        }
+    }
+}
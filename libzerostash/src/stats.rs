@@ -0,0 +1,220 @@
+use crate::progress::Progress;
+
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Stage {
+    Walk,
+    Read,
+    Chunk,
+    Compress,
+    Encrypt,
+    Upload,
+    Download,
+    Decrypt,
+    Decompress,
+    Write,
+}
+
+pub(crate) const STAGES: [Stage; 10] = [
+    Stage::Walk,
+    Stage::Read,
+    Stage::Chunk,
+    Stage::Compress,
+    Stage::Encrypt,
+    Stage::Upload,
+    Stage::Download,
+    Stage::Decrypt,
+    Stage::Decompress,
+    Stage::Write,
+];
+
+impl Stage {
+    pub fn name(self) -> &'static str {
+        use Stage::*;
+        match self {
+            Walk => "walk",
+            Read => "read",
+            Chunk => "chunk",
+            Compress => "compress",
+            Encrypt => "encrypt",
+            Upload => "upload",
+            Download => "download",
+            Decrypt => "decrypt",
+            Decompress => "decompress",
+            Write => "write",
+        }
+    }
+}
+
+/// Collects timings from all worker threads of a run.
+///
+/// Stage timings are the sum of time spent in a stage across threads,
+/// so with parallel workers they can add up to more than the wall
+/// clock time of the run.
+///
+/// Files, chunks and transfers are also forwarded to a `Progress`,
+/// if set.
+#[derive(Default)]
+pub struct Collector {
+    nanos: [AtomicU64; STAGES.len()],
+    bytes: AtomicU64,
+    files: AtomicU64,
+    progress: Option<Arc<dyn Progress>>,
+}
+
+impl Collector {
+    pub fn new(progress: Arc<dyn Progress>) -> Collector {
+        Collector {
+            progress: Some(progress),
+            ..Collector::default()
+        }
+    }
+
+    #[inline]
+    pub fn time<T>(&self, stage: Stage, f: impl FnOnce() -> T) -> T {
+        span!("stage", stage = stage.name());
+        let start = Instant::now();
+        let result = f();
+        self.add_time(stage, start.elapsed());
+
+        result
+    }
+
+    #[inline]
+    pub fn add_time(&self, stage: Stage, elapsed: Duration) {
+        self.nanos[stage as usize].fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+
+        if let Some(p) = &self.progress {
+            p.time(stage, elapsed);
+        }
+    }
+
+    #[inline]
+    pub fn add_file(&self, bytes: u64) {
+        self.files.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+
+        if let Some(p) = &self.progress {
+            p.item(bytes);
+        }
+    }
+
+    #[inline]
+    pub fn add_discovered(&self) {
+        if let Some(p) = &self.progress {
+            p.discovered();
+        }
+    }
+
+    #[inline]
+    pub fn start_file(&self, name: &str) {
+        if let Some(p) = &self.progress {
+            p.file(name);
+        }
+    }
+
+    #[inline]
+    pub fn add_chunk(&self, bytes: u64, new: bool) {
+        if let Some(p) = &self.progress {
+            p.chunk(bytes, new);
+        }
+    }
+
+    #[inline]
+    pub fn add_transfer(&self, stage: Stage, bytes: u64) {
+        if let Some(p) = &self.progress {
+            p.transfer(stage, bytes);
+        }
+    }
+
+    pub fn summary(&self, elapsed: Duration) -> Summary {
+        let mut stages = [Duration::default(); STAGES.len()];
+        for (i, s) in stages.iter_mut().enumerate() {
+            *s = Duration::from_nanos(self.nanos[i].load(Ordering::Relaxed));
+        }
+
+        Summary {
+            stages,
+            elapsed,
+            bytes: self.bytes.load(Ordering::Relaxed),
+            files: self.files.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Statistics of a finished backup or restore run.
+#[derive(Clone, Debug, Default)]
+pub struct Summary {
+    stages: [Duration; STAGES.len()],
+    pub elapsed: Duration,
+    pub bytes: u64,
+    pub files: u64,
+}
+
+impl Summary {
+    pub fn stage(&self, stage: Stage) -> Duration {
+        self.stages[stage as usize]
+    }
+
+    /// Throughput of the run in bytes per second
+    pub fn throughput(&self) -> f64 {
+        match self.elapsed.as_secs_f64() {
+            t if t > 0.0 => self.bytes as f64 / t,
+            _ => 0.0,
+        }
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "files: {}, bytes: {}, time: {:.3}s, throughput: {:.2} MiB/s",
+            self.files,
+            self.bytes,
+            self.elapsed.as_secs_f64(),
+            self.throughput() / 1024.0 / 1024.0
+        )?;
+
+        for stage in STAGES
+            .iter()
+            .filter(|s| self.stage(**s) > Duration::default())
+        {
+            writeln!(
+                f,
+                " * {}: {:.3}s",
+                stage.name(),
+                self.stage(*stage).as_secs_f64()
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn stage_times_add_up() {
+        use super::{Collector, Stage};
+        use std::time::Duration;
+
+        let stats = Collector::default();
+        stats.add_time(Stage::Chunk, Duration::from_millis(10));
+        stats.add_time(Stage::Chunk, Duration::from_millis(5));
+        stats.add_time(Stage::Upload, Duration::from_millis(1));
+        stats.add_file(1024);
+        stats.add_file(1024);
+
+        let summary = stats.summary(Duration::from_secs(2));
+        assert_eq!(summary.stage(Stage::Chunk), Duration::from_millis(15));
+        assert_eq!(summary.stage(Stage::Upload), Duration::from_millis(1));
+        assert_eq!(summary.stage(Stage::Walk), Duration::default());
+        assert_eq!(summary.files, 2);
+        assert_eq!(summary.throughput(), 1024.0);
+    }
+}
@@ -0,0 +1,13 @@
+//! Helpers shared by the unit tests.
+
+/// `len` bytes that don't compress or deduplicate, the same for the
+/// same `seed`.
+pub(crate) fn pseudo_random(len: usize, seed: u32) -> Vec<u8> {
+    let mut state = seed;
+    (0..len)
+        .map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (state >> 16) as u8
+        })
+        .collect()
+}
@@ -0,0 +1,27 @@
+//! Spans and events for `tracing` subscribers, so embedders can see
+//! where the time of a backup or a restore goes.
+//!
+//! With the `tracing` feature, these are `debug` spans and `trace`
+//! events, which cost a check of a static when no subscriber is
+//! interested, and their fields are only evaluated if one is.
+//! Without it, they compile to nothing.
+
+/// Enter a span until the end of the enclosing block, like
+/// `span!("upload", object = %id, bytes = len)`.
+macro_rules! span {
+    ($($args:tt)*) => {
+        #[cfg(feature = "tracing")]
+        let span = ::tracing::debug_span!($($args)*);
+        #[cfg(feature = "tracing")]
+        let _entered = span.enter();
+    };
+}
+
+/// Record an event in the current span, like
+/// `event!(bytes = len, "chunked file")`.
+macro_rules! event {
+    ($($args:tt)*) => {
+        #[cfg(feature = "tracing")]
+        ::tracing::trace!($($args)*);
+    };
+}
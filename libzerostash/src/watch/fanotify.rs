@@ -0,0 +1,229 @@
+//! A mark on the whole file system of every root, which needs no
+//! walk to set up, and no watches per directory. The events only
+//! identify directories by handle, which are opened to find their
+//! path again, so this needs `CAP_SYS_ADMIN` and
+//! `CAP_DAC_READ_SEARCH`.
+
+use super::Source;
+
+use std::collections::BTreeSet;
+use std::ffi::{CString, OsStr};
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const MASK: u64 = libc::FAN_CREATE
+    | libc::FAN_DELETE
+    | libc::FAN_MODIFY
+    | libc::FAN_CLOSE_WRITE
+    | libc::FAN_MOVED_FROM
+    | libc::FAN_MOVED_TO
+    | libc::FAN_ATTRIB
+    | libc::FAN_ONDIR;
+
+/// Changes of directories themselves that matter
+const DIR_MASK: u64 =
+    libc::FAN_CREATE | libc::FAN_DELETE | libc::FAN_MOVED_FROM | libc::FAN_MOVED_TO;
+
+/// Size of `fanotify_event_metadata`
+const METADATA_LEN: usize = 24;
+
+pub(super) struct Fanotify {
+    fd: libc::c_int,
+    /// The roots as they were given, where they really are, and a
+    /// descriptor on their file system to open handles with
+    roots: Vec<(PathBuf, PathBuf, libc::c_int)>,
+}
+
+impl Fanotify {
+    pub(super) fn new(roots: &[PathBuf]) -> io::Result<Fanotify> {
+        let fd = unsafe {
+            libc::fanotify_init(
+                libc::FAN_CLASS_NOTIF | libc::FAN_CLOEXEC | libc::FAN_REPORT_DFID_NAME,
+                (libc::O_RDONLY | libc::O_LARGEFILE) as libc::c_uint,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // the descriptor is closed on drop, even if marking fails
+        let mut fanotify = Fanotify { fd, roots: vec![] };
+        for root in roots.iter() {
+            let real = fs::canonicalize(root)?;
+            let c_path = CString::new(real.as_os_str().as_bytes())?;
+
+            let marked = unsafe {
+                libc::fanotify_mark(
+                    fd,
+                    libc::FAN_MARK_ADD | libc::FAN_MARK_FILESYSTEM,
+                    MASK,
+                    libc::AT_FDCWD,
+                    c_path.as_ptr(),
+                )
+            };
+            if marked < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let mount = unsafe { libc::open(c_path.as_ptr(), libc::O_RDONLY | libc::O_CLOEXEC) };
+            if mount < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            fanotify.roots.push((root.clone(), real, mount));
+        }
+
+        Ok(fanotify)
+    }
+
+    /// The path of the directory with the `file_handle` in `handle`,
+    /// if it still exists.
+    fn open_handle(&self, handle: &[u8]) -> Option<PathBuf> {
+        // `file_handle` is made of `u32`s, so align a copy of it
+        let mut aligned = vec![0u32; handle.len().div_ceil(4)];
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                handle.as_ptr(),
+                aligned.as_mut_ptr() as *mut u8,
+                handle.len(),
+            )
+        };
+
+        self.roots.iter().find_map(|(_, _, mount)| {
+            let fd = unsafe {
+                libc::open_by_handle_at(
+                    *mount,
+                    aligned.as_mut_ptr() as *mut libc::file_handle,
+                    libc::O_PATH | libc::O_CLOEXEC,
+                )
+            };
+            if fd < 0 {
+                return None;
+            }
+
+            let path = fs::read_link(format!("/proc/self/fd/{}", fd));
+            unsafe { libc::close(fd) };
+            path.ok()
+        })
+    }
+
+    /// `path` as it would be found under the roots that were given,
+    /// if it's under any of them.
+    fn under_roots(&self, path: &Path) -> Option<PathBuf> {
+        self.roots.iter().find_map(|(root, real, _)| {
+            let rest = path.strip_prefix(real).ok()?;
+            Some(match rest.as_os_str().is_empty() {
+                true => root.clone(),
+                false => root.join(rest),
+            })
+        })
+    }
+}
+
+impl Source for Fanotify {
+    fn poll(
+        &mut self,
+        roots: &[PathBuf],
+        timeout: Duration,
+        changed: &mut BTreeSet<PathBuf>,
+    ) -> io::Result<bool> {
+        let mut pollfd = libc::pollfd {
+            fd: self.fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let ready = unsafe { libc::poll(&mut pollfd, 1, timeout.as_millis() as libc::c_int) };
+        match ready {
+            0 => return Ok(false),
+            r if r < 0 => {
+                let e = io::Error::last_os_error();
+                return match e.kind() {
+                    io::ErrorKind::Interrupted => Ok(false),
+                    _ => Err(e),
+                };
+            }
+            _ => {}
+        }
+
+        let mut buffer = vec![0u8; 64 * 1024];
+        let len = unsafe { libc::read(self.fd, buffer.as_mut_ptr() as *mut _, buffer.len()) };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let buffer = &buffer[..len as usize];
+
+        let u16_at = |b: &[u8], at: usize| u16::from_ne_bytes([b[at], b[at + 1]]) as usize;
+        let u32_at = |b: &[u8], at: usize| {
+            u32::from_ne_bytes([b[at], b[at + 1], b[at + 2], b[at + 3]]) as usize
+        };
+
+        let mut found = false;
+        let mut pos = 0;
+        while pos + METADATA_LEN <= buffer.len() {
+            let event_len = u32_at(buffer, pos);
+            if event_len < METADATA_LEN || pos + event_len > buffer.len() {
+                break;
+            }
+            let event = &buffer[pos..pos + event_len];
+            let mask = (u32_at(event, 8) as u64) | ((u32_at(event, 12) as u64) << 32);
+            pos += event_len;
+
+            if mask & libc::FAN_Q_OVERFLOW != 0 {
+                warn!("too many changes to keep track of, rescanning everything");
+                changed.extend(roots.iter().cloned());
+                found = true;
+                continue;
+            }
+            if mask & libc::FAN_ONDIR != 0 && mask & DIR_MASK == 0 {
+                continue;
+            }
+
+            // info records: a header, the file system id, then a
+            // `file_handle` of the directory followed by the name
+            let mut info = METADATA_LEN;
+            while info + 4 <= event.len() {
+                let (kind, info_len) = (event[info], u16_at(event, info + 2));
+                if info_len < 4 || info + info_len > event.len() {
+                    break;
+                }
+                let record = &event[info..info + info_len];
+                info += info_len;
+
+                if kind != libc::FAN_EVENT_INFO_TYPE_DFID_NAME || record.len() < 20 {
+                    continue;
+                }
+                let handle_bytes = u32_at(record, 12);
+                let handle_end = (20 + handle_bytes).min(record.len());
+                let name = &record[handle_end..];
+                let name = &name[..name.iter().position(|b| *b == 0).unwrap_or(name.len())];
+
+                let dir = match self.open_handle(&record[12..handle_end]) {
+                    Some(dir) => dir,
+                    None => continue,
+                };
+                let path = match name {
+                    b"" | b"." => dir,
+                    name => dir.join(OsStr::from_bytes(name)),
+                };
+                // the mark covers the whole file system
+                if let Some(path) = self.under_roots(&path) {
+                    changed.insert(path);
+                    found = true;
+                }
+            }
+        }
+
+        Ok(found)
+    }
+}
+
+impl Drop for Fanotify {
+    fn drop(&mut self) {
+        for (_, _, mount) in self.roots.iter() {
+            unsafe { libc::close(*mount) };
+        }
+        unsafe { libc::close(self.fd) };
+    }
+}
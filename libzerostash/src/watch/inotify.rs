@@ -0,0 +1,164 @@
+//! A watch on every directory, which is cheap for small trees, but
+//! has to walk the whole tree first, and is limited by
+//! `fs.inotify.max_user_watches`.
+
+use super::Source;
+
+use std::collections::{BTreeSet, HashMap};
+use std::ffi::{CString, OsStr};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+pub(super) struct Inotify {
+    fd: libc::c_int,
+    /// Watch descriptors and the path they watch, which is either a
+    /// directory, or one of the roots
+    watches: HashMap<libc::c_int, (PathBuf, bool)>,
+}
+
+impl Inotify {
+    const MASK: u32 = libc::IN_CREATE
+        | libc::IN_DELETE
+        | libc::IN_MODIFY
+        | libc::IN_CLOSE_WRITE
+        | libc::IN_MOVED_FROM
+        | libc::IN_MOVED_TO
+        | libc::IN_ATTRIB;
+
+    pub(super) fn new(roots: &[PathBuf]) -> io::Result<Inotify> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut inner = Inotify {
+            fd,
+            watches: Default::default(),
+        };
+        for root in roots.iter() {
+            inner.watch_tree(root)?;
+        }
+        Ok(inner)
+    }
+
+    fn watch_tree(&mut self, root: &Path) -> io::Result<()> {
+        for entry in walkdir::WalkDir::new(root)
+            .into_iter()
+            .filter_map(|e| e.ok())
+        {
+            let is_dir = entry.file_type().is_dir();
+            if !is_dir && entry.depth() > 0 {
+                continue;
+            }
+
+            match self.add_watch(entry.path(), is_dir) {
+                Ok(()) => {}
+                // the directory disappeared since it was listed
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn add_watch(&mut self, path: &Path, is_dir: bool) -> io::Result<()> {
+        let c_path = CString::new(path.as_os_str().as_bytes())?;
+        let wd = unsafe { libc::inotify_add_watch(self.fd, c_path.as_ptr(), Self::MASK) };
+        if wd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        self.watches.insert(wd, (path.to_path_buf(), is_dir));
+        Ok(())
+    }
+}
+
+impl Source for Inotify {
+    fn poll(
+        &mut self,
+        roots: &[PathBuf],
+        timeout: Duration,
+        changed: &mut BTreeSet<PathBuf>,
+    ) -> io::Result<bool> {
+        let mut pollfd = libc::pollfd {
+            fd: self.fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let ready = unsafe { libc::poll(&mut pollfd, 1, timeout.as_millis() as libc::c_int) };
+        match ready {
+            0 => return Ok(false),
+            r if r < 0 => {
+                let e = io::Error::last_os_error();
+                return match e.kind() {
+                    io::ErrorKind::Interrupted => Ok(false),
+                    _ => Err(e),
+                };
+            }
+            _ => {}
+        }
+
+        let mut buffer = vec![0u8; 64 * 1024];
+        let len = unsafe { libc::read(self.fd, buffer.as_mut_ptr() as *mut _, buffer.len()) };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let field = |at: usize| {
+            u32::from_ne_bytes([buffer[at], buffer[at + 1], buffer[at + 2], buffer[at + 3]])
+        };
+        let mut pos = 0;
+        while pos + 16 <= len as usize {
+            let (wd, mask, name_len) = (field(pos) as libc::c_int, field(pos + 4), field(pos + 12));
+            let name = &buffer[pos + 16..pos + 16 + name_len as usize];
+            let name = &name[..name.iter().position(|b| *b == 0).unwrap_or(name.len())];
+            pos += 16 + name_len as usize;
+
+            if mask & libc::IN_Q_OVERFLOW != 0 {
+                warn!("too many changes to keep track of, rescanning everything");
+                changed.extend(roots.iter().cloned());
+                continue;
+            }
+            if mask & libc::IN_IGNORED != 0 {
+                self.watches.remove(&wd);
+                continue;
+            }
+
+            let (path, is_dir) = match self.watches.get(&wd) {
+                Some(watch) => watch.clone(),
+                None => continue,
+            };
+            // directories themselves aren't stored
+            if name.is_empty() && is_dir {
+                continue;
+            }
+            let path = match name.is_empty() {
+                true => path,
+                false => path.join(OsStr::from_bytes(name)),
+            };
+
+            if mask & libc::IN_ISDIR != 0 {
+                let moved =
+                    libc::IN_CREATE | libc::IN_MOVED_TO | libc::IN_MOVED_FROM | libc::IN_DELETE;
+                if mask & moved == 0 {
+                    continue;
+                }
+                if mask & (libc::IN_CREATE | libc::IN_MOVED_TO) != 0 {
+                    self.watch_tree(&path)?;
+                }
+            }
+            changed.insert(path);
+        }
+
+        Ok(true)
+    }
+}
+
+impl Drop for Inotify {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
@@ -0,0 +1,190 @@
+//! Watching trees of files for changes, for continuous backups.
+//!
+//! On Linux, `Watcher` is notified of changes by fanotify if the
+//! process may mark whole file systems, and by inotify otherwise.
+//! Elsewhere it compares the modification times of every file on each
+//! wait, which finds the same changes, only slower.
+
+use std::collections::BTreeSet;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+#[cfg(target_os = "linux")]
+mod fanotify;
+#[cfg(target_os = "linux")]
+mod inotify;
+#[cfg(not(target_os = "linux"))]
+mod scan;
+
+/// Where changes come from.
+trait Source: Send {
+    /// Collect the changes under `roots` that arrive within `timeout`
+    /// into `changed`. Returns whether there were any.
+    fn poll(
+        &mut self,
+        roots: &[PathBuf],
+        timeout: Duration,
+        changed: &mut BTreeSet<PathBuf>,
+    ) -> io::Result<bool>;
+}
+
+pub struct Watcher {
+    roots: Vec<PathBuf>,
+    source: Box<dyn Source>,
+}
+
+impl Watcher {
+    /// Watch `paths`, and everything under them.
+    pub fn new(paths: &[impl AsRef<Path>]) -> io::Result<Watcher> {
+        let roots = paths
+            .iter()
+            .map(|p| p.as_ref().to_path_buf())
+            .collect::<Vec<_>>();
+
+        #[cfg(target_os = "linux")]
+        let source: Box<dyn Source> = match fanotify::Fanotify::new(&roots) {
+            Ok(fanotify) => Box::new(fanotify),
+            Err(e) => {
+                debug!("can't use fanotify, falling back to inotify: {}", e);
+                Box::new(inotify::Inotify::new(&roots)?)
+            }
+        };
+        #[cfg(not(target_os = "linux"))]
+        let source = Box::new(scan::Scan::new(&roots)?);
+
+        Ok(Watcher { roots, source })
+    }
+
+    /// Wait up to `timeout` for a change. Once something changed,
+    /// keep collecting changes until there were none for `debounce`.
+    ///
+    /// Returns the paths that were created, changed or removed. If a
+    /// directory appeared, only the directory is returned.
+    pub fn wait(&mut self, timeout: Duration, debounce: Duration) -> io::Result<BTreeSet<PathBuf>> {
+        self.wait_until(timeout, debounce, || false)
+    }
+
+    /// Like `wait`, but stop collecting changes as soon as `now`
+    /// returns true, even if they didn't settle yet.
+    pub fn wait_until(
+        &mut self,
+        timeout: Duration,
+        debounce: Duration,
+        now: impl Fn() -> bool,
+    ) -> io::Result<BTreeSet<PathBuf>> {
+        let mut changed = BTreeSet::new();
+
+        if self.source.poll(&self.roots, timeout, &mut changed)? {
+            // in short steps, so `now` is noticed soon
+            let step = debounce.min(Duration::from_secs(1));
+            let mut quiet = Duration::ZERO;
+            while quiet < debounce && !now() {
+                if self.source.poll(&self.roots, step, &mut changed)? {
+                    quiet = Duration::ZERO;
+                } else {
+                    quiet += step;
+                }
+            }
+        }
+
+        // paths are ordered by component, so directories come before
+        // what's in them
+        let mut outermost = BTreeSet::<PathBuf>::new();
+        for path in changed {
+            if !outermost.iter().any(|dir| path.starts_with(dir)) {
+                outermost.insert(path);
+            }
+        }
+        Ok(outermost)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check_changes(dir: &Path, source: impl FnOnce(&[PathBuf]) -> Box<dyn Source>) {
+        use std::fs;
+
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("sub/old"), b"old").unwrap();
+
+        let roots = vec![dir.to_path_buf()];
+        let mut watcher = Watcher {
+            source: source(&roots),
+            roots,
+        };
+        let quiet = Duration::from_millis(100);
+        let mut wait = || {
+            let changed = watcher.wait(Duration::from_secs(5), quiet).unwrap();
+            changed.into_iter().collect::<Vec<_>>()
+        };
+
+        fs::write(dir.join("sub/old"), b"changed").unwrap();
+        fs::write(dir.join("new"), b"new").unwrap();
+        assert_eq!(wait(), vec![dir.join("new"), dir.join("sub/old")]);
+
+        fs::create_dir(dir.join("fresh")).unwrap();
+        fs::write(dir.join("fresh/file"), b"file").unwrap();
+        assert_eq!(wait(), vec![dir.join("fresh")]);
+
+        // files in new directories are watched too
+        fs::remove_file(dir.join("fresh/file")).unwrap();
+        assert_eq!(wait(), vec![dir.join("fresh/file")]);
+    }
+
+    #[test]
+    fn changes_are_collected() {
+        let dir = std::env::temp_dir().join("0s_test_watch");
+        check_changes(&dir, |roots| {
+            let watcher = Watcher::new(roots).unwrap();
+            watcher.source
+        });
+    }
+
+    #[test]
+    fn waits_can_be_cut_short() {
+        use std::fs;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::time::Instant;
+
+        let dir = std::env::temp_dir().join("0s_test_watch_now");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let mut watcher = Watcher::new(&[&dir]).unwrap();
+
+        let now = AtomicBool::new(false);
+        let start = Instant::now();
+        fs::write(dir.join("file"), b"file").unwrap();
+        let changed = watcher
+            .wait_until(Duration::from_secs(5), Duration::from_secs(60), || {
+                now.swap(true, Ordering::Relaxed)
+            })
+            .unwrap();
+        assert_eq!(
+            changed.into_iter().collect::<Vec<_>>(),
+            vec![dir.join("file")]
+        );
+        assert!(start.elapsed() < Duration::from_secs(30));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn inotify_and_fanotify_find_the_same_changes() {
+        let dir = std::env::temp_dir().join("0s_test_watch_inotify");
+        check_changes(&dir, |roots| {
+            Box::new(inotify::Inotify::new(roots).unwrap())
+        });
+
+        // marking file systems needs CAP_SYS_ADMIN
+        let dir = std::env::temp_dir().join("0s_test_watch_fanotify");
+        if let Ok(fanotify) = fanotify::Fanotify::new(&[std::env::temp_dir()]) {
+            drop(fanotify);
+            check_changes(&dir, |roots| {
+                Box::new(fanotify::Fanotify::new(roots).unwrap())
+            });
+        }
+    }
+}
@@ -0,0 +1,73 @@
+//! Comparing the modification times of every file, where the system
+//! has no way to be notified of changes.
+
+use super::Source;
+
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+/// Modification time and size of every file. Directories are
+/// included with neither, so new directories are found as a whole.
+type State = HashMap<PathBuf, Option<(SystemTime, u64)>>;
+
+pub(super) struct Scan {
+    state: State,
+}
+
+impl Scan {
+    pub(super) fn new(roots: &[PathBuf]) -> io::Result<Scan> {
+        Ok(Scan {
+            state: Self::scan(roots),
+        })
+    }
+
+    fn scan(roots: &[PathBuf]) -> State {
+        roots
+            .iter()
+            .flat_map(|root| {
+                walkdir::WalkDir::new(root)
+                    .into_iter()
+                    .filter_map(|e| e.ok())
+            })
+            .filter_map(|e| {
+                if e.file_type().is_dir() {
+                    return Some((e.into_path(), None));
+                }
+                let metadata = e.metadata().ok()?;
+                let file = (metadata.modified().ok()?, metadata.len());
+                Some((e.into_path(), Some(file)))
+            })
+            .collect()
+    }
+}
+
+impl Source for Scan {
+    fn poll(
+        &mut self,
+        roots: &[PathBuf],
+        timeout: Duration,
+        changed: &mut BTreeSet<PathBuf>,
+    ) -> io::Result<bool> {
+        std::thread::sleep(timeout);
+
+        let state = Self::scan(roots);
+        let mut found = false;
+        for (path, file) in state.iter() {
+            if self.state.get(path) != Some(file) {
+                changed.insert(path.clone());
+                found = true;
+            }
+        }
+        for path in self.state.keys() {
+            if !state.contains_key(path) {
+                changed.insert(path.clone());
+                found = true;
+            }
+        }
+
+        self.state = state;
+        Ok(found)
+    }
+}
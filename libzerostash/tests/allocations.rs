@@ -0,0 +1,128 @@
+//! Checks that the chunking hot path doesn't hit the allocator.
+//!
+//! Allocations are counted per thread, so tests running in parallel
+//! don't interfere with each other.
+
+use libzerostash::backends::NullBackend;
+use libzerostash::compress;
+use libzerostash::crypto::ObjectOperations;
+use libzerostash::objects::{ObjectStore, Storage};
+use libzerostash::rollsum::SeaSplit;
+use libzerostash::splitter::{Chunker, Chunking, FileSplitter};
+use libzerostash::stats::Collector;
+
+use secrecy::Secret;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::sync::Arc;
+
+const PATH: &str = "tests/data/10k_random_blob";
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+struct CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|a| a.set(a.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|a| a.set(a.get() + 1));
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn count_allocations<T>(f: impl FnOnce() -> T) -> (usize, T) {
+    let before = ALLOCATIONS.with(Cell::get);
+    let result = f();
+    (ALLOCATIONS.with(Cell::get) - before, result)
+}
+
+#[test]
+fn splitter_does_not_allocate() {
+    let data = std::fs::read(PATH).unwrap();
+
+    let (allocations, size) = count_allocations(|| {
+        FileSplitter::<SeaSplit>::new(&data, &Chunker::SeaSplit.default_sizes())
+            .map(|(_, _, c)| c.len())
+            .sum::<usize>()
+    });
+
+    assert_eq!(size, data.len());
+    assert_eq!(allocations, 0);
+}
+
+#[test]
+fn chunkers_only_allocate_their_splitter() {
+    let data = std::fs::read(PATH).unwrap();
+
+    for chunker in [Chunker::SeaSplit, Chunker::FastCdc, Chunker::Fixed] {
+        let chunking = Chunking::new(chunker);
+        let (allocations, size) = count_allocations(|| {
+            chunking
+                .split(&data)
+                .map(|(_, _, c)| c.len())
+                .sum::<usize>()
+        });
+
+        assert_eq!(size, data.len());
+        assert_eq!(allocations, 1, "{:?}", chunker);
+    }
+}
+
+#[test]
+fn compressing_into_scratch_does_not_allocate() {
+    let data = std::fs::read(PATH).unwrap();
+    let mut scratch = Vec::new();
+
+    // the first round sizes the buffer up
+    compress::block_into(&mut scratch, &data).unwrap();
+
+    let (allocations, _) = count_allocations(|| {
+        for (_, _, chunk) in
+            FileSplitter::<SeaSplit>::new(&data, &Chunker::SeaSplit.default_sizes())
+        {
+            compress::block_into(&mut scratch, chunk).unwrap();
+        }
+    });
+    assert_eq!(allocations, 0);
+
+    compress::block_into(&mut scratch, &data).unwrap();
+    assert_eq!(compress::deblock(&scratch).unwrap(), data);
+}
+
+#[test]
+fn storing_chunks_only_allocates_pointers() {
+    let data = std::fs::read(PATH).unwrap();
+    let key = Secret::new(*b"abcdef1234567890abcdef1234567890");
+    let mut storage = Storage::new(
+        Arc::new(NullBackend::default()),
+        ObjectOperations::new(key),
+        Arc::new(Collector::default()),
+    );
+
+    // warm up the scratch buffer
+    storage.store_chunk(&Default::default(), &data).unwrap();
+
+    let chunks = FileSplitter::<SeaSplit>::new(&data, &Chunker::SeaSplit.default_sizes())
+        .collect::<Vec<_>>();
+    let (allocations, _) = count_allocations(|| {
+        for (_, hash, chunk) in chunks.iter() {
+            storage.store_chunk(hash, chunk).unwrap();
+        }
+    });
+
+    assert_eq!(allocations, chunks.len());
+}
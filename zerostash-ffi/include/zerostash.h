@@ -0,0 +1,65 @@
+/*
+ * C interface of libzerostash, built by the zerostash-ffi crate.
+ *
+ * All strings are null-terminated UTF-8. Every function returning a
+ * zerostash_status can be followed by zerostash_last_error() on the
+ * same thread to get a description of what went wrong.
+ */
+
+#ifndef ZEROSTASH_H
+#define ZEROSTASH_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef enum zerostash_status {
+    ZEROSTASH_OK = 0,
+    ZEROSTASH_INVALID_ARGUMENT = 1,
+    ZEROSTASH_WRONG_PASSPHRASE = 2,
+    ZEROSTASH_BACKEND = 3,
+    ZEROSTASH_CORRUPT = 4,
+    ZEROSTASH_IO = 5,
+    ZEROSTASH_OTHER = 6,
+    /* only returned by builds with panic = "unwind", the release
+     * profile aborts the process on panics instead */
+    ZEROSTASH_PANIC = 7,
+    ZEROSTASH_CANCELLED = 8,
+    ZEROSTASH_INCOMPATIBLE = 9,
+    /* the stash is locked, immutable, or already has it */
+    ZEROSTASH_CONFLICT = 10,
+    /* zerostash_create() found a stash the credentials open */
+    ZEROSTASH_EXISTS = 11,
+} zerostash_status;
+
+typedef struct zerostash zerostash;
+
+typedef void (*zerostash_list_cb)(const char *name, uint64_t size, void *data);
+
+zerostash_status zerostash_create(const char *path, const char *user,
+                                  const char *password, zerostash **out);
+zerostash_status zerostash_open(const char *path, const char *user,
+                                const char *password, zerostash **out);
+void zerostash_close(zerostash *stash);
+
+zerostash_status zerostash_backup(zerostash *stash, const char *path);
+/* `pattern` may be NULL to restore everything */
+zerostash_status zerostash_restore(zerostash *stash, const char *pattern,
+                                   const char *target);
+/* `pattern` may be NULL to list everything */
+zerostash_status zerostash_list(zerostash *stash, const char *pattern,
+                                zerostash_list_cb callback, void *data);
+/* `chunks` may be NULL */
+zerostash_status zerostash_verify(zerostash *stash, uint64_t *chunks);
+
+/* Returns the length of the message; `buf` is always null-terminated */
+size_t zerostash_last_error(char *buf, size_t len);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* ZEROSTASH_H */
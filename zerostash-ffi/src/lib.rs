@@ -0,0 +1,435 @@
+//! C bindings for libzerostash.
+//!
+//! A stash is used through an opaque `zerostash` handle. Every call
+//! returns a `ZerostashStatus`, and on failure a description of the
+//! error is available from `zerostash_last_error` on the same thread.
+//! See `include/zerostash.h` for the C declarations.
+//!
+//! Panics are only returned as `Panic` by builds that unwind. The
+//! release profile of the workspace sets `panic = "abort"`, so there a
+//! panic aborts the host process instead.
+//!
+//! # Safety
+//!
+//! Pointer arguments must be null or valid for the duration of the
+//! call, and handles must not be used from several threads at once,
+//! or after they're closed.
+
+// the module docs describe the contract shared by all functions
+#![allow(clippy::missing_safety_doc)]
+
+use libzerostash::backends::Directory;
+use libzerostash::error::{ErrorKind, ZerostashError};
+use libzerostash::stash::{BackupOptions, CancelToken, Stash, StashBuilder};
+
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_void};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::sync::Arc;
+
+/// Status codes returned by every call. Values are part of the ABI,
+/// new ones are only ever appended.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ZerostashStatus {
+    Ok = 0,
+    InvalidArgument = 1,
+    WrongPassphrase = 2,
+    Backend = 3,
+    Corrupt = 4,
+    Io = 5,
+    Other = 6,
+    Panic = 7,
+    Cancelled = 8,
+    Incompatible = 9,
+    Conflict = 10,
+    Exists = 11,
+}
+
+/// Opaque handle to an open stash
+pub struct ZerostashHandle(Stash);
+
+pub type ListCallback = extern "C" fn(name: *const c_char, size: u64, data: *mut c_void);
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn status_of(error: &ZerostashError) -> ZerostashStatus {
+    if let ZerostashError::Exists = error {
+        return ZerostashStatus::Exists;
+    }
+
+    match error.kind() {
+        ErrorKind::Credentials => ZerostashStatus::WrongPassphrase,
+        ErrorKind::Backend => ZerostashStatus::Backend,
+        ErrorKind::Corrupt => ZerostashStatus::Corrupt,
+        ErrorKind::Incompatible => ZerostashStatus::Incompatible,
+        ErrorKind::InvalidInput => ZerostashStatus::InvalidArgument,
+        ErrorKind::Conflict => ZerostashStatus::Conflict,
+        ErrorKind::Io => ZerostashStatus::Io,
+        ErrorKind::Cancelled => ZerostashStatus::Cancelled,
+    }
+}
+
+fn set_error(msg: impl ToString) {
+    let msg = CString::new(msg.to_string().replace('\0', "")).unwrap_or_default();
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(msg));
+}
+
+/// Run `f`, translating errors and panics into status codes, so
+/// nothing unwinds across the FFI boundary. With `panic = "abort"`,
+/// panics never get here.
+fn guard(f: impl FnOnce() -> Result<(), ZerostashStatus>) -> ZerostashStatus {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => ZerostashStatus::Ok,
+        Ok(Err(status)) => status,
+        Err(_) => {
+            set_error("internal error");
+            ZerostashStatus::Panic
+        }
+    }
+}
+
+fn check<T>(result: libzerostash::error::Result<T>) -> Result<T, ZerostashStatus> {
+    result.map_err(|e| {
+        set_error(&e);
+        status_of(&e)
+    })
+}
+
+unsafe fn string<'a>(s: *const c_char) -> Result<&'a str, ZerostashStatus> {
+    if s.is_null() {
+        set_error("unexpected null pointer");
+        return Err(ZerostashStatus::InvalidArgument);
+    }
+
+    CStr::from_ptr(s).to_str().map_err(|_| {
+        set_error("string is not valid UTF-8");
+        ZerostashStatus::InvalidArgument
+    })
+}
+
+unsafe fn handle<'a>(h: *mut ZerostashHandle) -> Result<&'a mut Stash, ZerostashStatus> {
+    match h.as_mut() {
+        Some(h) => Ok(&mut h.0),
+        None => {
+            set_error("unexpected null handle");
+            Err(ZerostashStatus::InvalidArgument)
+        }
+    }
+}
+
+unsafe fn patterns(pattern: *const c_char) -> Result<Vec<String>, ZerostashStatus> {
+    if pattern.is_null() {
+        Ok(vec![])
+    } else {
+        Ok(vec![string(pattern)?.to_string()])
+    }
+}
+
+unsafe fn stash(
+    path: *const c_char,
+    user: *const c_char,
+    password: *const c_char,
+) -> Result<Stash, ZerostashStatus> {
+    let (path, user, password) = (string(path)?, string(user)?, string(password)?);
+
+    let backend = check(Directory::new(path).map_err(ZerostashError::from))?;
+    check(
+        StashBuilder::new()
+            .backend(Arc::new(backend))
+            .credentials(user, password)
+            .build(),
+    )
+}
+
+/// Create a new stash in the directory `path`.
+///
+/// Returns `Exists` if there's a stash in it already that these
+/// credentials open.
+#[no_mangle]
+pub unsafe extern "C" fn zerostash_create(
+    path: *const c_char,
+    user: *const c_char,
+    password: *const c_char,
+    out: *mut *mut ZerostashHandle,
+) -> ZerostashStatus {
+    guard(|| {
+        if out.is_null() {
+            set_error("unexpected null pointer");
+            return Err(ZerostashStatus::InvalidArgument);
+        }
+
+        let mut stash = stash(path, user, password)?;
+        match stash.read() {
+            Ok(_) => check(Err(ZerostashError::Exists))?,
+            Err(ZerostashError::WrongPassphrase) => {}
+            Err(e) => check(Err(e))?,
+        }
+        *out = Box::into_raw(Box::new(ZerostashHandle(stash)));
+        Ok(())
+    })
+}
+
+/// Open the existing stash in the directory `path`.
+#[no_mangle]
+pub unsafe extern "C" fn zerostash_open(
+    path: *const c_char,
+    user: *const c_char,
+    password: *const c_char,
+    out: *mut *mut ZerostashHandle,
+) -> ZerostashStatus {
+    guard(|| {
+        if out.is_null() {
+            set_error("unexpected null pointer");
+            return Err(ZerostashStatus::InvalidArgument);
+        }
+
+        let mut stash = stash(path, user, password)?;
+        check(stash.read().map(|_| ()))?;
+        *out = Box::into_raw(Box::new(ZerostashHandle(stash)));
+        Ok(())
+    })
+}
+
+/// Close a handle returned by `zerostash_open` or `zerostash_create`.
+#[no_mangle]
+pub unsafe extern "C" fn zerostash_close(h: *mut ZerostashHandle) {
+    if !h.is_null() {
+        drop(Box::from_raw(h));
+    }
+}
+
+/// Back up `path` as a new snapshot, and commit the stash.
+#[no_mangle]
+pub unsafe extern "C" fn zerostash_backup(
+    h: *mut ZerostashHandle,
+    path: *const c_char,
+) -> ZerostashStatus {
+    guard(|| {
+        let (stash, path) = (handle(h)?, string(path)?);
+        check(stash.backup(&[path], &BackupOptions::default()).map(|_| ()))
+    })
+}
+
+/// Restore files matching `pattern` under `target`. A null `pattern`
+/// restores everything.
+#[no_mangle]
+pub unsafe extern "C" fn zerostash_restore(
+    h: *mut ZerostashHandle,
+    pattern: *const c_char,
+    target: *const c_char,
+) -> ZerostashStatus {
+    guard(|| {
+        let (stash, target) = (handle(h)?, string(target)?);
+        let patterns = patterns(pattern)?;
+        let threads = stash.threads();
+
+        check(
+            stash
+                .restore_by_glob(threads, &patterns, target)
+                .map(|_| ()),
+        )
+    })
+}
+
+/// Call `callback` with the name and size of every file matching
+/// `pattern`. A null `pattern` lists everything.
+///
+/// The name is only valid for the duration of the call.
+#[no_mangle]
+pub unsafe extern "C" fn zerostash_list(
+    h: *mut ZerostashHandle,
+    pattern: *const c_char,
+    callback: ListCallback,
+    data: *mut c_void,
+) -> ZerostashStatus {
+    guard(|| {
+        let stash = handle(h)?;
+        let patterns = patterns(pattern)?;
+
+        for entry in check(stash.list(&patterns))? {
+            let name = CString::new(entry.name.as_str()).unwrap_or_default();
+            callback(name.as_ptr(), entry.size, data);
+        }
+
+        Ok(())
+    })
+}
+
+/// Check that all stored chunks can be read and authenticated. The
+/// number of chunks checked is written to `chunks` if it's not null.
+#[no_mangle]
+pub unsafe extern "C" fn zerostash_verify(
+    h: *mut ZerostashHandle,
+    chunks: *mut u64,
+) -> ZerostashStatus {
+    guard(|| {
+        let checked = check(handle(h)?.verify(&CancelToken::default()))?;
+        if !chunks.is_null() {
+            *chunks = checked;
+        }
+
+        Ok(())
+    })
+}
+
+/// Copy the last error message of the current thread into `buf`,
+/// truncating and always null-terminating it.
+///
+/// Returns the full length of the message, excluding the terminator.
+#[no_mangle]
+pub unsafe extern "C" fn zerostash_last_error(buf: *mut c_char, len: usize) -> usize {
+    LAST_ERROR.with(|e| {
+        let e = e.borrow();
+        let msg = e.as_ref().map(|m| m.as_bytes()).unwrap_or_default();
+
+        if !buf.is_null() && len > 0 {
+            let n = msg.len().min(len - 1);
+            ptr::copy_nonoverlapping(msg.as_ptr() as *const c_char, buf, n);
+            *buf.add(n) = 0;
+        }
+
+        msg.len()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn backup_list_restore_through_c_abi() {
+        use super::*;
+        use std::env;
+        use std::fs;
+
+        extern "C" fn count(_name: *const c_char, _size: u64, data: *mut c_void) {
+            unsafe { *(data as *mut usize) += 1 };
+        }
+
+        let c = |s: &str| CString::new(s).unwrap();
+        let dir = env::temp_dir().join("0s_test_ffi");
+        let target = env::temp_dir().join("0s_test_ffi_restore");
+        let path = c(dir.to_str().unwrap());
+        let _ = fs::remove_dir_all(&dir);
+        let (user, password) = (c("ffi"), c("test"));
+
+        unsafe {
+            let mut h = ptr::null_mut();
+            assert_eq!(
+                zerostash_open(path.as_ptr(), user.as_ptr(), password.as_ptr(), &mut h),
+                ZerostashStatus::WrongPassphrase
+            );
+            assert!(zerostash_last_error(ptr::null_mut(), 0) > 0);
+
+            assert_eq!(
+                zerostash_create(path.as_ptr(), user.as_ptr(), password.as_ptr(), &mut h),
+                ZerostashStatus::Ok
+            );
+            let data = fs::canonicalize("../libzerostash/tests/data/100_random_1k").unwrap();
+            let data = c(data.to_str().unwrap());
+            assert_eq!(zerostash_backup(h, data.as_ptr()), ZerostashStatus::Ok);
+            zerostash_close(h);
+
+            // an existing stash is never written over
+            assert_eq!(
+                zerostash_create(path.as_ptr(), user.as_ptr(), password.as_ptr(), &mut h),
+                ZerostashStatus::Exists
+            );
+
+            assert_eq!(
+                zerostash_open(path.as_ptr(), user.as_ptr(), password.as_ptr(), &mut h),
+                ZerostashStatus::Ok
+            );
+
+            let mut files = 0usize;
+            let status = zerostash_list(
+                h,
+                ptr::null(),
+                count,
+                &mut files as *mut usize as *mut c_void,
+            );
+            assert_eq!((status, files), (ZerostashStatus::Ok, 100));
+
+            let mut chunks = 0;
+            assert_eq!(zerostash_verify(h, &mut chunks), ZerostashStatus::Ok);
+            assert!(chunks > 0);
+
+            let target_path = c(target.to_str().unwrap());
+            assert_eq!(
+                zerostash_restore(h, ptr::null(), target_path.as_ptr()),
+                ZerostashStatus::Ok
+            );
+            zerostash_close(h);
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_dir_all(&target).unwrap();
+
+        // a stash locked by another process can be tried again later
+        let locked = ZerostashError::Locked("pid 1".into());
+        assert_eq!(status_of(&locked), ZerostashStatus::Conflict);
+    }
+
+    #[test]
+    fn opens_stashes_made_by_the_cli() {
+        use super::*;
+        use libzerostash::Kdf;
+        use std::env;
+        use std::fs;
+
+        extern "C" fn count(_name: *const c_char, _size: u64, data: *mut c_void) {
+            unsafe { *(data as *mut usize) += 1 };
+        }
+
+        let dir = env::temp_dir().join("0s_test_ffi_cli");
+        let _ = fs::remove_dir_all(&dir);
+        let data = fs::canonicalize("../libzerostash/tests/data/100_random_1k").unwrap();
+
+        // the CLI stores the calibrated `Kdf` of new stashes, which is
+        // rarely `Kdf::default()`
+        let mut stash = StashBuilder::new()
+            .backend(Arc::new(Directory::new(&dir).unwrap()))
+            .credentials("ffi", "test")
+            .kdf(Kdf::Argon2id {
+                memory_kib: 1024,
+                iterations: 1,
+                parallelism: 1,
+            })
+            .build()
+            .unwrap();
+        stash.backup(&[data], &BackupOptions::default()).unwrap();
+        drop(stash);
+
+        let c = |s: &str| CString::new(s).unwrap();
+        let path = c(dir.to_str().unwrap());
+        let (user, password) = (c("ffi"), c("test"));
+
+        unsafe {
+            let mut h = ptr::null_mut();
+            assert_eq!(
+                zerostash_open(path.as_ptr(), user.as_ptr(), password.as_ptr(), &mut h),
+                ZerostashStatus::Ok
+            );
+
+            let mut files = 0usize;
+            let status = zerostash_list(
+                h,
+                ptr::null(),
+                count,
+                &mut files as *mut usize as *mut c_void,
+            );
+            assert_eq!((status, files), (ZerostashStatus::Ok, 100));
+            zerostash_close(h);
+
+            assert_eq!(
+                zerostash_create(path.as_ptr(), user.as_ptr(), password.as_ptr(), &mut h),
+                ZerostashStatus::Exists
+            );
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
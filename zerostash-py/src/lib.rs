@@ -0,0 +1,241 @@
+//! Python bindings for libzerostash
+//!
+//! ```python
+//! import zerostash
+//!
+//! stash = zerostash.Stash.create("/mnt/backup", "user", "password")
+//! snapshot = stash.backup(["/home/user/documents"])
+//! for f in stash.list(["*.txt"]):
+//!     print(f.name, f.size)
+//! stash.restore("/tmp/restored", snapshot=snapshot)
+//! ```
+
+use libzerostash::backends::Directory;
+use libzerostash::stash::{BackupOptions, CancelToken, RestoreOptions, Snapshot, StashBuilder};
+use libzerostash::ZerostashError;
+
+use pyo3::create_exception;
+use pyo3::exceptions::PyException;
+use pyo3::prelude::*;
+
+use std::sync::Arc;
+
+create_exception!(zerostash, Error, PyException);
+create_exception!(zerostash, WrongPassphrase, Error);
+create_exception!(zerostash, Exists, Error);
+
+fn to_py(e: ZerostashError) -> PyErr {
+    match e {
+        ZerostashError::WrongPassphrase => WrongPassphrase::new_err(e.to_string()),
+        ZerostashError::Exists => Exists::new_err(e.to_string()),
+        e => Error::new_err(e.to_string()),
+    }
+}
+
+fn open(path: &str, user: &str, password: &str) -> Result<libzerostash::Stash, ZerostashError> {
+    let backend = Arc::new(Directory::new(path)?);
+
+    StashBuilder::new()
+        .backend(backend)
+        .credentials(user, password)
+        .build()
+}
+
+fn create(path: &str, user: &str, password: &str) -> Result<libzerostash::Stash, ZerostashError> {
+    let mut stash = open(path, user, password)?;
+
+    match stash.read() {
+        Ok(_) => Err(ZerostashError::Exists),
+        Err(ZerostashError::WrongPassphrase) => Ok(stash),
+        Err(e) => Err(e),
+    }
+}
+
+#[pyclass(name = "File", frozen)]
+struct PyFile {
+    #[pyo3(get)]
+    name: String,
+    #[pyo3(get)]
+    size: u64,
+    #[pyo3(get)]
+    unix_secs: u64,
+}
+
+impl PyFile {
+    fn new(entry: &libzerostash::files::Entry) -> PyFile {
+        PyFile {
+            name: entry.name.clone(),
+            size: entry.size,
+            unix_secs: entry.unix_secs,
+        }
+    }
+}
+
+#[pyclass(name = "Snapshot", frozen)]
+struct PySnapshot(Arc<Snapshot>);
+
+#[pymethods]
+impl PySnapshot {
+    #[getter]
+    fn id(&self) -> u64 {
+        self.0.id
+    }
+
+    #[getter]
+    fn unix_secs(&self) -> u64 {
+        self.0.unix_secs
+    }
+
+    #[getter]
+    fn paths(&self) -> Vec<String> {
+        self.0.paths.clone()
+    }
+
+    #[getter]
+    fn size(&self) -> u64 {
+        self.0.size()
+    }
+
+    #[getter]
+    fn files(&self) -> Vec<PyFile> {
+        self.0.files.iter().map(|f| PyFile::new(f)).collect()
+    }
+
+    fn __repr__(&self) -> String {
+        format!("<Snapshot {} of {:?}>", self.0.id, self.0.paths)
+    }
+}
+
+#[pyclass(name = "Stash")]
+struct PyStash(libzerostash::Stash);
+
+#[pymethods]
+impl PyStash {
+    /// Create a new stash in the directory `path`.
+    ///
+    /// Raises `Exists` if there's a stash in it already that these
+    /// credentials open.
+    #[staticmethod]
+    fn create(path: &str, user: &str, password: &str) -> PyResult<PyStash> {
+        create(path, user, password).map(PyStash).map_err(to_py)
+    }
+
+    /// Open the existing stash in the directory `path`.
+    #[staticmethod]
+    fn open(py: Python, path: &str, user: &str, password: &str) -> PyResult<PyStash> {
+        py.allow_threads(|| {
+            let mut stash = open(path, user, password)?;
+            stash.read()?;
+            Ok(PyStash(stash))
+        })
+        .map_err(to_py)
+    }
+
+    /// Back up `paths` as a new snapshot, and commit the stash.
+    #[pyo3(signature = (paths, threads = None))]
+    fn backup(
+        &mut self,
+        py: Python,
+        paths: Vec<String>,
+        threads: Option<usize>,
+    ) -> PyResult<PySnapshot> {
+        let options = BackupOptions {
+            threads,
+            ..BackupOptions::default()
+        };
+        py.allow_threads(|| self.0.backup(&paths, &options))
+            .map(PySnapshot)
+            .map_err(to_py)
+    }
+
+    fn snapshots(&self) -> Vec<PySnapshot> {
+        self.0.snapshots().into_iter().map(PySnapshot).collect()
+    }
+
+    /// Files matching any of `patterns`, or all files.
+    #[pyo3(signature = (patterns = vec![]))]
+    fn list(&self, patterns: Vec<String>) -> PyResult<Vec<PyFile>> {
+        let files = self.0.list(&patterns).map_err(to_py)?;
+        Ok(files.map(|f| PyFile::new(&f)).collect())
+    }
+
+    /// Restore files matching any of `patterns` under `target`, from
+    /// `snapshot` or the latest version of every file. Returns the
+    /// number of files restored.
+    #[pyo3(signature = (target, patterns = vec![], snapshot = None, threads = None))]
+    fn restore(
+        &mut self,
+        py: Python,
+        target: String,
+        patterns: Vec<String>,
+        snapshot: Option<Py<PySnapshot>>,
+        threads: Option<usize>,
+    ) -> PyResult<u64> {
+        let stash = &mut self.0;
+        let snapshot = snapshot.map(|s| s.get().0.clone());
+
+        py.allow_threads(|| match snapshot {
+            Some(snapshot) => {
+                let options = RestoreOptions {
+                    threads,
+                    patterns,
+                    ..RestoreOptions::default()
+                };
+                stash.restore(&snapshot, &target, &options)
+            }
+            None => {
+                let threads = threads.unwrap_or_else(|| stash.threads());
+                stash.restore_by_glob(threads, &patterns, &target)
+            }
+        })
+        .map(|summary| summary.files)
+        .map_err(to_py)
+    }
+
+    /// Check that all stored chunks can be read and authenticated.
+    /// Returns the number of chunks checked.
+    fn verify(&mut self, py: Python) -> PyResult<u64> {
+        py.allow_threads(|| self.0.verify(&CancelToken::default()))
+            .map_err(to_py)
+    }
+}
+
+#[pymodule]
+fn zerostash(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyStash>()?;
+    m.add_class::<PySnapshot>()?;
+    m.add_class::<PyFile>()?;
+    m.add("Error", m.py().get_type::<Error>())?;
+    m.add("WrongPassphrase", m.py().get_type::<WrongPassphrase>())?;
+    m.add("Exists", m.py().get_type::<Exists>())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn create_refuses_existing_stashes() {
+        use super::*;
+        use std::fs;
+
+        let dir = std::env::temp_dir().join("0s_test_py_create");
+        let _ = fs::remove_dir_all(&dir);
+        let path = dir.to_str().unwrap();
+        let data = fs::canonicalize("../libzerostash/tests/data/100_random_1k").unwrap();
+        let data = data.to_str().unwrap().to_string();
+
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let mut stash = PyStash::create(path, "py", "test").unwrap();
+            stash.backup(py, vec![data], None).unwrap();
+
+            let e = PyStash::create(path, "py", "test").err().unwrap();
+            assert!(e.is_instance_of::<Exists>(py));
+
+            let stash = PyStash::open(py, path, "py", "test").unwrap();
+            assert_eq!(stash.snapshots().len(), 1);
+        });
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -6,10 +6,14 @@ use crate::{
 };
 use abscissa_core::{
     application::{self, AppCell},
-    config, status_err, status_warn, trace, Application, EntryPoint, FrameworkError, StandardPaths,
+    config, status_err, trace, Application, EntryPoint, FrameworkError, StandardPaths,
 };
-use anyhow::{format_err, Error, Result};
-use libzerostash::Stash;
+use anyhow::{Error, Result};
+use libzerostash::{
+    stash::{Field, LockKind, StashBuilder, ZerostashError},
+    Stash,
+};
+use secrecy::ExposeSecret;
 
 use std::{process, sync::Arc};
 
@@ -66,29 +70,75 @@ impl ZerostashApp {
     /// * `pathy` - Can be a path or an alias stored in the config
     pub(crate) fn open_stash(&self, pathy: impl AsRef<str>) -> Stash {
         let config = &*app_config();
-
-        let mut stash = match config.resolve_stash(&pathy) {
+        match config.resolve_stash(&pathy) {
             None => {
                 let path = pathy.as_ref();
-                let key = ask_credentials().unwrap_or_else(|e| fatal_error(e));
+                let (user, password) = ask_credentials().unwrap_or_else(|e| fatal_error(e));
                 let backend = Arc::new(
                     libzerostash::backends::Directory::new(path)
                         .unwrap_or_else(|e| fatal_error(e.into())),
                 );
 
-                Stash::new(backend, key)
+                config
+                    .tuning
+                    .apply(
+                        StashBuilder::new()
+                            .backend(backend)
+                            .credentials(user, password.expose_secret()),
+                    )
+                    .build()
+                    .unwrap_or_else(|e| fatal_error2(e.into()))
             }
-            Some(cfg) => cfg.try_open().unwrap_or_else(|e| fatal_error(e)),
-        };
+            Some(cfg) => config.try_open(cfg).unwrap_or_else(|e| fatal_error(e)),
+        }
+    }
+
+    /// Open an existing stash, loading only the metadata `fields`
+    /// the command needs up front
+    pub(crate) fn stash_exists(&self, pathy: impl AsRef<str>, fields: &[Field]) -> Stash {
+        let mut stash = self.open_stash(pathy);
+        match stash.read_fields(fields) {
+            Ok(_) => stash,
+            Err(e) => fatal_error2(e.into()),
+        }
+    }
 
-        stash
+    /// Like `stash_exists`, with the stash locked exclusively before
+    /// it's read, to change it.
+    pub(crate) fn stash_locked(&self, pathy: impl AsRef<str>, fields: &[Field]) -> Stash {
+        self.stash_with_lock(pathy, fields, LockKind::Exclusive)
     }
 
-    pub(crate) fn stash_exists(&self, pathy: impl AsRef<str>) -> Stash {
+    /// Like `stash_exists`, with a shared lock taken before it's read,
+    /// so the objects it reads aren't deleted under it.
+    pub(crate) fn stash_shared(&self, pathy: impl AsRef<str>, fields: &[Field]) -> Stash {
+        self.stash_with_lock(pathy, fields, LockKind::Shared)
+    }
+
+    fn stash_with_lock(&self, pathy: impl AsRef<str>, fields: &[Field], kind: LockKind) -> Stash {
         let mut stash = self.open_stash(pathy);
-        match stash.read() {
+        // the lock of a grant isn't the one of the stash it's from
+        let locked = if stash.is_granted() {
+            Ok(())
+        } else {
+            stash.lock(kind)
+        };
+        match locked.and_then(|_| stash.read_fields(fields)) {
             Ok(_) => stash,
-            Err(e) => fatal_error2(e),
+            Err(e) => fatal_error2(e.into()),
+        }
+    }
+
+    /// Open the stash to add snapshots to, locked exclusively, with
+    /// what it has read, or a new one.
+    pub(crate) fn stash_to_extend(&self, pathy: impl AsRef<str>) -> Stash {
+        let mut stash = self.open_stash(pathy);
+        match stash
+            .lock(LockKind::Exclusive)
+            .and_then(|_| stash.read().map(|_| ()))
+        {
+            Ok(_) | Err(ZerostashError::WrongPassphrase) => stash,
+            Err(e) => fatal_error2(e.into()),
         }
     }
 
@@ -154,11 +204,14 @@ impl Application for ZerostashApp {
     }
 }
 
+/// Report `err`, and exit
 pub fn fatal_error2(err: Box<dyn std::error::Error>) -> ! {
-    status_err!("{} fatal error: {}", (&*app_reader()).name(), err);
+    status_err!("{} fatal error: {}", app_reader().name(), err);
     process::exit(1)
 }
+
+/// Report `err`, and exit
 pub fn fatal_error(err: Error) -> ! {
-    status_err!("{} fatal error: {}", (&*app_reader()).name(), err);
+    status_err!("{} fatal error: {}", app_reader().name(), err);
     process::exit(1)
 }
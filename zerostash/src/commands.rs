@@ -13,20 +13,67 @@
 mod alias_add;
 mod alias_del;
 mod alias_list;
+mod analyze;
+mod apply_bundle;
+mod audit;
+mod cat;
 mod checkout;
+mod collect;
 mod commit;
+mod compact;
+mod diff;
+mod export_bundle;
+mod export_manifest;
+mod export_zip;
+mod find;
+mod gc;
+mod grant;
+mod import_borg;
+mod import_restic;
+mod import_tar;
+mod key_slot;
+mod keyfile;
+mod kms_key;
 mod ls;
+mod migrate;
+#[cfg(target_os = "linux")]
+mod mount;
+mod orphans;
+mod passwd;
+mod prune;
+mod public_key;
+mod repack;
+mod repair;
+mod serve;
+mod sign_policy;
+mod snapshots;
+mod split_key;
+mod stats;
+mod status;
+mod sync;
+mod unlock;
+mod verify;
 mod version;
+mod watch;
 mod wipe;
+mod writer_key;
 
+#[cfg(target_os = "linux")]
+use self::mount::Mount;
 use self::{
-    alias_add::AliasAdd, alias_del::AliasDel, alias_list::AliasList, checkout::Checkout,
-    commit::Commit, ls::Ls, version::VersionCmd, wipe::Wipe,
+    alias_add::AliasAdd, alias_del::AliasDel, alias_list::AliasList, analyze::Analyze,
+    apply_bundle::ApplyBundle, audit::Audit, cat::Cat, checkout::Checkout, collect::Collect,
+    commit::Commit, compact::Compact, diff::Diff, export_bundle::ExportBundle,
+    export_manifest::ExportManifest, export_zip::ExportZip, find::Find, gc::Gc, grant::Grant,
+    import_borg::ImportBorg, import_restic::ImportRestic, import_tar::ImportTar, key_slot::KeySlot,
+    keyfile::KeyfileCmd, kms_key::KmsKey, ls::Ls, migrate::Migrate, orphans::Orphans,
+    passwd::Passwd, prune::Prune, public_key::PublicKeyCmd, repack::Repack, repair::Repair,
+    serve::Serve, sign_policy::SignPolicy, snapshots::Snapshots, split_key::SplitKey, stats::Stats,
+    status::Status, sync::Sync, unlock::Unlock, verify::Verify, version::VersionCmd, watch::Watch,
+    wipe::Wipe, writer_key::WriterKeyCmd,
 };
 use crate::config::ZerostashConfig;
-use abscissa_core::{
-    config::Override, Command, Configurable, FrameworkError, Help, Options, Runnable,
-};
+use abscissa_core::{Command, Configurable, FrameworkError, Help, Options, Runnable};
 use std::path::PathBuf;
 
 /// Zerostash Subcommands
@@ -52,28 +99,181 @@ pub enum ZerostashCmd {
     #[options(help = "list existing stash shorthands")]
     AliasList(AliasList),
 
+    /// The `analyze` subcommand
+    #[options(help = "estimate the new data a backup would add")]
+    Analyze(Analyze),
+
+    /// The `apply-bundle` subcommand
+    #[options(help = "add a bundle to a copy of the stash")]
+    ApplyBundle(ApplyBundle),
+
+    /// The `audit` subcommand
+    #[options(help = "check the signatures of a shared stash against its policy")]
+    Audit(Audit),
+
+    /// The `cat` subcommand
+    #[options(help = "write the contents of a stored file to stdout")]
+    Cat(Cat),
+
     /// The `start` subcommand
     #[options(help = "check out files")]
     Checkout(Checkout),
 
+    /// The `collect` subcommand
+    #[options(help = "copy the snapshots of append-only writers into a stash")]
+    Collect(Collect),
+
     /// The `start` subcommand
     #[options(help = "add files to a stash")]
     Commit(Commit),
 
+    /// The `compact` subcommand
+    #[options(help = "retire the metadata earlier commits left behind")]
+    Compact(Compact),
+
+    /// The `diff` subcommand
+    #[options(help = "list the files that changed between snapshots")]
+    Diff(Diff),
+
+    /// The `export-bundle` subcommand
+    #[options(help = "export new data since a snapshot as a bundle")]
+    ExportBundle(ExportBundle),
+
+    /// The `export-manifest` subcommand
+    #[options(help = "list the files of a snapshot as newline-delimited JSON")]
+    ExportManifest(ExportManifest),
+
+    /// The `export-zip` subcommand
+    #[options(help = "write a directory of a snapshot to a zip archive")]
+    ExportZip(ExportZip),
+
+    /// The `find` subcommand
+    #[options(help = "search all snapshots for files")]
+    Find(Find),
+
+    /// The `gc` subcommand
+    #[options(help = "delete the data objects nothing refers to")]
+    Gc(Gc),
+
+    /// The `grant` subcommand
+    #[options(help = "give read access to some snapshots or a directory")]
+    Grant(Grant),
+
+    /// The `import-borg` subcommand
+    #[options(help = "import all archives of a Borg repository")]
+    ImportBorg(ImportBorg),
+
+    /// The `import-restic` subcommand
+    #[options(help = "import all snapshots of a restic repository")]
+    ImportRestic(ImportRestic),
+
+    /// The `import-tar` subcommand
+    #[options(help = "import a tar archive as a snapshot")]
+    ImportTar(ImportTar),
+
+    /// The `key-slot` subcommand
+    #[options(help = "list, add or revoke the credentials that open a stash")]
+    KeySlot(KeySlot),
+
+    /// The `keyfile` subcommand
+    #[options(help = "generate a keyfile to open a stash with")]
+    Keyfile(KeyfileCmd),
+
+    /// The `kms-key` subcommand
+    #[options(help = "generate a master key wrapped by a key management service")]
+    KmsKey(KmsKey),
+
     /// The `start` subcommand
     #[options(help = "list files in a stash")]
     Ls(Ls),
 
+    /// The `migrate` subcommand
+    #[options(help = "rewrite the metadata of a stash in the current layout")]
+    Migrate(Migrate),
+
+    /// The `mount` subcommand
+    #[cfg(target_os = "linux")]
+    #[options(help = "mount the snapshots of a stash as a read-only file system")]
+    Mount(Mount),
+
+    /// The `orphans` subcommand
+    #[options(help = "list stored objects nothing refers to, and missing ones")]
+    Orphans(Orphans),
+
+    /// The `passwd` subcommand
+    #[options(help = "change the credentials of a stash")]
+    Passwd(Passwd),
+
+    /// The `prune` subcommand
+    #[options(help = "remove the snapshots a retention policy doesn't keep")]
+    Prune(Prune),
+
+    /// The `repack` subcommand
+    #[options(help = "move the chunks still in use out of sparse objects")]
+    Repack(Repack),
+
+    /// The `public-key` subcommand
+    #[options(help = "print the key append-only writers add to a stash with")]
+    PublicKey(PublicKeyCmd),
+
+    /// The `repair` subcommand
+    #[options(help = "rebuild lost or corrupt data objects from their parity")]
+    Repair(Repair),
+
+    /// The `serve` subcommand
+    #[options(help = "serve a stash read-only over HTTP")]
+    Serve(Serve),
+
+    /// The `sign-policy` subcommand
+    #[options(help = "sign who may write to which namespace of a stash")]
+    SignPolicy(SignPolicy),
+
+    /// The `snapshots` subcommand
+    #[options(help = "list the snapshots of a stash, by tag and label")]
+    Snapshots(Snapshots),
+
+    /// The `split-key` subcommand
+    #[options(help = "split the master key of a stash into shares")]
+    SplitKey(SplitKey),
+
+    /// The `stats` subcommand
+    #[options(help = "show the size of a stash and its snapshots")]
+    Stats(Stats),
+
+    /// The `status` subcommand
+    #[options(help = "list the files that changed since a snapshot")]
+    Status(Status),
+
+    /// The `sync` subcommand
+    #[options(help = "copy new snapshots to another stash")]
+    Sync(Sync),
+
+    /// The `unlock` subcommand
+    #[options(help = "remove locks left on a stash by processes that are gone")]
+    Unlock(Unlock),
+
+    /// The `verify` subcommand
+    #[options(help = "check that the data of a stash is there and intact")]
+    Verify(Verify),
+
+    /// The `watch` subcommand
+    #[options(help = "keep backing up changes to paths")]
+    Watch(Watch),
+
     /// The `start` subcommand
     #[options(help = "delete all data of a stash")]
     Wipe(Wipe),
+
+    /// The `writer-key` subcommand
+    #[options(help = "generate a key to sign snapshots with")]
+    WriterKey(WriterKeyCmd),
 }
 
 /// This trait allows you to define how application configuration is loaded.
 impl Configurable<ZerostashConfig> for ZerostashCmd {
     /// Location of the configuration file
     fn config_path(&self) -> Option<PathBuf> {
-        let filename = PathBuf::from(ZerostashConfig::path());
+        let filename = ZerostashConfig::path();
 
         if filename.exists() {
             Some(filename)
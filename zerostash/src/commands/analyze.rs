@@ -0,0 +1,44 @@
+//! `analyze` subcommand
+
+use crate::application::{app_reader, fatal_error2};
+use abscissa_core::{Command, Options, Runnable};
+use libzerostash::stash::{BackupOptions, Field};
+
+/// `analyze` subcommand
+///
+/// Reports how much new data backing up some paths would add to a
+/// stash, without uploading anything.
+#[derive(Command, Debug, Options)]
+pub struct Analyze {
+    #[options(free)]
+    stash: String,
+
+    #[options(free)]
+    paths: Vec<String>,
+}
+
+impl Runnable for Analyze {
+    /// Start the application.
+    fn run(&self) {
+        let app = &*app_reader();
+        let mut stash = app.stash_shared(&self.stash, &[Field::Chunks]);
+
+        let options = BackupOptions {
+            threads: Some(app.get_worker_threads()),
+            ..BackupOptions::default()
+        };
+        let analysis = stash
+            .analyze(&self.paths, &options)
+            .unwrap_or_else(|e| fatal_error2(e.into()));
+
+        println!("files: {}", analysis.files);
+        println!("read: {} bytes", analysis.bytes);
+        println!(
+            "new: {} bytes in {} chunks",
+            analysis.new_bytes, analysis.new_chunks
+        );
+        println!("deduplicated: {} bytes", analysis.dedup_bytes);
+        println!("compressed: {} bytes", analysis.compressed_bytes);
+        println!("upload: {} bytes", analysis.upload_bytes);
+    }
+}
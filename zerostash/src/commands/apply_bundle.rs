@@ -0,0 +1,37 @@
+//! `apply-bundle` subcommand
+
+use crate::application::{app_reader, fatal_error2};
+use abscissa_core::{Command, Options, Runnable};
+use libzerostash::stash::LockKind;
+use std::fs::File;
+use std::io::BufReader;
+
+/// `apply-bundle` subcommand
+///
+/// Adds a bundle made by `export-bundle` to a copy of the stash.
+#[derive(Command, Debug, Options)]
+pub struct ApplyBundle {
+    #[options(free)]
+    stash: String,
+
+    #[options(free)]
+    bundle: String,
+}
+
+impl Runnable for ApplyBundle {
+    /// Start the application.
+    fn run(&self) {
+        let app = &*app_reader();
+        let mut stash = app.open_stash(&self.stash);
+        stash
+            .lock(LockKind::Exclusive)
+            .unwrap_or_else(|e| fatal_error2(e.into()));
+
+        let file = File::open(&self.bundle).unwrap_or_else(|e| fatal_error2(e.into()));
+        let objects = stash
+            .apply_bundle(&mut BufReader::new(file))
+            .unwrap_or_else(|e| fatal_error2(e.into()));
+
+        println!("{} objects stored", objects);
+    }
+}
@@ -0,0 +1,68 @@
+//! `audit` subcommand
+
+use crate::application::{app_reader, fatal_error2};
+use abscissa_core::{Command, Options, Runnable};
+use libzerostash::crypto::CryptoDigest;
+use libzerostash::namespaces::{PublicKey, SignedPolicy};
+use libzerostash::stash::Field;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fs;
+
+/// `audit` subcommand
+///
+/// Checks that every snapshot of a shared stash is signed by a writer
+/// the policy allows in its namespace, and that none are missing.
+#[derive(Command, Debug, Options)]
+pub struct Audit {
+    #[options(help = "policy signed by sign-policy", required)]
+    policy: String,
+
+    #[options(help = "public key of the stash owner", required)]
+    owner: String,
+
+    #[options(help = "file keeping the namespace heads between audits")]
+    heads: Option<String>,
+
+    #[options(free)]
+    stash: String,
+}
+
+impl Runnable for Audit {
+    /// Start the application.
+    fn run(&self) {
+        let owner =
+            PublicKey::try_from(self.owner.clone()).unwrap_or_else(|e| fatal_error2(e.into()));
+        let policy = fs::read_to_string(&self.policy).unwrap_or_else(|e| fatal_error2(e.into()));
+        let policy: SignedPolicy =
+            toml::from_str(&policy).unwrap_or_else(|e| fatal_error2(e.into()));
+        let policy = policy
+            .verify(&owner)
+            .unwrap_or_else(|e| fatal_error2(e.into()));
+
+        let heads: BTreeMap<String, CryptoDigest> = match &self.heads {
+            Some(path) if fs::metadata(path).is_ok() => {
+                let heads = fs::read_to_string(path).unwrap_or_else(|e| fatal_error2(e.into()));
+                toml::from_str(&heads).unwrap_or_else(|e| fatal_error2(e.into()))
+            }
+            _ => BTreeMap::new(),
+        };
+
+        let app = &*app_reader();
+        let stash = app.stash_shared(&self.stash, &[Field::Snapshots]);
+        let snapshots = stash.snapshots();
+        let heads = policy
+            .audit(&snapshots, &heads)
+            .unwrap_or_else(|e| fatal_error2(e.into()));
+
+        if let Some(path) = &self.heads {
+            let heads = toml::to_string(&heads).unwrap_or_else(|e| fatal_error2(e.into()));
+            fs::write(path, heads).unwrap_or_else(|e| fatal_error2(e.into()));
+        }
+        println!(
+            "{} snapshots in {} namespaces are signed",
+            snapshots.len(),
+            heads.len()
+        );
+    }
+}
@@ -0,0 +1,56 @@
+//! `cat` subcommand
+
+use crate::application::{app_reader, fatal_error2};
+use abscissa_core::{Command, Options, Runnable};
+use anyhow::format_err;
+use libzerostash::stash::Field;
+use std::io::{self, BufWriter};
+
+/// `cat` subcommand
+///
+/// Writes the contents of a stored file to stdout, so it can be piped
+/// to another program without restoring it first.
+#[derive(Command, Debug, Options)]
+pub struct Cat {
+    #[options(help = "snapshot to read the file from, the latest version by default")]
+    snapshot: Option<u64>,
+
+    #[options(free)]
+    stash: String,
+
+    #[options(free)]
+    path: String,
+}
+
+impl Runnable for Cat {
+    /// Start the application.
+    fn run(&self) {
+        let app = &*app_reader();
+        let stdout = io::stdout();
+        let out = BufWriter::new(stdout.lock());
+
+        let result = match self.snapshot {
+            Some(id) => {
+                let stash = app.stash_shared(&self.stash, &[Field::Snapshots]);
+                let snapshot = stash
+                    .snapshots()
+                    .into_iter()
+                    .find(|s| s.id == id)
+                    .unwrap_or_else(|| fatal_error2(format_err!("No such snapshot").into()));
+                let entry = snapshot
+                    .files
+                    .iter()
+                    .find(|f| f.name == self.path)
+                    .unwrap_or_else(|| {
+                        fatal_error2(format_err!("No file is named {}", self.path).into())
+                    });
+                stash.read_entry_to(entry, out)
+            }
+            None => {
+                let mut stash = app.stash_shared(&self.stash, &[Field::Files]);
+                stash.read_to(&self.path, out)
+            }
+        };
+        result.unwrap_or_else(|e| fatal_error2(e.into()));
+    }
+}
@@ -1,7 +1,10 @@
 //! `checkout` subcommand
 
-use crate::application::{app_reader, fatal_error};
+use crate::application::app_reader;
+use crate::progress::StatusLine;
 use abscissa_core::{Command, Options, Runnable};
+use libzerostash::stash::{Collisions, Field, Remap, RestoreOptions};
+use std::sync::Arc;
 
 /// `checkout` subcommand
 ///
@@ -12,6 +15,18 @@ use abscissa_core::{Command, Options, Runnable};
 /// <https://docs.rs/gumdrop/>
 #[derive(Command, Debug, Options)]
 pub struct Checkout {
+    #[options(help = "restore paths starting with FROM under TO instead, as FROM=TO")]
+    remap: Vec<Remap>,
+
+    #[options(help = "paths naming the same file in the target: rename, skip or error")]
+    collisions: Option<Collisions>,
+
+    #[options(help = "show what's restored so far on stderr")]
+    progress: bool,
+
+    #[options(help = "read objects on this many threads, while the ones read are written")]
+    downloads: Option<usize>,
+
     #[options(free)]
     stash: String,
 
@@ -26,10 +41,26 @@ impl Runnable for Checkout {
     /// Start the application.
     fn run(&self) {
         let app = &*app_reader();
-        let mut stash = app.stash_exists(&self.stash);
+        let mut stash = app.stash_shared(&self.stash, &[Field::Files]);
+        let status = Arc::new(StatusLine::default());
+        if self.progress {
+            stash.set_progress(status.clone());
+        }
+        if let Some(threads) = self.downloads {
+            stash.set_downloads(threads);
+        }
 
-        stash
-            .restore_by_glob(app.get_worker_threads(), &self.paths, &self.target)
-            .expect("Error extracting data");
+        let options = RestoreOptions {
+            threads: Some(app.get_worker_threads()),
+            patterns: self.paths.clone(),
+            remap: self.remap.clone(),
+            collisions: self.collisions.unwrap_or_default(),
+            ..RestoreOptions::default()
+        };
+        let result = stash.checkout(&self.target, &options);
+        if self.progress {
+            status.finish();
+        }
+        result.expect("Error extracting data");
     }
 }
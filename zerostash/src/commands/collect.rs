@@ -0,0 +1,30 @@
+//! `collect` subcommand
+
+use crate::application::{app_reader, fatal_error2};
+use abscissa_core::{Command, Options, Runnable};
+
+/// `collect` subcommand
+///
+/// Copies the snapshots committed by append-only writers into the
+/// stash, so they are deduplicated and listed with the rest.
+#[derive(Command, Debug, Options)]
+pub struct Collect {
+    #[options(free)]
+    stash: String,
+}
+
+impl Runnable for Collect {
+    /// Start the application.
+    fn run(&self) {
+        // a stash with no commits of its own yet only has drops
+        let mut stash = app_reader().stash_to_extend(&self.stash);
+
+        let collected = stash
+            .collect_drops()
+            .unwrap_or_else(|e| fatal_error2(e.into()));
+        println!(
+            "{} snapshots collected, with {} new chunks",
+            collected.snapshots, collected.chunks
+        );
+    }
+}
@@ -1,7 +1,20 @@
 //! `commit` subcommand
 
-use crate::application::app_reader;
+use crate::application::{app_reader, fatal_error2};
+use crate::progress::StatusLine;
 use abscissa_core::{Command, Options, Runnable};
+use libzerostash::files::Entry;
+use libzerostash::journal::Journal;
+use libzerostash::metrics::Metrics;
+use libzerostash::namespaces::{Writer, WriterKey};
+use libzerostash::parity::Scheme;
+use libzerostash::snapshots::{parse_labels, Snapshot};
+use libzerostash::stash::{BackupOptions, Labels, LockKind, Schedule, Stash, ZerostashError};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 /// `commit` subcommand
 ///
@@ -12,6 +25,58 @@ use abscissa_core::{Command, Options, Runnable};
 /// <https://docs.rs/gumdrop/>
 #[derive(Command, Debug, Options)]
 pub struct Commit {
+    #[options(help = "file cache to skip unchanged files")]
+    cache: Option<String>,
+
+    #[options(
+        help = "save the file cache every this many seconds, so an interrupted commit resumes"
+    )]
+    checkpoint: Option<u64>,
+
+    #[options(help = "change journal state, to only look at changed files")]
+    journal: Option<String>,
+
+    #[options(help = "order of processing files: walk, small-first or interleave")]
+    schedule: Option<Schedule>,
+
+    #[options(
+        help = "chunk and compress large files, like disk images, and streams on all threads"
+    )]
+    parallel_chunking: bool,
+
+    #[options(help = "upload objects on this many threads, while the next ones are filled")]
+    uploads: Option<usize>,
+
+    #[options(help = "write parity objects for each group of data objects, as DATA+PARITY")]
+    parity: Option<Scheme>,
+
+    #[options(help = "write Prometheus metrics of the run to this file")]
+    metrics: Option<String>,
+
+    #[options(help = "push Prometheus metrics of the run to this Pushgateway")]
+    push_gateway: Option<String>,
+
+    #[options(help = "sign the snapshot with this writer key")]
+    writer_key: Option<String>,
+
+    #[options(help = "namespace of the signed snapshot")]
+    namespace: Option<String>,
+
+    #[options(help = "show what's stored so far on stderr")]
+    progress: bool,
+
+    #[options(help = "only report the files and chunks that would be stored")]
+    dry_run: bool,
+
+    #[options(help = "store stdin as a file with this name, instead of paths")]
+    stdin_name: Option<String>,
+
+    #[options(help = "tag the snapshot, can be given more than once")]
+    tag: Vec<String>,
+
+    #[options(help = "label the snapshot with KEY=VALUE, can be given more than once")]
+    label: Vec<String>,
+
     #[options(free)]
     stash: String,
 
@@ -24,13 +89,184 @@ impl Runnable for Commit {
     fn run(&self) {
         let app = &*app_reader();
         let mut stash = app.open_stash(&self.stash);
+        let kind = if self.dry_run {
+            LockKind::Shared
+        } else {
+            LockKind::Exclusive
+        };
+        stash.lock(kind).unwrap_or_else(|e| fatal_error2(e.into()));
+
+        if let Some(cache) = &self.cache {
+            stash
+                .use_file_cache(cache)
+                .expect("Failed to open file cache");
+        }
+
+        if let Some(secs) = self.checkpoint {
+            stash.set_checkpoints(Some(Duration::from_secs(secs)));
+        }
 
-        for path in self.paths.iter() {
+        if let Some(schedule) = self.schedule {
+            stash.set_schedule(schedule);
+        }
+        stash.set_parallel_chunking(self.parallel_chunking);
+        if let Some(threads) = self.uploads {
+            stash.set_uploads(threads);
+        }
+        if let Some(parity) = self.parity {
             stash
-                .add_recursive(app.get_worker_threads(), path)
-                .expect("Failed to add path");
+                .set_parity(Some(parity))
+                .expect("Invalid parity scheme");
         }
 
-        stash.commit().expect("Failed to write metadata");
+        let mut journal = self
+            .journal
+            .as_ref()
+            .map(|path| Journal::open(path).expect("Failed to open journal"));
+        let changed = journal
+            .as_mut()
+            .and_then(|j| j.changes(&self.paths).expect("Failed to read journal"));
+        let paths = self.paths.iter().map(PathBuf::from).collect::<Vec<_>>();
+
+        if self.dry_run {
+            let paths = match changed {
+                Some(changed) => changed.into_iter().filter(|p| p.exists()).collect(),
+                None => paths,
+            };
+            return dry_run(&mut stash, &paths, app.get_worker_threads());
+        }
+
+        let labels = parse_labels(&self.label).expect("Invalid label");
+
+        let metrics = Arc::new(Metrics::new(self.stash.as_str()));
+        let status = Arc::new(StatusLine::default());
+        if self.progress {
+            stash.set_progress(Arc::new((metrics.clone(), status.clone())));
+        } else {
+            stash.set_progress(metrics.clone());
+        }
+        let start = Instant::now();
+
+        let writer = self.writer_key.as_ref().map(|path| {
+            let pkcs8 = fs::read(path).expect("Failed to read writer key");
+            Writer {
+                namespace: self
+                    .namespace
+                    .clone()
+                    .expect("Signed snapshots need a namespace"),
+                key: Arc::new(WriterKey::from_pkcs8(&pkcs8).expect("Invalid writer key")),
+            }
+        });
+
+        let options = BackupOptions {
+            threads: Some(app.get_worker_threads()),
+            writer,
+            tags: self.tag.clone(),
+            labels,
+            ..BackupOptions::default()
+        };
+        // every commit is a snapshot that follows the earlier ones,
+        // and a signed one those of its namespace, so they need to be
+        // read first, or the new root would leave them out
+        let result = match stash.read() {
+            Ok(_) | Err(ZerostashError::WrongPassphrase) => match (&self.stdin_name, changed) {
+                (Some(name), _) => stdin_snapshot(
+                    &mut stash,
+                    name,
+                    options.tags.clone(),
+                    options.labels.clone(),
+                ),
+                // the journal only knows what changed since the last
+                // snapshot of the same paths
+                (None, Some(changed)) => match last_snapshot(&stash, &self.paths) {
+                    Some(base) => {
+                        let changed = changed.into_iter().collect::<Vec<_>>();
+                        stash.backup_changes(&base, &changed, &options)
+                    }
+                    None => stash.backup(&paths, &options),
+                }
+                .map(|_| ()),
+                (None, None) => stash.backup(&paths, &options).map(|_| ()),
+            },
+            Err(e) => Err(e),
+        };
+
+        if self.progress {
+            status.finish();
+        }
+        match &result {
+            Ok(_) => {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+                metrics.success(start.elapsed(), now.as_secs());
+            }
+            Err(_) => metrics.failure(start.elapsed()),
+        }
+        if let Some(path) = &self.metrics {
+            metrics
+                .write_textfile(path)
+                .expect("Failed to write metrics");
+        }
+        if let Some(address) = &self.push_gateway {
+            metrics
+                .push(address, "zerostash")
+                .expect("Failed to push metrics");
+        }
+
+        result.expect("Failed to commit");
+        if let Some(journal) = journal.as_mut() {
+            journal.save().expect("Failed to save journal");
+        }
+    }
+}
+
+/// The latest snapshot of exactly `paths`.
+fn last_snapshot(stash: &Stash, paths: &[String]) -> Option<Arc<Snapshot>> {
+    stash
+        .snapshots()
+        .into_iter()
+        .rev()
+        .find(|s| s.paths == paths)
+}
+
+/// Report what committing `paths` would store.
+fn dry_run(stash: &mut Stash, paths: &[PathBuf], threads: usize) {
+    let options = BackupOptions {
+        threads: Some(threads),
+        ..BackupOptions::default()
+    };
+    let dry = match stash.read() {
+        Ok(_) | Err(ZerostashError::WrongPassphrase) => stash.dry_run(paths, &options),
+        Err(e) => Err(e),
     }
+    .unwrap_or_else(|e| fatal_error2(e.into()));
+
+    let mut files = dry.stats.files;
+    files.sort_by(|a, b| a.name.cmp(&b.name));
+    for file in files.iter() {
+        println!(
+            "{}: {} of {} bytes new",
+            file.name, file.new_bytes, file.bytes
+        );
+    }
+    println!("files: {} walked, {} read", dry.summary.files, files.len());
+    println!("read: {} bytes", dry.stats.logical_bytes);
+    println!(
+        "new: {} bytes in {} chunks",
+        dry.stats.new_bytes, dry.stats.new_chunks
+    );
+}
+
+/// Store stdin as the only file of a new snapshot, called `name`.
+fn stdin_snapshot(
+    stash: &mut Stash,
+    name: &str,
+    tags: Vec<String>,
+    labels: Labels,
+) -> Result<(), ZerostashError> {
+    let mut ingest = stash.ingest()?;
+    ingest.add_stream(Entry::from_stream(name), &mut io::stdin().lock())?;
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    ingest.finish(now.as_secs(), vec![name.into()], tags, labels)?;
+    Ok(())
 }
@@ -0,0 +1,30 @@
+//! `compact` subcommand
+
+use crate::application::{app_reader, fatal_error2};
+use abscissa_core::{Command, Options, Runnable};
+use libzerostash::stash::Field;
+
+/// `compact` subcommand
+///
+/// Rewrites the metadata of a stash, and marks what earlier commits
+/// left behind to be deleted by `gc`.
+#[derive(Command, Debug, Options)]
+pub struct Compact {
+    #[options(free)]
+    stash: String,
+}
+
+impl Runnable for Compact {
+    /// Start the application.
+    fn run(&self) {
+        let app = &*app_reader();
+        let mut stash = app.stash_locked(&self.stash, &[Field::Snapshots]);
+
+        let compacted = stash.compact().unwrap_or_else(|e| fatal_error2(e.into()));
+        println!(
+            "retired {} metadata objects of {} commits",
+            compacted.objects.len(),
+            compacted.commits
+        );
+    }
+}
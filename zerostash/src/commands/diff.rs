@@ -0,0 +1,68 @@
+//! `diff` subcommand
+
+use crate::application::{app_reader, fatal_error2};
+use abscissa_core::{Command, Options, Runnable};
+use anyhow::format_err;
+use libzerostash::stash::{self, Change, Field};
+
+/// `diff` subcommand
+///
+/// Lists the files added (`+`), removed (`-`) and modified (`M`)
+/// between two snapshots, with how much their size changed. Without
+/// snapshots, the last two are compared, and with one, it's compared
+/// to the latest.
+#[derive(Command, Debug, Options)]
+pub struct Diff {
+    #[options(free)]
+    stash: String,
+
+    #[options(free)]
+    snapshots: Vec<u64>,
+}
+
+impl Runnable for Diff {
+    /// Start the application.
+    fn run(&self) {
+        let app = &*app_reader();
+        let stash = app.stash_shared(&self.stash, &[Field::Snapshots]);
+
+        let snapshots = stash.snapshots();
+        let get = |id: u64| {
+            snapshots
+                .iter()
+                .find(|s| s.id == id)
+                .unwrap_or_else(|| fatal_error2(format_err!("No such snapshot: {}", id).into()))
+        };
+        let latest = || {
+            snapshots
+                .last()
+                .unwrap_or_else(|| fatal_error2(format_err!("The stash has no snapshots").into()))
+        };
+        let (from, to) = match self.snapshots[..] {
+            [] if snapshots.len() < 2 => {
+                fatal_error2(format_err!("The stash has fewer than two snapshots").into())
+            }
+            [] => (&snapshots[snapshots.len() - 2], latest()),
+            [from] => (get(from), latest()),
+            [from, to] => (get(from), get(to)),
+            _ => fatal_error2(format_err!("Expected at most two snapshots").into()),
+        };
+
+        let diff = stash::diff(from, to);
+        for change in diff.changes.iter() {
+            let kind = match change {
+                Change::Added(_) => "+",
+                Change::Removed(_) => "-",
+                Change::Modified { .. } => "M",
+            };
+            println!("{} {}\t{:+}", kind, change.name(), change.size_delta());
+        }
+        println!(
+            "{} added, {} removed, {} modified, {:+} bytes",
+            diff.added(),
+            diff.removed(),
+            diff.modified(),
+            diff.size_delta()
+        );
+    }
+}
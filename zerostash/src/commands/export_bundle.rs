@@ -0,0 +1,39 @@
+//! `export-bundle` subcommand
+
+use crate::application::{app_reader, fatal_error2};
+use abscissa_core::{Command, Options, Runnable};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+/// `export-bundle` subcommand
+///
+/// Writes everything stored since a snapshot into a single file,
+/// which `apply-bundle` adds to another copy of the stash.
+#[derive(Command, Debug, Options)]
+pub struct ExportBundle {
+    #[options(help = "only include what was stored after this snapshot")]
+    since: Option<u64>,
+
+    #[options(free)]
+    stash: String,
+
+    #[options(free)]
+    output: String,
+}
+
+impl Runnable for ExportBundle {
+    /// Start the application.
+    fn run(&self) {
+        let app = &*app_reader();
+        let mut stash = app.stash_shared(&self.stash, &[]);
+
+        let file = File::create(&self.output).unwrap_or_else(|e| fatal_error2(e.into()));
+        let mut out = BufWriter::new(file);
+        let objects = stash
+            .export_bundle(self.since, &mut out)
+            .unwrap_or_else(|e| fatal_error2(e.into()));
+        out.flush().unwrap_or_else(|e| fatal_error2(e.into()));
+
+        println!("{} objects written to {}", objects, self.output);
+    }
+}
@@ -0,0 +1,40 @@
+//! `export-manifest` subcommand
+
+use crate::application::{app_reader, fatal_error2};
+use abscissa_core::{Command, Options, Runnable};
+use anyhow::format_err;
+use libzerostash::stash::Field;
+use std::io::{self, BufWriter};
+
+/// `export-manifest` subcommand
+///
+/// Prints the files of a snapshot as newline-delimited JSON, for
+/// indexing and auditing the contents of backups elsewhere.
+#[derive(Command, Debug, Options)]
+pub struct ExportManifest {
+    #[options(help = "snapshot to list, the latest by default")]
+    snapshot: Option<u64>,
+
+    #[options(free)]
+    stash: String,
+}
+
+impl Runnable for ExportManifest {
+    /// Start the application.
+    fn run(&self) {
+        let app = &*app_reader();
+        let stash = app.stash_shared(&self.stash, &[Field::Snapshots]);
+
+        let snapshots = stash.snapshots();
+        let snapshot = match self.snapshot {
+            Some(id) => snapshots.iter().find(|s| s.id == id),
+            None => snapshots.last(),
+        }
+        .unwrap_or_else(|| fatal_error2(format_err!("No such snapshot").into()));
+
+        let stdout = io::stdout();
+        stash
+            .export_manifest(snapshot, BufWriter::new(stdout.lock()))
+            .unwrap_or_else(|e| fatal_error2(e.into()));
+    }
+}
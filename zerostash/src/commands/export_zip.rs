@@ -0,0 +1,49 @@
+//! `export-zip` subcommand
+
+use crate::application::{app_reader, fatal_error2};
+use abscissa_core::{Command, Options, Runnable};
+use anyhow::format_err;
+use libzerostash::stash::Field;
+use std::fs::File;
+use std::io::BufWriter;
+
+/// `export-zip` subcommand
+///
+/// Writes a directory of a snapshot to a zip archive, without
+/// restoring it first.
+#[derive(Command, Debug, Options)]
+pub struct ExportZip {
+    #[options(help = "snapshot to export from, the latest by default")]
+    snapshot: Option<u64>,
+
+    #[options(free)]
+    stash: String,
+
+    #[options(free)]
+    prefix: String,
+
+    #[options(free)]
+    output: String,
+}
+
+impl Runnable for ExportZip {
+    /// Start the application.
+    fn run(&self) {
+        let app = &*app_reader();
+        let stash = app.stash_shared(&self.stash, &[Field::Snapshots]);
+
+        let snapshots = stash.snapshots();
+        let snapshot = match self.snapshot {
+            Some(id) => snapshots.iter().find(|s| s.id == id),
+            None => snapshots.last(),
+        }
+        .unwrap_or_else(|| fatal_error2(format_err!("No such snapshot").into()));
+
+        let file = File::create(&self.output).unwrap_or_else(|e| fatal_error2(e.into()));
+        let files = stash
+            .export_zip(snapshot, &self.prefix, BufWriter::new(file))
+            .unwrap_or_else(|e| fatal_error2(e.into()));
+
+        println!("{} files written to {}", files, self.output);
+    }
+}
@@ -0,0 +1,89 @@
+//! `find` subcommand
+
+use crate::application::{app_reader, fatal_error2};
+use abscissa_core::{Command, Options, Runnable};
+use libzerostash::stash::Query;
+
+/// `find` subcommand
+///
+/// Searches all snapshots for files, and lists every version found
+/// with the ids of the snapshots that have it.
+#[derive(Command, Debug, Options)]
+pub struct Find {
+    #[options(help = "regular expression the path has to match")]
+    regex: Option<String>,
+
+    #[options(help = "smallest size in bytes")]
+    min_size: Option<u64>,
+
+    #[options(help = "largest size in bytes")]
+    max_size: Option<u64>,
+
+    #[options(help = "modified at or after this time, in seconds since the epoch")]
+    newer: Option<u64>,
+
+    #[options(help = "modified before this time, in seconds since the epoch")]
+    older: Option<u64>,
+
+    #[options(help = "owned by this user id")]
+    uid: Option<u32>,
+
+    #[options(help = "owned by this group id")]
+    gid: Option<u32>,
+
+    #[options(help = "only search this snapshot")]
+    snapshot: Option<u64>,
+
+    #[options(free)]
+    stash: String,
+
+    #[options(free)]
+    patterns: Vec<String>,
+}
+
+impl Runnable for Find {
+    /// Start the application.
+    fn run(&self) {
+        let app = &*app_reader();
+        // the snapshots are only read if there's no find index of
+        // this commit
+        let mut stash = app.stash_shared(&self.stash, &[]);
+
+        let query = Query {
+            patterns: self
+                .patterns
+                .iter()
+                .map(|p| glob::Pattern::new(p).unwrap_or_else(|e| fatal_error2(e.into())))
+                .collect(),
+            regex: self
+                .regex
+                .as_ref()
+                .map(|r| regex::Regex::new(r).unwrap_or_else(|e| fatal_error2(e.into()))),
+            min_size: self.min_size,
+            max_size: self.max_size,
+            newer_than: self.newer,
+            older_than: self.older,
+            uid: self.uid,
+            gid: self.gid,
+            snapshot: self.snapshot,
+        };
+
+        let found = stash
+            .find(&query)
+            .unwrap_or_else(|e| fatal_error2(e.into()));
+        for f in found {
+            let snapshots = f
+                .snapshots
+                .iter()
+                .map(|id| id.to_string())
+                .collect::<Vec<_>>();
+            println!(
+                "{}\t{}\t{}\t{}",
+                f.entry.name,
+                f.entry.size,
+                f.entry.unix_secs,
+                snapshots.join(",")
+            );
+        }
+    }
+}
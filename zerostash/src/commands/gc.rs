@@ -0,0 +1,58 @@
+//! `gc` subcommand
+
+use crate::application::{app_reader, fatal_error2};
+use abscissa_core::{Command, Options, Runnable};
+use libzerostash::stash::{Field, GcOptions};
+use std::time::Duration;
+
+/// `gc` subcommand
+///
+/// Deletes the data objects no file of the stash refers to anymore,
+/// once they've been unreferenced for the grace period.
+#[derive(Command, Debug, Options)]
+pub struct Gc {
+    #[options(free)]
+    stash: String,
+
+    #[options(
+        no_short,
+        meta = "HOURS",
+        help = "keep unreferenced objects this long",
+        default = "24"
+    )]
+    grace: u64,
+
+    #[options(short = "n", help = "only list the objects to delete")]
+    dry_run: bool,
+}
+
+impl Runnable for Gc {
+    /// Start the application.
+    fn run(&self) {
+        let app = &*app_reader();
+        let mut stash = app.stash_locked(&self.stash, &[Field::Snapshots]);
+        let options = GcOptions {
+            dry_run: self.dry_run,
+            grace: Duration::from_secs(self.grace * 60 * 60),
+            ..GcOptions::default()
+        };
+
+        let collected = stash
+            .collect_garbage(&options)
+            .unwrap_or_else(|e| fatal_error2(e.into()));
+        let verb = if self.dry_run {
+            "would delete"
+        } else {
+            "deleted"
+        };
+        for object in collected.deleted.iter() {
+            println!("{} {}", verb, object.to_string());
+        }
+        println!(
+            "{} {} objects, {} waiting for the grace period",
+            verb,
+            collected.deleted.len(),
+            collected.pending.len()
+        );
+    }
+}
@@ -0,0 +1,75 @@
+//! `grant` subcommand
+
+use crate::application::{app_reader, fatal_error2};
+use abscissa_core::{Command, Options, Runnable};
+use anyhow::format_err;
+use libzerostash::crypto::keyfile::Keyfile;
+use libzerostash::stash::Field;
+use std::fs;
+use std::io::Write;
+
+/// `grant` subcommand
+///
+/// Gives read access to some snapshots of a stash, or a directory of
+/// them, with a keyfile that opens nothing else, and prints it as a
+/// `key` of the configuration. The grant is named, to revoke it.
+#[derive(Command, Debug, Options)]
+pub struct Grant {
+    #[options(help = "grant the files of this snapshot, may be repeated")]
+    snapshot: Vec<u64>,
+
+    #[options(help = "only grant the files under this path")]
+    prefix: Option<String>,
+
+    #[options(help = "write the key of the grant to this keyfile")]
+    keyfile: Option<String>,
+
+    #[options(help = "revoke the grant instead")]
+    revoke: bool,
+
+    #[options(free)]
+    stash: String,
+
+    #[options(free)]
+    name: String,
+}
+
+impl Runnable for Grant {
+    /// Start the application.
+    fn run(&self) {
+        let app = &*app_reader();
+        if self.revoke {
+            let mut stash = app.stash_exists(&self.stash, &[]);
+            stash
+                .revoke_grant(&self.name)
+                .unwrap_or_else(|e| fatal_error2(e.into()));
+            return;
+        }
+
+        let path = self
+            .keyfile
+            .as_ref()
+            .unwrap_or_else(|| fatal_error2(format_err!("A keyfile to write is needed").into()));
+        let mut stash = app.stash_exists(&self.stash, &[Field::Files, Field::Snapshots]);
+        let key = stash
+            .grant(&self.name, &self.snapshot, self.prefix.as_deref())
+            .unwrap_or_else(|e| fatal_error2(e.into()));
+        let keyfile = Keyfile::from_key(key);
+
+        // never overwrite the only copy of another key
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options
+            .open(path)
+            .and_then(|mut file| file.write_all(keyfile.to_json().as_bytes()))
+            .unwrap_or_else(|e| fatal_error2(e.into()));
+
+        println!(
+            "key = {{ source = \"keyfile\", path = \"{}\" }}  # id {}",
+            path,
+            keyfile.id()
+        );
+    }
+}
@@ -0,0 +1,36 @@
+//! `import-borg` subcommand
+
+use crate::application::{app_reader, fatal_error};
+use abscissa_core::{Command, Options, Runnable};
+use libzerostash::import::borg::Borg;
+
+/// `import-borg` subcommand
+///
+/// The passphrase of the repository is asked for by `borg` itself,
+/// or taken from `BORG_PASSPHRASE` in the environment.
+#[derive(Command, Debug, Options)]
+pub struct ImportBorg {
+    #[options(free)]
+    stash: String,
+
+    #[options(free)]
+    repository: String,
+}
+
+impl Runnable for ImportBorg {
+    /// Start the application.
+    fn run(&self) {
+        let app = &*app_reader();
+        let mut stash = app.stash_to_extend(&self.stash);
+
+        let borg = Borg::new(self.repository.as_str());
+        let archives = borg.archives().unwrap_or_else(|e| fatal_error(e.into()));
+
+        for snapshot in borg
+            .import(&mut stash, &archives)
+            .unwrap_or_else(|e| fatal_error(e.into()))
+        {
+            println!("{}: {}", snapshot.id, snapshot.tags.join(" "));
+        }
+    }
+}
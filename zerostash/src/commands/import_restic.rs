@@ -0,0 +1,46 @@
+//! `import-restic` subcommand
+
+use crate::application::{app_reader, fatal_error};
+use crate::config::TtyPrompt;
+use abscissa_core::{Command, Options, Runnable};
+use libzerostash::import::restic::Repository;
+use libzerostash::prompt::SecretPrompt;
+use secrecy::ExposeSecret;
+
+/// `import-restic` subcommand
+///
+/// The `Options` proc macro generates an option parser based on the struct
+/// definition, and is defined in the `gumdrop` crate. See their documentation
+/// for a more comprehensive example:
+///
+/// <https://docs.rs/gumdrop/>
+#[derive(Command, Debug, Options)]
+pub struct ImportRestic {
+    #[options(free)]
+    stash: String,
+
+    #[options(free)]
+    repository: String,
+}
+
+impl Runnable for ImportRestic {
+    /// Start the application.
+    fn run(&self) {
+        let app = &*app_reader();
+        let mut stash = app.stash_to_extend(&self.stash);
+
+        let password = TtyPrompt
+            .ask_secret("Restic password: ")
+            .unwrap_or_else(|e| fatal_error(e.into()));
+        let repo = Repository::open(&self.repository, password.expose_secret())
+            .unwrap_or_else(|e| fatal_error(e.into()));
+        let snapshots = repo.snapshots().unwrap_or_else(|e| fatal_error(e.into()));
+
+        for snapshot in repo
+            .import(&mut stash, &snapshots)
+            .unwrap_or_else(|e| fatal_error(e.into()))
+        {
+            println!("{}: {}", snapshot.id, snapshot.paths.join(" "));
+        }
+    }
+}
@@ -0,0 +1,74 @@
+//! `import-tar` subcommand
+
+use crate::application::{app_reader, fatal_error};
+use abscissa_core::{Command, Options, Runnable};
+use libzerostash::import::tarball::Tarball;
+use libzerostash::snapshots::parse_labels;
+use std::fs::File;
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// `import-tar` subcommand
+///
+/// Stores the members of a tar archive, or of the one on stdin with
+/// `-`, as a snapshot, without unpacking it first. Archives
+/// compressed with gzip or zstd are recognized.
+#[derive(Command, Debug, Options)]
+pub struct ImportTar {
+    #[options(help = "time of the snapshot, in seconds since the epoch, the archive's by default")]
+    time: Option<u64>,
+
+    #[options(help = "tag the snapshot, can be given more than once")]
+    tag: Vec<String>,
+
+    #[options(help = "label the snapshot with KEY=VALUE, can be given more than once")]
+    label: Vec<String>,
+
+    #[options(free)]
+    stash: String,
+
+    #[options(free)]
+    archive: String,
+}
+
+impl Runnable for ImportTar {
+    /// Start the application.
+    fn run(&self) {
+        let app = &*app_reader();
+        let mut stash = app.stash_to_extend(&self.stash);
+
+        let labels = parse_labels(&self.label).expect("Invalid label");
+        let archive = match self.archive.as_str() {
+            "-" => None,
+            path => Some(File::open(path).unwrap_or_else(|e| fatal_error(e.into()))),
+        };
+        let modified = archive
+            .as_ref()
+            .and_then(|f| f.metadata().ok())
+            .and_then(|m| m.modified().ok())
+            .unwrap_or_else(SystemTime::now);
+        let unix_secs = self
+            .time
+            .unwrap_or_else(|| modified.duration_since(UNIX_EPOCH).unwrap().as_secs());
+
+        let mut tarball = Tarball::new(unix_secs);
+        for tag in self.tag.iter() {
+            tarball = tarball.tag(tag.as_str());
+        }
+        for (key, value) in labels {
+            tarball = tarball.label(key, value);
+        }
+
+        let snapshot = match archive {
+            Some(file) => tarball.import(&mut stash, file),
+            None => tarball.import(&mut stash, io::stdin()),
+        }
+        .unwrap_or_else(|e| fatal_error(e.into()));
+        println!(
+            "{}: {} files, {}",
+            snapshot.id,
+            snapshot.files.len(),
+            snapshot.paths.join(" ")
+        );
+    }
+}
@@ -0,0 +1,74 @@
+//! `key-slot` subcommand
+
+use crate::application::{app_reader, fatal_error2};
+use crate::config::TtyPrompt;
+use abscissa_core::{Command, Options, Runnable};
+use anyhow::format_err;
+use libzerostash::crypto::keyfile::Keyfile;
+use libzerostash::prompt::SecretPrompt;
+use libzerostash::Kdf;
+use secrecy::ExposeSecret;
+
+use std::fs;
+use std::time::Duration;
+
+/// `key-slot` subcommand
+///
+/// Lists the key slots of a stash, each with credentials that open
+/// it, or adds or revokes one.
+#[derive(Command, Debug, Options)]
+pub struct KeySlot {
+    #[options(help = "add a slot with new credentials")]
+    add: Option<String>,
+
+    #[options(help = "open the added slot with a keyfile instead")]
+    keyfile: Option<String>,
+
+    #[options(help = "revoke the credentials of a slot")]
+    revoke: Option<String>,
+
+    #[options(free)]
+    stash: String,
+}
+
+impl Runnable for KeySlot {
+    /// Start the application.
+    fn run(&self) {
+        let mut stash = app_reader().stash_exists(&self.stash, &[]);
+
+        fn fail<T>(e: std::io::Error) -> T {
+            fatal_error2(e.into())
+        }
+        if let (Some(name), Some(path)) = (&self.add, &self.keyfile) {
+            let data = fs::read(path).unwrap_or_else(fail);
+            let keyfile = Keyfile::parse(&data).unwrap_or_else(|e| fatal_error2(e.into()));
+            stash
+                .add_slot(name, &keyfile.into_key())
+                .unwrap_or_else(|e| fatal_error2(e.into()));
+        } else if let Some(name) = &self.add {
+            let user = TtyPrompt.ask("Username: ").unwrap_or_else(fail);
+            let password = TtyPrompt.ask_secret("Password: ").unwrap_or_else(fail);
+            let again = TtyPrompt
+                .ask_secret("Repeat the password: ")
+                .unwrap_or_else(fail);
+            if password.expose_secret() != again.expose_secret() {
+                fatal_error2(format_err!("The passwords don't match").into());
+            }
+
+            let kdf =
+                Kdf::calibrate(Duration::from_secs(1)).unwrap_or_else(|e| fatal_error2(e.into()));
+            stash
+                .add_credentials(name, &user, password.expose_secret(), &kdf)
+                .unwrap_or_else(|e| fatal_error2(e.into()));
+        }
+        if let Some(name) = &self.revoke {
+            stash
+                .revoke_slot(name)
+                .unwrap_or_else(|e| fatal_error2(e.into()));
+        }
+
+        for name in stash.slots().unwrap_or_else(|e| fatal_error2(e.into())) {
+            println!("{}", name);
+        }
+    }
+}
@@ -0,0 +1,41 @@
+//! `keyfile` subcommand
+
+use crate::application::fatal_error2;
+use abscissa_core::{Command, Options, Runnable};
+use libzerostash::crypto::keyfile::Keyfile;
+use std::fs;
+use std::io::Write;
+
+/// `keyfile` subcommand
+///
+/// Generates a keyfile, to open a new stash or a key slot of an
+/// existing one without a passphrase, and prints it as a `key` of the
+/// configuration.
+#[derive(Command, Debug, Options)]
+pub struct KeyfileCmd {
+    #[options(free)]
+    path: String,
+}
+
+impl Runnable for KeyfileCmd {
+    /// Start the application.
+    fn run(&self) {
+        let keyfile = Keyfile::generate();
+
+        // never overwrite the only copy of another key
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(keyfile.to_json().as_bytes()))
+            .unwrap_or_else(|e| fatal_error2(e.into()));
+
+        println!(
+            "key = {{ source = \"keyfile\", path = \"{}\" }}  # id {}",
+            self.path,
+            keyfile.id()
+        );
+    }
+}
@@ -0,0 +1,69 @@
+//! `kms-key` subcommand
+
+use crate::application::fatal_error2;
+use abscissa_core::{Command, Options, Runnable};
+use anyhow::format_err;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use libzerostash::{config::KmsService, kms};
+
+/// `kms-key` subcommand
+///
+/// Generates a master key for a new stash, wrapped by a key
+/// management service, or the key with the given id on a PKCS#11
+/// token, and prints it as a `key` of the configuration.
+#[derive(Command, Debug, Options)]
+pub struct KmsKey {
+    #[options(
+        help = "service to wrap the key with: aws, gcp, vault or pkcs11",
+        default = "aws"
+    )]
+    service: String,
+
+    #[options(help = "PKCS#11 module of the token, for pkcs11")]
+    module: Option<String>,
+
+    #[options(free)]
+    key: String,
+}
+
+impl Runnable for KmsKey {
+    /// Start the application.
+    fn run(&self) {
+        let service = match self.service.as_str() {
+            "aws" => KmsService::Aws,
+            "gcp" => KmsService::Gcp,
+            "vault" => KmsService::Vault,
+            "pkcs11" => return self.wrap_on_token(),
+            s => fatal_error2(format_err!("Unknown key management service: {}", s).into()),
+        };
+
+        let (_, wrapped) =
+            kms::new_key(&*service.wrapper(&self.key)).unwrap_or_else(|e| fatal_error2(e.into()));
+
+        println!(
+            "key = {{ source = \"kms\", service = \"{}\", key = \"{}\", wrapped = \"{}\" }}",
+            self.service,
+            self.key,
+            BASE64.encode(wrapped)
+        );
+    }
+}
+
+impl KmsKey {
+    fn wrap_on_token(&self) {
+        let module = self
+            .module
+            .as_ref()
+            .unwrap_or_else(|| fatal_error2(format_err!("--module is needed for pkcs11").into()));
+
+        let (_, wrapped) = kms::new_key(&kms::Pkcs11::new(module, &self.key))
+            .unwrap_or_else(|e| fatal_error2(e.into()));
+
+        println!(
+            "key = {{ source = \"pkcs11\", module = \"{}\", id = \"{}\", wrapped = \"{}\" }}",
+            module,
+            self.key,
+            BASE64.encode(wrapped)
+        );
+    }
+}
@@ -1,9 +1,8 @@
 //! `ls` subcommand
 
-use crate::application::app_reader;
+use crate::application::{app_reader, fatal_error2};
 use abscissa_core::{Command, Options, Runnable};
-use anyhow::{format_err, Error};
-use std::process;
+use libzerostash::stash::Field;
 
 /// `ls` subcommand
 ///
@@ -25,9 +24,13 @@ impl Runnable for Ls {
     /// Start the application.
     fn run(&self) {
         let app = &*app_reader();
-        let mut stash = app.stash_exists(&self.stash);
+        let stash = app.stash_shared(&self.stash, &[Field::Files]);
 
-        for file in stash.list(&self.paths) {
+        let files = stash
+            .list(&self.paths)
+            .unwrap_or_else(|e| fatal_error2(e.into()));
+
+        for file in files {
             println!("{}", file.name);
         }
     }
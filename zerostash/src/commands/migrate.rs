@@ -0,0 +1,34 @@
+//! `migrate` subcommand
+
+use crate::application::{app_reader, fatal_error2};
+use abscissa_core::{Command, Options, Runnable};
+use libzerostash::stash::Field;
+
+/// `migrate` subcommand
+///
+/// Rewrites the metadata of a stash in the layout of this build, if
+/// it's older.
+#[derive(Command, Debug, Options)]
+pub struct Migrate {
+    #[options(free)]
+    stash: String,
+}
+
+impl Runnable for Migrate {
+    /// Start the application.
+    fn run(&self) {
+        let app = &*app_reader();
+        let mut stash = app.stash_locked(&self.stash, &[Field::Snapshots]);
+
+        let from = stash.metadata_version();
+        match stash.migrate() {
+            Ok(true) => println!(
+                "migrated metadata from version {} to {}",
+                from,
+                stash.metadata_version()
+            ),
+            Ok(false) => println!("metadata is already at version {}", from),
+            Err(e) => fatal_error2(e.into()),
+        }
+    }
+}
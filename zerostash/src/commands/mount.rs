@@ -0,0 +1,40 @@
+//! `mount` subcommand
+
+use crate::application::{app_reader, fatal_error2};
+use abscissa_core::{Command, Options, Runnable};
+use libzerostash::fuse::Fuse;
+use libzerostash::stash::Field;
+
+/// `mount` subcommand
+///
+/// Mounts the snapshots of the stash as a read-only file system, one
+/// directory for each, until it's unmounted.
+#[derive(Command, Debug, Options)]
+pub struct Mount {
+    #[options(help = "only mount this snapshot, may be repeated")]
+    snapshot: Vec<u64>,
+
+    #[options(free)]
+    stash: String,
+
+    #[options(free)]
+    mountpoint: String,
+}
+
+impl Runnable for Mount {
+    /// Start the application.
+    fn run(&self) {
+        let app = &*app_reader();
+        let stash = app.stash_shared(&self.stash, &[Field::Snapshots]);
+        let snapshots = stash
+            .snapshots()
+            .into_iter()
+            .filter(|s| self.snapshot.is_empty() || self.snapshot.contains(&s.id))
+            .collect();
+
+        let fuse = Fuse::new(&stash, snapshots).unwrap_or_else(|e| fatal_error2(e.into()));
+        println!("Mounting {} on {}", self.stash, self.mountpoint);
+        fuse.mount(&self.mountpoint)
+            .unwrap_or_else(|e| fatal_error2(e.into()));
+    }
+}
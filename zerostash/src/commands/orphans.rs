@@ -0,0 +1,37 @@
+//! `orphans` subcommand
+
+use crate::application::{app_reader, fatal_error2};
+use abscissa_core::{Command, Options, Runnable};
+use libzerostash::stash::Field;
+
+/// `orphans` subcommand
+///
+/// Lists the objects in the storage of a stash that nothing in it
+/// refers to (`?`), and those it refers to that are missing (`!`),
+/// without changing anything.
+#[derive(Command, Debug, Options)]
+pub struct Orphans {
+    #[options(free)]
+    stash: String,
+}
+
+impl Runnable for Orphans {
+    /// Start the application.
+    fn run(&self) {
+        let app = &*app_reader();
+        let mut stash = app.stash_shared(&self.stash, &[Field::Snapshots]);
+
+        let orphans = stash.orphans().unwrap_or_else(|e| fatal_error2(e.into()));
+        for object in orphans.unreferenced.iter() {
+            println!("? {}", object.to_string());
+        }
+        for object in orphans.missing.iter() {
+            println!("! {}", object.to_string());
+        }
+        println!(
+            "{} objects unreferenced, {} missing",
+            orphans.unreferenced.len(),
+            orphans.missing.len()
+        );
+    }
+}
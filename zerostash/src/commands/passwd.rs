@@ -0,0 +1,48 @@
+//! `passwd` subcommand
+
+use crate::application::{app_reader, fatal_error2};
+use crate::config::TtyPrompt;
+use abscissa_core::{Command, Options, Runnable};
+use anyhow::format_err;
+use libzerostash::prompt::SecretPrompt;
+use libzerostash::Kdf;
+use secrecy::ExposeSecret;
+
+use std::time::Duration;
+
+/// `passwd` subcommand
+///
+/// Changes the credentials of a stash. Only its key object is
+/// rewritten, the data stays encrypted with the same master key.
+/// The new key is derived with parameters calibrated on this machine.
+#[derive(Command, Debug, Options)]
+pub struct Passwd {
+    #[options(free)]
+    stash: String,
+}
+
+impl Runnable for Passwd {
+    /// Start the application.
+    fn run(&self) {
+        // reading the root object checks the current credentials
+        let mut stash = app_reader().stash_exists(&self.stash, &[]);
+
+        fn fail<T>(e: std::io::Error) -> T {
+            fatal_error2(e.into())
+        }
+        let user = TtyPrompt.ask("New username: ").unwrap_or_else(fail);
+        let password = TtyPrompt.ask_secret("New password: ").unwrap_or_else(fail);
+        let again = TtyPrompt
+            .ask_secret("Repeat the new password: ")
+            .unwrap_or_else(fail);
+        if password.expose_secret() != again.expose_secret() {
+            fatal_error2(format_err!("The passwords don't match").into());
+        }
+
+        let kdf = Kdf::calibrate(Duration::from_secs(1)).unwrap_or_else(|e| fatal_error2(e.into()));
+        stash
+            .change_credentials(&user, password.expose_secret(), &kdf)
+            .unwrap_or_else(|e| fatal_error2(e.into()));
+        println!("The credentials of {} are changed", self.stash);
+    }
+}
@@ -0,0 +1,73 @@
+//! `prune` subcommand
+
+use crate::application::{app_reader, fatal_error2};
+use abscissa_core::{Command, Options, Runnable};
+use libzerostash::stash::{Field, Retention};
+
+/// `prune` subcommand
+///
+/// Removes the snapshots the `--keep-*` rules don't keep, and the
+/// files and chunks only they referred to. With `--dry-run`, only
+/// lists the snapshots that would be removed.
+#[derive(Command, Debug, Options)]
+pub struct Prune {
+    #[options(free)]
+    stash: String,
+
+    #[options(no_short, meta = "N", help = "keep the latest N snapshots")]
+    keep_last: usize,
+
+    #[options(no_short, meta = "N", help = "keep the latest snapshot of N days")]
+    keep_daily: usize,
+
+    #[options(no_short, meta = "N", help = "keep the latest snapshot of N weeks")]
+    keep_weekly: usize,
+
+    #[options(no_short, meta = "N", help = "keep the latest snapshot of N months")]
+    keep_monthly: usize,
+
+    #[options(no_short, meta = "N", help = "keep the latest snapshot of N years")]
+    keep_yearly: usize,
+
+    #[options(short = "n", help = "only list the snapshots to remove")]
+    dry_run: bool,
+}
+
+impl Runnable for Prune {
+    /// Start the application.
+    fn run(&self) {
+        let app = &*app_reader();
+        let mut stash = app.stash_locked(&self.stash, &[Field::Snapshots]);
+        let retention = Retention {
+            last: self.keep_last,
+            daily: self.keep_daily,
+            weekly: self.keep_weekly,
+            monthly: self.keep_monthly,
+            yearly: self.keep_yearly,
+        };
+
+        if self.dry_run {
+            let snapshots = stash.snapshots();
+            let kept = retention.kept(&snapshots);
+            for snapshot in snapshots.iter() {
+                if !kept.contains(&snapshot.id) && snapshot.manifest.is_none() {
+                    println!("would remove snapshot {}", snapshot.id);
+                }
+            }
+            return;
+        }
+
+        let pruned = stash
+            .prune(&retention)
+            .unwrap_or_else(|e| fatal_error2(e.into()));
+        for id in pruned.snapshots.iter() {
+            println!("removed snapshot {}", id);
+        }
+        println!(
+            "{} files, {} chunks removed, {} objects can be collected",
+            pruned.files,
+            pruned.chunks,
+            pruned.objects.len()
+        );
+    }
+}
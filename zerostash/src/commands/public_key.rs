@@ -0,0 +1,26 @@
+//! `public-key` subcommand
+
+use crate::application::{app_reader, fatal_error2};
+use abscissa_core::{Command, Options, Runnable};
+
+/// `public-key` subcommand
+///
+/// Prints the public key of a stash, which append-only writers are
+/// configured with instead of its credentials.
+#[derive(Command, Debug, Options)]
+pub struct PublicKeyCmd {
+    #[options(free)]
+    stash: String,
+}
+
+impl Runnable for PublicKeyCmd {
+    /// Start the application.
+    fn run(&self) {
+        let stash = app_reader().stash_exists(&self.stash, &[]);
+        let key = stash
+            .public_key()
+            .unwrap_or_else(|e| fatal_error2(e.into()));
+
+        println!("{}", key);
+    }
+}
@@ -0,0 +1,58 @@
+//! `repack` subcommand
+
+use crate::application::{app_reader, fatal_error2};
+use abscissa_core::{Command, Options, Runnable};
+use libzerostash::stash::{Field, RepackOptions};
+
+/// `repack` subcommand
+///
+/// Moves the chunks files still refer to out of sparse data objects,
+/// and marks the objects to be deleted by `gc`.
+#[derive(Command, Debug, Options)]
+pub struct Repack {
+    #[options(free)]
+    stash: String,
+
+    #[options(
+        no_short,
+        meta = "SHARE",
+        help = "repack objects less than this share of which is in use",
+        default = "0.5"
+    )]
+    threshold: f64,
+
+    #[options(short = "n", help = "only list the objects to repack")]
+    dry_run: bool,
+}
+
+impl Runnable for Repack {
+    /// Start the application.
+    fn run(&self) {
+        let app = &*app_reader();
+        let mut stash = app.stash_locked(&self.stash, &[Field::Snapshots]);
+        let options = RepackOptions {
+            dry_run: self.dry_run,
+            threshold: self.threshold,
+            ..RepackOptions::default()
+        };
+
+        let repacked = stash
+            .repack(&options)
+            .unwrap_or_else(|e| fatal_error2(e.into()));
+        let verb = if self.dry_run {
+            "would repack"
+        } else {
+            "repacked"
+        };
+        for object in repacked.objects.iter() {
+            println!("{} {}", verb, object.to_string());
+        }
+        println!(
+            "{} {} chunks of {} objects, with {:.1} MiB unused",
+            verb,
+            repacked.chunks,
+            repacked.objects.len(),
+            repacked.reclaimed as f64 / (1024.0 * 1024.0)
+        );
+    }
+}
@@ -0,0 +1,42 @@
+//! `repair` subcommand
+
+use crate::application::{app_reader, fatal_error2};
+use abscissa_core::{Command, Options, Runnable};
+use libzerostash::stash::{CancelToken, Field};
+use std::process;
+
+/// `repair` subcommand
+///
+/// Rebuilds the data objects that are missing or corrupt from the
+/// rest of their parity group, and lists the ones that can't be.
+#[derive(Command, Debug, Options)]
+pub struct Repair {
+    #[options(free)]
+    stash: String,
+}
+
+impl Runnable for Repair {
+    /// Start the application.
+    fn run(&self) {
+        let app = &*app_reader();
+        let mut stash = app.stash_locked(&self.stash, &[Field::Chunks]);
+
+        let repaired = stash
+            .repair(&CancelToken::default())
+            .unwrap_or_else(|e| fatal_error2(e.into()));
+        for object in repaired.rebuilt.iter() {
+            println!("rebuilt {}", object.to_string());
+        }
+        for object in repaired.lost.iter() {
+            println!("lost {}", object.to_string());
+        }
+        println!(
+            "{} objects rebuilt, {} lost",
+            repaired.rebuilt.len(),
+            repaired.lost.len()
+        );
+        if !repaired.lost.is_empty() {
+            process::exit(1);
+        }
+    }
+}
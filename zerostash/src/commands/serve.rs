@@ -0,0 +1,100 @@
+//! `serve` subcommand
+
+use crate::application::{app_reader, fatal_error2};
+use abscissa_core::{Command, Options, Runnable};
+use anyhow::format_err;
+use libzerostash::gateway::{Daemon, Gateway, WebDav};
+use libzerostash::metrics::Metrics;
+use libzerostash::stash::Field;
+use std::env;
+use std::net::TcpListener;
+use std::sync::Arc;
+
+/// The variable holding the token of the API
+const API_TOKEN: &str = "ZEROSTASH_API_TOKEN";
+
+/// `serve` subcommand
+///
+/// Runs a read-only HTTP gateway, so other machines can open the
+/// stash without the credentials of its backend, serves the files of
+/// snapshots over WebDAV, or serves an API to back up, restore and
+/// verify.
+#[derive(Command, Debug, Options)]
+pub struct Serve {
+    #[options(help = "address to listen on", default = "127.0.0.1:8080")]
+    listen: String,
+
+    #[options(help = "don't serve snapshot manifests, only encrypted objects")]
+    objects_only: bool,
+
+    #[options(help = "serve the files of snapshots over WebDAV")]
+    webdav: bool,
+
+    #[options(help = "only serve this snapshot over WebDAV, may be repeated")]
+    snapshot: Vec<u64>,
+
+    #[options(
+        help = "serve an API to back up, restore and verify, for the token in ZEROSTASH_API_TOKEN"
+    )]
+    api: bool,
+
+    #[options(help = "serve Prometheus metrics of the API's runs at /metrics")]
+    metrics: bool,
+
+    #[options(free)]
+    stash: String,
+}
+
+impl Runnable for Serve {
+    /// Start the application.
+    fn run(&self) {
+        let app = &*app_reader();
+        if self.api {
+            let token = env::var(API_TOKEN).unwrap_or_default();
+            if token.is_empty() {
+                fatal_error2(format_err!("{} has to be set for the API", API_TOKEN).into());
+            }
+            let stash = app.stash_to_extend(&self.stash);
+            let listener =
+                TcpListener::bind(&self.listen).unwrap_or_else(|e| fatal_error2(e.into()));
+            println!(
+                "Serving the API of {} on http://{}",
+                self.stash, self.listen
+            );
+            let mut daemon = Daemon::new(stash, token);
+            if self.metrics {
+                daemon = daemon.with_metrics(Arc::new(Metrics::new(self.stash.as_str())));
+            }
+            return daemon
+                .serve(listener)
+                .unwrap_or_else(|e| fatal_error2(e.into()));
+        }
+
+        // the gateways only pass objects on, to clients that lock
+        // the stash themselves
+        let stash = if self.webdav {
+            app.stash_shared(&self.stash, &[Field::Snapshots])
+        } else {
+            app.stash_exists(&self.stash, &[Field::Snapshots])
+        };
+        let listener = TcpListener::bind(&self.listen).unwrap_or_else(|e| fatal_error2(e.into()));
+        println!("Serving {} on http://{}", self.stash, self.listen);
+
+        let result = if self.webdav {
+            let snapshots = stash
+                .snapshots()
+                .into_iter()
+                .filter(|s| self.snapshot.is_empty() || self.snapshot.contains(&s.id))
+                .collect();
+            WebDav::new(&stash, snapshots)
+                .unwrap_or_else(|e| fatal_error2(e.into()))
+                .serve(listener)
+        } else if self.objects_only {
+            Gateway::new(stash.backend()).serve(listener)
+        } else {
+            Gateway::for_stash(&stash).serve(listener)
+        };
+
+        result.unwrap_or_else(|e| fatal_error2(e.into()));
+    }
+}
@@ -0,0 +1,37 @@
+//! `sign-policy` subcommand
+
+use crate::application::fatal_error2;
+use abscissa_core::{Command, Options, Runnable};
+use libzerostash::namespaces::{Policy, WriterKey};
+use std::fs;
+
+/// `sign-policy` subcommand
+///
+/// Signs a policy of who may write to which namespace of a shared
+/// stash with the owner's key. The policy is a TOML file with a
+/// `[namespaces]` table of path prefixes and writer public keys.
+#[derive(Command, Debug, Options)]
+pub struct SignPolicy {
+    #[options(help = "owner key made by writer-key", required)]
+    key: String,
+
+    #[options(free)]
+    policy: String,
+
+    #[options(free)]
+    output: String,
+}
+
+impl Runnable for SignPolicy {
+    /// Start the application.
+    fn run(&self) {
+        let pkcs8 = fs::read(&self.key).unwrap_or_else(|e| fatal_error2(e.into()));
+        let key = WriterKey::from_pkcs8(&pkcs8).unwrap_or_else(|e| fatal_error2(e.into()));
+
+        let policy = fs::read_to_string(&self.policy).unwrap_or_else(|e| fatal_error2(e.into()));
+        let policy: Policy = toml::from_str(&policy).unwrap_or_else(|e| fatal_error2(e.into()));
+
+        let signed = toml::to_string(&policy.sign(&key)).unwrap_or_else(|e| fatal_error2(e.into()));
+        fs::write(&self.output, signed).unwrap_or_else(|e| fatal_error2(e.into()));
+    }
+}
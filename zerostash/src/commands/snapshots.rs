@@ -0,0 +1,49 @@
+//! `snapshots` subcommand
+
+use crate::application::{app_reader, fatal_error2};
+use abscissa_core::{Command, Options, Runnable};
+use anyhow::format_err;
+use libzerostash::snapshots::parse_labels;
+use libzerostash::stash::Field;
+
+/// `snapshots` subcommand
+///
+/// Lists the snapshots of a stash with their time, paths, tags and
+/// labels, tab separated, oldest first.
+#[derive(Command, Debug, Options)]
+pub struct Snapshots {
+    #[options(help = "only snapshots with this tag, can be given more than once")]
+    tag: Vec<String>,
+
+    #[options(help = "only snapshots with KEY=VALUE, can be given more than once")]
+    label: Vec<String>,
+
+    #[options(free)]
+    stash: String,
+}
+
+impl Runnable for Snapshots {
+    /// Start the application.
+    fn run(&self) {
+        let app = &*app_reader();
+        let labels =
+            parse_labels(&self.label).unwrap_or_else(|e| fatal_error2(format_err!(e).into()));
+        let stash = app.stash_shared(&self.stash, &[Field::Snapshots]);
+
+        for snapshot in stash.snapshots_matching(&self.tag, &labels) {
+            let labels = snapshot
+                .labels
+                .iter()
+                .map(|(key, value)| format!("{}={}", key, value))
+                .collect::<Vec<_>>();
+            println!(
+                "{}\t{}\t{}\t{}\t{}",
+                snapshot.id,
+                snapshot.unix_secs,
+                snapshot.paths.join(","),
+                snapshot.tags.join(","),
+                labels.join(",")
+            );
+        }
+    }
+}
@@ -0,0 +1,35 @@
+//! `split-key` subcommand
+
+use crate::application::{app_reader, fatal_error2};
+use abscissa_core::{Command, Options, Runnable};
+
+/// `split-key` subcommand
+///
+/// Splits the master key of a stash into shares, and prints one per
+/// line. Any `threshold` of them open the stash with a key of
+/// `{ source = "shares" }`, even after its credentials are changed.
+#[derive(Command, Debug, Options)]
+pub struct SplitKey {
+    #[options(help = "number of shares needed to open the stash", required)]
+    threshold: u8,
+
+    #[options(help = "number of shares to make", required)]
+    shares: u8,
+
+    #[options(free)]
+    stash: String,
+}
+
+impl Runnable for SplitKey {
+    /// Start the application.
+    fn run(&self) {
+        let stash = app_reader().stash_exists(&self.stash, &[]);
+        let shares = stash
+            .split_key(self.threshold, self.shares)
+            .unwrap_or_else(|e| fatal_error2(e.into()));
+
+        for share in shares {
+            println!("{}", share);
+        }
+    }
+}
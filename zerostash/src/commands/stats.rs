@@ -0,0 +1,46 @@
+//! `stats` subcommand
+
+use crate::application::{app_reader, fatal_error2};
+use abscissa_core::{Command, Options, Runnable};
+use libzerostash::stash::Field;
+
+/// `stats` subcommand
+///
+/// Shows the sizes and ratios of what a stash holds, then a tab
+/// separated line for each snapshot with its id, time, files, and
+/// logical, stored and exclusive bytes.
+#[derive(Command, Debug, Options)]
+pub struct Stats {
+    #[options(free)]
+    stash: String,
+}
+
+impl Runnable for Stats {
+    /// Start the application.
+    fn run(&self) {
+        let app = &*app_reader();
+        let mut stash = app.stash_shared(&self.stash, &[Field::Snapshots]);
+        let usage = stash.stats().unwrap_or_else(|e| fatal_error2(e.into()));
+
+        println!("logical: {} bytes", usage.logical_bytes);
+        println!("unique: {} bytes", usage.unique_bytes);
+        println!("stored: {} bytes", usage.stored_bytes);
+        println!("chunks: {} in {} objects", usage.chunks, usage.objects);
+        println!("deduplication: {:.2}x", usage.dedup_ratio());
+        println!("compression: {:.2}x", usage.compression_ratio());
+        if let Some(quota) = stash.quota() {
+            println!("quota: {} of {} bytes", usage.stored_bytes, quota);
+        }
+        for snapshot in usage.snapshots.iter() {
+            println!(
+                "{}\t{}\t{}\t{}\t{}\t{}",
+                snapshot.id,
+                snapshot.unix_secs,
+                snapshot.files,
+                snapshot.logical_bytes,
+                snapshot.stored_bytes,
+                snapshot.exclusive_bytes
+            );
+        }
+    }
+}
@@ -0,0 +1,55 @@
+//! `status` subcommand
+
+use crate::application::{app_reader, fatal_error2};
+use abscissa_core::{Command, Options, Runnable};
+use anyhow::format_err;
+use libzerostash::stash::{self, Change, Field};
+
+/// `status` subcommand
+///
+/// Lists the files below `path` added (`+`), removed (`-`) and
+/// modified (`M`) since a snapshot, the latest by default, from their
+/// metadata alone, like `git status`.
+#[derive(Command, Debug, Options)]
+pub struct Status {
+    #[options(help = "compare to this snapshot instead of the latest")]
+    snapshot: Option<u64>,
+
+    #[options(free)]
+    stash: String,
+
+    #[options(free)]
+    path: String,
+}
+
+impl Runnable for Status {
+    /// Start the application.
+    fn run(&self) {
+        let app = &*app_reader();
+        let stash = app.stash_shared(&self.stash, &[Field::Snapshots]);
+
+        let snapshots = stash.snapshots();
+        let snapshot = match self.snapshot {
+            Some(id) => snapshots.iter().find(|s| s.id == id),
+            None => snapshots.last(),
+        }
+        .unwrap_or_else(|| fatal_error2(format_err!("No such snapshot").into()));
+
+        let diff = stash::status(snapshot, &self.path);
+        for change in diff.changes.iter() {
+            let kind = match change {
+                Change::Added(_) => "+",
+                Change::Removed(_) => "-",
+                Change::Modified { .. } => "M",
+            };
+            println!("{} {}\t{:+}", kind, change.name(), change.size_delta());
+        }
+        println!(
+            "{} added, {} removed, {} modified, {:+} bytes",
+            diff.added(),
+            diff.removed(),
+            diff.modified(),
+            diff.size_delta()
+        );
+    }
+}
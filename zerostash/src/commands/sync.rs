@@ -0,0 +1,56 @@
+//! `sync` subcommand
+
+use crate::application::{app_reader, fatal_error2};
+use abscissa_core::{Command, Options, Runnable};
+use anyhow::format_err;
+use libzerostash::stash::{self, Field};
+
+/// `sync` subcommand
+///
+/// Copies the snapshots of a stash that another stash doesn't have
+/// yet, transferring only the chunks it's missing. The stashes can
+/// have different keys and backends, which makes this suitable for
+/// off-site copies.
+///
+/// With `--snapshot`, only the snapshots given are copied.
+#[derive(Command, Debug, Options)]
+pub struct Sync {
+    #[options(
+        no_short,
+        meta = "ID",
+        help = "only copy this snapshot, can be given more than once"
+    )]
+    snapshot: Vec<u64>,
+
+    #[options(free)]
+    src: String,
+
+    #[options(free)]
+    dst: String,
+}
+
+impl Runnable for Sync {
+    /// Start the application.
+    fn run(&self) {
+        let app = &*app_reader();
+        let mut src = app.stash_shared(&self.src, &[Field::Snapshots]);
+
+        // a destination that can't be opened yet is a new stash
+        let mut dst = app.stash_to_extend(&self.dst);
+
+        let synced = if self.snapshot.is_empty() {
+            stash::sync(&mut src, &mut dst)
+        } else {
+            let ids = src.snapshots().iter().map(|s| s.id).collect::<Vec<_>>();
+            if let Some(id) = self.snapshot.iter().find(|id| !ids.contains(id)) {
+                fatal_error2(format_err!("No such snapshot: {}", id).into());
+            }
+            src.copy_to(&mut dst, &self.snapshot)
+        }
+        .unwrap_or_else(|e| fatal_error2(e.into()));
+        println!(
+            "{} snapshots copied, with {} new chunks",
+            synced.snapshots, synced.chunks
+        );
+    }
+}
@@ -0,0 +1,33 @@
+//! `unlock` subcommand
+
+use crate::application::{app_reader, fatal_error2};
+use abscissa_core::{Command, Options, Runnable};
+
+/// `unlock` subcommand
+///
+/// Removes the locks that processes which are gone left behind on the
+/// stash, or all of them.
+#[derive(Command, Debug, Options)]
+pub struct Unlock {
+    #[options(free)]
+    stash: String,
+
+    #[options(no_short, help = "remove locks that aren't stale too")]
+    all: bool,
+}
+
+impl Runnable for Unlock {
+    /// Start the application.
+    fn run(&self) {
+        let app = &*app_reader();
+        let mut stash = app.open_stash(&self.stash);
+
+        let removed = stash
+            .force_unlock(self.all)
+            .unwrap_or_else(|e| fatal_error2(e.into()));
+        for holder in removed.iter() {
+            println!("removed {}", holder);
+        }
+        println!("{} locks removed", removed.len());
+    }
+}
@@ -0,0 +1,61 @@
+//! `verify` subcommand
+
+use crate::application::{app_reader, fatal_error2};
+use abscissa_core::{Command, Options, Runnable};
+use libzerostash::stash::{CancelToken, Field, VerifyLevel, VerifyOptions};
+use std::process;
+
+/// `verify` subcommand
+///
+/// Checks the data objects of the stash, and lists the ones that are
+/// missing or corrupt. Exits with an error if there are any.
+#[derive(Command, Debug, Options)]
+pub struct Verify {
+    #[options(free)]
+    stash: String,
+
+    #[options(no_short, help = "how far to check: exists, authenticate or rehash")]
+    level: Option<VerifyLevel>,
+
+    #[options(no_short, meta = "N", help = "only read N objects, picked at random")]
+    sample: Option<usize>,
+}
+
+impl Runnable for Verify {
+    /// Start the application.
+    fn run(&self) {
+        let app = &*app_reader();
+        let mut stash = app.stash_shared(&self.stash, &[Field::Chunks]);
+        let options = VerifyOptions {
+            level: self.level.unwrap_or_default(),
+            sample: self.sample,
+        };
+
+        let newer = stash
+            .newer_commits()
+            .unwrap_or_else(|e| fatal_error2(e.into()));
+        for generation in newer.iter() {
+            println!("newer commit {}, the root may be rolled back", generation);
+        }
+
+        let verified = stash
+            .verify_with(&options, &CancelToken::default())
+            .unwrap_or_else(|e| fatal_error2(e.into()));
+        for object in verified.missing.iter() {
+            println!("missing {}", object.to_string());
+        }
+        for object in verified.corrupt.iter() {
+            println!("corrupt {}", object.to_string());
+        }
+        println!(
+            "{} objects, {} chunks checked: {} missing, {} corrupt",
+            verified.objects,
+            verified.chunks,
+            verified.missing.len(),
+            verified.corrupt.len()
+        );
+        if !verified.is_ok() || !newer.is_empty() {
+            process::exit(1);
+        }
+    }
+}
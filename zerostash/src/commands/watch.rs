@@ -0,0 +1,69 @@
+//! `watch` subcommand
+
+use crate::application::{app_reader, fatal_error2};
+use abscissa_core::{Command, Options, Runnable};
+use libzerostash::stash::{Trigger, WatchOptions};
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+/// `watch` subcommand
+///
+/// Backs up paths, then keeps committing snapshots of what changed
+/// under them until it's stopped.
+#[derive(Command, Debug, Options)]
+pub struct Watch {
+    #[options(help = "file cache to skip unchanged files")]
+    cache: Option<String>,
+
+    #[options(help = "seconds without changes before committing", default = "10")]
+    debounce: u64,
+
+    #[options(
+        help = "commit what changed without waiting once this file is created, and remove it"
+    )]
+    now_file: Option<String>,
+
+    #[options(free)]
+    stash: String,
+
+    #[options(free)]
+    paths: Vec<String>,
+}
+
+impl Runnable for Watch {
+    /// Start the application.
+    fn run(&self) {
+        let app = &*app_reader();
+        // snapshots follow the earlier ones, which have to be read
+        let mut stash = app.stash_to_extend(&self.stash);
+
+        if let Some(cache) = &self.cache {
+            stash
+                .use_file_cache(cache)
+                .expect("Failed to open file cache");
+        }
+
+        let options = WatchOptions {
+            debounce: Duration::from_secs(self.debounce),
+            ..WatchOptions::default()
+        };
+        if let Some(path) = &self.now_file {
+            watch_now_file(PathBuf::from(path), options.now.clone());
+        }
+        stash
+            .watch(&self.paths, &options)
+            .unwrap_or_else(|e| fatal_error2(e.into()));
+    }
+}
+
+/// Pull `now` whenever `path` is created, like `touch`ed by a user.
+fn watch_now_file(path: PathBuf, now: Trigger) {
+    thread::spawn(move || loop {
+        if path.exists() {
+            let _ = std::fs::remove_file(&path);
+            now.pull();
+        }
+        thread::sleep(Duration::from_secs(1));
+    });
+}
@@ -1,7 +1,9 @@
 //! `wipe` subcommand
 
 use crate::application::app_config;
+use crate::config::TtyPrompt;
 use abscissa_core::{Command, Options, Runnable};
+use libzerostash::prompt::SecretPrompt;
 
 /// `wipe` subcommand
 ///
@@ -12,6 +14,9 @@ use abscissa_core::{Command, Options, Runnable};
 /// <https://docs.rs/gumdrop/>
 #[derive(Command, Debug, Options)]
 pub struct Wipe {
+    #[options(help = "don't ask for confirmation")]
+    yes: bool,
+
     #[options(free)]
     stash: String,
 }
@@ -19,16 +24,34 @@ pub struct Wipe {
 impl Runnable for Wipe {
     /// Start the application.
     fn run(&self) {
-        use crate::config::Backend::*;
+        use libzerostash::config::Backend::*;
 
         let config = &*app_config();
         let path = match config.resolve_stash(&self.stash) {
             None => self.stash.clone(),
             Some(stash) => match &stash.backend {
-                Filesystem { path } => path.clone(),
+                Filesystem { path, prefix: None } => path.clone(),
+                // only what's under its own prefix is the stash's
+                Filesystem {
+                    path,
+                    prefix: Some(prefix),
+                } if prefix.ends_with('/') => format!("{}/{}", path, prefix.trim_end_matches('/')),
+                Gateway { .. } => {
+                    eprintln!("Stashes behind a gateway are read-only");
+                    return;
+                }
+                _ => {
+                    eprintln!("Only stashes in local directories of their own can be wiped");
+                    return;
+                }
             },
         };
 
+        let question = format!("Delete everything in {}?", path);
+        if !self.yes && !TtyPrompt.confirm(&question).unwrap_or(false) {
+            return;
+        }
+
         std::fs::remove_dir_all(path).expect("Error while wiping stash...");
     }
 }
@@ -0,0 +1,34 @@
+//! `writer-key` subcommand
+
+use crate::application::fatal_error2;
+use abscissa_core::{Command, Options, Runnable};
+use libzerostash::namespaces::WriterKey;
+use std::fs;
+
+/// `writer-key` subcommand
+///
+/// Generates a key to sign snapshots or policies with, and prints its
+/// public key for the policy of a shared stash.
+#[derive(Command, Debug, Options)]
+pub struct WriterKeyCmd {
+    #[options(free)]
+    path: String,
+}
+
+impl Runnable for WriterKeyCmd {
+    /// Start the application.
+    fn run(&self) {
+        let pkcs8 = WriterKey::generate().unwrap_or_else(|e| fatal_error2(e.into()));
+        let key = WriterKey::from_pkcs8(&pkcs8).unwrap_or_else(|e| fatal_error2(e.into()));
+
+        fs::write(&self.path, &pkcs8).unwrap_or_else(|e| fatal_error2(e.into()));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&self.path, fs::Permissions::from_mode(0o600))
+                .unwrap_or_else(|e| fatal_error2(e.into()));
+        }
+
+        println!("{}", key.public_key());
+    }
+}
@@ -5,83 +5,62 @@
 //! for specifying it.
 
 use anyhow::Result;
+use libzerostash::config::{Config, ConfigError, Stash};
+use libzerostash::prompt::{self, SecretPrompt};
+use secrecy::SecretString;
 use serde::{Deserialize, Serialize};
 
-use std::{collections::HashMap, path::PathBuf, sync::Arc};
+use std::{convert::TryFrom, io, ops::Deref, path::PathBuf};
 
 /// Zerostash Configuration
-#[derive(Clone, Debug, Deserialize, Serialize)]
-#[serde(deny_unknown_fields)]
-pub struct ZerostashConfig {
-    /// An example configuration section
-    #[serde(rename = "stash")]
-    stashes: HashMap<String, Stash>,
+///
+/// The schema is defined by `libzerostash::config`, which also takes
+/// care of environment variable interpolation and validation.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(try_from = "toml::Value")]
+pub struct ZerostashConfig(Config);
+
+impl TryFrom<toml::Value> for ZerostashConfig {
+    type Error = ConfigError;
+
+    fn try_from(value: toml::Value) -> Result<Self, Self::Error> {
+        Config::from_value(value).map(ZerostashConfig)
+    }
 }
 
-impl Default for ZerostashConfig {
-    fn default() -> ZerostashConfig {
-        ZerostashConfig {
-            stashes: HashMap::new(),
-        }
+impl Deref for ZerostashConfig {
+    type Target = Config;
+
+    fn deref(&self) -> &Config {
+        &self.0
     }
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
-#[serde(deny_unknown_fields)]
-pub struct Stash {
-    key: Key,
-    pub(crate) backend: Backend,
-}
+/// Prompts on the terminal, writing to stderr
+pub struct TtyPrompt;
 
-impl Stash {
-    pub fn try_open(&self) -> Result<libzerostash::Stash> {
-        let key = {
-            use Key::*;
-            match &self.key {
-                None => ask_credentials()?,
-                Plaintext { user, password } => libzerostash::StashKey::open_stash(user, password)?,
-            }
-        };
-
-        let stash = {
-            use Backend::*;
-            match &self.backend {
-                Filesystem { path } => libzerostash::Stash::new(
-                    Arc::new(libzerostash::backends::Directory::new(path)?),
-                    key,
-                ),
-            }
-        };
-
-        Ok(stash)
+impl SecretPrompt for TtyPrompt {
+    fn ask(&self, prompt: &str) -> io::Result<String> {
+        rprompt::prompt_reply_stderr(prompt)
     }
-}
 
-pub fn ask_credentials() -> Result<libzerostash::StashKey> {
-    let username = rprompt::prompt_reply_stderr("Username: ")?;
-    let password = rpassword::prompt_password_stderr("Password: ")?;
-    Ok(libzerostash::StashKey::open_stash(username, password)?)
-}
+    fn ask_secret(&self, prompt: &str) -> io::Result<SecretString> {
+        rpassword::prompt_password_stderr(prompt).map(SecretString::new)
+    }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
-#[serde(deny_unknown_fields)]
-#[serde(tag = "source")]
-pub enum Key {
-    #[serde(rename = "plaintext")]
-    Plaintext { user: String, password: String },
-    #[serde(rename = "ask")]
-    None,
+    fn confirm(&self, prompt: &str) -> io::Result<bool> {
+        let answer = rprompt::prompt_reply_stderr(&format!("{} [y/N] ", prompt))?;
+        Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+    }
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
-#[serde(deny_unknown_fields)]
-#[serde(tag = "type")]
-pub enum Backend {
-    #[serde(rename = "fs")]
-    Filesystem { path: String },
+/// Ask for the user and password of a stash on the terminal
+pub fn ask_credentials() -> Result<(String, SecretString)> {
+    Ok(prompt::ask_credentials(&TtyPrompt)?)
 }
 
 impl ZerostashConfig {
+    /// Where the configuration file is, creating its directory
     pub fn path() -> PathBuf {
         xdg::BaseDirectories::with_prefix("zerostash")
             .unwrap()
@@ -89,12 +68,14 @@ impl ZerostashConfig {
             .expect("cannot create configuration directory")
     }
 
+    /// Save the configuration to `path()`
     pub fn write(&self) -> Result<()> {
         unimplemented!()
     }
 
-    pub fn resolve_stash(&self, alias: impl AsRef<str>) -> Option<&Stash> {
-        self.stashes.get(alias.as_ref())
+    /// Open a stash configured under an alias
+    pub fn try_open(&self, stash: &Stash) -> Result<libzerostash::Stash> {
+        Ok(stash.builder(&TtyPrompt, &self.tuning)?.build()?)
     }
 }
 
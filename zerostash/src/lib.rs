@@ -14,9 +14,12 @@
     unused_lifetimes,
     unused_qualifications
 )]
+// the derives of abscissa implement traits inside their own consts
+#![allow(non_local_definitions)]
 
 pub mod application;
 pub mod commands;
 pub mod config;
 pub mod error;
 pub mod prelude;
+pub mod progress;
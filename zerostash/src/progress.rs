@@ -0,0 +1,110 @@
+//! A status line of running commits and restores, on stderr
+
+use libzerostash::progress::{Phase, Progress};
+use libzerostash::stats::Stage;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How often the line is redrawn
+const REDRAW: Duration = Duration::from_millis(250);
+
+/// Counts what the library reports, and redraws a line with it.
+#[derive(Default)]
+pub struct StatusLine {
+    discovered: AtomicU64,
+    files: AtomicU64,
+    bytes: AtomicU64,
+    new_chunks: AtomicU64,
+    reused_chunks: AtomicU64,
+    uploaded: AtomicU64,
+    downloaded: AtomicU64,
+    drawn: Mutex<Option<Instant>>,
+}
+
+impl StatusLine {
+    /// Draw the final counts, and end the line.
+    pub fn finish(&self) {
+        self.draw("");
+        eprintln!();
+    }
+
+    fn draw(&self, name: &str) {
+        let count = |c: &AtomicU64| c.load(Ordering::Relaxed);
+        let mib = |c: &AtomicU64| count(c) as f64 / (1024.0 * 1024.0);
+        let files = match count(&self.discovered) {
+            0 => count(&self.files).to_string(),
+            total => format!("{} of {}", count(&self.files), total),
+        };
+        let mut line = format!("{} files, {:.1} MiB", files, mib(&self.bytes));
+        if count(&self.new_chunks) + count(&self.reused_chunks) > 0 {
+            line += &format!(
+                ", {} new chunks, {} deduplicated",
+                count(&self.new_chunks),
+                count(&self.reused_chunks)
+            );
+        }
+        if count(&self.uploaded) > 0 {
+            line += &format!(", {:.1} MiB uploaded", mib(&self.uploaded));
+        }
+        if count(&self.downloaded) > 0 {
+            line += &format!(", {:.1} MiB downloaded", mib(&self.downloaded));
+        }
+        if !name.is_empty() {
+            line += &format!(": {}", name);
+        }
+
+        // clear what's left of a longer line before
+        let mut stderr = io::stderr().lock();
+        let _ = write!(stderr, "\r{}\x1b[K", line);
+        let _ = stderr.flush();
+    }
+}
+
+impl Progress for StatusLine {
+    fn phase(&self, phase: Phase, total: Option<u64>) {
+        if let (Phase::Restore, Some(total)) = (phase, total) {
+            self.discovered.store(total, Ordering::Relaxed);
+        }
+    }
+
+    fn item(&self, bytes: u64) {
+        self.files.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn transfer(&self, stage: Stage, bytes: u64) {
+        let counter = match stage {
+            Stage::Upload => &self.uploaded,
+            _ => &self.downloaded,
+        };
+        counter.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn discovered(&self) {
+        self.discovered.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn file(&self, name: &str) {
+        // workers that find the line being drawn skip it
+        let mut drawn = match self.drawn.try_lock() {
+            Ok(drawn) => drawn,
+            Err(_) => return,
+        };
+        if drawn.is_some_and(|at| at.elapsed() < REDRAW) {
+            return;
+        }
+        *drawn = Some(Instant::now());
+        self.draw(name);
+    }
+
+    fn chunk(&self, _bytes: u64, new: bool) {
+        let counter = if new {
+            &self.new_chunks
+        } else {
+            &self.reused_chunks
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
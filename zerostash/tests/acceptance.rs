@@ -20,7 +20,9 @@
 
 use abscissa_core::testing::prelude::*;
 use once_cell::sync::Lazy;
-use zerostash::config::ZerostashConfig;
+use std::fs;
+use std::io::Read;
+use std::path::Path;
 
 /// Executes your application binary via `cargo run`.
 ///
@@ -28,7 +30,7 @@ use zerostash::config::ZerostashConfig;
 /// the runner acquire a mutex when executing commands and inspecting
 /// exit statuses, serializing what would otherwise be multithreaded
 /// invocations as `cargo test` executes tests in parallel by default.
-pub static RUNNER: Lazy<CmdRunner> = Lazy::new(|| CmdRunner::default());
+pub static RUNNER: Lazy<CmdRunner> = Lazy::new(CmdRunner::default);
 
 // /// Use `ZerostashConfig::default()` value if no config or args
 // #[test]
@@ -89,3 +91,72 @@ pub static RUNNER: Lazy<CmdRunner> = Lazy::new(|| CmdRunner::default());
 //     let mut cmd = runner.arg("version").capture_stdout().run();
 //     cmd.stdout().expect_regex(r"\A\w+ [\d\.\-]+\z");
 // }
+
+/// Runs `0s` with a config holding a `test` stash in `dir`, so
+/// nothing asks for credentials.
+fn zerostash(dir: &Path, args: &[&str]) -> CmdRunner {
+    let config = dir.join("config.toml");
+    fs::write(
+        &config,
+        format!(
+            r#"
+[stash.test]
+key = {{ source = "plaintext", user = "test", password = "test" }}
+backend = {{ type = "fs", path = "{}" }}
+"#,
+            dir.join("stash").display()
+        ),
+    )
+    .unwrap();
+
+    let mut runner = CmdRunner::new(env!("CARGO_BIN_EXE_0s"));
+    runner.arg("-c").arg(&config).args(args);
+    runner
+}
+
+/// What `runner` prints on stdout, once it succeeded.
+fn output(mut runner: CmdRunner) -> String {
+    let mut cmd = runner.capture_stdout().run();
+    let mut out = String::new();
+    cmd.stdout().read_to_string(&mut out).unwrap();
+    cmd.wait().unwrap().expect_success();
+    out
+}
+
+#[test]
+fn plain_commits_keep_the_earlier_snapshots() {
+    let dir = std::env::temp_dir().join("0s_test_plain_commits");
+    let _ = fs::remove_dir_all(&dir);
+    let (first, second) = (dir.join("first"), dir.join("second"));
+    fs::create_dir_all(&first).unwrap();
+    fs::create_dir_all(&second).unwrap();
+    fs::write(first.join("a"), b"first file").unwrap();
+    fs::write(second.join("b"), b"second file").unwrap();
+
+    for path in [&first, &second] {
+        zerostash(&dir, &["commit", "test", path.to_str().unwrap()])
+            .status()
+            .expect_success();
+    }
+
+    let snapshots = output(zerostash(&dir, &["snapshots", "test"]));
+    let paths = snapshots
+        .lines()
+        .map(|line| line.split('\t').nth(2).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        paths,
+        [first.to_str().unwrap(), second.to_str().unwrap()],
+        "{}",
+        snapshots
+    );
+
+    let files = output(zerostash(&dir, &["ls", "test"]));
+    let mut files = files.lines().collect::<Vec<_>>();
+    files.sort_unstable();
+    assert_eq!(files.len(), 2, "{:?}", files);
+    assert!(files[0].ends_with("first/a"), "{:?}", files);
+    assert!(files[1].ends_with("second/b"), "{:?}", files);
+
+    fs::remove_dir_all(&dir).unwrap();
+}